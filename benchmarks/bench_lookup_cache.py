@@ -0,0 +1,39 @@
+"""Throughput of the topic normalization cache with lookups from several threads.
+
+`test_filters` normalizes each topic with the GIL released, like the pipeline does for
+every flattened key, so threads calling it contend only for the lookup cache and the
+filters. Compare the rates for 1 and more threads; with one lock for the whole cache
+they fall as threads are added, with the sharded cache they rise up to the core count.
+
+    python benchmarks/bench_lookup_cache.py [threads ...]
+"""
+import sys
+import time
+from concurrent.futures import ThreadPoolExecutor
+
+from loxmqttrelay.config import global_config
+from tests.test_miniserver_data_processor import TestMiniserverDataProcessor
+
+TOPICS = [f"zigbee2mqtt/device{i}/sensor/temperature" for i in range(5000)]
+ROUNDS = 20
+
+
+def run(processor, threads):
+    chunk = [TOPICS[i::threads] for i in range(threads)]
+    start = time.perf_counter()
+    with ThreadPoolExecutor(threads) as pool:
+        for _ in range(ROUNDS):
+            list(pool.map(processor.test_filters, chunk))
+    return len(TOPICS) * ROUNDS / (time.perf_counter() - start)
+
+
+def main():
+    global_config.general.cache_size = 100000
+    processor = TestMiniserverDataProcessor(global_config).processor
+    run(processor, 1)  # warm up the caches
+    for threads in [int(arg) for arg in sys.argv[1:]] or [1, 2, 4, 8]:
+        print(f"{threads} threads: {run(processor, threads):,.0f} lookups/s")
+
+
+if __name__ == "__main__":
+    main()
@@ -1,5 +1,6 @@
 from setuptools import find_packages, setup
 from setuptools_rust import Binding, RustExtension
+import os
 import platform
 import logging
 
@@ -23,6 +24,11 @@ base_setup = {
 arch = platform.uname().machine.lower()
 logger.info(f"Detected platform: {arch}")
 
+# Optionale Cargo-Features, z.B. LOXMQTTRELAY_FEATURES=wasm fuer WASM-Plugins
+features = [f.strip() for f in os.environ.get("LOXMQTTRELAY_FEATURES", "").split(",") if f.strip()]
+if features:
+    logger.info(f"Enabling cargo features: {features}")
+
 rust_extensions = []
 
 # AMD64 optimierte und kompatible Builds
@@ -34,6 +40,7 @@ if arch in ("x86_64", "amd64"):
             "loxmqttrelay.optimized._loxmqttrelay",
             path="Cargo.toml",
             binding=Binding.PyO3,
+            features=features,
             rustc_flags=["-C", "opt-level=3", "-C", "target-cpu=native"]
         )
     )
@@ -43,6 +50,7 @@ if arch in ("x86_64", "amd64"):
             "loxmqttrelay.compatible._loxmqttrelay",
             path="Cargo.toml",
             binding=Binding.PyO3,
+            features=features,
             rustc_flags=["-C", "opt-level=2", "-C", "target-cpu=generic"]
         )
     )
@@ -53,6 +61,7 @@ else:
             "loxmqttrelay.compatible._loxmqttrelay",
             path="Cargo.toml",
             binding=Binding.PyO3,
+            features=features,
             rustc_flags=["-C", "opt-level=2", "-C", "target-cpu=generic"]
         )
     )
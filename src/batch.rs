@@ -0,0 +1,158 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+use crate::completion::PendingDelivery;
+
+/// Largest datagram built from a batch; stays below a typical MTU, so batches aren't
+/// fragmented on the way to the Miniserver.
+pub const MAX_DATAGRAM_BYTES: usize = 1400;
+
+/// When values going to the Miniserver are combined (`miniserver.batch_mode`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BatchMode {
+    /// Every value is sent on its own
+    #[default]
+    Off,
+    /// The values of one message go out together
+    Message,
+    /// Values arriving within `batch_window_ms` go out together, across messages
+    Window,
+}
+
+impl BatchMode {
+    pub fn parse(value: &str) -> PyResult<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "" | "off" => Ok(BatchMode::Off),
+            "message" => Ok(BatchMode::Message),
+            "window" => Ok(BatchMode::Window),
+            other => Err(PyValueError::new_err(format!(
+                "Invalid batch_mode '{}': expected 'off', 'message' or 'window'",
+                other
+            ))),
+        }
+    }
+}
+
+/// A value waiting in a batch, with the id of its delivery record.
+#[derive(Debug)]
+pub struct BatchEntry {
+    pub name: String,
+    pub value: String,
+    pub id: u64,
+    /// Set for values of `handle_mqtt_message_async`
+    pub pending: Option<PendingDelivery>,
+}
+
+/// `name=value` of every entry, joined by `separator`.
+pub fn join(entries: &[BatchEntry], separator: &str) -> String {
+    entries
+        .iter()
+        .map(|entry| format!("{}={}", entry.name, entry.value))
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+/// Split `entries` into runs of at most `max_values` whose joined text stays within
+/// `max_bytes`. An entry longer than `max_bytes` on its own gets a run of its own.
+pub fn chunks<'a>(entries: &'a [BatchEntry], separator: &str, max_values: usize, max_bytes: usize) -> Vec<&'a [BatchEntry]> {
+    let mut chunks = Vec::new();
+    let (mut start, mut bytes) = (0, 0);
+    for (i, entry) in entries.iter().enumerate() {
+        let len = entry.name.len() + 1 + entry.value.len();
+        let added = if i == start { len } else { separator.len() + len };
+        if i > start && (i - start >= max_values.max(1) || bytes + added > max_bytes) {
+            chunks.push(&entries[start..i]);
+            start = i;
+            bytes = len;
+        } else {
+            bytes += added;
+        }
+    }
+    if start < entries.len() {
+        chunks.push(&entries[start..]);
+    }
+    chunks
+}
+
+/// What happened to a value added to a `Window`.
+pub enum Pushed {
+    /// It opened a new window, to be sent once it ends; the window's generation
+    Opened(u64),
+    /// It joined the open window
+    Joined,
+    /// It filled the window, which has to be sent right away
+    Full(Vec<BatchEntry>),
+}
+
+/// The values collected for one transport while `batch_mode = "window"`.
+///
+/// The first value opens a window; whoever opened it sends the batch after
+/// `batch_window_ms`, unless `batch_max_values` filled it before.
+pub struct Window {
+    max_values: usize,
+    /// The generation of the open window and its values
+    pending: Mutex<(u64, Vec<BatchEntry>)>,
+}
+
+impl Window {
+    pub fn new(max_values: usize) -> Self {
+        Window {
+            max_values: max_values.max(1),
+            pending: Mutex::new((0, Vec::new())),
+        }
+    }
+
+    pub fn push(&self, entry: BatchEntry) -> Pushed {
+        let mut pending = self.pending.lock().unwrap();
+        pending.1.push(entry);
+        if pending.1.len() >= self.max_values {
+            pending.0 += 1;
+            return Pushed::Full(std::mem::take(&mut pending.1));
+        }
+        if pending.1.len() == 1 {
+            Pushed::Opened(pending.0)
+        } else {
+            Pushed::Joined
+        }
+    }
+
+    /// The values of window `generation`, unless it was already sent because it filled up.
+    pub fn take(&self, generation: u64) -> Vec<BatchEntry> {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.0 != generation {
+            return Vec::new();
+        }
+        pending.0 += 1;
+        std::mem::take(&mut pending.1)
+    }
+}
+
+/// `miniserver.batch_*`: how values are combined before they are sent.
+pub struct Batching {
+    pub mode: BatchMode,
+    pub window: Duration,
+    pub max_values: usize,
+    /// Virtual text input receiving HTTP and websocket batches; empty: only UDP is batched
+    pub input: String,
+    pub separator: String,
+    pub udp: Window,
+    pub miniserver: Window,
+}
+
+impl Batching {
+    /// `None` with `batch_mode = "off"`.
+    pub fn new(mode: BatchMode, window_ms: u64, max_values: usize, input: String, separator: String) -> Option<Self> {
+        (mode != BatchMode::Off).then(|| Batching {
+            mode,
+            window: Duration::from_millis(window_ms),
+            max_values: max_values.max(1),
+            input,
+            separator,
+            udp: Window::new(max_values),
+            miniserver: Window::new(max_values),
+        })
+    }
+}
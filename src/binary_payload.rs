@@ -0,0 +1,503 @@
+use base64::{engine::general_purpose, Engine};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use regex::Regex;
+use serde_json::{Map, Number, Value};
+
+use log::{debug, error};
+
+use crate::config_entries::dict_entries;
+
+/// Nesting deeper than this is rejected instead of risking the stack.
+const MAX_DEPTH: usize = 128;
+
+/// Binary encoding of the payloads of a topic (`processing.payload_formats`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PayloadFormat {
+    MessagePack,
+    Cbor,
+    /// MessagePack or CBOR, whichever decodes the whole payload; payloads that are valid
+    /// UTF-8 are left as text
+    Auto,
+}
+
+impl PayloadFormat {
+    pub fn parse(value: &str) -> PyResult<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "msgpack" | "messagepack" => Ok(PayloadFormat::MessagePack),
+            "cbor" => Ok(PayloadFormat::Cbor),
+            "auto" => Ok(PayloadFormat::Auto),
+            other => Err(PyValueError::new_err(format!(
+                "Invalid payload format '{}': expected 'msgpack', 'cbor' or 'auto'",
+                other
+            ))),
+        }
+    }
+
+    /// The payload as a JSON value, or `None` if `Auto` finds a text payload.
+    pub fn decode(self, raw: &[u8]) -> Option<Result<Value, String>> {
+        match self {
+            PayloadFormat::MessagePack => Some(Decoder::new(raw).complete(Decoder::msgpack)),
+            PayloadFormat::Cbor => Some(Decoder::new(raw).complete(Decoder::cbor)),
+            PayloadFormat::Auto => {
+                if std::str::from_utf8(raw).is_ok() {
+                    return None;
+                }
+                let msgpack = Decoder::new(raw).complete(Decoder::msgpack);
+                Some(msgpack.or_else(|_| Decoder::new(raw).complete(Decoder::cbor)))
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct FormatEntry {
+    pattern: Regex,
+    format: PayloadFormat,
+}
+
+/// Ordered topic patterns with the format their payloads are decoded from.
+#[derive(Debug, Default)]
+pub struct PayloadFormats {
+    entries: Vec<FormatEntry>,
+}
+
+impl PayloadFormats {
+    /// The format of the first entry whose pattern matches `topic`.
+    pub fn find(&self, topic: &str) -> Option<PayloadFormat> {
+        self.entries.iter().find(|e| e.pattern.is_match(topic)).map(|e| e.format)
+    }
+}
+
+/// Build `PayloadFormats` from a list of `{"pattern": ..., "format": ...}` dicts.
+///
+/// Malformed entries and unknown formats always raise a `ValueError`; invalid regexes
+/// are dropped with an error log unless `strict` is set.
+pub fn parse_payload_formats(entries: &Bound<'_, PyAny>, strict: bool) -> PyResult<PayloadFormats> {
+    let mut formats = Vec::new();
+    let mut invalid = Vec::new();
+    for (index, entry) in dict_entries(entries, "Payload format")?.iter().enumerate() {
+        let (Some(source), Some(format)) = (entry.get_item("pattern")?, entry.get_item("format")?) else {
+            return Err(PyValueError::new_err(format!(
+                "Payload format {} needs both 'pattern' and 'format'",
+                index
+            )));
+        };
+        let source: String = source.extract()?;
+        let format = PayloadFormat::parse(&format.extract::<String>()?)?;
+        match Regex::new(&source) {
+            Ok(pattern) => formats.push(FormatEntry { pattern, format }),
+            Err(e) => {
+                error!("Invalid payload format pattern '{}': {}", source, e);
+                invalid.push(format!("'{}': {}", source, e));
+            }
+        }
+    }
+    if strict && !invalid.is_empty() {
+        return Err(PyValueError::new_err(format!(
+            "Invalid payload format patterns: {}",
+            invalid.join(", ")
+        )));
+    }
+    debug!("Compiled {} payload formats", formats.len());
+    Ok(PayloadFormats { entries: formats })
+}
+
+/// Byte strings have no JSON counterpart; they become `[base64:...]` like binary payloads.
+fn bytes_value(raw: &[u8]) -> Value {
+    Value::String(format!("[base64:{}]", general_purpose::STANDARD.encode(raw)))
+}
+
+fn float_value(f: f64) -> Value {
+    Number::from_f64(f).map(Value::Number).unwrap_or(Value::Null)
+}
+
+/// Integers that don't fit `i64`/`u64` (CBOR negatives down to -2^64) keep all digits.
+fn int_value(i: i128) -> Value {
+    serde_json::from_str(&i.to_string()).unwrap_or(Value::Null)
+}
+
+/// Map keys that aren't strings are used in their JSON form, e.g. `1` or `true`.
+fn key_text(key: Value) -> String {
+    match key {
+        Value::String(s) => s,
+        other => other.to_string(),
+    }
+}
+
+/// IEEE 754 half precision, as used by CBOR.
+fn half_to_f64(half: u16) -> f64 {
+    let exponent = (half >> 10) & 0x1f;
+    let mantissa = (half & 0x3ff) as f64;
+    let value = match exponent {
+        0 => mantissa * 2f64.powi(-24),
+        31 if mantissa == 0.0 => f64::INFINITY,
+        31 => f64::NAN,
+        _ => (mantissa + 1024.0) * 2f64.powi(exponent as i32 - 25),
+    };
+    if half & 0x8000 != 0 { -value } else { value }
+}
+
+struct Decoder<'a> {
+    raw: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(raw: &'a [u8]) -> Self {
+        Decoder { raw, pos: 0, depth: 0 }
+    }
+
+    /// Decode one value with `item` and require it to span the whole payload.
+    fn complete(mut self, item: fn(&mut Self) -> Result<Value, String>) -> Result<Value, String> {
+        let value = item(&mut self)?;
+        if self.pos != self.raw.len() {
+            return Err(format!("{} trailing bytes after the value", self.raw.len() - self.pos));
+        }
+        Ok(value)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.raw.len() - self.pos < len {
+            return Err(format!("truncated at byte {}", self.pos));
+        }
+        let bytes = &self.raw[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn uint(&mut self, len: usize) -> Result<u64, String> {
+        Ok(self.take(len)?.iter().fold(0, |acc, &b| (acc << 8) | b as u64))
+    }
+
+    /// A length from the payload, checked against the bytes left: every element takes
+    /// at least one byte, so nothing longer can be valid.
+    fn length(&self, len: u64) -> Result<usize, String> {
+        if len > (self.raw.len() - self.pos) as u64 {
+            return Err(format!("length {} exceeds the payload at byte {}", len, self.pos));
+        }
+        Ok(len as usize)
+    }
+
+    fn text(&mut self, len: u64) -> Result<String, String> {
+        let len = self.length(len)?;
+        let pos = self.pos;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| format!("invalid UTF-8 string at byte {}", pos))
+    }
+
+    fn nested<T>(&mut self, inner: impl FnOnce(&mut Self) -> Result<T, String>) -> Result<T, String> {
+        if self.depth == MAX_DEPTH {
+            return Err(format!("nested deeper than {} levels", MAX_DEPTH));
+        }
+        self.depth += 1;
+        let result = inner(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn msgpack(&mut self) -> Result<Value, String> {
+        let marker = self.byte()?;
+        Ok(match marker {
+            0x00..=0x7f => Value::from(marker),
+            0xe0..=0xff => Value::from(marker as i8),
+            0x80..=0x8f => self.msgpack_map((marker & 0x0f) as u64)?,
+            0x90..=0x9f => self.msgpack_array((marker & 0x0f) as u64)?,
+            0xa0..=0xbf => Value::String(self.text((marker & 0x1f) as u64)?),
+            0xc0 => Value::Null,
+            0xc2 => Value::Bool(false),
+            0xc3 => Value::Bool(true),
+            0xc4..=0xc6 => {
+                let len = self.uint(1 << (marker - 0xc4))?;
+                let len = self.length(len)?;
+                bytes_value(self.take(len)?)
+            }
+            0xc7..=0xc9 => {
+                let len = self.uint(1 << (marker - 0xc7))?;
+                self.msgpack_ext(len)?
+            }
+            0xca => float_value(f32::from_bits(self.uint(4)? as u32) as f64),
+            0xcb => float_value(f64::from_bits(self.uint(8)?)),
+            0xcc..=0xcf => Value::from(self.uint(1 << (marker - 0xcc))?),
+            0xd0 => Value::from(self.uint(1)? as u8 as i8),
+            0xd1 => Value::from(self.uint(2)? as u16 as i16),
+            0xd2 => Value::from(self.uint(4)? as u32 as i32),
+            0xd3 => Value::from(self.uint(8)? as i64),
+            0xd4..=0xd8 => self.msgpack_ext(1 << (marker - 0xd4))?,
+            0xd9..=0xdb => {
+                let len = self.uint(1 << (marker - 0xd9))?;
+                Value::String(self.text(len)?)
+            }
+            0xdc | 0xdd => {
+                let len = self.uint(if marker == 0xdc { 2 } else { 4 })?;
+                self.msgpack_array(len)?
+            }
+            0xde | 0xdf => {
+                let len = self.uint(if marker == 0xde { 2 } else { 4 })?;
+                self.msgpack_map(len)?
+            }
+            0xc1 => return Err(format!("reserved MessagePack marker 0xc1 at byte {}", self.pos - 1)),
+        })
+    }
+
+    fn msgpack_array(&mut self, len: u64) -> Result<Value, String> {
+        let len = self.length(len)?;
+        self.nested(|d| (0..len).map(|_| d.msgpack()).collect::<Result<_, _>>().map(Value::Array))
+    }
+
+    fn msgpack_map(&mut self, len: u64) -> Result<Value, String> {
+        let len = self.length(len)?;
+        self.nested(|d| {
+            let mut map = Map::new();
+            for _ in 0..len {
+                let key = key_text(d.msgpack()?);
+                map.insert(key, d.msgpack()?);
+            }
+            Ok(Value::Object(map))
+        })
+    }
+
+    /// Extension types: the timestamp (-1) becomes seconds since the epoch, others their
+    /// data as base64.
+    fn msgpack_ext(&mut self, len: u64) -> Result<Value, String> {
+        let kind = self.byte()? as i8;
+        let len = self.length(len)?;
+        let data = self.take(len)?;
+        let number = |bytes: &[u8]| bytes.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64);
+        Ok(match (kind, len) {
+            (-1, 4) => Value::from(number(data)),
+            (-1, 8) => {
+                let packed = number(data);
+                float_value((packed & 0x3_ffff_ffff) as f64 + (packed >> 34) as f64 / 1e9)
+            }
+            (-1, 12) => float_value(number(&data[4..]) as i64 as f64 + number(&data[..4]) as f64 / 1e9),
+            _ => bytes_value(data),
+        })
+    }
+
+    /// The argument of a CBOR initial byte, or `None` for indefinite length.
+    fn cbor_argument(&mut self, info: u8) -> Result<Option<u64>, String> {
+        match info {
+            0..=23 => Ok(Some(info as u64)),
+            24..=27 => Ok(Some(self.uint(1 << (info - 24))?)),
+            31 => Ok(None),
+            _ => Err(format!("reserved CBOR additional information {} at byte {}", info, self.pos - 1)),
+        }
+    }
+
+    fn cbor(&mut self) -> Result<Value, String> {
+        let initial = self.byte()?;
+        let (major, info) = (initial >> 5, initial & 0x1f);
+        if major == 7 {
+            return Ok(match info {
+                20 => Value::Bool(false),
+                21 => Value::Bool(true),
+                22 | 23 => Value::Null,
+                25 => float_value(half_to_f64(self.uint(2)? as u16)),
+                26 => float_value(f32::from_bits(self.uint(4)? as u32) as f64),
+                27 => float_value(f64::from_bits(self.uint(8)?)),
+                0..=19 => Value::from(info),
+                24 => Value::from(self.byte()?),
+                _ => return Err(format!("unexpected CBOR simple value {} at byte {}", info, self.pos - 1)),
+            });
+        }
+        let argument = self.cbor_argument(info)?;
+        match (major, argument) {
+            (0, Some(n)) => Ok(Value::from(n)),
+            (1, Some(n)) => Ok(int_value(-1 - n as i128)),
+            (2, Some(len)) => {
+                let len = self.length(len)?;
+                Ok(bytes_value(self.take(len)?))
+            }
+            (2, None) => Ok(bytes_value(&self.cbor_chunks(2)?)),
+            (3, Some(len)) => Ok(Value::String(self.text(len)?)),
+            (3, None) => {
+                let pos = self.pos;
+                String::from_utf8(self.cbor_chunks(3)?)
+                    .map(Value::String)
+                    .map_err(|_| format!("invalid UTF-8 string at byte {}", pos))
+            }
+            (4, len) => self.nested(|d| {
+                let mut items = Vec::new();
+                while d.cbor_more(len, items.len())? {
+                    items.push(d.cbor()?);
+                }
+                Ok(Value::Array(items))
+            }),
+            (5, len) => self.nested(|d| {
+                let mut map = Map::new();
+                while d.cbor_more(len, map.len())? {
+                    let key = key_text(d.cbor()?);
+                    map.insert(key, d.cbor()?);
+                }
+                Ok(Value::Object(map))
+            }),
+            // Tags (dates, bignums, ...) are dropped, the tagged value stays
+            (6, Some(_)) => self.nested(Self::cbor),
+            _ => Err(format!("indefinite length not allowed for CBOR major type {} at byte {}", major, self.pos - 1)),
+        }
+    }
+
+    /// Whether another element of a CBOR array or map follows; indefinite ones end with
+    /// a break byte.
+    fn cbor_more(&mut self, len: Option<u64>, done: usize) -> Result<bool, String> {
+        match len {
+            Some(len) => Ok((done as u64) < len),
+            None if self.raw.get(self.pos) == Some(&0xff) => {
+                self.pos += 1;
+                Ok(false)
+            }
+            None => Ok(true),
+        }
+    }
+
+    /// The concatenated chunks of an indefinite length byte (`major` 2) or text (3) string.
+    fn cbor_chunks(&mut self, major: u8) -> Result<Vec<u8>, String> {
+        let mut bytes = Vec::new();
+        loop {
+            let initial = self.byte()?;
+            if initial == 0xff {
+                return Ok(bytes);
+            }
+            if initial >> 5 != major {
+                return Err(format!("invalid chunk in indefinite CBOR string at byte {}", self.pos - 1));
+            }
+            let Some(len) = self.cbor_argument(initial & 0x1f)? else {
+                return Err(format!("nested indefinite CBOR string at byte {}", self.pos - 1));
+            };
+            let len = self.length(len)?;
+            bytes.extend_from_slice(self.take(len)?);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn msgpack(raw: &[u8]) -> Result<Value, String> {
+        PayloadFormat::MessagePack.decode(raw).unwrap()
+    }
+
+    fn cbor(raw: &[u8]) -> Result<Value, String> {
+        PayloadFormat::Cbor.decode(raw).unwrap()
+    }
+
+    /// `depth` single-element arrays around a null, with `array` as the array header.
+    fn nested(array: u8, null: u8, depth: usize) -> Vec<u8> {
+        let mut raw = vec![array; depth];
+        raw.push(null);
+        raw
+    }
+
+    #[test]
+    fn decodes_values() {
+        // {"t": 21.5, "on": true}
+        let raw = [0x82, 0xa1, b't', 0xcb, 0x40, 0x35, 0x80, 0, 0, 0, 0, 0, 0xa2, b'o', b'n', 0xc3];
+        assert_eq!(msgpack(&raw).unwrap(), json!({"t": 21.5, "on": true}));
+        let raw = [0xa2, 0x61, b't', 0xf9, 0x4d, 0x60, 0x62, b'o', b'n', 0xf5];
+        assert_eq!(cbor(&raw).unwrap(), json!({"t": 21.5, "on": true}));
+        // Indefinite array with a negative integer and a tagged value
+        assert_eq!(cbor(&[0x9f, 0x38, 0x63, 0xc1, 0x1a, 0, 0, 0, 1, 0xff]).unwrap(), json!([-100, 1]));
+    }
+
+    #[test]
+    fn auto_leaves_text_alone() {
+        assert!(PayloadFormat::Auto.decode(b"21.5").is_none());
+        assert_eq!(PayloadFormat::Auto.decode(&[0x92, 0x01, 0xc0]).unwrap().unwrap(), json!([1, null]));
+        // Neither MessagePack (0xc1 is reserved) nor CBOR (a trailing byte after [-2])
+        assert_eq!(PayloadFormat::Auto.decode(&[0x81, 0x21, 0xc1]).map(|r| r.is_err()), Some(true));
+        // Not MessagePack, but CBOR
+        assert_eq!(PayloadFormat::Auto.decode(&[0x81, 0xf9, 0xc1, 0x00]).unwrap().unwrap(), json!([-2.5]));
+    }
+
+    #[test]
+    fn truncated_msgpack() {
+        let raw = [0x82, 0xa1, b't', 0xcb, 0x40, 0x35, 0x80, 0, 0, 0, 0, 0, 0xa2, b'o', b'n', 0xc3];
+        for len in 0..raw.len() {
+            assert!(msgpack(&raw[..len]).is_err(), "{} bytes", len);
+        }
+        assert_eq!(msgpack(&[0xcd, 0x01]).unwrap_err(), "truncated at byte 1");
+        assert!(msgpack(&[0xd6, 0xff, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn truncated_cbor() {
+        let raw = [0xa2, 0x61, b't', 0xf9, 0x4d, 0x60, 0x62, b'o', b'n', 0xf5];
+        for len in 0..raw.len() {
+            assert!(cbor(&raw[..len]).is_err(), "{} bytes", len);
+        }
+        // Indefinite containers and strings without their break byte
+        assert!(cbor(&[0x9f, 0x01, 0x02]).is_err());
+        assert!(cbor(&[0xbf, 0x01, 0x02]).is_err());
+        assert!(cbor(&[0x7f, 0x61, b'a']).is_err());
+        // Tag without a value
+        assert!(cbor(&[0xc1]).is_err());
+    }
+
+    #[test]
+    fn trailing_bytes() {
+        assert_eq!(msgpack(&[0x01, 0x02]).unwrap_err(), "1 trailing bytes after the value");
+        assert_eq!(cbor(&[0x01, 0x02, 0x03]).unwrap_err(), "2 trailing bytes after the value");
+    }
+
+    #[test]
+    fn maximum_depth() {
+        assert!(msgpack(&nested(0x91, 0xc0, MAX_DEPTH)).is_ok());
+        assert_eq!(msgpack(&nested(0x91, 0xc0, MAX_DEPTH + 1)).unwrap_err(), "nested deeper than 128 levels");
+        assert!(cbor(&nested(0x81, 0xf6, MAX_DEPTH)).is_ok());
+        assert_eq!(cbor(&nested(0x81, 0xf6, MAX_DEPTH + 1)).unwrap_err(), "nested deeper than 128 levels");
+        // Tags count as a level too
+        assert!(cbor(&nested(0xc1, 0xf6, MAX_DEPTH + 1)).is_err());
+        // Far deeper payloads fail the same way instead of overflowing the stack
+        assert!(msgpack(&nested(0x91, 0xc0, 100_000)).is_err());
+        assert!(cbor(&nested(0x9f, 0xf6, 100_000)).is_err());
+    }
+
+    #[test]
+    fn oversized_lengths() {
+        // array 32, map 32, str 32 and bin 32 claiming 4 GiB
+        for marker in [0xdd, 0xdf, 0xdb, 0xc6] {
+            let err = msgpack(&[marker, 0xff, 0xff, 0xff, 0xff, 0x00]).unwrap_err();
+            assert!(err.starts_with("length 4294967295 exceeds the payload"), "{:#x}: {}", marker, err);
+        }
+        assert!(msgpack(&[0xc9, 0xff, 0xff, 0xff, 0xff, 0x01, 0x00]).unwrap_err().contains("exceeds the payload"));
+        // byte and text strings claiming 2^64 - 1 bytes
+        for initial in [0x5b, 0x7b] {
+            let mut raw = vec![initial];
+            raw.extend_from_slice(&[0xff; 8]);
+            assert!(cbor(&raw).unwrap_err().contains("exceeds the payload"));
+        }
+        // Huge arrays and maps run out of bytes instead of allocating up front
+        let mut raw = vec![0x9b];
+        raw.extend_from_slice(&[0xff; 8]);
+        raw.push(0x01);
+        assert!(cbor(&raw).unwrap_err().starts_with("truncated"));
+        // Chunk of an indefinite string longer than the payload
+        assert!(cbor(&[0x5f, 0x5a, 0xff, 0xff, 0xff, 0xff, 0xff]).unwrap_err().contains("exceeds the payload"));
+    }
+
+    #[test]
+    fn malformed_markers() {
+        assert!(msgpack(&[0xc1]).unwrap_err().contains("reserved"));
+        assert!(cbor(&[0x1c]).unwrap_err().contains("reserved"));
+        assert!(cbor(&[0x1f]).unwrap_err().contains("indefinite length not allowed"));
+        assert!(cbor(&[0x5f, 0x61, b'a', 0xff]).unwrap_err().contains("invalid chunk"));
+        assert!(msgpack(&[0xa1, 0xff]).unwrap_err().contains("invalid UTF-8"));
+    }
+
+    #[test]
+    fn numbers_beyond_i64() {
+        let mut raw = vec![0x3b];
+        raw.extend_from_slice(&[0xff; 8]);
+        assert_eq!(cbor(&raw).unwrap().to_string(), "-18446744073709551616");
+        assert_eq!(half_to_f64(0x3c00), 1.0);
+        assert_eq!(half_to_f64(0xfc00), f64::NEG_INFINITY);
+        assert_eq!(float_value(f64::NAN), Value::Null);
+    }
+}
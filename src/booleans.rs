@@ -0,0 +1,53 @@
+use std::collections::HashMap;
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+/// Default of `processing.bool_true_values`.
+pub const DEFAULT_TRUE_VALUES: [&str; 10] =
+    ["true", "yes", "on", "enabled", "enable", "1", "check", "checked", "select", "selected"];
+/// Default of `processing.bool_false_values`.
+pub const DEFAULT_FALSE_VALUES: [&str; 6] = ["false", "no", "off", "disabled", "disable", "0"];
+
+/// The words forwarded as "1" and "0" (`processing.bool_true_values` and
+/// `bool_false_values`), matched after trimming and ignoring case.
+pub struct BoolTable {
+    values: HashMap<String, &'static str>,
+}
+
+impl Default for BoolTable {
+    fn default() -> Self {
+        BoolTable::new(
+            DEFAULT_TRUE_VALUES.iter().map(|v| v.to_string()).collect(),
+            DEFAULT_FALSE_VALUES.iter().map(|v| v.to_string()).collect(),
+        )
+        .expect("the default lists don't overlap")
+    }
+}
+
+impl BoolTable {
+    /// A word in both lists raises a `ValueError`.
+    pub fn new(true_values: Vec<String>, false_values: Vec<String>) -> PyResult<Self> {
+        let mut values = HashMap::new();
+        for value in true_values {
+            values.insert(value.trim().to_lowercase(), "1");
+        }
+        for value in false_values {
+            let value = value.trim().to_lowercase();
+            if values.get(&value) == Some(&"1") {
+                return Err(PyValueError::new_err(format!(
+                    "'{}' is in both bool_true_values and bool_false_values",
+                    value
+                )));
+            }
+            values.insert(value, "0");
+        }
+        values.remove("");
+        Ok(BoolTable { values })
+    }
+
+    /// "1" or "0" for a known word, given trimmed and lowercase.
+    pub fn convert(&self, normalized: &str) -> Option<&'static str> {
+        self.values.get(normalized).copied()
+    }
+}
@@ -0,0 +1,161 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+/// First bytes of a capture file, so a replay doesn't feed some other file through the pipeline.
+const MAGIC: &[u8; 8] = b"LMRCAP1\n";
+
+/// A message of a capture: when it arrived (Unix seconds), its topic and its raw payload.
+pub struct Record {
+    pub at: f64,
+    pub topic: String,
+    pub payload: Vec<u8>,
+}
+
+/// `debug.capture_file`: every incoming data message as it arrived, appended to a binary
+/// file for `replay_capture`. A record is the arrival time (f64), then the topic and the
+/// payload, each as a u32 length and the bytes, all little-endian. Recording stops with a
+/// warning once the file would grow past `max_bytes` (0: no limit).
+pub struct Capture {
+    path: PathBuf,
+    max_bytes: u64,
+    file: Mutex<File>,
+    size: AtomicU64,
+    records: AtomicU64,
+    /// Set once the file is full or a write failed, so that's only logged once
+    stopped: AtomicBool,
+}
+
+impl Capture {
+    /// Open `path` for appending, writing the header if the file is new or empty.
+    pub fn open(path: impl Into<PathBuf>, max_bytes: u64) -> PyResult<Self> {
+        let path = path.into();
+        let failed = |e: io::Error| PyValueError::new_err(format!("Can't open the capture {}: {}", path.display(), e));
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).map_err(failed)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&path).map_err(failed)?;
+        let mut size = file.metadata().map_err(failed)?.len();
+        if size == 0 {
+            file.write_all(MAGIC).map_err(failed)?;
+            size = MAGIC.len() as u64;
+        } else {
+            check_header(&path)?;
+        }
+        info!("Capturing incoming MQTT messages to {}", path.display());
+        Ok(Capture {
+            path,
+            max_bytes,
+            file: Mutex::new(file),
+            size: AtomicU64::new(size),
+            records: AtomicU64::new(0),
+            stopped: AtomicBool::new(false),
+        })
+    }
+
+    /// Append a message, in one write so a crash leaves at most the last record incomplete.
+    pub fn record(&self, topic: &str, payload: &[u8]) {
+        if self.stopped.load(Ordering::Relaxed) {
+            return;
+        }
+        let at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or_default();
+        let mut record = Vec::with_capacity(16 + topic.len() + payload.len());
+        record.extend_from_slice(&at.to_le_bytes());
+        record.extend_from_slice(&(topic.len() as u32).to_le_bytes());
+        record.extend_from_slice(topic.as_bytes());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(payload);
+        let mut file = self.file.lock().unwrap();
+        let size = self.size.load(Ordering::Relaxed) + record.len() as u64;
+        if self.max_bytes > 0 && size > self.max_bytes {
+            warn!("Capture {} reached {} bytes, not recording any more messages", self.path.display(), self.max_bytes);
+            self.stopped.store(true, Ordering::Relaxed);
+            return;
+        }
+        if let Err(e) = file.write_all(&record) {
+            warn!("Can't write to the capture {}, not recording any more messages: {}", self.path.display(), e);
+            self.stopped.store(true, Ordering::Relaxed);
+            return;
+        }
+        self.size.store(size, Ordering::Relaxed);
+        self.records.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Messages recorded since the capture was opened.
+    pub fn records(&self) -> u64 {
+        self.records.load(Ordering::Relaxed)
+    }
+}
+
+fn check_header(path: &Path) -> PyResult<()> {
+    let mut header = [0u8; MAGIC.len()];
+    let read = File::open(path).and_then(|mut file| file.read_exact(&mut header));
+    match read {
+        Ok(()) if &header == MAGIC => Ok(()),
+        Ok(()) | Err(_) => Err(PyValueError::new_err(format!("{} isn't a capture file", path.display()))),
+    }
+}
+
+/// Marks a replay as running until dropped, so a replay that is cancelled or fails
+/// still clears the flag.
+pub struct Replaying(Arc<AtomicBool>);
+
+impl Replaying {
+    /// `None` if a replay is already running.
+    pub fn start(flag: &Arc<AtomicBool>) -> Option<Self> {
+        (!flag.swap(true, Ordering::Relaxed)).then(|| Replaying(Arc::clone(flag)))
+    }
+}
+
+impl Drop for Replaying {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Read the records of a capture file. An incomplete last record, from a crash while
+/// capturing, is left out with a warning.
+pub fn read(path: &Path) -> PyResult<Vec<Record>> {
+    check_header(path)?;
+    let data = fs::read(path).map_err(|e| PyValueError::new_err(format!("Can't read the capture {}: {}", path.display(), e)))?;
+    let mut rest = &data[MAGIC.len()..];
+    let mut records = Vec::new();
+    while !rest.is_empty() {
+        match next_record(rest) {
+            Some((record, remaining)) => {
+                records.push(record);
+                rest = remaining;
+            }
+            None => {
+                warn!("Ignoring the incomplete last record of the capture {}", path.display());
+                break;
+            }
+        }
+    }
+    Ok(records)
+}
+
+fn next_record(data: &[u8]) -> Option<(Record, &[u8])> {
+    let (at, rest) = data.split_first_chunk::<8>()?;
+    let (topic, rest) = length_prefixed(rest)?;
+    let (payload, rest) = length_prefixed(rest)?;
+    let record = Record {
+        at: f64::from_le_bytes(*at),
+        topic: String::from_utf8_lossy(topic).into_owned(),
+        payload: payload.to_vec(),
+    };
+    Some((record, rest))
+}
+
+fn length_prefixed(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (len, rest) = data.split_first_chunk::<4>()?;
+    let len = u32::from_le_bytes(*len) as usize;
+    (rest.len() >= len).then(|| rest.split_at(len))
+}
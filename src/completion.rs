@@ -0,0 +1,106 @@
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+use crate::delivery::DeliveryStatus;
+
+/// What became of one value of a message handled with `handle_mqtt_message_async`.
+#[derive(Debug)]
+pub struct Delivery {
+    /// Position among the message's values, to report them in message order
+    index: usize,
+    topic: String,
+    name: String,
+    value: String,
+    status: &'static str,
+    http_code: Option<i64>,
+}
+
+impl Delivery {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "topic": self.topic,
+            "name": self.name,
+            "value": self.value,
+            "status": self.status,
+            "http_code": self.http_code,
+        })
+    }
+}
+
+/// Collects the outcomes of one message's values. Each value handed to a sender carries
+/// a `PendingDelivery`; the message is done once all of them reported or were dropped.
+pub struct Completion {
+    tx: mpsc::UnboundedSender<Delivery>,
+    rx: mpsc::UnboundedReceiver<Delivery>,
+    tracked: std::cell::Cell<usize>,
+}
+
+impl Default for Completion {
+    fn default() -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        Completion { tx, rx, tracked: std::cell::Cell::new(0) }
+    }
+}
+
+impl Completion {
+    /// Follow one value to its outcome.
+    pub fn track(&self, topic: &str, name: &str, value: &str) -> PendingDelivery {
+        let index = self.tracked.replace(self.tracked.get() + 1);
+        PendingDelivery {
+            tx: Some(self.tx.clone()),
+            index,
+            topic: topic.to_string(),
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    /// The outcomes of all values, in message order, once the last of them is known.
+    pub async fn wait(self) -> Vec<Delivery> {
+        let Completion { tx, mut rx, .. } = self;
+        drop(tx);
+        let mut deliveries = Vec::new();
+        while let Some(delivery) = rx.recv().await {
+            deliveries.push(delivery);
+        }
+        deliveries.sort_by_key(|delivery| delivery.index);
+        deliveries
+    }
+}
+
+/// A value on its way to the Miniserver. Dropped without `complete`, e.g. replaced by a
+/// newer value while rate limited, pushed out of a full send queue or aborted at
+/// shutdown, it reports `dropped`.
+#[derive(Debug)]
+pub struct PendingDelivery {
+    tx: Option<mpsc::UnboundedSender<Delivery>>,
+    index: usize,
+    topic: String,
+    name: String,
+    value: String,
+}
+
+impl PendingDelivery {
+    pub fn complete(mut self, status: DeliveryStatus, http_code: Option<i64>) {
+        self.report(status.as_str(), http_code);
+    }
+
+    fn report(&mut self, status: &'static str, http_code: Option<i64>) {
+        if let Some(tx) = self.tx.take() {
+            let _ = tx.send(Delivery {
+                index: self.index,
+                topic: std::mem::take(&mut self.topic),
+                name: std::mem::take(&mut self.name),
+                value: std::mem::take(&mut self.value),
+                status,
+                http_code,
+            });
+        }
+    }
+}
+
+impl Drop for PendingDelivery {
+    fn drop(&mut self) {
+        self.report("dropped", None);
+    }
+}
@@ -0,0 +1,19 @@
+//! Config sections that are lists of tables (`rules`, `processing.transforms`, ...).
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+/// The tables of a config list, in order. An entry that isn't a table/dict raises a
+/// `ValueError` naming it by `what` and its index, e.g. "Rule 2 must be a table/dict".
+pub fn dict_entries<'py>(entries: &Bound<'py, PyAny>, what: &str) -> PyResult<Vec<Bound<'py, PyDict>>> {
+    entries
+        .try_iter()?
+        .enumerate()
+        .map(|(index, item)| {
+            item?
+                .cast_into::<PyDict>()
+                .map_err(|_| PyValueError::new_err(format!("{} {} must be a table/dict", what, index)))
+        })
+        .collect()
+}
@@ -0,0 +1,152 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::{debug, error, info};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use serde::Deserialize;
+
+use crate::py_json;
+
+/// Changes to the file within this time are applied together, so an editor's
+/// truncate-and-write or the relay's own save cause a single reload.
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+/// The options of `config.toml` checked before the relay uses the file. Everything else
+/// is taken as it is and checked by the `AppConfig` dataclasses and `validate_config`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ConfigFile {
+    broker: BrokerFile,
+    miniserver: MiniserverFile,
+    udp: UdpFile,
+    debug: DebugFile,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct BrokerFile {
+    port: Option<i64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct MiniserverFile {
+    miniserver_port: Option<i64>,
+    failover_port: Option<i64>,
+    miniserver_discovery: bool,
+    miniserver_serial: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct UdpFile {
+    udp_in_port: Option<i64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct DebugFile {
+    prometheus_port: Option<i64>,
+}
+
+impl ConfigFile {
+    /// One message per out-of-range port or pair of options that exclude each other.
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut port = |name: &str, value: Option<i64>, zero_allowed: bool| {
+            let min = if zero_allowed { 0 } else { 1 };
+            if let Some(value) = value.filter(|v| !(min..=65535).contains(v)) {
+                problems.push(format!("{}: {} is not a port ({}-65535)", name, value, min));
+            }
+        };
+        port("broker.port", self.broker.port, false);
+        port("miniserver.miniserver_port", self.miniserver.miniserver_port, false);
+        port("miniserver.failover_port", self.miniserver.failover_port, false);
+        port("udp.udp_in_port", self.udp.udp_in_port, false);
+        port("debug.prometheus_port", self.debug.prometheus_port, true);
+        if self.miniserver.miniserver_discovery && !self.miniserver.miniserver_serial.is_empty() {
+            problems.push(
+                "miniserver: miniserver_discovery and miniserver_serial both locate the Miniserver, set only one".to_string(),
+            );
+        }
+        problems
+    }
+}
+
+/// Parse `config.toml` and return its sections as a dict. Syntax errors, options of the
+/// wrong type, ports out of range and options that exclude each other raise a
+/// `ValueError` listing every problem, so a broken file is never half applied.
+#[pyfunction]
+pub fn load_config_file<'py>(py: Python<'py>, path: &str) -> PyResult<Bound<'py, PyAny>> {
+    let text = std::fs::read_to_string(path)?;
+    let document: toml::Table = toml::from_str(&text)
+        .map_err(|e| PyValueError::new_err(format!("Invalid config file {}: {}", path, e)))?;
+    let typed = ConfigFile::deserialize(toml::Value::Table(document.clone()))
+        .map_err(|e| PyValueError::new_err(format!("Invalid config file {}: {}", path, e)))?;
+    let problems = typed.problems();
+    if !problems.is_empty() {
+        return Err(PyValueError::new_err(format!(
+            "Invalid config file {}: {}",
+            path,
+            problems.join("; ")
+        )));
+    }
+    let value = serde_json::to_value(&document)
+        .map_err(|e| PyValueError::new_err(format!("Invalid config file {}: {}", path, e)))?;
+    py_json::to_py(py, &value)
+}
+
+/// Calls `callback()` once the config file was changed, e.g. by an editor or a
+/// deployment tool. The directory is watched rather than the file, so files replaced by
+/// a rename are followed too. Changes are collected for `SETTLE_TIME` first.
+#[pyclass]
+pub struct ConfigWatcher {
+    watcher: Mutex<Option<RecommendedWatcher>>,
+}
+
+#[pymethods]
+impl ConfigWatcher {
+    #[new]
+    fn new(path: &str, callback: Py<PyAny>) -> PyResult<Self> {
+        let path = std::fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+        let file_name = path.file_name().map(|n| n.to_os_string());
+        let dir = path.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new(".")).to_path_buf();
+        let (tx, rx) = mpsc::channel::<()>();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event) if event.kind.is_access() => {}
+            Ok(event) => {
+                if event.paths.iter().any(|p| p.file_name().map(|n| n.to_os_string()) == file_name) {
+                    let _ = tx.send(());
+                }
+            }
+            Err(e) => error!("Error watching the config file: {}", e),
+        })
+        .map_err(|e| PyValueError::new_err(format!("Can't watch {}: {}", dir.display(), e)))?;
+        watcher
+            .watch(&dir, RecursiveMode::NonRecursive)
+            .map_err(|e| PyValueError::new_err(format!("Can't watch {}: {}", dir.display(), e)))?;
+        std::thread::Builder::new().name("config-watcher".into()).spawn(move || {
+            // Ends when the watcher, and with it the sender, is dropped
+            while rx.recv().is_ok() {
+                while rx.recv_timeout(SETTLE_TIME).is_ok() {}
+                debug!("Config file changed");
+                Python::attach(|py| {
+                    if let Err(e) = callback.call0(py) {
+                        error!("Error handling the config file change: {:?}", e);
+                    }
+                });
+            }
+        })?;
+        info!("Watching {} for changes", path.display());
+        Ok(ConfigWatcher { watcher: Mutex::new(Some(watcher)) })
+    }
+
+    /// Stop watching; no callback runs after this returns, apart from one already running.
+    fn stop(&self) {
+        self.watcher.lock().unwrap().take();
+    }
+}
@@ -0,0 +1,261 @@
+use pyo3::exceptions::PyValueError;
+use pyo3::intern;
+use pyo3::prelude::*;
+use pyo3::types::{PyBool, PyDict, PyFloat, PyInt, PyList, PySet, PyString, PyTuple};
+
+/// Type of a config field, read from its dataclass type hint.
+#[derive(Debug)]
+enum FieldType {
+    Any,
+    Str,
+    Int,
+    Float,
+    Bool,
+    Optional(Box<FieldType>),
+    Literal(Vec<String>),
+    List(Box<FieldType>),
+    Set(Box<FieldType>),
+    Dict(Box<FieldType>, Box<FieldType>),
+}
+
+impl FieldType {
+    fn from_hint(hint: &Bound<'_, PyAny>) -> PyResult<Self> {
+        let py = hint.py();
+        let typing = py.import(intern!(py, "typing"))?;
+        if hint.is(typing.getattr(intern!(py, "Any"))?) {
+            return Ok(FieldType::Any);
+        }
+        if hint.is(py.get_type::<PyString>()) {
+            return Ok(FieldType::Str);
+        }
+        if hint.is(py.get_type::<PyBool>()) {
+            return Ok(FieldType::Bool);
+        }
+        if hint.is(py.get_type::<PyInt>()) {
+            return Ok(FieldType::Int);
+        }
+        if hint.is(py.get_type::<PyFloat>()) {
+            return Ok(FieldType::Float);
+        }
+        let origin = typing.call_method1(intern!(py, "get_origin"), (hint,))?;
+        let args = typing.call_method1(intern!(py, "get_args"), (hint,))?.cast_into::<PyTuple>()?;
+        let arg = |i: usize| -> PyResult<Box<FieldType>> {
+            match args.get_item(i) {
+                Ok(a) => Ok(Box::new(FieldType::from_hint(&a)?)),
+                Err(_) => Ok(Box::new(FieldType::Any)),
+            }
+        };
+        if origin.is(py.get_type::<PyList>()) {
+            return Ok(FieldType::List(arg(0)?));
+        }
+        if origin.is(py.get_type::<PySet>()) {
+            return Ok(FieldType::Set(arg(0)?));
+        }
+        if origin.is(py.get_type::<PyDict>()) {
+            return Ok(FieldType::Dict(arg(0)?, arg(1)?));
+        }
+        if origin.is(typing.getattr(intern!(py, "Literal"))?) {
+            return Ok(FieldType::Literal(args.iter().map(|a| a.str().map(|s| s.to_string())).collect::<PyResult<_>>()?));
+        }
+        if origin.is(typing.getattr(intern!(py, "Union"))?) {
+            // Only Optional[T] is used in the config
+            let none_type = py.None().bind(py).get_type();
+            let inner: Vec<_> = args.iter().filter(|a| !a.is(&none_type)).collect();
+            if inner.len() == 1 {
+                return Ok(FieldType::Optional(Box::new(FieldType::from_hint(&inner[0])?)));
+            }
+        }
+        Err(PyValueError::new_err(format!("Unsupported config field type {}", hint.repr()?)))
+    }
+
+    fn name(&self) -> String {
+        match self {
+            FieldType::Any => "any value".to_string(),
+            FieldType::Str => "str".to_string(),
+            FieldType::Int => "int".to_string(),
+            FieldType::Float => "float".to_string(),
+            FieldType::Bool => "bool".to_string(),
+            FieldType::Optional(t) => format!("{} or null", t.name()),
+            FieldType::Literal(values) => format!("one of {}", values.join(", ")),
+            FieldType::List(t) | FieldType::Set(t) => format!("list of {}", t.name()),
+            FieldType::Dict(k, v) => format!("object of {} to {}", k.name(), v.name()),
+        }
+    }
+
+    fn is_collection(&self) -> bool {
+        matches!(self, FieldType::List(_) | FieldType::Set(_) | FieldType::Dict(..))
+    }
+
+    /// `value` checked against this type (ints become floats for float fields), or an error naming `path`.
+    fn check<'py>(&self, path: &str, value: &Bound<'py, PyAny>) -> PyResult<Bound<'py, PyAny>> {
+        let py = value.py();
+        let is_bool = value.is_instance_of::<PyBool>();
+        let ok = match self {
+            FieldType::Any => true,
+            FieldType::Str => value.is_instance_of::<PyString>(),
+            // True is an int in Python, but not a valid port or size
+            FieldType::Int => value.is_instance_of::<PyInt>() && !is_bool,
+            FieldType::Float => {
+                if value.is_instance_of::<PyInt>() && !is_bool {
+                    return Ok(PyFloat::new(py, value.extract::<f64>()?).into_any());
+                }
+                value.is_instance_of::<PyFloat>()
+            }
+            FieldType::Bool => is_bool,
+            FieldType::Optional(inner) => {
+                if value.is_none() {
+                    return Ok(value.clone());
+                }
+                return inner.check(path, value).map_err(|_| type_error(path, self, value));
+            }
+            FieldType::Literal(values) => {
+                value.is_instance_of::<PyString>() && values.iter().any(|v| value.eq(v).unwrap_or(false))
+            }
+            FieldType::List(item) | FieldType::Set(item) => {
+                let Ok(list) = value.cast::<PyList>() else {
+                    return Err(type_error(path, self, value));
+                };
+                let checked = PyList::empty(py);
+                for (i, v) in list.iter().enumerate() {
+                    checked.append(item.check(&format!("{}[{}]", path, i), &v)?)?;
+                }
+                return Ok(checked.into_any());
+            }
+            FieldType::Dict(key, val) => {
+                let Ok(dict) = value.cast::<PyDict>() else {
+                    return Err(type_error(path, self, value));
+                };
+                let checked = PyDict::new(py);
+                for (k, v) in dict.iter() {
+                    let k = key.check(&format!("{} key {}", path, k.repr()?), &k)?;
+                    let v = val.check(&format!("{}.{}", path, k.str()?), &v)?;
+                    checked.set_item(k, v)?;
+                }
+                return Ok(checked.into_any());
+            }
+        };
+        if ok {
+            Ok(value.clone())
+        } else {
+            Err(type_error(path, self, value))
+        }
+    }
+}
+
+fn type_error(path: &str, expected: &FieldType, value: &Bound<'_, PyAny>) -> PyErr {
+    let got = value.get_type().name().map(|n| n.to_string()).unwrap_or_else(|_| "?".to_string());
+    let shown = value.repr().map(|r| r.to_string()).unwrap_or_default();
+    PyValueError::new_err(format!("{}: expected {}, got {} {}", path, expected.name(), got, shown))
+}
+
+/// Merge `value` into the config field `field_name` (type hint `hint`, current value
+/// `current`) according to `list_mode`:
+/// - `set` replaces the value; a single item is accepted for lists and sets
+/// - `add` appends list items that aren't there yet, adds set items, merges dict keys
+/// - `remove` drops the given items from lists and sets, the given keys from dicts
+///
+/// `add` and `remove` only apply to lists, sets and dicts. The value is checked against
+/// the type hint, item by item, and a `ValueError` names the first mismatch; nothing
+/// is changed in that case.
+#[pyfunction]
+#[pyo3(signature = (field_name, hint, current, value, list_mode="set"))]
+pub fn merge_config_value<'py>(
+    field_name: &str,
+    hint: &Bound<'py, PyAny>,
+    current: &Bound<'py, PyAny>,
+    value: &Bound<'py, PyAny>,
+    list_mode: &str,
+) -> PyResult<Bound<'py, PyAny>> {
+    let py = value.py();
+    let field_type = FieldType::from_hint(hint)?;
+    if !matches!(list_mode, "set" | "add" | "remove") {
+        return Err(PyValueError::new_err(format!(
+            "Invalid update mode '{}': expected set, add or remove",
+            list_mode
+        )));
+    }
+    if list_mode != "set" && !field_type.is_collection() {
+        return Err(PyValueError::new_err(format!(
+            "{}: {} only works for lists and objects, this field is {}",
+            field_name,
+            list_mode,
+            field_type.name()
+        )));
+    }
+    match &field_type {
+        FieldType::List(_) | FieldType::Set(_) => {
+            // A single item stands for a one item list
+            let items = if value.is_instance_of::<PyList>() {
+                value.clone()
+            } else if value.is_instance_of::<PySet>() || value.is_instance_of::<PyTuple>() {
+                PyList::new(py, value.try_iter()?.collect::<PyResult<Vec<_>>>()?)?.into_any()
+            } else {
+                PyList::new(py, [value])?.into_any()
+            };
+            let items = field_type.check(field_name, &items)?.cast_into::<PyList>()?;
+            let merged = match list_mode {
+                "set" => items,
+                "add" => {
+                    let merged = PyList::new(py, current.try_iter()?.collect::<PyResult<Vec<_>>>()?)?;
+                    for item in items.iter() {
+                        if !merged.contains(&item)? {
+                            merged.append(item)?;
+                        }
+                    }
+                    merged
+                }
+                _ => {
+                    let kept = PyList::empty(py);
+                    for item in current.try_iter()? {
+                        let item = item?;
+                        if !items.contains(&item)? {
+                            kept.append(item)?;
+                        }
+                    }
+                    kept
+                }
+            };
+            if matches!(field_type, FieldType::Set(_)) {
+                // Sets are stored as lists when loaded from TOML; keep whichever the field holds
+                if current.is_instance_of::<PySet>() {
+                    return Ok(PySet::new(py, merged.iter())?.into_any());
+                }
+                let unique = PyList::empty(py);
+                for item in merged.iter() {
+                    if !unique.contains(&item)? {
+                        unique.append(item)?;
+                    }
+                }
+                return Ok(unique.into_any());
+            }
+            Ok(merged.into_any())
+        }
+        FieldType::Dict(..) => match list_mode {
+            "set" => field_type.check(field_name, value),
+            "add" => {
+                let additions = field_type.check(field_name, value)?.cast_into::<PyDict>()?;
+                let merged = current.cast::<PyDict>()?.copy()?;
+                merged.update(additions.as_mapping())?;
+                Ok(merged.into_any())
+            }
+            _ => {
+                // Keys to remove: a list of keys, one key, or an object whose keys are removed
+                let keys: Vec<Bound<'py, PyAny>> = if let Ok(dict) = value.cast::<PyDict>() {
+                    dict.keys().iter().collect()
+                } else if let Ok(list) = value.cast::<PyList>() {
+                    list.iter().collect()
+                } else {
+                    vec![value.clone()]
+                };
+                let merged = current.cast::<PyDict>()?.copy()?;
+                for key in keys {
+                    if merged.contains(&key)? {
+                        merged.del_item(&key)?;
+                    }
+                }
+                Ok(merged.into_any())
+            }
+        },
+        _ => field_type.check(field_name, value),
+    }
+}
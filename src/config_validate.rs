@@ -0,0 +1,308 @@
+use std::time::Duration;
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+
+use crate::batch::BatchMode;
+use crate::binary_payload::parse_payload_formats;
+use crate::booleans::BoolTable;
+use crate::decode::Utf8Policy;
+use crate::derived::parse_derived;
+use crate::extract::parse_extractions;
+use crate::flatten::FlattenOptions;
+use crate::filters::{compile_filters_checked, FilterAnchor, FilterPolicy, FilterSyntax};
+use crate::lox_states::parse_state_mappings;
+use crate::outbox::Replay;
+use crate::plugins::parse_plugins;
+use crate::rate_limit::parse_rate_limits;
+use crate::retry::RetryPolicy;
+use crate::rewrites::parse_rewrites;
+use crate::rules::{parse_rules, Transport};
+use crate::scripting::parse_scripts;
+use crate::send_queue::Overflow;
+use crate::targets::parse_targets;
+use crate::templates::parse_templates;
+use crate::timestamps::parse_timestamp_rules;
+use crate::tls::TlsOptions;
+use crate::transforms::parse_transforms;
+use crate::udp_forwarder::UdpForwarder;
+use crate::vi_names::NameNormalizer;
+
+/// How long a host may take to accept a TCP connection during validation.
+const HOST_CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+macro_rules! field {
+    ($config:expr, $section:literal, $field:literal) => {
+        $config
+            .getattr(pyo3::intern!($config.py(), $section))
+            .and_then(|s| s.getattr(pyo3::intern!($config.py(), $field)))
+    };
+}
+
+/// Run everything the processor parses at startup against `config` (an `AppConfig` or
+/// the global config) and return one message per problem. Filters and rules are checked
+/// as with `strict_filters`, so bad regexes are reported even where they'd be skipped.
+#[pyfunction]
+pub fn validate_config(config: &Bound<'_, PyAny>) -> Vec<String> {
+    let mut errors = Vec::new();
+    let mut check = |name: &str, result: PyResult<()>| {
+        if let Err(e) = result {
+            errors.push(format!("{}: {}", name, e.value(config.py())));
+        }
+    };
+    check("topics.filter_anchor", (|| {
+        FilterAnchor::parse(&field!(config, "topics", "filter_anchor")?.extract::<String>()?).map(drop)
+    })());
+    check("topics.filter_syntax", (|| {
+        FilterSyntax::parse(&field!(config, "topics", "filter_syntax")?.extract::<String>()?).map(drop)
+    })());
+    // Filters are checked as regexes if the syntax itself is invalid
+    let syntax = field!(config, "topics", "filter_syntax")
+        .and_then(|s| s.extract::<String>())
+        .and_then(|s| FilterSyntax::parse(&s))
+        .unwrap_or_default();
+    check("topics.policy", (|| {
+        FilterPolicy::parse(&field!(config, "topics", "policy")?.extract::<String>()?).map(drop)
+    })());
+    check("processing.invalid_utf8", (|| {
+        Utf8Policy::parse(&field!(config, "processing", "invalid_utf8")?.extract::<String>()?).map(drop)
+    })());
+    check("miniserver.send_queue_overflow", (|| {
+        Overflow::parse(&field!(config, "miniserver", "send_queue_overflow")?.extract::<String>()?).map(drop)
+    })());
+    check("processing.bool_true_values", (|| {
+        BoolTable::new(
+            field!(config, "processing", "bool_true_values")?.extract()?,
+            field!(config, "processing", "bool_false_values")?.extract()?,
+        )
+        .map(drop)
+    })());
+    check("miniserver.batch_mode", (|| {
+        BatchMode::parse(&field!(config, "miniserver", "batch_mode")?.extract::<String>()?).map(drop)
+    })());
+    check("miniserver.outbox_replay", (|| {
+        Replay::parse(&field!(config, "miniserver", "outbox_replay")?.extract::<String>()?).map(drop)
+    })());
+    check("miniserver.send_retries", (|| {
+        RetryPolicy::new(
+            field!(config, "miniserver", "send_retries")?.extract()?,
+            field!(config, "miniserver", "send_retry_backoff")?.extract()?,
+            field!(config, "miniserver", "send_retry_max_backoff")?.extract()?,
+            field!(config, "miniserver", "send_retry_jitter")?.extract()?,
+        )
+        .map(drop)
+    })());
+    check("processing.flatten_arrays", (|| {
+        FlattenOptions::parse(
+            &field!(config, "processing", "flatten_separator")?.extract::<String>()?,
+            field!(config, "processing", "flatten_max_depth")?.extract()?,
+            &field!(config, "processing", "flatten_arrays")?.extract::<String>()?,
+        )
+        .map(drop)
+    })());
+    check("topics.subscription_filters", (|| {
+        // The anchor only wraps each pattern, so it can't make a valid one invalid
+        compile_filters_checked(field!(config, "topics", "subscription_filters")?.extract()?, FilterAnchor::None, syntax, true)
+            .map(drop)
+    })());
+    check("topics.post_expansion_filters", (|| {
+        compile_filters_checked(field!(config, "topics", "post_expansion_filters")?.extract()?, FilterAnchor::None, syntax, true)
+            .map(drop)
+    })());
+    check("processing.bool_exclude_topics", (|| {
+        compile_filters_checked(field!(config, "processing", "bool_exclude_topics")?.extract()?, FilterAnchor::None, syntax, true)
+            .map(drop)
+    })());
+    check("processing.numeric_topics", (|| {
+        compile_filters_checked(field!(config, "processing", "numeric_topics")?.extract()?, FilterAnchor::None, syntax, true)
+            .map(drop)
+    })());
+    check("processing.decompress_topics", (|| {
+        compile_filters_checked(field!(config, "processing", "decompress_topics")?.extract()?, FilterAnchor::None, syntax, true)
+            .map(drop)
+    })());
+    check("processing.xml_topics", (|| {
+        compile_filters_checked(field!(config, "processing", "xml_topics")?.extract()?, FilterAnchor::None, syntax, true)
+            .map(drop)
+    })());
+    check("processing.key_value_topics", (|| {
+        compile_filters_checked(field!(config, "processing", "key_value_topics")?.extract()?, FilterAnchor::None, syntax, true)
+            .map(drop)
+    })());
+    check("processing.influx_topics", (|| {
+        compile_filters_checked(field!(config, "processing", "influx_topics")?.extract()?, FilterAnchor::None, syntax, true)
+            .map(drop)
+    })());
+    check("topics.do_not_forward", (|| {
+        compile_filters_checked(field!(config, "topics", "do_not_forward")?.extract()?, FilterAnchor::None, syntax, true).map(drop)
+    })());
+    check("topics.monitor_topics", (|| {
+        compile_filters_checked(field!(config, "topics", "monitor_topics")?.extract()?, FilterAnchor::None, syntax, true).map(drop)
+    })());
+    check("topics.rules", (|| {
+        let destinations: Vec<String> = field!(config, "udp", "udp_out_destinations")?.extract()?;
+        parse_rules(&field!(config, "topics", "rules")?, true)?.check_udp_destinations(!destinations.is_empty())
+    })());
+    check("miniserver.default_transport", (|| {
+        let transport = Transport::parse(&field!(config, "miniserver", "default_transport")?.extract::<String>()?)?;
+        let destinations: Vec<String> = field!(config, "udp", "udp_out_destinations")?.extract()?;
+        if transport == Transport::Udp && destinations.is_empty() {
+            return Err(PyValueError::new_err("default_transport is 'udp', but udp.udp_out_destinations is empty"));
+        }
+        Ok(())
+    })());
+    check("udp.udp_out_destinations", (|| {
+        UdpForwarder::new(&field!(config, "udp", "udp_out_destinations")?.extract::<Vec<String>>()?).map(drop)
+    })());
+    check("miniserver.health_check_interval", (|| {
+        let interval: f64 = field!(config, "miniserver", "health_check_interval")?.extract()?;
+        if interval > 0.0 {
+            Ok(())
+        } else {
+            Err(PyValueError::new_err(format!("{} is not a positive number of seconds", interval)))
+        }
+    })());
+    check("broker.tls", (|| {
+        if !field!(config, "broker", "tls")?.extract::<bool>()? {
+            return Ok(());
+        }
+        TlsOptions {
+            ca_file: field!(config, "broker", "tls_ca_file")?.extract()?,
+            cert_file: field!(config, "broker", "tls_cert_file")?.extract()?,
+            key_file: field!(config, "broker", "tls_key_file")?.extract()?,
+            insecure: field!(config, "broker", "tls_insecure")?.extract()?,
+        }
+        .client_config()
+        .map(drop)
+    })());
+    check("miniserver.miniserver_tls", (|| {
+        if !field!(config, "miniserver", "miniserver_tls")?.extract::<bool>()? {
+            return Ok(());
+        }
+        TlsOptions {
+            ca_file: field!(config, "miniserver", "miniserver_tls_ca_file")?.extract()?,
+            cert_file: field!(config, "miniserver", "miniserver_tls_cert_file")?.extract()?,
+            key_file: field!(config, "miniserver", "miniserver_tls_key_file")?.extract()?,
+            insecure: field!(config, "miniserver", "miniserver_tls_insecure")?.extract()?,
+        }
+        .client_config()
+        .map(drop)
+    })());
+    check("miniserver.routes", (|| {
+        parse_targets(&field!(config, "miniserver", "targets")?, &field!(config, "miniserver", "routes")?, 1).map(drop)
+    })());
+    check("processing.rate_limit", (|| parse_rate_limits(&field!(config, "processing", "rate_limit")?, true).map(drop))());
+    check("miniserver.state_mappings", (|| parse_state_mappings(&field!(config, "miniserver", "state_mappings")?).map(drop))());
+    check("processing.payload_formats", (|| {
+        parse_payload_formats(&field!(config, "processing", "payload_formats")?, true).map(drop)
+    })());
+    check("processing.extract", (|| parse_extractions(&field!(config, "processing", "extract")?, true).map(drop))());
+    check("processing.transforms", (|| parse_transforms(&field!(config, "processing", "transforms")?).map(drop))());
+    check("processing.derived", (|| parse_derived(&field!(config, "processing", "derived")?).map(drop))());
+    check("processing.timestamps", (|| parse_timestamp_rules(&field!(config, "processing", "timestamps")?).map(drop))());
+    check("topics.rewrites", (|| parse_rewrites(&field!(config, "topics", "rewrites")?, true).map(drop))());
+    check("topics.name_replacement", (|| {
+        NameNormalizer::new(
+            &field!(config, "topics", "name_replacement")?.extract::<String>()?,
+            &field!(config, "topics", "name_replace_chars")?.extract::<String>()?,
+            field!(config, "topics", "name_lowercase")?.extract()?,
+        )
+        .map(drop)
+    })());
+    check("processing.scripts", (|| {
+        parse_scripts(
+            &field!(config, "processing", "scripts")?,
+            field!(config, "processing", "script_max_operations")?.extract()?,
+            field!(config, "processing", "script_timeout_ms")?.extract()?,
+        )
+        .map(drop)
+    })());
+    check("processing.plugins", (|| {
+        parse_plugins(
+            &field!(config, "processing", "plugins")?,
+            field!(config, "processing", "plugin_fuel")?.extract()?,
+        )
+        .map(drop)
+    })());
+    check("debug.prometheus_port", field!(config, "debug", "prometheus_port").and_then(|port| port.extract::<u16>()).map(drop));
+    check("processing.max_json_depth", field!(config, "processing", "max_json_depth").and_then(|depth| depth.extract::<usize>()).map(drop));
+    check("processing.max_json_keys", field!(config, "processing", "max_json_keys").and_then(|keys| keys.extract::<usize>()).map(drop));
+    check("processing.stale_timeout", (|| {
+        let timeout: f64 = field!(config, "processing", "stale_timeout")?.extract()?;
+        if !(0.0..=Duration::MAX.as_secs_f64()).contains(&timeout) {
+            return Err(PyValueError::new_err(format!("stale_timeout must be 0 or a positive number of seconds, not {}", timeout)));
+        }
+        field!(config, "processing", "stale_value")?.extract::<String>()?;
+        compile_filters_checked(field!(config, "processing", "stale_topics")?.extract()?, FilterAnchor::None, syntax, true).map(drop)
+    })());
+    check("debug.trace_messages", field!(config, "debug", "trace_messages").and_then(|size| size.extract::<usize>()).map(drop));
+    check("debug.capture_max_bytes", field!(config, "debug", "capture_max_bytes").and_then(|size| size.extract::<u64>()).map(drop));
+    check("processing.payload_templates", (|| {
+        // Only the template syntax is checked, the flatten options have their own entry
+        parse_templates(field!(config, "processing", "payload_templates")?.extract()?, FlattenOptions::default()).map(drop)
+    })());
+    errors
+}
+
+/// Split an error of `validate_config` or of merging an update into the field it is about
+/// and the reason, e.g. `("topics.do_not_forward", "Invalid filter patterns: ...")`. An
+/// index stays with the reason (`subscriptions[1]: expected str` -> `[1]: expected str`).
+/// `None` if the error doesn't start with a field.
+pub fn error_field(error: &str) -> (Option<String>, String) {
+    if let Some(field) = error.strip_prefix("Unknown configuration field: ") {
+        return (Some(field.trim().to_string()), "unknown configuration field".to_string());
+    }
+    match error.split_once(": ") {
+        Some((path, reason)) if !path.is_empty() && path.chars().all(|c| c.is_ascii_alphanumeric() || "_.[]".contains(c)) => {
+            let field = path.split('[').next().unwrap_or(path);
+            let reason = match &path[field.len()..] {
+                "" => reason.to_string(),
+                index => format!("{}: {}", index, reason),
+            };
+            (Some(field.to_string()), reason)
+        }
+        _ => (None, error.to_string()),
+    }
+}
+
+/// Broker and Miniserver `(name, host, port)` of `config`, for `check_hosts`.
+pub fn hosts(config: &Bound<'_, PyAny>) -> PyResult<Vec<(&'static str, String, u16)>> {
+    Ok(vec![
+        (
+            "broker",
+            field!(config, "broker", "host")?.extract()?,
+            field!(config, "broker", "port")?.extract()?,
+        ),
+        (
+            "miniserver",
+            field!(config, "miniserver", "miniserver_ip")?.extract()?,
+            field!(config, "miniserver", "miniserver_port")?.extract()?,
+        ),
+    ])
+}
+
+/// Try a TCP connection to each host; `{name: "reachable"}` or the error per host,
+/// plus one error message per unreachable host.
+pub async fn check_hosts(hosts: Vec<(&'static str, String, u16)>) -> (Value, Vec<String>) {
+    let mut results = serde_json::Map::new();
+    let mut errors = Vec::new();
+    for (name, host, port) in hosts {
+        let outcome = match tokio::time::timeout(HOST_CHECK_TIMEOUT, TcpStream::connect((host.as_str(), port))).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err(format!("no answer within {}s", HOST_CHECK_TIMEOUT.as_secs())),
+        };
+        match outcome {
+            Ok(()) => {
+                results.insert(name.to_string(), json!("reachable"));
+            }
+            Err(e) => {
+                errors.push(format!("{}: {}:{} is unreachable: {}", name, host, port, e));
+                results.insert(name.to_string(), json!(e));
+            }
+        }
+    }
+    (Value::Object(results), errors)
+}
@@ -0,0 +1,136 @@
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+/// Connectivity of one link: the MQTT broker or the Miniserver.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinkState {
+    /// Not connected yet, or reconnecting
+    Connecting,
+    Connected,
+    /// Reachable, but requests fail or are deferred (e.g. the Miniserver is busy)
+    Degraded,
+    /// Unreachable
+    Offline,
+}
+
+impl LinkState {
+    fn as_str(self) -> &'static str {
+        match self {
+            LinkState::Connecting => "connecting",
+            LinkState::Connected => "connected",
+            LinkState::Degraded => "degraded",
+            LinkState::Offline => "offline",
+        }
+    }
+
+    /// Whether the other end answers: connected or degraded.
+    pub fn reachable(self) -> bool {
+        matches!(self, LinkState::Connected | LinkState::Degraded)
+    }
+
+    pub fn parse(state: &str) -> PyResult<Self> {
+        match state {
+            "connecting" => Ok(LinkState::Connecting),
+            "connected" => Ok(LinkState::Connected),
+            "degraded" => Ok(LinkState::Degraded),
+            "offline" => Ok(LinkState::Offline),
+            _ => Err(PyValueError::new_err(format!(
+                "Invalid connection state '{}': expected connecting, connected, degraded or offline",
+                state
+            ))),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Link {
+    state: LinkState,
+    /// When the current state was entered
+    since: f64,
+    previous: Option<LinkState>,
+    transitions: u64,
+    last_error: Option<String>,
+}
+
+impl Link {
+    fn new(at: f64) -> Self {
+        Link { state: LinkState::Connecting, since: at, previous: None, transitions: 0, last_error: None }
+    }
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default()
+}
+
+/// State of the broker and Miniserver links. The Python side reports what it observes,
+/// this keeps the current state per link with the time and number of transitions.
+pub struct ConnectionStates {
+    broker: Mutex<Link>,
+    miniserver: Mutex<Link>,
+}
+
+impl Default for ConnectionStates {
+    fn default() -> Self {
+        let at = now();
+        ConnectionStates { broker: Mutex::new(Link::new(at)), miniserver: Mutex::new(Link::new(at)) }
+    }
+}
+
+impl ConnectionStates {
+    fn link(&self, name: &str) -> PyResult<&Mutex<Link>> {
+        match name {
+            "broker" => Ok(&self.broker),
+            "miniserver" => Ok(&self.miniserver),
+            _ => Err(PyValueError::new_err(format!(
+                "Invalid connection '{}': expected broker or miniserver",
+                name
+            ))),
+        }
+    }
+
+    /// Move `name` to `state`; the previous state, or `None` if it already was in that state.
+    /// `error` is kept as the last error, it isn't cleared by later transitions.
+    pub fn transition(&self, name: &str, state: &str, error: Option<String>) -> PyResult<Option<LinkState>> {
+        let state = LinkState::parse(state)?;
+        let mut link = self.link(name)?.lock().unwrap();
+        if error.is_some() {
+            link.last_error = error;
+        }
+        if link.state == state {
+            return Ok(None);
+        }
+        let previous = link.state;
+        link.previous = Some(previous);
+        link.state = state;
+        link.since = now();
+        link.transitions += 1;
+        Ok(Some(previous))
+    }
+
+    /// `{link: {state, since, previous, transitions, last_error}}` for the broker and the Miniserver.
+    pub fn to_py<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        // Copied before creating Python objects, see LastValueStore::to_py
+        let snapshot = [
+            ("broker", self.broker.lock().unwrap().clone()),
+            ("miniserver", self.miniserver.lock().unwrap().clone()),
+        ];
+        let out = PyDict::new(py);
+        for (name, link) in snapshot {
+            let entry = PyDict::new(py);
+            entry.set_item("state", link.state.as_str())?;
+            entry.set_item("since", link.since)?;
+            entry.set_item("previous", link.previous.map(LinkState::as_str))?;
+            entry.set_item("transitions", link.transitions)?;
+            entry.set_item("last_error", link.last_error)?;
+            out.set_item(name, entry)?;
+        }
+        Ok(out)
+    }
+}
@@ -0,0 +1,510 @@
+//! The primitives the Loxone websocket handshake needs: SHA-1/SHA-256 with HMAC (from
+//! `ring`), AES-256-CBC encryption and RSA PKCS#1 v1.5 encryption with the Miniserver's
+//! public key. Only the directions the client uses are implemented.
+//!
+//! AES-CBC and RSA are written out here because no crate providing them is available; they
+//! are only used for the Miniserver token handshake and the encrypted commands of that
+//! session (`lox_ws`), plus SHA-1 for the websocket upgrade. Not for anything else.
+
+use base64::{engine::general_purpose, Engine};
+use ring::{digest, hmac};
+
+fn invalid(message: &str) -> String {
+    format!("invalid public key: {}", message)
+}
+
+/// Fill `buf` from the operating system's random source.
+pub fn random_bytes(buf: &mut [u8]) -> Result<(), String> {
+    getrandom::fill(buf).map_err(|e| format!("no random source: {}", e))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashAlg {
+    Sha1,
+    Sha256,
+}
+
+impl HashAlg {
+    /// The `hashAlg` of a getkey2 response; the Miniserver defaults to SHA1.
+    pub fn parse(name: &str) -> Self {
+        if name.eq_ignore_ascii_case("SHA256") {
+            HashAlg::Sha256
+        } else {
+            HashAlg::Sha1
+        }
+    }
+
+    pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+        digest::digest(self.digest_algorithm(), data).as_ref().to_vec()
+    }
+
+    pub fn hmac(&self, key: &[u8], data: &[u8]) -> Vec<u8> {
+        let algorithm = match self {
+            HashAlg::Sha1 => hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+            HashAlg::Sha256 => hmac::HMAC_SHA256,
+        };
+        hmac::sign(&hmac::Key::new(algorithm, key), data).as_ref().to_vec()
+    }
+
+    fn digest_algorithm(&self) -> &'static digest::Algorithm {
+        match self {
+            HashAlg::Sha1 => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+            HashAlg::Sha256 => &digest::SHA256,
+        }
+    }
+}
+
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut out = [0u8; 20];
+    out.copy_from_slice(digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, data).as_ref());
+    out
+}
+
+pub fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// Multiplication in GF(2^8) without branches or lookups on the operands.
+const fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0u8;
+    let mut i = 0;
+    while i < 8 {
+        product ^= a & (b & 1).wrapping_neg();
+        a = (a << 1) ^ (0x1b & (a >> 7).wrapping_neg());
+        b >>= 1;
+        i += 1;
+    }
+    product
+}
+
+/// The AES S-box, computed instead of looked up in a table, so its timing doesn't depend
+/// on the key or the data: the inverse in GF(2^8) as x^254 (0 stays 0), then the affine map.
+fn sub_byte(x: u8) -> u8 {
+    let square = |v: u8| gf_mul(v, v);
+    let x2 = square(x);
+    let x3 = gf_mul(x2, x);
+    let x12 = square(square(x3));
+    let x15 = gf_mul(x12, x3);
+    let x240 = square(square(square(square(x15))));
+    let b = gf_mul(gf_mul(x240, x12), x2);
+    b ^ b.rotate_left(1) ^ b.rotate_left(2) ^ b.rotate_left(3) ^ b.rotate_left(4) ^ 0x63
+}
+
+/// AES-256 with the key schedule expanded once. No table lookups indexed by key or data,
+/// see `sub_byte`.
+pub struct Aes256 {
+    round_keys: [[u8; 16]; 15],
+}
+
+impl Aes256 {
+    pub fn new(key: &[u8; 32]) -> Self {
+        let mut words = [[0u8; 4]; 60];
+        for (i, word) in words.iter_mut().take(8).enumerate() {
+            word.copy_from_slice(&key[4 * i..4 * i + 4]);
+        }
+        let mut rcon = 1u8;
+        for i in 8..60 {
+            let mut temp = words[i - 1];
+            if i % 8 == 0 {
+                temp = [sub_byte(temp[1]) ^ rcon, sub_byte(temp[2]), sub_byte(temp[3]), sub_byte(temp[0])];
+                rcon = gf_mul(rcon, 2);
+            } else if i % 8 == 4 {
+                temp = temp.map(sub_byte);
+            }
+            for j in 0..4 {
+                words[i][j] = words[i - 8][j] ^ temp[j];
+            }
+        }
+        let mut round_keys = [[0u8; 16]; 15];
+        for (round, round_key) in round_keys.iter_mut().enumerate() {
+            for column in 0..4 {
+                round_key[4 * column..4 * column + 4].copy_from_slice(&words[4 * round + column]);
+            }
+        }
+        Aes256 { round_keys }
+    }
+
+    pub fn encrypt_block(&self, block: &mut [u8; 16]) {
+        let add_round_key = |block: &mut [u8; 16], key: &[u8; 16]| block.iter_mut().zip(key).for_each(|(b, k)| *b ^= k);
+        add_round_key(block, &self.round_keys[0]);
+        for round in 1..15 {
+            let mut state = [0u8; 16];
+            // SubBytes and ShiftRows: row r moves r columns to the left
+            for column in 0..4 {
+                for row in 0..4 {
+                    state[row + 4 * column] = sub_byte(block[row + 4 * ((column + row) % 4)]);
+                }
+            }
+            if round < 14 {
+                for column in state.chunks_mut(4) {
+                    let [a0, a1, a2, a3] = [column[0], column[1], column[2], column[3]];
+                    column[0] = gf_mul(a0, 2) ^ gf_mul(a1, 3) ^ a2 ^ a3;
+                    column[1] = a0 ^ gf_mul(a1, 2) ^ gf_mul(a2, 3) ^ a3;
+                    column[2] = a0 ^ a1 ^ gf_mul(a2, 2) ^ gf_mul(a3, 3);
+                    column[3] = gf_mul(a0, 3) ^ a1 ^ a2 ^ gf_mul(a3, 2);
+                }
+            }
+            *block = state;
+            add_round_key(block, &self.round_keys[round]);
+        }
+    }
+
+    /// CBC encryption of `data`, which must be a multiple of 16 bytes long.
+    pub fn encrypt_cbc(&self, iv: &[u8; 16], data: &[u8]) -> Vec<u8> {
+        debug_assert!(data.len().is_multiple_of(16));
+        let mut previous = *iv;
+        let mut out = Vec::with_capacity(data.len());
+        for chunk in data.chunks(16) {
+            let mut block = [0u8; 16];
+            for (i, byte) in chunk.iter().enumerate() {
+                block[i] = byte ^ previous[i];
+            }
+            self.encrypt_block(&mut block);
+            out.extend_from_slice(&block);
+            previous = block;
+        }
+        out
+    }
+}
+
+/// Unsigned big integer, little-endian 32 bit limbs without leading zero limbs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct BigUint(Vec<u32>);
+
+impl BigUint {
+    fn from_be_bytes(bytes: &[u8]) -> Self {
+        let mut limbs: Vec<u32> = bytes
+            .rchunks(4)
+            .map(|chunk| chunk.iter().fold(0u32, |acc, &b| (acc << 8) | u32::from(b)))
+            .collect();
+        while limbs.last() == Some(&0) {
+            limbs.pop();
+        }
+        BigUint(limbs)
+    }
+
+    fn to_be_bytes(&self, len: usize) -> Vec<u8> {
+        let mut out = vec![0u8; len];
+        for (i, limb) in self.0.iter().enumerate() {
+            for (j, byte) in limb.to_le_bytes().iter().enumerate() {
+                if let Some(at) = len.checked_sub(4 * i + j + 1) {
+                    out[at] = *byte;
+                }
+            }
+        }
+        out
+    }
+
+    fn bits(&self) -> usize {
+        self.0.last().map_or(0, |top| 32 * self.0.len() - top.leading_zeros() as usize)
+    }
+
+    fn bit(&self, i: usize) -> bool {
+        self.0.get(i / 32).is_some_and(|limb| limb >> (i % 32) & 1 != 0)
+    }
+
+    fn mul(&self, other: &BigUint) -> BigUint {
+        let mut out = vec![0u32; self.0.len() + other.0.len()];
+        for (i, &a) in self.0.iter().enumerate() {
+            let mut carry = 0u64;
+            for (j, &b) in other.0.iter().enumerate() {
+                let t = u64::from(a) * u64::from(b) + u64::from(out[i + j]) + carry;
+                out[i + j] = t as u32;
+                carry = t >> 32;
+            }
+            out[i + other.0.len()] = carry as u32;
+        }
+        while out.last() == Some(&0) {
+            out.pop();
+        }
+        BigUint(out)
+    }
+
+    /// `self mod modulus` by binary long division; fast enough for one handshake.
+    fn rem(&self, modulus: &BigUint) -> BigUint {
+        let limbs = modulus.0.len() + 1;
+        let mut remainder = vec![0u32; limbs];
+        for i in (0..self.bits()).rev() {
+            // remainder = remainder * 2 + bit
+            let mut carry = u32::from(self.bit(i));
+            for limb in remainder.iter_mut() {
+                let next = *limb >> 31;
+                *limb = (*limb << 1) | carry;
+                carry = next;
+            }
+            if !less_than(&remainder, &modulus.0) {
+                let mut borrow = 0i64;
+                for (j, limb) in remainder.iter_mut().enumerate() {
+                    let t = i64::from(*limb) - i64::from(modulus.0.get(j).copied().unwrap_or(0)) - borrow;
+                    *limb = t as u32;
+                    borrow = i64::from(t < 0);
+                }
+            }
+        }
+        while remainder.last() == Some(&0) {
+            remainder.pop();
+        }
+        BigUint(remainder)
+    }
+
+    fn modpow(&self, exponent: &BigUint, modulus: &BigUint) -> BigUint {
+        let base = self.rem(modulus);
+        let mut result = BigUint(vec![1]);
+        for i in (0..exponent.bits()).rev() {
+            result = result.mul(&result).rem(modulus);
+            if exponent.bit(i) {
+                result = result.mul(&base).rem(modulus);
+            }
+        }
+        result
+    }
+}
+
+/// `a < b` for little-endian limbs of any length.
+fn less_than(a: &[u32], b: &[u32]) -> bool {
+    for i in (0..a.len().max(b.len())).rev() {
+        let (x, y) = (a.get(i).copied().unwrap_or(0), b.get(i).copied().unwrap_or(0));
+        if x != y {
+            return x < y;
+        }
+    }
+    false
+}
+
+/// One DER element: its tag and contents, and the rest of the input.
+fn der_element(input: &[u8]) -> Result<(u8, &[u8], &[u8]), String> {
+    let (&tag, rest) = input.split_first().ok_or_else(|| invalid("truncated"))?;
+    let (&first, rest) = rest.split_first().ok_or_else(|| invalid("truncated"))?;
+    let (len, rest) = if first & 0x80 == 0 {
+        (usize::from(first), rest)
+    } else {
+        let count = usize::from(first & 0x7f);
+        if count == 0 || count > 4 || rest.len() < count {
+            return Err(invalid("bad length"));
+        }
+        (rest[..count].iter().fold(0usize, |acc, &b| (acc << 8) | usize::from(b)), &rest[count..])
+    };
+    if rest.len() < len {
+        return Err(invalid("truncated"));
+    }
+    Ok((tag, &rest[..len], &rest[len..]))
+}
+
+const DER_INTEGER: u8 = 0x02;
+const DER_BIT_STRING: u8 = 0x03;
+const DER_SEQUENCE: u8 = 0x30;
+
+pub struct RsaPublicKey {
+    modulus: BigUint,
+    exponent: BigUint,
+    /// Length of the modulus in bytes
+    size: usize,
+}
+
+impl RsaPublicKey {
+    /// Parse the key of `jdev/sys/getPublicKey`: PEM, with or without line breaks, of a
+    /// SubjectPublicKeyInfo (the Miniserver labels it CERTIFICATE) or a PKCS#1 RSAPublicKey.
+    pub fn from_pem(pem: &str) -> Result<Self, String> {
+        // The labels sit between "-----" markers, the base64 body outside of them
+        let body: String = pem.split("-----").step_by(2).collect::<String>().split_whitespace().collect();
+        let der = general_purpose::STANDARD.decode(body).map_err(|e| invalid(&e.to_string()))?;
+        let (tag, contents, _) = der_element(&der)?;
+        if tag != DER_SEQUENCE {
+            return Err(invalid("not a sequence"));
+        }
+        let (first_tag, _, rest) = der_element(contents)?;
+        let rsa_key = match first_tag {
+            // SubjectPublicKeyInfo: algorithm, then the key as bit string
+            DER_SEQUENCE => {
+                let (tag, bits, _) = der_element(rest)?;
+                if tag != DER_BIT_STRING || bits.first() != Some(&0) {
+                    return Err(invalid("no key bit string"));
+                }
+                let (tag, key, _) = der_element(&bits[1..])?;
+                if tag != DER_SEQUENCE {
+                    return Err(invalid("not an RSA key"));
+                }
+                key
+            }
+            DER_INTEGER => contents,
+            _ => return Err(invalid("unknown format")),
+        };
+        let (tag, modulus, rest) = der_element(rsa_key)?;
+        let (exponent_tag, exponent, _) = der_element(rest)?;
+        if tag != DER_INTEGER || exponent_tag != DER_INTEGER {
+            return Err(invalid("not an RSA key"));
+        }
+        let modulus = BigUint::from_be_bytes(modulus);
+        let size = modulus.bits().div_ceil(8);
+        if size < 64 {
+            return Err(invalid("modulus too small"));
+        }
+        Ok(RsaPublicKey { modulus, exponent: BigUint::from_be_bytes(exponent), size })
+    }
+
+    /// RSAES-PKCS1-v1_5 encryption of `message`.
+    pub fn encrypt(&self, message: &[u8]) -> Result<Vec<u8>, String> {
+        if message.len() + 11 > self.size {
+            return Err("message too long for the RSA key".to_string());
+        }
+        // 0x00 0x02, at least eight non-zero random bytes, 0x00, message
+        let mut padded = vec![0u8, 2];
+        let mut padding = vec![0u8; self.size - message.len() - 3];
+        random_bytes(&mut padding)?;
+        for byte in padding.iter_mut() {
+            while *byte == 0 {
+                let mut replacement = [0u8];
+                random_bytes(&mut replacement)?;
+                *byte = replacement[0];
+            }
+        }
+        padded.extend_from_slice(&padding);
+        padded.push(0);
+        padded.extend_from_slice(message);
+        let encrypted = BigUint::from_be_bytes(&padded).modpow(&self.exponent, &self.modulus);
+        Ok(encrypted.to_be_bytes(self.size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUBLIC_KEY_SPKI: &str = "-----BEGIN PUBLIC KEY-----
+MFwwDQYJKoZIhvcNAQEBBQADSwAwSAJBAOyj5f2t7KjquvjHMrc5JnosDUZN66X2
+UNV1VQ6WS+ZhclM0kmL031ReLhLOdXKi0n7Cu0f5ouLqG00Ti0L2kzkCAwEAAQ==
+-----END PUBLIC KEY-----";
+    const PUBLIC_KEY_PKCS1: &str = "-----BEGIN RSA PUBLIC KEY-----
+MEgCQQDso+X9reyo6rr4xzK3OSZ6LA1GTeul9lDVdVUOlkvmYXJTNJJi9N9UXi4S
+znVyotJ+wrtH+aLi6htNE4tC9pM5AgMBAAE=
+-----END RSA PUBLIC KEY-----";
+    const MODULUS: &str = "eca3e5fdadeca8eabaf8c732b739267a2c0d464deba5f650d575550e964be6617253349262f4df545e2e12ce7572a2d27ec2bb47f9a2e2ea1b4d138b42f69339";
+    const PRIVATE_EXPONENT: &str = "1e3c9b089d0e9e4ecff135d2bf22a84df80f022ab6af9017a80ec3fd872acf96e90cab89cbadfda73b7442b2cc3ef15141663c1ead2b43928efe2fe7fb0458e1";
+
+    fn big(hex: &str) -> BigUint {
+        BigUint::from_be_bytes(&from_hex(hex).unwrap())
+    }
+
+    #[test]
+    fn aes256_cbc_matches_sp800_38a() {
+        // NIST SP 800-38A, F.2.5 CBC-AES256.Encrypt
+        let key: [u8; 32] = from_hex("603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4").unwrap().try_into().unwrap();
+        let iv: [u8; 16] = from_hex("000102030405060708090a0b0c0d0e0f").unwrap().try_into().unwrap();
+        let plain = from_hex(concat!(
+            "6bc1bee22e409f96e93d7e117393172a",
+            "ae2d8a571e03ac9c9eb76fac45af8e51",
+            "30c81c46a35ce411e5fbc1191a0a52ef",
+            "f69f2445df4f9b17ad2b417be66c3710",
+        ))
+        .unwrap();
+        let expected = concat!(
+            "f58c4c04d6e5f1ba779eabfb5f7bfbd6",
+            "9cfc4e967edb808d679f777bc6702c7d",
+            "39f23369a9d9bacfa530e26304231461",
+            "b2eb05e2c39be9fcda6c19078c6a9d1b",
+        );
+        assert_eq!(hex(&Aes256::new(&key).encrypt_cbc(&iv, &plain)), expected);
+    }
+
+    #[test]
+    fn sub_byte_matches_known_sbox_entries() {
+        for (input, output) in [(0x00, 0x63), (0x01, 0x7c), (0x53, 0xed), (0xff, 0x16)] {
+            assert_eq!(sub_byte(input), output);
+        }
+    }
+
+    #[test]
+    fn hashes_and_hmacs_match_known_answers() {
+        assert_eq!(hex(&sha1(b"abc")), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(hex(&HashAlg::Sha1.digest(b"abc")), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(
+            hex(&HashAlg::Sha256.digest(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        // RFC 2202 and RFC 4231, test case 2
+        assert_eq!(hex(&HashAlg::Sha1.hmac(b"Jefe", b"what do ya want for nothing?")), "effcdf6ae5eb2fa2d27416d5f184df9c259a7c79");
+        assert_eq!(
+            hex(&HashAlg::Sha256.hmac(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn modpow_matches_known_answer() {
+        let message = big("00020102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f202122232425262728292a2b2c2d2e2f300030313233343536373839616263");
+        let cipher = message.modpow(&BigUint::from_be_bytes(&[1, 0, 1]), &big(MODULUS));
+        assert_eq!(
+            hex(&cipher.to_be_bytes(64)),
+            "36a6e5b4a3d46cf4974236b8d74275f118619681af50eafc0a834cd96b4600216a023c40da39c1631a4251e256833b18a68bfba0cd0d4ab7f1cf642a9981d7dd"
+        );
+        assert_eq!(cipher.modpow(&big(PRIVATE_EXPONENT), &big(MODULUS)), message.rem(&big(MODULUS)));
+    }
+
+    #[test]
+    fn rsa_encrypt_decrypts_with_the_private_key() {
+        for pem in [PUBLIC_KEY_SPKI, PUBLIC_KEY_PKCS1] {
+            let key = RsaPublicKey::from_pem(pem).unwrap();
+            assert_eq!(key.size, 64);
+            assert_eq!(key.modulus, big(MODULUS));
+            let message = b"0123456789abcdef:fedcba9876543210";
+            let cipher = key.encrypt(message).unwrap();
+            assert_eq!(cipher.len(), 64);
+            let padded = BigUint::from_be_bytes(&cipher).modpow(&big(PRIVATE_EXPONENT), &key.modulus).to_be_bytes(64);
+            let separator = 64 - message.len() - 1;
+            assert_eq!(&padded[..2], &[0, 2]);
+            assert!(padded[2..separator].iter().all(|&b| b != 0));
+            assert_eq!(padded[separator], 0);
+            assert_eq!(&padded[separator + 1..], message);
+        }
+    }
+
+    #[test]
+    fn rsa_encrypt_rejects_long_messages() {
+        let key = RsaPublicKey::from_pem(PUBLIC_KEY_SPKI).unwrap();
+        assert!(key.encrypt(&[b'x'; 53]).is_ok());
+        assert!(key.encrypt(&[b'x'; 54]).is_err());
+    }
+
+    #[test]
+    fn der_element_reads_short_and_long_lengths() {
+        assert_eq!(der_element(&[0x02, 0x01, 0x05, 0xff]).unwrap(), (0x02, &[0x05][..], &[0xff][..]));
+        let mut long = vec![0x04, 0x82, 0x01, 0x00];
+        long.extend_from_slice(&[7; 256]);
+        let (tag, contents, rest) = der_element(&long).unwrap();
+        assert_eq!((tag, contents.len(), rest.len()), (0x04, 256, 0));
+    }
+
+    #[test]
+    fn der_element_rejects_truncated_input() {
+        assert!(der_element(&[]).is_err());
+        assert!(der_element(&[0x30]).is_err());
+        // Length larger than what follows
+        assert!(der_element(&[0x02, 0x03, 0x01, 0x02]).is_err());
+        assert!(der_element(&[0x04, 0x82, 0x01, 0x00, 0x00]).is_err());
+        // Long form with fewer length bytes than announced
+        assert!(der_element(&[0x04, 0x82, 0x01]).is_err());
+    }
+
+    #[test]
+    fn der_element_rejects_bad_long_form_lengths() {
+        // Indefinite length, and more length bytes than fit a sane key
+        assert!(der_element(&[0x30, 0x80, 0x00, 0x00]).is_err());
+        assert!(der_element(&[0x04, 0x85, 0x01, 0x00, 0x00, 0x00, 0x00]).is_err());
+        assert!(der_element(&[0x04, 0x84, 0xff, 0xff, 0xff, 0xff]).is_err());
+    }
+
+    #[test]
+    fn from_pem_rejects_malformed_keys() {
+        assert!(RsaPublicKey::from_pem("-----BEGIN PUBLIC KEY-----\n!!!\n-----END PUBLIC KEY-----").is_err());
+        // A truncated SubjectPublicKeyInfo
+        let truncated = &PUBLIC_KEY_SPKI.replace("UNV1VQ6WS+ZhclM0kmL031ReLhLOdXKi0n7Cu0f5ouLqG00Ti0L2kzkCAwEAAQ==", "");
+        assert!(RsaPublicKey::from_pem(truncated).is_err());
+    }
+}
@@ -0,0 +1,52 @@
+use base64::{engine::general_purpose, Engine};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+/// What happens to topics and payloads that aren't valid UTF-8.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Utf8Policy {
+    /// Payloads become `[base64:...]` so the exact bytes are preserved; topics are
+    /// decoded like `Replace`, since they must stay usable as topics.
+    #[default]
+    Base64,
+    /// Like `Base64`, but the payload becomes `[hex:...]` (lowercase), which is easier
+    /// to take apart in a Loxone formula or read in a log.
+    Hex,
+    /// Invalid sequences become U+FFFD (and `_` in topics).
+    Replace,
+    /// The message is dropped.
+    Drop,
+}
+
+impl Utf8Policy {
+    pub fn parse(value: &str) -> PyResult<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "" | "base64" => Ok(Utf8Policy::Base64),
+            "hex" => Ok(Utf8Policy::Hex),
+            "replace" | "lossy" => Ok(Utf8Policy::Replace),
+            "drop" => Ok(Utf8Policy::Drop),
+            other => Err(PyValueError::new_err(format!(
+                "Invalid invalid_utf8 '{}': expected 'base64', 'hex', 'replace' or 'drop'",
+                other
+            ))),
+        }
+    }
+
+    /// Text for a payload that isn't valid UTF-8, or `None` if the message is dropped.
+    pub fn decode_binary(&self, raw: &[u8]) -> Option<String> {
+        match self {
+            Utf8Policy::Base64 => Some(format!("[base64:{}]", general_purpose::STANDARD.encode(raw))),
+            Utf8Policy::Hex => {
+                let mut text = String::with_capacity(raw.len() * 2 + 6);
+                text.push_str("[hex:");
+                for byte in raw {
+                    text.push_str(&format!("{:02x}", byte));
+                }
+                text.push(']');
+                Some(text)
+            }
+            Utf8Policy::Replace => Some(String::from_utf8_lossy(raw).into_owned()),
+            Utf8Policy::Drop => None,
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use lru::LruCache;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeliveryStatus {
+    /// Handed to the sender, no result yet
+    Pending,
+    /// The Miniserver answered with 200
+    Delivered,
+    /// Error status, timeout or connection error
+    Failed,
+    /// Held in the sender's offline queue
+    Queued,
+    /// The sender finished without reporting a result
+    Unconfirmed,
+    /// Held back while forwarding is paused, sent on resume
+    Paused,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::Failed => "failed",
+            DeliveryStatus::Queued => "queued",
+            DeliveryStatus::Unconfirmed => "unconfirmed",
+            DeliveryStatus::Paused => "paused",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DeliveryRecord {
+    pub id: u64,
+    pub topic: String,
+    pub value: String,
+    pub status: DeliveryStatus,
+    pub code: Option<i64>,
+    pub sent_at: f64,
+    pub completed_at: Option<f64>,
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default()
+}
+
+/// Outcome of one send as reported by the Python sender: `{"code": <status>}`,
+/// `{"queued": True}` or nothing.
+pub fn outcome_from_result(result: &Bound<'_, PyAny>) -> (DeliveryStatus, Option<i64>) {
+    let Ok(dict) = result.cast::<PyDict>() else {
+        return (DeliveryStatus::Unconfirmed, None);
+    };
+    if let Ok(Some(queued)) = dict.get_item("queued") {
+        if queued.is_truthy().unwrap_or(false) {
+            return (DeliveryStatus::Queued, None);
+        }
+    }
+    match dict.get_item("code").ok().flatten().and_then(|c| c.extract::<i64>().ok()) {
+        Some(200) => (DeliveryStatus::Delivered, Some(200)),
+        Some(code) => (DeliveryStatus::Failed, Some(code)),
+        None => (DeliveryStatus::Unconfirmed, None),
+    }
+}
+
+/// The last value forwarded per virtual input, with the id of the send and its outcome.
+///
+/// Every forwarded value gets a new id; a result only updates the record if no newer
+/// value was sent to the same input in the meantime.
+pub struct LastValueStore {
+    next_id: AtomicU64,
+    records: Mutex<LruCache<String, DeliveryRecord>>,
+}
+
+impl LastValueStore {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        LastValueStore {
+            next_id: AtomicU64::new(1),
+            records: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    /// Record a value about to be sent to `name` and return its id.
+    pub fn begin(&self, name: &str, topic: &str, value: &str) -> u64 {
+        self.insert(name, topic, value, DeliveryStatus::Pending)
+    }
+
+    /// Record a value that is held back instead of sent.
+    pub fn hold(&self, name: &str, topic: &str, value: &str) {
+        self.insert(name, topic, value, DeliveryStatus::Paused);
+    }
+
+    /// Source topics of the recorded values, most recently sent first.
+    pub fn topics(&self) -> Vec<String> {
+        self.records.lock().unwrap().iter().map(|(_, record)| record.topic.clone()).collect()
+    }
+
+    /// Virtual inputs with a record.
+    pub fn len(&self) -> usize {
+        self.records.lock().unwrap().len()
+    }
+
+    /// Virtual inputs whose latest value is held back.
+    pub fn held_count(&self) -> usize {
+        self.records.lock().unwrap().iter().filter(|(_, record)| record.status == DeliveryStatus::Paused).count()
+    }
+
+    /// (name, topic, value) of every input whose latest value is held back, oldest first.
+    pub fn held(&self) -> Vec<(String, String, String)> {
+        let records = self.records.lock().unwrap();
+        let mut held: Vec<(String, String, String)> = records
+            .iter()
+            .filter(|(_, record)| record.status == DeliveryStatus::Paused)
+            .map(|(name, record)| (name.clone(), record.topic.clone(), record.value.clone()))
+            .collect();
+        // The cache iterates most recently used first
+        held.reverse();
+        held
+    }
+
+    fn insert(&self, name: &str, topic: &str, value: &str, status: DeliveryStatus) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.records.lock().unwrap().put(
+            name.to_string(),
+            DeliveryRecord {
+                id,
+                topic: topic.to_string(),
+                value: value.to_string(),
+                status,
+                code: None,
+                sent_at: now(),
+                completed_at: None,
+            },
+        );
+        id
+    }
+
+    /// Whether send `id` is still the latest value for `name`.
+    pub fn is_latest(&self, name: &str, id: u64) -> bool {
+        self.records.lock().unwrap().peek(name).is_some_and(|record| record.id == id)
+    }
+
+    pub fn complete(&self, name: &str, id: u64, status: DeliveryStatus, code: Option<i64>) {
+        let mut records = self.records.lock().unwrap();
+        if let Some(record) = records.peek_mut(name) {
+            if record.id == id {
+                record.status = status;
+                record.code = code;
+                record.completed_at = Some(now());
+            }
+        }
+    }
+
+    /// (topic, value) of the last value sent to `name`, if it was sent after `at` (Unix
+    /// seconds) and delivered.
+    pub fn delivered_after(&self, name: &str, at: f64) -> Option<(String, String)> {
+        let records = self.records.lock().unwrap();
+        records
+            .peek(name)
+            .filter(|record| record.status == DeliveryStatus::Delivered && record.sent_at > at)
+            .map(|record| (record.topic.clone(), record.value.clone()))
+    }
+
+    /// Whether `value` is the last value sent to `name` and was delivered less than
+    /// `window` seconds ago.
+    pub fn recently_delivered(&self, name: &str, value: &str, window: f64) -> bool {
+        let records = self.records.lock().unwrap();
+        records.peek(name).is_some_and(|record| {
+            record.status == DeliveryStatus::Delivered
+                && record.value == value
+                && record.completed_at.is_some_and(|done| now() - done < window)
+        })
+    }
+
+    /// Whether `value` is the last value sent to `name` and that send didn't fail, for
+    /// `forward_only_changes`. With a `max_age` (seconds, 0 for none) an unchanged value
+    /// counts as new again once the last send is that old.
+    pub fn unchanged(&self, name: &str, value: &str, max_age: f64) -> bool {
+        let records = self.records.lock().unwrap();
+        records.peek(name).is_some_and(|record| {
+            record.value == value
+                && !matches!(record.status, DeliveryStatus::Failed | DeliveryStatus::Paused)
+                && (max_age <= 0.0 || now() - record.sent_at < max_age)
+        })
+    }
+
+    /// Virtual input name -> record dict, for the inputs matching `filter` (name or
+    /// source topic) or all of them.
+    pub fn to_py<'py>(&self, py: Python<'py>, filter: Option<&str>) -> PyResult<Bound<'py, PyDict>> {
+        // Copy first: no lock may be held while creating Python objects (a GC run on a
+        // free-threaded build would wait for threads blocked on it)
+        let records: Vec<(String, DeliveryRecord)> = self
+            .records
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(name, record)| filter.is_none_or(|f| f == name.as_str() || f == record.topic))
+            .map(|(name, record)| (name.clone(), record.clone()))
+            .collect();
+        let out = PyDict::new(py);
+        for (name, record) in records {
+            let entry = PyDict::new(py);
+            entry.set_item("id", record.id)?;
+            entry.set_item("topic", &record.topic)?;
+            entry.set_item("value", &record.value)?;
+            entry.set_item("status", record.status.as_str())?;
+            entry.set_item("code", record.code)?;
+            entry.set_item("sent_at", record.sent_at)?;
+            entry.set_item("completed_at", record.completed_at)?;
+            entry.set_item(
+                "latency_ms",
+                record.completed_at.map(|done| ((done - record.sent_at) * 1000.0).round()),
+            )?;
+            out.set_item(name, entry)?;
+        }
+        Ok(out)
+    }
+}
@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use log::{debug, warn};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList};
+use rhai::{Engine, Scope, AST};
+
+use crate::config_entries::dict_entries;
+
+/// How a derived topic is computed from its sources.
+enum Aggregate {
+    Sum,
+    Avg,
+    Min,
+    Max,
+    /// A Rhai expression over the source variables, e.g. `flow - ret`
+    Expression(String, AST),
+}
+
+struct Derivation {
+    /// The computed topic, forwarded like a received one
+    topic: String,
+    /// Variable name and topic of each source; the names are only used by expressions
+    sources: Vec<(String, String)>,
+    aggregate: Aggregate,
+    /// Decimal places of the result; unrounded if unset
+    precision: Option<usize>,
+}
+
+/// `processing.derived`: topics computed from the latest values of other topics, e.g.
+/// the total power of three Shelly channels. A derived topic is recomputed whenever one of
+/// its sources gets a numeric value, once all of them have one.
+pub struct DerivedTopics {
+    engine: Engine,
+    derivations: Vec<Derivation>,
+    /// Source topic -> the derivations using it
+    by_source: HashMap<String, Vec<usize>>,
+    /// The latest numeric value of each source topic
+    latest: Mutex<HashMap<String, f64>>,
+}
+
+impl Default for DerivedTopics {
+    fn default() -> Self {
+        DerivedTopics { engine: engine(), derivations: Vec::new(), by_source: HashMap::new(), latest: Mutex::default() }
+    }
+}
+
+fn engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(10_000);
+    engine.set_max_expr_depths(32, 16);
+    engine
+}
+
+impl DerivedTopics {
+    /// Take over the source values `previous` has seen, so an update of the list doesn't
+    /// wait for every source to come in again.
+    pub fn keep_values(&self, previous: &DerivedTopics) {
+        let previous = previous.latest.lock().unwrap().clone();
+        let mut latest = self.latest.lock().unwrap();
+        latest.extend(previous.into_iter().filter(|(topic, _)| self.by_source.contains_key(topic)));
+    }
+
+    /// Record the values among `values` that are sources and return the `(topic, value)`
+    /// pairs of the derived topics that changed because of them. Without `live` (for
+    /// `explain`) nothing is recorded.
+    pub fn update(&self, values: &[(String, String)], live: bool) -> Vec<(String, String)> {
+        if self.derivations.is_empty() {
+            return Vec::new();
+        }
+        let mut affected: Vec<usize> = Vec::new();
+        let mut updates: Vec<(&str, f64)> = Vec::new();
+        for (topic, value) in values {
+            let Some(indexes) = self.by_source.get(topic) else { continue };
+            match value.trim().parse::<f64>() {
+                Ok(number) if number.is_finite() => {
+                    updates.push((topic, number));
+                    affected.extend(indexes);
+                }
+                _ => debug!("Ignoring non-numeric value {}={} for derived topics", topic, value),
+            }
+        }
+        if affected.is_empty() {
+            return Vec::new();
+        }
+        affected.sort_unstable();
+        affected.dedup();
+        let mut latest = self.latest.lock().unwrap();
+        let mut overlay;
+        let latest = if live {
+            &mut *latest
+        } else {
+            overlay = latest.clone();
+            &mut overlay
+        };
+        for (topic, number) in updates {
+            latest.insert(topic.to_string(), number);
+        }
+        affected
+            .into_iter()
+            .filter_map(|index| {
+                let derivation = &self.derivations[index];
+                let inputs: Option<Vec<f64>> = derivation.sources.iter().map(|(_, topic)| latest.get(topic).copied()).collect();
+                let Some(inputs) = inputs else {
+                    debug!("Derived topic '{}' waits for all of its sources", derivation.topic);
+                    return None;
+                };
+                let result = self.compute(derivation, &inputs)?;
+                debug!("Derived {}={} from {:?}", derivation.topic, result, inputs);
+                Some((derivation.topic.clone(), match derivation.precision {
+                    Some(precision) => format!("{:.*}", precision, result),
+                    None => result.to_string(),
+                }))
+            })
+            .collect()
+    }
+
+    fn compute(&self, derivation: &Derivation, inputs: &[f64]) -> Option<f64> {
+        let result = match &derivation.aggregate {
+            Aggregate::Sum => inputs.iter().sum(),
+            Aggregate::Avg => inputs.iter().sum::<f64>() / inputs.len() as f64,
+            Aggregate::Min => inputs.iter().copied().fold(f64::INFINITY, f64::min),
+            Aggregate::Max => inputs.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            Aggregate::Expression(source, ast) => {
+                let mut scope = Scope::new();
+                for ((name, _), value) in derivation.sources.iter().zip(inputs) {
+                    scope.push(name.clone(), *value);
+                }
+                let result = match self.engine.eval_ast_with_scope::<rhai::Dynamic>(&mut scope, ast) {
+                    Ok(result) => result,
+                    Err(e) => {
+                        warn!("Expression '{}' of derived topic '{}' failed: {}", source, derivation.topic, e);
+                        return None;
+                    }
+                };
+                match result.as_float().or_else(|_| result.as_int().map(|int| int as f64)) {
+                    Ok(number) => number,
+                    Err(kind) => {
+                        warn!("Expression '{}' of derived topic '{}' returned a {}, not a number", source, derivation.topic, kind);
+                        return None;
+                    }
+                }
+            }
+        };
+        result.is_finite().then_some(result)
+    }
+
+    /// Convert the derived topics back into the dict form they were configured with.
+    pub fn to_py<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyList>> {
+        let list = PyList::empty(py);
+        for derivation in &self.derivations {
+            let dict = PyDict::new(py);
+            dict.set_item("topic", &derivation.topic)?;
+            match &derivation.aggregate {
+                Aggregate::Expression(source, _) => {
+                    let sources = PyDict::new(py);
+                    for (name, topic) in &derivation.sources {
+                        sources.set_item(name, topic)?;
+                    }
+                    dict.set_item("sources", sources)?;
+                    dict.set_item("expression", source)?;
+                }
+                aggregate => {
+                    dict.set_item("sources", derivation.sources.iter().map(|(_, topic)| topic).collect::<Vec<_>>())?;
+                    dict.set_item("op", match aggregate {
+                        Aggregate::Sum => "sum",
+                        Aggregate::Avg => "avg",
+                        Aggregate::Min => "min",
+                        _ => "max",
+                    })?;
+                }
+            }
+            if let Some(precision) = derivation.precision {
+                dict.set_item("precision", precision)?;
+            }
+            list.append(dict)?;
+        }
+        Ok(list)
+    }
+}
+
+/// Build `DerivedTopics` from a list of `{"topic", "sources": [topics], "op": "sum" | "avg" |
+/// "min" | "max", "precision"}` or `{"topic", "sources": {name: topic}, "expression", "precision"}`
+/// dicts. Any invalid entry raises a `ValueError`.
+pub fn parse_derived(entries: &Bound<'_, PyAny>) -> PyResult<DerivedTopics> {
+    let mut derived = DerivedTopics::default();
+    for (index, entry) in dict_entries(entries, "Derived topic")?.iter().enumerate() {
+        let what = format!("Derived topic {}", index);
+        let get = |key: &str| -> PyResult<Option<Bound<'_, PyAny>>> { Ok(entry.get_item(key)?.filter(|v| !v.is_none())) };
+        let topic: String = get("topic")?
+            .ok_or_else(|| PyValueError::new_err(format!("{} is missing the 'topic'", what)))?
+            .extract()?;
+        let sources_item = get("sources")?.ok_or_else(|| PyValueError::new_err(format!("{} is missing the 'sources'", what)))?;
+        let sources: Vec<(String, String)> = match sources_item.cast::<PyDict>() {
+            Ok(named) => named.iter().map(|(name, topic)| Ok((name.extract()?, topic.extract()?))).collect::<PyResult<_>>()?,
+            Err(_) => sources_item
+                .try_iter()?
+                .enumerate()
+                .map(|(i, topic)| Ok((format!("v{}", i), topic?.extract()?)))
+                .collect::<PyResult<_>>()?,
+        };
+        if sources.is_empty() {
+            return Err(PyValueError::new_err(format!("{} has no sources", what)));
+        }
+        if sources.iter().any(|(_, source)| *source == topic) {
+            return Err(PyValueError::new_err(format!("{} '{}' is one of its own sources", what, topic)));
+        }
+        let aggregate = match (get("op")?, get("expression")?) {
+            (Some(_), Some(_)) => return Err(PyValueError::new_err(format!("{} has both an 'op' and an 'expression'", what))),
+            (None, Some(expression)) => {
+                let source: String = expression.extract()?;
+                let ast = derived
+                    .engine
+                    .compile_expression(&source)
+                    .map_err(|e| PyValueError::new_err(format!("{} has the invalid expression '{}': {}", what, source, e)))?;
+                Aggregate::Expression(source, ast)
+            }
+            (op, None) => match op.map(|op| op.extract::<String>()).transpose()?.as_deref().map(str::trim) {
+                Some("sum") | None => Aggregate::Sum,
+                Some("avg") | Some("average") => Aggregate::Avg,
+                Some("min") => Aggregate::Min,
+                Some("max") => Aggregate::Max,
+                Some(other) => {
+                    return Err(PyValueError::new_err(format!(
+                        "{} has the invalid op '{}': expected 'sum', 'avg', 'min' or 'max'",
+                        what, other
+                    )))
+                }
+            },
+        };
+        let precision = get("precision")?.map(|v| v.extract()).transpose()?;
+        for (_, source) in &sources {
+            derived.by_source.entry(source.clone()).or_default().push(derived.derivations.len());
+        }
+        derived.derivations.push(Derivation { topic, sources, aggregate, precision });
+    }
+    debug!("Configured {} derived topics", derived.derivations.len());
+    Ok(derived)
+}
@@ -0,0 +1,107 @@
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList};
+use regex::Regex;
+use serde_json::Value;
+
+use log::{debug, error};
+
+use crate::config_entries::dict_entries;
+use crate::flatten::{flatten_value, FlattenOptions};
+
+/// Picks named fields out of the JSON payloads of matching topics
+/// (`processing.extract`), instead of flattening the whole payload.
+#[derive(Clone, Debug)]
+struct Extraction {
+    source: String,
+    pattern: Regex,
+    /// Sub-topic name and JSON pointer of each field
+    fields: Vec<(String, String)>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ExtractionSet {
+    extractions: Vec<Extraction>,
+}
+
+impl ExtractionSet {
+    /// Index of the first extraction whose pattern matches `topic`.
+    pub fn find(&self, topic: &str) -> Option<usize> {
+        self.extractions.iter().position(|e| e.pattern.is_match(topic))
+    }
+
+    /// `topic/<name>` and value for each field present in `json`. Objects and arrays are
+    /// flattened below their name with `options`; missing fields are left out.
+    pub fn extract(&self, index: usize, topic: &str, json: &Value, options: &FlattenOptions) -> Vec<(String, String)> {
+        let extraction = &self.extractions[index];
+        let mut values = Vec::with_capacity(extraction.fields.len());
+        for (name, pointer) in &extraction.fields {
+            let key = format!("{}/{}", topic, name);
+            match json.pointer(pointer) {
+                Some(value) => flatten_value(key, value, options, 1, &mut values),
+                None => debug!("Field '{}' not in the payload of '{}' ('{}')", name, topic, extraction.source),
+            }
+        }
+        values
+    }
+}
+
+/// `a.b.0` as the JSON pointer `/a/b/0`; pointers (`/a/b/0`) are kept.
+fn to_pointer(path: &str) -> String {
+    if path.starts_with('/') {
+        return path.to_string();
+    }
+    path.split('.').map(|key| format!("/{}", key.replace('~', "~0").replace('/', "~1"))).collect()
+}
+
+/// Build an `ExtractionSet` from a list of `{"pattern": ..., "fields": ...}` dicts, where
+/// `fields` maps sub-topic names to paths or lists paths (named after the path).
+///
+/// Malformed entries always raise a `ValueError`; invalid regexes are dropped with an
+/// error log unless `strict` is set.
+pub fn parse_extractions(entries: &Bound<'_, PyAny>, strict: bool) -> PyResult<ExtractionSet> {
+    let mut extractions = Vec::new();
+    let mut invalid = Vec::new();
+    for (index, entry) in dict_entries(entries, "Extraction")?.iter().enumerate() {
+        let (Some(source), Some(fields)) = (entry.get_item("pattern")?, entry.get_item("fields")?) else {
+            return Err(PyValueError::new_err(format!(
+                "Extraction {} needs both 'pattern' and 'fields'",
+                index
+            )));
+        };
+        let source: String = source.extract()?;
+        let fields: Vec<(String, String)> = if let Ok(named) = fields.cast::<PyDict>() {
+            named.iter().map(|(name, path)| Ok((name.extract()?, path.extract()?))).collect::<PyResult<_>>()?
+        } else if fields.is_instance_of::<PyList>() {
+            fields
+                .extract::<Vec<String>>()?
+                .into_iter()
+                .map(|path| (path.trim_start_matches('/').replace('.', "/"), path))
+                .collect()
+        } else {
+            return Err(PyValueError::new_err(format!(
+                "Extraction {}: 'fields' must be a list of paths or a table of name = path",
+                index
+            )));
+        };
+        if let Some((name, _)) = fields.iter().find(|(name, path)| name.is_empty() || path.is_empty()) {
+            return Err(PyValueError::new_err(format!("Extraction {} has an empty field name or path ('{}')", index, name)));
+        }
+        let fields = fields.into_iter().map(|(name, path)| (name, to_pointer(&path))).collect();
+        match Regex::new(&source) {
+            Ok(pattern) => extractions.push(Extraction { source, pattern, fields }),
+            Err(e) => {
+                error!("Invalid extraction pattern '{}': {}", source, e);
+                invalid.push(format!("'{}': {}", source, e));
+            }
+        }
+    }
+    if strict && !invalid.is_empty() {
+        return Err(PyValueError::new_err(format!(
+            "Invalid extraction patterns: {}",
+            invalid.join(", ")
+        )));
+    }
+    debug!("Compiled {} extractions", extractions.len());
+    Ok(ExtractionSet { extractions })
+}
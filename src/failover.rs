@@ -0,0 +1,132 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+use tokio_rustls::rustls::ClientConfig;
+
+use crate::http_sender::get_once;
+
+/// Which Miniserver values go to, as published to `{base_topic}miniserver/availability`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Availability {
+    Primary,
+    Secondary,
+    /// The Miniserver in use fails its checks and there's none to switch to
+    Unavailable,
+}
+
+impl Availability {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Availability::Primary => "primary",
+            Availability::Secondary => "secondary",
+            Availability::Unavailable => "unavailable",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Health {
+    on_secondary: bool,
+    /// Checks of the primary in a row that failed, or that passed
+    primary_failures: u32,
+    primary_successes: u32,
+    primary_up: Option<bool>,
+    secondary_up: Option<bool>,
+    availability: Option<Availability>,
+    since: f64,
+}
+
+/// `miniserver.failover_ip`: the secondary Miniserver forwarding switches to once the
+/// primary failed `threshold` health checks in a row, and back from once the primary
+/// passed as many again.
+pub struct Failover {
+    pub secondary: (String, u16),
+    pub interval: Duration,
+    threshold: u32,
+    /// `miniserver.miniserver_tls`, for the checks of both
+    tls: Option<Arc<ClientConfig>>,
+    health: Mutex<Health>,
+    stopped: AtomicBool,
+}
+
+impl Failover {
+    /// `None` without a `failover_ip`.
+    pub fn new(host: String, port: u16, interval: f64, threshold: u32, tls: Option<Arc<ClientConfig>>) -> Option<Self> {
+        (!host.is_empty()).then(|| Failover {
+            secondary: (host, port),
+            interval: Duration::from_secs_f64(interval.max(0.1)),
+            threshold: threshold.max(1),
+            tls,
+            health: Mutex::new(Health::default()),
+            stopped: AtomicBool::new(false),
+        })
+    }
+
+    /// Record the outcome of one round of checks; the new availability if it changed.
+    pub fn record(&self, primary_up: bool, secondary_up: bool) -> Option<Availability> {
+        let mut health = self.health.lock().unwrap();
+        if primary_up {
+            health.primary_failures = 0;
+            health.primary_successes += 1;
+        } else {
+            health.primary_successes = 0;
+            health.primary_failures += 1;
+        }
+        health.primary_up = Some(primary_up);
+        health.secondary_up = Some(secondary_up);
+        let primary_down = health.primary_failures >= self.threshold;
+        let primary_back = health.primary_successes >= self.threshold;
+        health.on_secondary = match health.on_secondary {
+            false => primary_down && secondary_up,
+            // With the secondary gone as well, the primary gets the values as soon as it answers
+            true => !(primary_back || (primary_up && !secondary_up)),
+        };
+        let availability = match health.on_secondary {
+            false if primary_down => Availability::Unavailable,
+            false => Availability::Primary,
+            true if !secondary_up => Availability::Unavailable,
+            true => Availability::Secondary,
+        };
+        if health.availability == Some(availability) {
+            return None;
+        }
+        health.availability = Some(availability);
+        health.since = now();
+        Some(availability)
+    }
+
+    /// Whether values go to the secondary.
+    pub fn on_secondary(&self) -> bool {
+        self.health.lock().unwrap().on_secondary
+    }
+
+    /// `{state, since, primary, secondary}` with `true`/`false`/`null` for each host's last check.
+    pub fn snapshot(&self) -> Value {
+        let health = self.health.lock().unwrap();
+        json!({
+            "state": health.availability.map(Availability::as_str),
+            "since": (health.availability.is_some()).then_some(health.since),
+            "primary": health.primary_up,
+            "secondary": health.secondary_up,
+        })
+    }
+
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    pub fn stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    /// Whether the Miniserver at `host:port` answers `jdev/cfg/api`, which needs no login.
+    pub async fn check(&self, host: &str, port: u16) -> bool {
+        matches!(get_once(host, port, "/jdev/cfg/api", self.tls.as_ref()).await, Ok((200, _)))
+    }
+}
+
+fn now() -> f64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or_default()
+}
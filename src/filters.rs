@@ -0,0 +1,340 @@
+use std::collections::{HashMap, HashSet};
+use std::str::Split;
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use regex::Regex;
+
+use log::{debug, error, warn};
+
+/// How filter patterns are anchored against the topic before matching.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FilterAnchor {
+    /// Plain regex semantics: the pattern may match anywhere in the topic.
+    #[default]
+    None,
+    /// The pattern must match at the start of the topic (`^` is prepended).
+    Start,
+    /// The pattern must match the whole topic.
+    Full,
+}
+
+impl FilterAnchor {
+    pub fn parse(value: &str) -> PyResult<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "" | "none" => Ok(FilterAnchor::None),
+            "start" => Ok(FilterAnchor::Start),
+            "full" => Ok(FilterAnchor::Full),
+            other => Err(PyValueError::new_err(format!(
+                "Invalid filter_anchor '{}': expected 'none', 'start' or 'full'",
+                other
+            ))),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FilterAnchor::None => "none",
+            FilterAnchor::Start => "start",
+            FilterAnchor::Full => "full",
+        }
+    }
+
+    /// Rewrite an already validated pattern so it honours the anchor mode.
+    fn apply(&self, pattern: &str) -> String {
+        match self {
+            FilterAnchor::None => pattern.to_string(),
+            // Grouped, so every alternative of `a|b` is anchored
+            FilterAnchor::Start => format!("^(?:{})", pattern),
+            FilterAnchor::Full => format!("^(?:{})$", pattern),
+        }
+    }
+}
+
+/// How filter entries are written (`topics.filter_syntax`). A `regex:` or `mqtt:` prefix
+/// selects the syntax of a single entry.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FilterSyntax {
+    #[default]
+    Regex,
+    /// MQTT topic filters: `+` matches one topic level, a trailing `#` any number of them.
+    /// Always matched against the whole topic, whatever the anchor.
+    Mqtt,
+}
+
+impl FilterSyntax {
+    pub fn parse(value: &str) -> PyResult<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "" | "regex" => Ok(FilterSyntax::Regex),
+            "mqtt" => Ok(FilterSyntax::Mqtt),
+            other => Err(PyValueError::new_err(format!(
+                "Invalid filter_syntax '{}': expected 'regex' or 'mqtt'",
+                other
+            ))),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FilterSyntax::Regex => "regex",
+            FilterSyntax::Mqtt => "mqtt",
+        }
+    }
+
+    /// The syntax of `entry` and the pattern without its prefix.
+    fn of(self, entry: &str) -> (FilterSyntax, &str) {
+        if let Some(pattern) = entry.strip_prefix("mqtt:") {
+            (FilterSyntax::Mqtt, pattern)
+        } else if let Some(pattern) = entry.strip_prefix("regex:") {
+            (FilterSyntax::Regex, pattern)
+        } else {
+            (self, entry)
+        }
+    }
+}
+
+/// Whether `entry` is an MQTT topic filter with wildcards rather than a topic.
+pub fn has_wildcards(entry: &str) -> bool {
+    entry.contains(['+', '#'])
+}
+
+/// MQTT topic filters, kept as a tree of topic levels: matching a topic costs a lookup
+/// per level, however many filters there are.
+#[derive(Clone, Debug, Default)]
+pub struct TopicFilters {
+    children: HashMap<String, TopicFilters>,
+    /// `+` at this level
+    single: Option<Box<TopicFilters>>,
+    /// `#` at this level: anything below matches, and so does this level itself
+    multi: bool,
+    /// A filter ends here
+    end: bool,
+}
+
+impl TopicFilters {
+    /// Add `filter`, or explain why it isn't a valid MQTT topic filter.
+    pub fn insert(&mut self, filter: &str) -> Result<(), String> {
+        let levels: Vec<&str> = filter.split('/').collect();
+        for (index, level) in levels.iter().enumerate() {
+            if *level == "#" && index != levels.len() - 1 {
+                return Err("'#' must be the last level".to_string());
+            }
+            if level.len() > 1 && has_wildcards(level) {
+                return Err(format!("wildcard within the level '{}'", level));
+            }
+        }
+        let mut node = self;
+        for level in levels {
+            node = match level {
+                "#" => {
+                    node.multi = true;
+                    return Ok(());
+                }
+                "+" => node.single.get_or_insert_with(Default::default),
+                literal => node.children.entry(literal.to_string()).or_default(),
+            };
+        }
+        node.end = true;
+        Ok(())
+    }
+
+    pub fn is_match(&self, topic: &str) -> bool {
+        self.matches(topic.split('/'))
+    }
+
+    fn matches(&self, mut levels: Split<'_, char>) -> bool {
+        if self.multi {
+            return true;
+        }
+        match levels.next() {
+            None => self.end,
+            Some(level) => {
+                self.children.get(level).is_some_and(|child| child.matches(levels.clone()))
+                    || self.single.as_ref().is_some_and(|child| child.matches(levels))
+            }
+        }
+    }
+}
+
+/// How subscription filters, the whitelist and do_not_forward are combined.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FilterPolicy {
+    /// A topic must be whitelisted (if a whitelist exists) and must not match any
+    /// subscription filter or do_not_forward pattern.
+    #[default]
+    DenyOverrides,
+    /// Whitelisted topics are always forwarded; other topics are forwarded unless a
+    /// subscription filter or do_not_forward pattern matches.
+    AllowOverrides,
+    /// Only the whitelist decides; subscription filters and do_not_forward are ignored.
+    WhitelistOnly,
+}
+
+impl FilterPolicy {
+    pub fn parse(value: &str) -> PyResult<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "" | "deny_overrides" => Ok(FilterPolicy::DenyOverrides),
+            "allow_overrides" => Ok(FilterPolicy::AllowOverrides),
+            "whitelist_only" => Ok(FilterPolicy::WhitelistOnly),
+            other => Err(PyValueError::new_err(format!(
+                "Invalid policy '{}': expected 'deny_overrides', 'allow_overrides' or 'whitelist_only'",
+                other
+            ))),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FilterPolicy::DenyOverrides => "deny_overrides",
+            FilterPolicy::AllowOverrides => "allow_overrides",
+            FilterPolicy::WhitelistOnly => "whitelist_only",
+        }
+    }
+}
+
+/// A compiled filter list together with the patterns it was built from.
+#[derive(Clone, Debug, Default)]
+pub struct FilterList {
+    regex: Option<Regex>,
+    topics: TopicFilters,
+    patterns: Vec<String>,
+    anchor: FilterAnchor,
+    syntax: FilterSyntax,
+}
+
+impl FilterList {
+    pub fn is_match(&self, topic: &str) -> bool {
+        self.topics.is_match(topic) || self.regex.as_ref().is_some_and(|r| r.is_match(topic))
+    }
+
+    /// The configured (valid, un-anchored) patterns, with their syntax prefix if any.
+    pub fn patterns(&self) -> &[String] {
+        &self.patterns
+    }
+
+    /// Index and text of the first pattern matching `topic`, to explain a decision.
+    /// Matches the patterns one by one, so it's much slower than `is_match`.
+    pub fn matching_pattern(&self, topic: &str) -> Option<(usize, &str)> {
+        self.patterns
+            .iter()
+            .enumerate()
+            .find(|(_, entry)| match self.syntax.of(entry) {
+                (FilterSyntax::Mqtt, pattern) => {
+                    let mut filter = TopicFilters::default();
+                    filter.insert(pattern).is_ok() && filter.is_match(topic)
+                }
+                (FilterSyntax::Regex, pattern) => Regex::new(&self.anchor.apply(pattern)).is_ok_and(|r| r.is_match(topic)),
+            })
+            .map(|(index, entry)| (index, entry.as_str()))
+    }
+}
+
+/// Private helper function to compile regex filters and MQTT topic filters.
+/// Invalid patterns are dropped from the combined regex and returned as
+/// `'<pattern>': <error>` descriptions, so strict mode can reject them.
+pub fn compile_filters(filters: Vec<String>, anchor: FilterAnchor, syntax: FilterSyntax) -> (FilterList, Vec<String>) {
+    if filters.is_empty() {
+        debug!("No filters provided.");
+        return (FilterList::default(), Vec::new());
+    }
+    let mut valid_filters = Vec::new();
+    let mut regex_filters = Vec::new();
+    let mut topics = TopicFilters::default();
+    let mut invalid_filters = Vec::new();
+    for flt in filters {
+        let checked = match syntax.of(&flt) {
+            (FilterSyntax::Mqtt, pattern) => topics.insert(pattern),
+            // Validate the pattern as written, so anchoring can't turn a broken pattern into a valid one
+            (FilterSyntax::Regex, pattern) => Regex::new(pattern).map(|_| regex_filters.push(anchor.apply(pattern))).map_err(|e| e.to_string()),
+        };
+        match checked {
+            Ok(()) => {
+                debug!("Filter '{}' is valid", flt);
+                valid_filters.push(flt);
+            }
+            Err(e) => {
+                error!("Invalid filter '{}': {}", flt, e);
+                invalid_filters.push(format!("'{}': {}", flt, e));
+            }
+        }
+    }
+    if valid_filters.is_empty() {
+        debug!("No valid filters found.");
+        return (FilterList::default(), invalid_filters);
+    }
+    if regex_filters.is_empty() {
+        return (FilterList { regex: None, topics, patterns: valid_filters, anchor, syntax }, invalid_filters);
+    }
+    let pattern = format!("({})", regex_filters.join("|"));
+    match Regex::new(&pattern) {
+        Ok(compiled_regex) => (
+            FilterList {
+                regex: Some(compiled_regex),
+                topics,
+                patterns: valid_filters,
+                anchor,
+                syntax,
+            },
+            invalid_filters,
+        ),
+        Err(e) => {
+            error!("Failed to compile combined regex '{}': {}", pattern, e);
+            invalid_filters.push(format!("'{}': {}", pattern, e));
+            (FilterList::default(), invalid_filters)
+        }
+    }
+}
+
+/// Compile filters, raising a `ValueError` listing every bad pattern when `strict` is set.
+pub fn compile_filters_checked(
+    filters: Vec<String>,
+    anchor: FilterAnchor,
+    syntax: FilterSyntax,
+    strict: bool,
+) -> PyResult<FilterList> {
+    let (compiled, invalid) = compile_filters(filters, anchor, syntax);
+    if strict && !invalid.is_empty() {
+        return Err(PyValueError::new_err(format!(
+            "Invalid filter patterns: {}",
+            invalid.join(", ")
+        )));
+    }
+    Ok(compiled)
+}
+
+/// `topics.topic_whitelist`: virtual input names, plus the entries with MQTT wildcards
+/// matched against the topic. Replaced as a whole, so names and patterns always belong
+/// to the same version.
+#[derive(Debug)]
+pub struct Whitelist {
+    pub names: HashSet<String>,
+    patterns: TopicFilters,
+}
+
+impl Whitelist {
+    pub fn new(names: HashSet<String>) -> Self {
+        let patterns = wildcard_entries(&names);
+        Whitelist { names, patterns }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Whether the virtual input `name` of `topic` is whitelisted.
+    pub fn allows(&self, name: &str, topic: &str) -> bool {
+        self.names.contains(name) || self.patterns.is_match(topic)
+    }
+}
+
+/// The whitelist entries with MQTT wildcards (`zigbee2mqtt/+/temperature`), as topic filters.
+/// Invalid ones can never match a topic and are left out.
+fn wildcard_entries(whitelist: &HashSet<String>) -> TopicFilters {
+    let mut filters = TopicFilters::default();
+    for entry in whitelist.iter().filter(|entry| has_wildcards(entry)) {
+        if let Err(e) = filters.insert(entry) {
+            warn!("Ignoring whitelist entry '{}': {}", entry, e);
+        }
+    }
+    filters
+}
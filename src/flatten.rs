@@ -0,0 +1,116 @@
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use serde_json::Value;
+
+use crate::number_to_string;
+
+/// What happens to JSON arrays when a payload is flattened.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ArrayMode {
+    /// One key per element: `list/0`, `list/1`, ...
+    #[default]
+    Index,
+    /// A single `a,b,c` value; elements that are objects or arrays are kept as JSON text
+    Join,
+    /// Arrays are left out
+    Skip,
+}
+
+/// How nested JSON becomes `key/value` pairs (`processing.flatten_separator`,
+/// `flatten_max_depth` and `flatten_arrays`).
+#[derive(Clone, Debug)]
+pub struct FlattenOptions {
+    separator: String,
+    /// Nesting levels that are flattened; deeper objects and arrays are sent as JSON text. 0 for no limit
+    max_depth: usize,
+    arrays: ArrayMode,
+}
+
+impl Default for FlattenOptions {
+    fn default() -> Self {
+        FlattenOptions { separator: "/".to_string(), max_depth: 0, arrays: ArrayMode::Index }
+    }
+}
+
+impl FlattenOptions {
+    pub fn parse(separator: &str, max_depth: usize, arrays: &str) -> PyResult<Self> {
+        if separator.is_empty() {
+            return Err(PyValueError::new_err("flatten_separator must not be empty"));
+        }
+        let arrays = match arrays.trim().to_lowercase().as_str() {
+            "" | "index" => ArrayMode::Index,
+            "join" => ArrayMode::Join,
+            "skip" => ArrayMode::Skip,
+            other => {
+                return Err(PyValueError::new_err(format!(
+                    "Invalid flatten_arrays '{}': expected 'index', 'join' or 'skip'",
+                    other
+                )))
+            }
+        };
+        Ok(FlattenOptions { separator: separator.to_string(), max_depth, arrays })
+    }
+}
+
+/// Levels of objects and arrays in `value`: 0 for a scalar, 1 for `{"a": 1}`.
+pub fn json_depth(value: &Value) -> usize {
+    match value {
+        Value::Object(map) => 1 + map.values().map(json_depth).max().unwrap_or(0),
+        Value::Array(items) => 1 + items.iter().map(json_depth).max().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+/// Flatten the objects and arrays in `obj` into `key/value` pairs below `prefix`.
+pub fn flatten_json(obj: &Value, prefix: &str, options: &FlattenOptions, acc: &mut Vec<(String, String)>) {
+    flatten_level(obj, prefix, options, 1, acc);
+}
+
+/// The pairs for `value` stored under `key`, which is at nesting level `depth` (1 for the
+/// top-level keys): scalars as one pair, objects and arrays as configured.
+pub fn flatten_value(key: String, value: &Value, options: &FlattenOptions, depth: usize, acc: &mut Vec<(String, String)>) {
+    match value {
+        Value::Array(_) if options.arrays == ArrayMode::Skip => {}
+        Value::Array(items) if options.arrays == ArrayMode::Join => {
+            acc.push((key, items.iter().map(scalar_text).collect::<Vec<_>>().join(",")));
+        }
+        Value::Object(_) | Value::Array(_) if options.max_depth != 0 && depth >= options.max_depth => {
+            acc.push((key, value.to_string()));
+        }
+        Value::Object(_) | Value::Array(_) => flatten_level(value, &key, options, depth + 1, acc),
+        scalar => acc.push((key, scalar_text(scalar))),
+    }
+}
+
+fn flatten_level(obj: &Value, prefix: &str, options: &FlattenOptions, depth: usize, acc: &mut Vec<(String, String)>) {
+    let key = |k: &str| {
+        if prefix.is_empty() {
+            k.to_string()
+        } else {
+            format!("{}{}{}", prefix, options.separator, k)
+        }
+    };
+    match obj {
+        Value::Object(map) => {
+            for (k, v) in map {
+                flatten_value(key(k), v, options, depth, acc);
+            }
+        }
+        Value::Array(arr) => {
+            for (i, item) in arr.iter().enumerate() {
+                flatten_value(key(&i.to_string()), item, options, depth, acc);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn scalar_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Number(num) => number_to_string(num),
+        Value::Bool(b) => b.to_string(),
+        Value::Null => "null".to_string(),
+        nested => nested.to_string(),
+    }
+}
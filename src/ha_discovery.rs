@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use log::{debug, error, info};
+use pyo3::intern;
+use pyo3::prelude::*;
+use pyo3_async_runtimes::tokio::into_future;
+use serde_json::json;
+
+/// Announced inputs are capped like the tracked ones, so a flood of topics can't grow
+/// the retained discovery configs without bound.
+const MAX_ANNOUNCED: usize = 10_000;
+
+/// `[A-Za-z0-9_-]` only, as Home Assistant wants for node and object ids.
+fn id_part(text: &str) -> String {
+    text.trim_matches('/')
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect()
+}
+
+/// Home Assistant MQTT discovery (`general.homeassistant_discovery`): the first value
+/// forwarded to a virtual input publishes a retained config to
+/// `<prefix>/<component>/<node>/<input>/config`, with the input's copy below
+/// `<base_topic>forwardedtopics/` as state topic. Inputs dropped from the whitelist have
+/// their config cleared again.
+pub struct HaDiscovery {
+    mqtt_client: Py<PyAny>,
+    prefix: String,
+    /// Node id and device of every config: the relay topic, so instances stay apart
+    node: String,
+    state_prefix: String,
+    /// Virtual input -> (source topic, config topic) of everything announced
+    announced: Mutex<HashMap<String, (String, String)>>,
+}
+
+impl HaDiscovery {
+    pub fn new(mqtt_client: Py<PyAny>, prefix: &str, base_topic: &str) -> Self {
+        HaDiscovery {
+            mqtt_client,
+            prefix: prefix.trim_end_matches('/').to_string(),
+            node: id_part(base_topic),
+            state_prefix: format!("{}forwardedtopics/", base_topic),
+            announced: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Announce `name` (forwarded from `topic`) unless it was already. Values the relay
+    /// converted from a boolean (`boolean`, sent as `1`/`0`) make a binary sensor, others
+    /// a sensor, with a state class for numbers so Home Assistant keeps statistics.
+    pub fn announce(&self, py: Python<'_>, name: &str, topic: &str, value: &str, boolean: bool) {
+        let object_id = id_part(name);
+        let component = if boolean && matches!(value, "1" | "0") { "binary_sensor" } else { "sensor" };
+        let config_topic = format!("{}/{}/{}/{}/config", self.prefix, component, self.node, object_id);
+        {
+            let mut announced = self.announced.lock().unwrap();
+            if announced.contains_key(name) || announced.len() >= MAX_ANNOUNCED {
+                return;
+            }
+            announced.insert(name.to_string(), (topic.to_string(), config_topic.clone()));
+        }
+        let mut config = json!({
+            "name": name,
+            "unique_id": format!("{}_{}", self.node, object_id),
+            "state_topic": format!("{}{}", self.state_prefix, name),
+            "device": {
+                "identifiers": [format!("loxmqttrelay_{}", self.node)],
+                "name": format!("loxMqttRelay {}", self.node),
+                "manufacturer": "loxMqttRelay",
+            },
+        });
+        if component == "binary_sensor" {
+            config["payload_on"] = json!("1");
+            config["payload_off"] = json!("0");
+        } else if value.trim().parse::<f64>().is_ok_and(f64::is_finite) {
+            config["state_class"] = json!("measurement");
+        }
+        debug!("Announcing {} to Home Assistant on {}", name, config_topic);
+        self.publish(py, config_topic, config.to_string());
+    }
+
+    /// Clear the configs of the inputs `keep` rejects, given name and source topic.
+    pub fn retract(&self, py: Python<'_>, keep: impl Fn(&str, &str) -> bool) {
+        let removed: Vec<String> = {
+            let mut announced = self.announced.lock().unwrap();
+            let gone: Vec<String> = announced.iter().filter(|(name, (topic, _))| !keep(name, topic)).map(|(name, _)| name.clone()).collect();
+            gone.iter().filter_map(|name| announced.remove(name)).map(|(_, config_topic)| config_topic).collect()
+        };
+        if !removed.is_empty() {
+            info!("Removing {} inputs no longer whitelisted from Home Assistant", removed.len());
+        }
+        for config_topic in removed {
+            // An empty retained config deletes the entity
+            self.publish(py, config_topic, String::new());
+        }
+    }
+
+    fn publish(&self, py: Python<'_>, topic: String, payload: String) {
+        let published = self
+            .mqtt_client
+            .bind(py)
+            .call_method1(intern!(py, "publish"), (topic, payload, true))
+            .and_then(into_future);
+        match published {
+            Ok(fut) => {
+                pyo3_async_runtimes::tokio::get_runtime().spawn(async move {
+                    if let Err(e) = fut.await {
+                        error!("Error publishing a Home Assistant discovery config: {:?}", e);
+                    }
+                });
+            }
+            Err(e) => error!("Error publishing a Home Assistant discovery config: {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use base64::{engine::general_purpose, Engine};
+use log::debug;
+use tokio::io::{self, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::Semaphore;
+use tokio_rustls::rustls::ClientConfig;
+
+use crate::tls::{self, Stream};
+
+/// Same as the Python handler's `ClientTimeout(total=10)`.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Responses to `/dev/sps/io` are a few hundred bytes; anything far larger isn't a Miniserver.
+const MAX_HEADER_BYTES: usize = 16 * 1024;
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+type Connection = BufReader<Stream>;
+
+/// HTTP/1.1 sender for `/dev/sps/io/<input>/<value>` with a pool of keep-alive
+/// connections (`miniserver.native_http`). Only HTTP(S) with basic auth: token auth,
+/// proxies and the websocket stay with the Python handler.
+pub struct HttpSender {
+    authorization: Option<String>,
+    /// HTTPS when set
+    tls: Option<Arc<ClientConfig>>,
+    permits: Semaphore,
+    max_idle: usize,
+    /// Idle connections and the address they go to; cleared when the address changes
+    idle: Mutex<(String, u16, Vec<Connection>)>,
+}
+
+impl HttpSender {
+    pub fn new(user: &str, password: &str, max_parallel: usize, tls: Option<Arc<ClientConfig>>) -> Self {
+        let max_parallel = max_parallel.max(1);
+        HttpSender {
+            authorization: (!user.is_empty() && !password.is_empty())
+                .then(|| format!("Basic {}", general_purpose::STANDARD.encode(format!("{}:{}", user, password)))),
+            tls,
+            permits: Semaphore::new(max_parallel),
+            max_idle: max_parallel,
+            idle: Mutex::new((String::new(), 0, Vec::new())),
+        }
+    }
+
+    /// Set virtual input `name` to `value` on the Miniserver at `host:port`; the HTTP status.
+    pub async fn send(&self, host: &str, port: u16, name: &str, value: &str) -> io::Result<u16> {
+        let path = format!("/dev/sps/io/{}/{}", encode_path(name), encode_path(value));
+        let _permit = self.permits.acquire().await.map_err(io::Error::other)?;
+        tokio::time::timeout(REQUEST_TIMEOUT, self.get(host, port, &path))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "request timed out after 10 seconds"))?
+    }
+
+    fn take_idle(&self, host: &str, port: u16) -> Option<Connection> {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.0 != host || idle.1 != port {
+            *idle = (host.to_string(), port, Vec::new());
+        }
+        idle.2.pop()
+    }
+
+    fn put_idle(&self, host: &str, port: u16, connection: Connection) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.0 == host && idle.1 == port && idle.2.len() < self.max_idle {
+            idle.2.push(connection);
+        }
+    }
+
+    async fn get(&self, host: &str, port: u16, path: &str) -> io::Result<u16> {
+        let mut request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: keep-alive\r\n", path, host_header(host, port));
+        if let Some(authorization) = &self.authorization {
+            request.push_str(&format!("Authorization: {}\r\n", authorization));
+        }
+        request.push_str("\r\n");
+
+        // A pooled connection the Miniserver has closed in the meantime fails before any
+        // response byte arrives; that one request is retried on a fresh connection
+        if let Some(mut connection) = self.take_idle(host, port) {
+            match exchange(&mut connection, request.as_bytes()).await {
+                Ok((status, keep_alive, _)) => {
+                    if keep_alive {
+                        self.put_idle(host, port, connection);
+                    }
+                    return Ok(status);
+                }
+                Err(e) if e.kind() == io::ErrorKind::ConnectionAborted => debug!("Pooled Miniserver connection closed, reconnecting"),
+                Err(e) => return Err(e),
+            }
+        }
+        let mut connection = BufReader::new(tls::connect(host, port, self.tls.as_ref()).await?);
+        let (status, keep_alive, _) = exchange(&mut connection, request.as_bytes()).await.map_err(|e| {
+            if e.kind() == io::ErrorKind::ConnectionAborted {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed without a response")
+            } else {
+                e
+            }
+        })?;
+        if keep_alive {
+            self.put_idle(host, port, connection);
+        }
+        Ok(status)
+    }
+}
+
+fn host_header(host: &str, port: u16) -> String {
+    match (host.contains(':'), port) {
+        (true, 80) => format!("[{}]", host),
+        (true, _) => format!("[{}]:{}", host, port),
+        (false, 80) => host.to_string(),
+        (false, _) => format!("{}:{}", host, port),
+    }
+}
+
+/// One request on its own connection, without authentication: the status and body.
+pub async fn get_once(host: &str, port: u16, path: &str, tls: Option<&Arc<ClientConfig>>) -> io::Result<(u16, Vec<u8>)> {
+    let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", path, host_header(host, port));
+    tokio::time::timeout(REQUEST_TIMEOUT, async {
+        let stream = tls::connect(host, port, tls).await?;
+        let (status, _, body) = exchange(&mut BufReader::new(stream), request.as_bytes()).await?;
+        Ok((status, body))
+    })
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "request timed out after 10 seconds"))?
+}
+
+/// Write `request` and read the response: the status, whether the connection can be
+/// reused and the body. `ConnectionAborted` means the connection was gone before the
+/// response started.
+async fn exchange(connection: &mut Connection, request: &[u8]) -> io::Result<(u16, bool, Vec<u8>)> {
+    let aborted = |e: io::Error| match e.kind() {
+        io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset | io::ErrorKind::UnexpectedEof => {
+            io::Error::new(io::ErrorKind::ConnectionAborted, e)
+        }
+        _ => e,
+    };
+    connection.get_mut().write_all(request).await.map_err(aborted)?;
+    // A TLS stream keeps what was written in its buffer until flushed
+    connection.get_mut().flush().await.map_err(aborted)?;
+    let mut line = String::new();
+    if connection.read_line(&mut line).await.map_err(aborted)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed"));
+    }
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("invalid HTTP response: {}", what));
+    let mut parts = line.split_whitespace();
+    let version = parts.next().unwrap_or_default().to_string();
+    let status: u16 = parts.next().and_then(|s| s.parse().ok()).ok_or_else(|| invalid("status line"))?;
+    let mut keep_alive = version == "HTTP/1.1";
+    let mut content_length = None;
+    let mut chunked = false;
+    let mut header_bytes = line.len();
+    loop {
+        line.clear();
+        header_bytes += connection.read_line(&mut line).await?;
+        if header_bytes > MAX_HEADER_BYTES {
+            return Err(invalid("headers too large"));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else { return Err(invalid("header line")) };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => content_length = Some(value.parse::<usize>().map_err(|_| invalid("content-length"))?),
+            "transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+            "connection" => {
+                keep_alive = !value.eq_ignore_ascii_case("close") && (version == "HTTP/1.1" || value.eq_ignore_ascii_case("keep-alive"))
+            }
+            _ => {}
+        }
+    }
+    let mut body = Vec::new();
+    if chunked {
+        loop {
+            line.clear();
+            connection.read_line(&mut line).await?;
+            let size = usize::from_str_radix(line.trim().split(';').next().unwrap_or_default(), 16).map_err(|_| invalid("chunk size"))?;
+            if body.len() + size > MAX_BODY_BYTES {
+                return Err(invalid("body too large"));
+            }
+            // The chunk and its CRLF; the last chunk is followed by (empty) trailers
+            if size == 0 {
+                loop {
+                    line.clear();
+                    if connection.read_line(&mut line).await? == 0 || line.trim_end().is_empty() {
+                        break;
+                    }
+                }
+                break;
+            }
+            read_into(connection, size, &mut body).await?;
+            read_into(connection, 2, &mut Vec::new()).await?;
+        }
+    } else if let Some(length) = content_length {
+        if length > MAX_BODY_BYTES {
+            return Err(invalid("body too large"));
+        }
+        read_into(connection, length, &mut body).await?;
+    } else {
+        // Body until the connection closes
+        connection.take(MAX_BODY_BYTES as u64).read_to_end(&mut body).await?;
+        keep_alive = false;
+    }
+    Ok((status, keep_alive, body))
+}
+
+async fn read_into(connection: &mut Connection, len: usize, body: &mut Vec<u8>) -> io::Result<()> {
+    let copied = connection.take(len as u64).read_to_end(body).await?;
+    if copied < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "response body truncated"));
+    }
+    Ok(())
+}
+
+/// Percent-encode a path segment the way aiohttp quotes the Python sender's URLs: `/` is
+/// kept, so values behave the same on both paths.
+fn encode_path(segment: &str) -> String {
+    let mut out = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' | b'!' | b'$' | b'&' | b'\''
+            | b'(' | b')' | b'*' | b'+' | b',' | b';' | b'=' | b':' | b'@' => out.push(byte as char),
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
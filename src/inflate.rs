@@ -0,0 +1,461 @@
+//! DEFLATE (RFC 1951) decompression of zlib (RFC 1950), gzip (RFC 1952) and raw
+//! payloads, with a cap on the decompressed size.
+
+/// Base values and extra bits of the length and distance codes
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097,
+    6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+/// Order in which the code length code lengths of a dynamic block are stored
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xedb8_8320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+};
+
+/// The container around the DEFLATE stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Container {
+    Zlib,
+    Gzip,
+}
+
+/// The container announced by the first bytes of `raw`, if any.
+pub fn detect(raw: &[u8]) -> Option<Container> {
+    match raw {
+        [0x1f, 0x8b, 0x08, ..] => Some(Container::Gzip),
+        // Compression method 8 with a window of at most 32K, and a valid header check
+        [cmf, flg, ..] if cmf & 0x0f == 8 && cmf >> 4 <= 7 && (*cmf as u16 * 256 + *flg as u16).is_multiple_of(31) => {
+            Some(Container::Zlib)
+        }
+        _ => None,
+    }
+}
+
+/// Decompress `raw`: zlib or gzip if its header says so, raw DEFLATE otherwise. Fails
+/// once the output would exceed `limit` bytes.
+pub fn decompress(raw: &[u8], limit: usize) -> Result<Vec<u8>, String> {
+    match detect(raw) {
+        Some(Container::Zlib) => {
+            if raw[1] & 0x20 != 0 {
+                return Err("zlib preset dictionaries aren't supported".to_string());
+            }
+            let (data, end) = inflate(&raw[2..], limit)?;
+            let trailer = raw.get(2 + end..2 + end + 4).ok_or("zlib stream without checksum")?;
+            if u32::from_be_bytes(trailer.try_into().unwrap()) != adler32(&data) {
+                return Err("zlib checksum mismatch".to_string());
+            }
+            Ok(data)
+        }
+        Some(Container::Gzip) => {
+            let start = gzip_header_len(raw).ok_or("truncated gzip header")?;
+            let (data, end) = inflate(&raw[start..], limit)?;
+            let trailer = raw.get(start + end..start + end + 8).ok_or("gzip stream without trailer")?;
+            if u32::from_le_bytes(trailer[..4].try_into().unwrap()) != crc32(&data) {
+                return Err("gzip checksum mismatch".to_string());
+            }
+            if u32::from_le_bytes(trailer[4..].try_into().unwrap()) != data.len() as u32 {
+                return Err("gzip size mismatch".to_string());
+            }
+            Ok(data)
+        }
+        None => inflate(raw, limit).map(|(data, _)| data),
+    }
+}
+
+fn gzip_header_len(raw: &[u8]) -> Option<usize> {
+    let flags = *raw.get(3)?;
+    let mut pos = 10;
+    if flags & 0x04 != 0 {
+        let extra = u16::from_le_bytes(raw.get(pos..pos + 2)?.try_into().ok()?) as usize;
+        pos += 2 + extra;
+    }
+    // File name and comment, zero terminated
+    for flag in [0x08, 0x10] {
+        if flags & flag != 0 {
+            pos += raw.get(pos..)?.iter().position(|&b| b == 0)? + 1;
+        }
+    }
+    if flags & 0x02 != 0 {
+        pos += 2;
+    }
+    (pos <= raw.len()).then_some(pos)
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8))
+}
+
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buffer: u32,
+    count: u32,
+}
+
+impl Bits<'_> {
+    fn bits(&mut self, need: u32) -> Result<u32, String> {
+        while self.count < need {
+            let byte = *self.data.get(self.pos).ok_or("truncated DEFLATE stream")?;
+            self.pos += 1;
+            self.buffer |= (byte as u32) << self.count;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1u64 << need) - 1) as u32;
+        self.buffer >>= need;
+        self.count -= need;
+        Ok(value)
+    }
+
+    /// Drop the bits left of the current byte, before a stored block.
+    fn align(&mut self) {
+        self.buffer = 0;
+        self.count = 0;
+    }
+}
+
+/// A canonical Huffman code, as symbol counts per code length and symbols in code order.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Huffman, String> {
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err("over-subscribed Huffman code".to_string());
+            }
+        }
+        let mut offsets = [0u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Result<u16, String> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= bits.bits(1)? as i32;
+            let count = count as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err("invalid Huffman code".to_string())
+    }
+}
+
+/// Inflate the DEFLATE stream at the start of `data`; the output and the number of
+/// bytes the stream took.
+fn inflate(data: &[u8], limit: usize) -> Result<(Vec<u8>, usize), String> {
+    let mut bits = Bits { data, pos: 0, buffer: 0, count: 0 };
+    let mut out = Vec::new();
+    let too_large = || format!("decompressed payload exceeds {} bytes", limit);
+    loop {
+        let last = bits.bits(1)? == 1;
+        match bits.bits(2)? {
+            0 => {
+                bits.align();
+                let header = data.get(bits.pos..bits.pos + 4).ok_or("truncated stored block")?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                if len != !u16::from_le_bytes([header[2], header[3]]) {
+                    return Err("stored block length mismatch".to_string());
+                }
+                let start = bits.pos + 4;
+                let block = data.get(start..start + len as usize).ok_or("truncated stored block")?;
+                if out.len() + block.len() > limit {
+                    return Err(too_large());
+                }
+                out.extend_from_slice(block);
+                bits.pos = start + len as usize;
+            }
+            1 => {
+                let mut lengths = [0u8; 288];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..].fill(8);
+                let literals = Huffman::new(&lengths)?;
+                let distances = Huffman::new(&[5; 30])?;
+                inflate_block(&mut bits, &mut out, &literals, &distances, limit)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(&mut bits)?;
+                inflate_block(&mut bits, &mut out, &literals, &distances, limit)?;
+            }
+            _ => return Err("invalid DEFLATE block type".to_string()),
+        }
+        if out.len() > limit {
+            return Err(too_large());
+        }
+        if last {
+            return Ok((out, bits.pos));
+        }
+    }
+}
+
+fn dynamic_codes(bits: &mut Bits) -> Result<(Huffman, Huffman), String> {
+    let literal_count = bits.bits(5)? as usize + 257;
+    let distance_count = bits.bits(5)? as usize + 1;
+    let code_length_count = bits.bits(4)? as usize + 4;
+    if literal_count > 286 || distance_count > 30 {
+        return Err("invalid dynamic block code counts".to_string());
+    }
+    let mut code_lengths = [0u8; 19];
+    for &index in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[index] = bits.bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&code_lengths)?;
+    let mut lengths = vec![0u8; literal_count + distance_count];
+    let mut index = 0;
+    while index < lengths.len() {
+        let symbol = code_length_code.decode(bits)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 if index > 0 => (lengths[index - 1], 3 + bits.bits(2)? as usize),
+            16 => return Err("repeated code length without a previous one".to_string()),
+            17 => (0, 3 + bits.bits(3)? as usize),
+            _ => (0, 11 + bits.bits(7)? as usize),
+        };
+        if index + repeat > lengths.len() {
+            return Err("too many code lengths".to_string());
+        }
+        lengths[index..index + repeat].fill(value);
+        index += repeat;
+    }
+    if lengths[256] == 0 {
+        return Err("dynamic block without end-of-block code".to_string());
+    }
+    Ok((Huffman::new(&lengths[..literal_count])?, Huffman::new(&lengths[literal_count..])?))
+}
+
+fn inflate_block(
+    bits: &mut Bits,
+    out: &mut Vec<u8>,
+    literals: &Huffman,
+    distances: &Huffman,
+    limit: usize,
+) -> Result<(), String> {
+    loop {
+        let symbol = literals.decode(bits)? as usize;
+        match symbol {
+            0..=255 => {
+                if out.len() == limit {
+                    return Err(format!("decompressed payload exceeds {} bytes", limit));
+                }
+                out.push(symbol as u8);
+            }
+            256 => return Ok(()),
+            257..=285 => {
+                let code = symbol - 257;
+                let len = LENGTH_BASE[code] as usize + bits.bits(LENGTH_EXTRA[code] as u32)? as usize;
+                let code = distances.decode(bits)? as usize;
+                if code >= 30 {
+                    return Err("invalid distance code".to_string());
+                }
+                let distance = DISTANCE_BASE[code] as usize + bits.bits(DISTANCE_EXTRA[code] as u32)? as usize;
+                if distance > out.len() {
+                    return Err("distance before the start of the output".to_string());
+                }
+                if out.len() + len > limit {
+                    return Err(format!("decompressed payload exceeds {} bytes", limit));
+                }
+                let start = out.len() - distance;
+                for i in 0..len {
+                    out.push(out[start + i]);
+                }
+            }
+            _ => return Err("invalid literal/length code".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// "hello" in a stored block
+    const STORED: [u8; 10] = [0x01, 0x05, 0x00, 0xfa, 0xff, 0x68, 0x65, 0x6c, 0x6c, 0x6f];
+    /// "hello hello hello" with the fixed codes
+    const FIXED: [u8; 10] = [0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x90, 0x00];
+    const FIXED_CRC32: u32 = 0xe5f9_8880;
+    /// `dynamic_text()` with dynamic codes
+    const DYNAMIC: [u8; 86] = [
+        0x1d, 0x8e, 0x89, 0x0d, 0x00, 0x30, 0x08, 0x02, 0x67, 0x15, 0xd8, 0x7f, 0x06, 0x39, 0x62, 0x9b, 0x52, 0xe5,
+        0xd1, 0x56, 0x12, 0xe7, 0xfa, 0xde, 0x29, 0x2d, 0xdb, 0x6b, 0xc9, 0x6d, 0x80, 0x2d, 0x1d, 0xb3, 0x82, 0xd8,
+        0xc5, 0x81, 0xa2, 0x09, 0xab, 0x14, 0x0a, 0x5f, 0x4f, 0x51, 0x36, 0x8f, 0xae, 0xa5, 0xab, 0x47, 0xe7, 0x34,
+        0x77, 0xf7, 0xa9, 0x6d, 0x99, 0xa5, 0xc3, 0xc6, 0xdb, 0xe4, 0x54, 0x44, 0xfa, 0xf8, 0xc1, 0x59, 0x18, 0x90,
+        0x81, 0x0c, 0x8a, 0xd9, 0x74, 0xe6, 0xde, 0x32, 0x75, 0x37, 0x39, 0x6c, 0xac, 0x7b,
+    ];
+
+    /// 200 pseudo-random letters from "abcd", mostly literals so zlib picks dynamic codes
+    fn dynamic_text() -> Vec<u8> {
+        let mut x = 1u64;
+        (0..200)
+            .map(|_| {
+                x = (x * 1_103_515_245 + 12_345) % (1 << 31);
+                b'a' + ((x >> 16) % 4) as u8
+            })
+            .collect()
+    }
+
+    fn gzip(flags: u8, extra: &[u8], deflate: &[u8], crc: u32, size: u32) -> Vec<u8> {
+        let mut out = vec![0x1f, 0x8b, 0x08, flags, 0, 0, 0, 0, 0, 3];
+        out.extend_from_slice(extra);
+        out.extend_from_slice(deflate);
+        out.extend_from_slice(&crc.to_le_bytes());
+        out.extend_from_slice(&size.to_le_bytes());
+        out
+    }
+
+    #[test]
+    fn stored_block() {
+        assert_eq!(decompress(&STORED, 1024).unwrap(), b"hello");
+        let mut bad = STORED;
+        bad[3] = 0;
+        assert!(decompress(&bad, 1024).unwrap_err().contains("length mismatch"));
+        assert!(decompress(&STORED[..7], 1024).unwrap_err().contains("truncated"));
+    }
+
+    #[test]
+    fn fixed_block() {
+        assert_eq!(decompress(&FIXED, 1024).unwrap(), b"hello hello hello");
+    }
+
+    #[test]
+    fn dynamic_block() {
+        assert_eq!(DYNAMIC[0] >> 1 & 0x03, 2);
+        assert_eq!(decompress(&DYNAMIC, 1024).unwrap(), dynamic_text());
+        assert!(decompress(&DYNAMIC[..40], 1024).unwrap_err().contains("truncated"));
+    }
+
+    #[test]
+    fn invalid_block_type() {
+        assert_eq!(decompress(&[0x07], 1024).unwrap_err(), "invalid DEFLATE block type");
+    }
+
+    #[test]
+    fn over_subscribed_code() {
+        assert!(Huffman::new(&[1, 1, 1]).is_err());
+        assert!(Huffman::new(&[1, 2, 2, 2]).is_err());
+        assert!(Huffman::new(&[1, 2, 2]).is_ok());
+        // Dynamic block whose code length code has three 1-bit codes
+        assert_eq!(decompress(&[0x05, 0x00, 0x92, 0x00], 1024).unwrap_err(), "over-subscribed Huffman code");
+    }
+
+    #[test]
+    fn distance_before_start() {
+        // Fixed block starting with length 3 at distance 1, with no output to copy from
+        assert_eq!(decompress(&[0x03, 0x02, 0x00], 1024).unwrap_err(), "distance before the start of the output");
+    }
+
+    #[test]
+    fn output_limit_boundary() {
+        assert_eq!(decompress(&STORED, 5).unwrap(), b"hello");
+        assert!(decompress(&STORED, 4).unwrap_err().contains("exceeds 4 bytes"));
+        assert_eq!(decompress(&FIXED, 17).unwrap().len(), 17);
+        assert!(decompress(&FIXED, 16).unwrap_err().contains("exceeds 16 bytes"));
+        assert_eq!(decompress(&DYNAMIC, 200).unwrap().len(), 200);
+        assert!(decompress(&DYNAMIC, 199).is_err());
+    }
+
+    #[test]
+    fn zlib_container() {
+        let zlib = [0x78, 0x9c, 0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x90, 0x00, 0x3a, 0x2e, 0x06, 0x7d];
+        assert_eq!(detect(&zlib), Some(Container::Zlib));
+        assert_eq!(decompress(&zlib, 1024).unwrap(), b"hello hello hello");
+        let mut bad = zlib;
+        bad[15] ^= 1;
+        assert_eq!(decompress(&bad, 1024).unwrap_err(), "zlib checksum mismatch");
+        assert_eq!(decompress(&zlib[..14], 1024).unwrap_err(), "zlib stream without checksum");
+    }
+
+    #[test]
+    fn gzip_headers() {
+        let plain = gzip(0, &[], &FIXED, FIXED_CRC32, 17);
+        assert_eq!(detect(&plain), Some(Container::Gzip));
+        assert_eq!(decompress(&plain, 1024).unwrap(), b"hello hello hello");
+
+        // FEXTRA with four bytes and FNAME "x.txt"
+        let extra = [&[4, 0][..], b"ab\0c", b"x.txt\0"].concat();
+        let named = gzip(0x04 | 0x08, &extra, &FIXED, FIXED_CRC32, 17);
+        assert_eq!(gzip_header_len(&named), Some(10 + extra.len()));
+        assert_eq!(decompress(&named, 1024).unwrap(), b"hello hello hello");
+
+        // FCOMMENT and FHCRC
+        let extra = [&b"note\0"[..], &[0, 0]].concat();
+        let commented = gzip(0x10 | 0x02, &extra, &FIXED, FIXED_CRC32, 17);
+        assert_eq!(decompress(&commented, 1024).unwrap(), b"hello hello hello");
+
+        assert_eq!(decompress(&gzip(0, &[], &FIXED, FIXED_CRC32 ^ 1, 17), 1024).unwrap_err(), "gzip checksum mismatch");
+        assert_eq!(decompress(&gzip(0, &[], &FIXED, FIXED_CRC32, 18), 1024).unwrap_err(), "gzip size mismatch");
+        // FNAME without its terminator, FEXTRA longer than the payload
+        assert_eq!(decompress(&named[..18], 1024).unwrap_err(), "truncated gzip header");
+        assert_eq!(decompress(&[0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 3, 0xff, 0x00], 1024).unwrap_err(), "truncated gzip header");
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b"hello hello hello"), FIXED_CRC32);
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        assert_eq!(adler32(&vec![0xff; 100_000]), {
+            let (mut a, mut b) = (1u64, 0u64);
+            for _ in 0..100_000 {
+                a = (a + 0xff) % 65521;
+                b = (b + a) % 65521;
+            }
+            (b << 16 | a) as u32
+        });
+    }
+}
@@ -1,24 +1,141 @@
-use pyo3::{prelude::*, types::PyFrozenSet};
-use regex::Regex;
+use pyo3::{prelude::*, types::{PyBytes, PyDict, PyFrozenSet, PyList, PyString}};
+use pyo3::buffer::PyBuffer;
 use pyo3::intern;
+use pyo3::exceptions::PyValueError;
 
-use std::collections::HashSet;
-use std::sync::Mutex;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::task::JoinSet;
 
 // For caching
-use lru::LruCache;
 use std::num::NonZeroUsize;
 
 // For JSON flattening
-use serde_json::Value;
+use serde_json::{json, Map, Number, Value};
 
 // For logging
 use log::{debug, error, info, warn};
-use base64::{Engine, engine::general_purpose};
+
+mod batch;
+use batch::{chunks, join, BatchEntry, BatchMode, Batching, Pushed, MAX_DATAGRAM_BYTES};
+mod binary_payload;
+mod booleans;
+mod capture;
+use capture::{Capture, Replaying};
+mod completion;
+use completion::{Completion, Delivery, PendingDelivery};
+use booleans::BoolTable;
+use binary_payload::{parse_payload_formats, PayloadFormats};
+mod extract;
+mod failover;
+use failover::Failover;
+use extract::{parse_extractions, ExtractionSet};
+mod filters;
+mod flatten;
+use flatten::{flatten_json, FlattenOptions};
+mod http_sender;
+mod inflate;
+use http_sender::HttpSender;
+mod crypto;
+mod log_file;
+mod log_sink;
+mod line_formats;
+use line_formats::LineFormat;
+mod lookup_cache;
+use lookup_cache::LookupCache;
+mod ha_discovery;
+use ha_discovery::HaDiscovery;
+mod lox_states;
+use lox_states::{parse_state_mappings, StatePublisher};
+mod lox_ws;
+use lox_ws::{LoxWs, LoxWsClient};
+mod metrics;
+use metrics::{Cache, Metrics, TopicEvent};
+mod mqtt_client;
+mod mqtt_packet;
+mod numbers;
+use numbers::extract_number;
+mod outbox;
+use outbox::{Outbox, Replay};
+use filters::{compile_filters_checked, has_wildcards, FilterAnchor, FilterList, FilterPolicy, FilterSyntax, Whitelist};
+mod rules;
+use rules::{parse_rules, RuleDecision, RuleSet, Transport, Verdict};
+mod rate_limit;
+mod retry;
+use retry::RetryPolicy;
+use rate_limit::{parse_rate_limits, Admission, RateLimiter};
+mod rewrites;
+use rewrites::{parse_rewrites, RewriteSet};
+mod scripting;
+mod send_queue;
+use send_queue::{Overflow, QueuedSend, SendQueue};
+use scripting::{parse_scripts, ScriptEngine};
+mod plugins;
+use plugins::{parse_plugins, PluginHost};
+mod prometheus;
+use prometheus::{Exporter, Sources, StringCache};
+mod targets;
+use targets::{parse_targets, Target, TargetStats, Targets};
+mod templates;
+mod timestamps;
+use timestamps::{parse_timestamp_rules, TimestampRules};
+mod tls;
+use tls::TlsOptions;
+use tokio_rustls::rustls::ClientConfig;
+mod transforms;
+use transforms::{parse_transforms, TransformSet, Transformed};
+mod udp_forwarder;
+use udp_forwarder::UdpForwarder;
+mod config_entries;
+mod config_file;
+mod config_merge;
+mod config_validate;
+mod connection_state;
+mod decode;
+use decode::Utf8Policy;
+use connection_state::{ConnectionStates, LinkState};
+mod delivery;
+mod derived;
+use derived::{parse_derived, DerivedTopics};
+use delivery::{outcome_from_result, DeliveryStatus, LastValueStore};
+mod skips;
+mod structure;
+use skips::{SkipReason, SkipStats};
+mod stats;
+use stats::{Counter, FilterKind, Stats};
+mod timing;
+use timing::{Stage, StageTimings};
+mod trace;
+use trace::{MessageTrace, Outcome, TraceLog, ValueTrace};
+mod shared;
+use shared::Shared;
+mod panic_hook;
+mod py_json;
+mod py_log;
+mod vi_names;
+mod watchdog;
+use watchdog::Watchdog;
+mod websocket;
+mod xml_payload;
+use vi_names::{NameNormalizer, ViNameLimiter};
+use templates::{parse_templates, MessageContext, PayloadTemplates};
 
 // Import `into_future` from pyo3_async_runtimes and `spawn` from tokio
 use pyo3_async_runtimes::tokio::into_future;
 
+/// Upper bound for the forwarded virtual inputs remembered for exports.
+const MAX_TRACKED_INPUTS: usize = 10_000;
+
+/// Whitelist entries per `whitelist/response` message unless the request asks otherwise.
+const WHITELIST_CHUNK_SIZE: usize = 500;
+
+/// How long the restart after a `config/set` waits for its `config/response` to go out.
+const CONFIG_RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+
 /// A small struct to store all relevant MQTT topics in Rust, so we don't fetch them repeatedly
 #[derive(Clone, Debug)]
 struct MqttTopics {
@@ -32,75 +149,100 @@ struct MqttTopics {
     config_remove_topic: String,
     config_update_topic: String,
     config_restart_topic: String,
+    config_validate_topic: String,
+    config_validate_response_topic: String,
+    filters_set_topic: String,
+    dnf_set_topic: String,
+    whitelist_add_topic: String,
+    whitelist_remove_topic: String,
+    whitelist_get_topic: String,
+    whitelist_response_topic: String,
+    stats_get_topic: String,
+    stats_reset_topic: String,
+    stats_response_topic: String,
+    debug_set_topic: String,
+    ha_lock_topic: String,
+    export_topic: String,
+    delivery_get_topic: String,
+    delivery_response_topic: String,
+    filters_test_topic: String,
+    filters_test_response_topic: String,
+    pause_topic: String,
+    resume_topic: String,
+    state_topic: String,
 }
 
-/// Convert a known boolean string to "1"/"0", or None if unrecognized.
-fn convert_boolean_str(input: &str) -> Option<&'static str> {
-    match input {
-        "true" | "yes" | "on" | "enabled" | "enable" | "1"
-        | "check" | "checked" | "select" | "selected" => Some("1"),
-        "false" | "no" | "off" | "disabled" | "disable" | "0" => Some("0"),
-        _ => None,
+/// See `MiniserverDataProcessor::update_topic_whitelist`.
+#[derive(Clone, Copy, Debug, Default)]
+struct WhitelistTimes {
+    updated_at: Option<f64>,
+    synced_at: Option<f64>,
+}
+
+fn unix_now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default()
+}
+
+/// Host and port of a Miniserver address as the Python handler reads it: `host`,
+/// `host:port`, `[v6]:port` or a bare IPv6 literal.
+fn split_host_port(py: Python<'_>, address: &str, default_port: u16) -> PyResult<(String, u16)> {
+    py.import(intern!(py, "loxmqttrelay.utils"))?
+        .getattr(intern!(py, "split_host_port"))?
+        .call1((address, default_port))?
+        .extract()
+}
+
+/// `topic` with everything that can't be republished or used in a virtual input name
+/// replaced by `_`: invalid UTF-8 (decoded as U+FFFD), MQTT wildcards, NUL and other
+/// control characters. Borrowed if nothing had to be replaced.
+fn sanitize_topic(topic: Cow<'_, str>) -> Cow<'_, str> {
+    let invalid = |c: char| c == '\u{FFFD}' || c == '+' || c == '#' || c.is_control();
+    if !topic.contains(invalid) {
+        return topic;
     }
+    Cow::Owned(topic.replace(invalid, "_"))
 }
 
-/// Flatten a serde_json `Value` into `key/value` pairs using '/' as separator.
-fn flatten_json(obj: &Value, prefix: &str, acc: &mut Vec<(String, String)>) {
-    match obj {
-        Value::Object(map) => {
-            for (k, v) in map {
-                let new_key = if prefix.is_empty() {
-                    k.clone()
-                } else {
-                    format!("{}/{}", prefix, k)
-                };
-                match v {
-                    Value::Object(_) | Value::Array(_) => {
-                        flatten_json(v, &new_key, acc);
-                    }
-                    Value::String(s) => {
-                        acc.push((new_key, s.clone()));
-                    }
-                    Value::Number(num) => {
-                        acc.push((new_key, num.to_string()));
-                    }
-                    Value::Bool(b) => {
-                        acc.push((new_key, b.to_string()));
-                    }
-                    Value::Null => {
-                        acc.push((new_key, "null".to_string()));
-                    }
-                }
-            }
-        }
-        Value::Array(arr) => {
-            for (i, item) in arr.iter().enumerate() {
-                let new_key = if prefix.is_empty() {
-                    i.to_string()
-                } else {
-                    format!("{}/{}", prefix, i)
-                };
-                match item {
-                    Value::Object(_) | Value::Array(_) => {
-                        flatten_json(item, &new_key, acc);
-                    }
-                    Value::String(s) => {
-                        acc.push((new_key, s.clone()));
-                    }
-                    Value::Number(num) => {
-                        acc.push((new_key, num.to_string()));
-                    }
-                    Value::Bool(b) => {
-                        acc.push((new_key, b.to_string()));
-                    }
-                    Value::Null => {
-                        acc.push((new_key, "null".to_string()));
-                    }
-                }
-            }
-        }
-        _ => {}
+/// Render a JSON number without routing integers through f64.
+/// serde_json keeps the original token (`arbitrary_precision`), so integers of any size
+/// (e.g. 128-bit Zigbee IEEE addresses) come out exactly as received; floats are
+/// formatted as before.
+fn number_to_string(num: &Number) -> String {
+    let text = num.to_string();
+    if text.bytes().all(|b| b.is_ascii_digit() || b == b'-') {
+        return text;
     }
+    match num.as_f64().and_then(Number::from_f64) {
+        Some(n) => n.to_string(),
+        None => text,
+    }
+}
+
+/// The part of `config` at the dotted `path`, e.g. `topics.topic_whitelist`, nested
+/// under its keys so it keeps the shape of the full config. The section may be left out
+/// for a field: `topic_whitelist` is looked up in every section.
+fn config_subtree(config: &Value, path: &str) -> Result<Value, String> {
+    let unknown = || format!("Unknown configuration path: {}", path);
+    let mut keys: Vec<&str> = path.split('.').collect();
+    if keys.iter().any(|k| k.is_empty()) {
+        return Err(unknown());
+    }
+    if config.get(keys[0]).is_none() {
+        let section = config
+            .as_object()
+            .and_then(|sections| sections.iter().find(|(_, fields)| fields.get(keys[0]).is_some()))
+            .map(|(name, _)| name.as_str())
+            .ok_or_else(unknown)?;
+        keys.insert(0, section);
+    }
+    let mut node = config;
+    for key in &keys {
+        node = node.get(*key).ok_or_else(unknown)?;
+    }
+    Ok(keys.iter().rev().fold(node.clone(), |inner, key| json!({ *key: inner })))
 }
 
 macro_rules! pyget {
@@ -111,36 +253,19 @@ macro_rules! pyget {
     }};
 }
 
-/// Private helper function to compile regex filters
-fn compile_filters(filters: Vec<String>) -> Option<Regex> {
-    if filters.is_empty() {
-        debug!("No filters provided.");
-        return None;
-    }
-    let mut valid_filters = Vec::new();
-    for flt in filters {
-        match Regex::new(&flt) {
-            Ok(_) => {
-                debug!("Filter '{}' is valid", flt);
-                valid_filters.push(flt);
-            }
-            Err(e) => {
-                error!("Invalid filter '{}': {}", flt, e);
-            }
-        }
+/// The TLS config for HTTPS to the Miniserver, `None` without `miniserver.miniserver_tls`.
+fn miniserver_tls(py: Python<'_>, global_config: &Py<PyAny>) -> PyResult<Option<Arc<ClientConfig>>> {
+    if !pyget!(global_config, py, "miniserver", "miniserver_tls").extract::<bool>()? {
+        return Ok(None);
     }
-    if valid_filters.is_empty() {
-        debug!("No valid filters found.");
-        return None;
-    }
-    let pattern = format!("({})", valid_filters.join("|"));
-    match Regex::new(&pattern) {
-        Ok(compiled_regex) => Some(compiled_regex),
-        Err(e) => {
-            error!("Failed to compile combined regex '{}': {}", pattern, e);
-            None
-        }
+    TlsOptions {
+        ca_file: pyget!(global_config, py, "miniserver", "miniserver_tls_ca_file").extract()?,
+        cert_file: pyget!(global_config, py, "miniserver", "miniserver_tls_cert_file").extract()?,
+        key_file: pyget!(global_config, py, "miniserver", "miniserver_tls_key_file").extract()?,
+        insecure: pyget!(global_config, py, "miniserver", "miniserver_tls_insecure").extract()?,
     }
+    .client_config()
+    .map(Some)
 }
 
 #[pyclass]
@@ -148,43 +273,368 @@ pub struct MiniserverDataProcessor {
     #[pyo3(get)]
     global_config: Py<PyAny>,
 
-    compiled_subscription_filter: Option<Regex>,
-    
-    do_not_forward_patterns: Option<Regex>,
+    subscription_filters: Shared<FilterList>,
+    /// Whether `subscription_filters` also run on every flattened key
+    filter_flattened_keys: bool,
+    /// Filters for the keys after JSON expansion, scripts and plugins only
+    post_expansion_filters: Shared<FilterList>,
 
-    #[pyo3(get)]
-    topic_whitelist: HashSet<String>,
-    convert_bool_cache: Mutex<LruCache<String, String>>,
-    normalize_topic_cache: Mutex<LruCache<String, String>>,
+    do_not_forward_patterns: Shared<FilterList>,
+    /// `topics.monitor_topics`: values converted and published to `{base_topic}monitored/`, never forwarded
+    monitor_topics: Shared<FilterList>,
+    strict_filters: bool,
+    filter_anchor: FilterAnchor,
+    filter_syntax: FilterSyntax,
+    policy: FilterPolicy,
+    rules: Shared<RuleSet>,
+    rewrites: Shared<RewriteSet>,
+    /// `processing.payload_formats`: topics with MessagePack or CBOR payloads, decoded to JSON
+    payload_formats: PayloadFormats,
+    /// `processing.extract`: named JSON fields to forward instead of the flattened payload
+    extractions: ExtractionSet,
+    /// `processing.flatten_*`: separator, depth limit and array handling of expanded JSON
+    flatten: FlattenOptions,
+    /// `processing.transforms`: scale, offset, rounding and range checks for numeric values
+    transforms: Shared<TransformSet>,
+    /// `processing.derived`: virtual topics computed from the latest values of several topics
+    derived: Shared<DerivedTopics>,
+    /// `processing.timestamps`: date and time values converted to Loxone or Unix seconds
+    timestamps: Shared<TimestampRules>,
+    strip_prefixes: Shared<Vec<String>>,
+    vi_names: ViNameLimiter,
+    /// `topics.name_replacement`, `name_replace_chars` and `name_lowercase`
+    name_normalizer: NameNormalizer,
+    /// False while this instance is the HA standby; nothing is forwarded then.
+    active: AtomicBool,
+    /// `processing.expand_json`, kept here so messages don't read the config; see `reload_config`
+    expand_json: AtomicBool,
+    /// `processing.scripts`, recompiled when the list is changed over `config/*`
+    scripts: Shared<ScriptEngine>,
+    plugins: PluginHost,
+    templates: PayloadTemplates,
+    /// Virtual input name -> source topic of everything forwarded so far, for exports.
+    forwarded_inputs: Mutex<HashMap<String, String>>,
+    last_values: Arc<LastValueStore>,
+    connection_states: ConnectionStates,
+    /// Seconds after a successful delivery during which the identical value isn't resent
+    duplicate_window: f64,
+    /// `processing.forward_only_changes`, and after how many seconds an unchanged value goes out anyway (0: never)
+    forward_only_changes: bool,
+    changes_max_age: f64,
+    /// `processing.rate_limit`: minimum intervals and debounce windows per virtual input
+    rate_limiter: Arc<RateLimiter>,
+    /// Messages above this size are dropped; 0 means no limit
+    max_message_bytes: usize,
+    /// JSON payloads nested deeper, or producing more values, are dropped; 0 means no limit
+    max_json_depth: usize,
+    max_json_keys: usize,
+    /// `processing.decompress`: inflate payloads with a zlib or gzip header
+    decompress: bool,
+    /// `processing.decompress_topics`: topics whose payloads are always inflated, raw DEFLATE included
+    decompress_topics: FilterList,
+    /// `processing.xml_topics`: topics whose XML payloads are flattened like JSON ones
+    xml_topics: FilterList,
+    /// `processing.key_value_topics`: topics with `key=value,key2=value2` payloads
+    key_value_topics: FilterList,
+    /// `processing.influx_topics`: topics with InfluxDB line protocol payloads
+    influx_topics: FilterList,
+    max_decompressed_bytes: usize,
+    skips: SkipStats,
+    stats: Arc<Stats>,
+    timings: Arc<StageTimings>,
+    metrics: Arc<Metrics>,
+    /// Serves `metrics` and `stats` to Prometheus, if `debug.prometheus_port` is set
+    _exporter: Option<Exporter>,
+    /// Sends still in flight, drained by `shutdown`
+    pending_sends: Mutex<JoinSet<()>>,
+    /// Bounds the sends in flight, with `miniserver.send_concurrency` set
+    send_queue: Arc<SendQueue>,
+    retry: RetryPolicy,
+    /// `miniserver.dead_letter`: publish sends that failed for good below `<base_topic>deadletter/`
+    dead_letter: bool,
+    /// `miniserver.outbox`: sends that failed for good, kept on disk until the Miniserver is back
+    outbox: Option<Arc<Outbox>>,
+    /// `miniserver.failover_ip`: the secondary Miniserver and the health of both
+    failover: Option<Arc<Failover>>,
+    /// The primary Miniserver as configured or as found by discovery, which never goes
+    /// back into the config
+    miniserver_address: Arc<Shared<(String, u16)>>,
+    /// `processing.stale_timeout`: inputs that got no value for that long, sent `stale_value`
+    watchdog: Option<Arc<Watchdog>>,
+    /// Set by `shutdown`; nothing new is forwarded afterwards
+    closing: AtomicBool,
+    /// While set, values run through the pipeline and are recorded, but not sent
+    paused: AtomicBool,
+    /// Publish every value after expansion to `{base_topic}processedtopics/<name>`
+    publish_processed: AtomicBool,
+    /// Publish every value sent to the Miniserver to `{base_topic}forwardedtopics/<name>`
+    publish_forwarded: AtomicBool,
+    /// Publish the trace of every data message to `{base_topic}trace/<topic>`
+    publish_trace: AtomicBool,
+    /// `debug.trace_messages`: the traces of the last data messages, for `get_traces`
+    traces: TraceLog,
+    /// `debug.capture_file`: incoming data messages recorded for `replay_capture`
+    capture: Shared<Option<Capture>>,
+    /// Set while `replay_capture` runs, so replayed messages aren't recorded again
+    replaying: Arc<AtomicBool>,
+    /// `general.homeassistant_discovery`: announces forwarded inputs, whose values then
+    /// always go to `forwardedtopics`
+    ha_discovery: Option<HaDiscovery>,
+    /// Called with (topic, value, ok, error) after each send finished
+    send_callbacks: Shared<Vec<Py<PyAny>>>,
+    /// `on_connect` and `on_disconnect` callbacks, called with (connection, error)
+    connect_hooks: Shared<Vec<Py<PyAny>>>,
+    disconnect_hooks: Shared<Vec<Py<PyAny>>>,
+    /// The reachability last published to `{base_topic}status/miniserver`
+    miniserver_reachable: Mutex<Option<bool>>,
+
+    topic_whitelist: Shared<Whitelist>,
+    /// When the whitelist was last replaced, and when by a successful Miniserver sync
+    whitelist_times: Mutex<WhitelistTimes>,
+    /// Virtual input -> control UUID, from the last `sync_whitelist_from_structure`
+    input_uuids: Mutex<BTreeMap<String, String>>,
+    /// `processing.bool_true_values` and `bool_false_values`
+    bool_table: BoolTable,
+    /// `processing.bool_exclude_topics`: topics whose values are never converted to 1/0
+    bool_exclude_topics: FilterList,
+    /// `processing.numeric_topics`: topics whose values are cut down to the number they start with
+    numeric_topics: FilterList,
+    convert_bool_cache: StringCache,
+    normalize_topic_cache: StringCache,
+    /// Results of `filtered_by`, keyed by `filter_generation` and its arguments
+    filter_decision_cache: StringCache,
+    /// Bumped on every change of the filters, rules or whitelist, so decisions made with
+    /// the previous ones are never looked up again
+    filter_generation: AtomicU64,
 
     relay_main_obj: Py<PyAny>,
     mqtt_client_obj: Py<PyAny>,
     #[pyo3(get)]
     http_handler_obj: Py<PyAny>,
-    orjson_obj: Py<PyAny>,
+    /// Sends HTTP requests to the Miniserver from Rust (`miniserver.native_http`)
+    http_sender: Option<Arc<HttpSender>>,
+    /// The handler's `native_ws` (`miniserver.native_websocket`), used while it is connected
+    lox_ws: Option<Arc<LoxWs>>,
+    /// `miniserver.default_transport`: how values are sent unless their rule has a `transport`
+    default_transport: Transport,
+    /// Virtual UDP Input destinations for `transport = "udp"` rules (`udp.udp_out_destinations`)
+    udp_forwarder: Option<Arc<UdpForwarder>>,
+    /// `miniserver.batch_mode`: values combined into one datagram or request, unless off
+    batching: Option<Arc<Batching>>,
+    /// `miniserver.targets` and the `miniserver.routes` sending values to them
+    targets: Shared<Targets>,
+    target_stats: Arc<TargetStats>,
     mqtt_topics: Option<MqttTopics>,
+    /// How topics and payloads that aren't valid UTF-8 are decoded
+    invalid_utf8: Utf8Policy,
+    /// The relay topic, always ending with '/'
     base_topic: String,
+    /// Send topics below `base_topic` that aren't control topics through `process_data`
+    forward_unknown_subtopics: bool,
 }
 
 #[pymethods]
 impl MiniserverDataProcessor {
 
     #[new]
-    #[pyo3(text_signature = "(self, global_config_py, relay_main_obj, mqtt_client_obj, http_handler_obj, orjson_obj)")]
-    fn new(py: Python, topic_ns: Py<PyAny>, global_config_py: Py<PyAny>, relay_main_obj: Py<PyAny>, mqtt_client_obj: Py<PyAny>, http_handler_obj: Py<PyAny>, orjson_obj: Py<PyAny>) -> PyResult<Self> {
+    #[pyo3(text_signature = "(self, global_config_py, relay_main_obj, mqtt_client_obj, http_handler_obj)")]
+    fn new(py: Python, topic_ns: Py<PyAny>, global_config_py: Py<PyAny>, relay_main_obj: Py<PyAny>, mqtt_client_obj: Py<PyAny>, http_handler_obj: Py<PyAny>) -> PyResult<Self> {
         debug!(
             "Initializing MiniserverDataProcessor with cache_size={}",
             pyget!(global_config_py, py, "general", "cache_size").extract::<i32>()?
         );
 
-        let compiled = compile_filters(pyget!(global_config_py, py, "topics", "subscription_filters").extract()?);
+        let strict_filters: bool = pyget!(global_config_py, py, "topics", "strict_filters").extract()?;
+        let filter_anchor = FilterAnchor::parse(
+            &pyget!(global_config_py, py, "topics", "filter_anchor").extract::<String>()?,
+        )?;
+        let filter_syntax = FilterSyntax::parse(
+            &pyget!(global_config_py, py, "topics", "filter_syntax").extract::<String>()?,
+        )?;
+        let policy = FilterPolicy::parse(
+            &pyget!(global_config_py, py, "topics", "policy").extract::<String>()?,
+        )?;
+        let compiled = compile_filters_checked(
+            pyget!(global_config_py, py, "topics", "subscription_filters").extract()?,
+            filter_anchor,
+            filter_syntax,
+            strict_filters,
+        )?;
+        let post_expansion_filters = compile_filters_checked(
+            pyget!(global_config_py, py, "topics", "post_expansion_filters").extract()?,
+            filter_anchor,
+            filter_syntax,
+            strict_filters,
+        )?;
+        let rules = parse_rules(
+            &pyget!(global_config_py, py, "topics", "rules"),
+            strict_filters,
+        )?;
+        if pyget!(global_config_py, py, "topics", "unified_rules").extract::<bool>()? {
+            warn!("topics.unified_rules is deprecated and has no effect: rules always run first and the filter lists decide about the values no rule decides about");
+        }
+        let rewrites = parse_rewrites(
+            &pyget!(global_config_py, py, "topics", "rewrites"),
+            strict_filters,
+        )?;
+        let payload_formats = parse_payload_formats(
+            &pyget!(global_config_py, py, "processing", "payload_formats"),
+            strict_filters,
+        )?;
+        let extractions = parse_extractions(&pyget!(global_config_py, py, "processing", "extract"), strict_filters)?;
+        let transforms = parse_transforms(&pyget!(global_config_py, py, "processing", "transforms"))?;
+        let derived = parse_derived(&pyget!(global_config_py, py, "processing", "derived"))?;
+        let timestamps = parse_timestamp_rules(&pyget!(global_config_py, py, "processing", "timestamps"))?;
+        let flatten = FlattenOptions::parse(
+            &pyget!(global_config_py, py, "processing", "flatten_separator").extract::<String>()?,
+            pyget!(global_config_py, py, "processing", "flatten_max_depth").extract()?,
+            &pyget!(global_config_py, py, "processing", "flatten_arrays").extract::<String>()?,
+        )?;
+        let scripts = parse_scripts(
+            &pyget!(global_config_py, py, "processing", "scripts"),
+            pyget!(global_config_py, py, "processing", "script_max_operations").extract()?,
+            pyget!(global_config_py, py, "processing", "script_timeout_ms").extract()?,
+        )?;
+        let plugins = parse_plugins(
+            &pyget!(global_config_py, py, "processing", "plugins"),
+            pyget!(global_config_py, py, "processing", "plugin_fuel").extract()?,
+        )?;
+        let templates = parse_templates(
+            pyget!(global_config_py, py, "processing", "payload_templates").extract()?,
+            flatten.clone(),
+        )?;
+        let do_not_forward = compile_filters_checked(
+            pyget!(global_config_py, py, "topics", "do_not_forward").extract()?,
+            FilterAnchor::None,
+            filter_syntax,
+            strict_filters,
+        )?;
+        let monitor_topics = compile_filters_checked(
+            pyget!(global_config_py, py, "topics", "monitor_topics").extract()?,
+            filter_anchor,
+            filter_syntax,
+            strict_filters,
+        )?;
         let cache_size = if pyget!(global_config_py, py, "general", "cache_size").extract::<i32>()? == 0 {
             64
         } else {
             pyget!(global_config_py, py, "general", "cache_size").extract()? 
         };
         let lru_size = NonZeroUsize::new(cache_size).unwrap();
-        let base_topic: String = pyget!(global_config_py, py, "general", "base_topic").extract()?;
+        let mut base_topic: String = pyget!(global_config_py, py, "general", "relay_topic").extract()?;
+        // Control topics are below base_topic as a whole segment: "myrelay" must not claim "myrelay2/foo"
+        if !base_topic.is_empty() && !base_topic.ends_with('/') {
+            base_topic.push('/');
+        }
+        let decompress_topics = compile_filters_checked(
+            pyget!(global_config_py, py, "processing", "decompress_topics").extract()?,
+            FilterAnchor::None,
+            filter_syntax,
+            strict_filters,
+        )?;
+        let xml_topics = compile_filters_checked(
+            pyget!(global_config_py, py, "processing", "xml_topics").extract()?,
+            FilterAnchor::None,
+            filter_syntax,
+            strict_filters,
+        )?;
+        let key_value_topics = compile_filters_checked(
+            pyget!(global_config_py, py, "processing", "key_value_topics").extract()?,
+            FilterAnchor::None,
+            filter_syntax,
+            strict_filters,
+        )?;
+        let influx_topics = compile_filters_checked(
+            pyget!(global_config_py, py, "processing", "influx_topics").extract()?,
+            FilterAnchor::None,
+            filter_syntax,
+            strict_filters,
+        )?;
+        let bool_exclude_topics = compile_filters_checked(
+            pyget!(global_config_py, py, "processing", "bool_exclude_topics").extract()?,
+            FilterAnchor::None,
+            filter_syntax,
+            strict_filters,
+        )?;
+        let numeric_topics = compile_filters_checked(
+            pyget!(global_config_py, py, "processing", "numeric_topics").extract()?,
+            FilterAnchor::None,
+            filter_syntax,
+            strict_filters,
+        )?;
+        let bool_table = BoolTable::new(
+            pyget!(global_config_py, py, "processing", "bool_true_values").extract()?,
+            pyget!(global_config_py, py, "processing", "bool_false_values").extract()?,
+        )?;
+        let invalid_utf8 = Utf8Policy::parse(
+            &pyget!(global_config_py, py, "processing", "invalid_utf8").extract::<String>()?,
+        )?;
+        let forward_unknown_subtopics: bool = pyget!(global_config_py, py, "general", "forward_unknown_subtopics").extract()?;
+        let lox_ws = if pyget!(global_config_py, py, "miniserver", "use_websocket").extract::<bool>()? {
+            http_handler_obj
+                .bind(py)
+                .getattr(intern!(py, "native_ws"))
+                .ok()
+                .and_then(|ws| ws.cast::<LoxWsClient>().ok().map(|ws| Arc::clone(&ws.borrow().inner)))
+        } else {
+            None
+        };
+        let state_mappings = parse_state_mappings(&pyget!(global_config_py, py, "miniserver", "state_mappings"))?;
+        if pyget!(global_config_py, py, "miniserver", "publish_states").extract::<bool>()? {
+            match &lox_ws {
+                Some(ws) => {
+                    let publisher = StatePublisher {
+                        mqtt_client: mqtt_client_obj.clone_ref(py),
+                        prefix: format!("{}miniserver/", base_topic),
+                        mappings: state_mappings,
+                        unmapped: pyget!(global_config_py, py, "miniserver", "publish_unmapped_states").extract()?,
+                        retain: pyget!(global_config_py, py, "miniserver", "retain_states").extract()?,
+                    };
+                    ws.set_state_listener(Arc::new(move |updates| publisher.publish(updates)));
+                }
+                None => warn!("publish_states needs the native websocket (miniserver.native_websocket and use_websocket)"),
+            }
+        }
+        let miniserver_tls = miniserver_tls(py, &global_config_py)?;
+        let http_sender = if !pyget!(global_config_py, py, "miniserver", "native_http").extract::<bool>()? {
+            None
+        } else if pyget!(global_config_py, py, "miniserver", "use_websocket").extract::<bool>()?
+            || pyget!(global_config_py, py, "miniserver", "miniserver_token_auth").extract::<bool>()?
+            || !pyget!(global_config_py, py, "miniserver", "miniserver_proxy").extract::<String>()?.is_empty()
+            || pyget!(global_config_py, py, "miniserver", "miniserver_proxy_from_env").extract::<bool>()?
+        {
+            warn!("native_http only covers HTTP(S) with basic auth; websocket, token auth and proxies use the Python sender");
+            None
+        } else {
+            Some(Arc::new(HttpSender::new(
+                &pyget!(global_config_py, py, "miniserver", "miniserver_user").extract::<String>()?,
+                &pyget!(global_config_py, py, "miniserver", "miniserver_pass").extract::<String>()?,
+                pyget!(global_config_py, py, "miniserver", "miniserver_max_parallel_connections").extract()?,
+                miniserver_tls.clone(),
+            )))
+        };
+        let udp_destinations: Vec<String> = pyget!(global_config_py, py, "udp", "udp_out_destinations").extract()?;
+        let udp_forwarder = if udp_destinations.is_empty() { None } else { Some(Arc::new(UdpForwarder::new(&udp_destinations)?)) };
+        let batching = Batching::new(
+            BatchMode::parse(&pyget!(global_config_py, py, "miniserver", "batch_mode").extract::<String>()?)?,
+            pyget!(global_config_py, py, "miniserver", "batch_window_ms").extract()?,
+            pyget!(global_config_py, py, "miniserver", "batch_max_values").extract()?,
+            pyget!(global_config_py, py, "miniserver", "batch_input").extract()?,
+            pyget!(global_config_py, py, "miniserver", "batch_separator").extract()?,
+        );
+        if batching.as_ref().is_some_and(|b| b.input.is_empty()) && udp_forwarder.is_none() {
+            warn!("batch_mode has no effect without batch_input or udp_out_destinations");
+        }
+        rules.check_udp_destinations(udp_forwarder.is_some())?;
+        let default_transport = Transport::parse(&pyget!(global_config_py, py, "miniserver", "default_transport").extract::<String>()?)?;
+        if default_transport == Transport::Udp && udp_forwarder.is_none() {
+            return Err(PyValueError::new_err("default_transport is 'udp', but udp.udp_out_destinations is empty"));
+        }
+        let targets = parse_targets(
+            &pyget!(global_config_py, py, "miniserver", "targets"),
+            &pyget!(global_config_py, py, "miniserver", "routes"),
+            pyget!(global_config_py, py, "miniserver", "miniserver_max_parallel_connections").extract()?,
+        )?;
         let start_ui_topic: String = topic_ns.bind(py).getattr(intern!(py, "START_UI"))?.extract()?;
         let stop_ui_topic: String = topic_ns.bind(py).getattr(intern!(py, "STOP_UI"))?.extract()?;
         let miniserver_startup_topic: String = topic_ns.bind(py).getattr(intern!(py, "MINISERVER_STARTUP_EVENT"))?.extract()?;
@@ -195,6 +645,27 @@ impl MiniserverDataProcessor {
         let config_remove_topic: String = topic_ns.bind(py).getattr(intern!(py, "CONFIG_REMOVE"))?.extract()?;
         let config_update_topic: String = topic_ns.bind(py).getattr(intern!(py, "CONFIG_UPDATE"))?.extract()?;
         let config_restart_topic: String = topic_ns.bind(py).getattr(intern!(py, "CONFIG_RESTART"))?.extract()?;
+        let config_validate_topic: String = topic_ns.bind(py).getattr(intern!(py, "CONFIG_VALIDATE"))?.extract()?;
+        let config_validate_response_topic: String = topic_ns.bind(py).getattr(intern!(py, "CONFIG_VALIDATE_RESPONSE"))?.extract()?;
+        let filters_set_topic: String = topic_ns.bind(py).getattr(intern!(py, "FILTERS_SET"))?.extract()?;
+        let dnf_set_topic: String = topic_ns.bind(py).getattr(intern!(py, "DNF_SET"))?.extract()?;
+        let whitelist_add_topic: String = topic_ns.bind(py).getattr(intern!(py, "WHITELIST_ADD"))?.extract()?;
+        let whitelist_remove_topic: String = topic_ns.bind(py).getattr(intern!(py, "WHITELIST_REMOVE"))?.extract()?;
+        let whitelist_get_topic: String = topic_ns.bind(py).getattr(intern!(py, "WHITELIST_GET"))?.extract()?;
+        let whitelist_response_topic: String = topic_ns.bind(py).getattr(intern!(py, "WHITELIST_RESPONSE"))?.extract()?;
+        let stats_get_topic: String = topic_ns.bind(py).getattr(intern!(py, "STATS_GET"))?.extract()?;
+        let stats_reset_topic: String = topic_ns.bind(py).getattr(intern!(py, "STATS_RESET"))?.extract()?;
+        let stats_response_topic: String = topic_ns.bind(py).getattr(intern!(py, "STATS_RESPONSE"))?.extract()?;
+        let debug_set_topic: String = topic_ns.bind(py).getattr(intern!(py, "DEBUG_SET"))?.extract()?;
+        let ha_lock_topic: String = topic_ns.bind(py).getattr(intern!(py, "HA_LOCK"))?.extract()?;
+        let export_topic: String = topic_ns.bind(py).getattr(intern!(py, "EXPORT_VI"))?.extract()?;
+        let delivery_get_topic: String = topic_ns.bind(py).getattr(intern!(py, "DELIVERY_GET"))?.extract()?;
+        let delivery_response_topic: String = topic_ns.bind(py).getattr(intern!(py, "DELIVERY_RESPONSE"))?.extract()?;
+        let filters_test_topic: String = topic_ns.bind(py).getattr(intern!(py, "FILTERS_TEST"))?.extract()?;
+        let filters_test_response_topic: String = topic_ns.bind(py).getattr(intern!(py, "FILTERS_TEST_RESPONSE"))?.extract()?;
+        let pause_topic: String = topic_ns.bind(py).getattr(intern!(py, "PAUSE"))?.extract()?;
+        let resume_topic: String = topic_ns.bind(py).getattr(intern!(py, "RESUME"))?.extract()?;
+        let state_topic: String = topic_ns.bind(py).getattr(intern!(py, "STATE"))?.extract()?;
 
         let topics = MqttTopics {
             start_ui_topic,
@@ -207,88 +678,641 @@ impl MiniserverDataProcessor {
             config_remove_topic,
             config_update_topic,
             config_restart_topic,
+            config_validate_topic,
+            config_validate_response_topic,
+            filters_set_topic,
+            dnf_set_topic,
+            whitelist_add_topic,
+            whitelist_remove_topic,
+            whitelist_get_topic,
+            whitelist_response_topic,
+            stats_get_topic,
+            stats_reset_topic,
+            stats_response_topic,
+            debug_set_topic,
+            ha_lock_topic,
+            export_topic,
+            delivery_get_topic,
+            delivery_response_topic,
+            filters_test_topic,
+            filters_test_response_topic,
+            pause_topic,
+            resume_topic,
+            state_topic,
         };
         // processor.mqtt_topics = Some(topics);
 
 
+        let stats = Arc::new(Stats::default());
+        let metrics = Arc::new(Metrics::new(lru_size));
+        let last_values = Arc::new(LastValueStore::new(lru_size));
+        let convert_bool_cache: StringCache = Arc::new(LookupCache::new(lru_size));
+        let normalize_topic_cache: StringCache = Arc::new(LookupCache::new(lru_size));
+        let filter_decision_cache: StringCache = Arc::new(LookupCache::new(lru_size));
+        let send_queue = Arc::new(SendQueue::new(
+            pyget!(global_config_py, py, "miniserver", "send_concurrency").extract()?,
+            pyget!(global_config_py, py, "miniserver", "send_queue_size").extract()?,
+            Overflow::parse(&pyget!(global_config_py, py, "miniserver", "send_queue_overflow").extract::<String>()?)?,
+        ));
+        let outbox = if pyget!(global_config_py, py, "miniserver", "outbox").extract::<bool>()? {
+            let mut path: String = pyget!(global_config_py, py, "miniserver", "outbox_path").extract()?;
+            if path.is_empty() {
+                // Next to the config, like vi_names.json
+                let config_path: String = global_config_py.bind(py).getattr(intern!(py, "config_path"))?.extract()?;
+                path = std::path::Path::new(&config_path).with_file_name("outbox.jsonl").to_string_lossy().into_owned();
+            }
+            Some(Arc::new(Outbox::open(
+                path,
+                Replay::parse(&pyget!(global_config_py, py, "miniserver", "outbox_replay").extract::<String>()?)?,
+                pyget!(global_config_py, py, "miniserver", "outbox_max_entries").extract()?,
+                pyget!(global_config_py, py, "miniserver", "outbox_max_age").extract()?,
+            )?))
+        } else {
+            None
+        };
+        let stale_timeout: f64 = pyget!(global_config_py, py, "processing", "stale_timeout").extract()?;
+        let watchdog = if stale_timeout > 0.0 {
+            Some(Arc::new(Watchdog::new(
+                Duration::try_from_secs_f64(stale_timeout).map_err(|e| PyValueError::new_err(format!("Invalid stale_timeout: {}", e)))?,
+                pyget!(global_config_py, py, "processing", "stale_value").extract()?,
+                compile_filters_checked(
+                    pyget!(global_config_py, py, "processing", "stale_topics").extract()?,
+                    FilterAnchor::None,
+                    filter_syntax,
+                    strict_filters,
+                )?,
+            )))
+        } else {
+            None
+        };
+        let failover_ip: String = pyget!(global_config_py, py, "miniserver", "failover_ip").extract()?;
+        let failover = if failover_ip.trim().is_empty() {
+            None
+        } else {
+            let (host, port): (String, u16) = split_host_port(py, &failover_ip, pyget!(global_config_py, py, "miniserver", "failover_port").extract()?)?;
+            Failover::new(
+                host,
+                port,
+                pyget!(global_config_py, py, "miniserver", "health_check_interval").extract()?,
+                pyget!(global_config_py, py, "miniserver", "health_check_failures").extract()?,
+                miniserver_tls.clone(),
+            )
+            .map(Arc::new)
+        };
+        let prometheus_port: u16 = pyget!(global_config_py, py, "debug", "prometheus_port").extract()?;
+        let exporter = if prometheus_port == 0 {
+            None
+        } else {
+            let sources = Sources {
+                stats: Arc::clone(&stats),
+                metrics: Arc::clone(&metrics),
+                last_values: Arc::clone(&last_values),
+                send_queue: Arc::clone(&send_queue),
+                outbox: outbox.clone(),
+                lox_ws: lox_ws.clone(),
+                caches: vec![
+                    ("normalize_topic", Arc::clone(&normalize_topic_cache)),
+                    ("convert_boolean", Arc::clone(&convert_bool_cache)),
+                    ("filter_decision", Arc::clone(&filter_decision_cache)),
+                ],
+            };
+            let host: String = pyget!(global_config_py, py, "debug", "prometheus_host").extract()?;
+            Some(Exporter::start(&host, prometheus_port, sources)?)
+        };
         let processor = MiniserverDataProcessor {
-            compiled_subscription_filter: compiled,
-            do_not_forward_patterns: None,
-            topic_whitelist: pyget!(global_config_py, py, "topics", "topic_whitelist")
-                .extract::<Vec<String>>()?
-                .into_iter()
-                .collect(),
-            convert_bool_cache: Mutex::new(LruCache::new(lru_size)),
-            normalize_topic_cache: Mutex::new(LruCache::new(lru_size)),
+            subscription_filters: Shared::new(compiled),
+            filter_flattened_keys: pyget!(global_config_py, py, "topics", "filter_flattened_keys").extract()?,
+            post_expansion_filters: Shared::new(post_expansion_filters),
+            do_not_forward_patterns: Shared::new(do_not_forward),
+            monitor_topics: Shared::new(monitor_topics),
+            strict_filters,
+            filter_anchor,
+            filter_syntax,
+            policy,
+            rules: Shared::new(rules),
+            rewrites: Shared::new(rewrites),
+            transforms: Shared::new(transforms),
+            derived: Shared::new(derived),
+            timestamps: Shared::new(timestamps),
+            payload_formats,
+            extractions,
+            flatten,
+            vi_names: ViNameLimiter::new(pyget!(global_config_py, py, "topics", "max_name_length").extract()?),
+            name_normalizer: NameNormalizer::new(
+                &pyget!(global_config_py, py, "topics", "name_replacement").extract::<String>()?,
+                &pyget!(global_config_py, py, "topics", "name_replace_chars").extract::<String>()?,
+                pyget!(global_config_py, py, "topics", "name_lowercase").extract()?,
+            )?,
+            active: AtomicBool::new(!pyget!(global_config_py, py, "ha", "ha_enabled").extract::<bool>()?),
+            expand_json: AtomicBool::new(pyget!(global_config_py, py, "processing", "expand_json").extract()?),
+            strip_prefixes: Shared::new(pyget!(global_config_py, py, "topics", "strip_prefixes").extract()?),
+            scripts: Shared::new(scripts),
+            plugins,
+            templates,
+            forwarded_inputs: Mutex::new(HashMap::new()),
+            last_values,
+            connection_states: ConnectionStates::default(),
+            duplicate_window: pyget!(global_config_py, py, "processing", "duplicate_window_seconds").extract()?,
+            forward_only_changes: pyget!(global_config_py, py, "processing", "forward_only_changes").extract()?,
+            changes_max_age: pyget!(global_config_py, py, "processing", "forward_only_changes_max_age").extract()?,
+            rate_limiter: Arc::new(parse_rate_limits(
+                &pyget!(global_config_py, py, "processing", "rate_limit"),
+                strict_filters,
+            )?),
+            max_message_bytes: pyget!(global_config_py, py, "processing", "max_message_bytes").extract()?,
+            max_json_depth: pyget!(global_config_py, py, "processing", "max_json_depth").extract()?,
+            max_json_keys: pyget!(global_config_py, py, "processing", "max_json_keys").extract()?,
+            decompress: pyget!(global_config_py, py, "processing", "decompress").extract()?,
+            decompress_topics,
+            xml_topics,
+            key_value_topics,
+            influx_topics,
+            max_decompressed_bytes: pyget!(global_config_py, py, "processing", "max_decompressed_bytes").extract()?,
+            skips: SkipStats::default(),
+            stats,
+            timings: Arc::new(StageTimings::new(pyget!(global_config_py, py, "debug", "stage_timing").extract()?)),
+            metrics,
+            _exporter: exporter,
+            pending_sends: Mutex::new(JoinSet::new()),
+            send_queue,
+            retry: RetryPolicy::new(
+                pyget!(global_config_py, py, "miniserver", "send_retries").extract()?,
+                pyget!(global_config_py, py, "miniserver", "send_retry_backoff").extract()?,
+                pyget!(global_config_py, py, "miniserver", "send_retry_max_backoff").extract()?,
+                pyget!(global_config_py, py, "miniserver", "send_retry_jitter").extract()?,
+            )?,
+            dead_letter: pyget!(global_config_py, py, "miniserver", "dead_letter").extract()?,
+            outbox,
+            failover,
+            miniserver_address: Arc::new(Shared::new((
+                pyget!(global_config_py, py, "miniserver", "miniserver_ip").extract()?,
+                pyget!(global_config_py, py, "miniserver", "miniserver_port").extract()?,
+            ))),
+            watchdog,
+            closing: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            publish_processed: AtomicBool::new(
+                pyget!(global_config_py, py, "debug", "publish_processed_topics").extract()?,
+            ),
+            publish_forwarded: AtomicBool::new(
+                pyget!(global_config_py, py, "debug", "publish_forwarded_topics").extract()?,
+            ),
+            publish_trace: AtomicBool::new(pyget!(global_config_py, py, "debug", "publish_trace").extract()?),
+            traces: TraceLog::new(pyget!(global_config_py, py, "debug", "trace_messages").extract()?),
+            capture: Shared::new(match pyget!(global_config_py, py, "debug", "capture_file").extract::<String>()?.trim() {
+                "" => None,
+                path => Some(Capture::open(path, pyget!(global_config_py, py, "debug", "capture_max_bytes").extract()?)?),
+            }),
+            replaying: Arc::new(AtomicBool::new(false)),
+            ha_discovery: if pyget!(global_config_py, py, "general", "homeassistant_discovery").extract::<bool>()? {
+                Some(HaDiscovery::new(
+                    mqtt_client_obj.clone_ref(py),
+                    &pyget!(global_config_py, py, "general", "homeassistant_prefix").extract::<String>()?,
+                    &base_topic,
+                ))
+            } else {
+                None
+            },
+            send_callbacks: Shared::new(Vec::new()),
+            connect_hooks: Shared::new(Vec::new()),
+            disconnect_hooks: Shared::new(Vec::new()),
+            miniserver_reachable: Mutex::new(None),
+            topic_whitelist: Shared::new(Whitelist::new(
+                // A list when loaded from TOML, a set by default
+                pyget!(global_config_py, py, "topics", "topic_whitelist")
+                    .try_iter()?
+                    .map(|topic| topic?.extract::<String>())
+                    .collect::<PyResult<_>>()?,
+            )),
+            whitelist_times: Mutex::new(WhitelistTimes::default()),
+            input_uuids: Mutex::new(BTreeMap::new()),
+            bool_table,
+            bool_exclude_topics,
+            numeric_topics,
+            convert_bool_cache,
+            normalize_topic_cache,
+            filter_decision_cache,
+            filter_generation: AtomicU64::new(0),
             global_config: global_config_py,
             mqtt_topics: Some(topics),
             relay_main_obj,
             mqtt_client_obj,
             http_handler_obj,
-            orjson_obj,
-            base_topic:base_topic,
+            http_sender,
+            lox_ws,
+            default_transport,
+            udp_forwarder,
+            batching: batching.map(Arc::new),
+            targets: Shared::new(targets),
+            target_stats: Arc::default(),
+            invalid_utf8,
+            base_topic,
+            forward_unknown_subtopics,
         };
 
+        panic_hook::register(
+            py,
+            processor.mqtt_client_obj.clone_ref(py),
+            format!("{}errors/panic", processor.base_topic),
+        );
+        let whitelist = processor.whitelist_names(processor.topic_whitelist.get().names.iter().cloned())?;
+        processor.set_whitelist(whitelist);
   
         debug!("MiniserverDataProcessor initialization complete");
         Ok(processor)
     }
 
+    #[pyo3(text_signature = "(self, prefixes)")]
+    fn update_strip_prefixes(&self, prefixes: Vec<String>) {
+        debug!("Updating strip prefixes: {:?}", prefixes);
+        self.strip_prefixes.set(prefixes);
+    }
+
     #[pyo3(text_signature = "(self, filters)")]
-    fn update_subscription_filters(&mut self, filters: Vec<String>) {
+    fn update_subscription_filters(&self, filters: Vec<String>) -> PyResult<()> {
         debug!("Updating subscription filters: {:?}", filters);
-        self.compiled_subscription_filter = compile_filters(filters);
+        self.subscription_filters.set(compile_filters_checked(filters, self.filter_anchor, self.filter_syntax, self.strict_filters)?);
+        self.retire_filter_decisions();
+        Ok(())
     }
 
-    #[pyo3(text_signature = "(self, whitelist)")]
-    fn update_topic_whitelist(&mut self, whitelist: Vec<String>) {
-        let set: HashSet<String> = whitelist.into_iter().collect();
+    /// Replace the whitelist; `synced` marks it as the result of a Miniserver sync.
+    #[pyo3(signature = (whitelist, synced=false))]
+    fn update_topic_whitelist(&self, py: Python<'_>, whitelist: Vec<String>, synced: bool) -> PyResult<()> {
+        let set = self.whitelist_names(whitelist)?;
         debug!("Updating topic whitelist: {:?}", set);
-        self.topic_whitelist = set;
+        self.set_whitelist(set);
+        self.retract_unlisted(py);
+        let at = unix_now();
+        let mut times = self.whitelist_times.lock().unwrap();
+        times.updated_at = Some(at);
+        if synced {
+            times.synced_at = Some(at);
+        }
+        Ok(())
+    }
+
+    /// Replace the whitelist with the virtual inputs of a `LoxAPP3.json` structure file
+    /// (bytes or str), as a Miniserver sync; `control_types` defaults to the types of
+    /// virtual inputs, an empty list takes every control. Returns the names, sorted, and
+    /// keeps their control UUIDs for `get_input_uuids`.
+    #[pyo3(signature = (structure, control_types=None))]
+    fn sync_whitelist_from_structure(
+        &self,
+        py: Python<'_>,
+        structure: &Bound<'_, PyAny>,
+        control_types: Option<Vec<String>>,
+    ) -> PyResult<Vec<String>> {
+        let types = control_types
+            .unwrap_or_else(|| structure::VIRTUAL_INPUT_TYPES.iter().map(|kind| kind.to_string()).collect());
+        let inputs = if let Ok(text) = structure.cast::<PyString>() {
+            structure::virtual_inputs(text.to_str()?.as_bytes(), &types)?
+        } else {
+            structure::virtual_inputs(&structure.extract::<Cow<'_, [u8]>>()?, &types)?
+        };
+        info!("Structure file lists {} virtual inputs", inputs.len());
+        let names: Vec<String> = inputs.keys().cloned().collect();
+        self.update_topic_whitelist(py, names.clone(), true)?;
+        *self.input_uuids.lock().unwrap() = inputs;
+        Ok(names)
+    }
+
+    /// Virtual input -> control UUID from the last `sync_whitelist_from_structure`.
+    fn get_input_uuids(&self) -> BTreeMap<String, String> {
+        self.input_uuids.lock().unwrap().clone()
+    }
+
+    #[getter]
+    fn topic_whitelist(&self) -> HashSet<String> {
+        self.topic_whitelist.get().names.clone()
     }
 
     #[pyo3(text_signature = "(self, filters)")]
-    fn update_do_not_forward(&mut self, filters: Vec<String>) {
+    fn update_post_expansion_filters(&self, filters: Vec<String>) -> PyResult<()> {
+        debug!("Updating post-expansion filters: {:?}", filters);
+        self.post_expansion_filters.set(compile_filters_checked(filters, self.filter_anchor, self.filter_syntax, self.strict_filters)?);
+        self.retire_filter_decisions();
+        Ok(())
+    }
+
+    #[pyo3(text_signature = "(self, filters)")]
+    fn update_do_not_forward(&self, filters: Vec<String>) -> PyResult<()> {
         debug!("Updating do_not_forward filters: {:?}", filters);
-        self.do_not_forward_patterns = compile_filters(filters);
+        self.do_not_forward_patterns.set(compile_filters_checked(filters, FilterAnchor::None, self.filter_syntax, self.strict_filters)?);
+        self.retire_filter_decisions();
+        Ok(())
+    }
+
+    #[pyo3(text_signature = "(self, filters)")]
+    fn update_monitor_topics(&self, filters: Vec<String>) -> PyResult<()> {
+        debug!("Updating monitor topics: {:?}", filters);
+        self.monitor_topics.set(compile_filters_checked(filters, self.filter_anchor, self.filter_syntax, self.strict_filters)?);
+        Ok(())
     }
 
     
 
     #[pyo3(text_signature = "(self, val)")]
     fn _convert_boolean(&self, val: &str) -> PyResult<Option<String>> {
-        let mut cache = self.convert_bool_cache.lock().unwrap();
-        if let Some(cached) = cache.get(val) {
-            return Ok(Some(cached.clone()));
+        let cache = &self.convert_bool_cache;
+        let cached = cache.get(val);
+        self.metrics.cache(Cache::ConvertBoolean, cached.is_some());
+        if let Some(cached) = cached {
+            return Ok(Some(cached));
         }
         if val.is_empty() {
             return Ok(Some(val.to_string()));
         }
         let normalized = val.trim().to_lowercase();
-        if let Some(mapped) = convert_boolean_str(&normalized) {
-            cache.put(val.to_string(), mapped.to_string());
+        if let Some(mapped) = self.bool_table.convert(&normalized) {
+            self.metrics.cache_put(Cache::ConvertBoolean, cache, val.to_string(), mapped.to_string());
             Ok(Some(mapped.to_string()))
         } else {
-            cache.put(val.to_string(), val.to_string());
+            self.metrics.cache_put(Cache::ConvertBoolean, cache, val.to_string(), val.to_string());
             Ok(Some(val.to_string()))
         }
     }
 
     #[pyo3(text_signature = "(self, topic)")]
     fn normalize_topic(&self, topic: &str) -> PyResult<String> {
-        let mut cache = self.normalize_topic_cache.lock().unwrap();
-        if let Some(cached) = cache.get(topic) {
-            return Ok(cached.clone());
-        }
-        if !topic.contains('/') && !topic.contains('%') {
-            cache.put(topic.to_string(), topic.to_string());
-            return Ok(topic.to_string());
+        let cache = &self.normalize_topic_cache;
+        let cached = cache.get(topic);
+        self.metrics.cache(Cache::NormalizeTopic, cached.is_some());
+        if let Some(cached) = cached {
+            return Ok(cached);
         }
-        let normalized = topic.replace('/', "_").replace('%', "_");
-        cache.put(topic.to_string(), normalized.clone());
+        let normalized = self.name_normalizer.normalize(topic).into_owned();
+        self.metrics.cache_put(Cache::NormalizeTopic, cache, topic.to_string(), normalized.clone());
         Ok(normalized)
     }
 
+    /// Pre-populate the normalization and filter decision caches, so the flood of retained
+    /// messages after a reconnect hits warm caches. `topics` are raw MQTT topics, e.g. the
+    /// `seen_topics()` of a previous run; without them, the topics of the last-value store
+    /// and the whitelist entries are used.
+    /// Returns the number of topics that were added.
+    #[pyo3(signature = (topics=None))]
+    fn warm_up_caches(&self, topics: Option<Vec<String>>) -> PyResult<usize> {
+        let topics = topics.unwrap_or_else(|| {
+            let mut topics = self.last_values.topics();
+            topics.extend(self.topic_whitelist.get().names.iter().cloned());
+            topics
+        });
+        let mut warmed = 0;
+        for topic in topics {
+            if topic.is_empty() {
+                continue;
+            }
+            let name = self.virtual_input_name(&topic)?;
+            self.filtered_by(&topic, &topic, &name);
+            warmed += 1;
+        }
+        debug!("Warmed up normalization and filter decision caches with {} topics", warmed);
+        Ok(warmed)
+    }
+
+    /// Raw topics currently held in the normalization cache, most recently used first.
+    #[pyo3(text_signature = "(self)")]
+    fn cached_topics(&self) -> Vec<String> {
+        self.normalize_topic_cache.keys()
+    }
+
+    /// Raw MQTT topics whose filter decision is cached, most recently used first. Passed to
+    /// `warm_up_caches` on the next start, they make its first messages cache hits.
+    #[pyo3(text_signature = "(self)")]
+    fn seen_topics(&self) -> Vec<String> {
+        let mut seen = HashSet::new();
+        self.filter_decision_cache
+            .keys()
+            .into_iter()
+            .filter_map(|key| {
+                // Only whole-message decisions; flattened keys are derived from the payload
+                let mut parts = key.splitn(4, '\0').skip(1);
+                let (original, t) = (parts.next()?, parts.next()?);
+                (original == t && seen.insert(original.to_string())).then(|| original.to_string())
+            })
+            .collect()
+    }
+
+    /// Cache name -> `{size, capacity, hits, misses, evictions, hit_rate}` for the
+    /// normalization and boolean conversion caches, counted since the start or the last
+    /// `get_metrics(reset=True)`.
+    #[pyo3(text_signature = "(self)")]
+    fn cache_stats<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let stats: Map<String, Value> = self
+            .metrics
+            .cache_lookups()
+            .into_iter()
+            .map(|(name, hits, misses, evictions)| {
+                let cache = self.lookup_cache(name);
+                let lookups = hits + misses;
+                let hit_rate = if lookups > 0 { hits as f64 / lookups as f64 } else { 0.0 };
+                let entry = json!({
+                    "size": cache.len(),
+                    "capacity": cache.capacity(),
+                    "hits": hits,
+                    "misses": misses,
+                    "evictions": evictions,
+                    "hit_rate": hit_rate,
+                });
+                (name.to_string(), entry)
+            })
+            .collect();
+        py_json::to_py(py, &Value::Object(stats))
+    }
+
+    /// Change the capacity of the lookup caches to `size` entries, dropping the least
+    /// recently used ones if they hold more. Not saved; `general.cache_size` applies again
+    /// on the next start.
+    #[pyo3(text_signature = "(self, size)")]
+    fn resize_caches(&self, size: usize) -> PyResult<()> {
+        let size = NonZeroUsize::new(size).ok_or_else(|| PyValueError::new_err("Cache size must be at least 1"))?;
+        info!("Resizing lookup caches to {} entries", size);
+        self.convert_bool_cache.resize(size);
+        self.normalize_topic_cache.resize(size);
+        self.filter_decision_cache.resize(size);
+        Ok(())
+    }
+
+    /// Empty the lookup caches; their counters are kept.
+    #[pyo3(text_signature = "(self)")]
+    fn clear_caches(&self) {
+        info!("Clearing lookup caches");
+        self.convert_bool_cache.clear();
+        self.normalize_topic_cache.clear();
+        self.filter_decision_cache.clear();
+    }
+
+    /// Full virtual input name -> shortened name for every name that exceeded
+    /// `topics.max_name_length` so far.
+    #[pyo3(text_signature = "(self)")]
+    fn get_vi_name_mapping(&self) -> HashMap<String, String> {
+        self.vi_names.mapping()
+    }
+
+    /// Virtual input name -> last forwarded value with its send id and delivery outcome
+    /// (`pending`, `delivered`, `failed`, `queued` or `unconfirmed`), optionally only for
+    /// one virtual input name or source topic.
+    #[pyo3(signature = (filter=None))]
+    fn get_delivery_status<'py>(&self, py: Python<'py>, filter: Option<&str>) -> PyResult<Bound<'py, PyDict>> {
+        self.last_values.to_py(py, filter)
+    }
+
+    /// Counters per skip reason and the most recent skipped messages (payloads redacted).
+    #[pyo3(text_signature = "(self)")]
+    fn get_skip_stats<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        self.skips.to_py(py)
+    }
+
+    #[pyo3(text_signature = "(self)")]
+    fn reset_skip_stats(&self) {
+        self.skips.reset();
+    }
+
+    /// Message counters since the start or the last `reset_stats`, with the skip counts.
+    #[pyo3(text_signature = "(self)")]
+    fn get_stats<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        py_json::to_py(py, &self.stats_snapshot(false))
+    }
+
+    /// Reset the counters (and skip counts) and return their final values, read and
+    /// cleared in one step.
+    #[pyo3(text_signature = "(self)")]
+    fn reset_stats<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        py_json::to_py(py, &self.stats_snapshot(true))
+    }
+
+    /// Processing metrics: `{enabled, stages, counters, topics, caches, send_queue}`.
+    ///
+    /// `stages` holds per-stage duration histograms (decode, parse, flatten, filter,
+    /// convert, dispatch and the whole message), each `{count, mean_us, max_us, p50_us,
+    /// p90_us, p99_us, buckets}`, only recorded while `stage_timing` is on. `counters` is
+    /// `get_stats()`, `topics` the received/filtered/forwarded counts per MQTT topic,
+    /// `caches` the hit rates of the lookup caches and `send_queue` the sends waiting for
+    /// and running in a worker, `websocket_token` the expiry and refreshes of the native
+    /// websocket's token (`None` without it). `reset` clears the topics, caches and stages
+    /// as they are read; the counters belong to `reset_stats`.
+    #[pyo3(signature = (reset=false))]
+    fn get_metrics<'py>(&self, py: Python<'py>, reset: bool) -> PyResult<Bound<'py, PyAny>> {
+        let mut metrics = self.metrics.snapshot(reset);
+        metrics.insert("enabled".to_string(), json!(self.timings.enabled()));
+        metrics.insert("stages".to_string(), self.timings.snapshot(reset));
+        metrics.insert("counters".to_string(), self.stats.snapshot(self.skips.counts(false), false));
+        metrics.insert(
+            "send_queue".to_string(),
+            json!({"queued": self.send_queue.len(), "running": self.send_queue.running()}),
+        );
+        metrics.insert("outbox".to_string(), json!(self.outbox.as_ref().map(|outbox| outbox.len())));
+        metrics.insert("websocket_token".to_string(), json!(self.lox_ws.as_ref().map(|ws| ws.token_status())));
+        py_json::to_py(py, &Value::Object(metrics))
+    }
+
+    /// Record that `connection` ("broker" or "miniserver") is now `state`: connecting,
+    /// connected, degraded or offline. On a change the state of both links is published
+    /// retained, the Miniserver's reachability to `{base_topic}status/miniserver`, and the
+    /// `on_connect` / `on_disconnect` callbacks run when the link comes up or goes down.
+    /// Returns whether the state changed.
+    #[pyo3(signature = (connection, state, error=None))]
+    #[pyo3(text_signature = "(self, connection, state, error=None)")]
+    fn set_connection_state(&self, py: Python<'_>, connection: &str, state: &str, error: Option<String>) -> PyResult<bool> {
+        let Some(previous) = self.connection_states.transition(connection, state, error.clone())? else {
+            return Ok(false);
+        };
+        info!("Connection to {} is {}", connection, state);
+        let current = LinkState::parse(state)?;
+        // Connecting says nothing about reachability, the next state does
+        if connection == "miniserver" && current != LinkState::Connecting {
+            let reachable = current.reachable();
+            if self.miniserver_reachable.lock().unwrap().replace(reachable) != Some(reachable) {
+                self.publish_reachability(py, reachable);
+            }
+        }
+        match (previous.reachable(), current.reachable()) {
+            (false, true) => self.run_connection_hooks(py, &self.connect_hooks.get(), connection, error.as_deref()),
+            (true, false) => self.run_connection_hooks(py, &self.disconnect_hooks.get(), connection, error.as_deref()),
+            _ => {}
+        }
+        if connection == "miniserver" && state == "connected" && self.outbox.is_some() {
+            match pyo3_async_runtimes::tokio::get_current_locals(py) {
+                Ok(locals) => self.outbox_replay(py, &locals).into_iter().for_each(OutboxReplay::start),
+                Err(_) => debug!("No running event loop, replaying the outbox after the next delivered send"),
+            }
+        }
+        let Some(ref topics) = self.mqtt_topics else {
+            return Ok(true);
+        };
+        let serialized = py_json::dumps(self.connection_states.to_py(py)?.as_any())?;
+        let coro = self
+            .mqtt_client_obj
+            .bind(py)
+            .call_method1("publish", (topics.state_topic.clone(), serialized, true))?;
+        let fut = into_future(coro.clone())?;
+        pyo3_async_runtimes::tokio::get_runtime().spawn(async move {
+            if let Err(e) = fut.await {
+                error!("Error publishing connection state: {:?}", e);
+            }
+        });
+        Ok(true)
+    }
+
+    /// `{"broker": {...}, "miniserver": {...}}` with each link's state, the time it was
+    /// entered, the previous state, the number of transitions and the last error.
+    #[pyo3(text_signature = "(self)")]
+    fn get_state<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        self.connection_states.to_py(py)
+    }
+
+    /// Register `callback(topic, value, ok, error)`, called after every send to the
+    /// Miniserver finished. `ok` is false for errors, timeouts and non-200 answers, with
+    /// `error` describing why. Coroutine functions are awaited.
+    #[pyo3(text_signature = "(self, callback)")]
+    fn add_send_callback(&self, py: Python<'_>, callback: Bound<'_, PyAny>) -> PyResult<()> {
+        if !callback.is_callable() {
+            return Err(PyValueError::new_err("Send callback must be callable"));
+        }
+        let mut callbacks: Vec<Py<PyAny>> = self.send_callbacks.get().iter().map(|c| c.clone_ref(py)).collect();
+        callbacks.push(callback.unbind());
+        self.send_callbacks.set(callbacks);
+        Ok(())
+    }
+
+    /// Register `callback(connection, error)`, called when the link to the broker or the
+    /// Miniserver (`connection`) is up: connected or degraded after it wasn't. Coroutine
+    /// functions are awaited.
+    #[pyo3(text_signature = "(self, callback)")]
+    fn on_connect(&self, py: Python<'_>, callback: Bound<'_, PyAny>) -> PyResult<()> {
+        add_hook(py, &self.connect_hooks, callback)
+    }
+
+    /// Register `callback(connection, error)`, called when the link to the broker or the
+    /// Miniserver goes down: connecting or offline after it was up. `error` is the reported
+    /// cause, if any. Coroutine functions are awaited.
+    #[pyo3(text_signature = "(self, callback)")]
+    fn on_disconnect(&self, py: Python<'_>, callback: Bound<'_, PyAny>) -> PyResult<()> {
+        add_hook(py, &self.disconnect_hooks, callback)
+    }
+
+    /// Unregister a callback added with `add_send_callback`; false if it wasn't registered.
+    #[pyo3(text_signature = "(self, callback)")]
+    fn remove_send_callback(&self, py: Python<'_>, callback: Bound<'_, PyAny>) -> bool {
+        let current = self.send_callbacks.get();
+        let callbacks: Vec<Py<PyAny>> = current
+            .iter()
+            .filter(|c| !c.bind(py).is(&callback))
+            .map(|c| c.clone_ref(py))
+            .collect();
+        let removed = callbacks.len() < current.len();
+        self.send_callbacks.set(callbacks);
+        removed
+    }
+
+    /// Virtual input name -> source topic for every value forwarded to the Miniserver
+    /// so far (up to `MAX_TRACKED_INPUTS` names).
+    #[pyo3(text_signature = "(self)")]
+    fn get_forwarded_inputs(&self) -> HashMap<String, String> {
+        self.forwarded_inputs.lock().unwrap().clone()
+    }
+
     #[pyo3(text_signature = "(self, topic, val)")]
     fn expand_json(&self, py: Python, topic: &str, val: &str) -> PyResult<Py<PyFrozenSet>> {
         if val.is_empty() || ((!val.starts_with('{')) && (!val.starts_with('['))) {
@@ -304,7 +1328,7 @@ impl MiniserverDataProcessor {
                     return Ok(set.into());
                 }
                 let mut flattened = Vec::new();
-                flatten_json(&json_val, "", &mut flattened);
+                flatten_json(&json_val, "", &self.flatten, &mut flattened);
                 let results: Vec<(String, String)> = flattened
                     .into_iter()
                     .map(|(k, v)| (format!("{}/{}", topic, k), v))
@@ -322,99 +1346,108 @@ impl MiniserverDataProcessor {
 
     #[pyo3(text_signature = "(self, topic)")]
     fn is_in_whitelist(&self, topic: &str) -> PyResult<bool> {
-        let normalized = self.normalize_topic(topic)?;
-        Ok(self.topic_whitelist.contains(&normalized))
+        let normalized = self.virtual_input_name(topic)?;
+        Ok(self.topic_whitelist.get().allows(&normalized, topic))
     }
 
+    /// Run a message through the pipeline; `message` is a str or a bytes-like payload.
+    /// Parsing, filtering and conversion run without holding the GIL.
     #[pyo3(text_signature = "(self, topic, message)")]
-    fn process_data(
-        &self,
-        py: Python,
-        topic: &str,
-        message: &str,
-    ) -> PyResult<()> {
-        debug!("Processing data - topic: {}, message: {}", topic, message);
-
-        // Normalize topic for whitelist comparison right away
-        let normalized_topic = self.normalize_topic(topic)?;
-        debug!("Normalized topic for processing: '{}'", normalized_topic);
-
-        // subscription filter (on original topic)
-        if let Some(ref regex) = self.compiled_subscription_filter {
-            if regex.is_match(topic) {
-                debug!("Topic '{}' filtered by subscription filter", topic);
-                return Ok(());
-            }
+    fn process_data(&self, py: Python, topic: &str, message: &Bound<'_, PyAny>) -> PyResult<()> {
+        match self.message_text(topic, message)? {
+            Some(text) => self.process_message(py, topic, &text, None),
+            None => Ok(()),
         }
+    }
 
-        let expand = pyget!(self.global_config, py, "processing", "expand_json").extract()?;
-        debug!("Transforming data with expand_json={}", expand);
-
-        let flattened: Vec<(String, String)> = if expand {
-            match serde_json::from_str::<Value>(message) {
-                Ok(json_val) => {
-                    if !json_val.is_object() {
-                        vec![(topic.to_string(), message.to_string())]
-                    } else {
-                        let mut flat_vec = Vec::new();
-                        flatten_json(&json_val, "", &mut flat_vec);
-                        flat_vec.into_iter().map(|(k, v)| (format!("{}/{}", topic, k), v)).collect()
-                    }
+    /// `process_data` for a list of `(topic, message)` pairs in one call. The messages are
+    /// prepared together with the GIL released once, and with `batch_mode = "message"`
+    /// their values go out as one batch. A message that fails is logged and skipped, like
+    /// in `handle_mqtt_messages_batch`; returns the number of messages handled without error.
+    #[pyo3(text_signature = "(self, messages)")]
+    fn process_data_batch(&self, py: Python, messages: Vec<(String, Bound<'_, PyAny>)>) -> usize {
+        let mut data = Vec::with_capacity(messages.len());
+        let mut failed = 0;
+        for (topic, message) in &messages {
+            match self.message_text(topic, message) {
+                Ok(Some(text)) => data.push((topic.clone(), text.into_owned())),
+                // A dropped binary payload counts as handled, as `process_data` returns normally
+                Ok(None) => {}
+                Err(e) => {
+                    error!("Error processing message on topic '{}' in batch: {:?}", topic, e);
+                    failed += 1;
                 }
-                Err(_) => vec![(topic.to_string(), message.to_string())],
             }
-        } else {
-            vec![(topic.to_string(), message.to_string())]
+        }
+        let data_count = data.len();
+        let handled_data = self.process_messages(py, &data);
+        messages.len() - failed - (data_count - handled_data)
+    }
+
+    /// The text of a `process_data` message: a str, or a bytes-like payload as UTF-8 or
+    /// decoded according to `invalid_utf8`; `None` if a binary payload is dropped.
+    fn message_text<'a>(&self, topic: &str, message: &'a Bound<'_, PyAny>) -> PyResult<Option<Cow<'a, str>>> {
+        if let Ok(text) = message.cast::<PyString>() {
+            return text.to_cow().map(Some);
+        }
+        let payload = match message.extract::<Cow<'a, [u8]>>()? {
+            Cow::Borrowed(bytes) => match std::str::from_utf8(bytes) {
+                Ok(text) => return Ok(Some(Cow::Borrowed(text))),
+                Err(_) => Cow::Borrowed(bytes),
+            },
+            Cow::Owned(bytes) => match String::from_utf8(bytes) {
+                Ok(text) => return Ok(Some(Cow::Owned(text))),
+                Err(e) => Cow::Owned(e.into_bytes()),
+            },
         };
-        debug!("Data after flattening: {:?}", flattened);
+        self.skips.record(SkipReason::InvalidUtf8, topic, &payload);
+        match self.invalid_utf8.decode_binary(&payload) {
+            Some(decoded) => Ok(Some(Cow::Owned(decoded))),
+            None => {
+                warn!("Dropping binary payload on topic '{}': {} bytes", topic, payload.len());
+                Ok(None)
+            }
+        }
+    }
 
-        // Loop for sending topics to the miniserver asynchronously
-        for (t, v) in flattened {
-            // Check whitelist first (using normalized topic)
-            let cur_t_normalized = self.normalize_topic(&t)?;
-            if !self.topic_whitelist.is_empty() {
-                debug!("Checking whitelist for topic '{}' (normalized: '{}') against whitelist: {:?}", 
-                       t, cur_t_normalized, self.topic_whitelist);
-                
-                if !self.topic_whitelist.contains(&cur_t_normalized) {
-                    debug!("Topic '{}' (normalized: '{}') not in whitelist", t, cur_t_normalized);
-                    continue;
-                }
-                debug!("Topic '{}' (normalized: '{}') found in whitelist", t, cur_t_normalized);
-            }
-            
-            // second pass subscription filter (on original topic)
-            if let Some(ref regex) = self.compiled_subscription_filter {
-                if regex.is_match(&t) {
-                    debug!("Topic '{}' filtered by second pass", t);
-                    continue;
-                }
-            }
-            
-            // do_not_forward (on original topic)
-            if let Some(ref regex) = self.do_not_forward_patterns {
-                if regex.is_match(&t) {
-                    debug!("Topic '{}' filtered by do_not_forward", t);
-                    continue;
-                }
-            }
-            
-            debug!("Topic '{}' passed all filters, sending to miniserver", t);
-            let converted = self._convert_boolean(&v)?;
-            if let Some(val) = converted {
-                let coro = self
-                    .http_handler_obj
-                    .bind(py)
-                    .call_method1("send_to_miniserver", (t, cur_t_normalized, val))?;
-                let fut = into_future(coro.clone())?;
-                pyo3_async_runtimes::tokio::get_runtime().spawn(async move {
-                    if let Err(e) = fut.await {
-                        error!("Error in send_to_miniserver async call: {:?}", e);
-                    }
-                });
+    /// Run `payload` on `topic` through expansion, rules, filters and conversions without
+    /// sending anything or touching statistics, and return its trace: which filter, rule
+    /// or check drops each value, or the value that would be sent.
+    #[pyo3(text_signature = "(self, topic, payload)")]
+    fn explain<'py>(&self, py: Python<'py>, topic: &str, payload: &str) -> PyResult<Bound<'py, PyAny>> {
+        let mut trace = MessageTrace::new(unix_now(), topic, payload);
+        if !self.active.load(Ordering::Relaxed) {
+            trace.dropped = Some("standby instance".to_string());
+        } else {
+            py.detach(|| self.prepare_values(topic, payload, false, Some(&mut trace)))?;
+            if self.paused.load(Ordering::Relaxed) {
+                for value in trace.values.iter_mut().filter(|value| value.outcome == Outcome::Forwarded) {
+                    value.outcome = Outcome::Paused;
+                }
             }
         }
+        py_json::to_py(py, &trace.to_json())
+    }
+
+    /// The traces of the last `debug.trace_messages` data messages, oldest first,
+    /// optionally only those involving `topic` (MQTT topic, flattened key or virtual input).
+    #[pyo3(signature = (topic=None))]
+    fn get_traces<'py>(&self, py: Python<'py>, topic: Option<&str>) -> PyResult<Bound<'py, PyAny>> {
+        py_json::to_py(py, &self.traces.snapshot(topic))
+    }
+
+    #[pyo3(text_signature = "(self)")]
+    fn clear_traces(&self) {
+        self.traces.clear();
+    }
 
+    /// Re-read the settings `process_data` keeps in Rust instead of reading them from the
+    /// config per message: `processing.expand_json`.
+    #[pyo3(text_signature = "(self)")]
+    fn reload_config(&self, py: Python<'_>) -> PyResult<()> {
+        let expand = pyget!(self.global_config, py, "processing", "expand_json").extract()?;
+        debug!("Reloaded config, expand_json={}", expand);
+        self.expand_json.store(expand, Ordering::Relaxed);
         Ok(())
     }
 
@@ -428,187 +1461,2828 @@ impl MiniserverDataProcessor {
     ///       self,  # MQTTRelay instance
     ///       mqtt_client,
     ///       http_handler_obj,
-    ///       orjson,
     ///    )
     ///    ...
     ///    asyncio.create_task(callback(topic, message))
     #[pyo3(text_signature = "(self,topic, message)")]
     #[allow(clippy::too_many_arguments)]
-    fn handle_mqtt_message(
+    pub(crate) fn handle_mqtt_message(
         &self,
         py: Python<'_>,
-        topic: String,
-        message_in: Vec<u8>
+        topic_in: &Bound<'_, PyAny>,
+        message_in: &Bound<'_, PyAny>
     ) -> PyResult<()> {
-        // Try UTF-8 conversion, but don't crash on failure
-        let message = match String::from_utf8(message_in) {
-            Ok(s) => s,
-            Err(e) => {
-                // e.into_bytes() gives us the original bytes back
-                let original_bytes = e.into_bytes();
-                warn!("Received binary MQTT message on topic '{}': {} bytes. Encoding as base64 for exact preservation.", topic, original_bytes.len());
-                
-                // Encode binary data as base64 to preserve exact data
-                format!("[base64:{}]", general_purpose::STANDARD.encode(original_bytes))
+        self.handle_message(py, topic_in, message_in, None, None)
+    }
+
+    /// `handle_mqtt_message`, but awaitable: resolves once every value of the message
+    /// handed to a sender has its outcome, with a list of `{"topic", "name", "value",
+    /// "status", "http_code"}` in message order. `status` is the delivery status
+    /// (`delivered`, `failed`, `queued`, `unconfirmed`, `paused`) or `dropped` for a send
+    /// that never happened, e.g. a rate limited value replaced by a newer one. Filtered
+    /// values and control messages aren't listed.
+    #[pyo3(text_signature = "(self, topic, message)")]
+    fn handle_mqtt_message_async<'py>(
+        &self,
+        py: Python<'py>,
+        topic_in: &Bound<'py, PyAny>,
+        message_in: &Bound<'py, PyAny>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let completion = Completion::default();
+        self.handle_message(py, topic_in, message_in, Some(&completion), None)?;
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let deliveries = completion.wait().await;
+            let deliveries = Value::Array(deliveries.iter().map(Delivery::to_json).collect());
+            Python::attach(|py| py_json::to_py(py, &deliveries).map(Bound::unbind))
+        })
+    }
+
+    /// Sends started and not finished yet, including those waiting in the send queue, for
+    /// the rate limit or for a batch. Lets the caller throttle ingestion.
+    #[getter]
+    fn in_flight(&self) -> u64 {
+        self.metrics.in_flight()
+    }
+
+    /// `handle_mqtt_message` for a list of `(topic, payload)` pairs in one call, e.g. the
+    /// retained messages after a reconnect. The data messages are prepared together with
+    /// the GIL released once, and with `batch_mode = "message"` their values go out as one
+    /// batch. Same error policy as `process_data_batch`: a message that fails is logged
+    /// and skipped; returns the number of messages handled without error.
+    #[pyo3(text_signature = "(self, messages)")]
+    fn handle_mqtt_messages_batch(&self, py: Python<'_>, messages: Vec<(Bound<'_, PyAny>, Bound<'_, PyAny>)>) -> usize {
+        let mut data = Vec::with_capacity(messages.len());
+        let mut failed = 0;
+        for (topic, payload) in &messages {
+            if let Err(e) = self.handle_message(py, topic, payload, None, Some(&mut data)) {
+                error!("Error processing MQTT message in batch: {:?}", e);
+                failed += 1;
             }
-        };
+        }
+        let data_count = data.len();
+        let handled_data = self.process_messages(py, &data);
+        messages.len() - failed - (data_count - handled_data)
+    }
 
-        debug!("(Rust) handle_mqtt_message: {} => {}", topic, message);
+    /// Record every incoming data message to the capture file `path` (appending to an
+    /// existing capture), replacing a capture in progress. Messages on the relay's own
+    /// topics aren't recorded. `max_bytes` (0: no limit) stops the recording once reached.
+    #[pyo3(signature = (path, max_bytes=0))]
+    fn start_capture(&self, path: &str, max_bytes: u64) -> PyResult<()> {
+        self.capture.set(Some(Capture::open(path, max_bytes)?));
+        Ok(())
+    }
 
-        let Some(ref topics) = self.mqtt_topics else {
-            error!("mqtt_topics was never initialized!");
-            return Ok(()); 
+    /// Stop recording; the number of messages recorded, `None` if no capture was running.
+    #[pyo3(text_signature = "(self)")]
+    fn stop_capture(&self) -> Option<u64> {
+        let previous = self.capture.get();
+        self.capture.set(None);
+        let records = previous.as_ref().as_ref().map(Capture::records);
+        if let Some(records) = records {
+            info!("Stopped capturing after {} messages", records);
+        }
+        records
+    }
+
+    /// Feed the messages of the capture file `path` through `handle_mqtt_message`, as if
+    /// they came from the broker, with the delays between them as recorded divided by
+    /// `speed`; `speed=0` replays without delays. Awaitable, resolving to the number of
+    /// messages replayed. Values are forwarded like live ones.
+    #[pyo3(signature = (path, speed=1.0))]
+    fn replay_capture<'py>(slf: Py<Self>, py: Python<'py>, path: &str, speed: f64) -> PyResult<Bound<'py, PyAny>> {
+        if !speed.is_finite() || speed < 0.0 {
+            return Err(PyValueError::new_err(format!("Invalid replay speed {}: expected 0 or more", speed)));
+        }
+        let records = capture::read(std::path::Path::new(path))?;
+        let Some(replaying) = Replaying::start(&slf.borrow(py).replaying) else {
+            return Err(PyValueError::new_err("A capture is already being replayed"));
         };
-        if topic.starts_with(&self.base_topic) {
-        // Match the topic to whichever action it needs
-            if topic == topics.miniserver_startup_topic {
-                if pyget!(self.global_config, py, "miniserver", "sync_with_miniserver").extract::<bool>()? {
-                    info!("Miniserver startup detected, resyncing whitelist (from Rust)");
-                    let _ = self.relay_main_obj.bind(py).call_method0("schedule_miniserver_sync")?;
+        info!("Replaying {} messages from {} at {}x speed", records.len(), path, speed);
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let _replaying = replaying;
+            let started = tokio::time::Instant::now();
+            let first = records.first().map(|record| record.at).unwrap_or_default();
+            let mut replayed = 0usize;
+            for record in &records {
+                if speed > 0.0 {
+                    let offset = ((record.at - first) / speed).max(0.0);
+                    tokio::time::sleep_until(started + Duration::from_secs_f64(offset)).await;
                 }
-            }
+                let closing = Python::attach(|py| {
+                    let processor = slf.borrow(py);
+                    if processor.closing.load(Ordering::Relaxed) {
+                        return true;
+                    }
+                    let topic = PyString::new(py, &record.topic);
+                    let payload = PyBytes::new(py, &record.payload);
+                    if let Err(e) = processor.handle_mqtt_message(py, topic.as_any(), payload.as_any()) {
+                        error!("Error replaying the message on topic '{}': {:?}", record.topic, e);
+                    }
+                    false
+                });
+                if closing {
+                    break;
+                }
+                replayed += 1;
+            }
+            info!("Replayed {} messages", replayed);
+            Ok(replayed)
+        })
+    }
+
+    /// Stop forwarding, wait up to `timeout` seconds for the sends in flight and abort
+    /// the rest. Awaitable, resolving to `{"drained": n, "aborted": n}`.
+    #[pyo3(signature = (timeout=5.0))]
+    fn shutdown<'py>(&self, py: Python<'py>, timeout: f64) -> PyResult<Bound<'py, PyAny>> {
+        self.closing.store(true, Ordering::Relaxed);
+        if let Some(failover) = &self.failover {
+            failover.stop();
+        }
+        let mut pending = std::mem::take(&mut *self.pending_sends.lock().unwrap());
+        let queue = Arc::clone(&self.send_queue);
+        let queued = queue.len() + queue.running();
+        let mut workers = queue.close();
+        info!("Shutting down, waiting for {} sends in flight", pending.len() + queued);
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let deadline = tokio::time::Instant::now() + Duration::from_secs_f64(timeout.max(0.0));
+            let mut drained = 0;
+            while let Ok(Some(_)) = tokio::time::timeout_at(deadline, pending.join_next()).await {
+                drained += 1;
+            }
+            for worker in &mut workers {
+                let _ = tokio::time::timeout_at(deadline, worker).await;
+            }
+            let left = queue.len() + queue.running();
+            drained += queued.saturating_sub(left);
+            let aborted = pending.len() + left;
+            if aborted > 0 {
+                warn!("Aborting {} sends still in flight after {}s", aborted, timeout);
+            }
+            workers.iter().for_each(|worker| worker.abort());
+            pending.shutdown().await;
+            Ok(HashMap::from([("drained", drained), ("aborted", aborted)]))
+        })
+    }
+
+    /// Check the primary and the secondary Miniserver every `health_check_interval` seconds
+    /// until `shutdown`, point the handler at the secondary once the primary failed
+    /// `health_check_failures` checks in a row and back once it passed as many, and publish
+    /// each change of availability retained to `{base_topic}miniserver/availability`.
+    /// Awaitable; finishes right away without `failover_ip`.
+    #[pyo3(text_signature = "(self)")]
+    fn run_health_checks<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let Some(failover) = self.failover.clone() else {
+            return pyo3_async_runtimes::tokio::future_into_py(py, async { Ok(()) });
+        };
+        let locals = pyo3_async_runtimes::tokio::get_current_locals(py)?;
+        let handler = self.http_handler_obj.clone_ref(py);
+        let mqtt_client = self.mqtt_client_obj.clone_ref(py);
+        let miniserver_address = Arc::clone(&self.miniserver_address);
+        let topic = format!("{}miniserver/availability", self.base_topic);
+        info!("Checking the Miniservers every {:?}, failover to {}:{}", failover.interval, failover.secondary.0, failover.secondary.1);
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            while !failover.stopped() {
+                // The primary is read every time, discovery may have moved it
+                let (primary_ip, primary_port) = miniserver_address.get().as_ref().clone();
+                let primary: (String, u16) = Python::attach(|py| split_host_port(py, &primary_ip, primary_port))?;
+                let (secondary_host, secondary_port) = &failover.secondary;
+                let (primary_up, secondary_up) =
+                    tokio::join!(failover.check(&primary.0, primary.1), failover.check(secondary_host, *secondary_port));
+                if let Some(availability) = failover.record(primary_up, secondary_up) {
+                    let (host, port) = if failover.on_secondary() { failover.secondary.clone() } else { primary };
+                    match availability {
+                        failover::Availability::Unavailable => warn!("No Miniserver passes its health check"),
+                        _ => info!("Forwarding to the {} Miniserver at {}:{}", availability.as_str(), host, port),
+                    }
+                    let mut payload = failover.snapshot();
+                    payload["host"] = json!(host);
+                    payload["port"] = json!(port);
+                    // The handler gets the primary as configured, so it splits it like before
+                    let address = if failover.on_secondary() { (host, port) } else { (primary_ip, primary_port) };
+                    let published = Python::attach(|py| {
+                        handler.bind(py).call_method1(intern!(py, "set_miniserver_address"), address)?;
+                        let coro = mqtt_client.bind(py).call_method1("publish", (topic.as_str(), payload.to_string(), true))?;
+                        pyo3_async_runtimes::into_future_with_locals(&locals, coro)
+                    });
+                    if let Err(e) = match published {
+                        Ok(fut) => fut.await.map(drop),
+                        Err(e) => Err(e),
+                    } {
+                        error!("Error publishing the Miniserver availability: {:?}", e);
+                    }
+                }
+                tokio::time::sleep(failover.interval).await;
+            }
+            Ok(())
+        })
+    }
+
+    /// Send `stale_value` for the inputs that got no value within `stale_timeout` and publish
+    /// `offline` retained to `{base_topic}availability/<name>` for each; once an input gets a
+    /// value again, `online` is published. Returns the number of inputs that went stale.
+    #[pyo3(text_signature = "(self)")]
+    fn check_stale(&self, py: Python<'_>) -> PyResult<usize> {
+        let Some(watchdog) = &self.watchdog else {
+            return Ok(0);
+        };
+        let expired = watchdog.expire();
+        let targets = self.targets.get();
+        for (name, topic) in &expired {
+            warn!("No value for {} (as {}) in {:?}, reporting it offline", topic, name, watchdog.timeout);
+            self.stats.add(Counter::Stale);
+            self.publish_availability(py, name, false);
+            if watchdog.offline_value.is_empty() || self.closing.load(Ordering::Relaxed) {
+                continue;
+            }
+            let (t, name, val) = (topic.clone(), name.clone(), watchdog.offline_value.clone());
+            if self.paused.load(Ordering::Relaxed) {
+                self.last_values.hold(&name, &t, &val);
+            } else if let Some(target) = targets.route(&t) {
+                self.send_to_target(Arc::clone(target), t, name, val, None);
+            } else {
+                self.dispatch(py, t, name, val, self.default_transport)?;
+            }
+        }
+        Ok(expired.len())
+    }
+
+    /// Run `check_stale` until `shutdown`. Awaitable; finishes right away without `stale_timeout`.
+    #[pyo3(text_signature = "(self)")]
+    fn run_watchdog<'py>(slf: Py<Self>, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let Some(watchdog) = slf.borrow(py).watchdog.clone() else {
+            return pyo3_async_runtimes::tokio::future_into_py(py, async { Ok(()) });
+        };
+        info!("Reporting inputs without a value for {:?} as stale", watchdog.timeout);
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            loop {
+                tokio::time::sleep(watchdog.interval()).await;
+                let closing = Python::attach(|py| -> PyResult<bool> {
+                    let processor = slf.borrow(py);
+                    if processor.closing.load(Ordering::Relaxed) {
+                        return Ok(true);
+                    }
+                    if let Err(e) = processor.check_stale(py) {
+                        error!("Error reporting stale inputs: {:?}", e);
+                    }
+                    Ok(false)
+                })?;
+                if closing {
+                    return Ok(());
+                }
+            }
+        })
+    }
+
+    /// The inputs reported stale, with the seconds since their last value.
+    #[pyo3(text_signature = "(self)")]
+    fn get_stale_inputs(&self) -> HashMap<String, f64> {
+        self.watchdog.as_ref().map(|watchdog| watchdog.stale().into_iter().collect()).unwrap_or_default()
+    }
+
+    /// Point the relay at the primary Miniserver found by discovery. Kept in memory only, the
+    /// configured address stays the fallback after a restart. The handler keeps sending to
+    /// the secondary while the failover is on it.
+    #[pyo3(text_signature = "(self, ip, port)")]
+    fn set_miniserver_address(&self, py: Python<'_>, ip: String, port: u16) -> PyResult<()> {
+        self.miniserver_address.set((ip.clone(), port));
+        if self.failover.as_ref().is_some_and(|failover| failover.on_secondary()) {
+            return Ok(());
+        }
+        self.http_handler_obj.bind(py).call_method1(intern!(py, "set_miniserver_address"), (ip, port))?;
+        Ok(())
+    }
+
+    /// `(ip, port)` of the primary Miniserver, see `set_miniserver_address`.
+    #[getter]
+    fn miniserver_address(&self) -> (String, u16) {
+        self.miniserver_address.get().as_ref().clone()
+    }
+
+    /// `{state, since, primary, secondary}` of the failover health checks, `None` without `failover_ip`.
+    #[pyo3(text_signature = "(self)")]
+    fn get_availability<'py>(&self, py: Python<'py>) -> PyResult<Option<Bound<'py, PyAny>>> {
+        self.failover.as_ref().map(|failover| py_json::to_py(py, &failover.snapshot())).transpose()
+    }
+
+    /// Stop sending to the Miniserver, e.g. while it reboots during a Loxone Config
+    /// deployment. Messages are still processed and their latest values recorded.
+    #[pyo3(text_signature = "(self)")]
+    fn pause(&self) {
+        if !self.paused.swap(true, Ordering::Relaxed) {
+            info!("Forwarding to the Miniserver paused");
+        }
+    }
+
+    /// Resume sending and send the latest value held back per virtual input.
+    /// Returns the number of values sent.
+    #[pyo3(text_signature = "(self)")]
+    fn resume(&self, py: Python<'_>) -> PyResult<usize> {
+        if !self.paused.swap(false, Ordering::Relaxed) {
+            return Ok(0);
+        }
+        let held = self.last_values.held();
+        info!("Forwarding to the Miniserver resumed, sending {} held values", held.len());
+        let count = held.len();
+        for (name, topic, value) in held {
+            self.dispatch(py, topic, name, value, self.default_transport)?;
+        }
+        Ok(count)
+    }
+
+    #[getter]
+    fn paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Switch the debug copies of processed and forwarded values, the stage timing and the
+    /// publishing of message traces on or off until the next restart; `None` keeps the
+    /// current setting. Returns the settings now in effect.
+    #[pyo3(signature = (processed_topics=None, forwarded_topics=None, stage_timing=None, trace=None))]
+    fn set_debug_options(
+        &self,
+        processed_topics: Option<bool>,
+        forwarded_topics: Option<bool>,
+        stage_timing: Option<bool>,
+        trace: Option<bool>,
+    ) -> HashMap<&'static str, bool> {
+        if let Some(on) = processed_topics {
+            self.publish_processed.store(on, Ordering::Relaxed);
+        }
+        if let Some(on) = forwarded_topics {
+            self.publish_forwarded.store(on, Ordering::Relaxed);
+        }
+        if let Some(on) = stage_timing {
+            self.timings.set_enabled(on);
+        }
+        if let Some(on) = trace {
+            self.publish_trace.store(on, Ordering::Relaxed);
+        }
+        let options = HashMap::from([
+            ("publish_processed_topics", self.publish_processed.load(Ordering::Relaxed)),
+            ("publish_forwarded_topics", self.publish_forwarded.load(Ordering::Relaxed)),
+            ("stage_timing", self.timings.enabled()),
+            ("publish_trace", self.publish_trace.load(Ordering::Relaxed)),
+        ]);
+        info!("Debug publishing: {:?}", options);
+        options
+    }
+
+    /// Switch between active (forwarding) and HA standby (not forwarding).
+    #[pyo3(text_signature = "(self, active)")]
+    fn set_active(&self, active: bool) {
+        info!("Relay instance is now {}", if active { "active" } else { "standby" });
+        self.active.store(active, Ordering::Relaxed);
+    }
+
+    #[getter]
+    fn active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    #[pyo3(text_signature = "(self, rules)")]
+    fn update_rules(&self, rules: &Bound<'_, PyAny>) -> PyResult<()> {
+        debug!("Updating rules: {:?}", rules);
+        let rules = parse_rules(rules, self.strict_filters)?;
+        rules.check_udp_destinations(self.udp_forwarder.is_some())?;
+        self.rules.set(rules);
+        Ok(())
+    }
+
+    #[pyo3(text_signature = "(self)")]
+    fn get_rules<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyList>> {
+        self.rules.get().to_py(py)
+    }
+
+    /// Deprecated alias of `get_rules`, from when `topics.unified_rules` ran a second rule list.
+    #[pyo3(text_signature = "(self)")]
+    fn get_unified_rules<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyList>> {
+        self.get_rules(py)
+    }
+
+    #[pyo3(text_signature = "(self, targets, routes)")]
+    fn update_targets(&self, py: Python<'_>, targets: &Bound<'_, PyAny>, routes: &Bound<'_, PyAny>) -> PyResult<()> {
+        debug!("Updating Miniserver routes: {:?}", routes);
+        let max_parallel = pyget!(self.global_config, py, "miniserver", "miniserver_max_parallel_connections").extract()?;
+        self.targets.set(parse_targets(targets, routes, max_parallel)?);
+        Ok(())
+    }
+
+    /// The Miniserver targets, without credentials, and the routes to them.
+    #[pyo3(text_signature = "(self)")]
+    fn get_targets<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        self.targets.get().to_py(py)
+    }
+
+    #[pyo3(text_signature = "(self, rewrites)")]
+    fn update_rewrites(&self, rewrites: &Bound<'_, PyAny>) -> PyResult<()> {
+        debug!("Updating rewrites: {:?}", rewrites);
+        self.rewrites.set(parse_rewrites(rewrites, self.strict_filters)?);
+        self.retire_filter_decisions();
+        Ok(())
+    }
+
+    #[pyo3(text_signature = "(self, transforms)")]
+    fn update_transforms(&self, transforms: &Bound<'_, PyAny>) -> PyResult<()> {
+        debug!("Updating transforms: {:?}", transforms);
+        self.transforms.set(parse_transforms(transforms)?);
+        Ok(())
+    }
+
+    #[pyo3(text_signature = "(self)")]
+    fn get_transforms<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyList>> {
+        self.transforms.get().to_py(py)
+    }
+
+    /// Replace the derived topics; source values already seen carry over.
+    #[pyo3(text_signature = "(self, derived)")]
+    fn update_derived(&self, derived: &Bound<'_, PyAny>) -> PyResult<()> {
+        debug!("Updating derived topics: {:?}", derived);
+        let derived = parse_derived(derived)?;
+        self.derived.update(|previous| {
+            derived.keep_values(previous);
+            derived
+        });
+        Ok(())
+    }
+
+    #[pyo3(text_signature = "(self)")]
+    fn get_derived<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyList>> {
+        self.derived.get().to_py(py)
+    }
+
+    #[pyo3(text_signature = "(self, timestamps)")]
+    fn update_timestamps(&self, timestamps: &Bound<'_, PyAny>) -> PyResult<()> {
+        debug!("Updating timestamp rules: {:?}", timestamps);
+        self.timestamps.set(parse_timestamp_rules(timestamps)?);
+        Ok(())
+    }
+
+    #[pyo3(text_signature = "(self)")]
+    fn get_timestamps<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyList>> {
+        self.timestamps.get().to_py(py)
+    }
+
+    /// Alias of `update_rewrites`.
+    #[pyo3(text_signature = "(self, rewrites)")]
+    fn update_topic_rewrites(&self, rewrites: &Bound<'_, PyAny>) -> PyResult<()> {
+        self.update_rewrites(rewrites)
+    }
+
+    #[pyo3(text_signature = "(self)")]
+    fn get_rewrites<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyList>> {
+        self.rewrites.get().to_py(py)
+    }
+
+    /// Topic patterns that have a script attached, in evaluation order.
+    #[pyo3(text_signature = "(self)")]
+    fn get_script_patterns(&self) -> Vec<String> {
+        self.scripts.get().patterns()
+    }
+
+    /// Recompile `processing.scripts` from the given list (same dicts as in the config) and
+    /// use them for the next message, with the configured operation and time limits.
+    /// Nothing changes if any script fails to compile.
+    #[pyo3(text_signature = "(self, scripts)")]
+    fn update_scripts(&self, scripts: &Bound<'_, PyAny>) -> PyResult<()> {
+        let (max_operations, timeout_ms) = self.scripts.get().limits();
+        let compiled = parse_scripts(scripts, max_operations, timeout_ms)?;
+        info!("Reloaded {} scripts", compiled.patterns().len());
+        self.scripts.set(compiled);
+        Ok(())
+    }
+
+    /// Render the payload template configured for `destination` ("miniserver", "udp" or "mqtt").
+    /// Returns `None` when that destination has no template, so callers keep the raw value.
+    #[pyo3(text_signature = "(self, destination, topic, value)")]
+    fn render_payload(&self, destination: &str, topic: &str, value: &str) -> PyResult<Option<String>> {
+        let normalized = self.normalize_topic(topic)?;
+        self.templates
+            .render(destination, &mut MessageContext::new(value), topic, &normalized, value)
+            .transpose()
+            .map_err(PyValueError::new_err)
+    }
+
+    /// Topic patterns that have a WASM plugin attached, in evaluation order.
+    #[pyo3(text_signature = "(self)")]
+    fn get_plugin_patterns(&self) -> Vec<String> {
+        self.plugins.patterns()
+    }
+
+    /// Whether this build was compiled with the `wasm` feature and can load plugins.
+    #[staticmethod]
+    fn wasm_plugins_supported() -> bool {
+        plugins::WASM_SUPPORTED
+    }
+
+    #[pyo3(text_signature = "(self)")]
+    fn get_do_not_forward_patterns(&self) -> Vec<String> {
+        self.do_not_forward_patterns.get().patterns().to_vec()
+    }
+
+    #[pyo3(text_signature = "(self)")]
+    fn get_monitor_topics(&self) -> Vec<String> {
+        self.monitor_topics.get().patterns().to_vec()
+    }
+
+    /// Check candidate topics against the current rules, subscription filters, whitelist,
+    /// post-expansion filters and do_not_forward, without side effects. Returns per topic
+    /// its virtual input name, the `result` ("forwarded" or "filtered") with the deciding
+    /// `reason`, and what each list says on its own: the first matching pattern (index and
+    /// text) per filter list, whether the whitelist has it (`None` without a whitelist)
+    /// and the index of the first matching rule.
+    #[pyo3(text_signature = "(self, topics)")]
+    fn test_filters<'py>(&self, py: Python<'py>, topics: Vec<String>) -> PyResult<Bound<'py, PyAny>> {
+        let results = py.detach(|| self.filter_results(&topics))?;
+        py_json::to_py(py, &Value::Object(results))
+    }
+
+    #[pyo3(text_signature = "(self)")]
+    fn get_subscription_filters(&self) -> Vec<String> {
+        self.subscription_filters.get().patterns().to_vec()
+    }
+
+    #[pyo3(text_signature = "(self)")]
+    fn get_post_expansion_filters(&self) -> Vec<String> {
+        self.post_expansion_filters.get().patterns().to_vec()
+    }
+
+    /// How filters, whitelist and do_not_forward are combined.
+    #[getter]
+    fn policy(&self) -> &'static str {
+        self.policy.as_str()
+    }
+
+    /// The anchor mode applied to subscription filters ("none", "start" or "full").
+    #[getter]
+    fn filter_anchor(&self) -> &'static str {
+        self.filter_anchor.as_str()
+    }
+
+    /// The default syntax of filter entries ("regex" or "mqtt").
+    #[getter]
+    fn filter_syntax(&self) -> &'static str {
+        self.filter_syntax.as_str()
+    }
+
+}
+
+impl MiniserverDataProcessor {
+    /// The lookup cache reported as `name` by `Metrics::cache_lookups`.
+    fn lookup_cache(&self, name: &str) -> &StringCache {
+        match name {
+            "convert_boolean" => &self.convert_bool_cache,
+            "filter_decision" => &self.filter_decision_cache,
+            _ => &self.normalize_topic_cache,
+        }
+    }
+
+    fn track_forwarded(&self, name: &str, topic: &str) {
+        let mut inputs = self.forwarded_inputs.lock().unwrap();
+        if inputs.len() < MAX_TRACKED_INPUTS && !inputs.contains_key(name) {
+            inputs.insert(name.to_string(), topic.to_string());
+        }
+    }
+
+    /// The Rust sender for the next value, if one is configured and may be used now.
+    /// `Http` and `Websocket` only take that sender, and fall back to the Python handler.
+    fn native_route(&self, py: Python<'_>, transport: Transport) -> PyResult<Option<NativeRoute>> {
+        let websocket = self.lox_ws.as_ref().filter(|ws| ws.is_connected() && transport != Transport::Http);
+        let http_sender = self.http_sender.as_ref().filter(|_| transport != Transport::Websocket);
+        if websocket.is_none() && http_sender.is_none() {
+            return Ok(None);
+        }
+        let target = self
+            .http_handler_obj
+            .bind(py)
+            .call_method0(intern!(py, "native_target"))?
+            .extract::<Option<(String, u16)>>()?;
+        Ok(match (target, websocket, http_sender) {
+            (None, _, _) => None,
+            (Some(_), Some(ws), _) => Some(NativeRoute::Websocket(Arc::clone(ws))),
+            (Some((host, port)), None, Some(sender)) => Some(NativeRoute::Http(Arc::clone(sender), host, port)),
+            (Some(_), None, None) => None,
+        })
+    }
+
+    /// Send one value as a datagram (`transport = "udp"` rules). Nothing answers, so a
+    /// datagram that went out is recorded as unconfirmed.
+    fn forward_udp(&self, forwarder: &UdpForwarder, t: &str, name: &str, val: &str, pending: Option<PendingDelivery>) {
+        let id = self.last_values.begin(name, t, val);
+        debug!("Forwarding #{} {} (as {})={} over UDP", id, t, name, val);
+        self.stats.add(Counter::Forwarded);
+        let status = match forwarder.send(name, val) {
+            Ok(()) => DeliveryStatus::Unconfirmed,
+            Err(e) => {
+                warn!("UDP send of {} (as {})={} failed: {}", t, name, val, e);
+                DeliveryStatus::Failed
+            }
+        };
+        self.last_values.complete(name, id, status, None);
+        self.stats.add_outcome(status);
+        if let Some(pending) = pending {
+            pending.complete(status, None);
+        }
+    }
+
+    /// Send one value to a Miniserver of `miniserver.targets`, in a task of its own.
+    /// Batching, rate limits, retries and the outbox only apply to the default Miniserver.
+    fn send_to_target(&self, target: Arc<Target>, t: String, name: String, val: String, pending: Option<PendingDelivery>) {
+        debug!("Forwarding {} (as {})={} to Miniserver '{}'", t, name, val, target.name());
+        self.stats.add(Counter::Forwarded);
+        self.target_stats.add_forwarded(target.name());
+        let stats = Arc::clone(&self.stats);
+        let target_stats = Arc::clone(&self.target_stats);
+        let in_flight = self.metrics.send_started();
+        let task = async move {
+            let _in_flight = in_flight;
+            let status = target.send(&name, &val).await;
+            debug!("Send of {} to Miniserver '{}' finished: {:?}", name, target.name(), status);
+            stats.add_outcome(status);
+            target_stats.add_outcome(target.name(), status);
+            if let Some(pending) = pending {
+                pending.complete(status, None);
+            }
+        };
+        let mut pending = self.pending_sends.lock().unwrap();
+        while pending.try_join_next().is_some() {}
+        pending.spawn_on(task, pyo3_async_runtimes::tokio::get_runtime().handle());
+    }
+
+    /// Record a value for a batch: collected in `batched` until the message is done, or
+    /// added to the open window, which is sent once it ends or is full.
+    #[allow(clippy::too_many_arguments)]
+    fn add_to_batch(
+        &self,
+        py: Python<'_>,
+        udp: bool,
+        t: String,
+        name: String,
+        val: String,
+        pending: Option<PendingDelivery>,
+        batched: &mut Vec<BatchEntry>,
+    ) -> PyResult<()> {
+        let Some(batching) = &self.batching else {
+            return Ok(());
+        };
+        let id = self.last_values.begin(&name, &t, &val);
+        debug!("Batching #{} {} (as {})={}", id, t, name, val);
+        self.stats.add(Counter::Forwarded);
+        let entry = BatchEntry { name, value: val, id, pending };
+        if batching.mode != BatchMode::Window {
+            batched.push(entry);
+            return Ok(());
+        }
+        let window = if udp { &batching.udp } else { &batching.miniserver };
+        match window.push(entry) {
+            Pushed::Opened(generation) => self.start_batch(py, udp, Vec::new(), Some(generation)),
+            Pushed::Joined => Ok(()),
+            Pushed::Full(entries) => self.start_batch(py, udp, entries, None),
+        }
+    }
+
+    /// Send `entries` as batches in a task, or with `window` wait until that window ends
+    /// and send whatever it collected.
+    fn start_batch(&self, py: Python<'_>, udp: bool, entries: Vec<BatchEntry>, window: Option<u64>) -> PyResult<()> {
+        let Some(batching) = &self.batching else {
+            return Ok(());
+        };
+        let target = match (udp, &self.udp_forwarder) {
+            (true, Some(forwarder)) => BatchTarget::Udp(Arc::clone(forwarder)),
+            _ => BatchTarget::Miniserver {
+                handler: self.http_handler_obj.clone_ref(py),
+                route: self.native_route(py, Transport::Miniserver)?,
+                input: batching.input.clone(),
+                retry: self.retry,
+            },
+        };
+        let send = BatchSend {
+            target,
+            separator: batching.separator.clone(),
+            max_values: batching.max_values,
+            last_values: Arc::clone(&self.last_values),
+            stats: Arc::clone(&self.stats),
+        };
+        let batching = Arc::clone(batching);
+        let in_flight = self.metrics.send_started();
+        let task = async move {
+            let _in_flight = in_flight;
+            let entries = match window {
+                Some(generation) => {
+                    tokio::time::sleep(batching.window).await;
+                    if udp { batching.udp.take(generation) } else { batching.miniserver.take(generation) }
+                }
+                None => entries,
+            };
+            send.run(entries).await;
+        };
+        // Datagrams don't need Python, requests may fall back to the Python handler
+        let task: std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send>> = if udp {
+            Box::pin(task)
+        } else {
+            Box::pin(pyo3_async_runtimes::tokio::scope(pyo3_async_runtimes::tokio::get_current_locals(py)?, task))
+        };
+        let mut pending = self.pending_sends.lock().unwrap();
+        while pending.try_join_next().is_some() {}
+        pending.spawn_on(task, pyo3_async_runtimes::tokio::get_runtime().handle());
+        Ok(())
+    }
+
+    /// Hand one value to the sender and record its outcome once the send finishes.
+    ///
+    /// With `native_http` or `native_websocket` the value goes out from Rust while the Python
+    /// handler reports the Miniserver as healthy (`native_target`); anything but a 200 is
+    /// repeated through `send_to_miniserver`, which owns the offline queue, busy back-off and
+    /// state reporting.
+    fn dispatch(&self, py: Python<'_>, t: String, name: String, val: String, transport: Transport) -> PyResult<()> {
+        self.dispatch_held(py, t, name, val, transport, None, None)
+    }
+
+    fn outbox_replay(&self, py: Python<'_>, locals: &pyo3_async_runtimes::TaskLocals) -> Option<OutboxReplay> {
+        self.outbox.as_ref().map(|outbox| OutboxReplay {
+            outbox: Arc::clone(outbox),
+            handler: self.http_handler_obj.clone_ref(py),
+            last_values: Arc::clone(&self.last_values),
+            stats: Arc::clone(&self.stats),
+            locals: locals.clone(),
+        })
+    }
+
+    /// `dispatch`, but with `held` the send first waits in its task until the rate limiter
+    /// releases it, and ends there if a newer value for the input replaced it. `pending`
+    /// gets the outcome of the send.
+    #[allow(clippy::too_many_arguments)]
+    fn dispatch_held(
+        &self,
+        py: Python<'_>,
+        t: String,
+        name: String,
+        val: String,
+        transport: Transport,
+        held: Option<(Duration, u64)>,
+        pending: Option<PendingDelivery>,
+    ) -> PyResult<()> {
+        let id = match held {
+            Some(_) => None,
+            None => {
+                let id = self.last_values.begin(&name, &t, &val);
+                debug!("Forwarding #{} {} (as {})={}", id, t, name, val);
+                self.stats.add(Counter::Forwarded);
+                Some(id)
+            }
+        };
+        let callbacks = self.send_callbacks.get();
+        let route = self.native_route(py, transport)?;
+        // Retries and dead letters need the value again after the first attempt
+        let follow_up = (self.retry.retries > 0 || self.dead_letter).then(|| FollowUp {
+            handler: self.http_handler_obj.clone_ref(py),
+            mqtt: self.mqtt_client_obj.clone_ref(py),
+            t: t.clone(),
+            val: val.clone(),
+            transport,
+            retry: self.retry,
+            dead_letter_topic: self.dead_letter.then(|| format!("{}deadletter/{}", self.base_topic, name)),
+        });
+        // Values held by the rate limit wait in their own task, outside the queue, so the
+        // wait doesn't take a worker; there's at most one per input
+        let queued = held.is_none() && self.send_queue.enabled();
+        // Queued and held sends start their coroutine only once it's their turn
+        let deferred = held.is_some() || queued;
+        // Coroutines (callbacks, the fallback send) run on the loop the send was started from
+        let locals = if deferred || follow_up.is_some() || self.outbox.is_some() || !callbacks.is_empty() || route.is_some() {
+            Some(pyo3_async_runtimes::tokio::get_current_locals(py)?)
+        } else {
+            None
+        };
+        let notify = locals
+            .as_ref()
+            .filter(|_| !callbacks.is_empty())
+            .map(|locals| (callbacks, locals.clone(), t.clone(), val.clone()));
+        // A failed value goes to the outbox, a delivered one means it can be replayed
+        let outbox = locals.as_ref().and_then(|locals| self.outbox_replay(py, locals)).map(|replay| (replay, t.clone(), val.clone()));
+        // A held value is only recorded once it is released
+        let record = held.map(|held| (held, t.clone(), val.clone()));
+        let outcome: std::pin::Pin<Box<dyn std::future::Future<Output = SendOutcome> + Send>> = match route {
+            Some(route) => {
+                let handler = self.http_handler_obj.clone_ref(py);
+                let name = name.clone();
+                Box::pin(async move {
+                    let result = match route {
+                        NativeRoute::Http(sender, host, port) => sender.send(&host, port, &name, &val).await,
+                        NativeRoute::Websocket(ws) => ws.send_io(&name, &val).await.map(|()| 200),
+                    };
+                    match result {
+                        Ok(200) => return (DeliveryStatus::Delivered, Some(200), None),
+                        Ok(code) => debug!("Miniserver returned {} for {}, resending through Python", code, t),
+                        Err(e) => debug!("Native send of {} failed ({}), resending through Python", t, e),
+                    }
+                    python_send(handler, t, name, val, transport).await
+                })
+            }
+            // The coroutine of a held or queued value may only start once it's sent
+            None if deferred => {
+                Box::pin(python_send(self.http_handler_obj.clone_ref(py), t.clone(), name.clone(), val.clone(), transport))
+            }
+            None => {
+                let coro = self
+                    .http_handler_obj
+                    .bind(py)
+                    .call_method("send_to_miniserver", (t, name.clone(), val), transport_kwargs(py, transport)?.as_ref())?;
+                let fut = into_future(coro)?;
+                Box::pin(async move { send_outcome(fut.await) })
+            }
+        };
+        let last_values = Arc::clone(&self.last_values);
+        let stats = Arc::clone(&self.stats);
+        let rate_limiter = Arc::clone(&self.rate_limiter);
+        let in_flight = self.metrics.send_started();
+        let queued_as = (name.clone(), id);
+        let task = async move {
+            let _in_flight = in_flight;
+            let id = match (id, record) {
+                (Some(id), _) => id,
+                (None, Some(((wait, generation), t, val))) => {
+                    if !rate_limiter.release(&name, generation, wait).await {
+                        debug!("Rate limit: dropping {}={}, replaced by a newer value", name, val);
+                        stats.add(Counter::RateLimited);
+                        return;
+                    }
+                    let id = last_values.begin(&name, &t, &val);
+                    debug!("Forwarding #{} {} (as {})={}", id, t, name, val);
+                    stats.add(Counter::Forwarded);
+                    id
+                }
+                (None, None) => unreachable!("a send is either started or held"),
+            };
+            let mut outcome = outcome.await;
+            if let Some(follow_up) = follow_up {
+                outcome = follow_up.run(&last_values, &stats, &name, id, outcome).await;
+            }
+            let (status, code, err) = outcome;
+            debug!("Send #{} to {} finished: {:?} ({:?})", id, name, status, code);
+            last_values.complete(&name, id, status, code);
+            stats.add_outcome(status);
+            if let Some(pending) = pending {
+                pending.complete(status, code);
+            }
+            match outbox {
+                Some((replay, t, val)) if status == DeliveryStatus::Failed => {
+                    debug!("Keeping {}={} in the outbox", name, val);
+                    replay.outbox.push(&name, &t, &val);
+                    stats.add(Counter::Buffered);
+                }
+                Some((replay, _, _)) if status == DeliveryStatus::Delivered => replay.start(),
+                _ => {}
+            }
+            if let Some((callbacks, locals, topic, value)) = notify {
+                let ok = status != DeliveryStatus::Failed;
+                run_send_callbacks(&callbacks, &locals, &topic, &value, ok, err.as_deref()).await;
+            }
+        };
+        let task: std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send>> = match locals {
+            Some(locals) => Box::pin(pyo3_async_runtimes::tokio::scope(locals, task)),
+            None => Box::pin(task),
+        };
+        if queued {
+            let (name, id) = queued_as;
+            if let Some(dropped) = self.send_queue.push(QueuedSend { name, id, task }) {
+                debug!("Send queue full, dropping the send to {}", dropped.name);
+                self.stats.add(Counter::QueueOverflow);
+                if let Some(id) = dropped.id {
+                    self.last_values.complete(&dropped.name, id, DeliveryStatus::Failed, None);
+                }
+            }
+            return Ok(());
+        }
+        let mut pending = self.pending_sends.lock().unwrap();
+        // Reap finished sends so the set only holds the ones in flight
+        while pending.try_join_next().is_some() {}
+        pending.spawn_on(task, pyo3_async_runtimes::tokio::get_runtime().handle());
+        Ok(())
+    }
+
+    /// Apply a `filters/set`, `dnf/set`, `whitelist/add` or `whitelist/remove` payload
+    /// (a JSON list of strings) right away and save it to the config, without a restart.
+    /// Nothing is saved if the update is rejected, e.g. a bad regex with `strict_filters`.
+    /// The whitelist topics also take a single MQTT topic, which is added under its
+    /// virtual input name.
+    fn apply_list_update(&self, py: Python<'_>, topics: &MqttTopics, topic: &str, message: &str) -> PyResult<()> {
+        let whitelist_change = topic == topics.whitelist_add_topic || topic == topics.whitelist_remove_topic;
+        let items: Vec<String> = if !whitelist_change {
+            py_json::loads(py, message)?.extract()?
+        } else {
+            match py_json::loads(py, message) {
+                Ok(list) if list.is_instance_of::<PyList>() => {
+                    self.whitelist_names(list.extract::<Vec<String>>()?)?.into_iter().collect()
+                }
+                Ok(single) if single.is_instance_of::<PyString>() => {
+                    vec![self.virtual_input_name(single.extract::<&str>()?.trim())?]
+                }
+                // Plain text, e.g. typed into a phone's MQTT app
+                _ if !message.trim().is_empty() => vec![self.virtual_input_name(message.trim())?],
+                _ => return Err(PyValueError::new_err("Expected a topic or a JSON list of topics")),
+            }
+        };
+        let (field, saved) = if topic == topics.filters_set_topic {
+            self.update_subscription_filters(items.clone())?;
+            ("subscription_filters", items)
+        } else if topic == topics.dnf_set_topic {
+            self.update_do_not_forward(items.clone())?;
+            ("do_not_forward", items)
+        } else {
+            let add = topic == topics.whitelist_add_topic;
+            let whitelist = self.topic_whitelist.update(|current| {
+                let mut names = current.names.clone();
+                if add {
+                    names.extend(items);
+                } else {
+                    for item in &items {
+                        names.remove(item);
+                    }
+                }
+                Whitelist::new(names)
+            });
+            self.retire_filter_decisions();
+            self.whitelist_times.lock().unwrap().updated_at = Some(unix_now());
+            self.retract_unlisted(py);
+            let mut saved: Vec<String> = whitelist.names.iter().cloned().collect();
+            saved.sort();
+            ("topic_whitelist", saved)
+        };
+        info!("Updated {} via MQTT ({} entries), no restart needed", field, saved.len());
+        let updates = PyDict::new(py);
+        updates.set_item(field, saved)?;
+        self.global_config.bind(py).call_method1(intern!(py, "update_fields"), (updates, "set"))?;
+        Ok(())
+    }
+
+    /// Whether a `config/*` payload only changes `scripts`, which can be applied live.
+    /// Validate a `config/set` (`add`, `remove`) payload against the merged config and apply
+    /// it if every field passes; nothing is applied otherwise. Returns the result for
+    /// `config/response` and whether the relay has to restart for it:
+    /// `{"mode", "applied", "restart", "accepted": [fields], "rejected": [{"field", "reason"}]}`.
+    fn apply_config_update(&self, py: Python<'_>, message: &str, update_mode: &str) -> PyResult<(Value, bool)> {
+        let result = |accepted: Vec<String>, rejected: Vec<(Option<String>, String)>, restart: bool| {
+            let rejected: Vec<Value> = rejected.into_iter().map(|(field, reason)| json!({"field": field, "reason": reason})).collect();
+            json!({
+                "mode": update_mode,
+                "applied": rejected.is_empty(),
+                "restart": restart,
+                "accepted": accepted,
+                "rejected": rejected,
+            })
+        };
+        let updates = match py_json::loads(py, message) {
+            Ok(updates) if updates.is_instance_of::<PyDict>() => updates,
+            Ok(_) => {
+                error!("Invalid configuration update via MQTT: not a JSON object");
+                return Ok((result(Vec::new(), vec![(None, "Expected a JSON object of configuration fields".to_string())], false), false));
+            }
+            Err(e) => {
+                error!("Invalid JSON format in MQTT message: {:?}", e);
+                return Ok((result(Vec::new(), vec![(None, format!("Invalid JSON: {}", e.value(py)))], false), false));
+            }
+        };
+        let global_config = self
+            .relay_main_obj
+            .bind(py)
+            .getattr(intern!(py, "miniserver_data_processor"))?
+            .getattr(intern!(py, "global_config"))?;
+        let qualify = |field: String| -> PyResult<String> {
+            if field.contains('.') {
+                return Ok(field);
+            }
+            match global_config.getattr(intern!(py, "field_mappings"))?.get_item(&field) {
+                Ok(mapping) => Ok(format!("{}.{}", mapping.get_item(0)?.getattr(intern!(py, "value"))?.extract::<String>()?, field)),
+                Err(_) => Ok(field),
+            }
+        };
+        let mut fields = Vec::new();
+        for entry in global_config.call_method1(intern!(py, "_flatten_updates"), (&updates,))?.try_iter()? {
+            let (section, field, _): (Option<String>, String, Bound<'_, PyAny>) = entry?.extract()?;
+            fields.push(match section {
+                Some(section) => format!("{}.{}", section, field),
+                None => qualify(field)?,
+            });
+        }
+        if self.is_script_update(&updates) {
+            // Scripts are recompiled in place, no restart needed
+            return Ok(match self.apply_script_update(py, &global_config, &updates, update_mode) {
+                Ok(()) => (result(fields, Vec::new(), false), false),
+                Err(e) => {
+                    error!("Error updating scripts: {:?}", e);
+                    (result(Vec::new(), vec![(Some("processing.scripts".to_string()), e.value(py).to_string())], false), false)
+                }
+            });
+        }
+        let (candidate, mut errors): (Bound<'_, PyAny>, Vec<String>) =
+            global_config.call_method1(intern!(py, "candidate"), (&updates, update_mode))?.extract()?;
+        errors.extend(config_validate::validate_config(&candidate));
+        if errors.is_empty() {
+            if let Err(e) = global_config.call_method1(intern!(py, "update_fields"), (&updates, update_mode)) {
+                error!("Error updating configuration: {:?}", e);
+                return Ok((result(Vec::new(), vec![(None, e.value(py).to_string())], false), false));
+            }
+            return Ok((result(fields, Vec::new(), true), true));
+        }
+        error!("Rejected configuration update via MQTT: {}", errors.join("; "));
+        let mut rejected = Vec::new();
+        for error in &errors {
+            let (field, reason) = config_validate::error_field(error);
+            rejected.push((field.map(qualify).transpose()?, reason));
+        }
+        let accepted = fields.into_iter().filter(|field| rejected.iter().all(|(rejected, _)| rejected.as_ref() != Some(field))).collect();
+        Ok((result(accepted, rejected, false), false))
+    }
+
+    fn is_script_update(&self, updates: &Bound<'_, PyAny>) -> bool {
+        updates
+            .cast::<PyDict>()
+            .map(|d| d.len() == 1 && d.contains("scripts").unwrap_or(false))
+            .unwrap_or(false)
+    }
+
+    /// Merge a `scripts` update into a copy of the config, compile the result and only then
+    /// save it and swap the scripts in. A script that fails to compile changes nothing.
+    fn apply_script_update(
+        &self,
+        py: Python<'_>,
+        global_config: &Bound<'_, PyAny>,
+        updates: &Bound<'_, PyAny>,
+        update_mode: &str,
+    ) -> PyResult<()> {
+        let (candidate, errors): (Bound<'_, PyAny>, Vec<String>) = global_config
+            .call_method1(intern!(py, "candidate"), (updates, update_mode))?
+            .extract()?;
+        if !errors.is_empty() {
+            return Err(PyValueError::new_err(errors.join("; ")));
+        }
+        let scripts = candidate.getattr(intern!(py, "processing"))?.getattr(intern!(py, "scripts"))?;
+        self.update_scripts(&scripts)?;
+        global_config.call_method1(intern!(py, "update_fields"), (updates, update_mode))?;
+        info!("Updated scripts via MQTT, no restart needed");
+        Ok(())
+    }
+
+    fn stats_snapshot(&self, reset: bool) -> Value {
+        let mut snapshot = self.stats.snapshot(self.skips.counts(reset), reset);
+        if self.timings.enabled() {
+            snapshot["stages"] = self.timings.snapshot(reset);
+        }
+        if !self.target_stats.is_empty() {
+            snapshot["targets"] = self.target_stats.snapshot(reset);
+        }
+        snapshot
+    }
+
+    /// Publish the whitelist, sorted and in chunks of `message` (or `WHITELIST_CHUNK_SIZE`)
+    /// entries, each with `{size, updated_at, synced_at, chunk, chunks, topics}`.
+    fn publish_whitelist(&self, py: Python<'_>, message: &str, response_topic: String) -> PyResult<()> {
+        let chunk_size = message.trim().parse::<usize>().ok().filter(|&n| n > 0).unwrap_or(WHITELIST_CHUNK_SIZE);
+        let mut whitelist: Vec<String> = self.topic_whitelist.get().names.iter().cloned().collect();
+        whitelist.sort();
+        let times = *self.whitelist_times.lock().unwrap();
+        let chunks: Vec<&[String]> = if whitelist.is_empty() {
+            vec![&[]]
+        } else {
+            whitelist.chunks(chunk_size).collect()
+        };
+        let mut pending = Vec::with_capacity(chunks.len());
+        for (i, chunk) in chunks.iter().enumerate() {
+            let payload = json!({
+                "size": whitelist.len(),
+                "updated_at": times.updated_at,
+                "synced_at": times.synced_at,
+                "chunk": i + 1,
+                "chunks": chunks.len(),
+                "topics": chunk,
+            });
+            let coro = self
+                .mqtt_client_obj
+                .bind(py)
+                .call_method1("publish", (response_topic.as_str(), payload.to_string()))?;
+            pending.push(into_future(coro)?);
+        }
+        pyo3_async_runtimes::tokio::get_runtime().spawn(async move {
+            for fut in pending {
+                if let Err(e) = fut.await {
+                    error!("Error publishing whitelist: {:?}", e);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Validate the candidate config in `message` without applying it and publish
+    /// `{"valid", "errors", "hosts"}` to `response_topic`. The message is either the
+    /// updates themselves, or `{"config": updates, "mode": ..., "check_hosts": ...}`.
+    fn publish_config_validation(&self, py: Python<'_>, message: &str, response_topic: String) -> PyResult<()> {
+        let mut errors = Vec::new();
+        let mut hosts = None;
+        match py_json::loads(py, message) {
+            Err(e) => errors.push(e.value(py).to_string()),
+            Ok(request) => {
+                let (updates, mode, check_hosts) = match request.cast::<PyDict>() {
+                    Ok(envelope) if envelope.contains(intern!(py, "config"))? => (
+                        envelope.get_item(intern!(py, "config"))?.unwrap(),
+                        envelope.get_item(intern!(py, "mode"))?.unwrap_or_else(|| PyString::new(py, "set").into_any()),
+                        envelope.get_item(intern!(py, "check_hosts"))?.map(|c| c.is_truthy()).transpose()?.unwrap_or(false),
+                    ),
+                    _ => (request.clone(), PyString::new(py, "set").into_any(), false),
+                };
+                if !updates.is_instance_of::<PyDict>() {
+                    errors.push("Expected a JSON object of configuration fields".to_string());
+                } else {
+                    match self.global_config.bind(py).call_method1(intern!(py, "candidate"), (updates, mode)) {
+                        Err(e) => errors.push(e.value(py).to_string()),
+                        Ok(result) => {
+                            let (candidate, merge_errors): (Bound<'_, PyAny>, Vec<String>) = result.extract()?;
+                            errors.extend(merge_errors);
+                            errors.extend(config_validate::validate_config(&candidate));
+                            if check_hosts {
+                                hosts = Some(config_validate::hosts(&candidate)?);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        let Some(hosts) = hosts else {
+            let result = json!({"valid": errors.is_empty(), "errors": errors, "hosts": null});
+            let coro = self
+                .mqtt_client_obj
+                .bind(py)
+                .call_method1("publish", (response_topic, result.to_string()))?;
+            let fut = into_future(coro.clone())?;
+            pyo3_async_runtimes::tokio::get_runtime().spawn(async move {
+                if let Err(e) = fut.await {
+                    error!("Error publishing config validation: {:?}", e);
+                }
+            });
+            return Ok(());
+        };
+        // The publish is created after the host checks, on the loop this message came from
+        let locals = pyo3_async_runtimes::tokio::get_current_locals(py)?;
+        let mqtt_client = self.mqtt_client_obj.clone_ref(py);
+        pyo3_async_runtimes::tokio::get_runtime().spawn(async move {
+            let (reachability, host_errors) = config_validate::check_hosts(hosts).await;
+            errors.extend(host_errors);
+            let result = json!({"valid": errors.is_empty(), "errors": errors, "hosts": reachability});
+            let published = Python::attach(|py| {
+                let coro = mqtt_client.bind(py).call_method1("publish", (response_topic, result.to_string()))?;
+                pyo3_async_runtimes::into_future_with_locals(&locals, coro)
+            });
+            if let Err(e) = match published {
+                Ok(fut) => fut.await.map(drop),
+                Err(e) => Err(e),
+            } {
+                error!("Error publishing config validation: {:?}", e);
+            }
+        });
+        Ok(())
+    }
+
+    /// `handle_mqtt_message`, following the values handed to a sender with `completion`.
+    /// With `collect`, data messages are added to it instead of being processed, for
+    /// `process_messages`; control messages are handled right away either way.
+    fn handle_message(
+        &self,
+        py: Python<'_>,
+        topic_in: &Bound<'_, PyAny>,
+        message_in: &Bound<'_, PyAny>,
+        completion: Option<&Completion>,
+        collect: Option<&mut Vec<(String, String)>>,
+    ) -> PyResult<()> {
+        panic_hook::bind_running_loop(py);
+        // Topics may arrive as str or, from bridges with broken firmware, as raw bytes.
+        // A broken encoding must not raise here, that would fail the client's callback.
+        let (raw_topic, valid_utf8): (Cow<'_, str>, bool) = if let Ok(s) = topic_in.cast::<PyString>() {
+            match s.to_cow() {
+                Ok(s) => (s, true),
+                // Lone surrogates are possible in str, they become U+FFFD
+                Err(_) => (Cow::Owned(s.to_string_lossy().into_owned()), false),
+            }
+        } else {
+            match String::from_utf8(PyBuffer::<u8>::get(topic_in)?.to_vec(py)?) {
+                Ok(s) => (Cow::Owned(s), true),
+                Err(e) => (Cow::Owned(String::from_utf8_lossy(e.as_bytes()).into_owned()), false),
+            }
+        };
+        if !valid_utf8 && self.invalid_utf8 == Utf8Policy::Drop {
+            warn!("Dropping MQTT message on topic {:?}, the topic isn't valid UTF-8", raw_topic);
+            self.skips.record(SkipReason::InvalidTopic, &raw_topic, raw_topic.as_bytes());
+            return Ok(());
+        }
+        let topic = sanitize_topic(raw_topic.clone());
+        if topic != raw_topic {
+            warn!("Sanitized invalid MQTT topic {:?} to '{}'", raw_topic, topic);
+            self.skips.record(SkipReason::InvalidTopic, &topic, raw_topic.as_bytes());
+        }
+        let topic = topic.into_owned();
+        // Borrow bytes instead of copying them. Anything else (bytearray, memoryview, ...)
+        // is copied: it is read without the GIL below, while other threads may change it
+        let copied;
+        let payload: &[u8] = if let Ok(bytes) = message_in.cast::<PyBytes>() {
+            bytes.as_bytes()
+        } else {
+            copied = PyBuffer::<u8>::get(message_in)?.to_vec(py)?;
+            &copied
+        };
+
+        // The relay's own control topics are commands, not data to reproduce
+        if !self.replaying.load(Ordering::Relaxed) && !topic.starts_with(&self.base_topic) {
+            if let Some(capture) = &*self.capture.get() {
+                capture.record(&topic, payload);
+            }
+        }
+        if self.max_message_bytes > 0 && payload.len() > self.max_message_bytes && !topic.starts_with(&self.base_topic) {
+            warn!("Dropping {} byte MQTT message on topic '{}' (limit {} bytes)", payload.len(), topic, self.max_message_bytes);
+            self.skips.record(SkipReason::Oversized, &topic, payload);
+            return Ok(());
+        }
+        // Compressed payloads continue inflated, before anything looks at their content
+        let inflated;
+        let payload: &[u8] = if self.decompress_topics.is_match(&topic) || (self.decompress && inflate::detect(payload).is_some()) {
+            match py.detach(|| inflate::decompress(payload, self.max_decompressed_bytes)) {
+                Ok(data) => {
+                    debug!("Inflated {} byte payload on topic '{}' to {} bytes", payload.len(), topic, data.len());
+                    inflated = data;
+                    &inflated
+                }
+                Err(e) => {
+                    warn!("Payload on topic '{}' didn't decompress: {}", topic, e);
+                    self.skips.record(SkipReason::InvalidCompressed, &topic, payload);
+                    payload
+                }
+            }
+        } else {
+            payload
+        };
+        // MessagePack and CBOR continue as the equivalent JSON text
+        let converted;
+        let payload: &[u8] = match self.payload_formats.find(&topic).and_then(|format| format.decode(payload)) {
+            Some(Ok(json_val)) => {
+                converted = json_val.to_string();
+                converted.as_bytes()
+            }
+            Some(Err(e)) => {
+                warn!("Payload on topic '{}' didn't decode: {}", topic, e);
+                self.skips.record(SkipReason::InvalidBinary, &topic, payload);
+                payload
+            }
+            None => payload,
+        };
+        // Try UTF-8 conversion, but don't crash on failure
+        let started = self.timings.start();
+        let decoded = std::str::from_utf8(payload);
+        self.timings.record(Stage::Decode, started);
+        let message: Cow<'_, str> = match decoded {
+            Ok(s) => Cow::Borrowed(s),
+            Err(_) => {
+                self.skips.record(SkipReason::InvalidUtf8, &topic, payload);
+                let Some(decoded) = self.invalid_utf8.decode_binary(payload) else {
+                    warn!("Dropping binary MQTT message on topic '{}': {} bytes", topic, payload.len());
+                    return Ok(());
+                };
+                warn!("Received binary MQTT message on topic '{}': {} bytes, decoding with invalid_utf8={:?}", topic, payload.len(), self.invalid_utf8);
+                Cow::Owned(decoded)
+            }
+        };
+
+        debug!("(Rust) handle_mqtt_message: {} => {}", topic, message);
+
+        let Some(ref topics) = self.mqtt_topics else {
+            error!("mqtt_topics was never initialized!");
+            return Ok(()); 
+        };
+        if topic.starts_with(&self.base_topic) {
+        // Match the topic to whichever action it needs
+            if topic == topics.miniserver_startup_topic {
+                if pyget!(self.global_config, py, "miniserver", "sync_with_miniserver").extract::<bool>()? {
+                    info!("Miniserver startup detected, resyncing whitelist (from Rust)");
+                    let _ = self.relay_main_obj.bind(py).call_method0("schedule_miniserver_sync")?;
+                }
+            }
             else if topic == topics.start_ui_topic {
                 let coro = self.relay_main_obj.bind(py).call_method0("start_ui")?;
                 let fut = into_future(coro.clone())?;
                 pyo3_async_runtimes::tokio::get_runtime().spawn(async move {
                     if let Err(e) = fut.await {
-                        error!("Error in start_ui async call: {:?}", e);
+                        error!("Error in start_ui async call: {:?}", e);
+                    }
+                });
+            }
+            else if topic == topics.stop_ui_topic {
+                let coro = self.relay_main_obj.bind(py).call_method0("stop_ui")?;
+                let fut = into_future(coro.clone())?;
+                pyo3_async_runtimes::tokio::get_runtime().spawn(async move {
+                    if let Err(e) = fut.await {
+                        error!("Error in stop_ui async call: {:?}", e);
+                    }
+                });
+            }
+       
+            else if topic == topics.config_get_topic {
+                // global_config.get_safe_config -> JSON -> publish, optionally just the part at the path in the payload
+                let global_config_py = self
+                    .relay_main_obj
+                    .bind(py)
+                    .getattr(intern!(py, "miniserver_data_processor"))?
+                    .getattr(intern!(py, "global_config"))?;
+                let safe_cfg = py_json::to_value(&global_config_py.call_method0("get_safe_config")?)?;
+                let path = message.trim();
+                let serialized = if path.is_empty() {
+                    safe_cfg.to_string()
+                } else {
+                    config_subtree(&safe_cfg, path).unwrap_or_else(|e| json!({"error": e})).to_string()
+                };
+                let coro = self
+                    .mqtt_client_obj
+                    .bind(py)
+                    .call_method1("publish", (topics.config_response_topic.clone(), serialized))?;
+                let fut = into_future(coro.clone())?;
+                pyo3_async_runtimes::tokio::get_runtime().spawn(async move {
+                    if let Err(e) = fut.await {
+                        error!("Error publishing config response: {:?}", e);
+                    }
+                });
+            }
+            else if topic == topics.config_set_topic || topic == topics.config_add_topic || topic == topics.config_remove_topic {
+                let update_mode = if topic == topics.config_set_topic {
+                    "set"
+                } else if topic == topics.config_add_topic {
+                    "add"
+                } else {
+                    "remove"
+                };
+                let (result, restart) = self.apply_config_update(py, &message, update_mode)?;
+                let coro = self
+                    .mqtt_client_obj
+                    .bind(py)
+                    .call_method1("publish", (topics.config_response_topic.clone(), result.to_string()))?;
+                let fut = into_future(coro.clone())?;
+                // The restart waits for the result to go out
+                let relay_main = restart.then(|| self.relay_main_obj.clone_ref(py));
+                pyo3_async_runtimes::tokio::get_runtime().spawn(async move {
+                    match tokio::time::timeout(CONFIG_RESPONSE_TIMEOUT, fut).await {
+                        Ok(Err(e)) => error!("Error publishing config response: {:?}", e),
+                        Err(_) => warn!("Publishing the config response timed out"),
+                        Ok(Ok(_)) => {}
+                    }
+                    if let Some(relay_main) = relay_main {
+                        info!("Configuration updated via MQTT. Restarting program (from Rust).");
+                        Python::attach(|py| {
+                            if let Err(e) = relay_main.bind(py).call_method0("restart_relay_incl_ui") {
+                                error!("Error restarting after the config update: {:?}", e);
+                            }
+                        });
+                    }
+                });
+            }
+            else if topic == topics.config_validate_topic {
+                self.publish_config_validation(py, &message, topics.config_validate_response_topic.clone())?;
+            }
+            else if topic == topics.filters_set_topic
+                || topic == topics.dnf_set_topic
+                || topic == topics.whitelist_add_topic
+                || topic == topics.whitelist_remove_topic
+            {
+                if let Err(e) = self.apply_list_update(py, topics, &topic, &message) {
+                    error!("Error applying {} from MQTT: {:?}", topic, e);
+                }
+            }
+            else if topic == topics.whitelist_get_topic {
+                self.publish_whitelist(py, &message, topics.whitelist_response_topic.clone())?;
+            }
+            else if topic == topics.stats_get_topic || topic == topics.stats_reset_topic {
+                let reset = topic == topics.stats_reset_topic;
+                let mut snapshot = self.stats_snapshot(reset);
+                snapshot["reset"] = json!(reset);
+                if reset {
+                    info!("Stats reset via MQTT");
+                }
+                let coro = self
+                    .mqtt_client_obj
+                    .bind(py)
+                    .call_method1("publish", (topics.stats_response_topic.clone(), snapshot.to_string()))?;
+                let fut = into_future(coro.clone())?;
+                pyo3_async_runtimes::tokio::get_runtime().spawn(async move {
+                    if let Err(e) = fut.await {
+                        error!("Error publishing stats: {:?}", e);
+                    }
+                });
+            }
+            else if topic == topics.debug_set_topic {
+                let options = py_json::loads(py, &message).and_then(|options| {
+                    let get = |key: &str| -> PyResult<Option<bool>> {
+                        match options.get_item(key) {
+                            Ok(value) => value.extract().map(Some),
+                            Err(e) if e.is_instance_of::<pyo3::exceptions::PyKeyError>(py) => Ok(None),
+                            Err(e) => Err(e),
+                        }
+                    };
+                    Ok((
+                        get("publish_processed_topics")?,
+                        get("publish_forwarded_topics")?,
+                        get("stage_timing")?,
+                        get("publish_trace")?,
+                    ))
+                });
+                match options {
+                    Ok((processed, forwarded, stage_timing, trace)) => {
+                        self.set_debug_options(processed, forwarded, stage_timing, trace);
+                    }
+                    Err(e) => error!("Invalid debug options via MQTT: {:?}", e),
+                }
+                // {"rust_log_levels": {"<module>": "<level>" or null}}
+                let levels = py_json::loads(py, &message)
+                    .and_then(|options| options.get_item("rust_log_levels"))
+                    .and_then(|levels| levels.extract::<HashMap<String, Option<Bound<'_, PyAny>>>>());
+                if let Ok(levels) = levels {
+                    for (module, level) in levels {
+                        if let Err(e) = set_rust_log_level(&module, level.as_ref()) {
+                            error!("Invalid Rust log level via MQTT: {:?}", e);
+                        }
+                    }
+                }
+            }
+            else if topic == topics.ha_lock_topic {
+                let _ = self.relay_main_obj.bind(py).call_method1("handle_ha_lock", (message.as_ref(),))?;
+            }
+            else if topic == topics.delivery_get_topic {
+                let filter = message.trim();
+                let status = self.last_values.to_py(py, (!filter.is_empty()).then_some(filter))?;
+                let serialized = py_json::dumps(status.as_any())?;
+                let coro = self
+                    .mqtt_client_obj
+                    .bind(py)
+                    .call_method1("publish", (topics.delivery_response_topic.clone(), serialized))?;
+                let fut = into_future(coro.clone())?;
+                pyo3_async_runtimes::tokio::get_runtime().spawn(async move {
+                    if let Err(e) = fut.await {
+                        error!("Error publishing delivery status: {:?}", e);
+                    }
+                });
+            }
+            else if topic == topics.filters_test_topic {
+                // A JSON list of topics, or one topic per line
+                let candidates = serde_json::from_str::<Vec<String>>(&message)
+                    .unwrap_or_else(|_| message.lines().map(str::trim).filter(|l| !l.is_empty()).map(String::from).collect());
+                let results = py.detach(|| self.filter_results(&candidates))?;
+                let coro = self
+                    .mqtt_client_obj
+                    .bind(py)
+                    .call_method1("publish", (topics.filters_test_response_topic.clone(), Value::Object(results).to_string()))?;
+                let fut = into_future(coro.clone())?;
+                pyo3_async_runtimes::tokio::get_runtime().spawn(async move {
+                    if let Err(e) = fut.await {
+                        error!("Error publishing filter test results: {:?}", e);
+                    }
+                });
+            }
+            else if topic == topics.pause_topic {
+                self.pause();
+            }
+            else if topic == topics.resume_topic {
+                self.resume(py)?;
+            }
+            else if topic == topics.export_topic {
+                let _ = self.relay_main_obj.bind(py).call_method1("export_virtual_inputs", (message.as_ref(),))?;
+            }
+            else if topic == topics.config_update_topic || topic == topics.config_restart_topic {
+                info!("Reloading configuration. Restarting program (from Rust).");
+                let _ = self.relay_main_obj.bind(py).call_method0("restart_relay_incl_ui");
+            }
+            else if self.forward_unknown_subtopics {
+                self.data_message(py, topic, message, completion, collect);
+            }
+            else {
+                debug!("Ignoring unknown control topic '{}'", topic);
+            }
+        }
+        else {
+            self.data_message(py, topic, message, completion, collect);
+        }
+
+        Ok(())
+    }
+
+    /// A data message of `handle_message`: processed, or added to `collect`.
+    fn data_message(
+        &self,
+        py: Python<'_>,
+        topic: String,
+        message: Cow<'_, str>,
+        completion: Option<&Completion>,
+        collect: Option<&mut Vec<(String, String)>>,
+    ) {
+        match collect {
+            Some(collect) => collect.push((topic, message.into_owned())),
+            None => {
+                let _ = self.process_message(py, &topic, &message, completion);
+            }
+        }
+    }
+
+    fn process_message(&self, py: Python<'_>, topic: &str, message: &str, completion: Option<&Completion>) -> PyResult<()> {
+        debug!("Processing data - topic: {}, message: {}", topic, message);
+
+        if !self.accepting(topic) {
+            return Ok(());
+        }
+        let _timer = self.timings.time(Stage::Message);
+        let mut trace = self.new_trace(topic, message);
+        let prepared = py.detach(|| self.prepare_values(topic, message, true, trace.as_mut()))?;
+        // With batch_mode = "message": the values for UDP and for the Miniserver's batch_input
+        let mut batched = Batched::default();
+        self.dispatch_values(py, topic, prepared, trace.as_mut(), completion, &mut batched)?;
+        self.start_batches(py, batched)?;
+        self.finish_trace(py, topic, trace)
+    }
+
+    /// The data messages of a batch call, as one message each would be, except that all
+    /// are prepared with the GIL released once and, with `batch_mode = "message"`, the
+    /// values of all of them go to the sender as one batch. A message that fails is
+    /// logged and skipped, the others are still sent. Returns the number of messages
+    /// handled without error.
+    fn process_messages(&self, py: Python<'_>, messages: &[(String, String)]) -> usize {
+        if messages.iter().all(|(topic, _)| !self.accepting(topic)) {
+            return messages.len();
+        }
+        let prepared: Vec<_> = py.detach(|| {
+            messages
+                .iter()
+                .map(|(topic, message)| {
+                    debug!("Processing data - topic: {}, message: {}", topic, message);
+                    let _timer = self.timings.time(Stage::Message);
+                    let mut trace = self.new_trace(topic, message);
+                    let prepared = self.prepare_values(topic, message, true, trace.as_mut());
+                    (trace, prepared)
+                })
+                .collect()
+        });
+        let mut batched = Batched::default();
+        let mut handled = 0;
+        for ((topic, _), (mut trace, prepared)) in messages.iter().zip(prepared) {
+            let result = prepared
+                .and_then(|prepared| self.dispatch_values(py, topic, prepared, trace.as_mut(), None, &mut batched))
+                .and_then(|()| self.finish_trace(py, topic, trace));
+            match result {
+                Ok(()) => handled += 1,
+                Err(e) => error!("Error processing MQTT message on topic '{}' in batch: {:?}", topic, e),
+            }
+        }
+        if let Err(e) = self.start_batches(py, batched) {
+            error!("Error sending the batched values: {:?}", e);
+        }
+        handled
+    }
+
+    /// Whether data messages are processed at all: not on a standby instance or while
+    /// shutting down.
+    fn accepting(&self, topic: &str) -> bool {
+        if !self.active.load(Ordering::Relaxed) {
+            debug!("Standby instance, not forwarding topic '{}'", topic);
+            return false;
+        }
+        if self.closing.load(Ordering::Relaxed) {
+            debug!("Shutting down, not forwarding topic '{}'", topic);
+            return false;
+        }
+        true
+    }
+
+    fn new_trace(&self, topic: &str, message: &str) -> Option<MessageTrace> {
+        (self.publish_trace.load(Ordering::Relaxed) || self.traces.enabled()).then(|| MessageTrace::new(unix_now(), topic, message))
+    }
+
+    fn finish_trace(&self, py: Python<'_>, topic: &str, trace: Option<MessageTrace>) -> PyResult<()> {
+        if let Some(trace) = trace {
+            if self.publish_trace.load(Ordering::Relaxed) {
+                self.publish_debug(py, "trace", &self.normalize_topic(topic)?, &trace.to_json().to_string());
+            }
+            self.traces.push(trace);
+        }
+        Ok(())
+    }
+
+    /// Start the batches collected by `dispatch_values`.
+    fn start_batches(&self, py: Python<'_>, batched: Batched) -> PyResult<()> {
+        let Batched { udp, miniserver } = batched;
+        if !udp.is_empty() {
+            self.start_batch(py, true, udp, None)?;
+        }
+        if !miniserver.is_empty() {
+            self.start_batch(py, false, miniserver, None)?;
+        }
+        Ok(())
+    }
+
+    /// Hand the prepared values of a message on `topic` to their senders; values for a
+    /// batch are added to `batched`.
+    fn dispatch_values(
+        &self,
+        py: Python<'_>,
+        topic: &str,
+        prepared: Vec<PreparedValue>,
+        mut trace: Option<&mut MessageTrace>,
+        completion: Option<&Completion>,
+        batched: &mut Batched,
+    ) -> PyResult<()> {
+        let targets = self.targets.get();
+        for (index, value) in prepared.into_iter().enumerate() {
+            if let Some((name, original)) = &value.processed {
+                self.publish_debug(py, "processedtopics", name, original);
+            }
+            if let Some(monitored) = &value.monitored {
+                self.publish_debug(py, "monitored", &value.topic, monitored);
+            }
+            let Some(ForwardValue { name, value: val, transport, boolean }) = value.forward else {
+                continue;
+            };
+            // One trace entry per value, in the same order
+            let traced = trace.as_deref_mut().and_then(|trace| trace.values.get_mut(index));
+            let t = value.topic;
+            let pending = completion.map(|completion| completion.track(&t, &name, &val));
+            if let Some(ha) = &self.ha_discovery {
+                ha.announce(py, &name, &t, &val, boolean);
+            }
+            if let Some(watchdog) = self.watchdog.as_ref().filter(|watchdog| watchdog.watches(&t)) {
+                if watchdog.touch(&name, &t) {
+                    info!("{} (as {}) is sending values again", t, name);
+                    self.publish_availability(py, &name, true);
+                }
+            }
+            if self.paused.load(Ordering::Relaxed) {
+                debug!("Forwarding paused, holding {} (as {})={}", t, name, val);
+                self.last_values.hold(&name, &t, &val);
+                self.stats.add(Counter::Held);
+                if let Some(pending) = pending {
+                    pending.complete(DeliveryStatus::Paused, None);
+                }
+                if let Some(traced) = traced {
+                    traced.outcome = Outcome::Paused;
+                }
+                continue;
+            }
+            if self.publish_forwarded.load(Ordering::Relaxed) || self.ha_discovery.is_some() {
+                self.publish_debug(py, "forwardedtopics", &name, &val);
+            }
+            self.metrics.topic(topic, TopicEvent::Forwarded);
+            let started = self.timings.start();
+            let target = if transport != Transport::Udp { targets.route(&t) } else { None };
+            let outcome = match (transport, &self.udp_forwarder, target) {
+                (_, _, Some(target)) => {
+                    let reason = format!("to Miniserver '{}'", target.name());
+                    self.send_to_target(Arc::clone(target), t, name, val, pending);
+                    (Outcome::Forwarded, Some(reason))
+                }
+                (Transport::Udp, Some(_), _) if self.batching.is_some() => {
+                    self.add_to_batch(py, true, t, name, val, pending, &mut batched.udp)?;
+                    (Outcome::Batched, None)
+                }
+                (Transport::Udp, Some(forwarder), _) => {
+                    self.forward_udp(forwarder, &t, &name, &val, pending);
+                    (Outcome::Forwarded, Some("udp".to_string()))
+                }
+                _ => match self.rate_limiter.admit(&name) {
+                    Admission::Send if self.batching.as_ref().is_some_and(|b| !b.input.is_empty()) => {
+                        self.add_to_batch(py, false, t, name, val, pending, &mut batched.miniserver)?;
+                        (Outcome::Batched, None)
+                    }
+                    Admission::Send => {
+                        self.dispatch_held(py, t, name, val, transport, None, pending)?;
+                        (Outcome::Forwarded, None)
+                    }
+                    Admission::Hold(wait, generation) => {
+                        debug!("Rate limit: holding {} (as {})={} for {:?}", t, name, val, wait);
+                        self.dispatch_held(py, t, name, val, transport, Some((wait, generation)), pending)?;
+                        (Outcome::RateLimited, Some(format!("held for {} ms", wait.as_millis())))
+                    }
+                },
+            };
+            self.timings.record(Stage::Dispatch, started);
+            if let Some(traced) = trace.as_deref_mut().and_then(|trace| trace.values.get_mut(index)) {
+                (traced.outcome, traced.reason) = outcome;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `json` is nested deeper than `processing.max_json_depth`.
+    fn too_deep(&self, json: &Value) -> bool {
+        self.max_json_depth > 0 && flatten::json_depth(json) > self.max_json_depth
+    }
+
+    /// The line format of `topic`'s payloads, if it has one.
+    fn line_format(&self, topic: &str) -> Option<LineFormat> {
+        if self.key_value_topics.is_match(topic) {
+            Some(LineFormat::KeyValue)
+        } else if self.influx_topics.is_match(topic) {
+            Some(LineFormat::Influx)
+        } else {
+            None
+        }
+    }
+
+    /// Drop a message over `max_json_depth` or `max_json_keys`; no values.
+    fn drop_too_complex(&self, topic: &str, message: &str, live: bool, trace: Option<&mut MessageTrace>, reason: String) -> Vec<PreparedValue> {
+        warn!("Dropping MQTT message on topic '{}': {}", topic, reason);
+        if live {
+            self.skips.record(SkipReason::TooComplex, topic, message.as_bytes());
+        }
+        if let Some(trace) = trace {
+            trace.dropped = Some(reason);
+        }
+        Vec::new()
+    }
+
+    /// Everything of `process_message` that doesn't need Python: expansion, filters,
+    /// rules, conversion and duplicate checks, run with the GIL released. Without `live`
+    /// (for `explain`) no statistics or records are touched; `trace` gets an entry per value.
+    fn prepare_values(
+        &self,
+        topic: &str,
+        message: &str,
+        live: bool,
+        mut trace: Option<&mut MessageTrace>,
+    ) -> PyResult<Vec<PreparedValue>> {
+        // Normalize topic for whitelist comparison right away
+        let normalized_topic = self.normalize_topic(topic)?;
+        debug!("Normalized topic for processing: '{}'", normalized_topic);
+
+        // subscription filter (on original topic). With rules configured or a policy that
+        // lets the whitelist win, flattened keys may still pass, and rewrites change the
+        // topic the filters see, so the check moves into the per-key pass.
+        if live {
+            self.stats.add(Counter::Messages);
+            self.metrics.topic(topic, TopicEvent::Received);
+        }
+        if self.filters_checked_early() && self.subscription_filters.get().is_match(topic) {
+            debug!("Topic '{}' filtered by subscription filter", topic);
+            if live {
+                self.stats.add_filtered(FilterKind::Subscription);
+                self.metrics.topic(topic, TopicEvent::Filtered);
+            }
+            if let Some(trace) = trace {
+                trace.dropped = Some(self.filter_reason(FilterKind::Subscription, topic, topic, &normalized_topic));
+            }
+            return Ok(Vec::new());
+        }
+
+        let expand = self.expand_json.load(Ordering::Relaxed);
+        debug!("Transforming data with expand_json={}", expand);
+
+        let scripts = self.scripts.get();
+        let mut flattened: Vec<(String, String)> = if let Some(index) = scripts.find(topic) {
+            // A matching script replaces JSON expansion and decides the outputs itself
+            let json = serde_json::from_str::<Value>(message).ok();
+            match scripts.run(index, topic, message, json.as_ref()) {
+                Ok(outputs) => outputs,
+                Err(e) => {
+                    error!("Script for topic '{}' failed: {}", topic, e);
+                    if live {
+                        self.skips.record(SkipReason::ScriptError, topic, message.as_bytes());
+                    }
+                    if let Some(trace) = trace {
+                        trace.dropped = Some(format!("script failed: {}", e));
+                    }
+                    return Ok(Vec::new());
+                }
+            }
+        } else if let Some(index) = self.plugins.find(topic) {
+            // Same for a matching WASM plugin
+            match self.plugins.run(index, topic, message) {
+                Ok(outputs) => outputs,
+                Err(e) => {
+                    error!("Plugin for topic '{}' failed: {}", topic, e);
+                    if live {
+                        self.skips.record(SkipReason::PluginError, topic, message.as_bytes());
+                    }
+                    if let Some(trace) = trace {
+                        trace.dropped = Some(format!("plugin failed: {}", e));
+                    }
+                    return Ok(Vec::new());
+                }
+            }
+        } else if let Some(index) = self.extractions.find(topic) {
+            // Only the configured fields, whether expand_json is on or not
+            let started = self.timings.start();
+            let parsed = serde_json::from_str::<Value>(message);
+            self.timings.record(Stage::Parse, started);
+            match parsed {
+                Ok(json_val) if self.too_deep(&json_val) => {
+                    return Ok(self.drop_too_complex(topic, message, live, trace, format!("nested deeper than {} levels", self.max_json_depth)));
+                }
+                Ok(json_val) => {
+                    let started = self.timings.start();
+                    let extracted = self.extractions.extract(index, topic, &json_val, &self.flatten);
+                    self.timings.record(Stage::Flatten, started);
+                    extracted
+                }
+                Err(_) => {
+                    if live {
+                        self.skips.record(SkipReason::InvalidJson, topic, message.as_bytes());
+                    }
+                    vec![(topic.to_string(), message.to_string())]
+                }
+            }
+        } else if self.xml_topics.is_match(topic) {
+            // Flattened whether expand_json is on or not, XML has no other use for the Miniserver
+            let started = self.timings.start();
+            let parsed = xml_payload::decode(message);
+            self.timings.record(Stage::Parse, started);
+            match parsed {
+                Ok(xml_val) if self.too_deep(&xml_val) => {
+                    return Ok(self.drop_too_complex(topic, message, live, trace, format!("nested deeper than {} levels", self.max_json_depth)));
+                }
+                Ok(Value::String(text)) => vec![(topic.to_string(), text)],
+                Ok(xml_val) => {
+                    let started = self.timings.start();
+                    let mut flat_vec = Vec::new();
+                    flatten_json(&xml_val, "", &self.flatten, &mut flat_vec);
+                    self.timings.record(Stage::Flatten, started);
+                    flat_vec.into_iter().map(|(k, v)| (format!("{}/{}", topic, k), v)).collect()
+                }
+                Err(e) => {
+                    debug!("Payload on topic '{}' isn't well-formed XML: {}", topic, e);
+                    if live {
+                        self.skips.record(SkipReason::InvalidXml, topic, message.as_bytes());
+                    }
+                    vec![(topic.to_string(), message.to_string())]
+                }
+            }
+        } else if let Some(format) = self.line_format(topic) {
+            let started = self.timings.start();
+            let parsed = format.parse(message);
+            self.timings.record(Stage::Parse, started);
+            match parsed {
+                Ok(values) => values.into_iter().map(|(k, v)| (format!("{}/{}", topic, k), v)).collect(),
+                Err(e) => {
+                    debug!("Payload on topic '{}' isn't valid {:?}: {}", topic, format, e);
+                    if live {
+                        let reason = match format {
+                            LineFormat::KeyValue => SkipReason::InvalidKeyValue,
+                            LineFormat::Influx => SkipReason::InvalidInflux,
+                        };
+                        self.skips.record(reason, topic, message.as_bytes());
+                    }
+                    vec![(topic.to_string(), message.to_string())]
+                }
+            }
+        } else if expand {
+            let started = self.timings.start();
+            let parsed = serde_json::from_str::<Value>(message);
+            self.timings.record(Stage::Parse, started);
+            match parsed {
+                Ok(json_val) if self.too_deep(&json_val) => {
+                    return Ok(self.drop_too_complex(topic, message, live, trace, format!("nested deeper than {} levels", self.max_json_depth)));
+                }
+                Ok(json_val) => {
+                    if !json_val.is_object() {
+                        vec![(topic.to_string(), message.to_string())]
+                    } else {
+                        let started = self.timings.start();
+                        let mut flat_vec = Vec::new();
+                        flatten_json(&json_val, "", &self.flatten, &mut flat_vec);
+                        let flattened = flat_vec.into_iter().map(|(k, v)| (format!("{}/{}", topic, k), v)).collect();
+                        self.timings.record(Stage::Flatten, started);
+                        flattened
+                    }
+                }
+                Err(_) => {
+                    if live && message.starts_with('{') {
+                        self.skips.record(SkipReason::InvalidJson, topic, message.as_bytes());
+                    }
+                    vec![(topic.to_string(), message.to_string())]
+                }
+            }
+        } else {
+            vec![(topic.to_string(), message.to_string())]
+        };
+        debug!("Data after flattening: {:?}", flattened);
+        if self.max_json_keys > 0 && flattened.len() > self.max_json_keys {
+            let reason = format!("{} values, more than {}", flattened.len(), self.max_json_keys);
+            return Ok(self.drop_too_complex(topic, message, live, trace, reason));
+        }
+        // Derived topics go through the same filters and conversion as the values they come from
+        let derived = self.derived.get().update(&flattened, live);
+        if !derived.is_empty() {
+            debug!("Derived values: {:?}", derived);
+            flattened.extend(derived);
+        }
+
+        let rules = self.rules.get();
+        let monitor_topics = self.monitor_topics.get();
+        let publish_processed = live && self.publish_processed.load(Ordering::Relaxed);
+        let mut prepared = Vec::with_capacity(flattened.len());
+        let mut template_context = MessageContext::new(message);
+        for (t, mut v) in flattened {
+            if live {
+                self.stats.add(Counter::Values);
+            }
+            let mut cur_t_normalized = self.virtual_input_name(&t)?;
+            let processed = publish_processed.then(|| (cur_t_normalized.clone(), v.clone()));
+
+            // Monitored topics are converted like any other but only ever published
+            if monitor_topics.is_match(&t) {
+                let started = self.timings.start();
+                let convert_bools = !self.bool_exclude_topics.is_match(&t);
+                let result = self.normalize_value(topic, &t, &v, convert_bools, live)?;
+                self.timings.record(Stage::Convert, started);
+                if live && result.is_ok() {
+                    self.stats.add(Counter::Monitored);
+                }
+                if let Some(trace) = trace.as_deref_mut() {
+                    let (value, outcome, reason) = match &result {
+                        Ok(val) => (val.clone(), Outcome::Monitored, self.monitor_reason(&t)),
+                        Err(not_sent) => (v, not_sent.outcome, not_sent.reason.clone()),
+                    };
+                    trace.values.push(ValueTrace { topic: t.clone(), name: cur_t_normalized, value, outcome, reason: Some(reason) });
+                }
+                prepared.push(PreparedValue { topic: t, processed, forward: None, monitored: result.ok() });
+                continue;
+            }
+
+            // Ordered rules decide first; topics no rule decides about fall through to the
+            // filter lists
+            let started = self.timings.start();
+            let decision = rules.evaluate(&t, &mut v).unwrap_or_default();
+            let transport = decision.transport.unwrap_or(self.default_transport);
+            if let Some(target) = &decision.target {
+                cur_t_normalized = self.vi_names.fit(self.normalize_topic(target)?);
+            }
+            let filtered = match decision.verdict {
+                Some(Verdict::Drop { index }) => {
+                    debug!("Topic '{}' dropped by rule {}", t, index);
+                    Some(FilterKind::Rule)
+                }
+                Some(Verdict::Accept { index }) => {
+                    debug!("Topic '{}' accepted by rule {}", t, index);
+                    None
+                }
+                None => self.filtered_by(topic, &t, &cur_t_normalized),
+            };
+            self.timings.record(Stage::Filter, started);
+            let result = match filtered {
+                Some(kind) => {
+                    if live {
+                        self.stats.add_filtered(kind);
+                        self.metrics.topic(topic, TopicEvent::Filtered);
+                    }
+                    let reason = match decision.verdict {
+                        Some(Verdict::Drop { index }) => format!("rule #{}", index),
+                        _ if trace.is_some() => self.filter_reason(kind, topic, &t, &cur_t_normalized),
+                        _ => String::new(),
+                    };
+                    Err(NotSent { outcome: Outcome::Filtered, reason })
+                }
+                None => self.convert_value(topic, &t, cur_t_normalized.clone(), &v, transport, &mut template_context, live)?,
+            };
+            if let Some(trace) = trace.as_deref_mut() {
+                trace.values.push(match &result {
+                    Ok(forward) => ValueTrace {
+                        topic: t.clone(),
+                        name: forward.name.clone(),
+                        value: forward.value.clone(),
+                        outcome: Outcome::Forwarded,
+                        reason: rule_reason(&decision),
+                    },
+                    Err(not_sent) => ValueTrace {
+                        topic: t.clone(),
+                        name: cur_t_normalized,
+                        value: v,
+                        outcome: not_sent.outcome,
+                        reason: Some(not_sent.reason.clone()),
+                    },
+                });
+            }
+            prepared.push(PreparedValue { topic: t, processed, forward: result.ok(), monitored: None });
+        }
+        Ok(prepared)
+    }
+
+    /// Conversion, transforms, templates and duplicate checks for a value that passed the
+    /// filters.
+    #[allow(clippy::too_many_arguments)]
+    fn convert_value(
+        &self,
+        topic: &str,
+        t: &str,
+        name: String,
+        v: &str,
+        transport: Transport,
+        template_context: &mut MessageContext<'_>,
+        live: bool,
+    ) -> PyResult<Result<ForwardValue, NotSent>> {
+        debug!("Topic '{}' passed all filters, sending to miniserver", t);
+        let started = self.timings.start();
+        let convert_bools = !self.bool_exclude_topics.is_match(t);
+        let mut val = match self.normalize_value(topic, t, v, convert_bools, live)? {
+            Ok(val) => val,
+            Err(not_sent) => {
+                self.timings.record(Stage::Convert, started);
+                return Ok(Err(not_sent));
+            }
+        };
+        let destination = if transport == Transport::Udp { "udp" } else { "miniserver" };
+        let rendered = self.templates.render(destination, template_context, t, &name, &val);
+        self.timings.record(Stage::Convert, started);
+        match rendered {
+            Some(Ok(rendered)) => val = rendered,
+            Some(Err(e)) => {
+                error!("Failed to render payload for topic '{}': {}", t, e);
+                if live {
+                    self.skips.record(SkipReason::TemplateError, t, val.as_bytes());
+                }
+                return Ok(Err(NotSent { outcome: Outcome::Skipped, reason: format!("template failed: {}", e) }));
+            }
+            None => {}
+        }
+        if self.duplicate_window > 0.0 && self.last_values.recently_delivered(&name, &val, self.duplicate_window) {
+            debug!("Skipping {}={}, delivered less than {}s ago", name, val, self.duplicate_window);
+            if live {
+                self.stats.add(Counter::Duplicates);
+            }
+            let reason = format!("delivered less than {}s ago", self.duplicate_window);
+            return Ok(Err(NotSent { outcome: Outcome::Duplicate, reason }));
+        }
+        if self.forward_only_changes && self.last_values.unchanged(&name, &val, self.changes_max_age) {
+            debug!("Skipping {}={}, unchanged since the last send", name, val);
+            if live {
+                self.stats.add(Counter::Duplicates);
+            }
+            let reason = "unchanged since the last send".to_string();
+            return Ok(Err(NotSent { outcome: Outcome::Duplicate, reason }));
+        }
+        if live {
+            self.track_forwarded(&name, t);
+        }
+        // "on", "true" and the like, but not the numbers 1 and 0
+        let original = v.trim().to_lowercase();
+        let boolean = convert_bools && self.bool_table.convert(&original).is_some() && original.parse::<f64>().is_err();
+        Ok(Ok(ForwardValue { name, value: val, transport, boolean }))
+    }
+
+    /// Boolean conversion, timestamps, number extraction and transforms: what happens to
+    /// a value before templates and duplicate checks, for forwarded and monitored values alike.
+    fn normalize_value(&self, topic: &str, t: &str, v: &str, convert_bools: bool, live: bool) -> PyResult<Result<String, NotSent>> {
+        let converted = if convert_bools { self._convert_boolean(v)? } else { Some(v.to_string()) };
+        let Some(mut val) = converted else {
+            return Ok(Err(NotSent { outcome: Outcome::Skipped, reason: "no value after boolean conversion".to_string() }));
+        };
+        if let Some(seconds) = self.timestamps.get().apply(t, &val) {
+            val = seconds;
+        } else if self.numeric_topics.is_match(t) {
+            match extract_number(&val) {
+                Some(number) => val = number,
+                None => debug!("No number in {}={}, forwarding it as it is", t, val),
+            }
+        }
+        match self.transforms.get().apply(t, &val) {
+            Transformed::Unchanged => {}
+            Transformed::Value(transformed) => val = transformed,
+            Transformed::OutOfRange(number) => {
+                debug!("Dropping {}={}, {} is out of range", t, val, number);
+                if live {
+                    self.stats.add_filtered(FilterKind::OutOfRange);
+                    self.metrics.topic(topic, TopicEvent::Filtered);
+                }
+                let reason = format!("{} is outside the range of its transform", number);
+                return Ok(Err(NotSent { outcome: Outcome::Filtered, reason }));
+            }
+        }
+        Ok(Ok(val))
+    }
+
+    /// `test_filters` for `topics`, each taken as a topic that isn't expanded any further.
+    fn filter_results(&self, topics: &[String]) -> PyResult<Map<String, Value>> {
+        let rules = self.rules.get();
+        let targets = self.targets.get();
+        let whitelist = self.topic_whitelist.get();
+        let entry = |list: &FilterList, topic: &str| {
+            list.matching_pattern(topic).map(|(index, pattern)| json!({"index": index, "pattern": pattern}))
+        };
+        let mut results = Map::new();
+        for topic in topics {
+            let mut name = self.virtual_input_name(topic)?;
+            let decision = rules.evaluate(topic, &mut String::new()).unwrap_or_default();
+            if let Some(target) = &decision.target {
+                name = self.vi_names.fit(self.normalize_topic(target)?);
+            }
+            let filtered = match decision.verdict {
+                Some(Verdict::Drop { index }) => Some(format!("rule #{}", index)),
+                Some(Verdict::Accept { .. }) => None,
+                None if self.filters_checked_early() && self.subscription_filters.get().is_match(topic) => {
+                    Some(self.filter_reason(FilterKind::Subscription, topic, topic, &name))
+                }
+                None => self.filtered_by(topic, topic, &name).map(|kind| self.filter_reason(kind, topic, topic, &name)),
+            };
+            let rule_index = match decision.verdict {
+                Some(Verdict::Drop { index, .. }) | Some(Verdict::Accept { index, .. }) => Some(index),
+                None => None,
+            };
+            let monitored = entry(&self.monitor_topics.get(), topic);
+            let whitelisted = (!whitelist.is_empty()).then(|| whitelist.allows(&name, topic));
+            let (_, rewritten) = self.rewritten_for_filters(topic, topic);
+            results.insert(
+                topic.clone(),
+                json!({
+                    "name": name,
+                    "result": if monitored.is_some() { "monitored" } else if filtered.is_some() { "filtered" } else { "forwarded" },
+                    "reason": if monitored.is_some() { Some(self.monitor_reason(topic)) } else { filtered },
+                    "rule": rule_index,
+                    "subscription_filter": entry(&self.subscription_filters.get(), &rewritten),
+                    "whitelisted": whitelisted,
+                    "post_expansion_filter": entry(&self.post_expansion_filters.get(), &rewritten),
+                    "do_not_forward": entry(&self.do_not_forward_patterns.get(), &rewritten),
+                    "monitor_topics": monitored,
+                    "target": targets.route_name(topic),
+                }),
+            );
+        }
+        Ok(results)
+    }
+
+    /// The filter entry behind a `filtered_by` result, for traces: the list and the index
+    /// and text of the first pattern matching the topic.
+    fn filter_reason(&self, kind: FilterKind, original_topic: &str, t: &str, name: &str) -> String {
+        let (original_topic, t) = self.rewritten_for_filters(original_topic, t);
+        let (original_topic, t) = (original_topic.as_ref(), t.as_ref());
+        let entry = |list: &FilterList, topics: &[&str]| {
+            topics
+                .iter()
+                .find_map(|topic| list.matching_pattern(topic))
+                .map(|(index, pattern)| format!(" #{} '{}'", index, pattern))
+                .unwrap_or_default()
+        };
+        match kind {
+            FilterKind::Subscription => {
+                format!("subscription_filter{}", entry(&self.subscription_filters.get(), &[original_topic, t]))
+            }
+            FilterKind::PostExpansion => format!("post_expansion_filter{}", entry(&self.post_expansion_filters.get(), &[t])),
+            FilterKind::DoNotForward => format!("do_not_forward{}", entry(&self.do_not_forward_patterns.get(), &[t])),
+            FilterKind::Whitelist => format!("'{}' is not in the whitelist", name),
+            FilterKind::Rule => "rule".to_string(),
+            FilterKind::OutOfRange => "out of range".to_string(),
+        }
+    }
+
+    /// The `topics.monitor_topics` entry matching `t`, for traces.
+    fn monitor_reason(&self, t: &str) -> String {
+        match self.monitor_topics.get().matching_pattern(t) {
+            Some((index, pattern)) => format!("monitor_topics #{} '{}'", index, pattern),
+            None => "monitor_topics".to_string(),
+        }
+    }
+
+    /// The virtual input name for `topic`: a matching rewrite wins, otherwise the first
+    /// configured prefix is stripped. The result is normalized and shortened if it's too long.
+    fn virtual_input_name(&self, topic: &str) -> PyResult<String> {
+        if let Some(rewritten) = self.rewrites.get().apply(topic) {
+            return Ok(self.vi_names.fit(self.normalize_topic(&rewritten)?));
+        }
+        let prefixes = self.strip_prefixes.get();
+        let stripped = prefixes
+            .iter()
+            .find_map(|prefix| topic.strip_prefix(prefix.as_str()))
+            .filter(|rest| !rest.is_empty())
+            .unwrap_or(topic);
+        Ok(self.vi_names.fit(self.normalize_topic(stripped)?))
+    }
+
+    /// Publish a debug copy of a value to `{base_topic}<kind>/<name>`, without waiting for it.
+    fn publish_debug(&self, py: Python<'_>, kind: &str, name: &str, value: &str) {
+        let topic = format!("{}{}/{}", self.base_topic, kind, name);
+        let published = self
+            .mqtt_client_obj
+            .bind(py)
+            .call_method1(intern!(py, "publish"), (topic, value))
+            .and_then(into_future);
+        match published {
+            Ok(fut) => {
+                pyo3_async_runtimes::tokio::get_runtime().spawn(async move {
+                    if let Err(e) = fut.await {
+                        debug!("Error publishing debug topic: {:?}", e);
                     }
                 });
             }
-            else if topic == topics.stop_ui_topic {
-                let coro = self.relay_main_obj.bind(py).call_method0("stop_ui")?;
-                let fut = into_future(coro.clone())?;
+            Err(e) => debug!("Error publishing debug topic: {:?}", e),
+        }
+    }
+
+    /// Publish the Miniserver's reachability, `online` or `offline`, retained to
+    /// `{base_topic}status/miniserver`.
+    fn publish_reachability(&self, py: Python<'_>, online: bool) {
+        let topic = format!("{}status/miniserver", self.base_topic);
+        let payload = if online { "online" } else { "offline" };
+        let published = self
+            .mqtt_client_obj
+            .bind(py)
+            .call_method1(intern!(py, "publish"), (topic, payload, true))
+            .and_then(into_future);
+        match published {
+            Ok(fut) => {
                 pyo3_async_runtimes::tokio::get_runtime().spawn(async move {
                     if let Err(e) = fut.await {
-                        error!("Error in stop_ui async call: {:?}", e);
+                        error!("Error publishing the Miniserver reachability: {:?}", e);
                     }
                 });
             }
-       
-            else if topic == topics.config_get_topic {
-                // global_config.get_safe_config -> orjson.dumps -> publish
-                let global_config_py = self
-                    .relay_main_obj
-                    .bind(py)
-                    .getattr(intern!(py, "miniserver_data_processor"))?
-                    .getattr(intern!(py, "global_config"))?;
-                let safe_cfg = global_config_py.call_method0("get_safe_config")?;
-                let serialized = self.orjson_obj.bind(py).call_method1("dumps", (safe_cfg,))?;
-                let coro = self
-                    .mqtt_client_obj
-                    .bind(py)
-                    .call_method1("publish", (topics.config_response_topic.clone(), serialized))?;
-                let fut = into_future(coro.clone())?;
+            Err(e) => error!("Error publishing the Miniserver reachability: {:?}", e),
+        }
+    }
+
+    /// Call the `on_connect` or `on_disconnect` callbacks; awaitable results are awaited on
+    /// the running event loop, or dropped with a warning without one.
+    fn run_connection_hooks(&self, py: Python<'_>, hooks: &[Py<PyAny>], connection: &str, error: Option<&str>) {
+        for hook in hooks {
+            let result = hook.bind(py).call1((connection, error)).and_then(|result| {
+                if !result.hasattr(intern!(py, "__await__"))? {
+                    return Ok(());
+                }
+                let Ok(locals) = pyo3_async_runtimes::tokio::get_current_locals(py) else {
+                    warn!("No running event loop, not awaiting the connection callback");
+                    return Ok(());
+                };
+                let fut = pyo3_async_runtimes::into_future_with_locals(&locals, result)?;
                 pyo3_async_runtimes::tokio::get_runtime().spawn(async move {
                     if let Err(e) = fut.await {
-                        error!("Error publishing config response: {:?}", e);
+                        error!("Error in connection callback: {:?}", e);
                     }
                 });
+                Ok(())
+            });
+            if let Err(e) = result {
+                error!("Error in connection callback: {:?}", e);
             }
-            else if topic == topics.config_set_topic || topic == topics.config_add_topic || topic == topics.config_remove_topic {
-                let update_mode = if topic == topics.config_set_topic {
-                    "set"
-                } else if topic == topics.config_add_topic {
-                    "add"
-                } else {
-                    "remove"
-                };
-                let load_res = self.orjson_obj.bind(py).call_method1("loads", (message.as_str(),));
-                match load_res {
-                    Ok(py_obj) => {
-                        let global_config_py = self
-                            .relay_main_obj
-                            .bind(py)
-                            .getattr(intern!(py, "miniserver_data_processor"))?
-                            .getattr(intern!(py, "global_config"))?;
-                        let update_res = global_config_py.call_method1("update_fields", (py_obj, update_mode));
-                        if let Err(e) = update_res {
-                            error!("Error updating configuration: {:?}", e);
-                        } else {
-                            info!("Configuration updated via MQTT. Restarting program (from Rust).");
-                            let _ = self.relay_main_obj.bind(py).call_method0("restart_relay_incl_ui");
-                        }
-                    },
-                    Err(e) => {
-                        error!("Invalid JSON format in MQTT message: {:?}", e);
+        }
+    }
+
+    /// Publish `online` or `offline` retained to `{base_topic}availability/<name>`.
+    fn publish_availability(&self, py: Python<'_>, name: &str, online: bool) {
+        let topic = format!("{}availability/{}", self.base_topic, name);
+        let payload = if online { "online" } else { "offline" };
+        let published = self
+            .mqtt_client_obj
+            .bind(py)
+            .call_method1(intern!(py, "publish"), (topic, payload, true))
+            .and_then(into_future);
+        match published {
+            Ok(fut) => {
+                let name = name.to_string();
+                pyo3_async_runtimes::tokio::get_runtime().spawn(async move {
+                    if let Err(e) = fut.await {
+                        error!("Error publishing the availability of {}: {:?}", name, e);
                     }
+                });
+            }
+            Err(e) => error!("Error publishing the availability of {}: {:?}", name, e),
+        }
+    }
+
+    /// Whitelist entries as virtual input names. Entries pasted as original MQTT topics
+    /// (with `/` or `%`, which never occur in a name) are converted like incoming topics.
+    fn set_whitelist(&self, whitelist: HashSet<String>) {
+        self.topic_whitelist.set(Whitelist::new(whitelist));
+        self.retire_filter_decisions();
+    }
+
+    /// Retire the cached filter decisions after a filter list, the whitelist or the
+    /// rewrites changed, they were made with the old lists.
+    fn retire_filter_decisions(&self) {
+        self.filter_generation.fetch_add(1, Ordering::AcqRel);
+        self.filter_decision_cache.clear();
+    }
+
+    /// Clear the Home Assistant configs of inputs the whitelist drops now.
+    fn retract_unlisted(&self, py: Python<'_>) {
+        let Some(ha) = &self.ha_discovery else { return };
+        let whitelist = self.topic_whitelist.get();
+        if whitelist.is_empty() || !matches!(self.policy, FilterPolicy::DenyOverrides | FilterPolicy::WhitelistOnly) {
+            return;
+        }
+        ha.retract(py, |name, topic| whitelist.allows(name, topic));
+    }
+
+    fn whitelist_names(&self, entries: impl IntoIterator<Item = String>) -> PyResult<HashSet<String>> {
+        entries
+            .into_iter()
+            .map(|entry| {
+                if self.name_normalizer.changes(&entry) && !has_wildcards(&entry) {
+                    self.virtual_input_name(&entry)
+                } else {
+                    Ok(entry)
                 }
+            })
+            .collect()
+    }
+
+    /// Whether the subscription filter can reject a message on its original topic
+    /// before flattening, without changing the outcome for any flattened key.
+    fn filters_checked_early(&self) -> bool {
+        self.rules.get().is_empty() && self.rewrites.get().is_empty() && self.policy == FilterPolicy::DenyOverrides
+    }
+
+    /// The message topic and flattened key as the subscription filters, post-expansion
+    /// filters and do_not_forward see them: rewritten by `topics.rewrites`.
+    fn rewritten_for_filters<'a>(&self, original_topic: &'a str, t: &'a str) -> (Cow<'a, str>, Cow<'a, str>) {
+        let rewrites = self.rewrites.get();
+        let rewrite = |topic: &'a str| rewrites.apply(topic).map_or(Cow::Borrowed(topic), Cow::Owned);
+        (rewrite(original_topic), rewrite(t))
+    }
+
+    /// The fixed pipeline: whitelist, subscription filter and do_not_forward,
+    /// combined according to the configured policy. The filter that drops the value, if any.
+    fn filtered_by(&self, original_topic: &str, t: &str, normalized: &str) -> Option<FilterKind> {
+        // The generation is read before the lists, so a decision made with lists replaced
+        // meanwhile is stored under a generation that's already retired
+        let generation = self.filter_generation.load(Ordering::Acquire);
+        let key = format!("{}\0{}\0{}\0{}", generation, original_topic, t, normalized);
+        let cached = self.filter_decision_cache.get(&key);
+        self.metrics.cache(Cache::FilterDecision, cached.is_some());
+        if let Some(cached) = cached {
+            return FilterKind::parse(&cached);
+        }
+        let kind = self.decide_filter(original_topic, t, normalized);
+        self.metrics.cache_put(
+            Cache::FilterDecision,
+            &self.filter_decision_cache,
+            key,
+            kind.map(FilterKind::as_str).unwrap_or_default().to_string(),
+        );
+        kind
+    }
+
+    fn decide_filter(&self, original_topic: &str, t: &str, normalized: &str) -> Option<FilterKind> {
+        // Check whitelist first (using normalized topic)
+        let whitelist = self.topic_whitelist.get();
+        let whitelisted = if whitelist.is_empty() {
+            None
+        } else {
+            debug!("Checking whitelist for topic '{}' (normalized: '{}') against whitelist: {:?}",
+                   t, normalized, whitelist.names);
+            Some(whitelist.allows(normalized, t))
+        };
+
+        match (self.policy, whitelisted) {
+            (FilterPolicy::DenyOverrides | FilterPolicy::WhitelistOnly, Some(false)) => {
+                debug!("Topic '{}' (normalized: '{}') not in whitelist", t, normalized);
+                return Some(FilterKind::Whitelist);
             }
-            else if topic == topics.config_update_topic || topic == topics.config_restart_topic {
-                info!("Reloading configuration. Restarting program (from Rust).");
-                let _ = self.relay_main_obj.bind(py).call_method0("restart_relay_incl_ui");
+            (FilterPolicy::WhitelistOnly, _) => return None,
+            (FilterPolicy::AllowOverrides, Some(true)) => {
+                debug!("Topic '{}' (normalized: '{}') found in whitelist, overriding filters", t, normalized);
+                return None;
             }
+            (_, Some(true)) => {
+                debug!("Topic '{}' (normalized: '{}') found in whitelist", t, normalized);
+            }
+            _ => {}
         }
-        else {
 
-            // process_data(...) returns Vec<(String, Option<String>)>
-            let _ = self.process_data(
-                py,
-                &topic,
-                &message
-            );
+        // The lists match the topics as rewritten
+        let (original_topic, t) = self.rewritten_for_filters(original_topic, t);
+        let (original_topic, t) = (original_topic.as_ref(), t.as_ref());
+
+        // first pass subscription filter, unless it already ran before flattening
+        let subscription_filters = self.subscription_filters.get();
+        if !self.filters_checked_early() && subscription_filters.is_match(original_topic) {
+            debug!("Topic '{}' filtered by subscription filter", original_topic);
+            return Some(FilterKind::Subscription);
         }
 
-        Ok(())
-    }   
+        // second pass subscription filter (on the flattened key)
+        if self.filter_flattened_keys && subscription_filters.is_match(t) {
+            debug!("Topic '{}' filtered by second pass", t);
+            return Some(FilterKind::Subscription);
+        }
 
-    #[pyo3(text_signature = "(self)")]
-    fn get_do_not_forward_patterns(&self) -> Vec<String> {
-        if let Some(ref regex) = self.do_not_forward_patterns {
-            // Convert the regex pattern back to individual patterns by:
-            // 1. Remove the outer parentheses
-            // 2. Split on the '|' character
-            let pattern = regex.as_str();
-            if pattern.starts_with('(') && pattern.ends_with(')') {
-                pattern[1..pattern.len()-1]
-                    .split('|')
-                    .map(String::from)
-                    .collect()
-            } else {
-                vec![pattern.to_string()]
+        if self.post_expansion_filters.get().is_match(t) {
+            debug!("Topic '{}' filtered by post-expansion filter", t);
+            return Some(FilterKind::PostExpansion);
+        }
+
+        // do_not_forward (on original topic)
+        if self.do_not_forward_patterns.get().is_match(t) {
+            debug!("Topic '{}' filtered by do_not_forward", t);
+            return Some(FilterKind::DoNotForward);
+        }
+        None
+    }
+}
+
+/// The trace reason of a forwarded value the rules changed or accepted; `None` if none did.
+fn rule_reason(decision: &RuleDecision) -> Option<String> {
+    let applied = decision.applied.iter().map(|index| format!("#{}", index)).collect::<Vec<_>>().join(", ");
+    match (decision.verdict, applied.is_empty()) {
+        (Some(Verdict::Accept { index }), true) => Some(format!("accepted by rule #{}", index)),
+        (Some(Verdict::Accept { index }), false) => Some(format!("accepted by rule #{} after rule {}", index, applied)),
+        (_, false) => Some(format!("changed by rule {}", applied)),
+        _ => None,
+    }
+}
+
+/// Call every send callback, then await those that returned an awaitable.
+/// Errors are logged, they never affect the send itself.
+#[derive(Clone)]
+enum NativeRoute {
+    Http(Arc<HttpSender>, String, u16),
+    Websocket(Arc<LoxWs>),
+}
+
+/// Send through the Python handler's `send_to_miniserver`, from a send task.
+/// `transport="http"` or `"websocket"` for `send_to_miniserver` when the value asks for one.
+fn transport_kwargs(py: Python<'_>, transport: Transport) -> PyResult<Option<Bound<'_, PyDict>>> {
+    match transport {
+        Transport::Http | Transport::Websocket => {
+            let kwargs = PyDict::new(py);
+            kwargs.set_item(intern!(py, "transport"), transport.as_str())?;
+            Ok(Some(kwargs))
+        }
+        Transport::Miniserver | Transport::Udp => Ok(None),
+    }
+}
+
+async fn python_send(handler: Py<PyAny>, t: String, name: String, val: String, transport: Transport) -> SendOutcome {
+    let sent = Python::attach(|py| {
+        into_future(handler.bind(py).call_method("send_to_miniserver", (t, name, val), transport_kwargs(py, transport)?.as_ref())?)
+    });
+    match sent {
+        Ok(fut) => send_outcome(fut.await),
+        Err(e) => send_outcome(Err(e)),
+    }
+}
+
+/// Status, HTTP code and error of one send.
+type SendOutcome = (DeliveryStatus, Option<i64>, Option<String>);
+
+/// What a send task needs after a failed first attempt: the value to retry it through
+/// the Python handler, and where to publish it if every attempt fails.
+struct FollowUp {
+    handler: Py<PyAny>,
+    mqtt: Py<PyAny>,
+    t: String,
+    val: String,
+    transport: Transport,
+    retry: RetryPolicy,
+    dead_letter_topic: Option<String>,
+}
+
+impl FollowUp {
+    /// Retry a failed send with backoff while it is still the latest value for `name`,
+    /// then publish it as a dead letter if it still failed. The final outcome.
+    async fn run(self, last_values: &LastValueStore, stats: &Stats, name: &str, id: u64, mut outcome: SendOutcome) -> SendOutcome {
+        let mut attempts = 1;
+        while outcome.0 == DeliveryStatus::Failed && attempts <= self.retry.retries {
+            let wait = self.retry.delay(attempts - 1);
+            debug!("Send #{} to {} failed ({:?}), retrying in {:?}", id, name, outcome.2, wait);
+            tokio::time::sleep(wait).await;
+            if !last_values.is_latest(name, id) {
+                debug!("Not retrying send #{} to {}, a newer value replaced it", id, name);
+                return outcome;
+            }
+            stats.add(Counter::Retried);
+            let handler = Python::attach(|py| self.handler.clone_ref(py));
+            outcome = python_send(handler, self.t.clone(), name.to_string(), self.val.clone(), self.transport).await;
+            attempts += 1;
+        }
+        if let (DeliveryStatus::Failed, Some(topic)) = (outcome.0, &self.dead_letter_topic) {
+            warn!("Send of {}={} failed after {} attempts, publishing it to {}", name, self.val, attempts, topic);
+            stats.add(Counter::DeadLettered);
+            let payload = json!({
+                "topic": self.t,
+                "name": name,
+                "value": self.val,
+                "error": outcome.2,
+                "code": outcome.1,
+                "attempts": attempts,
+                "at": SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or_default(),
+            })
+            .to_string();
+            let published = Python::attach(|py| into_future(self.mqtt.bind(py).call_method1("publish", (topic.clone(), payload))?));
+            if let Err(e) = match published {
+                Ok(fut) => fut.await.map(drop),
+                Err(e) => Err(e),
+            } {
+                error!("Error publishing dead letter to {}: {:?}", topic, e);
             }
-        } else {
-            Vec::new()
         }
+        outcome
     }
+}
 
-    #[pyo3(text_signature = "(self)")]
-    fn get_subscription_filters(&self) -> Vec<String> {
-        if let Some(ref regex) = self.compiled_subscription_filter {
-            // Convert the regex pattern back to individual patterns by:
-            // 1. Remove the outer parentheses
-            // 2. Split on the '|' character
-            let pattern = regex.as_str();
-            if pattern.starts_with('(') && pattern.ends_with(')') {
-                pattern[1..pattern.len()-1]
-                    .split('|')
-                    .map(String::from)
-                    .collect()
-            } else {
-                vec![pattern.to_string()]
+/// A value of a message after the part of the pipeline that runs without the GIL.
+struct PreparedValue {
+    /// The flattened topic
+    topic: String,
+    /// Virtual input name and value for `processedtopics`, if they are published
+    processed: Option<(String, String)>,
+    /// `None` if the value was filtered, skipped or monitored
+    forward: Option<ForwardValue>,
+    /// The converted value of a `topics.monitor_topics` topic, published to `{base_topic}monitored/`
+    monitored: Option<String>,
+}
+
+/// Values collected by `dispatch_values` for `batch_mode = "message"`.
+#[derive(Default)]
+struct Batched {
+    udp: Vec<BatchEntry>,
+    /// For the Miniserver's `batch_input`
+    miniserver: Vec<BatchEntry>,
+}
+
+/// Why `convert_value` didn't produce a value to send.
+struct NotSent {
+    outcome: Outcome,
+    reason: String,
+}
+
+/// A value ready to be sent, with its virtual input name.
+struct ForwardValue {
+    name: String,
+    value: String,
+    transport: Transport,
+    /// Whether the value was a word like "on" or "true", for Home Assistant discovery
+    boolean: bool,
+}
+
+/// Where a batch goes: datagrams to the Virtual UDP Inputs, or requests setting the
+/// virtual text input `batch_input`.
+enum BatchTarget {
+    Udp(Arc<UdpForwarder>),
+    Miniserver {
+        handler: Py<PyAny>,
+        route: Option<NativeRoute>,
+        input: String,
+        retry: RetryPolicy,
+    },
+}
+
+/// A batch of values, sent as `name=value` pairs joined by `batch_separator`. Each
+/// value's delivery record gets the outcome of the datagram or request it went out in.
+struct BatchSend {
+    target: BatchTarget,
+    separator: String,
+    max_values: usize,
+    last_values: Arc<LastValueStore>,
+    stats: Arc<Stats>,
+}
+
+impl BatchSend {
+    async fn run(self, mut entries: Vec<BatchEntry>) {
+        // A datagram has to fit one packet, a request only the value limit
+        let max_bytes = match self.target {
+            BatchTarget::Udp(_) => MAX_DATAGRAM_BYTES,
+            BatchTarget::Miniserver { .. } => usize::MAX,
+        };
+        // Chunks are consecutive runs of `entries`
+        let mut pending = entries.iter_mut().map(|entry| entry.pending.take()).collect::<Vec<_>>().into_iter();
+        for chunk in chunks(&entries, &self.separator, self.max_values, max_bytes) {
+            let payload = join(chunk, &self.separator);
+            debug!("Sending a batch of {} values: {}", chunk.len(), payload);
+            let (status, code) = match &self.target {
+                BatchTarget::Udp(forwarder) => match forwarder.send_datagram(&payload) {
+                    Ok(()) => (DeliveryStatus::Unconfirmed, None),
+                    Err(e) => {
+                        warn!("UDP send of a batch of {} values failed: {}", chunk.len(), e);
+                        (DeliveryStatus::Failed, None)
+                    }
+                },
+                BatchTarget::Miniserver { handler, route, input, retry } => {
+                    let (status, code, err) = Self::send(handler, route, input, *retry, &payload).await;
+                    if status == DeliveryStatus::Failed {
+                        warn!("Batch of {} values to {} failed: {}", chunk.len(), input, err.unwrap_or_default());
+                    }
+                    (status, code)
+                }
+            };
+            for entry in chunk {
+                self.last_values.complete(&entry.name, entry.id, status, code);
+                self.stats.add_outcome(status);
+                if let Some(pending) = pending.next().flatten() {
+                    pending.complete(status, code);
+                }
             }
-        } else {
-            Vec::new()
         }
     }
 
+    /// Set `input` to `payload`, natively if possible, retrying failed attempts through
+    /// the Python handler with the configured backoff.
+    async fn send(handler: &Py<PyAny>, route: &Option<NativeRoute>, input: &str, retry: RetryPolicy, payload: &str) -> SendOutcome {
+        let native = match route {
+            Some(NativeRoute::Http(sender, host, port)) => Some(sender.send(host, *port, input, payload).await),
+            Some(NativeRoute::Websocket(ws)) => Some(ws.send_io(input, payload).await.map(|()| 200)),
+            None => None,
+        };
+        let python = || Python::attach(|py| handler.clone_ref(py));
+        let mut outcome = match native {
+            Some(Ok(200)) => return (DeliveryStatus::Delivered, Some(200), None),
+            _ => python_send(python(), input.to_string(), input.to_string(), payload.to_string(), Transport::Miniserver).await,
+        };
+        let mut attempts = 1;
+        while outcome.0 == DeliveryStatus::Failed && attempts <= retry.retries {
+            tokio::time::sleep(retry.delay(attempts - 1)).await;
+            outcome = python_send(python(), input.to_string(), input.to_string(), payload.to_string(), Transport::Miniserver).await;
+            attempts += 1;
+        }
+        outcome
+    }
+}
+
+/// What a replay of the outbox needs, to send its values through the Python handler.
+struct OutboxReplay {
+    outbox: Arc<Outbox>,
+    handler: Py<PyAny>,
+    last_values: Arc<LastValueStore>,
+    stats: Arc<Stats>,
+    locals: pyo3_async_runtimes::TaskLocals,
+}
+
+impl OutboxReplay {
+    /// Replay the outbox in a task of its own, unless it is empty or being replayed.
+    fn start(self) {
+        if self.outbox.start_replay() {
+            let locals = self.locals.clone();
+            pyo3_async_runtimes::tokio::get_runtime().spawn(pyo3_async_runtimes::tokio::scope(locals, self.run()));
+        }
+    }
+
+    /// Send the buffered values one at a time, and stop at the first one the Miniserver
+    /// can't be reached for. Values it answers with an error are dropped, so one bad value
+    /// doesn't hold up the rest.
+    async fn run(self) {
+        let outbox = &self.outbox;
+        info!("Replaying {} values from the outbox", outbox.len());
+        let (mut replayed, mut rejected) = (0, 0);
+        // Time of the last replayed value per input, see below
+        let mut last_replayed: HashMap<String, f64> = HashMap::new();
+        while let Some(entry) = outbox.next() {
+            if outbox.replay() == Replay::Latest && self.last_values.delivered_after(&entry.name, entry.at).is_some() {
+                debug!("Outbox: skipping {}={}, a newer value was delivered", entry.name, entry.value);
+                outbox.pop();
+                continue;
+            }
+            let handler = Python::attach(|py| self.handler.clone_ref(py));
+            let (status, code, err) = python_send(handler, entry.topic.clone(), entry.name.clone(), entry.value.clone(), Transport::Miniserver).await;
+            match (status, code) {
+                (DeliveryStatus::Failed, None) => {
+                    warn!("Outbox replay stopped, {} values left: {}", outbox.len(), err.unwrap_or_default());
+                    break;
+                }
+                (DeliveryStatus::Failed, Some(code)) => {
+                    warn!("Miniserver returned {} for {}={} from the outbox, dropping it", code, entry.name, entry.value);
+                    rejected += 1;
+                }
+                _ => {
+                    self.stats.add(Counter::Replayed);
+                    replayed += 1;
+                    last_replayed.insert(entry.name, entry.at);
+                }
+            }
+            outbox.pop();
+        }
+        outbox.finish_replay();
+        // Inputs that were sent a newer value while their older ones were still buffered
+        // must not end on a replayed one
+        for (name, at) in last_replayed {
+            if let Some((topic, value)) = self.last_values.delivered_after(&name, at) {
+                let handler = Python::attach(|py| self.handler.clone_ref(py));
+                python_send(handler, topic, name, value, Transport::Miniserver).await;
+            }
+        }
+        info!("Replayed {} values from the outbox, {} rejected, {} left", replayed, rejected, outbox.len());
+    }
+}
+
+fn send_outcome(result: PyResult<Py<PyAny>>) -> SendOutcome {
+    match result {
+        Ok(result) => {
+            let (status, code) = Python::attach(|py| outcome_from_result(result.bind(py)));
+            (status, code, code.filter(|_| status == DeliveryStatus::Failed).map(|c| format!("HTTP {}", c)))
+        }
+        Err(e) => {
+            error!("Error in send_to_miniserver async call: {:?}", e);
+            (DeliveryStatus::Failed, None, Some(e.to_string()))
+        }
+    }
+}
+
+/// Append `callback` to `hooks`, for `on_connect` and `on_disconnect`.
+fn add_hook(py: Python<'_>, hooks: &Shared<Vec<Py<PyAny>>>, callback: Bound<'_, PyAny>) -> PyResult<()> {
+    if !callback.is_callable() {
+        return Err(PyValueError::new_err("Connection callback must be callable"));
+    }
+    hooks.update(|current| current.iter().map(|c| c.clone_ref(py)).chain([callback.unbind()]).collect());
+    Ok(())
+}
+
+async fn run_send_callbacks(
+    callbacks: &[Py<PyAny>],
+    locals: &pyo3_async_runtimes::TaskLocals,
+    topic: &str,
+    value: &str,
+    ok: bool,
+    err: Option<&str>,
+) {
+    let pending: Vec<_> = Python::attach(|py| {
+        callbacks
+            .iter()
+            .filter_map(|callback| {
+                let result = callback
+                    .bind(py)
+                    .call1((topic, value, ok, err))
+                    .and_then(|result| {
+                        if result.hasattr(intern!(py, "__await__"))? {
+                            pyo3_async_runtimes::into_future_with_locals(locals, result).map(Some)
+                        } else {
+                            Ok(None)
+                        }
+                    });
+                result.unwrap_or_else(|e| {
+                    error!("Error in send callback: {:?}", e);
+                    None
+                })
+            })
+            .collect()
+    });
+    for fut in pending {
+        if let Err(e) = fut.await {
+            error!("Error in send callback: {:?}", e);
+        }
+    }
+}
+
+/// Initialize the Rust logger. With `log_file`, records also go to that file, rotated
+/// once it would exceed `max_bytes`, keeping `backups` old files. `sink` "syslog" or
+/// "journald" sends them to the system log instead of stderr; syslog goes to
+/// `syslog_address` ("host:port", UDP) if given, else to the local socket. "python"
+/// hands them to Python's `logging` instead. Only the first call takes effect.
+#[pyfunction]
+#[pyo3(signature = (log_file=None, max_bytes=10_485_760, backups=5, sink="stderr", syslog_address=None))]
+fn init_rust_logger(
+    log_file: Option<&str>,
+    max_bytes: u64,
+    backups: usize,
+    sink: &str,
+    syslog_address: Option<&str>,
+) -> PyResult<()> {
+    let filter = env_filter::Builder::from_env(env_logger::DEFAULT_FILTER_ENV).build();
+    let mut builder = env_logger::Builder::from_env(env_logger::Env::new().write_style(env_logger::DEFAULT_WRITE_STYLE_ENV));
+    builder.filter_level(log::LevelFilter::Trace);
+    let to_stderr = sink == "stderr";
+    if let Some(path) = log_file.filter(|p| !p.is_empty()) {
+        let file = log_file::RotatingFile::open(path, max_bytes, backups)?;
+        builder.target(env_logger::Target::Pipe(Box::new(log_file::Tee::new(file, to_stderr))));
+    } else if !to_stderr {
+        builder.target(env_logger::Target::Pipe(Box::new(std::io::sink())));
+    }
+    let _ = log_sink::RelayLogger::new(filter, builder.build(), sink, syslog_address)?.install();
+    Ok(())
 }
 
-/// Initialize the Rust logger
+/// Set the level of the Rust log records of `module` at runtime, overriding `RUST_LOG`:
+/// a module path like "loxmqttrelay::mqtt_client" or "loxmqttrelay.mqtt_client", "" for
+/// all of them. `level` is a name ("debug", "WARNING", ...) or a Python logging level
+/// number; `None` goes back to `RUST_LOG` for the module.
 #[pyfunction]
-fn init_rust_logger() {
-    let _ = env_logger::try_init();
+fn set_rust_log_level(module: &str, level: Option<&Bound<'_, PyAny>>) -> PyResult<()> {
+    let level = match level {
+        None => None,
+        Some(level) => Some(match level.extract::<i64>() {
+            Ok(number) => log_sink::from_python_level(number),
+            Err(_) => {
+                let name: String = level.extract()?;
+                log_sink::parse_level(&name).ok_or_else(|| {
+                    PyValueError::new_err(format!("Unknown log level '{}': expected off, error, warn, info, debug or trace", name))
+                })?
+            }
+        }),
+    };
+    log_sink::set_level(module, level);
+    info!("Rust log level of '{}' set to {}", module, level.map_or("the RUST_LOG default".to_string(), |l| l.to_string()));
+    Ok(())
 }
 
-#[pymodule]
+// The processor is Sync and holds no lock while calling into Python, so it runs
+// without the GIL on free-threaded builds
+#[pymodule(gil_used = false)]
 fn _loxmqttrelay(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()>{
     // Initialize the Tokio runtime for pyo3-asyncio.
     Python::initialize();
     let mut builder = pyo3_async_runtimes::tokio::re_exports::runtime::Builder::new_multi_thread();
     builder.enable_all();
     pyo3_async_runtimes::tokio::init(builder);
+    panic_hook::install();
     m.add_class::<MiniserverDataProcessor>()?;
+    m.add_class::<mqtt_client::RelayMqttClient>()?;
+    m.add_class::<LoxWsClient>()?;
+    m.add_class::<config_file::ConfigWatcher>()?;
     m.add_function(wrap_pyfunction!(init_rust_logger, m)?)?;
+    m.add_function(wrap_pyfunction!(set_rust_log_level, m)?)?;
+    m.add_function(wrap_pyfunction!(config_merge::merge_config_value, m)?)?;
+    m.add_function(wrap_pyfunction!(config_validate::validate_config, m)?)?;
+    m.add_function(wrap_pyfunction!(config_file::load_config_file, m)?)?;
+    m.add_function(wrap_pyfunction!(panic_hook::_panic, m)?)?;
     Ok(())
 }
\ No newline at end of file
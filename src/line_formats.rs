@@ -0,0 +1,146 @@
+/// Text payloads of `key=value` pairs or InfluxDB line protocol, parsed into `(key, value)`
+/// pairs that are forwarded below the topic (`processing.key_value_topics`, `influx_topics`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineFormat {
+    KeyValue,
+    Influx,
+}
+
+impl LineFormat {
+    pub fn parse(self, text: &str) -> Result<Vec<(String, String)>, String> {
+        let values = match self {
+            LineFormat::KeyValue => key_value(text)?,
+            LineFormat::Influx => influx(text)?,
+        };
+        if values.is_empty() {
+            return Err("no values".to_string());
+        }
+        Ok(values)
+    }
+}
+
+/// `key=value` pairs separated by `,`, `;`, `&` or line breaks, e.g. `temp=21.5,hum=40`.
+/// Values may be double-quoted to hold separators.
+fn key_value(text: &str) -> Result<Vec<(String, String)>, String> {
+    let mut values = Vec::new();
+    let mut rest = text.trim();
+    while !rest.is_empty() {
+        let (key, after) = rest.split_once('=').ok_or_else(|| format!("'{}' has no '='", rest))?;
+        let key = key.trim();
+        if key.is_empty() || key.contains([',', ';', '&', '\n']) {
+            return Err(format!("invalid key '{}'", key));
+        }
+        let after = after.trim_start();
+        let (value, remaining) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"').ok_or_else(|| format!("unterminated quote in the value of '{}'", key))?;
+                let remaining = quoted[end + 1..].trim_start();
+                if !remaining.is_empty() && !remaining.starts_with([',', ';', '&', '\n']) {
+                    return Err(format!("text after the quoted value of '{}'", key));
+                }
+                (&quoted[..end], remaining)
+            }
+            None => match after.find([',', ';', '&', '\n']) {
+                Some(end) => (after[..end].trim(), &after[end..]),
+                None => (after.trim(), ""),
+            },
+        };
+        values.push((key.to_string(), value.to_string()));
+        rest = remaining.trim_start_matches([',', ';', '&', '\n', '\r', ' ', '\t']);
+    }
+    Ok(values)
+}
+
+/// InfluxDB line protocol, one point per line: `measurement,tag=a,tag2=b field=1.5,other=2i 1700000000000000000`.
+/// Each field becomes `<measurement>/<tag values>/<field>`, e.g. `power/L1/watts`; the
+/// timestamp is dropped. Integer suffixes and string quotes are removed, booleans become
+/// `true` or `false`. Empty lines and `#` comments are skipped.
+fn influx(text: &str) -> Result<Vec<(String, String)>, String> {
+    let mut values = Vec::new();
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+        let (series, rest) = split_unescaped(line, ' ').ok_or_else(|| format!("'{}' has no fields", line))?;
+        let mut series = split_all(series, ',').into_iter();
+        let measurement = unescape(series.next().unwrap_or_default());
+        if measurement.is_empty() {
+            return Err(format!("'{}' has no measurement", line));
+        }
+        let mut prefix = measurement;
+        for tag in series {
+            let (key, value) = split_unescaped(tag, '=').ok_or_else(|| format!("invalid tag '{}'", tag))?;
+            if key.is_empty() || value.is_empty() {
+                return Err(format!("invalid tag '{}'", tag));
+            }
+            prefix.push('/');
+            prefix.push_str(&unescape(value));
+        }
+        let fields = match split_unescaped(rest.trim_start(), ' ') {
+            Some((fields, timestamp)) if timestamp.trim().parse::<i64>().is_ok() => fields,
+            Some(_) => return Err(format!("invalid timestamp in '{}'", line)),
+            None => rest.trim_start(),
+        };
+        for field in split_all(fields, ',') {
+            let (key, value) = split_unescaped(field, '=').ok_or_else(|| format!("invalid field '{}'", field))?;
+            if key.is_empty() {
+                return Err(format!("invalid field '{}'", field));
+            }
+            values.push((format!("{}/{}", prefix, unescape(key)), field_value(value)?));
+        }
+    }
+    Ok(values)
+}
+
+fn field_value(value: &str) -> Result<String, String> {
+    if let Some(quoted) = value.strip_prefix('"') {
+        let inner = quoted.strip_suffix('"').ok_or_else(|| format!("unterminated string {}", value))?;
+        return Ok(inner.replace("\\\"", "\"").replace("\\\\", "\\"));
+    }
+    match value {
+        "t" | "T" | "true" | "True" | "TRUE" => return Ok("true".to_string()),
+        "f" | "F" | "false" | "False" | "FALSE" => return Ok("false".to_string()),
+        _ => {}
+    }
+    let number = value.strip_suffix(['i', 'u']).unwrap_or(value);
+    if number.parse::<f64>().is_err() {
+        return Err(format!("invalid field value '{}'", value));
+    }
+    Ok(number.to_string())
+}
+
+/// Split at the first `separator` that isn't escaped with `\` or inside a double-quoted string.
+fn split_unescaped(text: &str, separator: char) -> Option<(&str, &str)> {
+    let mut escaped = false;
+    let mut quoted = false;
+    for (index, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => return Some((&text[..index], &text[index + 1..])),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn split_all(mut text: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    while let Some((part, rest)) = split_unescaped(text, separator) {
+        parts.push(part);
+        text = rest;
+    }
+    parts.push(text);
+    parts
+}
+
+/// Drop the `\` before escaped commas, spaces and equal signs of names and tags.
+fn unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => result.push(chars.next().unwrap_or('\\')),
+            c => result.push(c),
+        }
+    }
+    result
+}
@@ -0,0 +1,108 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Log file rotated by size: before a write would take `relay.log` past `max_bytes`, it
+/// becomes `relay.log.1`, `relay.log.1` becomes `relay.log.2` and so on, keeping `backups`
+/// old files. With no backups the file is truncated instead.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    backups: usize,
+    file: File,
+    size: u64,
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl RotatingFile {
+    pub fn open(path: impl Into<PathBuf>, max_bytes: u64, backups: usize) -> io::Result<Self> {
+        let path = path.into();
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let file = open_append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile { path, max_bytes, backups, file, size })
+    }
+
+    fn backup(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        name.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.backups == 0 {
+            self.file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.path)?;
+        } else {
+            for index in (1..self.backups).rev() {
+                match fs::rename(self.backup(index), self.backup(index + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, self.backup(1))?;
+            self.file = open_append(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.max_bytes > 0 && self.size > 0 && self.size + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Log target writing to the log file and, unless the logs go to syslog or the journal,
+/// to stderr (for `docker logs`).
+pub struct Tee {
+    file: RotatingFile,
+    stderr: bool,
+    /// Reported once on stderr, later file errors are dropped
+    file_failed: bool,
+}
+
+impl Tee {
+    pub fn new(file: RotatingFile, stderr: bool) -> Self {
+        Tee { file, stderr, file_failed: false }
+    }
+}
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.stderr {
+            io::stderr().write_all(buf)?;
+        }
+        // A full disk must not stop logging to stderr
+        if let Err(e) = self.file.write_all(buf) {
+            if !self.file_failed {
+                self.file_failed = true;
+                let _ = writeln!(io::stderr(), "Can't write log file {}: {}", self.file.path.display(), e);
+            }
+        } else {
+            self.file_failed = false;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()?;
+        let _ = self.file.flush();
+        Ok(())
+    }
+}
@@ -0,0 +1,300 @@
+use std::io;
+use std::net::UdpSocket;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::sync::{Mutex, OnceLock, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::py_log::PyLogBridge;
+
+/// Name the relay logs under in syslog and the journal.
+const APP_NAME: &str = "loxmqttrelay";
+/// Syslog facility `daemon`
+const FACILITY: u8 = 3;
+#[cfg(unix)]
+const SYSLOG_SOCKET: &str = "/dev/log";
+#[cfg(unix)]
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Syslog severity, also the journal's PRIORITY.
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// `2026-10-14T17:51:32.856Z` for `secs.millis` since the epoch (UTC).
+fn rfc3339(since_epoch: std::time::Duration) -> String {
+    let secs = since_epoch.as_secs() as i64;
+    let (days, rem) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty() && h.bytes().all(|b| b.is_ascii_graphic()))
+        .unwrap_or_else(|| "-".to_string())
+}
+
+enum Transport {
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+    Udp(UdpSocket),
+}
+
+impl Transport {
+    fn send(&self, datagram: &[u8]) -> io::Result<()> {
+        match self {
+            #[cfg(unix)]
+            Transport::Unix(socket) => socket.send(datagram).map(drop),
+            Transport::Udp(socket) => socket.send(datagram).map(drop),
+        }
+    }
+}
+
+/// Where log records go besides stderr and the log file.
+enum Sink {
+    /// RFC 5424 messages, to the local syslog socket or a remote server via UDP
+    Syslog { transport: Transport, hostname: String, pid: u32 },
+    /// systemd journal, native protocol with structured fields
+    #[cfg(unix)]
+    Journald(UnixDatagram),
+    /// Python's `logging`, under the record's module path as logger name
+    Python(PyLogBridge),
+}
+
+impl Sink {
+    fn open(kind: &str, syslog_address: Option<&str>) -> io::Result<Self> {
+        match kind {
+            "syslog" => {
+                let transport = match syslog_address.filter(|a| !a.is_empty()) {
+                    Some(address) => {
+                        let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+                        socket.connect(address)?;
+                        Transport::Udp(socket)
+                    }
+                    #[cfg(unix)]
+                    None => {
+                        let socket = UnixDatagram::unbound()?;
+                        socket.connect(SYSLOG_SOCKET)?;
+                        Transport::Unix(socket)
+                    }
+                    #[cfg(not(unix))]
+                    None => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::Unsupported,
+                            "no local syslog on this platform, set log_syslog_address",
+                        ))
+                    }
+                };
+                Ok(Sink::Syslog { transport, hostname: hostname(), pid: std::process::id() })
+            }
+            #[cfg(unix)]
+            "journald" => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(JOURNAL_SOCKET)?;
+                Ok(Sink::Journald(socket))
+            }
+            "python" => Ok(Sink::Python(PyLogBridge::default())),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported log sink '{}'", kind),
+            )),
+        }
+    }
+
+    fn send(&self, record: &Record<'_>) -> io::Result<()> {
+        let message = record.args().to_string();
+        match self {
+            Sink::Syslog { transport, hostname, pid } => {
+                let timestamp = rfc3339(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default());
+                let datagram = format!(
+                    "<{}>1 {} {} {} {} - - {}",
+                    FACILITY * 8 + severity(record.level()),
+                    timestamp,
+                    hostname,
+                    APP_NAME,
+                    pid,
+                    message
+                );
+                transport.send(datagram.as_bytes())
+            }
+            #[cfg(unix)]
+            Sink::Journald(socket) => {
+                let mut datagram = Vec::with_capacity(message.len() + 128);
+                journal_field(&mut datagram, "MESSAGE", &message);
+                journal_field(&mut datagram, "PRIORITY", &severity(record.level()).to_string());
+                journal_field(&mut datagram, "SYSLOG_IDENTIFIER", APP_NAME);
+                journal_field(&mut datagram, "RUST_TARGET", record.target());
+                if let Some(file) = record.file() {
+                    journal_field(&mut datagram, "CODE_FILE", file);
+                }
+                if let Some(line) = record.line() {
+                    journal_field(&mut datagram, "CODE_LINE", &line.to_string());
+                }
+                socket.send(&datagram).map(drop)
+            }
+            Sink::Python(bridge) => bridge.emit(record).map_err(|e| io::Error::other(e.to_string())),
+        }
+    }
+}
+
+/// `KEY=value\n`, or the length-prefixed form for values spanning several lines.
+#[cfg(unix)]
+fn journal_field(out: &mut Vec<u8>, key: &str, value: &str) {
+    out.extend_from_slice(key.as_bytes());
+    if value.contains('\n') {
+        out.push(b'\n');
+        out.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        out.push(b'=');
+    }
+    out.extend_from_slice(value.as_bytes());
+    out.push(b'\n');
+}
+
+/// Levels set at runtime with `set_level`, longest module path first. They take
+/// precedence over `RUST_LOG` for the modules they name.
+static LEVELS: RwLock<Vec<(String, LevelFilter)>> = RwLock::new(Vec::new());
+/// The most verbose level `RUST_LOG` lets through, set when the logger is installed
+static ENV_MAX_LEVEL: OnceLock<LevelFilter> = OnceLock::new();
+
+/// The level set for `target` with `set_level`, if any: the one of the longest module
+/// path that is `target` itself or one of its parents ("" matches every module).
+fn level_for(target: &str) -> Option<LevelFilter> {
+    LEVELS.read().unwrap().iter().find_map(|(module, level)| {
+        let matches = module.is_empty()
+            || target.strip_prefix(module.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with("::"));
+        matches.then_some(*level)
+    })
+}
+
+/// The `log` crate's global maximum, so records any level lets through aren't dropped early.
+fn update_max_level() {
+    let env_max = ENV_MAX_LEVEL.get().copied().unwrap_or(LevelFilter::Off);
+    let max = LEVELS.read().unwrap().iter().map(|(_, level)| *level).fold(env_max, Ord::max);
+    log::set_max_level(max);
+}
+
+/// Log records of `module` (a path like "loxmqttrelay::mqtt_client"; Python's dotted
+/// form works too, "" means every module) at `level` and above, regardless of `RUST_LOG`.
+/// `None` removes the level set for `module` again.
+pub fn set_level(module: &str, level: Option<LevelFilter>) {
+    let module = module.trim().replace('.', "::");
+    {
+        let mut levels = LEVELS.write().unwrap();
+        levels.retain(|(m, _)| *m != module);
+        if let Some(level) = level {
+            levels.push((module, level));
+            levels.sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+        }
+    }
+    update_max_level();
+}
+
+/// A level name as in `RUST_LOG` or Python's logging ("warning", "critical"), case-insensitive.
+pub fn parse_level(name: &str) -> Option<LevelFilter> {
+    match name.trim().to_ascii_lowercase().as_str() {
+        "warning" => Some(LevelFilter::Warn),
+        "critical" | "fatal" => Some(LevelFilter::Error),
+        name => name.parse().ok(),
+    }
+}
+
+/// The Rust level closest to a numeric Python logging level.
+pub fn from_python_level(level: i64) -> LevelFilter {
+    match level {
+        40.. => LevelFilter::Error,
+        30..=39 => LevelFilter::Warn,
+        20..=29 => LevelFilter::Info,
+        10..=19 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// env_logger (for stderr and the log file) plus an optional syslog, journald or Python
+/// logging sink, filtered by `RUST_LOG` and the levels set with `set_level`.
+pub struct RelayLogger {
+    /// `RUST_LOG`, for the modules without a level of their own
+    filter: env_filter::Filter,
+    /// Formats and writes every record passed on; filtering is done here
+    inner: env_logger::Logger,
+    sink: Option<Sink>,
+    /// Set after a failed send was reported, so a missing daemon doesn't flood stderr
+    sink_failed: Mutex<bool>,
+}
+
+impl RelayLogger {
+    /// `sink` is "stderr" (no extra sink), "syslog", "journald" or "python". `inner`
+    /// should let every level through, `filter` decides what is logged.
+    pub fn new(filter: env_filter::Filter, inner: env_logger::Logger, sink: &str, syslog_address: Option<&str>) -> io::Result<Self> {
+        let sink = if sink == "stderr" { None } else { Some(Sink::open(sink, syslog_address)?) };
+        Ok(RelayLogger { filter, inner, sink, sink_failed: Mutex::new(false) })
+    }
+
+    pub fn install(self) -> Result<(), log::SetLoggerError> {
+        let env_max = self.filter.filter();
+        log::set_boxed_logger(Box::new(self))?;
+        let _ = ENV_MAX_LEVEL.set(env_max);
+        update_max_level();
+        Ok(())
+    }
+}
+
+impl Log for RelayLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        match level_for(metadata.target()) {
+            Some(level) => metadata.level() <= level,
+            None => self.filter.enabled(metadata),
+        }
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        self.inner.log(record);
+        if let Some(sink) = &self.sink {
+            let result = sink.send(record);
+            let mut failed = self.sink_failed.lock().unwrap();
+            match result {
+                Err(e) if !*failed => {
+                    *failed = true;
+                    eprintln!("Can't send log record to the system log: {}", e);
+                }
+                Err(_) => {}
+                Ok(()) => *failed = false,
+            }
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
@@ -0,0 +1,90 @@
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use lru::LruCache;
+
+/// Most shards of a large cache.
+const MAX_SHARDS: usize = 16;
+/// Entries per shard below which a cache isn't split further, so small caches stay one exact LRU.
+const MIN_SHARD_CAPACITY: usize = 256;
+
+/// The string lookup caches of the hot path (topic normalization, boolean conversion).
+/// Keys are spread over up to 16 LRU shards with a lock each, so lookups from messages
+/// processed in parallel without the GIL rarely wait for each other. Each shard evicts
+/// on its own, so a large cache is only approximately least-recently-used overall.
+/// The number of shards follows the capacity at creation and stays on `resize`.
+pub struct LookupCache {
+    shards: Vec<Mutex<LruCache<String, String>>>,
+    capacity: AtomicUsize,
+}
+
+impl LookupCache {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        let count = (capacity.get() / MIN_SHARD_CAPACITY).clamp(1, MAX_SHARDS);
+        let cache = LookupCache {
+            // Unbounded to start with, so memory is only taken as entries come in
+            shards: (0..count).map(|_| Mutex::new(LruCache::unbounded())).collect(),
+            capacity: AtomicUsize::new(capacity.get()),
+        };
+        cache.resize(capacity);
+        cache
+    }
+
+    fn shard(&self, key: &str) -> &Mutex<LruCache<String, String>> {
+        if self.shards.len() == 1 {
+            return &self.shards[0];
+        }
+        // FxHash over 8 bytes at a time; it only picks the shard, the LRU hashes the key again
+        let mut hash = key.len() as u64;
+        for chunk in key.as_bytes().chunks(8) {
+            let mut word = [0u8; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            hash = (hash.rotate_left(5) ^ u64::from_le_bytes(word)).wrapping_mul(0x517c_c1b7_2722_0a95);
+        }
+        &self.shards[(hash >> 32) as usize % self.shards.len()]
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.shard(key).lock().unwrap().get(key).cloned()
+    }
+
+    /// Add `key`; whether that pushed out another entry.
+    pub fn put(&self, key: String, value: String) -> bool {
+        let mut shard = self.shard(&key).lock().unwrap();
+        matches!(shard.push(key.clone(), value), Some((evicted, _)) if evicted != key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().unwrap().len()).sum()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity.load(Ordering::Relaxed)
+    }
+
+    /// The keys, most recently used first; with several shards, their most recently
+    /// used keys take turns.
+    pub fn keys(&self) -> Vec<String> {
+        let per_shard: Vec<Vec<String>> =
+            self.shards.iter().map(|shard| shard.lock().unwrap().iter().map(|(key, _)| key.clone()).collect()).collect();
+        let longest = per_shard.iter().map(Vec::len).max().unwrap_or(0);
+        (0..longest).flat_map(|i| per_shard.iter().filter_map(move |keys| keys.get(i).cloned())).collect()
+    }
+
+    /// Change the capacity, dropping the least recently used entries of each shard that
+    /// holds more than its share.
+    pub fn resize(&self, capacity: NonZeroUsize) {
+        self.capacity.store(capacity.get(), Ordering::Relaxed);
+        let per_shard = NonZeroUsize::new(capacity.get().div_ceil(self.shards.len())).unwrap_or(NonZeroUsize::MIN);
+        for shard in &self.shards {
+            shard.lock().unwrap().resize(per_shard);
+        }
+    }
+
+    pub fn clear(&self) {
+        for shard in &self.shards {
+            shard.lock().unwrap().clear();
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use log::{debug, error};
+use pyo3::exceptions::PyValueError;
+use pyo3::intern;
+use pyo3::prelude::*;
+use pyo3_async_runtimes::tokio::into_future;
+
+use crate::config_entries::dict_entries;
+
+/// Message header identifiers of the event tables
+pub const HEADER_VALUE_STATES: u8 = 2;
+pub const HEADER_TEXT_STATES: u8 = 3;
+
+/// The value of a Loxone state.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StateValue {
+    Number(f64),
+    Text(String),
+}
+
+impl StateValue {
+    /// The MQTT payload: numbers without a trailing `.0` for whole values.
+    pub fn payload(&self) -> String {
+        match self {
+            StateValue::Number(value) => value.to_string(),
+            StateValue::Text(text) => text.clone(),
+        }
+    }
+}
+
+/// One entry of an event table: the UUID of the state and its new value.
+#[derive(Clone, Debug)]
+pub struct StateUpdate {
+    pub uuid: String,
+    pub value: StateValue,
+}
+
+/// Called by the websocket with each event table it receives.
+pub type StateListener = Arc<dyn Fn(Vec<StateUpdate>) + Send + Sync>;
+
+/// A UUID as Loxone writes it: `0f86a2fe-0378-3e08-ffffb2d4e9b0b6c8`, the first three
+/// groups little endian.
+fn format_uuid(raw: &[u8]) -> String {
+    format!(
+        "{:08x}-{:04x}-{:04x}-{}",
+        u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]),
+        u16::from_le_bytes([raw[4], raw[5]]),
+        u16::from_le_bytes([raw[6], raw[7]]),
+        raw[8..16].iter().map(|b| format!("{:02x}", b)).collect::<String>()
+    )
+}
+
+/// The entries of an event table with identifier `kind`; other tables and a truncated
+/// last entry yield nothing.
+pub fn parse_event_table(kind: u8, data: &[u8]) -> Vec<StateUpdate> {
+    let mut updates = Vec::new();
+    match kind {
+        // UUID and a little endian f64 per state
+        HEADER_VALUE_STATES => {
+            for entry in data.chunks_exact(24) {
+                let value = f64::from_le_bytes(entry[16..24].try_into().unwrap());
+                updates.push(StateUpdate { uuid: format_uuid(&entry[..16]), value: StateValue::Number(value) });
+            }
+        }
+        // UUID, icon UUID, text length and the text, padded to 4 bytes
+        HEADER_TEXT_STATES => {
+            let mut pos = 0;
+            while let Some(head) = data.get(pos..pos + 36) {
+                let len = u32::from_le_bytes(head[32..36].try_into().unwrap()) as usize;
+                let Some(text) = data.get(pos + 36..pos + 36 + len) else { break };
+                let text = String::from_utf8_lossy(text).into_owned();
+                updates.push(StateUpdate { uuid: format_uuid(&head[..16]), value: StateValue::Text(text) });
+                pos += 36 + len.div_ceil(4) * 4;
+            }
+        }
+        _ => {}
+    }
+    updates
+}
+
+/// Topics below `<base_topic>miniserver/` per state UUID, from a list of
+/// `{"uuid": ..., "control": ..., "state": ...}` dicts (`miniserver.state_mappings`); the
+/// state defaults to "value".
+pub fn parse_state_mappings(entries: &Bound<'_, PyAny>) -> PyResult<HashMap<String, String>> {
+    let mut mappings = HashMap::new();
+    for (index, entry) in dict_entries(entries, "State mapping")?.iter().enumerate() {
+        let field = |name: &str| -> PyResult<Option<String>> { entry.get_item(name)?.map(|v| v.extract()).transpose() };
+        let (Some(uuid), Some(control)) = (field("uuid")?, field("control")?) else {
+            return Err(PyValueError::new_err(format!("State mapping {} needs a 'uuid' and a 'control'", index)));
+        };
+        let state = field("state")?.unwrap_or_else(|| "value".to_string());
+        let path = format!("{}/{}", control.trim_matches('/'), state.trim_matches('/'));
+        if path.contains(['+', '#']) {
+            return Err(PyValueError::new_err(format!("State mapping {}: '{}' can't be part of a topic", index, path)));
+        }
+        mappings.insert(uuid.trim().to_lowercase(), path);
+    }
+    debug!("Loaded {} state mappings", mappings.len());
+    Ok(mappings)
+}
+
+/// Publishes Loxone state changes to `<base_topic>miniserver/<control>/<state>`
+/// (`miniserver.publish_states`), and the unmapped ones to `<base_topic>miniserver/<uuid>`
+/// with `publish_unmapped_states`.
+pub struct StatePublisher {
+    pub mqtt_client: Py<PyAny>,
+    /// `<base_topic>miniserver/`
+    pub prefix: String,
+    pub mappings: HashMap<String, String>,
+    pub unmapped: bool,
+    pub retain: bool,
+}
+
+impl StatePublisher {
+    /// Publish `updates`; called from the websocket task, within the loop's task locals.
+    pub fn publish(&self, updates: Vec<StateUpdate>) {
+        let messages: Vec<(String, String)> = updates
+            .into_iter()
+            .filter_map(|update| {
+                let path = match self.mappings.get(&update.uuid) {
+                    Some(path) => path.clone(),
+                    None if self.unmapped => update.uuid.clone(),
+                    None => return None,
+                };
+                Some((format!("{}{}", self.prefix, path), update.value.payload()))
+            })
+            .collect();
+        if messages.is_empty() {
+            return;
+        }
+        let published: PyResult<Vec<_>> = Python::attach(|py| {
+            messages
+                .iter()
+                .map(|(topic, payload)| {
+                    let coro = self.mqtt_client.bind(py).call_method1(intern!(py, "publish"), (topic, payload, self.retain))?;
+                    into_future(coro)
+                })
+                .collect()
+        });
+        match published {
+            Ok(futures) => {
+                tokio::spawn(async move {
+                    for fut in futures {
+                        if let Err(e) = fut.await {
+                            error!("Error publishing a Loxone state: {:?}", e);
+                        }
+                    }
+                });
+            }
+            Err(e) => error!("Error publishing Loxone states: {:?}", e),
+        }
+    }
+}
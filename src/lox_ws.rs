@@ -0,0 +1,615 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose, Engine};
+use log::{debug, error, info, warn};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use serde_json::{json, Value};
+use tokio::io;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::crypto::{self, Aes256, HashAlg, RsaPublicKey};
+use crate::http_sender;
+use crate::lox_states::{parse_event_table, StateListener};
+use crate::websocket::{Message, WebSocket};
+
+/// How long a command may wait for its response.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+/// Structure files aren't requested, so responses stay small; event tables are split by
+/// the Miniserver well below this.
+const MAX_MESSAGE_BYTES: usize = 4 * 1024 * 1024;
+/// Permission 4: long lived "app" token, like `token_auth.TOKEN_PERMISSION`.
+const TOKEN_PERMISSION: u8 = 4;
+/// Loxone timestamps count seconds from 2009-01-01 00:00:00 UTC
+const LOXONE_EPOCH: f64 = 1_230_768_000.0;
+/// Refresh once less than this share of the token's lifetime is left, like `TokenAuth`
+const TOKEN_REFRESH_MARGIN: f64 = 0.1;
+/// Wait before trying a failed refresh again
+const TOKEN_RETRY: Duration = Duration::from_secs(60);
+/// Message header identifiers (the first binary message before each response)
+const HEADER_TEXT: u8 = 0;
+const HEADER_OUT_OF_SERVICE: u8 = 5;
+const HEADER_KEEPALIVE: u8 = 6;
+/// Header info flag: the length is an estimate and the exact header follows
+const HEADER_ESTIMATED: u8 = 0x80;
+
+const DISCONNECTED: u8 = 0;
+const CONNECTING: u8 = 1;
+const CONNECTED: u8 = 2;
+
+fn other(message: impl Into<String>) -> io::Error {
+    io::Error::other(message.into())
+}
+
+/// Percent-encode everything but unreserved characters, like `encodeURIComponent`.
+fn encode_component(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => out.push(byte as char),
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+/// The same name-based UUID as `token_auth.TokenAuth.client_uuid`, uuid5 in the DNS namespace.
+fn client_uuid(client_id: &str) -> String {
+    const NAMESPACE_DNS: [u8; 16] = [
+        0x6b, 0xa7, 0xb8, 0x10, 0x9d, 0xad, 0x11, 0xd1, 0x80, 0xb4, 0x00, 0xc0, 0x4f, 0xd4, 0x30, 0xc8,
+    ];
+    let mut data = NAMESPACE_DNS.to_vec();
+    data.extend_from_slice(format!("loxmqttrelay.{}", client_id).as_bytes());
+    let mut bytes = crypto::sha1(&data);
+    bytes[6] = (bytes[6] & 0x0f) | 0x50;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = crypto::hex(&bytes[..16]);
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+/// The `LL` object of a response, failing unless its code is 200.
+fn response_value(response: &Value, command: &str) -> io::Result<Value> {
+    let ll = response.get("LL").ok_or_else(|| other(format!("{}: response without LL", command)))?;
+    let code = ll.get("Code").or_else(|| ll.get("code")).map(response_code).unwrap_or(0);
+    if code != 200 {
+        return Err(other(format!("{} failed with code {}", command, code)));
+    }
+    Ok(match ll.get("value") {
+        // Some firmware versions return the value as JSON string
+        Some(Value::String(text)) => serde_json::from_str(text).unwrap_or_else(|_| Value::String(text.clone())),
+        Some(value) => value.clone(),
+        None => Value::Null,
+    })
+}
+
+fn response_code(code: &Value) -> u16 {
+    match code {
+        Value::String(text) => text.parse().unwrap_or(0),
+        other => other.as_u64().and_then(|c| u16::try_from(c).ok()).unwrap_or(0),
+    }
+}
+
+/// `host` and `port` of `http://host[:port]`, `ws://...` or a bare address.
+fn parse_url(url: &str) -> PyResult<(String, u16)> {
+    let (scheme, rest) = url.split_once("://").unwrap_or(("http", url));
+    if !matches!(scheme, "http" | "ws") {
+        return Err(PyValueError::new_err(format!("The native websocket doesn't support {} (no TLS): {}", scheme, url)));
+    }
+    let authority = rest.split('/').next().unwrap_or_default();
+    let (host, port) = if let Some(bracketed) = authority.strip_prefix('[') {
+        let (host, rest) = bracketed.split_once(']').ok_or_else(|| PyValueError::new_err(format!("Invalid Miniserver URL {}", url)))?;
+        (host, rest.strip_prefix(':'))
+    } else {
+        match authority.rsplit_once(':') {
+            Some((host, port)) if !host.contains(':') => (host, Some(port)),
+            _ => (authority, None),
+        }
+    };
+    let port = match port {
+        Some(port) => port.parse().map_err(|_| PyValueError::new_err(format!("Invalid port in Miniserver URL {}", url)))?,
+        None => 80,
+    };
+    if host.is_empty() {
+        return Err(PyValueError::new_err(format!("Invalid Miniserver URL {}", url)));
+    }
+    Ok((host.to_string(), port))
+}
+
+struct Request {
+    command: String,
+    reply: oneshot::Sender<io::Result<Value>>,
+}
+
+/// The connection handshake: requests answered in order, before the background loop runs.
+struct Handshake {
+    ws: WebSocket,
+}
+
+impl Handshake {
+    async fn request(&mut self, command: &str, name: &str) -> io::Result<Value> {
+        self.ws.send_text(command).await?;
+        let response = tokio::time::timeout(COMMAND_TIMEOUT, async {
+            loop {
+                match self.ws.read_message().await? {
+                    Message::Text(text) => return serde_json::from_str::<Value>(&text).map_err(|e| other(e.to_string())),
+                    Message::Binary(header) => check_header(&header)?,
+                    Message::Ping(payload) => self.ws.pong(&payload).await?,
+                    Message::Close => return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "websocket closed by the Miniserver")),
+                }
+            }
+        })
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("{} timed out", name)))??;
+        response_value(&response, name)
+    }
+}
+
+fn unix_now() -> f64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64()
+}
+
+/// The token of the current connection and how refreshing it went.
+#[derive(Default)]
+struct TokenState {
+    token: String,
+    user: String,
+    /// Key for hashing the token in refreshjwt, from getjwt
+    key: Option<Vec<u8>>,
+    hash_alg: Option<HashAlg>,
+    /// Unix times; `valid_until` is 0 without a token
+    issued_at: f64,
+    valid_until: f64,
+    refreshing: bool,
+    retry_at: Option<tokio::time::Instant>,
+    refreshes: u64,
+    refresh_failures: u64,
+    last_error: Option<String>,
+}
+
+impl TokenState {
+    /// Take the token of a getjwt or refreshjwt response.
+    fn store(&mut self, value: &Value, command: &str) -> io::Result<()> {
+        let valid_until = value
+            .get("validUntil")
+            .and_then(Value::as_f64)
+            .ok_or_else(|| other(format!("{}: no validUntil in the response", command)))?;
+        if let Some(token) = value.get("token").and_then(Value::as_str) {
+            self.token = token.to_string();
+        }
+        if let Some(key) = value.get("key").and_then(Value::as_str) {
+            self.key = crypto::from_hex(key);
+        }
+        self.issued_at = unix_now();
+        self.valid_until = LOXONE_EPOCH + valid_until;
+        self.retry_at = None;
+        if value.get("unsecurePass").and_then(Value::as_bool).unwrap_or(false) {
+            warn!("Miniserver reports an unsecure password for the relay user");
+        }
+        Ok(())
+    }
+
+    /// Time until the next refresh; `None` without a token or while one is running.
+    fn refresh_delay(&self) -> Option<Duration> {
+        if self.refreshing || self.valid_until == 0.0 {
+            return None;
+        }
+        if let Some(retry_at) = self.retry_at {
+            return Some(retry_at.saturating_duration_since(tokio::time::Instant::now()));
+        }
+        let due = self.valid_until - (self.valid_until - self.issued_at) * TOKEN_REFRESH_MARGIN;
+        Some(Duration::from_secs_f64((due - unix_now()).max(0.0)))
+    }
+}
+
+fn check_header(header: &[u8]) -> io::Result<()> {
+    if header.len() == 8 && header[0] == 0x03 && header[1] == HEADER_OUT_OF_SERVICE {
+        return Err(other("Miniserver is out of service (rebooting or updating)"));
+    }
+    Ok(())
+}
+
+pub struct LoxWs {
+    client_id: String,
+    keepalive: Duration,
+    state: AtomicU8,
+    /// Numbers connections, so a closing one can't clear the channel of its successor
+    generation: AtomicU64,
+    commands: Mutex<Option<(u64, mpsc::UnboundedSender<Request>)>>,
+    /// Gets the event tables of connections opened with `receive_updates`
+    states: Mutex<Option<StateListener>>,
+    token: Mutex<TokenState>,
+}
+
+impl LoxWs {
+    pub fn is_connected(&self) -> bool {
+        self.state.load(Ordering::Relaxed) == CONNECTED
+    }
+
+    async fn command(&self, command: String) -> io::Result<Value> {
+        let (reply, response) = oneshot::channel();
+        let sent = self
+            .commands
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|(_, tx)| tx.send(Request { command, reply }).is_ok());
+        if !sent {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "websocket not connected"));
+        }
+        tokio::time::timeout(COMMAND_TIMEOUT, response)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "websocket command timed out after 10 seconds"))?
+            .map_err(|_| io::Error::new(io::ErrorKind::ConnectionAborted, "websocket closed before the response"))?
+    }
+
+    /// Set virtual input `name` to `value`.
+    pub async fn send_io(&self, name: &str, value: &str) -> io::Result<()> {
+        let response = self.command(format!("jdev/sps/io/{}/{}", encode_component(name), encode_component(value))).await?;
+        response_value(&response, "sps/io").map(drop)
+    }
+
+    /// Expiry and refresh state of the token: `{valid_until, expires_in, refreshes,
+    /// refresh_failures, last_error}`, the times in seconds (Unix time and from now).
+    pub fn token_status(&self) -> Value {
+        let token = self.token.lock().unwrap();
+        let valid = token.valid_until > 0.0;
+        json!({
+            "valid_until": valid.then_some(token.valid_until as u64),
+            "expires_in": valid.then(|| (token.valid_until - unix_now()).max(0.0) as u64),
+            "refreshes": token.refreshes,
+            "refresh_failures": token.refresh_failures,
+            "last_error": token.last_error,
+        })
+    }
+
+    /// Hand the Loxone state changes of connections with `receive_updates` to `listener`.
+    pub fn set_state_listener(&self, listener: StateListener) {
+        *self.states.lock().unwrap() = Some(listener);
+    }
+
+    fn event_table(&self, kind: u8, data: &[u8]) {
+        let updates = parse_event_table(kind, data);
+        if updates.is_empty() {
+            return;
+        }
+        debug!("Websocket: {} state updates", updates.len());
+        let listener = self.states.lock().unwrap().clone();
+        if let Some(listener) = listener {
+            listener(updates);
+        }
+    }
+
+    /// With `locals` the connection asks for state updates and runs within them, so the
+    /// state listener can start coroutines on the loop.
+    async fn connect(
+        self: Arc<Self>,
+        host: String,
+        port: u16,
+        user: String,
+        password: String,
+        locals: Option<pyo3_async_runtimes::TaskLocals>,
+    ) -> io::Result<()> {
+        self.state.store(CONNECTING, Ordering::Relaxed);
+        match self.open(&host, port, &user, &password).await {
+            Ok(ws) => {
+                let (tx, rx) = mpsc::unbounded_channel();
+                let generation = self.generation.fetch_add(1, Ordering::Relaxed);
+                if locals.is_some() {
+                    // Answered like any command; the event tables follow
+                    let (reply, _) = oneshot::channel();
+                    let _ = tx.send(Request { command: "jdev/sps/enablebinstatusupdate".to_string(), reply });
+                }
+                *self.commands.lock().unwrap() = Some((generation, tx));
+                self.state.store(CONNECTED, Ordering::Relaxed);
+                info!("Websocket connected to {}:{} as {}", host, port, user);
+                match locals {
+                    Some(locals) => tokio::spawn(pyo3_async_runtimes::tokio::scope(locals, self.run(generation, ws, rx))),
+                    None => tokio::spawn(self.run(generation, ws, rx)),
+                };
+                Ok(())
+            }
+            Err(e) => {
+                self.state.store(DISCONNECTED, Ordering::Relaxed);
+                Err(e)
+            }
+        }
+    }
+
+    /// Key exchange and token authentication (getkey2, then an encrypted getjwt).
+    async fn open(&self, host: &str, port: u16, user: &str, password: &str) -> io::Result<WebSocket> {
+        let (status, body) = http_sender::get_once(host, port, "/jdev/sys/getPublicKey", None).await?;
+        if status != 200 {
+            return Err(other(format!("getPublicKey failed with HTTP {}", status)));
+        }
+        let response: Value = serde_json::from_slice(&body).map_err(|e| other(format!("getPublicKey: {}", e)))?;
+        let pem = response_value(&response, "getPublicKey")?;
+        let public_key = RsaPublicKey::from_pem(pem.as_str().unwrap_or_default()).map_err(other)?;
+
+        let mut handshake = Handshake {
+            ws: WebSocket::connect(host, port, "/ws/rfc6455", "remotecontrol", MAX_MESSAGE_BYTES).await?,
+        };
+        let mut key = [0u8; 32];
+        let mut iv = [0u8; 16];
+        crypto::random_bytes(&mut key).map_err(other)?;
+        crypto::random_bytes(&mut iv).map_err(other)?;
+        let session_key = public_key.encrypt(format!("{}:{}", crypto::hex(&key), crypto::hex(&iv)).as_bytes()).map_err(other)?;
+        handshake
+            .request(&format!("jdev/sys/keyexchange/{}", general_purpose::STANDARD.encode(session_key)), "keyexchange")
+            .await?;
+
+        let key_info = handshake.request(&format!("jdev/sys/getkey2/{}", encode_component(user)), "getkey2").await?;
+        let field = |name: &str| {
+            key_info.get(name).and_then(Value::as_str).map(str::to_string).ok_or_else(|| other(format!("getkey2: no {}", name)))
+        };
+        let hash_alg = HashAlg::parse(&field("hashAlg").unwrap_or_else(|_| "SHA1".to_string()));
+        let user_key = crypto::from_hex(&field("key")?).ok_or_else(|| other("getkey2: key isn't hex"))?;
+        let password_hash = crypto::hex(&hash_alg.digest(format!("{}:{}", password, field("salt")?).as_bytes())).to_uppercase();
+        let credentials = crypto::hex(&hash_alg.hmac(&user_key, format!("{}:{}", user, password_hash).as_bytes()));
+        let getjwt = format!(
+            "jdev/sys/getjwt/{}/{}/{}/{}/{}",
+            credentials,
+            encode_component(user),
+            TOKEN_PERMISSION,
+            client_uuid(&self.client_id),
+            encode_component(&self.client_id)
+        );
+        let token = handshake.request(&encrypt_command(&key, &iv, &getjwt)?, "getjwt").await?;
+        if token.get("token").is_none() {
+            return Err(other("getjwt: no token in the response"));
+        }
+        let mut state = self.token.lock().unwrap();
+        state.store(&token, "getjwt")?;
+        state.user = user.to_string();
+        state.hash_alg = Some(hash_alg);
+        state.refreshing = false;
+        Ok(handshake.ws)
+    }
+
+    /// Refresh the token with refreshjwt, hashed with the key from getjwt (or getkey).
+    /// A failed refresh is tried again after `TOKEN_RETRY`, until the token expires and the
+    /// Miniserver closes the connection.
+    async fn refresh_token(self: Arc<Self>, generation: u64) {
+        let result = self.request_refresh().await;
+        let current = self.commands.lock().unwrap().as_ref().is_some_and(|(g, _)| *g == generation);
+        let mut token = self.token.lock().unwrap();
+        if !current {
+            // A new connection has its own token by now
+            return;
+        }
+        token.refreshing = false;
+        match result.and_then(|value| token.store(&value, "refreshjwt")) {
+            Ok(()) => {
+                token.refreshes += 1;
+                token.last_error = None;
+                info!("Refreshed the websocket token, valid for {:.0}s", token.valid_until - unix_now());
+            }
+            Err(e) => {
+                error!("Refreshing the websocket token failed: {}", e);
+                token.refresh_failures += 1;
+                token.last_error = Some(e.to_string());
+                token.retry_at = Some(tokio::time::Instant::now() + TOKEN_RETRY);
+            }
+        }
+    }
+
+    async fn request_refresh(&self) -> io::Result<Value> {
+        let (token, user, key, hash_alg) = {
+            let state = self.token.lock().unwrap();
+            (state.token.clone(), state.user.clone(), state.key.clone(), state.hash_alg.unwrap_or(HashAlg::Sha1))
+        };
+        let key = match key {
+            Some(key) => key,
+            None => {
+                let key = response_value(&self.command("jdev/sys/getkey".to_string()).await?, "getkey")?;
+                crypto::from_hex(key.as_str().unwrap_or_default()).ok_or_else(|| other("getkey: key isn't hex"))?
+            }
+        };
+        let hash = crypto::hex(&hash_alg.hmac(&key, token.as_bytes()));
+        let response = self.command(format!("jdev/sys/refreshjwt/{}/{}", hash, encode_component(&user))).await?;
+        response_value(&response, "refreshjwt")
+    }
+
+    /// Send commands and match responses (in order) until the connection fails or `close`.
+    /// The token is refreshed before it expires.
+    async fn run(self: Arc<Self>, generation: u64, mut ws: WebSocket, mut requests: mpsc::UnboundedReceiver<Request>) {
+        let mut pending: VecDeque<oneshot::Sender<io::Result<Value>>> = VecDeque::new();
+        let mut ping = tokio::time::interval_at(tokio::time::Instant::now() + self.keepalive, self.keepalive);
+        let mut awaiting_keepalive = false;
+        // Identifier of the binary message announced by the last header
+        let mut announced: Option<u8> = None;
+        let result: io::Result<()> = async {
+            loop {
+                let refresh_in = self.token.lock().unwrap().refresh_delay();
+                tokio::select! {
+                    message = ws.read_message() => match message? {
+                        Message::Text(text) => match pending.pop_front() {
+                            Some(reply) => {
+                                let _ = reply.send(serde_json::from_str(&text).map_err(|e| other(e.to_string())));
+                            }
+                            None => debug!("Unexpected websocket message: {}", text),
+                        },
+                        Message::Binary(data) => match announced.take() {
+                            Some(kind) => self.event_table(kind, &data),
+                            None => {
+                                check_header(&data)?;
+                                if data.len() == 8 && data[0] == 0x03 {
+                                    let length = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+                                    match data[1] {
+                                        HEADER_KEEPALIVE => awaiting_keepalive = false,
+                                        // Text responses come as text messages
+                                        HEADER_TEXT => {}
+                                        kind if data[2] & HEADER_ESTIMATED == 0 && length > 0 => announced = Some(kind),
+                                        _ => {}
+                                    }
+                                }
+                            }
+                        },
+                        Message::Ping(payload) => ws.pong(&payload).await?,
+                        Message::Close => return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "websocket closed by the Miniserver")),
+                    },
+                    request = requests.recv() => match request {
+                        Some(request) => {
+                            ws.send_text(&request.command).await?;
+                            pending.push_back(request.reply);
+                        }
+                        None => {
+                            let _ = ws.close().await;
+                            return Ok(());
+                        }
+                    },
+                    _ = tokio::time::sleep(refresh_in.unwrap_or_default()), if refresh_in.is_some() => {
+                        self.token.lock().unwrap().refreshing = true;
+                        tokio::spawn(Arc::clone(&self).refresh_token(generation));
+                    }
+                    _ = ping.tick() => {
+                        if awaiting_keepalive {
+                            return Err(io::Error::new(io::ErrorKind::TimedOut, "no keepalive response"));
+                        }
+                        ws.send_text("keepalive").await?;
+                        awaiting_keepalive = true;
+                    }
+                }
+            }
+        }
+        .await;
+        {
+            let mut commands = self.commands.lock().unwrap();
+            if commands.as_ref().is_some_and(|(current, _)| *current == generation) {
+                *commands = None;
+                self.state.store(DISCONNECTED, Ordering::Relaxed);
+            }
+        }
+        match result {
+            Ok(()) => info!("Websocket closed"),
+            Err(e) => error!("Websocket connection lost: {}", e),
+        }
+    }
+}
+
+/// `jdev/sys/enc/<cipher>`: AES-256-CBC with the session key over `salt/<salt>/<command>`,
+/// zero padded, so the credentials hash never goes over the wire in clear.
+fn encrypt_command(key: &[u8; 32], iv: &[u8; 16], command: &str) -> io::Result<String> {
+    let mut salt = [0u8; 2];
+    crypto::random_bytes(&mut salt).map_err(other)?;
+    let mut plain = format!("salt/{}/{}", crypto::hex(&salt), command).into_bytes();
+    plain.push(0);
+    plain.resize(plain.len().div_ceil(16) * 16, 0);
+    let cipher = Aes256::new(key).encrypt_cbc(iv, &plain);
+    Ok(format!("jdev/sys/enc/{}", encode_component(&general_purpose::STANDARD.encode(cipher))))
+}
+
+/// Loxone websocket client in Rust, a replacement for `loxwebsocket` with the same calls
+/// the relay uses (`miniserver.native_websocket`). While it is connected the processor
+/// sends virtual input updates over it directly, without a Python coroutine per value.
+#[pyclass]
+pub struct LoxWsClient {
+    pub(crate) inner: Arc<LoxWs>,
+}
+
+#[pymethods]
+impl LoxWsClient {
+    #[new]
+    #[pyo3(signature = (client_id="loxmqttrelay", keepalive=60))]
+    fn new(client_id: &str, keepalive: u64) -> Self {
+        LoxWsClient {
+            inner: Arc::new(LoxWs {
+                client_id: client_id.to_string(),
+                keepalive: Duration::from_secs(keepalive.max(1)),
+                state: AtomicU8::new(DISCONNECTED),
+                generation: AtomicU64::new(0),
+                commands: Mutex::new(None),
+                states: Mutex::new(None),
+                token: Mutex::new(TokenState::default()),
+            }),
+        }
+    }
+
+    /// "CONNECTED", "CONNECTING" or "DISCONNECTED".
+    #[getter]
+    fn state(&self) -> &'static str {
+        match self.inner.state.load(Ordering::Relaxed) {
+            CONNECTED => "CONNECTED",
+            CONNECTING => "CONNECTING",
+            _ => "DISCONNECTED",
+        }
+    }
+
+    /// Whether the websocket is open and authenticated.
+    #[getter]
+    fn is_connected(&self) -> bool {
+        self.inner.is_connected()
+    }
+
+    /// Expiry and refresh state of the token, see `get_metrics()["websocket_token"]`.
+    #[getter]
+    fn token<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        crate::py_json::to_py(py, &self.inner.token_status())
+    }
+
+    /// Open the websocket to `loxone_url` and authenticate. Awaitable; raises OSError if
+    /// the Miniserver can't be reached or rejects the credentials. With `receive_updates`
+    /// the Miniserver sends its state changes, which go to the processor's state
+    /// publisher (`miniserver.publish_states`).
+    #[pyo3(signature = (user, password, loxone_url, receive_updates=false))]
+    fn connect<'py>(&self, py: Python<'py>, user: String, password: String, loxone_url: &str, receive_updates: bool) -> PyResult<Bound<'py, PyAny>> {
+        let (host, port) = parse_url(loxone_url)?;
+        let locals = if receive_updates { Some(pyo3_async_runtimes::tokio::get_current_locals(py)?) } else { None };
+        // A connection still open is replaced
+        self.inner.commands.lock().unwrap().take();
+        let inner = self.inner.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move { Ok(inner.connect(host, port, user, password, locals).await?) })
+    }
+
+    /// Set virtual input `name` to `value`. Awaitable; raises OSError on failure.
+    fn send_websocket_command<'py>(&self, py: Python<'py>, name: String, value: String) -> PyResult<Bound<'py, PyAny>> {
+        let inner = self.inner.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move { Ok(inner.send_io(&name, &value).await?) })
+    }
+
+    /// Close the websocket. Awaitable.
+    fn close<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        self.inner.commands.lock().unwrap().take();
+        self.inner.state.store(DISCONNECTED, Ordering::Relaxed);
+        pyo3_async_runtimes::tokio::future_into_py(py, async { Ok(()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_refresh_delay() {
+        let mut token = TokenState::default();
+        assert_eq!(token.refresh_delay(), None);
+        let lifetime = 1000.0;
+        let valid_until = unix_now() - LOXONE_EPOCH + lifetime;
+        token.store(&json!({"token": "abc", "key": "3132", "validUntil": valid_until}), "getjwt").unwrap();
+        assert_eq!(token.key.as_deref(), Some(&b"12"[..]));
+        // Due with a tenth of the lifetime left
+        let delay = token.refresh_delay().unwrap().as_secs_f64();
+        assert!((899.0..=900.0).contains(&delay), "{}", delay);
+        token.refreshing = true;
+        assert_eq!(token.refresh_delay(), None);
+
+        // Expired or almost: right away
+        token.refreshing = false;
+        token.store(&json!({"validUntil": unix_now() - LOXONE_EPOCH - 5.0}), "refreshjwt").unwrap();
+        assert_eq!(token.token, "abc");
+        assert_eq!(token.refresh_delay(), Some(Duration::ZERO));
+        assert!(token.store(&json!({"token": "x"}), "refreshjwt").is_err());
+    }
+
+    #[test]
+    fn token_status() {
+        let ws = LoxWsClient::new("test", 60).inner;
+        assert_eq!(ws.token_status()["valid_until"], Value::Null);
+        ws.token.lock().unwrap().store(&json!({"token": "abc", "validUntil": unix_now() - LOXONE_EPOCH + 100.0}), "getjwt").unwrap();
+        let status = ws.token_status();
+        assert!((99..=100).contains(&status["expires_in"].as_u64().unwrap()));
+        assert_eq!(status["refreshes"], 0);
+    }
+}
@@ -16,7 +16,14 @@ logger = get_lazy_logger(__name__)
 if "arm" in platform.machine().lower():
     from loxmqttrelay.compatible._loxmqttrelay import (
         MiniserverDataProcessor,
-        init_rust_logger
+        RelayMqttClient,
+        LoxWsClient,
+        init_rust_logger,
+        set_rust_log_level,
+        merge_config_value,
+        validate_config,
+        load_config_file,
+        ConfigWatcher
     )
     logger.info("Using ARM compatible implementation")
 else:
@@ -32,13 +39,27 @@ else:
         if output and ("avx" in output.lower() and "avx2" in output.lower()):
             from loxmqttrelay.optimized._loxmqttrelay import (
                 MiniserverDataProcessor,
-                init_rust_logger
+                RelayMqttClient,
+                LoxWsClient,
+                init_rust_logger,
+        set_rust_log_level,
+                merge_config_value,
+                validate_config,
+                load_config_file,
+                ConfigWatcher
             )
             logger.info("Using optimized implementation with AVX/AVX2 support")
         else:
             from loxmqttrelay.compatible._loxmqttrelay import (
                 MiniserverDataProcessor,
-                init_rust_logger
+                RelayMqttClient,
+                LoxWsClient,
+                init_rust_logger,
+        set_rust_log_level,
+                merge_config_value,
+                validate_config,
+                load_config_file,
+                ConfigWatcher
             )
             logger.info("Using compatible implementation (AVX/AVX2 not detected)")
 
@@ -46,7 +67,14 @@ else:
         logger.error("Error checking CPU features. Using compatible implementation.")
         from loxmqttrelay.compatible._loxmqttrelay import (
             MiniserverDataProcessor,
-            init_rust_logger
+            RelayMqttClient,
+            LoxWsClient,
+            init_rust_logger,
+        set_rust_log_level,
+            merge_config_value,
+            validate_config,
+            load_config_file,
+            ConfigWatcher
         )
 
 from loxmqttrelay.config import global_config
@@ -60,5 +88,12 @@ setup_logging()
 __all__ = [
     'global_config',
     'MiniserverDataProcessor',
-    'init_rust_logger'
+    'RelayMqttClient',
+    'LoxWsClient',
+    'init_rust_logger',
+    'set_rust_log_level',
+    'merge_config_value',
+    'validate_config',
+    'load_config_file',
+    'ConfigWatcher'
 ]
\ No newline at end of file
@@ -0,0 +1,48 @@
+from typing import Any, Dict, Optional, Tuple
+from urllib.parse import urlsplit
+
+import aiohttp
+
+from loxmqttrelay.logging_config import get_lazy_logger
+from loxmqttrelay.utils import split_host_port
+
+logger = get_lazy_logger(__name__)
+
+CLOUD_DNS_URL = "https://dns.loxonecloud.com"
+
+
+def parse_getip(payload: Dict[str, Any]) -> Optional[Tuple[str, int]]:
+    """Address from a getip answer, e.g. {"Code": 200, "IP": "93.184.216.34:7777", ...}."""
+    if int(payload.get("Code", 0)) != 200 or not payload.get("IP"):
+        return None
+    return split_host_port(str(payload["IP"]), 80)
+
+
+def parse_redirect(location: str) -> Optional[Tuple[str, int]]:
+    """Address from the redirect of dns.loxonecloud.com/<serial>, e.g. "http://93.184.216.34:7777/"."""
+    parts = urlsplit(location)
+    if not parts.hostname:
+        return None
+    return parts.hostname, parts.port or (443 if parts.scheme == "https" else 80)
+
+
+async def resolve_cloud_dns(serial: str, base_url: str = CLOUD_DNS_URL, timeout: float = 10) -> Optional[Tuple[str, int]]:
+    """
+    Public address and HTTP port under which Loxone Cloud DNS reaches the Miniserver with
+    `serial`, or None if it isn't registered.
+    """
+    serial = serial.replace(":", "").upper()
+    url = f"{base_url}/?getip&snr={serial}&json=true"
+    async with aiohttp.ClientSession(timeout=aiohttp.ClientTimeout(total=timeout)) as session:
+        async with session.get(url, allow_redirects=False) as resp:
+            if resp.status in (301, 302, 303, 307, 308):
+                # Older service versions answer with a redirect to the Miniserver instead
+                return parse_redirect(resp.headers.get("Location", ""))
+            if resp.status != 200:
+                logger.warning(f"Loxone Cloud DNS returned {resp.status} for {serial}")
+                return None
+            payload = await resp.json(content_type=None)
+    address = parse_getip(payload)
+    if address is None:
+        logger.warning(f"Loxone Cloud DNS has no address for {serial}: {payload.get('DNS-Status', payload.get('Code'))}")
+    return address
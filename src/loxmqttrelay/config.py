@@ -1,6 +1,7 @@
 import os
+import copy
 import logging
-from dataclasses import dataclass, field, asdict, replace, fields
+from dataclasses import dataclass, field, asdict, fields
 import threading
 from typing import Dict, Any, List, Optional, Literal, get_type_hints, Set
 import tomlkit
@@ -18,12 +19,41 @@ class ConfigSection(Enum):
     PROCESSING = "processing"
     UDP = "udp"
     DEBUG = "debug"
+    HA = "ha"
+
+def instance_base_topic(base_topic: str, instance_id: str) -> str:
+    """Base topic with the instance namespace woven in, e.g. "myrelay/garage/"."""
+    if base_topic and not base_topic.endswith("/"):
+        # "myrelay" stands for the whole segment, not a prefix of "myrelay2"
+        base_topic += "/"
+    instance_id = (instance_id or "").strip("/")
+    return f"{base_topic}{instance_id}/" if instance_id else base_topic
 
 @dataclass
 class GeneralConfig:
     log_level: str = "INFO"
     base_topic: str = "myrelay/"
     cache_size: int = 100000
+    cache_warmup: bool = True
+    instance_id: str = ""
+    forward_unknown_subtopics: bool = False
+    shutdown_timeout: float = 5.0
+    log_file: str = ""
+    log_file_max_bytes: int = 10485760
+    log_file_backups: int = 5
+    # Where the Rust logs go besides the log file; "python" uses this logging config
+    log_sink: Literal["python", "stderr", "syslog", "journald"] = "python"
+    log_syslog_address: str = ""
+    # Announce forwarded inputs to Home Assistant via MQTT discovery
+    homeassistant_discovery: bool = False
+    homeassistant_prefix: str = "homeassistant"
+    # Apply changes to the config file while running
+    watch_config: bool = True
+
+    @property
+    def relay_topic(self) -> str:
+        """Base topic of this relay instance; control, status and config topics live below it."""
+        return instance_base_topic(self.base_topic, self.instance_id)
 
 @dataclass
 class BrokerConfig:
@@ -32,6 +62,19 @@ class BrokerConfig:
     user: Optional[str] = None
     password: Optional[str] = None
     client_id: str = "loxmqttrelay"
+    # Use the MQTT client built into the Rust extension instead of gmqtt
+    native_client: bool = False
+    # MQTTS for the native client; the CA file replaces the bundled roots, cert and key add a client certificate
+    tls: bool = False
+    tls_ca_file: str = ""
+    tls_cert_file: str = ""
+    tls_key_file: str = ""
+    # Accept any broker certificate, e.g. a self-signed one
+    tls_insecure: bool = False
+    # {base_topic}status after each connect, and the last will the broker publishes if the relay dies
+    birth_message: str = "Connected"
+    will_message: str = "Connection lost"
+    status_retain: bool = True
 
 @dataclass
 class MiniserverConfig:
@@ -40,29 +83,201 @@ class MiniserverConfig:
     miniserver_user: str = ""
     miniserver_pass: str = ""
     miniserver_max_parallel_connections: int = 5
+    # Workers sending from a bounded queue; 0 starts every send right away
+    send_concurrency: int = 0
+    send_queue_size: int = 1000
+    # "drop_oldest", "drop_newest" or "block" (pauses the native MQTT client)
+    send_queue_overflow: str = "drop_oldest"
+    # Retries of failed sends, waiting send_retry_backoff seconds, doubling up to the max
+    send_retries: int = 0
+    send_retry_backoff: float = 1.0
+    send_retry_max_backoff: float = 30.0
+    # Spread of each wait, as a fraction of it
+    send_retry_jitter: float = 0.2
+    # Publish sends that failed for good to <relay_topic>deadletter/<virtual input>
+    dead_letter: bool = False
+    # Keep sends that failed for good in an append log and replay them once the Miniserver is back
+    outbox: bool = False
+    # The log file; empty for outbox.jsonl next to the config file
+    outbox_path: str = ""
+    # "ordered" replays every value oldest first, "latest" only the newest per virtual input
+    outbox_replay: str = "ordered"
+    outbox_max_entries: int = 10000
+    # Seconds after which a buffered value is dropped instead of replayed; 0 keeps it
+    outbox_max_age: float = 86400.0
+    # Combine values into one datagram or request: "off", "message" (per MQTT message) or "window"
+    batch_mode: str = "off"
+    # With "window": values arriving within this many ms go out together
+    batch_window_ms: int = 50
+    batch_max_values: int = 50
+    # Virtual text input receiving HTTP/websocket batches; empty batches UDP only
+    batch_input: str = ""
+    batch_separator: str = ";"
+    # Publish Loxone state changes to <relay_topic>miniserver/<control>/<state> (needs native_websocket)
+    publish_states: bool = False
+    # States without a mapping go to <relay_topic>miniserver/<uuid>
+    publish_unmapped_states: bool = False
+    retain_states: bool = True
+    # [{uuid = "...", control = "Kitchen Light", state = "active"}]
+    state_mappings: List[Dict[str, str]] = field(default_factory=list)
+    # More Miniservers: [{name = "garden", ip = "...", port = 80, user = "", pass = "", transport = "http" | "udp"}]
+    targets: List[Dict[str, Any]] = field(default_factory=list)
+    # First match wins: [{match = "^garden/", target = "garden"}]; unmatched topics go to "default"
+    routes: List[Dict[str, str]] = field(default_factory=list)
+    # Secondary Miniserver taking over while the primary fails its health checks; empty disables failover
+    failover_ip: str = ""
+    failover_port: int = 80
+    health_check_interval: float = 5.0
+    # Failed (or, to switch back, passed) checks of the primary in a row before switching
+    health_check_failures: int = 3
     sync_with_miniserver: bool = True
+    # "config" reads the virtual inputs from the sps config over FTP, "structure" the
+    # controls of /data/LoxAPP3.json over HTTP (only those shown in the app)
+    sync_source: Literal["config", "structure"] = "config"
     use_websocket: bool = True
+    # Send HTTP requests from Rust over pooled connections (basic auth only)
+    native_http: bool = False
+    # How values are sent unless their rule sets a transport: "miniserver" (use_websocket decides), "http", "websocket" or "udp"
+    default_transport: str = "miniserver"
+    # HTTPS for native_http and the failover health checks, options as in [broker]
+    miniserver_tls: bool = False
+    miniserver_tls_ca_file: str = ""
+    miniserver_tls_cert_file: str = ""
+    miniserver_tls_key_file: str = ""
+    miniserver_tls_insecure: bool = False
+    # Loxone websocket client in Rust instead of loxwebsocket (plain ws:// only)
+    native_websocket: bool = False
+    miniserver_discovery: bool = False
+    miniserver_discovery_service: str = "_http._tcp.local."
+    miniserver_discovery_match: str = "Loxone"
+    miniserver_discovery_interval: int = 300
+    miniserver_serial: str = ""
+    miniserver_cloud_dns_interval: int = 600
+    miniserver_probe_interval: int = 10
+    miniserver_offline_queue_size: int = 1000
+    miniserver_busy_backoff: float = 1.0
+    miniserver_busy_backoff_max: float = 30.0
+    miniserver_keepalive_seconds: int = 30
+    miniserver_dns_refresh_seconds: int = 300
+    miniserver_proxy: str = ""
+    miniserver_proxy_from_env: bool = False
+    miniserver_token_auth: bool = False
+    websocket_keepalive_interval: int = 60
 
 @dataclass
 class TopicsConfig:
     subscriptions: List[str] = field(default_factory=list)
     subscription_filters: List[str] = field(default_factory=list)
+    filter_flattened_keys: bool = True
+    post_expansion_filters: List[str] = field(default_factory=list)
     topic_whitelist: Set[str] = field(default_factory=set)
     do_not_forward: List[str] = field(default_factory=list)
+    # Published to {base_topic}monitored/<topic> after conversion, never sent to the Miniserver
+    monitor_topics: List[str] = field(default_factory=list)
+    strict_filters: bool = False
+    filter_anchor: Literal["none", "start", "full"] = "none"
+    # Default syntax of filter entries; a "regex:" or "mqtt:" prefix selects it per entry
+    filter_syntax: Literal["regex", "mqtt"] = "regex"
+    rules: List[Dict[str, Any]] = field(default_factory=list)
+    rewrites: List[Dict[str, str]] = field(default_factory=list)
+    strip_prefixes: List[str] = field(default_factory=list)
+    max_name_length: int = 64
+    # Virtual input names: "/", "%" and name_replace_chars (e.g. " #+") become name_replacement
+    name_replacement: str = "_"
+    name_replace_chars: str = ""
+    name_lowercase: bool = False
+    policy: Literal["deny_overrides", "allow_overrides", "whitelist_only"] = "deny_overrides"
+    # Deprecated, no effect: rules always run ahead of the filter lists
+    unified_rules: bool = False
 
 @dataclass
 class ProcessingConfig:
     expand_json: bool = True
+    flatten_separator: str = "/"
+    # Levels of nesting that are flattened, deeper parts are sent as JSON text; 0 for no limit
+    flatten_max_depth: int = 0
+    flatten_arrays: Literal["index", "join", "skip"] = "index"
     convert_booleans: bool = True
+    # Words forwarded as 1 and 0, compared ignoring case
+    bool_true_values: List[str] = field(default_factory=lambda: ["true", "yes", "on", "enabled", "enable", "1", "check", "checked", "select", "selected"])
+    bool_false_values: List[str] = field(default_factory=lambda: ["false", "no", "off", "disabled", "disable", "0"])
+    # Topics whose values are forwarded as they are, without the conversion to 1/0
+    bool_exclude_topics: List[str] = field(default_factory=list)
+    # Topics whose values like "21.5 °C" or "3,14" are forwarded as the bare number
+    numeric_topics: List[str] = field(default_factory=list)
+    scripts: List[Dict[str, Any]] = field(default_factory=list)
+    script_max_operations: int = 100000
+    script_timeout_ms: int = 50
+    plugins: List[Dict[str, Any]] = field(default_factory=list)
+    plugin_fuel: int = 10000000
+    payload_templates: Dict[str, str] = field(default_factory=dict)
+    duplicate_window_seconds: float = 0.0
+    forward_only_changes: bool = False
+    forward_only_changes_max_age: float = 0.0
+    rate_limit: List[Dict[str, Any]] = field(default_factory=list)
+    # {"pattern": ..., "convert": "f_to_c" | ..., "scale": ..., "offset": ..., "precision": ..., "min"/"max"/"clamp": ...}
+    transforms: List[Dict[str, Any]] = field(default_factory=list)
+    # {"topic": ..., "sources": [...], "op": "sum" | "avg" | "min" | "max"} or {"topic", "sources": {name: topic}, "expression"}
+    derived: List[Dict[str, Any]] = field(default_factory=list)
+    # {"pattern": ..., "epoch": "loxone" | "unix", "utc_offset_minutes": ...}: timestamps forwarded as seconds
+    timestamps: List[Dict[str, Any]] = field(default_factory=list)
+    extract: List[Dict[str, Any]] = field(default_factory=list)
+    # {"pattern": ..., "format": "msgpack" | "cbor" | "auto"}: binary payloads decoded to JSON
+    payload_formats: List[Dict[str, str]] = field(default_factory=list)
+    max_message_bytes: int = 0
+    # Drop JSON payloads nested deeper or flattening into more values; 0 for no limit
+    max_json_depth: int = 0
+    max_json_keys: int = 0
+    # Seconds without a value after which an input is sent stale_value and reported offline; 0 for off
+    stale_timeout: float = 0.0
+    # Empty to only publish the availability
+    stale_value: str = "0"
+    # Topics watched for stale values; empty watches every forwarded topic
+    stale_topics: List[str] = field(default_factory=list)
+    # Inflate zlib/gzip payloads (detected by their header), and any payload of decompress_topics
+    decompress: bool = False
+    decompress_topics: List[str] = field(default_factory=list)
+    max_decompressed_bytes: int = 1048576
+    # Topics whose XML payloads are flattened into one value per element and attribute
+    xml_topics: List[str] = field(default_factory=list)
+    # Topics with "key=value,key2=value2" or InfluxDB line protocol payloads, one value per key or field
+    key_value_topics: List[str] = field(default_factory=list)
+    influx_topics: List[str] = field(default_factory=list)
+    invalid_utf8: Literal["base64", "hex", "replace", "lossy", "drop"] = "base64"
 
 @dataclass
 class UdpConfig:
     udp_in_port: int = 11884
+    udp_in_host: str = "0.0.0.0"
+    # "host:port" of Loxone Virtual UDP Inputs, for rules with transport = "udp"
+    udp_out_destinations: List[str] = field(default_factory=list)
 
 @dataclass
 class DebugConfig:
     mock_ip: str = ""
     enable_mock: bool = False
+    publish_processed_topics: bool = False
+    publish_forwarded_topics: bool = False
+    stage_timing: bool = False
+    # Seconds between publishes of get_metrics() to <relay_topic>stats; 0 to disable
+    metrics_interval: float = 0
+    # Port of the Prometheus /metrics endpoint; 0 to disable
+    prometheus_port: int = 0
+    prometheus_host: str = "0.0.0.0"
+    # Keep the trace of the last N data messages for get_traces(); 0 to disable
+    trace_messages: int = 0
+    # Publish the trace of every data message to <relay_topic>trace/<topic>
+    publish_trace: bool = False
+    # Record incoming data messages to this file for replay_capture(); empty to disable
+    capture_file: str = ""
+    # Stop recording once the file reaches this size; 0 for no limit
+    capture_max_bytes: int = 104857600
+
+@dataclass
+class HaConfig:
+    ha_enabled: bool = False
+    ha_node_id: str = ""
+    ha_lease_seconds: int = 30
 
 @dataclass
 class AppConfig:
@@ -73,6 +288,7 @@ class AppConfig:
     processing: ProcessingConfig = field(default_factory=ProcessingConfig)
     udp: UdpConfig = field(default_factory=UdpConfig)
     debug: DebugConfig = field(default_factory=DebugConfig)
+    ha: HaConfig = field(default_factory=HaConfig)
 
     def to_dict(self) -> Dict[str, Any]:
         return {f.name: asdict(getattr(self, f.name)) for f in fields(self)}
@@ -117,6 +333,8 @@ class Config:
             if not hasattr(self, '_initialized'):
                 self.config_path = config_path
                 self._config = self._load_config()
+                # The file as last read or written, to tell edits from changes made while running
+                self._file_state = self._config.to_dict()
                 self.field_mappings = self._map_fields_to_sections()
                 self._initialized = True
 
@@ -125,9 +343,31 @@ class Config:
             logger.warning(f"Config file not found, creating default config: {self.config_path}")
             return AppConfig()
 
-        with open(self.config_path, "r") as f:
-            config_dict = tomlkit.parse(f.read()).unwrap()
-        return AppConfig.from_dict(config_dict)
+        # Imported here, the package imports this module while loading the extension
+        from loxmqttrelay import load_config_file
+        return AppConfig.from_dict(load_config_file(self.config_path))
+
+    def reload_from_file(self) -> List[str]:
+        """
+        Read the config file again, take over the fields edited in it since it was last
+        read or written and return their "section.field" names. A file that doesn't load
+        or fails `validate_config` is rejected as a whole: the config stays as it is and a
+        ValueError lists the problems.
+        """
+        from loxmqttrelay import validate_config
+        candidate = self._load_config()
+        errors = validate_config(candidate)
+        if errors:
+            raise ValueError("; ".join(errors))
+        new = candidate.to_dict()
+        changed = []
+        for section, values in new.items():
+            for field_name, value in values.items():
+                if self._file_state.get(section, {}).get(field_name) != value:
+                    setattr(getattr(self._config, section), field_name, getattr(getattr(candidate, section), field_name))
+                    changed.append(f"{section}.{field_name}")
+        self._file_state = new
+        return changed
 
     def save_config(self) -> None:
         doc = tomlkit.document()
@@ -169,58 +409,81 @@ class Config:
                 logger.error("Please change the file permissions and restart.")
         except Exception as e:
             logger.error(f"Error saving config: {e}")
+        try:
+            self._file_state = self._load_config().to_dict()
+        except (OSError, ValueError) as e:
+            logger.error(f"Error reading back the saved config: {e}")
 
     def update_field(self, field_name: str, value: Any, list_mode: Literal["set", "add", "remove"] = "set") -> None:
-        section, field_type = self._get_field_info(field_name)
-        current_value = getattr(getattr(self._config, section.value), field_name)
-
-        if isinstance(current_value, (list, set)):
-            if isinstance(current_value, set):
-                if list_mode == "set":
-                    new_value = set(value) if isinstance(value, (list, set)) else {value}
-                elif list_mode == "add":
-                    new_value = current_value | (set(value) if isinstance(value, (list, set)) else {value})
-                elif list_mode == "remove":
-                    new_value = current_value - (set(value) if isinstance(value, (list, set)) else {value})
-            else:  # list type
-                if list_mode == "set":
-                    new_value = list(value) if isinstance(value, list) else [value]
-                elif list_mode == "add":
-                    new_value = list(set(current_value + (value if isinstance(value, list) else [value])))
-                elif list_mode == "remove":
-                    new_value = [item for item in current_value if item not in (value if isinstance(value, list) else [value])]
-            value = new_value
-
-        setattr(getattr(self._config, section.value), field_name, value)
-        self.save_config()
+        self.update_fields({field_name: value}, list_mode)
+
+    def _merge(self, section: ConfigSection, updates: Dict[str, Any], list_mode: str) -> Dict[str, Any]:
+        # Imported here: the package imports this module while loading the extension
+        from loxmqttrelay import merge_config_value
+        section_config = getattr(self._config, section.value)
+        hints = get_type_hints(type(section_config))
+        merged = {}
+        for field_name, value in updates.items():
+            if field_name not in hints:
+                raise ValueError(f"Unknown configuration field: {section.value}.{field_name}")
+            current_value = getattr(section_config, field_name)
+            merged[field_name] = merge_config_value(field_name, hints[field_name], current_value, value, list_mode)
+        return merged
 
     def update_fields(self, updates: Dict[str, Any], list_mode: Literal["set", "add", "remove"] = "set") -> None:
+        """
+        Merge `updates` (field name -> value) into the config, see `merge_config_value`.
+        All fields are checked first: on a ValueError nothing is changed.
+        """
+        by_section: Dict[ConfigSection, Dict[str, Any]] = {}
         for field_name, value in updates.items():
-            self.update_field(field_name, value, list_mode)
+            section, _ = self._get_field_info(field_name)
+            by_section.setdefault(section, {})[field_name] = value
+        merged = {section: self._merge(section, fields, list_mode) for section, fields in by_section.items()}
+        for section, values in merged.items():
+            self._apply(section, values)
+        self.save_config()
+
+    def candidate(self, updates: Dict[str, Any], list_mode: Literal["set", "add", "remove"] = "set") -> tuple[AppConfig, List[str]]:
+        """
+        A copy of the config with `updates` merged in, and the errors found while merging.
+        Nothing is applied or saved. `updates` maps field names to values like for
+        `update_fields`, or sections to such mappings like `get_safe_config` returns.
+        Fields with errors keep their current value in the copy.
+        """
+        candidate = copy.deepcopy(self._config)
+        errors: List[str] = []
+        for section_name, field_name, value in self._flatten_updates(updates):
+            try:
+                section, _ = self._get_field_info(field_name)
+                if section_name is not None and section.value != section_name:
+                    raise ValueError(f"Unknown configuration field: {section_name}.{field_name}")
+                merged = self._merge(section, {field_name: value}, list_mode)
+                setattr(getattr(candidate, section.value), field_name, merged[field_name])
+            except ValueError as e:
+                errors.append(str(e))
+        return candidate, errors
+
+    @staticmethod
+    def _flatten_updates(updates: Dict[str, Any]) -> List[tuple[Optional[str], str, Any]]:
+        sections = {section.value for section in ConfigSection}
+        flat: List[tuple[Optional[str], str, Any]] = []
+        for key, value in updates.items():
+            if key in sections and isinstance(value, dict):
+                flat.extend((key, field_name, field_value) for field_name, field_value in value.items())
+            else:
+                flat.append((None, key, value))
+        return flat
 
     def update_config(self, section: ConfigSection, updates: Dict[str, Any], list_mode: Literal["set", "add", "remove"] = "set") -> None:
-        section_config = getattr(self._config, section.value)
-        for field_name, value in updates.items():
-            current_value = getattr(section_config, field_name)
-            if isinstance(current_value, (list, set)):
-                if isinstance(current_value, set):
-                    if list_mode == "set":
-                        new_value = set(value) if isinstance(value, (list, set)) else {value}
-                    elif list_mode == "add":
-                        new_value = current_value | (set(value) if isinstance(value, (list, set)) else {value})
-                    elif list_mode == "remove":
-                        new_value = current_value - (set(value) if isinstance(value, (list, set)) else {value})
-                else:  # list type
-                    if list_mode == "set":
-                        new_value = list(value) if isinstance(value, list) else [value]
-                    elif list_mode == "add":
-                        new_value = list(set(current_value + (value if isinstance(value, list) else [value])))
-                    elif list_mode == "remove":
-                        new_value = [item for item in current_value if item not in (value if isinstance(value, list) else [value])]
-                updates[field_name] = new_value
-        setattr(self._config, section.value, replace(section_config, **updates))
+        self._apply(section, self._merge(section, updates, list_mode))
         self.save_config()
 
+    def _apply(self, section: ConfigSection, values: Dict[str, Any]) -> None:
+        section_config = getattr(self._config, section.value)
+        for field_name, value in values.items():
+            setattr(section_config, field_name, value)
+
     def _get_field_info(self, field_name: str) -> tuple[ConfigSection, type]:
         if field_name not in self.field_mappings:
             raise ValueError(f"Unknown configuration field: {field_name}")
@@ -266,6 +529,10 @@ class Config:
     def debug(self) -> DebugConfig:
         return self._config.debug
 
+    @property
+    def ha(self) -> HaConfig:
+        return self._config.ha
+
     def get_safe_config(self) -> Dict[str, Any]:
         """Return a copy of the config with sensitive data removed."""
         config_dict = self._config.to_dict()
@@ -282,6 +549,10 @@ class Config:
             miniserver = config_dict['miniserver'].copy()
             miniserver.pop('miniserver_user', None)
             miniserver.pop('miniserver_pass', None)
+            miniserver['targets'] = [
+                {k: v for k, v in target.items() if k not in ('user', 'pass')}
+                for target in miniserver.get('targets', [])
+            ]
             config_dict['miniserver'] = miniserver
             
         return config_dict
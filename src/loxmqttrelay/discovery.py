@@ -0,0 +1,129 @@
+import asyncio
+import socket
+import struct
+from dataclasses import dataclass
+from typing import Dict, List, Optional, Tuple
+
+from loxmqttrelay.logging_config import get_lazy_logger
+
+logger = get_lazy_logger(__name__)
+
+MDNS_GROUP = ("224.0.0.251", 5353)
+
+TYPE_A = 1
+TYPE_PTR = 12
+TYPE_SRV = 33
+CLASS_IN = 1
+
+
+@dataclass(frozen=True)
+class DiscoveredMiniserver:
+    name: str
+    host: str
+    port: int
+
+
+def build_query(service: str) -> bytes:
+    """One-shot mDNS PTR query for `service` (e.g. "_http._tcp.local.")."""
+    header = struct.pack("!HHHHHH", 0, 0, 1, 0, 0, 0)
+    return header + _encode_name(service) + struct.pack("!HH", TYPE_PTR, CLASS_IN)
+
+
+def _encode_name(name: str) -> bytes:
+    out = b""
+    for label in name.strip(".").split("."):
+        encoded = label.encode("utf-8")
+        out += bytes([len(encoded)]) + encoded
+    return out + b"\x00"
+
+
+def _decode_name(data: bytes, offset: int) -> Tuple[str, int]:
+    """Decode a (possibly compressed) DNS name, returning it and the offset behind it."""
+    labels: List[str] = []
+    end = None
+    for _ in range(128):  # guards against compression loops
+        length = data[offset]
+        if length & 0xC0 == 0xC0:
+            if end is None:
+                end = offset + 2
+            offset = ((length & 0x3F) << 8) | data[offset + 1]
+            continue
+        offset += 1
+        if length == 0:
+            break
+        labels.append(data[offset:offset + length].decode("utf-8", errors="replace"))
+        offset += length
+    else:
+        raise ValueError("DNS name compression loop")
+    return ".".join(labels) + ".", end if end is not None else offset
+
+
+def parse_response(data: bytes, service: str, match: str = "") -> List[DiscoveredMiniserver]:
+    """
+    Extract the instances of `service` whose name contains `match` (case-insensitive)
+    from an mDNS response, resolved to IPv4 address and port via its SRV and A records.
+    """
+    _, _, qdcount, ancount, nscount, arcount = struct.unpack("!HHHHHH", data[:12])
+    offset = 12
+    for _ in range(qdcount):
+        _, offset = _decode_name(data, offset)
+        offset += 4
+
+    instances: List[str] = []
+    srv: Dict[str, Tuple[str, int]] = {}
+    addresses: Dict[str, str] = {}
+    for _ in range(ancount + nscount + arcount):
+        name, offset = _decode_name(data, offset)
+        rtype, _, _, rdlength = struct.unpack("!HHIH", data[offset:offset + 10])
+        offset += 10
+        rdata_offset = offset
+        offset += rdlength
+        key = name.lower()
+        if rtype == TYPE_PTR and key == service.lower():
+            instances.append(_decode_name(data, rdata_offset)[0])
+        elif rtype == TYPE_SRV:
+            port = struct.unpack("!H", data[rdata_offset + 4:rdata_offset + 6])[0]
+            srv[key] = (_decode_name(data, rdata_offset + 6)[0].lower(), port)
+        elif rtype == TYPE_A and rdlength == 4:
+            addresses[key] = socket.inet_ntoa(data[rdata_offset:rdata_offset + 4])
+
+    found = []
+    for instance in instances:
+        if match.lower() not in instance.lower() or instance.lower() not in srv:
+            continue
+        target, port = srv[instance.lower()]
+        if target in addresses:
+            found.append(DiscoveredMiniserver(instance.rstrip("."), addresses[target], port))
+    return found
+
+
+class _MdnsProtocol(asyncio.DatagramProtocol):
+    def __init__(self, service: str, match: str):
+        self.service = service
+        self.match = match
+        self.result: asyncio.Future = asyncio.get_running_loop().create_future()
+
+    def datagram_received(self, data: bytes, addr) -> None:
+        try:
+            found = parse_response(data, self.service, self.match)
+        except (ValueError, IndexError, struct.error) as e:
+            logger.debug(f"Ignoring malformed mDNS response from {addr}: {e}")
+            return
+        if found and not self.result.done():
+            self.result.set_result(found[0])
+
+
+async def discover_miniserver(service: str, match: str = "", timeout: float = 3.0) -> Optional[DiscoveredMiniserver]:
+    """Ask the LAN for the Miniserver via mDNS; None if nobody answers within `timeout`."""
+    loop = asyncio.get_running_loop()
+    transport, protocol = await loop.create_datagram_endpoint(
+        lambda: _MdnsProtocol(service, match), local_addr=("0.0.0.0", 0), family=socket.AF_INET
+    )
+    try:
+        # Queries from a port other than 5353 are answered by unicast to that port
+        transport.sendto(build_query(service), MDNS_GROUP)
+        return await asyncio.wait_for(protocol.result, timeout)
+    except asyncio.TimeoutError:
+        return None
+    finally:
+        transport.close()
@@ -0,0 +1,51 @@
+import socket
+from time import monotonic
+from typing import Any, Dict, List, Optional, Tuple
+
+import aiohttp
+from aiohttp.abc import AbstractResolver
+
+from loxmqttrelay.logging_config import get_lazy_logger
+
+logger = get_lazy_logger(__name__)
+
+
+class CachingResolver(AbstractResolver):
+    """
+    Resolver for the Miniserver connection pool that keeps the last good resolution.
+
+    Entries are re-resolved after `refresh_seconds` or after `expire()` (called when a
+    connection fails), so dyndns address changes are picked up. If re-resolution fails,
+    the previous addresses keep being used instead of failing the send.
+    """
+
+    def __init__(self, refresh_seconds: float, resolver: Optional[AbstractResolver] = None):
+        self.refresh_seconds = refresh_seconds
+        self._resolver = resolver or aiohttp.DefaultResolver()
+        self._cache: Dict[Tuple[str, int, int], Tuple[float, List[Any]]] = {}
+
+    async def resolve(self, host: str, port: int = 0, family: int = socket.AF_INET) -> List[Any]:
+        key = (host, port, family)
+        cached = self._cache.get(key)
+        if cached and monotonic() - cached[0] < self.refresh_seconds:
+            return cached[1]
+        try:
+            addresses = await self._resolver.resolve(host, port, family)
+        except OSError as e:
+            if not cached:
+                raise
+            logger.warning(f"Re-resolving {host} failed ({e}), keeping cached address")
+            return cached[1]
+        if cached and [a["host"] for a in cached[1]] != [a["host"] for a in addresses]:
+            logger.info(f"{host} now resolves to {', '.join(a['host'] for a in addresses)}")
+        self._cache[key] = (monotonic(), addresses)
+        return addresses
+
+    def expire(self, host: Optional[str] = None) -> None:
+        """Re-resolve `host` (or every host) on its next lookup, keeping the cached result as fallback."""
+        for key, (_, addresses) in self._cache.items():
+            if host is None or key[0] == host:
+                self._cache[key] = (float("-inf"), addresses)
+
+    async def close(self) -> None:
+        await self._resolver.close()
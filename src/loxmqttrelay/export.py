@@ -0,0 +1,60 @@
+import csv
+import io
+from typing import Dict, Iterable, Mapping, Optional
+from xml.sax.saxutils import quoteattr
+
+
+def collect_virtual_inputs(whitelist: Iterable[str], forwarded: Mapping[str, str]) -> Dict[str, str]:
+    """
+    Merge the whitelist and the virtual inputs seen at runtime into a
+    virtual input name -> source topic mapping (topic is empty for names only known
+    from the whitelist), sorted by name.
+    """
+    inputs = {name: "" for name in whitelist}
+    inputs.update(forwarded)
+    return dict(sorted(inputs.items()))
+
+
+def to_csv(inputs: Mapping[str, str]) -> str:
+    """One row per virtual input: name and the MQTT topic feeding it."""
+    out = io.StringIO()
+    writer = csv.writer(out, lineterminator="\n")
+    writer.writerow(["virtual_input", "topic"])
+    writer.writerows(inputs.items())
+    return out.getvalue()
+
+
+# Analog command attributes shared by both template kinds
+_ANALOG = (
+    'Signed="true" Analog="true" SourceValLow="0" DestValLow="0" SourceValHigh="100" DestValHigh="100" '
+    'DefVal="0" MinVal="-10000" MaxVal="10000"'
+)
+
+
+def to_loxone_xml(inputs: Mapping[str, str], title: str = "loxmqttrelay", udp_port: Optional[int] = None) -> str:
+    """
+    Loxone Config template with one analog input per virtual input, the source topic as
+    comment. Without `udp_port` it's a Virtual Input template for values sent over HTTP or
+    the websocket (`/dev/sps/io/<name>/<value>`); with it a Virtual UDP Input on that port
+    matching `name=value` datagrams. Import it via "Virtual Inputs > Import template".
+    """
+    if udp_port is None:
+        lines = [
+            '<?xml version="1.0" encoding="utf-8"?>',
+            f'<VirtualIn Title={quoteattr(title)} Comment="Generated by loxmqttrelay">',
+        ]
+        for name, topic in inputs.items():
+            lines.append(f'\t<VirtualInCmd Title={quoteattr(name)} Comment={quoteattr(topic)} {_ANALOG}/>')
+        lines.append('</VirtualIn>')
+        return "\n".join(lines) + "\n"
+    lines = [
+        '<?xml version="1.0" encoding="utf-8"?>',
+        f'<VirtualInUdp Title={quoteattr(title)} Comment="Generated by loxmqttrelay" Address="" Port="{udp_port}">',
+    ]
+    for name, topic in inputs.items():
+        lines.append(
+            f'\t<VirtualInUdpCmd Title={quoteattr(name)} Comment={quoteattr(topic)} '
+            f'Address="" Check={quoteattr(name + "=" + chr(92) + "v")} {_ANALOG}/>'
+        )
+    lines.append('</VirtualInUdp>')
+    return "\n".join(lines) + "\n"
@@ -0,0 +1,93 @@
+import asyncio
+import socket
+from time import monotonic
+from typing import Awaitable, Callable, Optional
+
+import orjson
+
+from loxmqttrelay.logging_config import get_lazy_logger
+
+logger = get_lazy_logger(__name__)
+
+
+class HaCoordinator:
+    """
+    Active/standby coordination of relay instances via a retained MQTT lock.
+
+    The active instance renews the lock every third of the lease. A standby claims it once it
+    hasn't seen a renewal for a full lease. Lock expiry is measured on the local clock from the
+    moment a lock message arrives, so the instances' clocks don't need to agree. The broker
+    delivers lock messages to everyone in the same order, so the owner of the last lock message
+    seen is the active instance, even if two standbys claim at the same time.
+    """
+
+    def __init__(
+        self,
+        node_id: str,
+        lock_topic: str,
+        state_topic: str,
+        lease_seconds: float,
+        publish: Callable[[str, str | bytes, bool], Awaitable[None]],
+        on_change: Callable[[bool], None],
+    ):
+        self.node_id = node_id or socket.gethostname()
+        self.lock_topic = lock_topic
+        self.state_topic = state_topic
+        self.lease_seconds = max(float(lease_seconds), 3.0)
+        self._publish = publish
+        self._on_change = on_change
+        self._owner: Optional[str] = None
+        self._owner_lease = self.lease_seconds
+        self._last_seen = 0.0
+        self.active = False
+
+    def handle_lock_message(self, payload: str | bytes) -> None:
+        """Record the current lock owner from a (retained) lock message."""
+        if not payload:
+            logger.info("HA lock released")
+            self._owner = None
+        else:
+            try:
+                data = orjson.loads(payload)
+                owner = str(data["owner"])
+                lease = float(data.get("lease", self.lease_seconds))
+            except (orjson.JSONDecodeError, KeyError, TypeError, ValueError) as e:
+                logger.warning(f"Ignoring malformed HA lock message {payload!r}: {e}")
+                return
+            self._owner = owner
+            self._owner_lease = lease
+            self._last_seen = monotonic()
+        self._update_state()
+
+    @property
+    def owner(self) -> Optional[str]:
+        return None if self._lock_expired() else self._owner
+
+    def _lock_expired(self) -> bool:
+        return self._owner is None or monotonic() - self._last_seen > self._owner_lease
+
+    def _update_state(self) -> None:
+        active = self._owner == self.node_id and not self._lock_expired()
+        if active == self.active:
+            return
+        self.active = active
+        logger.info(f"HA node '{self.node_id}' is now {'active' if active else 'standby'} (lock owner: {self.owner})")
+        self._on_change(active)
+        asyncio.create_task(self._publish(self.state_topic, "active" if active else "standby", False))
+
+    async def tick(self) -> None:
+        """Renew the lock if we hold it, claim it if it expired."""
+        if self._owner == self.node_id or self._lock_expired():
+            payload = orjson.dumps({"owner": self.node_id, "lease": self.lease_seconds})
+            await self._publish(self.lock_topic, payload, True)
+        self._update_state()
+
+    async def run(self) -> None:
+        # Give the retained lock of an active peer time to arrive before claiming
+        await asyncio.sleep(self.lease_seconds / 3)
+        while True:
+            try:
+                await self.tick()
+            except Exception as e:
+                logger.error(f"HA lock update failed: {e}")
+            await asyncio.sleep(self.lease_seconds / 3)
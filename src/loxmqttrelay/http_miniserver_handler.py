@@ -1,8 +1,13 @@
 import asyncio
+import time
 import aiohttp
-from typing import Any 
+from collections import OrderedDict
+from typing import Any, Callable, Dict, Optional, Tuple
 from loxmqttrelay.config import global_config
+from loxmqttrelay.dns_cache import CachingResolver
 from loxmqttrelay.logging_config import get_lazy_logger
+from loxmqttrelay.token_auth import TokenAuth, TokenAuthError
+from loxmqttrelay.utils import build_base_url, split_host_port
 from loxwebsocket.lox_ws_api import loxwebsocket
 
 logger = get_lazy_logger(__name__)
@@ -19,30 +24,112 @@ class HttpMiniserverHandler:
     enable_mock_miniserver=global_config.debug.enable_mock
     mock_ms_ip=global_config.debug.mock_ip
     connection_semaphore = asyncio.Semaphore(global_config.miniserver.miniserver_max_parallel_connections)  # Default to 5 parallel connections
-    target_ip = mock_ms_ip if (mock_ms_ip and enable_mock_miniserver) else ms_ip
+    target_ip, target_port = split_host_port(mock_ms_ip if (mock_ms_ip and enable_mock_miniserver) else ms_ip, ms_port)
     # Construct WebSocket URL with proper port handling
-    protocol = "https" if ms_port == 443 else "http"
-    if ms_port not in [80, 443]:
-        ws_base_url = f"{protocol}://{target_ip}:{ms_port}"
-        http_base_url = f"http://{target_ip}:{ms_port}"
-    else:
-        ws_base_url = f"{protocol}://{target_ip}"
-        http_base_url = f"http://{target_ip}"
+    ws_base_url = build_base_url("https" if target_port == 443 else "http", target_ip, target_port)
+    http_base_url = build_base_url("http", target_ip, target_port)
     auth = aiohttp.BasicAuth(ms_user, ms_pass) if ms_user and ms_pass else None
     # Increase the timeout to 10 seconds
     timeout = aiohttp.ClientTimeout(total=10)
+    probe_timeout = aiohttp.ClientTimeout(total=3)
 
 
     """Handler for processing and sending data to Miniserver via HTTP."""
     def __init__(self):
         logger.info("MQTT Miniserver Handler created")
+        # False while the connectivity probe fails; sends are queued instead of timing out
+        self.online = True
+        # Last reported connection state; state_listener(state, error) is called on changes,
+        # see MiniserverDataProcessor.set_connection_state
+        self.state = "connecting"
+        self.state_listener: Optional[Callable[..., object]] = None
+        # normalized topic -> (topic, value); only the latest value per input is kept
+        self.offline_queue: "OrderedDict[str, Tuple[str, Any]]" = OrderedDict()
+        # While the Miniserver is busy (503 or refusing connections during boot or a config
+        # save), sends are queued until busy_until (monotonic), with growing back-off
+        self.busy_until = 0.0
+        self.busy_backoff = 0.0
+        self._busy_retry: Optional[asyncio.Task] = None
+        self._session: Optional[aiohttp.ClientSession] = None
+        self._resolver: Optional[CachingResolver] = None
+        # connected_since is None while disconnected
+        self.ws_state: Dict[str, Any] = {"connected_since": None, "reconnects": 0, "last_error": None}
+        self.token_auth: Optional[TokenAuth] = None
+        if global_config.miniserver.miniserver_token_auth and self.ms_user and self.ms_pass:
+            self.token_auth = TokenAuth(self.ms_user, self.ms_pass, global_config.broker.client_id)
+        # The Rust websocket client; the processor also sends over it directly while connected
+        self.native_ws = None
+        if global_config.miniserver.native_websocket:
+            from loxmqttrelay import LoxWsClient
+            self.native_ws = LoxWsClient(global_config.broker.client_id, global_config.miniserver.websocket_keepalive_interval)
+
+    def _get_session(self) -> aiohttp.ClientSession:
+        """
+        Shared session for all requests, so the pooled keep-alive connections (and their
+        authentication) are reused instead of a new TCP handshake per virtual input update.
+        """
+        if self._session is None or self._session.closed:
+            self._resolver = CachingResolver(global_config.miniserver.miniserver_dns_refresh_seconds)
+            connector = aiohttp.TCPConnector(
+                limit=global_config.miniserver.miniserver_max_parallel_connections,
+                keepalive_timeout=global_config.miniserver.miniserver_keepalive_seconds,
+                resolver=self._resolver,
+                use_dns_cache=False,
+            )
+            self._session = aiohttp.ClientSession(
+                # With token auth the credentials are only sent to acquire the token
+                auth=None if self.token_auth else self.auth,
+                timeout=self.timeout,
+                connector=connector,
+                # Without an explicit proxy, HTTP(S)_PROXY / NO_PROXY are honored if enabled
+                proxy=global_config.miniserver.miniserver_proxy or None,
+                trust_env=global_config.miniserver.miniserver_proxy_from_env,
+            )
+        return self._session
+
+    async def _authenticate(self, session: aiohttp.ClientSession, url: str) -> Tuple[str, Dict[str, Any]]:
+        """URL and request kwargs for `url`: token query parameters, or basic auth if no token can be had."""
+        if self.token_auth is None:
+            return url, {}
+        try:
+            await self.token_auth.get_token(session, self.http_base_url)
+        except (TokenAuthError, aiohttp.ClientError, KeyError, ValueError) as e:
+            logger.warning(f"Miniserver token unavailable ({e}), using basic auth")
+            return url, {"auth": self.auth}
+        return f"{url}?{self.token_auth.query()}", {}
+
+    def _expire_dns(self) -> None:
+        """The Miniserver's address may have changed: re-resolve on the next connection."""
+        if self._resolver is not None:
+            self._resolver.expire()
+
+    async def close(self) -> None:
+        if self._busy_retry is not None:
+            self._busy_retry.cancel()
+            self._busy_retry = None
+        if self._session is not None and not self._session.closed:
+            await self._session.close()
+        if self._resolver is not None:
+            await self._resolver.close()
+        self._session = None
+        self._resolver = None
+
+    def set_miniserver_address(self, ip: str, port: int) -> None:
+        """Point the handler at a new Miniserver address, e.g. one found via discovery."""
+        self.ms_ip = ip
+        self.ms_port = port
+        if self.mock_ms_ip and self.enable_mock_miniserver:
+            return
+        self.target_ip, self.target_port = split_host_port(ip, port)
+        self.ws_base_url = build_base_url("https" if self.target_port == 443 else "http", self.target_ip, self.target_port)
+        self.http_base_url = build_base_url("http", self.target_ip, self.target_port)
 
     async def send_to_minisever_via_websocket(
         self,
         topic: str,
         normalized_topic: str,
         value: Any
-    ) -> None:
+    ) -> Dict[str, Any]:
         """
         Sends data to the Loxone Miniserver via a WebSocket connection.
         Returns a dictionary with results for each topic.
@@ -50,26 +137,73 @@ class HttpMiniserverHandler:
         # Determine target IP
         logger.debug(f"Using miniserver address: {self.target_ip} {'(mock)' if (self.mock_ms_ip and self.enable_mock_miniserver) else '(real)'}")
 
-        ws_client = loxwebsocket
-        if "CONNECTED" not in ws_client.state:
-            await ws_client.connect(user=self.ms_user, password=self.ms_pass, loxone_url=self.ws_base_url, receive_updates=False)
-
+        ws_client = self._ws_client()
         try:
+            await self.ensure_websocket()
             await ws_client.send_websocket_command(normalized_topic, str(value))
+            self._report_state("connected")
             logger.debug(f"Sent {topic} (as {normalized_topic})={value} to Miniserver successfully via WebSocket.")
-            return 
+            return { 'code': 200 }
         except Exception as e:
+            if self._is_busy_error(e):
+                return self._defer_busy(topic, normalized_topic, value, str(e))
             error_msg = f"Error sending {topic} (as {normalized_topic})={value} to Miniserver via WebSocket: {str(e)}"
             logger.error(error_msg)
-            return 
+            self._report_state("degraded", str(e))
+            return { 'code': 500 }
 
 
+    async def ensure_websocket(self) -> None:
+        """
+        (Re)connect the websocket if it is not connected. The client acquires a token on
+        connect; the native one refreshes it before it expires (see LoxWsClient.token).
+        """
+        ws_client = self._ws_client()
+        if self._ws_connected():
+            return
+        if self.ws_state["connected_since"] is not None:
+            logger.warning(f"Websocket connection lost (state {ws_client.state}), reconnecting")
+            self.ws_state["reconnects"] += 1
+        self.ws_state["connected_since"] = None
+        try:
+            # Only the native client hands state updates to the processor (publish_states)
+            receive_updates = self.native_ws is not None and global_config.miniserver.publish_states
+            await ws_client.connect(user=self.ms_user, password=self.ms_pass, loxone_url=self.ws_base_url, receive_updates=receive_updates)
+        except Exception as e:
+            self.ws_state["last_error"] = str(e)
+            raise
+        self.ws_state["connected_since"] = time.time()
+
+    def websocket_status(self) -> Dict[str, Any]:
+        return {"state": str(self._ws_client().state), **self.ws_state}
+
+    def _ws_client(self):
+        return self.native_ws if self.native_ws is not None else loxwebsocket
+
+    def _ws_connected(self) -> bool:
+        # The native client's "DISCONNECTED" contains "CONNECTED", so it is asked directly
+        if self.native_ws is not None:
+            return self.native_ws.is_connected
+        return "CONNECTED" in loxwebsocket.state
+
+    async def run_websocket_keepalive(self) -> None:
+        """
+        Check the websocket every `websocket_keepalive_interval` seconds and reconnect a
+        dropped connection right away, instead of on (and delaying) the next send.
+        """
+        while True:
+            await asyncio.sleep(global_config.miniserver.websocket_keepalive_interval)
+            try:
+                await self.ensure_websocket()
+            except Exception as e:
+                logger.error(f"Websocket reconnect failed: {e}")
+
     async def send_to_miniserver_via_http(
         self,
         topic: str,
         normalized_topic: str,
         value: Any
-    ) -> None:
+    ) -> Dict[str, Any]:
         """
         Send data to Miniserver with rate limiting.
         If mock_ms_ip is provided and enable_mock_miniserver is True, mock server will be used instead of ms_ip.
@@ -78,66 +212,213 @@ class HttpMiniserverHandler:
         # Use mock miniserver IP only if both provided and enabled
         logger.debug(f"Using miniserver address: {self.target_ip} {'(mock)' if (self.mock_ms_ip and self.enable_mock_miniserver) else '(real)'}")
 
-        async with aiohttp.ClientSession(auth=self.auth, timeout=self.timeout) as session:
-            # Ensure value is converted to string
-            safe_value = str(value)
-            # Use pre-built HTTP base URL
-            url = f"{self.http_base_url}/dev/sps/io/{normalized_topic}/{safe_value}"
-            logger.debug(f"Sending to {url}")
-            
-            try:
-                # Use semaphore to limit concurrent connections
-                async with self.connection_semaphore:
-                    async with session.get(url) as resp:
-                        if resp.status != 200:
-                            logger.warning(f"Miniserver returned {resp.status} for topic {topic} (URL: {url})")
-                        else:
-                            logger.debug(f"Sent {topic}={value} to Miniserver successfully.")
-                        return { 'code': resp.status }
-            except asyncio.TimeoutError:
-                error_msg = f" Error 408: Timeout while sending {topic} (as {normalized_topic})={value} to Miniserver (URL: {url}): request timed out after 10 seconds"
-                logger.error(error_msg)
-                return 
-            except asyncio.CancelledError:
-                error_msg = f"Error 499: Request for {topic} (as {normalized_topic})={value} was cancelled (URL: {url})"
-                logger.error(error_msg)
-                return 
-            except OSError as e:
-                error_msg = f"Error 503: Connection error sending {topic} (as {normalized_topic})={value} to Miniserver (URL: {url}): {str(e)}"
-                logger.error(error_msg)
-                return 
-            except aiohttp.ClientError as e:
-                error_msg = f"Error 500: Client error sending {topic} (as {normalized_topic})={value} to Miniserver (URL: {url}): {str(e)}"
-                logger.error(error_msg)
-                return 
-            except Exception as e:
-                error_msg = f"Error 500: Unexpected error sending {topic} (as {normalized_topic})={value} to Miniserver (URL: {url}): {str(e)}"
-                logger.error(error_msg)
-                return 
+        session = self._get_session()
+        # Ensure value is converted to string
+        safe_value = str(value)
+        # Use pre-built HTTP base URL
+        url = f"{self.http_base_url}/dev/sps/io/{normalized_topic}/{safe_value}"
+        logger.debug(f"Sending to {url}")
+        
+        try:
+            # Use semaphore to limit concurrent connections
+            async with self.connection_semaphore:
+                request_url, request_kwargs = await self._authenticate(session, url)
+                async with session.get(request_url, **request_kwargs) as resp:
+                    if resp.status == 503 and self._busy_deferral_enabled():
+                        return self._defer_busy(topic, normalized_topic, value, "HTTP 503")
+                    if resp.status == 401 and self.token_auth:
+                        logger.warning("Miniserver rejected the token, acquiring a new one on the next send")
+                        self.token_auth.invalidate()
+                    if resp.status != 200:
+                        logger.warning(f"Miniserver returned {resp.status} for topic {topic} (URL: {url})")
+                        self._report_state("degraded", f"HTTP {resp.status}")
+                    else:
+                        self._busy_over()
+                        self._report_state("connected")
+                        logger.debug(f"Sent {topic}={value} to Miniserver successfully.")
+                    return { 'code': resp.status }
+        except asyncio.TimeoutError:
+            error_msg = f" Error 408: Timeout while sending {topic} (as {normalized_topic})={value} to Miniserver (URL: {url}): request timed out after 10 seconds"
+            logger.error(error_msg)
+            self._expire_dns()
+            self._go_offline(topic, normalized_topic, value, "timeout")
+            return { 'code': 408 }
+        except asyncio.CancelledError:
+            error_msg = f"Error 499: Request for {topic} (as {normalized_topic})={value} was cancelled (URL: {url})"
+            logger.error(error_msg)
+            return { 'code': 499 }
+        except OSError as e:
+            if self._is_busy_error(e):
+                return self._defer_busy(topic, normalized_topic, value, str(e))
+            error_msg = f"Error 503: Connection error sending {topic} (as {normalized_topic})={value} to Miniserver (URL: {url}): {str(e)}"
+            logger.error(error_msg)
+            self._expire_dns()
+            self._go_offline(topic, normalized_topic, value, str(e))
+            return { 'code': 503 }
+        except aiohttp.ClientError as e:
+            error_msg = f"Error 500: Client error sending {topic} (as {normalized_topic})={value} to Miniserver (URL: {url}): {str(e)}"
+            logger.error(error_msg)
+            self._report_state("degraded", str(e))
+            return { 'code': 500 }
+        except Exception as e:
+            error_msg = f"Error 500: Unexpected error sending {topic} (as {normalized_topic})={value} to Miniserver (URL: {url}): {str(e)}"
+            logger.error(error_msg)
+            return { 'code': 500 }
     
     async def send_to_miniserver(
         self,
         topic: str,
         normalized_topic: str,
         value: Any,
-    ) -> None:
+        transport: Optional[str] = None,
+    ) -> Dict[str, Any]:
         """
         Process data and send it to Miniserver.
         
         Args:
             data: The data to process and send
             mqtt_publish_callback: Callback for MQTT publishing (required for topic forwarding)
+            transport: "http" or "websocket" to use that connection regardless of use_websocket
             
         Returns:
-            None
+            {'code': <HTTP status>} with 200 on success, or {'queued': True} while offline
         """
         logger.debug(f"Sending {topic} (as {normalized_topic})={value} to Miniserver")
-        # Send to Miniserver using WebSocket or HTTP based on config
-        if global_config.miniserver.use_websocket:
-            await self.send_to_minisever_via_websocket(topic, normalized_topic, value)
-        else:
-            await self.send_to_miniserver_via_http(topic, normalized_topic, value)
+        if not self.online or time.monotonic() < self.busy_until:
+            self._queue_offline(topic, normalized_topic, value)
+            return { 'queued': True }
+        # Send to Miniserver using WebSocket or HTTP based on the rule or the config
+        if transport == "websocket" or (transport != "http" and global_config.miniserver.use_websocket):
+            return await self.send_to_minisever_via_websocket(topic, normalized_topic, value)
+        return await self.send_to_miniserver_via_http(topic, normalized_topic, value)
+
+    def native_target(self) -> Optional[Tuple[str, int]]:
+        """
+        Where the Rust senders (miniserver.native_http, native_websocket) may send right now: only while the
+        Miniserver is known to be healthy, so queueing, busy back-off and state changes
+        stay with send_to_miniserver.
+        """
+        if not self.online or self.busy_until or self.state != "connected":
+            return None
+        return self.target_ip, self.target_port
+
+    def _queue_offline(self, topic: str, normalized_topic: str, value: Any) -> None:
+        self.offline_queue.pop(normalized_topic, None)
+        self.offline_queue[normalized_topic] = (topic, value)
+        while len(self.offline_queue) > global_config.miniserver.miniserver_offline_queue_size:
+            dropped, _ = self.offline_queue.popitem(last=False)
+            logger.warning(f"Offline queue full, dropping queued value for {dropped}")
+
+    def _report_state(self, state: str, error: Optional[str] = None) -> None:
+        if state == self.state:
+            return
+        self.state = state
+        if self.state_listener is None:
+            return
+        try:
+            self.state_listener(state, error)
+        except Exception as e:
+            logger.error(f"Error reporting Miniserver connection state: {e}")
+
+    def _go_offline(self, topic: str, normalized_topic: str, value: Any, error: str) -> None:
+        """A send failed to connect: gate further sends until the probe succeeds again."""
+        if global_config.miniserver.miniserver_probe_interval <= 0:
+            # Without a probe there's no telling when it's back, so keep sending
+            self._report_state("degraded", error)
+            return
+        if self.online:
+            logger.warning("Miniserver unreachable, queueing values until it is back")
+            self.online = False
+        self._report_state("offline", error)
+        self._queue_offline(topic, normalized_topic, value)
+
+    @staticmethod
+    def _busy_deferral_enabled() -> bool:
+        return global_config.miniserver.miniserver_busy_backoff > 0
+
+    def _is_busy_error(self, error: BaseException) -> bool:
+        """Whether `error` (or what caused it) means the Miniserver is up but can't serve requests yet."""
+        if not self._busy_deferral_enabled():
+            return False
+        seen = set()
+        while error is not None and id(error) not in seen:
+            seen.add(id(error))
+            if isinstance(error, ConnectionRefusedError):
+                return True
+            if isinstance(error, aiohttp.ClientConnectorError) and isinstance(error.os_error, ConnectionRefusedError):
+                return True
+            if isinstance(error, aiohttp.ClientResponseError) and error.status == 503:
+                return True
+            error = error.__cause__ or error.__context__
+        return False
+
+    def _defer_busy(self, topic: str, normalized_topic: str, value: Any, reason: str) -> Dict[str, Any]:
+        """Queue the value and hold back all sends for a back-off that doubles while the Miniserver stays busy."""
+        base = global_config.miniserver.miniserver_busy_backoff
+        if self.busy_backoff == 0:
+            logger.warning(f"Miniserver busy ({reason}), deferring sends for {base}s")
+            self.busy_backoff = base
+        elif time.monotonic() >= self.busy_until:
+            # Still busy when retrying: back off further
+            backoff = min(self.busy_backoff * 2, global_config.miniserver.miniserver_busy_backoff_max)
+            log = logger.info if backoff != self.busy_backoff else logger.debug
+            self.busy_backoff = backoff
+            log(f"Miniserver still busy ({reason}), retrying in {backoff}s")
+        self.busy_until = time.monotonic() + self.busy_backoff
+        self._report_state("degraded", reason)
+        self._queue_offline(topic, normalized_topic, value)
+        if self._busy_retry is None or self._busy_retry.done():
+            self._busy_retry = asyncio.get_running_loop().create_task(self._retry_deferred())
+        return { 'queued': True }
+
+    def _busy_over(self) -> None:
+        if self.busy_backoff:
+            logger.info("Miniserver accepts requests again")
+            self.busy_backoff = 0.0
+
+    async def _retry_deferred(self) -> None:
+        """Once the back-off expired, send the latest queued value per input; repeats while sends are deferred again."""
+        while self.busy_until:
+            await asyncio.sleep(max(0.0, self.busy_until - time.monotonic()))
+            self.busy_until = 0.0
+            if self.online:
+                await self.flush_offline_queue()
+
+    async def probe_miniserver(self) -> bool:
+        """Cheap reachability check; updates the online state and flushes the queue on recovery."""
+        url = f"{self.http_base_url}/jdev/cfg/api"
+        try:
+            async with self._get_session().get(url, timeout=self.probe_timeout) as resp:
+                reachable = resp.status == 200
+        except (asyncio.TimeoutError, aiohttp.ClientError, OSError) as e:
+            logger.debug(f"Miniserver probe {url} failed: {e}")
+            reachable = False
+
+        if reachable and self.state in ("connecting", "offline"):
+            # Degraded stays until a send succeeds: the probe doesn't tell whether sends work
+            self._report_state("connected")
+        if reachable and not self.online:
+            logger.info(f"Miniserver reachable again, sending {len(self.offline_queue)} queued values")
+            self.online = True
+            await self.flush_offline_queue()
+        elif not reachable and self.online:
+            logger.warning("Miniserver probe failed, queueing values until it is back")
+            self.online = False
+        if not reachable:
+            self._report_state("offline", f"probe {url} failed")
+        return reachable
+
+    async def flush_offline_queue(self) -> None:
+        queued = list(self.offline_queue.items())
+        self.offline_queue.clear()
+        await asyncio.gather(*(
+            self.send_to_miniserver(topic, normalized_topic, value)
+            for normalized_topic, (topic, value) in queued
+        ))
 
-        return 
+    async def run_probe(self) -> None:
+        while True:
+            await asyncio.sleep(global_config.miniserver.miniserver_probe_interval)
+            await self.probe_miniserver()
 
 http_miniserver_handler = HttpMiniserverHandler()
\ No newline at end of file
@@ -1,57 +1,291 @@
 import asyncio
 import types
+from functools import partial
 from typing import Dict, Any, Optional, Literal
 import sys
 import os
+import aiohttp
 import orjson
 import subprocess
 import uvloop
 import typing
 import platform
+import signal
+import logging
 
 from loxmqttrelay.config import ConfigError, ConfigSection, global_config
 from loxmqttrelay.logging_config import get_lazy_logger
 from loxmqttrelay.mqtt_client import mqtt_client
 from loxmqttrelay.udp_handler import start_udp_server
-from loxmqttrelay.miniserver_sync import sync_miniserver_whitelist
+from loxmqttrelay.miniserver_sync import load_structure_file, sync_miniserver_whitelist
 from loxmqttrelay.http_miniserver_handler import http_miniserver_handler
+from loxmqttrelay.ha import HaCoordinator
+from loxmqttrelay.cloud_dns import resolve_cloud_dns
+from loxmqttrelay.discovery import discover_miniserver
+from loxmqttrelay.export import collect_virtual_inputs, to_csv, to_loxone_xml
 import loxmqttrelay.utils as utils
 
 # The imports are now handled by __init__.py
-from loxmqttrelay import MiniserverDataProcessor, init_rust_logger
+from loxmqttrelay import ConfigWatcher, MiniserverDataProcessor, init_rust_logger, set_rust_log_level
 
 TOPIC = types.SimpleNamespace(
-    CONFIG_SET = f"{global_config.general.base_topic}config/set",
-    CONFIG_ADD = f"{global_config.general.base_topic}config/add",
-    CONFIG_REMOVE = f"{global_config.general.base_topic}config/remove",
-    CONFIG_UPDATE = f"{global_config.general.base_topic}config/update",
-    CONFIG_RESTART = f"{global_config.general.base_topic}config/restart",
-    CONFIG_GET = f"{global_config.general.base_topic}config/get",
-    CONFIG_RESPONSE = f"{global_config.general.base_topic}config/response",
-    MINISERVER_STARTUP_EVENT = f"{global_config.general.base_topic}miniserverevent/startup",
-    START_UI = f"{global_config.general.base_topic}startui",
-    STOP_UI = f"{global_config.general.base_topic}stopui",
-    UI_STATUS = f"{global_config.general.base_topic}ui/status"
+    CONFIG_SET = f"{global_config.general.relay_topic}config/set",
+    CONFIG_ADD = f"{global_config.general.relay_topic}config/add",
+    CONFIG_REMOVE = f"{global_config.general.relay_topic}config/remove",
+    CONFIG_UPDATE = f"{global_config.general.relay_topic}config/update",
+    CONFIG_RESTART = f"{global_config.general.relay_topic}config/restart",
+    CONFIG_GET = f"{global_config.general.relay_topic}config/get",
+    CONFIG_RESPONSE = f"{global_config.general.relay_topic}config/response",
+    CONFIG_VALIDATE = f"{global_config.general.relay_topic}config/validate",
+    CONFIG_VALIDATE_RESPONSE = f"{global_config.general.relay_topic}config/validate/response",
+    FILTERS_SET = f"{global_config.general.relay_topic}filters/set",
+    DNF_SET = f"{global_config.general.relay_topic}dnf/set",
+    WHITELIST_ADD = f"{global_config.general.relay_topic}whitelist/add",
+    WHITELIST_REMOVE = f"{global_config.general.relay_topic}whitelist/remove",
+    WHITELIST_GET = f"{global_config.general.relay_topic}whitelist/get",
+    WHITELIST_RESPONSE = f"{global_config.general.relay_topic}whitelist/response",
+    STATS_GET = f"{global_config.general.relay_topic}stats/get",
+    STATS_RESET = f"{global_config.general.relay_topic}stats/reset",
+    STATS_RESPONSE = f"{global_config.general.relay_topic}stats/response",
+    METRICS = f"{global_config.general.relay_topic}stats",
+    DEBUG_SET = f"{global_config.general.relay_topic}debug/set",
+    MINISERVER_STARTUP_EVENT = f"{global_config.general.relay_topic}miniserverevent/startup",
+    START_UI = f"{global_config.general.relay_topic}startui",
+    STOP_UI = f"{global_config.general.relay_topic}stopui",
+    UI_STATUS = f"{global_config.general.relay_topic}ui/status",
+    HA_LOCK = f"{global_config.general.relay_topic}ha/lock",
+    HA_STATE = f"{global_config.general.relay_topic}ha/state",
+    EXPORT_VI = f"{global_config.general.relay_topic}export/virtual_inputs",
+    EXPORT_RESPONSE = f"{global_config.general.relay_topic}export/response",
+    DELIVERY_GET = f"{global_config.general.relay_topic}delivery/get",
+    DELIVERY_RESPONSE = f"{global_config.general.relay_topic}delivery/response",
+    FILTERS_TEST = f"{global_config.general.relay_topic}filters/test",
+    FILTERS_TEST_RESPONSE = f"{global_config.general.relay_topic}filters/test/response",
+    PAUSE = f"{global_config.general.relay_topic}pause",
+    RESUME = f"{global_config.general.relay_topic}resume",
+    STATE = f"{global_config.general.relay_topic}connection/state"
 )
 
 logger = get_lazy_logger(__name__)
 
+# Config fields the processor takes over while running; other edits of the file restart the relay
+LIVE_CONFIG_FIELDS = {
+    "topics.subscription_filters": lambda p, c: p.update_subscription_filters(c.topics.subscription_filters),
+    "topics.post_expansion_filters": lambda p, c: p.update_post_expansion_filters(c.topics.post_expansion_filters),
+    "topics.do_not_forward": lambda p, c: p.update_do_not_forward(c.topics.do_not_forward),
+    "topics.monitor_topics": lambda p, c: p.update_monitor_topics(c.topics.monitor_topics),
+    "topics.topic_whitelist": lambda p, c: p.update_topic_whitelist(c.topics.topic_whitelist),
+    "topics.strip_prefixes": lambda p, c: p.update_strip_prefixes(c.topics.strip_prefixes),
+    "topics.rules": lambda p, c: p.update_rules(c.topics.rules),
+    "topics.rewrites": lambda p, c: p.update_rewrites(c.topics.rewrites),
+    "miniserver.targets": lambda p, c: p.update_targets(c.miniserver.targets, c.miniserver.routes),
+    "miniserver.routes": lambda p, c: p.update_targets(c.miniserver.targets, c.miniserver.routes),
+    "processing.expand_json": lambda p, c: p.reload_config(),
+    "processing.transforms": lambda p, c: p.update_transforms(c.processing.transforms),
+    "processing.derived": lambda p, c: p.update_derived(c.processing.derived),
+    "processing.timestamps": lambda p, c: p.update_timestamps(c.processing.timestamps),
+    "processing.scripts": lambda p, c: p.update_scripts(c.processing.scripts),
+}
+
 # Initialize Rust logger
-init_rust_logger()
+try:
+    init_rust_logger(
+        global_config.general.log_file or None,
+        global_config.general.log_file_max_bytes,
+        global_config.general.log_file_backups,
+        global_config.general.log_sink,
+        global_config.general.log_syslog_address or None,
+    )
+    # Python's logging decides what is shown; without RUST_LOG, Rust logs at its level too
+    if global_config.general.log_sink == "python" and not os.getenv("RUST_LOG"):
+        set_rust_log_level("", logging.getLogger().getEffectiveLevel())
+except OSError as e:
+    logger.error(f"Can't set up Rust logging ({global_config.general.log_sink}, file '{global_config.general.log_file}'), logging to stderr only: {e}")
+    init_rust_logger()
 
 class MQTTRelay:
     def __init__(self):
         self.ui_process: Optional[subprocess.Popen] = None
-        self.miniserver_data_processor = MiniserverDataProcessor(TOPIC, global_config, self, mqtt_client, http_miniserver_handler, orjson)
+        self.background_tasks: list[asyncio.Task] = []
+        self.udp_server: Optional[asyncio.Task] = None
+        self.config_watcher: Optional[ConfigWatcher] = None
+        self.miniserver_data_processor = MiniserverDataProcessor(TOPIC, global_config, self, mqtt_client, http_miniserver_handler)
+        # Both report what they observe; the processor keeps and publishes the states
+        mqtt_client.state_listener = partial(self.miniserver_data_processor.set_connection_state, "broker")
+        http_miniserver_handler.state_listener = partial(self.miniserver_data_processor.set_connection_state, "miniserver")
+        self.warm_up_caches()
+        self.ha: Optional[HaCoordinator] = None
+        if global_config.ha.ha_enabled:
+            self.ha = HaCoordinator(
+                global_config.ha.ha_node_id,
+                TOPIC.HA_LOCK,
+                TOPIC.HA_STATE,
+                global_config.ha.ha_lease_seconds,
+                mqtt_client.publish,
+                self.miniserver_data_processor.set_active,
+            )
+
+    def _start_background_task(self, coro) -> asyncio.Task:
+        task = asyncio.create_task(coro)
+        self.background_tasks.append(task)
+        return task
 
     async def main(self):
         await self.connect_and_subscribe_mqtt()
+        if global_config.miniserver.miniserver_discovery:
+            await self.discover_miniserver()
+            if global_config.miniserver.miniserver_discovery_interval > 0:
+                self._start_background_task(self.run_miniserver_discovery())
+        elif global_config.miniserver.miniserver_serial:
+            await self.resolve_miniserver_via_cloud_dns()
+            if global_config.miniserver.miniserver_cloud_dns_interval > 0:
+                self._start_background_task(self.run_cloud_dns_resolution())
         await self.handle_miniserver_sync()
-        asyncio.create_task(start_udp_server())
+        if global_config.miniserver.miniserver_probe_interval > 0:
+            self._start_background_task(http_miniserver_handler.run_probe())
+        if global_config.miniserver.failover_ip:
+            self._start_background_task(self.miniserver_data_processor.run_health_checks())
+        if global_config.processing.stale_timeout > 0:
+            self._start_background_task(self.miniserver_data_processor.run_watchdog())
+        if global_config.miniserver.use_websocket and global_config.miniserver.websocket_keepalive_interval > 0:
+            self._start_background_task(http_miniserver_handler.run_websocket_keepalive())
+        if self.ha:
+            self._start_background_task(self.ha.run())
+        if global_config.debug.metrics_interval > 0:
+            self._start_background_task(self.run_metrics_publishing())
+        self.udp_server = self._start_background_task(start_udp_server(self.miniserver_data_processor.render_payload))
         await self.start_ui()
 
+        stopped = asyncio.Event()
+        loop = asyncio.get_running_loop()
+        if global_config.general.watch_config and os.path.exists(global_config.config_path):
+            try:
+                self.config_watcher = ConfigWatcher(
+                    global_config.config_path, lambda: loop.call_soon_threadsafe(self.apply_config_file)
+                )
+            except (OSError, ValueError) as e:
+                logger.warning(f"Can't watch the config file, changes need a restart: {e}")
+        signal_handlers = {
+            signal.SIGTERM: stopped.set,
+            signal.SIGINT: stopped.set,
+        }
+        if hasattr(signal, "SIGUSR1"):
+            # e.g. `docker kill -s USR1` before a Loxone Config deployment, USR2 after it
+            signal_handlers[signal.SIGUSR1] = self.miniserver_data_processor.pause
+            signal_handlers[signal.SIGUSR2] = self.miniserver_data_processor.resume
+        for sig, handler in signal_handlers.items():
+            try:
+                loop.add_signal_handler(sig, handler)
+            except NotImplementedError:  # Windows
+                pass
+
         logger.info("MQTT Relay started")
-        await asyncio.Future()
+        try:
+            await stopped.wait()
+            logger.info("Stop requested")
+        finally:
+            await self.shutdown()
+
+    async def shutdown(self):
+        """Stop the background tasks and let the values still being sent reach the Miniserver."""
+        if self.config_watcher is not None:
+            self.config_watcher.stop()
+        if self.udp_server is not None and self.udp_server.done() and not self.udp_server.cancelled() \
+                and self.udp_server.exception() is None:
+            transport, _ = self.udp_server.result()
+            transport.close()
+        for task in self.background_tasks:
+            task.cancel()
+        await asyncio.gather(*self.background_tasks, return_exceptions=True)
+        self.background_tasks.clear()
+        result = await self.miniserver_data_processor.shutdown(global_config.general.shutdown_timeout)
+        logger.info(f"Shutdown: {result['drained']} sends completed, {result['aborted']} aborted")
+        await http_miniserver_handler.close()
+
+    def apply_config_file(self):
+        """Called by the config watcher: take over the edited fields, restarting if one needs it."""
+        try:
+            changed = global_config.reload_from_file()
+        except (OSError, ValueError) as e:
+            logger.error(f"Config file change rejected: {e}")
+            return
+        if not changed:
+            return
+        if not all(name in LIVE_CONFIG_FIELDS for name in changed):
+            logger.info(f"Config file changed ({', '.join(changed)}), restarting")
+            self.restart_relay_incl_ui()
+            return
+        for name in changed:
+            try:
+                LIVE_CONFIG_FIELDS[name](self.miniserver_data_processor, global_config)
+            except ValueError as e:
+                logger.error(f"Error applying {name} from the config file: {e}")
+        logger.info(f"Config file changed, applied {', '.join(changed)}")
+
+    async def discover_miniserver(self) -> bool:
+        """Look up the Miniserver via mDNS and switch to its address if it changed."""
+        ms = global_config.miniserver
+        try:
+            found = await discover_miniserver(ms.miniserver_discovery_service, ms.miniserver_discovery_match)
+        except OSError as e:
+            logger.warning(f"Miniserver discovery failed: {e}")
+            return False
+        current = self.miniserver_data_processor.miniserver_address
+        if found is None:
+            logger.warning(f"No Miniserver found via mDNS, keeping {current[0]}:{current[1]}")
+            return False
+        if (found.host, found.port) == current:
+            return False
+        logger.info(f"Discovered Miniserver '{found.name}' at {found.host}:{found.port}")
+        self._use_miniserver_address(found.host, found.port)
+        return True
+
+    async def resolve_miniserver_via_cloud_dns(self) -> bool:
+        """Look up the Miniserver's public address via Loxone Cloud DNS and switch to it if it changed."""
+        ms = global_config.miniserver
+        try:
+            found = await resolve_cloud_dns(ms.miniserver_serial)
+        except (aiohttp.ClientError, asyncio.TimeoutError, ValueError) as e:
+            logger.warning(f"Loxone Cloud DNS lookup failed: {e}")
+            return False
+        if found is None or found == self.miniserver_data_processor.miniserver_address:
+            return False
+        logger.info(f"Loxone Cloud DNS: Miniserver {ms.miniserver_serial} is at {utils.format_host(found[0])}:{found[1]}")
+        self._use_miniserver_address(*found)
+        return True
+
+    def _use_miniserver_address(self, host: str, port: int) -> None:
+        # Only kept in the processor and the handler, never written to the config: a later
+        # save_config() would persist it, the configured address stays the fallback
+        self.miniserver_data_processor.set_miniserver_address(host, port)
+
+    async def run_miniserver_discovery(self):
+        """Re-run discovery periodically so DHCP address changes are picked up."""
+        while True:
+            await asyncio.sleep(global_config.miniserver.miniserver_discovery_interval)
+            try:
+                await self.discover_miniserver()
+            except Exception as e:
+                logger.error(f"Miniserver discovery failed: {e}")
+
+    async def run_cloud_dns_resolution(self):
+        """Re-resolve periodically so changes of the Miniserver's public IP are picked up."""
+        while True:
+            await asyncio.sleep(global_config.miniserver.miniserver_cloud_dns_interval)
+            try:
+                await self.resolve_miniserver_via_cloud_dns()
+            except Exception as e:
+                logger.error(f"Loxone Cloud DNS lookup failed: {e}")
+
+    async def run_metrics_publishing(self):
+        """Publish the processing metrics periodically, for dashboards without a scraper."""
+        while True:
+            await asyncio.sleep(global_config.debug.metrics_interval)
+            try:
+                metrics = self.miniserver_data_processor.get_metrics()
+                await mqtt_client.publish(TOPIC.METRICS, orjson.dumps(metrics).decode())
+            except Exception as e:
+                logger.error(f"Publishing metrics failed: {e}")
 
     async def handle_miniserver_sync(self):
         """Attempt to sync whitelist with miniserver if enabled"""        
@@ -62,9 +296,19 @@ class MQTTRelay:
         initial_whitelist = global_config.topics.topic_whitelist.copy()
 
         try:
-            inputs = sync_miniserver_whitelist()
-            global_config.update_config(ConfigSection.TOPICS, {'topic_whitelist': inputs})
-            self.miniserver_data_processor.update_topic_whitelist(list(inputs))
+            if global_config.miniserver.sync_source == "structure":
+                ms_ip, ms_port = utils.split_host_port(*self.miniserver_data_processor.miniserver_address)
+                structure = await asyncio.to_thread(
+                    load_structure_file, ms_ip, ms_port,
+                    global_config.miniserver.miniserver_user, global_config.miniserver.miniserver_pass
+                )
+                # Parsed and applied in Rust, the UUIDs kept for get_input_uuids
+                inputs = self.miniserver_data_processor.sync_whitelist_from_structure(structure)
+                global_config.update_config(ConfigSection.TOPICS, {'topic_whitelist': inputs})
+            else:
+                inputs = sync_miniserver_whitelist(self.miniserver_data_processor.miniserver_address)
+                global_config.update_config(ConfigSection.TOPICS, {'topic_whitelist': inputs})
+                self.miniserver_data_processor.update_topic_whitelist(list(inputs), synced=True)
             logger.info("Whitelist updated from miniserver configuration")
         except Exception as e:
             logger.error(f"Failed to sync with miniserver: {str(e)}")
@@ -72,12 +316,84 @@ class MQTTRelay:
             global_config.update_config(ConfigSection.TOPICS, {'topic_whitelist': initial_whitelist})
             self.miniserver_data_processor.update_topic_whitelist(list(initial_whitelist))
     
+    def handle_ha_lock(self, payload: str):
+        """Called from Rust for messages on the HA lock topic."""
+        if self.ha:
+            self.ha.handle_lock_message(payload)
+
+    def export_virtual_inputs(self, fmt: str):
+        """Called from Rust: publish the known virtual inputs as Loxone template XML or CSV."""
+        fmt = fmt.strip().lower() or "xml"
+        if fmt not in ("xml", "csv"):
+            logger.error(f"Unknown export format '{fmt}', expected 'xml' or 'csv'")
+            return
+        inputs = collect_virtual_inputs(
+            global_config.topics.topic_whitelist,
+            self.miniserver_data_processor.get_forwarded_inputs(),
+        )
+        payload = to_csv(inputs) if fmt == "csv" else to_loxone_xml(inputs, udp_port=self._udp_export_port())
+        logger.info(f"Exporting {len(inputs)} virtual inputs as {fmt}")
+        asyncio.create_task(mqtt_client.publish(TOPIC.EXPORT_RESPONSE, payload))
+
+    def _udp_export_port(self) -> Optional[int]:
+        """Port of the Virtual UDP Input to export for when values go out over UDP by default."""
+        destinations = global_config.udp.udp_out_destinations
+        if global_config.miniserver.default_transport != "udp" or not destinations:
+            return None
+        return utils.split_host_port(destinations[0], 0)[1]
+
     # UPDATED: Synchronous wrapper with added logging to help testing
     def schedule_miniserver_sync(self):
         """Schedule the asynchronous handle_miniserver_sync in the event loop."""
         logger.info("Miniserver startup detected, resyncing whitelist")
         asyncio.create_task(self.handle_miniserver_sync())
 
+    def _cache_state_path(self) -> str:
+        return os.path.join(os.path.dirname(global_config.config_path), "cache_state.json")
+
+    def warm_up_caches(self):
+        """Pre-populate the Rust caches from the last-value store, the whitelist and the
+        raw topics persisted by the previous run."""
+        if not global_config.general.cache_warmup:
+            return
+        warmed = self.miniserver_data_processor.warm_up_caches()
+        path = self._cache_state_path()
+        if os.path.exists(path):
+            try:
+                with open(path, "rb") as f:
+                    topics = orjson.loads(f.read())
+                warmed += self.miniserver_data_processor.warm_up_caches(topics)
+            except Exception as e:
+                logger.warning(f"Failed to warm up caches from {path}: {e}")
+        logger.info(f"Warmed up caches with {warmed} topics")
+
+    def persist_cache_state(self):
+        """Persist the raw topics seen so far, so the next start can warm up from them."""
+        if not global_config.general.cache_warmup:
+            return
+        topics = self.miniserver_data_processor.seen_topics()
+        path = self._cache_state_path()
+        if not topics and not os.path.exists(path):
+            return
+        try:
+            with open(path, "wb") as f:
+                f.write(orjson.dumps(topics))
+            logger.debug(f"Persisted cache state to {path}")
+        except Exception as e:
+            logger.warning(f"Failed to persist cache state to {path}: {e}")
+
+    def persist_vi_name_mapping(self):
+        """Write the names shortened to fit the Miniserver's limit, so the UI can show them."""
+        path = os.path.join(os.path.dirname(global_config.config_path), "vi_names.json")
+        mapping = self.miniserver_data_processor.get_vi_name_mapping()
+        if not mapping and not os.path.exists(path):
+            return
+        try:
+            with open(path, "wb") as f:
+                f.write(orjson.dumps(mapping, option=orjson.OPT_SORT_KEYS | orjson.OPT_INDENT_2))
+        except Exception as e:
+            logger.warning(f"Failed to write virtual input name mapping to {path}: {e}")
+
     async def connect_and_subscribe_mqtt(self):
         """Ensure MQTT client is connected with all required subscriptions."""
         # Subscribe to configuration topics and miniserver startup event
@@ -88,10 +404,26 @@ class MQTTRelay:
             TOPIC.CONFIG_UPDATE,
             TOPIC.CONFIG_RESTART,
             TOPIC.CONFIG_GET,
+            TOPIC.CONFIG_VALIDATE,
+            TOPIC.FILTERS_SET,
+            TOPIC.DNF_SET,
+            TOPIC.WHITELIST_ADD,
+            TOPIC.WHITELIST_REMOVE,
+            TOPIC.WHITELIST_GET,
+            TOPIC.STATS_GET,
+            TOPIC.STATS_RESET,
+            TOPIC.DEBUG_SET,
             TOPIC.MINISERVER_STARTUP_EVENT,
             TOPIC.START_UI,
-            TOPIC.STOP_UI
+            TOPIC.STOP_UI,
+            TOPIC.EXPORT_VI,
+            TOPIC.DELIVERY_GET,
+            TOPIC.FILTERS_TEST,
+            TOPIC.PAUSE,
+            TOPIC.RESUME
         ]
+        if self.ha:
+            all_topics.append(TOPIC.HA_LOCK)
         
         try:
             # Connect with all required subscriptions
@@ -148,6 +480,8 @@ class MQTTRelay:
             await mqtt_client.publish(TOPIC.UI_STATUS, "UI is not running")
 
     def restart_relay_incl_ui(self):
+        self.persist_cache_state()
+        self.persist_vi_name_mapping()
         if self.ui_process:
             self.ui_process.terminate()
         os.execv(sys.executable, [sys.executable] + sys.argv)
@@ -163,6 +497,8 @@ def main():
     except KeyboardInterrupt:
         pass
     finally:
+        relay.persist_cache_state()
+        relay.persist_vi_name_mapping()
         logger.info("MQTT Relay exited")
 
 if __name__ == "__main__":
@@ -1,12 +1,14 @@
 from lxml import etree
-from typing import List
+from typing import List, Optional, Tuple
 import ftplib
+import urllib.request
 import struct
 import zipfile
 import zlib
 from io import BytesIO
 from .config import global_config
 from .logging_config import get_lazy_logger
+from .utils import split_host_port
 import re
 
 # LZ4 Import - wird als verfügbar angenommen
@@ -157,10 +159,31 @@ def extract_inputs(config_xml: bytes) -> List[str]:
         logger.error(f"Error extracting inputs from configuration: {str(e)}")
         raise
 
-def sync_miniserver_whitelist() -> List[str]:
+def load_structure_file(ip: str, port: int, username: str, password: str) -> bytes:
+    """
+    Load the structure file LoxAPP3.json from the Miniserver over HTTP.
+
+    Args:
+        ip: Miniserver IP address
+        port: Miniserver HTTP port
+        username: Miniserver user
+        password: Miniserver password
+    """
+    host = f"[{ip}]" if ':' in ip else ip
+    url = f"http://{host}:{port}/data/LoxAPP3.json"
+    logger.debug(f"Loading structure file from {url} with username {username}")
+    passwords = urllib.request.HTTPPasswordMgrWithDefaultRealm()
+    passwords.add_password(None, url, username, password)
+    opener = urllib.request.build_opener(urllib.request.HTTPBasicAuthHandler(passwords))
+    with opener.open(url, timeout=30) as response:
+        return response.read()
+
+
+def sync_miniserver_whitelist(address: Optional[Tuple[str, int]] = None) -> List[str]:
     """
     Sync the whitelist with the miniserver configuration.
-    Uses Config singleton to access configuration values.
+    Uses Config singleton to access configuration values; `address` is the (ip, port) of a
+    discovered Miniserver that overrides the configured one.
     Returns the list of extracted inputs.
     """
     try:
@@ -168,7 +191,7 @@ def sync_miniserver_whitelist() -> List[str]:
             return []
 
         # Extract IP from miniserver_ip (which might include port)
-        ms_ip = global_config.miniserver.miniserver_ip.split(':')[0]
+        ms_ip, _ = split_host_port(*(address or (global_config.miniserver.miniserver_ip, global_config.miniserver.miniserver_port)))
         
         # Load the configuration from miniserver
         config_xml = load_miniserver_config(
@@ -1,7 +1,7 @@
 import asyncio
 import time
-from typing import List, Callable, Awaitable
-from gmqtt import Client
+from typing import List, Callable, Awaitable, Optional
+from gmqtt import Client, Message
 from gmqtt import constants as MQTTconstants
 from gmqtt.mqtt.constants import PubAckReasonCode
 from .config import global_config
@@ -17,12 +17,16 @@ class MQTTClient:
     """
     def __init__(self):
         unique_id = f"loxberry_{int(time.time())}"
-        self.client = Client(client_id=unique_id, logger=logger)
-        self.base_topic = global_config.general.base_topic
+        self.base_topic = global_config.general.relay_topic
+        # Published by the broker when the connection drops without a disconnect
+        will = Message(f"{self.base_topic}status", global_config.broker.will_message, retain=global_config.broker.status_retain)
+        self.client = Client(client_id=unique_id, will_message=will, logger=logger)
         self._callback: Callable[[str, str], Awaitable[None]]
         self._max_reconnect_delay = 15 
         self._reconnect_attempt = 0
         self._conn = asyncio.Event()
+        # Called with (state, error) on connectivity changes, see MiniserverDataProcessor.set_connection_state
+        self.state_listener: Optional[Callable[..., object]] = None
         self.client.on_connect = self._on_connect
         self.client.on_disconnect = self._on_disconnect
         self.client.on_message = self._on_message
@@ -43,6 +47,7 @@ class MQTTClient:
         self._conn.clear()
         
         while True:
+            self._report_state("connecting")
             try:
                 logger.info(f"Attempting MQTT connection to {global_config.broker.host}:{global_config.broker.port}")
                 await self.client.connect(
@@ -55,6 +60,7 @@ class MQTTClient:
                     
             except Exception as e:
                 logger.error(f"Failed to connect to MQTT broker: {e}")
+                self._report_state("offline", str(e))
                 logger.warning(f"Retrying connection in {self._max_reconnect_delay} seconds...")
                 await asyncio.sleep(self._max_reconnect_delay)
 
@@ -63,7 +69,7 @@ class MQTTClient:
         if self.client:
             try:
                 if self.client.is_connected:
-                    self.client.publish(f"{self.base_topic}status", "Disconnecting")
+                    self.client.publish(f"{self.base_topic}status", "Disconnecting", retain=global_config.broker.status_retain)
             except Exception:
                 logger.warning("Failed to publish disconnect status", exc_info=True)
             finally:
@@ -94,8 +100,8 @@ class MQTTClient:
             raise
     
     def _on_connect(self, session_present, result, properties, userdata):
-        # Publish connection status
-        self.client.publish(f"{self.base_topic}status", "Connected")
+        # Publish connection status (the birth message)
+        self.client.publish(f"{self.base_topic}status", global_config.broker.birth_message, retain=global_config.broker.status_retain)
         logger.info(f"Connected to MQTT Server {global_config.broker.host}:{global_config.broker.port}")
         logger.info("MQTT connected")
         # Wait for connection to be established
@@ -104,11 +110,47 @@ class MQTTClient:
         for topic in self._topics:
             self.client.subscribe(topic)
         self._conn.set()
+        self._report_state("connected")
     
     def _on_disconnect(self,client, packet, exc=None):
         logger.info("MQTT disconnected")
         if exc:
             logger.error(f"Disconnect error: {exc}")
         self._conn.clear()
+        # gmqtt reconnects on its own
+        self._report_state("connecting", str(exc) if exc else None)
 
-mqtt_client = MQTTClient()
\ No newline at end of file
+    def _report_state(self, state: str, error: Optional[str] = None) -> None:
+        if self.state_listener is None:
+            return
+        try:
+            self.state_listener(state, error)
+        except Exception as e:
+            logger.error(f"Error reporting MQTT connection state: {e}")
+
+def _create_client():
+    """The gmqtt client, or the Rust one with broker.native_client."""
+    if not global_config.broker.native_client:
+        if global_config.broker.tls:
+            logger.warning("broker.tls needs broker.native_client, connecting without TLS")
+        return MQTTClient()
+    from loxmqttrelay import RelayMqttClient
+    logger.info("Using the native MQTT client")
+    return RelayMqttClient(
+        global_config.broker.host,
+        global_config.broker.port,
+        client_id=f"loxberry_{int(time.time())}",
+        username=global_config.broker.user or None,
+        password=global_config.broker.password or None,
+        status_topic=f"{global_config.general.relay_topic}status",
+        birth_message=global_config.broker.birth_message,
+        will_message=global_config.broker.will_message,
+        status_retain=global_config.broker.status_retain,
+        tls=global_config.broker.tls,
+        ca_file=global_config.broker.tls_ca_file,
+        cert_file=global_config.broker.tls_cert_file,
+        key_file=global_config.broker.tls_key_file,
+        tls_insecure=global_config.broker.tls_insecure,
+    )
+
+mqtt_client = _create_client()
\ No newline at end of file
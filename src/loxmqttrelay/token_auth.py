@@ -0,0 +1,120 @@
+import hashlib
+import hmac
+import json
+import time
+import uuid
+from typing import Any, Dict, Optional
+from urllib.parse import quote
+
+import aiohttp
+
+from loxmqttrelay.logging_config import get_lazy_logger
+
+logger = get_lazy_logger(__name__)
+
+# Loxone timestamps count seconds from 2009-01-01 00:00:00 UTC
+LOXONE_EPOCH = 1230768000
+# Permission 4: long lived "app" token (weeks) instead of a "web" token (days)
+TOKEN_PERMISSION = 4
+
+
+class TokenAuthError(Exception):
+    pass
+
+
+def hash_credentials(user: str, password: str, key: str, salt: str, hash_alg: str = "SHA1") -> str:
+    """
+    Credential hash for getjwt: HMAC(key, "user:" + HASH("password:salt").upper()),
+    with `key` as returned hex-encoded by getkey2.
+    """
+    digest = hashlib.sha256 if hash_alg.upper() == "SHA256" else hashlib.sha1
+    pw_hash = digest(f"{password}:{salt}".encode()).hexdigest().upper()
+    return hmac.new(bytes.fromhex(key), f"{user}:{pw_hash}".encode(), digest).hexdigest()
+
+
+def _value(payload: Dict[str, Any], command: str) -> Dict[str, Any]:
+    ll = payload.get("LL", {})
+    if str(ll.get("Code", ll.get("code", ""))) != "200":
+        raise TokenAuthError(f"{command} failed with code {ll.get('Code', ll.get('code'))}")
+    value = ll.get("value")
+    # Some firmware versions return the value as JSON string
+    return json.loads(value) if isinstance(value, str) else value
+
+
+class TokenAuth:
+    """
+    Token (JWT) authentication for the Miniserver's HTTP API.
+
+    The token is acquired once with the user's credentials (getkey2 + getjwt), cached and
+    refreshed before it expires, and sent as `autht`/`user` query parameters, so the
+    Miniserver doesn't verify the password on every request.
+    """
+
+    def __init__(self, user: str, password: str, client_id: str, refresh_margin: float = 0.1):
+        self.user = user
+        self.password = password
+        self.client_uuid = str(uuid.uuid5(uuid.NAMESPACE_DNS, f"loxmqttrelay.{client_id}"))
+        self.client_info = quote(client_id or "loxmqttrelay")
+        # Refresh once less than this share of the token's lifetime is left
+        self.refresh_margin = refresh_margin
+        self.token: Optional[str] = None
+        self.key: Optional[str] = None
+        self.hash_alg = "SHA1"
+        self.issued_at = 0.0
+        self.valid_until = 0.0
+
+    async def _get(self, session: aiohttp.ClientSession, base_url: str, command: str) -> Dict[str, Any]:
+        async with session.get(f"{base_url}/jdev/sys/{command}") as resp:
+            if resp.status != 200:
+                raise TokenAuthError(f"{command.split('/')[0]} failed with HTTP {resp.status}")
+            return _value(await resp.json(content_type=None), command.split('/')[0])
+
+    def _store(self, value: Dict[str, Any]) -> None:
+        self.token = value.get("token", self.token)
+        self.key = value.get("key", self.key)
+        self.issued_at = time.time()
+        self.valid_until = LOXONE_EPOCH + float(value["validUntil"])
+        if value.get("unsecurePass"):
+            logger.warning("Miniserver reports an unsecure password for the relay user")
+
+    async def acquire(self, session: aiohttp.ClientSession, base_url: str) -> str:
+        user = quote(self.user)
+        key_info = await self._get(session, base_url, f"getkey2/{user}")
+        self.hash_alg = key_info.get("hashAlg", "SHA1")
+        credentials = hash_credentials(self.user, self.password, key_info["key"], key_info["salt"], self.hash_alg)
+        value = await self._get(
+            session, base_url, f"getjwt/{credentials}/{user}/{TOKEN_PERMISSION}/{self.client_uuid}/{self.client_info}"
+        )
+        self._store(value)
+        logger.info(f"Acquired Miniserver token for {self.user}, valid until {time.ctime(self.valid_until)}")
+        return self.token
+
+    async def refresh(self, session: aiohttp.ClientSession, base_url: str) -> str:
+        digest = hashlib.sha256 if self.hash_alg.upper() == "SHA256" else hashlib.sha1
+        token_hash = hmac.new(bytes.fromhex(self.key), self.token.encode(), digest).hexdigest()
+        self._store(await self._get(session, base_url, f"refreshjwt/{token_hash}/{quote(self.user)}"))
+        logger.debug(f"Refreshed Miniserver token, valid until {time.ctime(self.valid_until)}")
+        return self.token
+
+    def _needs_refresh(self) -> bool:
+        now = time.time()
+        return self.valid_until - now < (self.valid_until - self.issued_at) * self.refresh_margin
+
+    async def get_token(self, session: aiohttp.ClientSession, base_url: str) -> str:
+        """The cached token, refreshed or re-acquired as needed."""
+        if self.token is None or time.time() >= self.valid_until:
+            return await self.acquire(session, base_url)
+        if self._needs_refresh():
+            try:
+                return await self.refresh(session, base_url)
+            except (TokenAuthError, aiohttp.ClientError, KeyError) as e:
+                logger.warning(f"Refreshing Miniserver token failed ({e}), acquiring a new one")
+                return await self.acquire(session, base_url)
+        return self.token
+
+    def invalidate(self) -> None:
+        """Drop the cached token, e.g. after the Miniserver rejected it."""
+        self.token = None
+
+    def query(self) -> str:
+        return f"autht={quote(self.token or '')}&user={quote(self.user)}"
@@ -1,7 +1,8 @@
 import asyncio
-from typing import Tuple, Optional
+from typing import Callable, Tuple, Optional
 from loxmqttrelay.config import global_config
 from loxmqttrelay.logging_config import get_lazy_logger
+from loxmqttrelay.utils import format_host
 from loxmqttrelay.mqtt_client import mqtt_client
 
 logger = get_lazy_logger(__name__)
@@ -116,10 +117,11 @@ def parse_udp_message(udpmsg: str) -> Optional[Tuple[str, str, str]]:
     return (command, topic_str, payload_str)
 
 
-async def handle_udp_message(udpmsg: str, addr) -> None:
+async def handle_udp_message(udpmsg: str, addr, render_payload: Optional[Callable[[str, str, str], Optional[str]]] = None) -> None:
     """
     Handle an incoming UDP message:
       - parse
+      - render the configured "mqtt" payload template, if any
       - publish to MQTT with or without retain flag
     """
     logger.info(f"UDP IN: {addr}: {udpmsg}")
@@ -128,6 +130,14 @@ async def handle_udp_message(udpmsg: str, addr) -> None:
         return
 
     command, topic, message = result
+    if render_payload is not None:
+        try:
+            rendered = render_payload("mqtt", topic, message)
+        except ValueError as e:
+            logger.error(f"Failed to render MQTT payload for '{topic}': {e}")
+            return
+        if rendered is not None:
+            message = rendered
     if command == 'publish':
         logger.debug(f"Publishing: '{topic}'='{message}'")
         await mqtt_client.publish(topic, message, False)
@@ -139,17 +149,20 @@ async def handle_udp_message(udpmsg: str, addr) -> None:
 
 
 class UDPProtocol(asyncio.DatagramProtocol):
+    def __init__(self, render_payload: Optional[Callable[[str, str, str], Optional[str]]] = None):
+        self.render_payload = render_payload
+
     def datagram_received(self, data, addr):
         msg = data.decode('utf-8', errors='ignore')
-        asyncio.create_task(handle_udp_message(msg, addr))
+        asyncio.create_task(handle_udp_message(msg, addr, self.render_payload))
 
 
-async def start_udp_server():
+async def start_udp_server(render_payload: Optional[Callable[[str, str, str], Optional[str]]] = None):
     udpport = global_config.udp.udp_in_port
     loop = asyncio.get_running_loop()
     transport, protocol = await loop.create_datagram_endpoint(
-        lambda: UDPProtocol(),
-        local_addr=("0.0.0.0", udpport)
+        lambda: UDPProtocol(render_payload),
+        local_addr=(global_config.udp.udp_in_host, udpport)
     )
-    logger.info(f"UDP-IN listening on port {udpport}")
+    logger.info(f"UDP-IN listening on {format_host(global_config.udp.udp_in_host)}:{udpport}")
     return transport, protocol
\ No newline at end of file
@@ -6,6 +6,7 @@ from pathlib import Path
 import os
 import sys
 import asyncio
+import json
 import logging
 import time
 from gmqtt import Client as MQTTClient
@@ -36,19 +37,25 @@ initial_values = {
         'port': 1883,
         'user': '',
         'password': '',
-        'client_id': 'loxmqttrelay'
+        'client_id': 'loxmqttrelay',
+        'native_client': False
     },
     'general': {
         'base_topic': 'myrelay/',
+        'instance_id': '',
         'log_level': 'INFO',
-        'cache_size': 100000
+        'cache_size': 100000,
+        'homeassistant_discovery': False
     },
     'udp': {
-        'udp_in_port': 11884
+        'udp_in_port': 11884,
+        'udp_out_destinations': []
     },
     'processing': {
         'expand_json': False,
-        'convert_booleans': False
+        'convert_booleans': False,
+        'forward_only_changes': False,
+        'decompress': False
     },
     'miniserver': {
         'miniserver_ip': '127.0.0.1',
@@ -57,11 +64,21 @@ initial_values = {
         'miniserver_pass': '',
         'miniserver_max_parallel_connections': 5,
         'use_websocket': True,
-        'sync_with_miniserver': False
+        'native_http': False,
+        'native_websocket': False,
+        'sync_with_miniserver': False,
+        'dead_letter': False,
+        'outbox': False,
+        'publish_states': False,
+        'publish_unmapped_states': False,
+        'retain_states': True
     },
     'debug': {
         'mock_ip': '',
-        'enable_mock': False
+        'enable_mock': False,
+        'publish_processed_topics': False,
+        'publish_forwarded_topics': False,
+        'stage_timing': False
     },
     'topics': {
         'subscriptions': [],
@@ -262,19 +279,25 @@ def save_config(save_path: Path):
             'port': broker_port,
             'user': st.session_state.broker_user,
             'password': st.session_state.broker_pass,
-            'client_id': st.session_state.broker_client_id
+            'client_id': st.session_state.broker_client_id,
+            'native_client': st.session_state.broker_native_client
         },
         'general': {
             'base_topic': base_topic,
+            'instance_id': st.session_state.instance_id.strip().strip('/'),
             'log_level': st.session_state.log_level,
-            'cache_size': st.session_state.cache_size
+            'cache_size': st.session_state.cache_size,
+            'homeassistant_discovery': st.session_state.homeassistant_discovery
         },
         'udp': {
-            'udp_in_port': st.session_state.udp_in_port
+            'udp_in_port': st.session_state.udp_in_port,
+            'udp_out_destinations': [line.strip() for line in st.session_state.udp_out_destinations.splitlines() if line.strip()]
         },
         'processing': {
             'expand_json': st.session_state.expand_json,
-            'convert_booleans': st.session_state.convert_booleans
+            'convert_booleans': st.session_state.convert_booleans,
+            'forward_only_changes': st.session_state.forward_only_changes,
+            'decompress': st.session_state.decompress
         },
         'miniserver': {
             'miniserver_ip': miniserver_ip,
@@ -283,11 +306,21 @@ def save_config(save_path: Path):
             'miniserver_pass': st.session_state.miniserver_pass,
             'miniserver_max_parallel_connections': st.session_state.miniserver_max_parallel_connections,
             'use_websocket': st.session_state.use_websocket,
-            'sync_with_miniserver': st.session_state.sync_with_miniserver
+            'native_http': st.session_state.native_http,
+            'native_websocket': st.session_state.native_websocket,
+            'sync_with_miniserver': st.session_state.sync_with_miniserver,
+            'dead_letter': st.session_state.dead_letter,
+            'outbox': st.session_state.outbox,
+            'publish_states': st.session_state.publish_states,
+            'publish_unmapped_states': st.session_state.publish_unmapped_states,
+            'retain_states': st.session_state.retain_states
         },
         'debug': {
             'mock_ip': mock_miniserver_ip,
-            'enable_mock': st.session_state.enable_mock_miniserver
+            'enable_mock': st.session_state.enable_mock_miniserver,
+            'publish_processed_topics': st.session_state.publish_processed_topics,
+            'publish_forwarded_topics': st.session_state.publish_forwarded_topics,
+            'stage_timing': st.session_state.stage_timing
         },
         'topics': {
             'subscriptions': [line.strip() for line in st.session_state.subscriptions.splitlines() if line.strip()],
@@ -314,6 +347,31 @@ def save_config(save_path: Path):
         st.session_state.save_status = e
         return None
 
+def relay_topic(config):
+    """The relay's control topic prefix, including the instance namespace"""
+    base_topic = config.get('general', {}).get('base_topic', 'myrelay/')
+    if base_topic and not base_topic.endswith('/'):
+        base_topic += '/'
+    instance_id = str(config.get('general', {}).get('instance_id', '')).strip('/')
+    if instance_id:
+        base_topic = f"{base_topic}{instance_id}/"
+    return base_topic
+
+async def send_rust_log_level(config, module, level):
+    """Change the Rust log level of a module in the running relay via debug/set"""
+    broker = config.get('broker', {})
+    if not broker.get('host') or not broker.get('port'):
+        raise ValueError("Invalid broker configuration: Missing host or port")
+    client = MQTTClient(f'loxberry_ui_{int(time.time())}')
+    if broker.get('user'):
+        client.set_auth_credentials(broker.get('user'), broker.get('password', ''))
+    await client.connect(broker.get('host'), port=broker.get('port'), version=MQTTConstants.MQTTv311)
+    payload = json.dumps({"rust_log_levels": {module: level}}).encode()
+    client.publish(f"{relay_topic(config)}debug/set", payload, qos=1, retain=False)
+    await asyncio.sleep(1)  # Give time for message to be sent
+    await client.disconnect()
+    logger.info(f"Rust log level of '{module}' set to {level}")
+
 async def restart_relay(config, max_retries=3):
     """Send restart command to relay via MQTT with retries"""
     try:
@@ -325,7 +383,7 @@ async def restart_relay(config, max_retries=3):
         broker_pass = broker.get('password', '')
         broker_host = broker.get('host')
         broker_port = broker.get('port')
-        base_topic = config.get('general', {}).get('base_topic', 'myrelay/')
+        base_topic = relay_topic(config)
 
         if not broker_host or not broker_port:
             raise ValueError("Invalid broker configuration: Missing host or port")
@@ -389,6 +447,20 @@ if st.sidebar.button("Restart Relay"):
     else:
         st.sidebar.error("No configuration loaded")
 
+# Rust log levels of the running relay, not saved to the config
+with st.sidebar.expander("Rust Log Level"):
+    rust_log_module = st.text_input("Module", value="", key='rust_log_module', help="e.g. loxmqttrelay::mqtt_client, empty for all")
+    rust_log_level = st.selectbox("Level", options=['TRACE', 'DEBUG', 'INFO', 'WARNING', 'ERROR', 'OFF'], index=2, key='rust_log_level')
+    if st.button("Apply Log Level"):
+        if st.session_state.config_data:
+            try:
+                asyncio.run(send_rust_log_level(st.session_state.config_data, rust_log_module, rust_log_level))
+                st.success("Log level sent")
+            except Exception as e:
+                st.error(f"Failed to set the log level: {str(e)}")
+        else:
+            st.error("No configuration loaded")
+
 # Main content
 st.title("MQTT Relay Configuration")
 
@@ -427,6 +499,7 @@ with st.form("config_form"):
     broker_user = st.text_input("Broker User", value=broker.get('user', ''), key='broker_user')
     broker_pass = st.text_input("Broker Password", value=broker.get('password', ''), key='broker_pass')
     broker_client_id = st.text_input("Client ID", value=broker.get('client_id', 'loxmqttrelay'), key='broker_client_id')
+    st.checkbox("Use Native MQTT Client", value=broker.get('native_client', False), key='broker_native_client')
 
     st.subheader("General Settings")
     general = config_data.get('general', {})
@@ -437,16 +510,24 @@ with st.form("config_form"):
         index=1  # Default to INFO
     )
     base_topic = st.text_input("Relay Topic Base", value=general.get('base_topic', 'myrelay/'), key='base_topic')
+    instance_id = st.text_input("Instance ID", value=general.get('instance_id', ''), key='instance_id', help="Namespace below the topic base when several relays share one broker")
     cache_size = st.number_input("Cache Size", value=general.get('cache_size', 100000), min_value=1000, max_value=1000000, key='cache_size')
+    st.checkbox("Home Assistant Discovery", value=general.get('homeassistant_discovery', False), key='homeassistant_discovery')
 
     st.subheader("UDP Settings")
     udp = config_data.get('udp', {})
     udp_in_port = st.number_input("UDP In Port", value=udp.get('udp_in_port', 11884), min_value=1, max_value=65535, key='udp_in_port')
+    st.text_area("UDP Out Destinations (host:port, one per line)",
+                 value='\n'.join(udp.get('udp_out_destinations', [])),
+                 key='udp_out_destinations',
+                 help="Virtual UDP Inputs for rules with transport = \"udp\"")
 
     st.subheader("Processing Options")
     processing = config_data.get('processing', {})
     expand_json = st.checkbox("Expand JSON Messages", value=processing.get('expand_json', False), key='expand_json')
     convert_booleans = st.checkbox("Convert booleans", value=processing.get('convert_booleans', False), key='convert_booleans')
+    st.checkbox("Forward only changed values", value=processing.get('forward_only_changes', False), key='forward_only_changes')
+    st.checkbox("Decompress zlib/gzip payloads", value=processing.get('decompress', False), key='decompress')
 
     st.subheader("Miniserver Settings")
     miniserver = config_data.get('miniserver', {})
@@ -459,12 +540,22 @@ with st.form("config_form"):
                                                       min_value=1, max_value=100,
                                                       key='miniserver_max_parallel_connections')
     use_websocket = st.checkbox("Use WebSocket", value=miniserver.get('use_websocket', True), key='use_websocket')
+    st.checkbox("Native HTTP Sender", value=miniserver.get('native_http', False), key='native_http')
+    st.checkbox("Native WebSocket Client", value=miniserver.get('native_websocket', False), key='native_websocket')
     sync_with_miniserver = st.checkbox("Sync with Miniserver", value=miniserver.get('sync_with_miniserver', False), key='sync_with_miniserver')
+    st.checkbox("Publish Failed Sends as Dead Letters", value=miniserver.get('dead_letter', False), key='dead_letter')
+    st.checkbox("Buffer Failed Sends on Disk", value=miniserver.get('outbox', False), key='outbox')
+    st.checkbox("Publish Loxone States to MQTT", value=miniserver.get('publish_states', False), key='publish_states')
+    st.checkbox("Publish Unmapped States", value=miniserver.get('publish_unmapped_states', False), key='publish_unmapped_states')
+    st.checkbox("Retain Published States", value=miniserver.get('retain_states', True), key='retain_states')
 
     st.subheader("Debug Settings")
     debug = config_data.get('debug', {})
     mock_miniserver_ip = st.text_input("Mock Miniserver IP/Port", value=debug.get('mock_ip', ''), key='mock_miniserver_ip')
     enable_mock_miniserver = st.checkbox("Enable Mock Miniserver", value=debug.get('enable_mock', False), key='enable_mock_miniserver')
+    st.checkbox("Publish Processed Topics", value=debug.get('publish_processed_topics', False), key='publish_processed_topics')
+    st.checkbox("Publish Forwarded Topics", value=debug.get('publish_forwarded_topics', False), key='publish_forwarded_topics')
+    st.checkbox("Record Stage Timing", value=debug.get('stage_timing', False), key='stage_timing')
 
     st.subheader("Topics")
     topics = config_data.get('topics', {})
@@ -487,6 +578,21 @@ with st.form("config_form"):
                                      value='\n'.join(topics.get('topic_whitelist', [])),
                                      key='topic_whitelist')
 
+    # Names the relay had to shorten to fit the Miniserver's limit (written by the relay on restart/shutdown)
+    vi_names_path = st.session_state.config_path.parent / "vi_names.json" if st.session_state.config_path else None
+    if vi_names_path and vi_names_path.exists():
+        try:
+            vi_names = json.loads(vi_names_path.read_text())
+        except (OSError, ValueError) as e:
+            vi_names = {}
+            logger.warning(f"Failed to read {vi_names_path}: {e}")
+        if vi_names:
+            st.subheader("Shortened Virtual Input Names")
+            st.dataframe(
+                [{"Topic name": full, "Virtual input": short} for full, short in sorted(vi_names.items())],
+                use_container_width=True
+            )
+
     # Check if config path is set
     config_path_set = st.session_state.config_path is not None
     
@@ -1,10 +1,11 @@
 import argparse
 import functools
+import ipaddress
 import logging
 import os
 import time
 import sys
-from typing import Callable, TypeVar, ParamSpec, Optional
+from typing import Callable, TypeVar, ParamSpec, Optional, Tuple
 from loxmqttrelay.config import global_config
 from loxmqttrelay.logging_config import get_lazy_logger, set_log_level
 
@@ -43,6 +44,41 @@ def log_performance(name: Optional[str] = None, severity: Optional[int] = loggin
     return decorator
 
 
+def split_host_port(address: str, default_port: int) -> Tuple[str, int]:
+    """
+    Split "host", "host:port", "[v6]:port" or a bare IPv6 literal into host and port.
+    IPv6 hosts are returned without brackets.
+    """
+    address = address.strip()
+    if address.startswith('['):
+        host, _, rest = address[1:].partition(']')
+        port = rest[1:] if rest.startswith(':') else ''
+        return host, int(port) if port else default_port
+    if address.count(':') > 1:
+        # Unbracketed IPv6 literal, a port can't be told apart from the last group
+        return address, default_port
+    host, _, port = address.partition(':')
+    return host, int(port) if port else default_port
+
+
+def format_host(host: str) -> str:
+    """Host as it appears in a URL or "host:port", i.e. IPv6 literals in brackets."""
+    try:
+        if ipaddress.ip_address(host.split('%')[0]).version == 6:
+            return f"[{host}]"
+    except ValueError:
+        pass
+    return host
+
+
+def build_base_url(scheme: str, host: str, port: int) -> str:
+    """scheme://host[:port] leaving out the scheme's default port."""
+    default_port = {"http": 80, "https": 443, "ws": 80, "wss": 443}.get(scheme)
+    if port == default_port:
+        return f"{scheme}://{format_host(host)}"
+    return f"{scheme}://{format_host(host)}:{port}"
+
+
 _parser = argparse.ArgumentParser(description="MQTT Relay")
 _args = None
 
@@ -0,0 +1,144 @@
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use lru::LruCache;
+use serde_json::{json, Map, Value};
+
+use crate::lookup_cache::LookupCache;
+
+/// What happened to a message on a topic, for the per-topic counters.
+#[derive(Clone, Copy, Debug)]
+pub enum TopicEvent {
+    Received,
+    /// A value of the message (or the whole message) was dropped by a filter or rule
+    Filtered,
+    /// A value of the message was handed to the sender
+    Forwarded,
+}
+
+const TOPIC_EVENTS: [(TopicEvent, &str); 3] =
+    [(TopicEvent::Received, "received"), (TopicEvent::Filtered, "filtered"), (TopicEvent::Forwarded, "forwarded")];
+
+/// A lookup cache of the processor.
+#[derive(Clone, Copy, Debug)]
+pub enum Cache {
+    NormalizeTopic,
+    ConvertBoolean,
+    /// Which filter of the fixed pipeline drops a topic, if any
+    FilterDecision,
+}
+
+const CACHES: [(Cache, &str); 3] = [
+    (Cache::NormalizeTopic, "normalize_topic"),
+    (Cache::ConvertBoolean, "convert_boolean"),
+    (Cache::FilterDecision, "filter_decision"),
+];
+
+/// Counters per original MQTT topic, cache hit rates and the sends in flight, for
+/// `get_metrics` and the Prometheus exporter. Topics are
+/// kept in an LRU of `general.cache_size` entries, so the least recently seen ones drop
+/// out on relays with more topics than that.
+pub struct Metrics {
+    topics: Mutex<LruCache<String, [u64; TOPIC_EVENTS.len()]>>,
+    /// Hits, misses and evictions per cache
+    caches: [[AtomicU64; 3]; CACHES.len()],
+    /// Sends started and not finished yet, including those held by the rate limiter
+    in_flight: AtomicU64,
+}
+
+/// A send in flight, counted until dropped.
+pub struct InFlight(Arc<Metrics>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn new(max_topics: NonZeroUsize) -> Self {
+        Metrics { topics: Mutex::new(LruCache::new(max_topics)), caches: Default::default(), in_flight: AtomicU64::new(0) }
+    }
+
+    pub fn topic(&self, topic: &str, event: TopicEvent) {
+        let mut topics = self.topics.lock().unwrap();
+        if let Some(counts) = topics.get_mut(topic) {
+            counts[event as usize] += 1;
+        } else {
+            let mut counts = [0; TOPIC_EVENTS.len()];
+            counts[event as usize] = 1;
+            topics.put(topic.to_string(), counts);
+        }
+    }
+
+    pub fn cache(&self, cache: Cache, hit: bool) {
+        self.caches[cache as usize][usize::from(!hit)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Add `key` to `lru` after a miss, counting the entry it pushes out if the cache is full.
+    pub fn cache_put(&self, cache: Cache, lru: &LookupCache, key: String, value: String) {
+        if lru.put(key, value) {
+            self.caches[cache as usize][2].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Hits, misses and evictions so far per cache, by name.
+    pub fn cache_lookups(&self) -> Vec<(&'static str, u64, u64, u64)> {
+        CACHES
+            .iter()
+            .map(|&(cache, name)| {
+                let [hits, misses, evictions] = &self.caches[cache as usize];
+                (name, hits.load(Ordering::Relaxed), misses.load(Ordering::Relaxed), evictions.load(Ordering::Relaxed))
+            })
+            .collect()
+    }
+
+    pub fn send_started(self: &Arc<Self>) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(Arc::clone(self))
+    }
+
+    pub fn in_flight(&self) -> u64 {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Topics with counters.
+    pub fn topic_count(&self) -> usize {
+        self.topics.lock().unwrap().len()
+    }
+
+    /// `{topics: {topic: {received, filtered, forwarded}}, caches: {cache: {hits, misses,
+    /// evictions, hit_rate}}}`. With `reset` the counters are cleared as they are read.
+    pub fn snapshot(&self, reset: bool) -> Map<String, Value> {
+        let topics: Map<String, Value> = {
+            let mut topics = self.topics.lock().unwrap();
+            let out = topics
+                .iter()
+                .map(|(topic, counts)| {
+                    let counts = TOPIC_EVENTS.iter().map(|&(event, name)| (name.to_string(), json!(counts[event as usize])));
+                    (topic.clone(), Value::Object(counts.collect()))
+                })
+                .collect();
+            if reset {
+                topics.clear();
+            }
+            out
+        };
+        let read = |slot: &AtomicU64| if reset { slot.swap(0, Ordering::Relaxed) } else { slot.load(Ordering::Relaxed) };
+        let caches = CACHES
+            .iter()
+            .map(|&(cache, name)| {
+                let [hits, misses, evictions] = &self.caches[cache as usize];
+                let (hits, misses, evictions) = (read(hits), read(misses), read(evictions));
+                let lookups = hits + misses;
+                let hit_rate = if lookups > 0 { hits as f64 / lookups as f64 } else { 0.0 };
+                (name.to_string(), json!({"hits": hits, "misses": misses, "evictions": evictions, "hit_rate": hit_rate}))
+            })
+            .collect();
+        let mut out = Map::new();
+        out.insert("topics".to_string(), Value::Object(topics));
+        out.insert("caches".to_string(), Value::Object(caches));
+        out
+    }
+}
@@ -0,0 +1,506 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{debug, error, info, warn};
+use pyo3::buffer::PyBuffer;
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::intern;
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyString};
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+use tokio_rustls::rustls::ClientConfig;
+
+use crate::mqtt_packet::{self, ConnectOptions, Packet, Will};
+use crate::send_queue::SendQueue;
+use crate::tls::{self, TlsOptions};
+use crate::MiniserverDataProcessor;
+
+/// How long the TCP connect (with the TLS handshake) and the CONNACK may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Upper bound of the reconnect backoff, like the Python client's `reconnect_delay`.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(15);
+/// Incoming packets above this size close the connection.
+const MAX_PACKET_SIZE: usize = 16 * 1024 * 1024;
+/// How long `disconnect` waits for the DISCONNECT to go out.
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+enum Command {
+    Send(Vec<u8>),
+    Subscribe(Vec<String>),
+    Disconnect(oneshot::Sender<()>),
+}
+
+/// Where received messages go.
+enum Handler {
+    /// Straight into the processor, without a Python call per message
+    Processor(Py<MiniserverDataProcessor>),
+    /// Any callable, called with (topic, payload)
+    Callback(Py<PyAny>),
+}
+
+impl Handler {
+    fn from_callback(callback: &Bound<'_, PyAny>) -> Self {
+        if let Ok(processor) = callback.cast::<MiniserverDataProcessor>() {
+            return Handler::Processor(processor.clone().unbind());
+        }
+        // main.py passes the bound `handle_mqtt_message`, which is the processor as well
+        let py = callback.py();
+        let bound_processor = callback
+            .getattr(intern!(py, "__self__"))
+            .ok()
+            .filter(|_| {
+                callback
+                    .getattr(intern!(py, "__name__"))
+                    .is_ok_and(|name| name.eq("handle_mqtt_message").unwrap_or(false))
+            })
+            .and_then(|owner| owner.cast_into::<MiniserverDataProcessor>().ok());
+        match bound_processor {
+            Some(processor) => Handler::Processor(processor.unbind()),
+            None => Handler::Callback(callback.clone().unbind()),
+        }
+    }
+
+    /// The processor's send queue, for backpressure with `send_queue_overflow = "block"`.
+    fn send_queue(&self) -> Option<Arc<SendQueue>> {
+        match self {
+            Handler::Processor(processor) => Python::attach(|py| Some(Arc::clone(&processor.bind(py).borrow().send_queue))),
+            Handler::Callback(_) => None,
+        }
+    }
+
+    fn deliver(&self, topic: &[u8], payload: &[u8]) {
+        Python::attach(|py| {
+            // The processor handles broken topic encodings itself, so they stay bytes
+            let topic = match std::str::from_utf8(topic) {
+                Ok(topic) => PyString::new(py, topic).into_any(),
+                Err(_) => PyBytes::new(py, topic).into_any(),
+            };
+            let payload = PyBytes::new(py, payload);
+            let result = match self {
+                Handler::Processor(processor) => processor.bind(py).borrow().handle_mqtt_message(py, &topic, payload.as_any()),
+                Handler::Callback(callback) => callback.bind(py).call1((topic, payload)).map(drop),
+            };
+            if let Err(e) = result {
+                error!("Error processing MQTT message: {:?}", e);
+            }
+        });
+    }
+}
+
+struct Inner {
+    host: String,
+    port: u16,
+    /// MQTT over TLS when set
+    tls: Option<Arc<ClientConfig>>,
+    options: ConnectOptions,
+    status_topic: Option<String>,
+    /// Published to `status_topic` after each connect
+    birth_message: String,
+    /// Whether the status messages and the last will are retained
+    status_retain: bool,
+    subscribe_qos: u8,
+    connected: AtomicBool,
+    topics: Mutex<Vec<String>>,
+    commands: Mutex<Option<mpsc::UnboundedSender<Command>>>,
+    state_listener: Mutex<Option<Py<PyAny>>>,
+    next_packet_id: AtomicU16,
+    /// QoS 1 publishes the broker hasn't acknowledged yet, by packet id; published again
+    /// after a reconnect
+    unacked: Mutex<BTreeMap<u16, Vec<u8>>>,
+}
+
+/// Write `packet` and flush it, so it doesn't wait in the TLS stream's buffer.
+async fn write_packet(stream: &mut tls::Stream, packet: &[u8]) -> io::Result<()> {
+    stream.write_all(packet).await?;
+    stream.flush().await
+}
+
+impl Inner {
+    fn packet_id(&self) -> u16 {
+        loop {
+            let id = self.next_packet_id.fetch_add(1, Ordering::Relaxed);
+            if id != 0 {
+                return id;
+            }
+        }
+    }
+
+    fn send(&self, command: Command) -> bool {
+        self.commands.lock().unwrap().as_ref().is_some_and(|tx| tx.send(command).is_ok())
+    }
+
+    /// Report a connectivity change, see `MiniserverDataProcessor.set_connection_state`.
+    fn report_state(&self, state: &str, error: Option<String>) {
+        Python::attach(|py| {
+            let listener = self.state_listener.lock().unwrap().as_ref().map(|l| l.clone_ref(py));
+            if let Some(listener) = listener {
+                if let Err(e) = listener.bind(py).call1((state, error)) {
+                    error!("Error reporting MQTT connection state: {:?}", e);
+                }
+            }
+        });
+    }
+
+    async fn run(self: Arc<Self>, handler: Handler, mut commands: mpsc::UnboundedReceiver<Command>, first: oneshot::Sender<()>) {
+        let mut first = Some(first);
+        let mut delay = Duration::from_secs(1);
+        loop {
+            self.report_state("connecting", None);
+            info!("Attempting MQTT connection to {}:{}", self.host, self.port);
+            match self.session(&handler, &mut commands, &mut first, &mut delay).await {
+                Ok(()) => break,
+                Err(e) => {
+                    self.connected.store(false, Ordering::Relaxed);
+                    error!("MQTT connection to {}:{} failed: {}", self.host, self.port, e);
+                    self.report_state("offline", Some(e.to_string()));
+                }
+            }
+            warn!("Retrying MQTT connection in {}s", delay.as_secs());
+            let wait = tokio::time::sleep(delay);
+            tokio::pin!(wait);
+            // Publishes while offline are dropped, like with the Python client
+            loop {
+                tokio::select! {
+                    _ = &mut wait => break,
+                    command = commands.recv() => match command {
+                        Some(Command::Disconnect(done)) => {
+                            let _ = done.send(());
+                            return;
+                        }
+                        Some(_) => {}
+                        None => return,
+                    },
+                }
+            }
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+        self.connected.store(false, Ordering::Relaxed);
+    }
+
+    /// One connection, from CONNECT until the broker goes away (an error) or
+    /// `disconnect` is called (`Ok`).
+    async fn session(
+        &self,
+        handler: &Handler,
+        commands: &mut mpsc::UnboundedReceiver<Command>,
+        first: &mut Option<oneshot::Sender<()>>,
+        delay: &mut Duration,
+    ) -> io::Result<()> {
+        let timed_out = |what: &str| io::Error::new(io::ErrorKind::TimedOut, format!("{} timed out", what));
+        let mut stream = tokio::time::timeout(CONNECT_TIMEOUT, tls::connect(&self.host, self.port, self.tls.as_ref()))
+            .await
+            .map_err(|_| timed_out("connect"))??;
+        write_packet(&mut stream, &mqtt_packet::connect(&self.options)?).await?;
+
+        let mut buf = Vec::with_capacity(8192);
+        let connack = tokio::time::timeout(CONNECT_TIMEOUT, async {
+            loop {
+                if let Some((packet, used)) = mqtt_packet::decode(&buf, MAX_PACKET_SIZE)? {
+                    buf.drain(..used);
+                    return Ok::<_, io::Error>(packet);
+                }
+                if stream.read_buf(&mut buf).await? == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed before CONNACK"));
+                }
+            }
+        })
+        .await
+        .map_err(|_| timed_out("CONNACK"))??;
+        match connack {
+            Some(Packet::ConnAck { code: 0, .. }) => {}
+            Some(Packet::ConnAck { code, .. }) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    format!("broker refused the connection: {}", mqtt_packet::connack_reason(code)),
+                ))
+            }
+            other => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("expected CONNACK, got {:?}", other))),
+        }
+
+        self.connected.store(true, Ordering::Relaxed);
+        *delay = Duration::from_secs(1);
+        info!("Connected to MQTT Server {}:{}", self.host, self.port);
+        if let Some(status_topic) = &self.status_topic {
+            write_packet(&mut stream, &mqtt_packet::publish(status_topic, self.birth_message.as_bytes(), 0, self.status_retain, None)?).await?;
+        }
+        let topics = self.topics.lock().unwrap().clone();
+        if !topics.is_empty() {
+            info!("Subscribing {:?}", topics);
+            write_packet(&mut stream, &mqtt_packet::subscribe(self.packet_id(), &topics, self.subscribe_qos)?).await?;
+        }
+        // The session is clean, so the broker has no state for these and they go out as new
+        // publishes without DUP. One the broker got before the connection broke is delivered
+        // twice: at least once, not exactly once.
+        let unacked: Vec<Vec<u8>> = self.unacked.lock().unwrap().values().cloned().collect();
+        if !unacked.is_empty() {
+            info!("Republishing {} unacknowledged QoS 1 publishes", unacked.len());
+            for packet in unacked {
+                write_packet(&mut stream, &packet).await?;
+            }
+        }
+        self.report_state("connected", None);
+        if let Some(first) = first.take() {
+            let _ = first.send(());
+        }
+
+        let keepalive = Duration::from_secs(u64::from(self.options.keepalive.max(1)));
+        let mut ping = tokio::time::interval_at(tokio::time::Instant::now() + keepalive, keepalive);
+        let mut awaiting_pong = false;
+        let send_queue = handler.send_queue();
+        loop {
+            // While the send queue is full, leave new messages with the broker (and in
+            // the socket buffer), but keep handling commands and sending pings
+            let blocked = send_queue.as_ref().is_some_and(|queue| queue.blocks());
+            tokio::select! {
+                _ = async { send_queue.as_ref().unwrap().room().await }, if blocked => {}
+                read = stream.read_buf(&mut buf), if !blocked => {
+                    if read? == 0 {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by the broker"));
+                    }
+                    while let Some((packet, used)) = mqtt_packet::decode(&buf, MAX_PACKET_SIZE)? {
+                        let reply = match packet {
+                            Some(Packet::Publish { topic, payload, qos, packet_id, .. }) => {
+                                handler.deliver(&topic, &payload);
+                                match (qos, packet_id) {
+                                    (1, Some(id)) => Some(mqtt_packet::puback(id)),
+                                    (2, Some(id)) => Some(mqtt_packet::pubrec(id)),
+                                    _ => None,
+                                }
+                            }
+                            Some(Packet::PubAck(id)) => {
+                                self.unacked.lock().unwrap().remove(&id);
+                                None
+                            }
+                            Some(Packet::PubRel(id)) => Some(mqtt_packet::pubcomp(id)),
+                            Some(Packet::PubRec(id)) => Some(mqtt_packet::pubrel(id)),
+                            Some(Packet::SubAck { codes, .. }) => {
+                                if codes.contains(&0x80) {
+                                    warn!("The broker rejected some subscriptions: {:?}", codes);
+                                }
+                                None
+                            }
+                            Some(Packet::PingResp) => {
+                                awaiting_pong = false;
+                                None
+                            }
+                            Some(other) => {
+                                debug!("MQTT packet {:?}", other);
+                                None
+                            }
+                            None => None,
+                        };
+                        buf.drain(..used);
+                        if let Some(reply) = reply {
+                            write_packet(&mut stream, &reply).await?;
+                        }
+                    }
+                }
+                command = commands.recv() => match command {
+                    Some(Command::Send(packet)) => write_packet(&mut stream, &packet).await?,
+                    Some(Command::Subscribe(topics)) => {
+                        write_packet(&mut stream, &mqtt_packet::subscribe(self.packet_id(), &topics, self.subscribe_qos)?).await?
+                    }
+                    Some(Command::Disconnect(done)) => {
+                        self.connected.store(false, Ordering::Relaxed);
+                        if let Some(status_topic) = &self.status_topic {
+                            write_packet(&mut stream, &mqtt_packet::publish(status_topic, b"Disconnecting", 0, self.status_retain, None)?).await?;
+                        }
+                        write_packet(&mut stream, &mqtt_packet::disconnect()).await?;
+                        let _ = stream.shutdown().await;
+                        info!("MQTT disconnected");
+                        let _ = done.send(());
+                        return Ok(());
+                    }
+                    None => return Ok(()),
+                },
+                _ = ping.tick() => {
+                    if awaiting_pong && !blocked {
+                        return Err(timed_out("keepalive ping"));
+                    }
+                    write_packet(&mut stream, &mqtt_packet::pingreq()).await?;
+                    // The PINGRESP can't be read while blocked: the ping only keeps the
+                    // broker from dropping the connection, the next one checks it again
+                    awaiting_pong = !blocked;
+                }
+            }
+        }
+    }
+}
+
+/// MQTT 3.1.1 client running on the Rust runtime, a replacement for the Python
+/// `mqtt_client` (`broker.native_client`). Messages go from the socket straight into
+/// the processor; only control-plane calls (publish, state changes) involve Python.
+#[pyclass]
+pub struct RelayMqttClient {
+    inner: Arc<Inner>,
+}
+
+#[pymethods]
+impl RelayMqttClient {
+    /// `status_topic` gets `birth_message` after each connect and "Disconnecting" on
+    /// `disconnect`, and is the last will (`will_message`) if the connection drops; all
+    /// three are retained with `status_retain`.
+    /// With `tls` the connection is MQTTS, checked against `ca_file` (default: the webpki
+    /// roots) unless `tls_insecure`; `cert_file` and `key_file` add a client certificate.
+    #[new]
+    #[pyo3(signature = (host, port=1883, client_id="loxmqttrelay", username=None, password=None, keepalive=60, status_topic=None, birth_message="Connected", will_message="Connection lost", status_retain=false, subscribe_qos=0, tls=false, ca_file="", cert_file="", key_file="", tls_insecure=false))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        host: String,
+        port: u16,
+        client_id: &str,
+        username: Option<String>,
+        password: Option<String>,
+        keepalive: u16,
+        status_topic: Option<String>,
+        birth_message: &str,
+        will_message: &str,
+        status_retain: bool,
+        subscribe_qos: u8,
+        tls: bool,
+        ca_file: &str,
+        cert_file: &str,
+        key_file: &str,
+        tls_insecure: bool,
+    ) -> PyResult<Self> {
+        if subscribe_qos > 2 {
+            return Err(PyValueError::new_err(format!("Invalid subscribe_qos {}: expected 0, 1 or 2", subscribe_qos)));
+        }
+        let tls = match tls {
+            true => Some(
+                TlsOptions {
+                    ca_file: ca_file.to_string(),
+                    cert_file: cert_file.to_string(),
+                    key_file: key_file.to_string(),
+                    insecure: tls_insecure,
+                }
+                .client_config()?,
+            ),
+            false => None,
+        };
+        let will = status_topic.as_ref().map(|topic| Will {
+            topic: topic.clone(),
+            payload: will_message.as_bytes().to_vec(),
+            retain: status_retain,
+        });
+        Ok(RelayMqttClient {
+            inner: Arc::new(Inner {
+                host,
+                port,
+                tls,
+                options: ConnectOptions {
+                    client_id: client_id.to_string(),
+                    keepalive,
+                    clean_session: true,
+                    username,
+                    password,
+                    will,
+                },
+                status_topic,
+                birth_message: birth_message.to_string(),
+                status_retain,
+                subscribe_qos,
+                connected: AtomicBool::new(false),
+                topics: Mutex::new(Vec::new()),
+                commands: Mutex::new(None),
+                state_listener: Mutex::new(None),
+                next_packet_id: AtomicU16::new(1),
+                unacked: Mutex::new(BTreeMap::new()),
+            }),
+        })
+    }
+
+    #[getter]
+    fn is_connected(&self) -> bool {
+        self.inner.connected.load(Ordering::Relaxed)
+    }
+
+    /// Called with (state, error) on connectivity changes.
+    #[getter]
+    fn get_state_listener(&self, py: Python<'_>) -> Option<Py<PyAny>> {
+        self.inner.state_listener.lock().unwrap().as_ref().map(|l| l.clone_ref(py))
+    }
+
+    #[setter]
+    fn set_state_listener(&self, listener: Option<Py<PyAny>>) {
+        *self.inner.state_listener.lock().unwrap() = listener;
+    }
+
+    /// Connect, subscribe `topics` and deliver messages to `callback`. A processor (or its
+    /// `handle_mqtt_message`) is called directly in Rust, anything else with (topic,
+    /// payload). Awaitable, resolving once connected; reconnects on its own afterwards.
+    fn connect<'py>(&self, py: Python<'py>, topics: Vec<String>, callback: &Bound<'py, PyAny>) -> PyResult<Bound<'py, PyAny>> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        {
+            let mut commands = self.inner.commands.lock().unwrap();
+            if commands.as_ref().is_some_and(|tx| !tx.is_closed()) {
+                return Err(PyRuntimeError::new_err("The MQTT client is already connected"));
+            }
+            *commands = Some(tx);
+        }
+        *self.inner.topics.lock().unwrap() = topics;
+        let handler = Handler::from_callback(callback);
+        let (first_tx, first_rx) = oneshot::channel();
+        // Messages are processed inside this scope, so the processor's coroutines run
+        // on the caller's event loop
+        let locals = pyo3_async_runtimes::tokio::get_current_locals(py)?;
+        let inner = self.inner.clone();
+        pyo3_async_runtimes::tokio::get_runtime().spawn(pyo3_async_runtimes::tokio::scope(locals, inner.run(handler, rx, first_tx)));
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            first_rx.await.map_err(|_| PyRuntimeError::new_err("The MQTT client was disconnected before it connected"))
+        })
+    }
+
+    /// Subscribe to more topics, now and after every reconnect.
+    fn subscribe(&self, topics: Vec<String>) {
+        self.inner.topics.lock().unwrap().extend(topics.iter().cloned());
+        if self.is_connected() {
+            self.inner.send(Command::Subscribe(topics));
+        }
+    }
+
+    /// Publish `message` (str or bytes). Awaitable like the Python client's `publish`;
+    /// without a connection the message is dropped with a warning. QoS 1 publishes are
+    /// kept until the broker's PUBACK and published again after a reconnect (at least once).
+    #[pyo3(signature = (topic, message, retain=false, qos=0))]
+    fn publish<'py>(&self, py: Python<'py>, topic: &str, message: &Bound<'py, PyAny>, retain: bool, qos: u8) -> PyResult<Bound<'py, PyAny>> {
+        if qos > 1 {
+            return Err(PyValueError::new_err(format!("Invalid qos {}: expected 0 or 1", qos)));
+        }
+        if !self.is_connected() {
+            warn!("MQTT publish attempted without connection");
+        } else {
+            let payload: Vec<u8> = if let Ok(text) = message.cast::<PyString>() {
+                text.to_str()?.as_bytes().to_vec()
+            } else if let Ok(bytes) = message.cast::<PyBytes>() {
+                bytes.as_bytes().to_vec()
+            } else {
+                PyBuffer::<u8>::get(message)?.to_vec(py)?
+            };
+            let packet_id = (qos > 0).then(|| self.inner.packet_id());
+            let packet = mqtt_packet::publish(topic, &payload, qos, retain, packet_id)?;
+            if let Some(id) = packet_id {
+                self.inner.unacked.lock().unwrap().insert(id, packet.clone());
+            }
+            self.inner.send(Command::Send(packet));
+            debug!("Published: {} = {} bytes (retain={})", topic, payload.len(), retain);
+        }
+        pyo3_async_runtimes::tokio::future_into_py(py, async { Ok(()) })
+    }
+
+    /// Send DISCONNECT and stop reconnecting. Awaitable.
+    fn disconnect<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let (done_tx, done_rx) = oneshot::channel();
+        let sent = self.inner.send(Command::Disconnect(done_tx));
+        self.inner.commands.lock().unwrap().take();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            if sent && tokio::time::timeout(DISCONNECT_TIMEOUT, done_rx).await.is_err() {
+                warn!("MQTT disconnect timed out");
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,323 @@
+//! The subset of MQTT 3.1.1 the embedded client needs: encoding the packets it sends and
+//! decoding the ones a broker sends back.
+
+use std::io;
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const PUBREC: u8 = 5;
+const PUBREL: u8 = 6;
+const PUBCOMP: u8 = 7;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+/// Largest remaining length MQTT can express (four length bytes).
+const MAX_REMAINING_LENGTH: usize = 268_435_455;
+
+/// Last will, published by the broker when the connection drops without a DISCONNECT.
+#[derive(Clone, Debug)]
+pub struct Will {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub retain: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ConnectOptions {
+    pub client_id: String,
+    pub keepalive: u16,
+    pub clean_session: bool,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub will: Option<Will>,
+}
+
+/// A packet received from the broker.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Packet {
+    ConnAck { session_present: bool, code: u8 },
+    Publish { topic: Vec<u8>, payload: Vec<u8>, qos: u8, retain: bool, packet_id: Option<u16> },
+    PubAck(u16),
+    PubRec(u16),
+    PubRel(u16),
+    PubComp(u16),
+    SubAck { packet_id: u16, codes: Vec<u8> },
+    PingResp,
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// Readable reason for a refused CONNECT.
+pub fn connack_reason(code: u8) -> &'static str {
+    match code {
+        1 => "unacceptable protocol version",
+        2 => "client identifier rejected",
+        3 => "server unavailable",
+        4 => "bad user name or password",
+        5 => "not authorized",
+        _ => "unknown return code",
+    }
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_bytes(out: &mut Vec<u8>, value: &[u8]) -> io::Result<()> {
+    let len = u16::try_from(value.len()).map_err(|_| invalid("string longer than 65535 bytes"))?;
+    put_u16(out, len);
+    out.extend_from_slice(value);
+    Ok(())
+}
+
+/// Fixed header plus `body`.
+fn packet(first_byte: u8, body: Vec<u8>) -> io::Result<Vec<u8>> {
+    if body.len() > MAX_REMAINING_LENGTH {
+        return Err(invalid("packet too large"));
+    }
+    let mut out = Vec::with_capacity(body.len() + 5);
+    out.push(first_byte);
+    let mut len = body.len();
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+    out.extend_from_slice(&body);
+    Ok(out)
+}
+
+pub fn connect(options: &ConnectOptions) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    put_bytes(&mut body, b"MQTT")?;
+    body.push(4);
+    let mut flags = 0u8;
+    if options.username.is_some() {
+        flags |= 0x80;
+    }
+    if options.password.is_some() {
+        flags |= 0x40;
+    }
+    if let Some(will) = &options.will {
+        flags |= 0x04;
+        if will.retain {
+            flags |= 0x20;
+        }
+    }
+    if options.clean_session {
+        flags |= 0x02;
+    }
+    body.push(flags);
+    put_u16(&mut body, options.keepalive);
+    put_bytes(&mut body, options.client_id.as_bytes())?;
+    if let Some(will) = &options.will {
+        put_bytes(&mut body, will.topic.as_bytes())?;
+        put_bytes(&mut body, &will.payload)?;
+    }
+    if let Some(username) = &options.username {
+        put_bytes(&mut body, username.as_bytes())?;
+    }
+    if let Some(password) = &options.password {
+        put_bytes(&mut body, password.as_bytes())?;
+    }
+    packet(CONNECT << 4, body)
+}
+
+pub fn publish(topic: &str, payload: &[u8], qos: u8, retain: bool, packet_id: Option<u16>) -> io::Result<Vec<u8>> {
+    let mut body = Vec::with_capacity(topic.len() + payload.len() + 4);
+    put_bytes(&mut body, topic.as_bytes())?;
+    if qos > 0 {
+        put_u16(&mut body, packet_id.ok_or_else(|| invalid("QoS 1 and 2 need a packet id"))?);
+    }
+    body.extend_from_slice(payload);
+    packet(PUBLISH << 4 | qos << 1 | u8::from(retain), body)
+}
+
+pub fn subscribe(packet_id: u16, topics: &[String], qos: u8) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    put_u16(&mut body, packet_id);
+    for topic in topics {
+        put_bytes(&mut body, topic.as_bytes())?;
+        body.push(qos);
+    }
+    packet(SUBSCRIBE << 4 | 0x02, body)
+}
+
+fn with_packet_id(first_byte: u8, packet_id: u16) -> Vec<u8> {
+    let [high, low] = packet_id.to_be_bytes();
+    vec![first_byte, 2, high, low]
+}
+
+pub fn puback(packet_id: u16) -> Vec<u8> {
+    with_packet_id(PUBACK << 4, packet_id)
+}
+
+pub fn pubrec(packet_id: u16) -> Vec<u8> {
+    with_packet_id(PUBREC << 4, packet_id)
+}
+
+pub fn pubrel(packet_id: u16) -> Vec<u8> {
+    with_packet_id(PUBREL << 4 | 0x02, packet_id)
+}
+
+pub fn pubcomp(packet_id: u16) -> Vec<u8> {
+    with_packet_id(PUBCOMP << 4, packet_id)
+}
+
+pub fn pingreq() -> Vec<u8> {
+    vec![PINGREQ << 4, 0]
+}
+
+pub fn disconnect() -> Vec<u8> {
+    vec![DISCONNECT << 4, 0]
+}
+
+fn read_u16(body: &[u8], at: usize) -> io::Result<u16> {
+    body.get(at..at + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or_else(|| invalid("packet too short"))
+}
+
+/// Decode the first packet in `buf`. `Ok(None)` while it is incomplete, otherwise the
+/// packet (or `None` for types the client ignores) and the number of bytes it used.
+/// Packets larger than `max_size` are an error, the connection can't skip them safely.
+pub fn decode(buf: &[u8], max_size: usize) -> io::Result<Option<(Option<Packet>, usize)>> {
+    let Some(&first_byte) = buf.first() else { return Ok(None) };
+    let mut len = 0usize;
+    let mut header_len = 1;
+    loop {
+        let Some(&byte) = buf.get(header_len) else { return Ok(None) };
+        len += usize::from(byte & 0x7f) << (7 * (header_len - 1));
+        header_len += 1;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        if header_len > 4 {
+            return Err(invalid("malformed remaining length"));
+        }
+    }
+    if len > max_size {
+        return Err(invalid(format!("{} byte packet exceeds the {} byte limit", len, max_size)));
+    }
+    let Some(body) = buf.get(header_len..header_len + len) else { return Ok(None) };
+    let packet = match first_byte >> 4 {
+        CONNACK => Some(Packet::ConnAck {
+            session_present: body.first().is_some_and(|flags| flags & 0x01 != 0),
+            code: *body.get(1).ok_or_else(|| invalid("CONNACK too short"))?,
+        }),
+        PUBLISH => {
+            let qos = (first_byte >> 1) & 0x03;
+            if qos == 3 {
+                return Err(invalid("PUBLISH with QoS 3"));
+            }
+            let topic_len = usize::from(read_u16(body, 0)?);
+            let topic = body.get(2..2 + topic_len).ok_or_else(|| invalid("PUBLISH topic truncated"))?.to_vec();
+            let mut at = 2 + topic_len;
+            let packet_id = if qos > 0 {
+                at += 2;
+                Some(read_u16(body, at - 2)?)
+            } else {
+                None
+            };
+            Some(Packet::Publish { topic, payload: body[at..].to_vec(), qos, retain: first_byte & 0x01 != 0, packet_id })
+        }
+        PUBACK => Some(Packet::PubAck(read_u16(body, 0)?)),
+        PUBREC => Some(Packet::PubRec(read_u16(body, 0)?)),
+        PUBREL => Some(Packet::PubRel(read_u16(body, 0)?)),
+        PUBCOMP => Some(Packet::PubComp(read_u16(body, 0)?)),
+        SUBACK => Some(Packet::SubAck { packet_id: read_u16(body, 0)?, codes: body[2..].to_vec() }),
+        PINGRESP => Some(Packet::PingResp),
+        _ => None,
+    };
+    Ok(Some((packet, header_len + len)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn publish_round_trip() {
+        let packet = publish("a/b", b"42", 1, true, Some(7)).unwrap();
+        let (decoded, used) = decode(&packet, 1024).unwrap().unwrap();
+        assert_eq!(used, packet.len());
+        assert_eq!(
+            decoded,
+            Some(Packet::Publish { topic: b"a/b".to_vec(), payload: b"42".to_vec(), qos: 1, retain: true, packet_id: Some(7) })
+        );
+        assert!(publish("a/b", b"42", 1, false, None).is_err());
+    }
+
+    #[test]
+    fn truncated_packets_are_incomplete() {
+        let packet = publish("topic", b"payload", 1, false, Some(3)).unwrap();
+        for len in 0..packet.len() {
+            assert!(decode(&packet[..len], 1024).unwrap().is_none(), "{} bytes", len);
+        }
+        // Multi-byte remaining length cut off after the first length byte
+        assert!(decode(&[PUBLISH << 4, 0x80], 1024).unwrap().is_none());
+    }
+
+    #[test]
+    fn trailing_bytes_are_left_for_the_next_packet() {
+        let mut buf = puback(5);
+        buf.extend_from_slice(&pingreq());
+        let (packet, used) = decode(&buf, 1024).unwrap().unwrap();
+        assert_eq!(packet, Some(Packet::PubAck(5)));
+        assert_eq!(used, 4);
+    }
+
+    #[test]
+    fn malformed_remaining_length() {
+        assert!(decode(&[PUBLISH << 4, 0xff, 0xff, 0xff, 0xff, 0x01], usize::MAX).is_err());
+        // Four length bytes is the maximum MQTT allows
+        assert!(decode(&[PUBLISH << 4, 0xff, 0xff, 0xff, 0x7f], usize::MAX).unwrap().is_none());
+    }
+
+    #[test]
+    fn oversize_packets() {
+        let packet = publish("topic", &[0; 200], 0, false, None).unwrap();
+        assert!(decode(&packet, 100).is_err());
+        // Rejected from the header alone, before the body arrives
+        assert!(decode(&packet[..3], 100).is_err());
+        assert!(decode(&packet, packet.len()).unwrap().is_some());
+    }
+
+    #[test]
+    fn malformed_bodies() {
+        // PUBACK without a packet id
+        assert!(decode(&[PUBACK << 4, 0], 1024).is_err());
+        // CONNACK without a return code
+        assert!(decode(&[CONNACK << 4, 1, 0], 1024).is_err());
+        // Topic length beyond the packet
+        assert!(decode(&[PUBLISH << 4, 3, 0, 5, b'a'], 1024).is_err());
+        // QoS 1 PUBLISH without a packet id
+        assert!(decode(&[PUBLISH << 4 | 0x02, 3, 0, 1, b'a'], 1024).is_err());
+        assert!(decode(&[PUBLISH << 4 | 0x06, 3, 0, 1, b'a'], 1024).is_err());
+    }
+
+    #[test]
+    fn decodes_broker_packets() {
+        assert_eq!(decode(&[CONNACK << 4, 2, 1, 0], 1024).unwrap(), Some((Some(Packet::ConnAck { session_present: true, code: 0 }), 4)));
+        assert_eq!(
+            decode(&[SUBACK << 4, 4, 0, 9, 1, 0x80], 1024).unwrap(),
+            Some((Some(Packet::SubAck { packet_id: 9, codes: vec![1, 0x80] }), 6))
+        );
+        assert_eq!(decode(&[PINGRESP << 4, 0], 1024).unwrap(), Some((Some(Packet::PingResp), 2)));
+        // Types the client doesn't handle are skipped
+        assert_eq!(decode(&[SUBSCRIBE << 4 | 0x02, 1, 0], 1024).unwrap(), Some((None, 3)));
+    }
+}
@@ -0,0 +1,36 @@
+/// The number at the start of `value` with its unit dropped and a decimal comma turned
+/// into a dot (`processing.numeric_topics`): `21.5 °C` -> `21.5`, `3,14` -> `3.14`,
+/// `1.234,5 kWh` -> `1234.5`. `None` if `value` doesn't start with a number.
+///
+/// A lone comma is taken as the decimal separator, so `1,234` becomes `1.234`. With
+/// both a comma and a dot, whichever comes last is the decimal separator and the other
+/// one separates thousands.
+pub fn extract_number(value: &str) -> Option<String> {
+    let value = value.trim();
+    let sign_len = usize::from(value.starts_with(['-', '+']));
+    let digits_len = value[sign_len..]
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == ','))
+        .unwrap_or(value.len() - sign_len);
+    let (sign, digits) = (&value[..sign_len], &value[sign_len..sign_len + digits_len]);
+    let digits = digits.trim_end_matches(['.', ',']);
+    if !digits.starts_with(|c: char| c.is_ascii_digit() || c == '.' || c == ',') || !digits.contains(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    let decimal = match (digits.rfind('.'), digits.rfind(',')) {
+        (Some(dot), Some(comma)) => Some(dot.max(comma)),
+        (None, Some(comma)) if digits.matches(',').count() == 1 => Some(comma),
+        (Some(dot), None) if digits.matches('.').count() == 1 => Some(dot),
+        // Several of the same separator: they separate thousands
+        _ => None,
+    };
+    let mut number = String::with_capacity(sign.len() + digits.len());
+    number.push_str(sign.trim_start_matches('+'));
+    for (i, c) in digits.char_indices() {
+        match c {
+            '.' | ',' if Some(i) == decimal => number.push('.'),
+            '.' | ',' => {}
+            _ => number.push(c),
+        }
+    }
+    number.parse::<f64>().ok().map(|_| number)
+}
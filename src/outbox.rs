@@ -0,0 +1,244 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Which buffered values go out once the Miniserver is back (`miniserver.outbox_replay`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Replay {
+    /// Every value, oldest first, e.g. for meter readings
+    #[default]
+    Ordered,
+    /// Only the newest value per virtual input
+    Latest,
+}
+
+impl Replay {
+    pub fn parse(value: &str) -> PyResult<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "" | "ordered" => Ok(Replay::Ordered),
+            "latest" => Ok(Replay::Latest),
+            other => Err(PyValueError::new_err(format!(
+                "Invalid outbox_replay '{}': expected 'ordered' or 'latest'",
+                other
+            ))),
+        }
+    }
+}
+
+/// A value that failed to send, as one line of the log.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub name: String,
+    pub topic: String,
+    pub value: String,
+    /// When it was buffered, in Unix seconds
+    pub at: f64,
+}
+
+fn now() -> f64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or_default()
+}
+
+struct Log {
+    entries: VecDeque<Entry>,
+    file: File,
+    /// Lines in the file, including those of entries dropped or replayed since the last rewrite
+    lines: usize,
+    /// Set while full, so a burst logs one warning
+    full: bool,
+}
+
+/// Values that failed to send for good (`miniserver.outbox`), kept in an append log of
+/// JSON lines so they survive a restart of the relay, and replayed once the Miniserver
+/// is reachable again. At most `max_entries` are kept, dropping the oldest, and values
+/// older than `max_age` seconds (0: no limit) are dropped instead of replayed.
+///
+/// The file is only rewritten when it has grown well past the entries it still holds,
+/// or after a replay; values replayed right before a crash may be sent again.
+pub struct Outbox {
+    path: PathBuf,
+    replay: Replay,
+    max_entries: usize,
+    max_age: f64,
+    log: Mutex<Log>,
+    replaying: AtomicBool,
+}
+
+impl Outbox {
+    /// Open the log at `path`, keeping the entries it already holds within the limits.
+    pub fn open(path: impl Into<PathBuf>, replay: Replay, max_entries: usize, max_age: f64) -> PyResult<Self> {
+        let path = path.into();
+        let failed = |e: io::Error| PyValueError::new_err(format!("Can't open the outbox {}: {}", path.display(), e));
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).map_err(failed)?;
+        }
+        let (entries, lines) = match File::open(&path) {
+            Ok(file) => read_entries(&path, file),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (VecDeque::new(), 0),
+            Err(e) => return Err(failed(e)),
+        };
+        let file = OpenOptions::new().create(true).append(true).open(&path).map_err(failed)?;
+        let outbox = Outbox {
+            path: path.clone(),
+            replay,
+            max_entries: max_entries.max(1),
+            max_age,
+            log: Mutex::new(Log { entries, file, lines, full: false }),
+            replaying: AtomicBool::new(false),
+        };
+        {
+            let mut log = outbox.log.lock().unwrap();
+            outbox.trim(&mut log);
+            outbox.rewrite(&mut log).map_err(failed)?;
+            if !log.entries.is_empty() {
+                info!("Outbox {} holds {} values to replay", path.display(), log.entries.len());
+            }
+        }
+        Ok(outbox)
+    }
+
+    pub fn replay(&self) -> Replay {
+        self.replay
+    }
+
+    pub fn len(&self) -> usize {
+        self.log.lock().unwrap().entries.len()
+    }
+
+    /// Buffer a value that failed to send.
+    pub fn push(&self, name: &str, topic: &str, value: &str) {
+        let entry = Entry { name: name.to_string(), topic: topic.to_string(), value: value.to_string(), at: now() };
+        let mut log = self.log.lock().unwrap();
+        if let Err(e) = append(&mut log.file, &entry) {
+            warn!("Can't write to the outbox {}: {}", self.path.display(), e);
+        }
+        log.lines += 1;
+        log.entries.push_back(entry);
+        let full = log.entries.len() > self.max_entries;
+        if full && !log.full {
+            warn!("Outbox full ({} values), dropping the oldest", self.max_entries);
+        }
+        log.full = full;
+        self.trim(&mut log);
+        // Rewrite once the file holds a tenth more lines than entries
+        if log.lines > log.entries.len() + self.max_entries / 10 {
+            if let Err(e) = self.rewrite(&mut log) {
+                warn!("Can't rewrite the outbox {}: {}", self.path.display(), e);
+            }
+        }
+    }
+
+    /// Claim the replay, unless one is running already or there is nothing to replay.
+    /// With `Replay::Latest` the entries are reduced to the newest per input.
+    pub fn start_replay(&self) -> bool {
+        let mut log = self.log.lock().unwrap();
+        if log.entries.is_empty() || self.replaying.swap(true, Ordering::Relaxed) {
+            return false;
+        }
+        if self.replay == Replay::Latest {
+            // Index of the newest entry per input
+            let newest: HashMap<&str, usize> =
+                log.entries.iter().enumerate().map(|(index, entry)| (entry.name.as_str(), index)).collect();
+            if newest.len() < log.entries.len() {
+                let mut keep = vec![false; log.entries.len()];
+                newest.values().for_each(|&index| keep[index] = true);
+                let mut index = 0;
+                log.entries.retain(|_| {
+                    index += 1;
+                    keep[index - 1]
+                });
+            }
+        }
+        true
+    }
+
+    /// The next value to replay, without removing it. Expired entries are dropped first.
+    pub fn next(&self) -> Option<Entry> {
+        let mut log = self.log.lock().unwrap();
+        self.trim(&mut log);
+        log.entries.front().cloned()
+    }
+
+    /// Drop the value `next` returned, after it was sent.
+    pub fn pop(&self) {
+        self.log.lock().unwrap().entries.pop_front();
+    }
+
+    /// End the replay and write what is left to the file.
+    pub fn finish_replay(&self) {
+        let mut log = self.log.lock().unwrap();
+        if let Err(e) = self.rewrite(&mut log) {
+            warn!("Can't rewrite the outbox {}: {}", self.path.display(), e);
+        }
+        self.replaying.store(false, Ordering::Relaxed);
+    }
+
+    /// Drop expired entries and those above `max_entries`.
+    fn trim(&self, log: &mut Log) {
+        while log.entries.len() > self.max_entries {
+            log.entries.pop_front();
+        }
+        if self.max_age > 0.0 {
+            let oldest = now() - self.max_age;
+            let before = log.entries.len();
+            log.entries.retain(|entry| entry.at >= oldest);
+            if log.entries.len() < before {
+                info!("Outbox: dropped {} values older than {}s", before - log.entries.len(), self.max_age);
+            }
+        }
+    }
+
+    /// Replace the file with the entries still held, through a temporary file.
+    fn rewrite(&self, log: &mut Log) -> io::Result<()> {
+        if log.lines == log.entries.len() {
+            return Ok(());
+        }
+        let mut tmp_name = self.path.clone().into_os_string();
+        tmp_name.push(".tmp");
+        let tmp = PathBuf::from(tmp_name);
+        {
+            let mut file = File::create(&tmp)?;
+            for entry in &log.entries {
+                append(&mut file, entry)?;
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, &self.path)?;
+        log.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        log.lines = log.entries.len();
+        Ok(())
+    }
+}
+
+fn append(file: &mut File, entry: &Entry) -> io::Result<()> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    file.write_all(&line)
+}
+
+/// The entries of an existing log and its number of lines; lines that can't be read
+/// (e.g. half written when the relay was killed) are skipped.
+fn read_entries(path: &Path, file: File) -> (VecDeque<Entry>, usize) {
+    let mut entries = VecDeque::new();
+    let mut skipped = 0;
+    for line in BufReader::new(file).lines() {
+        match line.map(|line| serde_json::from_str::<Entry>(&line)) {
+            Ok(Ok(entry)) => entries.push_back(entry),
+            Ok(Err(_)) | Err(_) => skipped += 1,
+        }
+    }
+    if skipped > 0 {
+        warn!("Outbox {}: skipped {} unreadable lines", path.display(), skipped);
+    }
+    let lines = entries.len() + skipped;
+    (entries, lines)
+}
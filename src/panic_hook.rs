@@ -0,0 +1,96 @@
+use std::backtrace::Backtrace;
+use std::panic::{self, PanicHookInfo};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, Once};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{error, log_enabled, Level};
+use pyo3::prelude::*;
+use pyo3::intern;
+
+/// Where panics are reported: the relay's MQTT client and the asyncio loop it runs on.
+struct PanicSink {
+    mqtt_client: Py<PyAny>,
+    topic: String,
+    event_loop: Option<Py<PyAny>>,
+}
+
+static SINK: Mutex<Option<PanicSink>> = Mutex::new(None);
+static LOOP_BOUND: AtomicBool = AtomicBool::new(false);
+static INSTALL: Once = Once::new();
+
+/// Install the hook; called once from the module init.
+pub fn install() {
+    INSTALL.call_once(|| {
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            report(info);
+            // Without a Rust logger the panic would otherwise go unnoticed
+            if !log_enabled!(Level::Error) {
+                default_hook(info);
+            }
+        }));
+    });
+}
+
+/// Publish panics to `topic` via `mqtt_client` from now on. The latest processor wins.
+pub fn register(py: Python<'_>, mqtt_client: Py<PyAny>, topic: String) {
+    let event_loop = pyo3_async_runtimes::get_running_loop(py).ok().map(Bound::unbind);
+    LOOP_BOUND.store(event_loop.is_some(), Ordering::Relaxed);
+    *SINK.lock().unwrap() = Some(PanicSink { mqtt_client, topic, event_loop });
+}
+
+/// Remember the running asyncio loop if the processor was created outside of it.
+/// Cheap after the first call, so it can run on every message.
+pub fn bind_running_loop(py: Python<'_>) {
+    if LOOP_BOUND.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Ok(event_loop) = pyo3_async_runtimes::get_running_loop(py) {
+        if let Some(sink) = SINK.lock().unwrap().as_mut() {
+            sink.event_loop = Some(event_loop.unbind());
+            LOOP_BOUND.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+fn report(info: &PanicHookInfo<'_>) {
+    let message = info
+        .payload()
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| info.payload().downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "Box<dyn Any>".to_string());
+    let location = info.location().map(|l| format!("{}:{}", l.file(), l.line())).unwrap_or_default();
+    let thread = std::thread::current().name().unwrap_or("<unnamed>").to_string();
+    error!("Panic in thread '{}' at {}: {}\n{}", thread, location, message, Backtrace::force_capture());
+
+    let summary = serde_json::json!({
+        "message": message,
+        "location": location,
+        "thread": thread,
+        "at": SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or_default(),
+    })
+    .to_string();
+    // try_lock: nothing is published if the panic happened while registering
+    let Ok(sink) = SINK.try_lock() else { return };
+    let Some(sink) = sink.as_ref() else { return };
+    let Some(event_loop) = sink.event_loop.as_ref() else { return };
+    // The interpreter may already be finalizing, e.g. for panics during shutdown
+    let published = Python::try_attach(|py| -> PyResult<()> {
+        let coro = sink.mqtt_client.bind(py).call_method1(intern!(py, "publish"), (&sink.topic, summary))?;
+        let ensure_future = py.import(intern!(py, "asyncio"))?.getattr(intern!(py, "ensure_future"))?;
+        // The panic may be on a Tokio worker thread, so hand the publish over to the loop
+        event_loop.bind(py).call_method1(intern!(py, "call_soon_threadsafe"), (ensure_future, coro))?;
+        Ok(())
+    });
+    if let Some(Err(e)) = published {
+        error!("Error publishing panic summary: {:?}", e);
+    }
+}
+
+/// Panic with `message`, to check the panic reporting end to end.
+#[pyfunction]
+pub fn _panic(message: String) {
+    panic!("{}", message);
+}
@@ -0,0 +1,226 @@
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use regex::Regex;
+
+use crate::config_entries::dict_entries;
+
+/// Plugin host for sandboxed WASM decoders.
+///
+/// A plugin module must export:
+/// - `memory`: its linear memory
+/// - `alloc(len: i32) -> i32`: reserve `len` bytes and return the offset
+/// - `process(topic_ptr: i32, topic_len: i32, payload_ptr: i32, payload_len: i32) -> i64`:
+///   decode one message and return `(ptr << 32) | len` of a UTF-8 JSON array of
+///   `[topic, value]` pairs. A length of 0 drops the message.
+///
+/// Every message runs in a fresh instance with a fuel and memory budget, so plugins
+/// can't keep state between messages or stall the relay.
+#[cfg(feature = "wasm")]
+mod host {
+    use super::*;
+    use log::debug;
+    use serde_json::Value;
+    use wasmtime::{Config, Engine, InstancePre, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+    /// Upper bound for a plugin's linear memory.
+    const MAX_MEMORY_BYTES: usize = 16 * 1024 * 1024;
+
+    struct PluginHook {
+        source: String,
+        pattern: Regex,
+        file: String,
+        instance: InstancePre<StoreLimits>,
+    }
+
+    pub struct PluginHost {
+        engine: Engine,
+        hooks: Vec<PluginHook>,
+        fuel: u64,
+    }
+
+    impl PluginHost {
+        pub fn new(fuel: u64) -> PyResult<Self> {
+            let mut config = Config::new();
+            config.consume_fuel(true);
+            let engine = Engine::new(&config)
+                .map_err(|e| PyValueError::new_err(format!("Failed to start WASM engine: {}", e)))?;
+            Ok(PluginHost {
+                engine,
+                hooks: Vec::new(),
+                fuel,
+            })
+        }
+
+        pub fn add(&mut self, source: String, pattern: Regex, file: String) -> PyResult<()> {
+            let module = Module::from_file(&self.engine, &file).map_err(|e| {
+                PyValueError::new_err(format!("Failed to load plugin '{}': {}", file, e))
+            })?;
+            for export in ["memory", "alloc", "process"] {
+                if module.get_export(export).is_none() {
+                    return Err(PyValueError::new_err(format!(
+                        "Plugin '{}' doesn't export '{}'",
+                        file, export
+                    )));
+                }
+            }
+            let linker = Linker::new(&self.engine);
+            let instance = linker.instantiate_pre(&module).map_err(|e| {
+                PyValueError::new_err(format!("Plugin '{}' can't be instantiated: {}", file, e))
+            })?;
+            self.hooks.push(PluginHook {
+                source,
+                pattern,
+                file,
+                instance,
+            });
+            Ok(())
+        }
+
+        pub fn find(&self, topic: &str) -> Option<usize> {
+            self.hooks.iter().position(|hook| hook.pattern.is_match(topic))
+        }
+
+        pub fn patterns(&self) -> Vec<String> {
+            self.hooks.iter().map(|hook| hook.source.clone()).collect()
+        }
+
+        pub fn run(&self, index: usize, topic: &str, payload: &str) -> Result<Vec<(String, String)>, String> {
+            let hook = &self.hooks[index];
+            let limits = StoreLimitsBuilder::new().memory_size(MAX_MEMORY_BYTES).build();
+            let mut store = Store::new(&self.engine, limits);
+            store.limiter(|limits| limits);
+            store.set_fuel(self.fuel).map_err(|e| e.to_string())?;
+
+            let instance = hook.instance.instantiate(&mut store).map_err(|e| e.to_string())?;
+            let memory = instance
+                .get_memory(&mut store, "memory")
+                .ok_or("plugin doesn't export 'memory'")?;
+            let alloc = instance
+                .get_typed_func::<i32, i32>(&mut store, "alloc")
+                .map_err(|e| e.to_string())?;
+            let process = instance
+                .get_typed_func::<(i32, i32, i32, i32), i64>(&mut store, "process")
+                .map_err(|e| e.to_string())?;
+
+            let write = |store: &mut Store<StoreLimits>, bytes: &[u8]| -> Result<i32, String> {
+                let len = i32::try_from(bytes.len()).map_err(|_| "input too large".to_string())?;
+                let ptr = alloc.call(&mut *store, len).map_err(|e| e.to_string())?;
+                memory
+                    .write(&mut *store, ptr as u32 as usize, bytes)
+                    .map_err(|e| e.to_string())?;
+                Ok(ptr)
+            };
+            let topic_ptr = write(&mut store, topic.as_bytes())?;
+            let payload_ptr = write(&mut store, payload.as_bytes())?;
+            let packed = process
+                .call(
+                    &mut store,
+                    (topic_ptr, topic.len() as i32, payload_ptr, payload.len() as i32),
+                )
+                .map_err(|e| format!("plugin '{}' trapped: {}", hook.file, e))?;
+
+            let (ptr, len) = ((packed as u64 >> 32) as usize, (packed as u64 & 0xffff_ffff) as usize);
+            if len == 0 {
+                return Ok(Vec::new());
+            }
+            let mut output = vec![0u8; len];
+            memory.read(&store, ptr, &mut output).map_err(|e| e.to_string())?;
+            debug!("Plugin '{}' returned {} bytes for topic '{}'", hook.file, len, topic);
+            parse_output(&output)
+        }
+    }
+
+    /// Parse a plugin's JSON output into `(topic, value)` pairs.
+    fn parse_output(output: &[u8]) -> Result<Vec<(String, String)>, String> {
+        let value: Value = serde_json::from_slice(output).map_err(|e| format!("invalid plugin output: {}", e))?;
+        let Value::Array(items) = value else {
+            return Err("plugin output must be a JSON array".to_string());
+        };
+        items
+            .into_iter()
+            .map(|item| match item {
+                Value::Array(pair) if pair.len() == 2 => {
+                    let topic = match &pair[0] {
+                        Value::String(s) => s.clone(),
+                        _ => return Err("plugin output topics must be strings".to_string()),
+                    };
+                    let value = match &pair[1] {
+                        Value::String(s) => s.clone(),
+                        other => other.to_string(),
+                    };
+                    Ok((topic, value))
+                }
+                _ => Err("plugin outputs must be [topic, value] pairs".to_string()),
+            })
+            .collect()
+    }
+}
+
+/// Stand-in used when the crate is built without the `wasm` feature: no plugin can be loaded.
+#[cfg(not(feature = "wasm"))]
+mod host {
+    use super::*;
+
+    pub struct PluginHost;
+
+    impl PluginHost {
+        pub fn new(_fuel: u64) -> PyResult<Self> {
+            Ok(PluginHost)
+        }
+
+        pub fn add(&mut self, _source: String, _pattern: Regex, file: String) -> PyResult<()> {
+            Err(PyValueError::new_err(format!(
+                "Can't load plugin '{}': loxmqttrelay was built without the 'wasm' feature",
+                file
+            )))
+        }
+
+        pub fn find(&self, _topic: &str) -> Option<usize> {
+            None
+        }
+
+        pub fn patterns(&self) -> Vec<String> {
+            Vec::new()
+        }
+
+        pub fn run(&self, _index: usize, _topic: &str, _payload: &str) -> Result<Vec<(String, String)>, String> {
+            Err("WASM plugins are not supported by this build".to_string())
+        }
+    }
+}
+
+pub use host::PluginHost;
+
+/// Whether this build can load WASM plugins.
+pub const WASM_SUPPORTED: bool = cfg!(feature = "wasm");
+
+/// Build a `PluginHost` from a list of `{"match": ..., "file": ...}` dicts.
+/// Any invalid entry raises a `ValueError`.
+pub fn parse_plugins(plugins: &Bound<'_, PyAny>, fuel: u64) -> PyResult<PluginHost> {
+    let mut host = PluginHost::new(fuel)?;
+    for (index, entry) in dict_entries(plugins, "Plugin")?.iter().enumerate() {
+        let source: String = match entry.get_item("match")? {
+            Some(v) => v.extract()?,
+            None => {
+                return Err(PyValueError::new_err(format!(
+                    "Plugin {} is missing the 'match' pattern",
+                    index
+                )))
+            }
+        };
+        let pattern = Regex::new(&source).map_err(|e| {
+            PyValueError::new_err(format!("Invalid plugin pattern '{}': {}", source, e))
+        })?;
+        let file: String = match entry.get_item("file")? {
+            Some(v) => v.extract()?,
+            None => {
+                return Err(PyValueError::new_err(format!(
+                    "Plugin {} is missing the 'file' to load",
+                    index
+                )))
+            }
+        };
+        host.add(source, pattern, file)?;
+    }
+    Ok(host)
+}
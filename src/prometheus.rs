@@ -0,0 +1,231 @@
+use std::fmt::Write;
+use std::net::TcpListener as StdTcpListener;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+use crate::delivery::LastValueStore;
+use crate::lox_ws::LoxWs;
+use crate::lookup_cache::LookupCache;
+use crate::metrics::Metrics;
+use crate::outbox::Outbox;
+use crate::send_queue::SendQueue;
+use crate::stats::Stats;
+
+/// Requests with a longer head than this are answered with 431.
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub type StringCache = Arc<LookupCache>;
+
+/// What a scrape reads; all of it shared with the processor.
+pub struct Sources {
+    pub stats: Arc<Stats>,
+    pub metrics: Arc<Metrics>,
+    pub last_values: Arc<LastValueStore>,
+    pub send_queue: Arc<SendQueue>,
+    pub outbox: Option<Arc<Outbox>>,
+    /// The native websocket, for its token state
+    pub lox_ws: Option<Arc<LoxWs>>,
+    /// The lookup caches, by name
+    pub caches: Vec<(&'static str, StringCache)>,
+}
+
+/// Serves the processing metrics in the Prometheus text format on `GET /metrics`
+/// (`debug.prometheus_port`), from the embedded tokio runtime. Stops when dropped.
+pub struct Exporter {
+    task: JoinHandle<()>,
+}
+
+impl Drop for Exporter {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Exporter {
+    /// Listen on `host:port` right away, so a port in use fails the start.
+    pub fn start(host: &str, port: u16, sources: Sources) -> PyResult<Exporter> {
+        let listener = StdTcpListener::bind((host, port))
+            .map_err(|e| PyValueError::new_err(format!("Prometheus exporter can't listen on {}:{}: {}", host, port, e)))?;
+        listener.set_nonblocking(true)?;
+        info!("Serving Prometheus metrics on http://{}/metrics", listener.local_addr()?);
+        let sources = Arc::new(sources);
+        let task = pyo3_async_runtimes::tokio::get_runtime().spawn(async move {
+            let listener = match TcpListener::from_std(listener) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    warn!("Prometheus exporter stopped: {}", e);
+                    return;
+                }
+            };
+            loop {
+                match listener.accept().await {
+                    Ok((stream, peer)) => {
+                        let sources = Arc::clone(&sources);
+                        tokio::spawn(async move {
+                            if let Err(e) = tokio::time::timeout(REQUEST_TIMEOUT, answer(stream, &sources)).await {
+                                debug!("Prometheus scrape from {} timed out: {}", peer, e);
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        warn!("Prometheus exporter failed to accept a connection: {}", e);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                    }
+                }
+            }
+        });
+        Ok(Exporter { task })
+    }
+}
+
+/// Answer one request and close the connection.
+async fn answer(mut stream: TcpStream, sources: &Sources) {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_BYTES {
+            let _ = stream.write_all(&response("431 Request Header Fields Too Large", "")).await;
+            return;
+        }
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => head.extend_from_slice(&buf[..n]),
+        }
+    }
+    let request_line = String::from_utf8_lossy(&head);
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let path = path.split('?').next().unwrap_or("");
+    let reply = match (method, path) {
+        ("GET", "/metrics") => response("200 OK", &render(sources)),
+        ("GET", _) => response("404 Not Found", "Metrics are at /metrics\n"),
+        _ => response("405 Method Not Allowed", ""),
+    };
+    let _ = stream.write_all(&reply).await;
+    let _ = stream.shutdown().await;
+}
+
+fn response(status: &str, body: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+/// One metric family: `# HELP`, `# TYPE` and a sample per `(labels, value)`.
+fn family(out: &mut String, name: &str, kind: &str, help: &str, samples: impl IntoIterator<Item = (String, u64)>) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{}{} {}", name, labels, value);
+    }
+}
+
+/// The metrics in the Prometheus text exposition format. The counters start over after
+/// `stats/reset`, which Prometheus treats as a counter reset.
+pub fn render(sources: &Sources) -> String {
+    let mut out = String::new();
+    for (name, value) in sources.stats.counters() {
+        family(
+            &mut out,
+            &format!("loxmqttrelay_{}_total", name),
+            "counter",
+            &format!("The '{}' counter of the relay stats", name),
+            [(String::new(), value)],
+        );
+    }
+    family(
+        &mut out,
+        "loxmqttrelay_filtered_by_total",
+        "counter",
+        "Messages and values dropped, by the filter that dropped them",
+        sources.stats.filtered_by().into_iter().map(|(kind, n)| (format!("{{filter=\"{}\"}}", kind), n)),
+    );
+    family(
+        &mut out,
+        "loxmqttrelay_sends_in_flight",
+        "gauge",
+        "Sends to the Miniserver started and not finished, including those held by the rate limit",
+        [(String::new(), sources.metrics.in_flight())],
+    );
+    family(
+        &mut out,
+        "loxmqttrelay_send_queue_length",
+        "gauge",
+        "Sends waiting for a worker, with miniserver.send_concurrency set",
+        [(String::new(), sources.send_queue.len() as u64)],
+    );
+    if let Some(outbox) = &sources.outbox {
+        family(
+            &mut out,
+            "loxmqttrelay_outbox_length",
+            "gauge",
+            "Failed sends kept in the outbox for replay",
+            [(String::new(), outbox.len() as u64)],
+        );
+    }
+    if let Some(ws) = &sources.lox_ws {
+        let token = ws.token_status();
+        let field = |name: &str| token[name].as_u64().unwrap_or(0);
+        family(
+            &mut out,
+            "loxmqttrelay_websocket_token_expiry_timestamp_seconds",
+            "gauge",
+            "Unix time the websocket token expires, 0 without a token",
+            [(String::new(), field("valid_until"))],
+        );
+        family(
+            &mut out,
+            "loxmqttrelay_websocket_token_refreshes_total",
+            "counter",
+            "Websocket token refreshes",
+            [(String::new(), field("refreshes"))],
+        );
+        family(
+            &mut out,
+            "loxmqttrelay_websocket_token_refresh_failures_total",
+            "counter",
+            "Failed websocket token refreshes",
+            [(String::new(), field("refresh_failures"))],
+        );
+    }
+    family(
+        &mut out,
+        "loxmqttrelay_values_held",
+        "gauge",
+        "Values held back while forwarding is paused",
+        [(String::new(), sources.last_values.held_count() as u64)],
+    );
+    let mut entries: Vec<(String, u64)> = sources
+        .caches
+        .iter()
+        .map(|(name, cache)| (format!("{{cache=\"{}\"}}", name), cache.len() as u64))
+        .collect();
+    entries.push(("{cache=\"delivery\"}".to_string(), sources.last_values.len() as u64));
+    entries.push(("{cache=\"topic_metrics\"}".to_string(), sources.metrics.topic_count() as u64));
+    family(&mut out, "loxmqttrelay_cache_entries", "gauge", "Entries in the lookup caches", entries);
+    let lookups = sources.metrics.cache_lookups();
+    for (outcome, column) in [("hits", 0), ("misses", 1), ("evictions", 2)] {
+        family(
+            &mut out,
+            &format!("loxmqttrelay_cache_{}_total", outcome),
+            "counter",
+            &format!("Cache {} of the lookup caches", outcome),
+            lookups.iter().map(|&(name, hits, misses, evictions)| {
+                (format!("{{cache=\"{}\"}}", name), [hits, misses, evictions][column])
+            }),
+        );
+    }
+    out
+}
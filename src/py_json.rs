@@ -0,0 +1,113 @@
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::{PyBool, PyDict, PyFloat, PyFrozenSet, PyInt, PyList, PySet, PyString, PyTuple};
+use serde_json::{Map, Number, Value};
+
+/// Python object (as returned by the config and status accessors) to a JSON value:
+/// dicts with str keys, lists, tuples and sets, str, int, float, bool and None.
+pub fn to_value(obj: &Bound<'_, PyAny>) -> PyResult<Value> {
+    if obj.is_none() {
+        return Ok(Value::Null);
+    }
+    // bool before int: True is an int in Python
+    if let Ok(b) = obj.cast::<PyBool>() {
+        return Ok(Value::Bool(b.is_true()));
+    }
+    if let Ok(i) = obj.cast::<PyInt>() {
+        if let Ok(n) = i.extract::<i64>() {
+            return Ok(Value::from(n));
+        }
+        if let Ok(n) = i.extract::<u64>() {
+            return Ok(Value::from(n));
+        }
+        // Keeps integers of any size exact (`arbitrary_precision`)
+        return i
+            .str()?
+            .to_str()?
+            .parse::<Number>()
+            .map(Value::Number)
+            .map_err(|e| PyValueError::new_err(format!("Can't serialize integer: {}", e)));
+    }
+    if let Ok(f) = obj.cast::<PyFloat>() {
+        // NaN and infinity aren't JSON; null like orjson
+        return Ok(Number::from_f64(f.value()).map(Value::Number).unwrap_or(Value::Null));
+    }
+    if let Ok(s) = obj.cast::<PyString>() {
+        return Ok(Value::String(s.to_str()?.to_string()));
+    }
+    if let Ok(dict) = obj.cast::<PyDict>() {
+        let mut map = Map::with_capacity(dict.len());
+        for (k, v) in dict.iter() {
+            let Ok(key) = k.cast::<PyString>() else {
+                return Err(PyValueError::new_err(format!(
+                    "Can't serialize dict key {}: keys must be str",
+                    k.repr()?
+                )));
+            };
+            map.insert(key.to_str()?.to_string(), to_value(&v)?);
+        }
+        return Ok(Value::Object(map));
+    }
+    if let Ok(list) = obj.cast::<PyList>() {
+        return list.iter().map(|item| to_value(&item)).collect::<PyResult<Vec<_>>>().map(Value::Array);
+    }
+    if let Ok(tuple) = obj.cast::<PyTuple>() {
+        return tuple.iter().map(|item| to_value(&item)).collect::<PyResult<Vec<_>>>().map(Value::Array);
+    }
+    // Set fields such as topic_whitelist; sorted so the output is stable
+    if obj.is_instance_of::<PySet>() || obj.is_instance_of::<PyFrozenSet>() {
+        let mut items = obj.try_iter()?.map(|item| to_value(&item?)).collect::<PyResult<Vec<_>>>()?;
+        items.sort_by_key(|item| item.to_string());
+        return Ok(Value::Array(items));
+    }
+    Err(PyValueError::new_err(format!(
+        "Can't serialize object of type {} to JSON",
+        obj.get_type().name()?
+    )))
+}
+
+/// JSON value to the equivalent Python object; integers stay exact.
+pub fn to_py<'py>(py: Python<'py>, value: &Value) -> PyResult<Bound<'py, PyAny>> {
+    Ok(match value {
+        Value::Null => py.None().into_bound(py),
+        Value::Bool(b) => PyBool::new(py, *b).to_owned().into_any(),
+        Value::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                i.into_pyobject(py)?.into_any()
+            } else if let Some(u) = n.as_u64() {
+                u.into_pyobject(py)?.into_any()
+            } else if n.to_string().bytes().all(|b| b.is_ascii_digit() || b == b'-') {
+                // Beyond 64 bits: let Python parse the exact token
+                py.get_type::<PyInt>().call1((n.to_string(),))?
+            } else {
+                PyFloat::new(py, n.as_f64().unwrap_or(f64::NAN)).into_any()
+            }
+        }
+        Value::String(s) => PyString::new(py, s).into_any(),
+        Value::Array(items) => {
+            let list = PyList::empty(py);
+            for item in items {
+                list.append(to_py(py, item)?)?;
+            }
+            list.into_any()
+        }
+        Value::Object(map) => {
+            let dict = PyDict::new(py);
+            for (k, v) in map {
+                dict.set_item(k, to_py(py, v)?)?;
+            }
+            dict.into_any()
+        }
+    })
+}
+
+/// Serialize `obj` to JSON text.
+pub fn dumps(obj: &Bound<'_, PyAny>) -> PyResult<String> {
+    Ok(to_value(obj)?.to_string())
+}
+
+/// Parse JSON text into Python objects.
+pub fn loads<'py>(py: Python<'py>, text: &str) -> PyResult<Bound<'py, PyAny>> {
+    let value: Value = serde_json::from_str(text).map_err(|e| PyValueError::new_err(format!("Invalid JSON: {}", e)))?;
+    to_py(py, &value)
+}
@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use log::{Level, Record};
+use pyo3::intern;
+use pyo3::prelude::*;
+use pyo3::types::PyTuple;
+
+/// Python's numeric logging level for a Rust one; `trace` is below `DEBUG`.
+fn python_level(level: Level) -> u8 {
+    match level {
+        Level::Error => 40,
+        Level::Warn => 30,
+        Level::Info => 20,
+        Level::Debug => 10,
+        Level::Trace => 5,
+    }
+}
+
+/// Hands Rust log records to Python's `logging`, so they go through the handlers and
+/// format configured there. The logger of a record is named after its module path with
+/// dots (`loxmqttrelay.mqtt_client`), and its file and line are the Rust source's.
+#[derive(Default)]
+pub struct PyLogBridge {
+    /// `logging.getLogger` results by name
+    loggers: Mutex<HashMap<String, Py<PyAny>>>,
+}
+
+impl PyLogBridge {
+    fn logger<'py>(&self, py: Python<'py>, name: &str) -> PyResult<Bound<'py, PyAny>> {
+        if let Some(logger) = self.loggers.lock().unwrap().get(name) {
+            return Ok(logger.bind(py).clone());
+        }
+        // Not holding the lock while Python runs: it may switch threads in between
+        let logger = py.import(intern!(py, "logging"))?.call_method1(intern!(py, "getLogger"), (name,))?;
+        self.loggers.lock().unwrap().insert(name.to_string(), logger.clone().unbind());
+        Ok(logger)
+    }
+
+    /// Log `record` if its Python logger is enabled for the level. Records logged while
+    /// the interpreter shuts down are dropped.
+    pub fn emit(&self, record: &Record<'_>) -> PyResult<()> {
+        let name = record.target().replace("::", ".");
+        let level = python_level(record.level());
+        Python::try_attach(|py| {
+            let logger = self.logger(py, &name)?;
+            if !logger.call_method1(intern!(py, "isEnabledFor"), (level,))?.is_truthy()? {
+                return Ok(());
+            }
+            let file = record.file().unwrap_or("<rust>");
+            let line = record.line().unwrap_or(0);
+            let message = record.args().to_string();
+            let py_record = logger.call_method1(
+                intern!(py, "makeRecord"),
+                (&name, level, file, line, message, PyTuple::empty(py), py.None()),
+            )?;
+            logger.call_method1(intern!(py, "handle"), (py_record,)).map(drop)
+        })
+        .unwrap_or(Ok(()))
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use regex::Regex;
+use tokio::time::Instant;
+
+use log::{debug, error};
+
+use crate::config_entries::dict_entries;
+
+/// A `processing.rate_limit` entry, matched against the virtual input name.
+#[derive(Debug)]
+struct RateLimit {
+    pattern: Regex,
+    /// Minimum time between two values sent to the same input
+    interval: Duration,
+    /// Quiet time a value has to wait for before it is sent
+    debounce: Duration,
+}
+
+#[derive(Debug, Default)]
+struct Slot {
+    last_sent: Option<Instant>,
+    /// Bumped for every value, so a held value knows when a newer one replaced it
+    generation: u64,
+}
+
+/// What to do with a value that just passed the pipeline.
+#[derive(Debug, PartialEq)]
+pub enum Admission {
+    /// Send it now
+    Send,
+    /// Hold it for this long; it goes out unless a newer value for the input arrives
+    Hold(Duration, u64),
+}
+
+/// Per virtual input rate limiting and debouncing. The last value of a burst is never
+/// lost: values arriving too early are held and the newest one is sent as soon as the
+/// interval (or the debounce window) allows.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    limits: Vec<RateLimit>,
+    slots: Mutex<HashMap<String, Slot>>,
+}
+
+impl RateLimiter {
+    pub fn admit(&self, name: &str) -> Admission {
+        let Some(limit) = self.limits.iter().find(|limit| limit.pattern.is_match(name)) else {
+            return Admission::Send;
+        };
+        let mut slots = self.slots.lock().unwrap();
+        let slot = slots.entry(name.to_string()).or_default();
+        slot.generation += 1;
+        if !limit.debounce.is_zero() {
+            return Admission::Hold(limit.debounce, slot.generation);
+        }
+        let now = Instant::now();
+        match slot.last_sent.map(|sent| limit.interval.saturating_sub(now - sent)) {
+            Some(wait) if !wait.is_zero() => Admission::Hold(wait, slot.generation),
+            _ => {
+                slot.last_sent = Some(now);
+                Admission::Send
+            }
+        }
+    }
+
+    /// Wait until the held value may go out. False if a newer value replaced it.
+    pub async fn release(&self, name: &str, generation: u64, mut wait: Duration) -> bool {
+        loop {
+            tokio::time::sleep(wait).await;
+            let Some(limit) = self.limits.iter().find(|limit| limit.pattern.is_match(name)) else {
+                return true;
+            };
+            let mut slots = self.slots.lock().unwrap();
+            let slot = slots.entry(name.to_string()).or_default();
+            if slot.generation != generation {
+                return false;
+            }
+            let now = Instant::now();
+            wait = slot.last_sent.map(|sent| limit.interval.saturating_sub(now - sent)).unwrap_or_default();
+            if wait.is_zero() {
+                slot.last_sent = Some(now);
+                return true;
+            }
+            debug!("Holding {} for another {:?}", name, wait);
+        }
+    }
+}
+
+fn millis(entry: &Bound<'_, PyDict>, key: &str) -> PyResult<Duration> {
+    Ok(match entry.get_item(key)? {
+        Some(v) if !v.is_none() => Duration::from_millis(v.extract()?),
+        _ => Duration::ZERO,
+    })
+}
+
+/// Build a `RateLimiter` from a list of `{"pattern": ..., "interval_ms": ..., "debounce_ms": ...}`
+/// dicts; the first entry whose pattern matches a virtual input name applies to it.
+///
+/// Entries without a pattern or without either time raise a `ValueError`; invalid
+/// regexes are dropped with an error log unless `strict` is set.
+pub fn parse_rate_limits(entries: &Bound<'_, PyAny>, strict: bool) -> PyResult<RateLimiter> {
+    let mut limits = Vec::new();
+    let mut invalid = Vec::new();
+    for (index, entry) in dict_entries(entries, "Rate limit")?.iter().enumerate() {
+        let Some(source) = entry.get_item("pattern")? else {
+            return Err(PyValueError::new_err(format!("Rate limit {} is missing the 'pattern'", index)));
+        };
+        let source: String = source.extract()?;
+        let (interval, debounce) = (millis(entry, "interval_ms")?, millis(entry, "debounce_ms")?);
+        if interval.is_zero() && debounce.is_zero() {
+            return Err(PyValueError::new_err(format!(
+                "Rate limit {} needs 'interval_ms' or 'debounce_ms'",
+                index
+            )));
+        }
+        match Regex::new(&source) {
+            Ok(pattern) => limits.push(RateLimit { pattern, interval, debounce }),
+            Err(e) => {
+                error!("Invalid rate limit pattern '{}': {}", source, e);
+                invalid.push(format!("'{}': {}", source, e));
+            }
+        }
+    }
+    if strict && !invalid.is_empty() {
+        return Err(PyValueError::new_err(format!(
+            "Invalid rate limit patterns: {}",
+            invalid.join(", ")
+        )));
+    }
+    debug!("Compiled {} rate limits", limits.len());
+    Ok(RateLimiter { limits, slots: Mutex::new(HashMap::new()) })
+}
@@ -0,0 +1,39 @@
+use std::time::Duration;
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+/// Retries of failed sends (`miniserver.send_retries`, `send_retry_backoff`,
+/// `send_retry_max_backoff` and `send_retry_jitter`): the wait doubles per attempt up to
+/// the maximum, and is spread by up to `jitter` of itself either way so inputs that
+/// failed together don't retry together.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    backoff: f64,
+    max_backoff: f64,
+    jitter: f64,
+}
+
+impl RetryPolicy {
+    pub fn new(retries: u32, backoff: f64, max_backoff: f64, jitter: f64) -> PyResult<Self> {
+        if !(backoff.is_finite() && backoff >= 0.0 && max_backoff.is_finite() && max_backoff >= 0.0) {
+            return Err(PyValueError::new_err("send_retry_backoff and send_retry_max_backoff must be 0 or more seconds"));
+        }
+        if !(0.0..=1.0).contains(&jitter) {
+            return Err(PyValueError::new_err(format!("send_retry_jitter must be between 0 and 1, not {}", jitter)));
+        }
+        Ok(RetryPolicy { retries, backoff, max_backoff: max_backoff.max(backoff), jitter })
+    }
+
+    /// The wait before retry number `attempt` (0 for the first).
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let base = (self.backoff * 2f64.powi(attempt.min(30) as i32)).min(self.max_backoff);
+        let mut random = [0u8; 4];
+        let spread = match getrandom::fill(&mut random) {
+            Ok(()) => u32::from_le_bytes(random) as f64 / u32::MAX as f64 * 2.0 - 1.0,
+            Err(_) => 0.0,
+        };
+        Duration::from_secs_f64((base * (1.0 + self.jitter * spread)).max(0.0))
+    }
+}
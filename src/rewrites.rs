@@ -0,0 +1,88 @@
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList};
+use regex::Regex;
+
+use log::{debug, error};
+
+use crate::config_entries::dict_entries;
+
+/// A topic rewrite: the first match of `pattern` is replaced by `target`.
+#[derive(Clone, Debug)]
+struct Rewrite {
+    source: String,
+    pattern: Regex,
+    /// Replacement; may reference capture groups (`$1`, `$room`, `${room}`).
+    target: String,
+}
+
+/// Ordered topic rewrites mapping MQTT topics onto virtual input names.
+/// The first matching rewrite wins and its result is normalized like any other topic.
+#[derive(Clone, Debug, Default)]
+pub struct RewriteSet {
+    rewrites: Vec<Rewrite>,
+}
+
+impl RewriteSet {
+    pub fn is_empty(&self) -> bool {
+        self.rewrites.is_empty()
+    }
+
+    /// Rewrite `topic` with the first matching rule, or `None` if no rule matches.
+    pub fn apply(&self, topic: &str) -> Option<String> {
+        let rewrite = self.rewrites.iter().find(|r| r.pattern.is_match(topic))?;
+        let rewritten = rewrite.pattern.replace(topic, rewrite.target.as_str()).into_owned();
+        debug!("Rewrote topic '{}' to '{}' ('{}')", topic, rewritten, rewrite.source);
+        Some(rewritten)
+    }
+
+    /// Convert the rewrites back into the dict form they were configured with.
+    pub fn to_py<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyList>> {
+        let list = PyList::empty(py);
+        for rewrite in &self.rewrites {
+            let dict = PyDict::new(py);
+            dict.set_item("match", &rewrite.source)?;
+            dict.set_item("target", &rewrite.target)?;
+            list.append(dict)?;
+        }
+        Ok(list)
+    }
+}
+
+/// Build a `RewriteSet` from a list of `{"match": ..., "target": ...}` dicts.
+///
+/// Entries missing either key raise a `ValueError`; invalid regexes are dropped with
+/// an error log unless `strict` is set.
+pub fn parse_rewrites(rewrites: &Bound<'_, PyAny>, strict: bool) -> PyResult<RewriteSet> {
+    let mut parsed = Vec::new();
+    let mut invalid = Vec::new();
+    for (index, entry) in dict_entries(rewrites, "Rewrite")?.iter().enumerate() {
+        let (Some(source), Some(target)) = (entry.get_item("match")?, entry.get_item("target")?) else {
+            return Err(PyValueError::new_err(format!(
+                "Rewrite {} needs both 'match' and 'target'",
+                index
+            )));
+        };
+        let source: String = source.extract()?;
+        let target: String = target.extract()?;
+        match Regex::new(&source) {
+            Ok(pattern) => parsed.push(Rewrite {
+                source,
+                pattern,
+                target,
+            }),
+            Err(e) => {
+                error!("Invalid rewrite pattern '{}': {}", source, e);
+                invalid.push(format!("'{}': {}", source, e));
+            }
+        }
+    }
+    if strict && !invalid.is_empty() {
+        return Err(PyValueError::new_err(format!(
+            "Invalid rewrite patterns: {}",
+            invalid.join(", ")
+        )));
+    }
+    debug!("Compiled {} rewrites", parsed.len());
+    Ok(RewriteSet { rewrites: parsed })
+}
@@ -0,0 +1,282 @@
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList};
+use regex::Regex;
+
+use std::collections::HashMap;
+
+use log::{debug, error};
+
+use crate::config_entries::dict_entries;
+
+/// What happens to a topic matched by a rule.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RuleAction {
+    /// Forward the value, bypassing subscription filters, whitelist and do_not_forward.
+    Accept,
+    /// Never forward the value.
+    Drop,
+    /// Send the value to `target` and go on with the next rule.
+    Rewrite,
+    /// Replace the value through `value_map` and go on with the next rule.
+    Transform,
+}
+
+impl RuleAction {
+    fn parse(value: &str) -> PyResult<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "accept" | "forward" => Ok(RuleAction::Accept),
+            "drop" => Ok(RuleAction::Drop),
+            "rewrite" => Ok(RuleAction::Rewrite),
+            "transform" => Ok(RuleAction::Transform),
+            other => Err(PyValueError::new_err(format!(
+                "Invalid rule action '{}': expected 'accept', 'forward', 'drop', 'rewrite' or 'transform'",
+                other
+            ))),
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            RuleAction::Accept => "accept",
+            RuleAction::Drop => "drop",
+            RuleAction::Rewrite => "rewrite",
+            RuleAction::Transform => "transform",
+        }
+    }
+}
+
+/// How an accepted value is sent.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Transport {
+    /// The configured Miniserver connection (HTTP or websocket).
+    #[default]
+    Miniserver,
+    /// A request to the Miniserver over HTTP, even with `use_websocket`.
+    Http,
+    /// The websocket to the Miniserver, even without `use_websocket`.
+    Websocket,
+    /// A datagram to the Virtual UDP Inputs in `udp.udp_out_destinations`.
+    Udp,
+}
+
+impl Transport {
+    pub fn parse(value: &str) -> PyResult<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "miniserver" => Ok(Transport::Miniserver),
+            "http" => Ok(Transport::Http),
+            "websocket" => Ok(Transport::Websocket),
+            "udp" => Ok(Transport::Udp),
+            other => Err(PyValueError::new_err(format!(
+                "Invalid transport '{}': expected 'miniserver', 'http', 'websocket' or 'udp'",
+                other
+            ))),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Transport::Miniserver => "miniserver",
+            Transport::Http => "http",
+            Transport::Websocket => "websocket",
+            Transport::Udp => "udp",
+        }
+    }
+}
+
+/// A single entry of the ordered rule list.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    source: String,
+    pattern: Regex,
+    action: RuleAction,
+    /// Destination virtual input name; may reference capture groups (`$1`, `$name`).
+    target: Option<String>,
+    /// Value replacements applied before boolean conversion.
+    value_map: HashMap<String, String>,
+    /// Overrides `miniserver.default_transport`
+    transport: Option<Transport>,
+}
+
+/// The accept or drop rule that decided about a value.
+#[derive(Clone, Copy, Debug)]
+pub enum Verdict {
+    Accept { index: usize },
+    Drop { index: usize },
+}
+
+/// The outcome of the rules matching a value.
+#[derive(Debug, Default)]
+pub struct RuleDecision {
+    /// `None` if only rewrite and transform rules matched, which leaves the decision to
+    /// the filter lists
+    pub verdict: Option<Verdict>,
+    /// The rewrite and transform rules applied before the verdict
+    pub applied: Vec<usize>,
+    /// Virtual input name from the last matching rule with a `target`
+    pub target: Option<String>,
+    pub transport: Option<Transport>,
+}
+
+/// Ordered rule list evaluated first-match-wins, like firewall rules.
+#[derive(Clone, Debug, Default)]
+pub struct RuleSet {
+    rules: Vec<Rule>,
+}
+
+impl RuleSet {
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Rules can only send over UDP if `udp.udp_out_destinations` has somewhere to send to.
+    pub fn check_udp_destinations(&self, have_destinations: bool) -> PyResult<()> {
+        match self.rules.iter().position(|rule| rule.transport == Some(Transport::Udp)) {
+            Some(index) if !have_destinations => Err(PyValueError::new_err(format!(
+                "Rule {} sends over UDP, but udp.udp_out_destinations is empty",
+                index
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Run the rules in order until the first accept or drop rule matching the flattened
+    /// `key`, applying the rewrite and transform rules matched on the way; `value` is
+    /// replaced through the `value_map` of every matching rule. `None` if no rule matches.
+    pub fn evaluate(&self, key: &str, value: &mut String) -> Option<RuleDecision> {
+        let mut decision: Option<RuleDecision> = None;
+        for (index, rule) in self.rules.iter().enumerate() {
+            let Some(caps) = rule.pattern.captures(key) else { continue };
+            debug!("Topic '{}' matched rule {} ('{}')", key, index, rule.source);
+            let decision = decision.get_or_insert_with(RuleDecision::default);
+            if let Some(template) = &rule.target {
+                let mut expanded = String::new();
+                caps.expand(template, &mut expanded);
+                decision.target = Some(expanded);
+            }
+            if let Some(mapped) = rule.value_map.get(value.as_str()) {
+                *value = mapped.clone();
+            }
+            match rule.action {
+                RuleAction::Accept => {
+                    decision.transport = rule.transport;
+                    decision.verdict = Some(Verdict::Accept { index });
+                    break;
+                }
+                RuleAction::Drop => {
+                    decision.verdict = Some(Verdict::Drop { index });
+                    break;
+                }
+                RuleAction::Rewrite | RuleAction::Transform => decision.applied.push(index),
+            }
+        }
+        decision
+    }
+
+    /// Convert the rules back into the dict form they were configured with.
+    pub fn to_py<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyList>> {
+        let list = PyList::empty(py);
+        for rule in &self.rules {
+            let dict = PyDict::new(py);
+            dict.set_item("match", &rule.source)?;
+            dict.set_item("action", rule.action.as_str())?;
+            if let Some(ref target) = rule.target {
+                dict.set_item("target", target)?;
+            }
+            if !rule.value_map.is_empty() {
+                dict.set_item("value_map", rule.value_map.clone())?;
+            }
+            if let Some(transport) = rule.transport {
+                dict.set_item("transport", transport.as_str())?;
+            }
+            list.append(dict)?;
+        }
+        Ok(list)
+    }
+}
+
+/// Build a `RuleSet` from a list of rule dicts
+/// (`{"match": ..., "action": "accept" | "forward" | "drop" | "rewrite" | "transform",
+/// "target": ..., "value_map": {...}, "transport": "miniserver" | "http" | "websocket" | "udp"}`).
+///
+/// Malformed rules always raise a `ValueError`; rules with an invalid regex are
+/// dropped with an error log unless `strict` is set.
+pub fn parse_rules(rules: &Bound<'_, PyAny>, strict: bool) -> PyResult<RuleSet> {
+    let mut parsed = Vec::new();
+    let mut invalid = Vec::new();
+    for (index, rule) in dict_entries(rules, "Rule")?.iter().enumerate() {
+        let source: String = match rule.get_item("match")? {
+            Some(v) => v.extract()?,
+            None => {
+                return Err(PyValueError::new_err(format!(
+                    "Rule {} is missing the 'match' pattern",
+                    index
+                )))
+            }
+        };
+        let action = match rule.get_item("action")? {
+            Some(v) => RuleAction::parse(&v.extract::<String>()?)?,
+            None => {
+                return Err(PyValueError::new_err(format!(
+                    "Rule {} is missing the 'action'",
+                    index
+                )))
+            }
+        };
+        let target: Option<String> = match rule.get_item("target")? {
+            Some(v) if !v.is_none() => Some(v.extract()?),
+            _ => None,
+        };
+        let value_map: HashMap<String, String> = match rule.get_item("value_map")? {
+            Some(v) if !v.is_none() => v.extract()?,
+            _ => HashMap::new(),
+        };
+        let transport = match rule.get_item("transport")? {
+            Some(v) if !v.is_none() => Some(Transport::parse(&v.extract::<String>()?)?),
+            _ => None,
+        };
+        if action == RuleAction::Drop && (target.is_some() || !value_map.is_empty() || transport.is_some()) {
+            return Err(PyValueError::new_err(format!(
+                "Rule {} drops topics and can't define 'target', 'value_map' or 'transport'",
+                index
+            )));
+        }
+        match action {
+            RuleAction::Rewrite if target.is_none() => {
+                return Err(PyValueError::new_err(format!("Rule {} rewrites topics and needs a 'target'", index)))
+            }
+            RuleAction::Transform if value_map.is_empty() => {
+                return Err(PyValueError::new_err(format!("Rule {} transforms values and needs a 'value_map'", index)))
+            }
+            RuleAction::Rewrite | RuleAction::Transform if transport.is_some() => {
+                return Err(PyValueError::new_err(format!(
+                    "Rule {} doesn't decide about the value and can't define a 'transport'",
+                    index
+                )))
+            }
+            _ => {}
+        }
+        match Regex::new(&source) {
+            Ok(pattern) => parsed.push(Rule {
+                source,
+                pattern,
+                action,
+                target,
+                value_map,
+                transport,
+            }),
+            Err(e) => {
+                error!("Invalid rule pattern '{}': {}", source, e);
+                invalid.push(format!("'{}': {}", source, e));
+            }
+        }
+    }
+    if strict && !invalid.is_empty() {
+        return Err(PyValueError::new_err(format!(
+            "Invalid rule patterns: {}",
+            invalid.join(", ")
+        )));
+    }
+    debug!("Compiled {} rules", parsed.len());
+    Ok(RuleSet { rules: parsed })
+}
@@ -0,0 +1,211 @@
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use regex::Regex;
+use rhai::{Dynamic, Engine, Scope, AST};
+use serde_json::Value;
+
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+use log::{debug, error};
+
+use crate::config_entries::dict_entries;
+
+thread_local! {
+    /// Deadline of the script currently running on this thread, checked from `on_progress`.
+    static SCRIPT_DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// How often (in operations) the wall clock is checked against the deadline.
+const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+
+/// A compiled script attached to a topic pattern.
+struct ScriptHook {
+    source: String,
+    pattern: Regex,
+    ast: AST,
+}
+
+/// Rhai scripts transforming messages before they enter the filter pipeline.
+///
+/// A script sees `topic`, `value` (the raw payload) and `json` (the parsed payload, or `()`
+/// when it isn't JSON) and returns an array of `[topic, value]` pairs or `#{topic, value}`
+/// maps. Returning an empty array or `()` drops the message.
+pub struct ScriptEngine {
+    engine: Engine,
+    hooks: Vec<ScriptHook>,
+    max_operations: u64,
+    timeout: Duration,
+}
+
+impl ScriptEngine {
+    fn new(max_operations: u64, timeout: Duration) -> Self {
+        let mut engine = Engine::new();
+        engine.set_max_operations(max_operations);
+        engine.set_max_call_levels(32);
+        engine.set_max_expr_depths(64, 32);
+        engine.set_max_string_size(64 * 1024);
+        engine.set_max_array_size(10_000);
+        engine.set_max_map_size(10_000);
+        engine.on_progress(|ops| {
+            if ops % DEADLINE_CHECK_INTERVAL != 0 {
+                return None;
+            }
+            match SCRIPT_DEADLINE.with(Cell::get) {
+                Some(deadline) if Instant::now() > deadline => Some("script timeout".into()),
+                _ => None,
+            }
+        });
+        ScriptEngine {
+            engine,
+            hooks: Vec::new(),
+            max_operations,
+            timeout,
+        }
+    }
+
+    /// Index of the first script whose pattern matches `topic`.
+    pub fn find(&self, topic: &str) -> Option<usize> {
+        self.hooks.iter().position(|hook| hook.pattern.is_match(topic))
+    }
+
+    pub fn patterns(&self) -> Vec<String> {
+        self.hooks.iter().map(|hook| hook.source.clone()).collect()
+    }
+
+    /// `(max_operations, timeout_ms)` this engine was built with, for recompiling at runtime.
+    pub fn limits(&self) -> (u64, u64) {
+        (self.max_operations, self.timeout.as_millis() as u64)
+    }
+
+    /// Run script `index` and collect the `(topic, value)` pairs it returns.
+    pub fn run(
+        &self,
+        index: usize,
+        topic: &str,
+        value: &str,
+        json: Option<&Value>,
+    ) -> Result<Vec<(String, String)>, String> {
+        let hook = &self.hooks[index];
+        let json = match json {
+            Some(v) => json_to_dynamic(v),
+            None => Dynamic::UNIT,
+        };
+        let mut scope = Scope::new();
+        scope.push("topic", topic.to_string());
+        scope.push("value", value.to_string());
+        scope.push("json", json);
+
+        let deadline = (!self.timeout.is_zero()).then(|| Instant::now() + self.timeout);
+        SCRIPT_DEADLINE.with(|d| d.set(deadline));
+        let result = self.engine.eval_ast_with_scope::<Dynamic>(&mut scope, &hook.ast);
+        SCRIPT_DEADLINE.with(|d| d.set(None));
+
+        let result = result.map_err(|e| e.to_string())?;
+        debug!("Script '{}' returned {:?} for topic '{}'", hook.source, result, topic);
+        collect_outputs(result)
+    }
+}
+
+/// Convert parsed JSON into a Rhai value. Integers outside the `i64` range are passed
+/// as strings so they stay exact.
+fn json_to_dynamic(value: &Value) -> Dynamic {
+    match value {
+        Value::Null => Dynamic::UNIT,
+        Value::Bool(b) => (*b).into(),
+        Value::Number(num) => {
+            if let Some(i) = num.as_i64() {
+                i.into()
+            } else if num.is_f64() {
+                num.as_f64().unwrap_or(f64::NAN).into()
+            } else {
+                num.to_string().into()
+            }
+        }
+        Value::String(s) => s.clone().into(),
+        Value::Array(items) => items.iter().map(json_to_dynamic).collect::<rhai::Array>().into(),
+        Value::Object(map) => map
+            .iter()
+            .map(|(k, v)| (k.as_str().into(), json_to_dynamic(v)))
+            .collect::<rhai::Map>()
+            .into(),
+    }
+}
+
+/// Convert a script result into `(topic, value)` pairs.
+fn collect_outputs(result: Dynamic) -> Result<Vec<(String, String)>, String> {
+    if result.is_unit() {
+        return Ok(Vec::new());
+    }
+    let items = result
+        .into_array()
+        .map_err(|t| format!("script must return an array, got {}", t))?;
+    items.into_iter().map(output_pair).collect()
+}
+
+fn output_pair(item: Dynamic) -> Result<(String, String), String> {
+    if item.is_map() {
+        let map = item.cast::<rhai::Map>();
+        let topic = map.get("topic").ok_or("output map is missing 'topic'")?;
+        let value = map.get("value").ok_or("output map is missing 'value'")?;
+        return Ok((topic.to_string(), value.to_string()));
+    }
+    let pair = item
+        .into_array()
+        .map_err(|t| format!("script outputs must be [topic, value] pairs, got {}", t))?;
+    match pair.as_slice() {
+        [topic, value] => Ok((topic.to_string(), value.to_string())),
+        _ => Err(format!(
+            "script outputs must be [topic, value] pairs, got {} elements",
+            pair.len()
+        )),
+    }
+}
+
+/// Build a `ScriptEngine` from a list of `{"match": ..., "script": ...}` or
+/// `{"match": ..., "file": ...}` dicts. Any invalid entry raises a `ValueError`.
+pub fn parse_scripts(
+    scripts: &Bound<'_, PyAny>,
+    max_operations: u64,
+    timeout_ms: u64,
+) -> PyResult<ScriptEngine> {
+    let mut engine = ScriptEngine::new(max_operations, Duration::from_millis(timeout_ms));
+    for (index, entry) in dict_entries(scripts, "Script")?.iter().enumerate() {
+        let source: String = match entry.get_item("match")? {
+            Some(v) => v.extract()?,
+            None => {
+                return Err(PyValueError::new_err(format!(
+                    "Script {} is missing the 'match' pattern",
+                    index
+                )))
+            }
+        };
+        let pattern = Regex::new(&source).map_err(|e| {
+            PyValueError::new_err(format!("Invalid script pattern '{}': {}", source, e))
+        })?;
+        let code: String = if let Some(code) = entry.get_item("script")? {
+            code.extract()?
+        } else if let Some(file) = entry.get_item("file")? {
+            let file: String = file.extract()?;
+            std::fs::read_to_string(&file).map_err(|e| {
+                PyValueError::new_err(format!("Failed to read script file '{}': {}", file, e))
+            })?
+        } else {
+            return Err(PyValueError::new_err(format!(
+                "Script {} needs either 'script' or 'file'",
+                index
+            )));
+        };
+        let ast = engine.engine.compile(&code).map_err(|e| {
+            error!("Failed to compile script for '{}': {}", source, e);
+            PyValueError::new_err(format!("Failed to compile script for '{}': {}", source, e))
+        })?;
+        engine.hooks.push(ScriptHook {
+            source,
+            pattern,
+            ast,
+        });
+    }
+    debug!("Compiled {} scripts", engine.hooks.len());
+    Ok(engine)
+}
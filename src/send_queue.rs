@@ -0,0 +1,189 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use log::{info, warn};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use tokio::sync::{Notify, Semaphore};
+use tokio::task::JoinHandle;
+
+/// What happens to a send when the queue is full (`miniserver.send_queue_overflow`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overflow {
+    /// The longest waiting send makes room
+    #[default]
+    DropOldest,
+    /// The new send is dropped
+    DropNewest,
+    /// Nothing is dropped; the native MQTT client stops reading until there is room
+    Block,
+}
+
+impl Overflow {
+    pub fn parse(value: &str) -> PyResult<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "" | "drop_oldest" => Ok(Overflow::DropOldest),
+            "drop_newest" => Ok(Overflow::DropNewest),
+            "block" => Ok(Overflow::Block),
+            other => Err(PyValueError::new_err(format!(
+                "Invalid send_queue_overflow '{}': expected 'drop_oldest', 'drop_newest' or 'block'",
+                other
+            ))),
+        }
+    }
+}
+
+/// A send waiting in the queue: the virtual input, the id of its delivery record (unset
+/// for values still held by the rate limit) and the task doing the send.
+pub struct QueuedSend {
+    pub name: String,
+    pub id: Option<u64>,
+    pub task: Pin<Box<dyn Future<Output = ()> + Send>>,
+}
+
+struct State {
+    jobs: Mutex<VecDeque<QueuedSend>>,
+    /// One permit per queued send; closed by `close`
+    ready: Semaphore,
+    /// Notified whenever a send leaves the queue
+    room: Notify,
+    running: AtomicUsize,
+    /// Set while sends are dropped, so a burst logs one warning
+    overflowing: AtomicBool,
+}
+
+impl State {
+    fn pop(&self) -> Option<QueuedSend> {
+        let job = self.jobs.lock().unwrap().pop_front();
+        if job.is_some() {
+            self.room.notify_waiters();
+        }
+        job
+    }
+}
+
+/// Sends to the Miniserver, run by `miniserver.send_concurrency` workers from a queue of
+/// at most `send_queue_size` sends, so a burst of retained messages after a reconnect
+/// doesn't open thousands of requests at once. With a concurrency of 0 there is no
+/// queue and every send starts right away.
+pub struct SendQueue {
+    concurrency: usize,
+    capacity: usize,
+    overflow: Overflow,
+    state: Arc<State>,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Drop for SendQueue {
+    fn drop(&mut self) {
+        for worker in self.workers.get_mut().unwrap().drain(..) {
+            worker.abort();
+        }
+    }
+}
+
+impl SendQueue {
+    pub fn new(concurrency: usize, capacity: usize, overflow: Overflow) -> Self {
+        let state = Arc::new(State {
+            jobs: Mutex::new(VecDeque::new()),
+            ready: Semaphore::new(0),
+            room: Notify::new(),
+            running: AtomicUsize::new(0),
+            overflowing: AtomicBool::new(false),
+        });
+        let runtime = pyo3_async_runtimes::tokio::get_runtime();
+        let workers = (0..concurrency)
+            .map(|_| {
+                let state = Arc::clone(&state);
+                runtime.spawn(async move {
+                    loop {
+                        match state.ready.acquire().await {
+                            Ok(permit) => permit.forget(),
+                            // Closed: finish what is queued, then stop
+                            Err(_) if state.jobs.lock().unwrap().is_empty() => return,
+                            Err(_) => {}
+                        }
+                        // Another worker may have taken the last one after `close`
+                        if let Some(job) = state.pop() {
+                            state.running.fetch_add(1, Ordering::Relaxed);
+                            job.task.await;
+                            state.running.fetch_sub(1, Ordering::Relaxed);
+                        }
+                    }
+                })
+            })
+            .collect();
+        SendQueue { concurrency, capacity: capacity.max(1), overflow, state, workers: Mutex::new(workers) }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.concurrency > 0
+    }
+
+    /// Sends waiting for a worker.
+    pub fn len(&self) -> usize {
+        self.state.jobs.lock().unwrap().len()
+    }
+
+    /// Sends a worker is running.
+    pub fn running(&self) -> usize {
+        self.state.running.load(Ordering::Relaxed)
+    }
+
+    /// Queue `job`. Returns the send dropped to stay within the queue size, if any.
+    pub fn push(&self, job: QueuedSend) -> Option<QueuedSend> {
+        let mut jobs = self.state.jobs.lock().unwrap();
+        let full = jobs.len() >= self.capacity;
+        if full && !self.state.overflowing.swap(true, Ordering::Relaxed) {
+            match self.overflow {
+                Overflow::DropOldest => warn!("Send queue full ({} sends), dropping the oldest sends", self.capacity),
+                Overflow::DropNewest => warn!("Send queue full ({} sends), dropping new sends", self.capacity),
+                Overflow::Block => warn!("Send queue full ({} sends), pausing the native MQTT client", self.capacity),
+            }
+        } else if !full && self.state.overflowing.swap(false, Ordering::Relaxed) {
+            info!("Send queue has room again");
+        }
+        if !full || self.overflow == Overflow::Block {
+            jobs.push_back(job);
+            drop(jobs);
+            self.state.ready.add_permits(1);
+            return None;
+        }
+        match self.overflow {
+            Overflow::DropNewest => Some(job),
+            // The new send takes the permit of the one it replaces
+            _ => {
+                let oldest = jobs.pop_front();
+                jobs.push_back(job);
+                oldest
+            }
+        }
+    }
+
+    /// Whether the native MQTT client should stop reading: the queue is full and
+    /// `send_queue_overflow` is `block`.
+    pub fn blocks(&self) -> bool {
+        self.overflow == Overflow::Block && self.len() >= self.capacity
+    }
+
+    /// Wait until the queue has room.
+    pub async fn room(&self) {
+        loop {
+            let notified = self.state.room.notified();
+            if self.len() < self.capacity {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Stop taking sends: the workers finish the queued ones and end. Their handles,
+    /// to wait for.
+    pub fn close(&self) -> Vec<JoinHandle<()>> {
+        self.state.ready.close();
+        std::mem::take(&mut *self.workers.lock().unwrap())
+    }
+}
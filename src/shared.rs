@@ -0,0 +1,38 @@
+use std::sync::{Arc, Mutex};
+
+use arc_swap::ArcSwap;
+
+/// A value replaced as a whole at runtime (filters, rules, whitelist).
+///
+/// Readers load a snapshot without taking a lock, so reading never blocks on an update
+/// and no lock is held while processing a message or calling into Python. Updates swap
+/// in the new value atomically; a message in flight keeps the snapshot it started with.
+pub struct Shared<T> {
+    value: ArcSwap<T>,
+    /// Serializes `update`, so concurrent read-modify-write updates don't overwrite each other
+    writer: Mutex<()>,
+}
+
+impl<T> Shared<T> {
+    pub fn new(value: T) -> Self {
+        Shared { value: ArcSwap::from_pointee(value), writer: Mutex::new(()) }
+    }
+
+    pub fn get(&self) -> Arc<T> {
+        self.value.load_full()
+    }
+
+    pub fn set(&self, value: T) {
+        let _writer = self.writer.lock().unwrap();
+        self.value.store(Arc::new(value));
+    }
+
+    /// Replace the value with `f(current)`, so concurrent updates don't overwrite each
+    /// other. Returns the new value.
+    pub fn update(&self, f: impl FnOnce(&T) -> T) -> Arc<T> {
+        let _writer = self.writer.lock().unwrap();
+        let value = Arc::new(f(&self.value.load()));
+        self.value.store(Arc::clone(&value));
+        value
+    }
+}
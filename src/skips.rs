@@ -0,0 +1,151 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList};
+
+/// Number of samples kept across all reasons.
+const MAX_SAMPLES: usize = 32;
+/// Payload characters kept per sample.
+const PREVIEW_LEN: usize = 64;
+
+/// Why a message (or a value flattened from it) was not forwarded as received.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SkipReason {
+    /// Not UTF-8; forwarded base64 encoded
+    InvalidUtf8,
+    /// Larger than `processing.max_message_bytes`; dropped
+    Oversized,
+    /// JSON nested deeper than `processing.max_json_depth` or flattened into more than
+    /// `max_json_keys` values; dropped
+    TooComplex,
+    /// Looked like JSON but didn't parse; forwarded unexpanded
+    InvalidJson,
+    /// Didn't decompress, or exceeded `processing.max_decompressed_bytes`; forwarded as received
+    InvalidCompressed,
+    /// Didn't decode as the topic's `processing.payload_formats` entry; forwarded as received
+    InvalidBinary,
+    /// Not well-formed XML on a topic of `processing.xml_topics`; forwarded as received
+    InvalidXml,
+    /// Not `key=value` pairs on a topic of `processing.key_value_topics`; forwarded as received
+    InvalidKeyValue,
+    /// Not InfluxDB line protocol on a topic of `processing.influx_topics`; forwarded as received
+    InvalidInflux,
+    /// Topic with invalid UTF-8, wildcards or control characters; forwarded sanitized
+    InvalidTopic,
+    ScriptError,
+    PluginError,
+    TemplateError,
+}
+
+impl SkipReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            SkipReason::InvalidUtf8 => "invalid_utf8",
+            SkipReason::Oversized => "oversized",
+            SkipReason::TooComplex => "too_complex",
+            SkipReason::InvalidJson => "invalid_json",
+            SkipReason::InvalidCompressed => "invalid_compressed",
+            SkipReason::InvalidBinary => "invalid_binary",
+            SkipReason::InvalidXml => "invalid_xml",
+            SkipReason::InvalidKeyValue => "invalid_key_value",
+            SkipReason::InvalidInflux => "invalid_influx",
+            SkipReason::InvalidTopic => "invalid_topic",
+            SkipReason::ScriptError => "script_error",
+            SkipReason::PluginError => "plugin_error",
+            SkipReason::TemplateError => "template_error",
+        }
+    }
+}
+
+#[derive(Clone)]
+struct SkipSample {
+    reason: SkipReason,
+    topic: String,
+    size: usize,
+    preview: String,
+    at: f64,
+}
+
+/// Keeps only the shape of a payload: letters become `a`, digits `0`, other
+/// non-printable bytes `.`, punctuation and whitespace stay.
+fn redact(payload: &[u8]) -> String {
+    payload
+        .iter()
+        .take(PREVIEW_LEN)
+        .map(|&b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' => 'a',
+            b'0'..=b'9' => '0',
+            0x20..=0x7e => b as char,
+            _ => '.',
+        })
+        .collect()
+}
+
+/// Per-reason counters and a ring buffer of redacted samples of skipped messages.
+#[derive(Default)]
+pub struct SkipStats {
+    counts: Mutex<BTreeMap<SkipReason, u64>>,
+    samples: Mutex<VecDeque<SkipSample>>,
+}
+
+impl SkipStats {
+    pub fn record(&self, reason: SkipReason, topic: &str, payload: &[u8]) {
+        *self.counts.lock().unwrap().entry(reason).or_insert(0) += 1;
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() == MAX_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(SkipSample {
+            reason,
+            topic: topic.to_string(),
+            size: payload.len(),
+            preview: redact(payload),
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs_f64())
+                .unwrap_or_default(),
+        });
+    }
+
+    /// Counts per reason; with `reset` they are cleared under the same lock.
+    pub fn counts(&self, reset: bool) -> Vec<(&'static str, u64)> {
+        let mut counts = self.counts.lock().unwrap();
+        let out = counts.iter().map(|(reason, n)| (reason.as_str(), *n)).collect();
+        if reset {
+            counts.clear();
+        }
+        out
+    }
+
+    pub fn reset(&self) {
+        self.counts.lock().unwrap().clear();
+        self.samples.lock().unwrap().clear();
+    }
+
+    /// `{"counts": {reason: n}, "samples": [{reason, topic, size, preview, at}]}`, oldest sample first.
+    pub fn to_py<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        // Copied before creating Python objects, see LastValueStore::to_py
+        let snapshot_counts = self.counts.lock().unwrap().clone();
+        let snapshot_samples: Vec<SkipSample> = self.samples.lock().unwrap().iter().cloned().collect();
+        let counts = PyDict::new(py);
+        for (reason, count) in snapshot_counts {
+            counts.set_item(reason.as_str(), count)?;
+        }
+        let samples = PyList::empty(py);
+        for sample in snapshot_samples {
+            let entry = PyDict::new(py);
+            entry.set_item("reason", sample.reason.as_str())?;
+            entry.set_item("topic", &sample.topic)?;
+            entry.set_item("size", sample.size)?;
+            entry.set_item("preview", &sample.preview)?;
+            entry.set_item("at", sample.at)?;
+            samples.append(entry)?;
+        }
+        let out = PyDict::new(py);
+        out.set_item("counts", counts)?;
+        out.set_item("samples", samples)?;
+        Ok(out)
+    }
+}
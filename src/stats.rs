@@ -0,0 +1,179 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Map, Value};
+
+use crate::delivery::DeliveryStatus;
+
+/// A counter of `Stats`.
+#[derive(Clone, Copy, Debug)]
+pub enum Counter {
+    /// Data messages handed to the pipeline
+    Messages,
+    /// Values after JSON expansion, scripts and plugins
+    Values,
+    /// Messages or values dropped by filters, rules or the whitelist
+    Filtered,
+    /// Values suppressed by `duplicate_window_seconds` or `forward_only_changes`
+    Duplicates,
+    /// Values held back while paused
+    Held,
+    /// Values replaced by a newer one while held by `processing.rate_limit`
+    RateLimited,
+    /// Values of `topics.monitor_topics`, published instead of forwarded
+    Monitored,
+    /// Sends dropped because the send queue was full
+    QueueOverflow,
+    /// Values handed to the sender
+    Forwarded,
+    Delivered,
+    Failed,
+    Queued,
+    Unconfirmed,
+    /// Repeated attempts of failed sends (`miniserver.send_retries`)
+    Retried,
+    /// Failed sends published to the dead-letter topic
+    DeadLettered,
+    /// Failed sends kept in the outbox (`miniserver.outbox`)
+    Buffered,
+    /// Values from the outbox sent once the Miniserver was back
+    Replayed,
+    /// Inputs that got no value within `processing.stale_timeout`
+    Stale,
+}
+
+const COUNTERS: [(Counter, &str); 18] = [
+    (Counter::Messages, "messages"),
+    (Counter::Values, "values"),
+    (Counter::Filtered, "filtered"),
+    (Counter::Duplicates, "duplicates"),
+    (Counter::Held, "held"),
+    (Counter::RateLimited, "rate_limited"),
+    (Counter::Monitored, "monitored"),
+    (Counter::QueueOverflow, "queue_overflow"),
+    (Counter::Forwarded, "forwarded"),
+    (Counter::Delivered, "delivered"),
+    (Counter::Failed, "failed"),
+    (Counter::Queued, "queued"),
+    (Counter::Unconfirmed, "unconfirmed"),
+    (Counter::Retried, "retried"),
+    (Counter::DeadLettered, "dead_lettered"),
+    (Counter::Buffered, "buffered"),
+    (Counter::Replayed, "replayed"),
+    (Counter::Stale, "stale"),
+];
+
+/// Why a message or value was counted as `Counter::Filtered`.
+#[derive(Clone, Copy, Debug)]
+pub enum FilterKind {
+    /// `topics.subscription_filters`, on the original topic or the flattened key
+    Subscription,
+    PostExpansion,
+    DoNotForward,
+    /// Not in the whitelist
+    Whitelist,
+    /// Dropped by `processing.rules`
+    Rule,
+    /// Outside the `min`/`max` of a transform
+    OutOfRange,
+}
+
+const FILTER_KINDS: [(FilterKind, &str); 6] = [
+    (FilterKind::Subscription, "subscription_filter"),
+    (FilterKind::PostExpansion, "post_expansion_filter"),
+    (FilterKind::DoNotForward, "do_not_forward"),
+    (FilterKind::Whitelist, "whitelist"),
+    (FilterKind::Rule, "rule"),
+    (FilterKind::OutOfRange, "out_of_range"),
+];
+
+impl FilterKind {
+    pub fn as_str(self) -> &'static str {
+        FILTER_KINDS[self as usize].1
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        FILTER_KINDS.iter().find(|(_, kind)| *kind == name).map(|&(kind, _)| kind)
+    }
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default()
+}
+
+/// Message counters since the start or the last reset, for monitoring and daily reports.
+pub struct Stats {
+    counters: [AtomicU64; COUNTERS.len()],
+    filtered_by: [AtomicU64; FILTER_KINDS.len()],
+    /// Start of the current period
+    since: Mutex<f64>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats { counters: Default::default(), filtered_by: Default::default(), since: Mutex::new(now()) }
+    }
+}
+
+impl Stats {
+    pub fn add(&self, counter: Counter) {
+        self.counters[counter as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a filtered message or value, and why.
+    pub fn add_filtered(&self, kind: FilterKind) {
+        self.add(Counter::Filtered);
+        self.filtered_by[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count the outcome of a finished send.
+    pub fn add_outcome(&self, status: DeliveryStatus) {
+        match status {
+            DeliveryStatus::Delivered => self.add(Counter::Delivered),
+            DeliveryStatus::Failed => self.add(Counter::Failed),
+            DeliveryStatus::Queued => self.add(Counter::Queued),
+            DeliveryStatus::Unconfirmed | DeliveryStatus::Pending | DeliveryStatus::Paused => {
+                self.add(Counter::Unconfirmed)
+            }
+        }
+    }
+
+    /// Current value of every counter, by name.
+    pub fn counters(&self) -> Vec<(&'static str, u64)> {
+        COUNTERS.iter().map(|&(counter, name)| (name, self.counters[counter as usize].load(Ordering::Relaxed))).collect()
+    }
+
+    /// Current filtered count per filter kind, by name.
+    pub fn filtered_by(&self) -> Vec<(&'static str, u64)> {
+        FILTER_KINDS.iter().map(|&(kind, name)| (name, self.filtered_by[kind as usize].load(Ordering::Relaxed))).collect()
+    }
+
+    /// `{since, at, <counter>: n, ..., filtered_by: {kind: n}, skipped: {reason: n}}`. With `reset` every counter
+    /// is swapped for zero as it is read, so no event is lost or counted in two periods.
+    pub fn snapshot(&self, skipped: Vec<(&'static str, u64)>, reset: bool) -> Value {
+        let at = now();
+        let since = {
+            let mut since = self.since.lock().unwrap();
+            let start = *since;
+            if reset {
+                *since = at;
+            }
+            start
+        };
+        let read = |slot: &AtomicU64| if reset { slot.swap(0, Ordering::Relaxed) } else { slot.load(Ordering::Relaxed) };
+        let mut out = Map::new();
+        out.insert("since".to_string(), json!(since));
+        out.insert("at".to_string(), json!(at));
+        for (counter, name) in COUNTERS {
+            out.insert(name.to_string(), json!(read(&self.counters[counter as usize])));
+        }
+        let filtered_by = FILTER_KINDS.iter().map(|&(kind, name)| (name.to_string(), json!(read(&self.filtered_by[kind as usize]))));
+        out.insert("filtered_by".to_string(), Value::Object(filtered_by.collect()));
+        out.insert("skipped".to_string(), Value::Object(skipped.into_iter().map(|(r, n)| (r.to_string(), json!(n))).collect()));
+        Value::Object(out)
+    }
+}
@@ -0,0 +1,56 @@
+use std::collections::{BTreeMap, HashMap};
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use serde::Deserialize;
+
+/// Control types the Loxone Config gives virtual inputs shown in the app.
+pub const VIRTUAL_INPUT_TYPES: &[&str] =
+    &["Switch", "Pushbutton", "Slider", "TextInput", "ValueSelector", "UpDownDigital", "UpDownAnalog"];
+
+/// The part of `LoxAPP3.json` the sync reads; everything else is skipped unparsed.
+#[derive(Deserialize)]
+struct Structure {
+    #[serde(default)]
+    controls: HashMap<String, Control>,
+}
+
+#[derive(Deserialize)]
+struct Control {
+    #[serde(default)]
+    name: String,
+    #[serde(rename = "type", default)]
+    kind: String,
+    #[serde(rename = "uuidAction")]
+    uuid_action: Option<String>,
+    #[serde(rename = "subControls", default)]
+    sub_controls: HashMap<String, Control>,
+}
+
+/// Virtual input name -> control UUID of the controls of `types` (all controls if empty)
+/// in a `LoxAPP3.json` structure file, sub controls included. Of two controls with the
+/// same name the one with the smaller UUID wins, so the result doesn't depend on order.
+pub fn virtual_inputs(json: &[u8], types: &[String]) -> PyResult<BTreeMap<String, String>> {
+    let structure: Structure = serde_json::from_slice(json)
+        .map_err(|e| PyValueError::new_err(format!("Invalid LoxAPP3.json structure file: {}", e)))?;
+    let mut inputs = BTreeMap::new();
+    let mut pending: Vec<(&String, &Control)> = structure.controls.iter().collect();
+    while let Some((uuid, control)) = pending.pop() {
+        pending.extend(control.sub_controls.iter());
+        let wanted = types.is_empty() || types.iter().any(|kind| kind == &control.kind);
+        let name = control.name.trim();
+        if !wanted || name.is_empty() {
+            continue;
+        }
+        let uuid = control.uuid_action.as_ref().unwrap_or(uuid).to_lowercase();
+        inputs
+            .entry(name.to_string())
+            .and_modify(|known: &mut String| {
+                if uuid < *known {
+                    *known = uuid.clone();
+                }
+            })
+            .or_insert(uuid);
+    }
+    Ok(inputs)
+}
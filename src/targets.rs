@@ -0,0 +1,255 @@
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use log::{debug, warn};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList};
+use regex::Regex;
+use serde_json::{json, Value};
+
+use crate::config_entries::dict_entries;
+use crate::delivery::DeliveryStatus;
+use crate::http_sender::HttpSender;
+use crate::tls::TlsOptions;
+use crate::udp_forwarder::UdpForwarder;
+
+/// Route target for the Miniserver configured in `[miniserver]`.
+pub const DEFAULT_TARGET: &str = "default";
+
+/// How a target Miniserver is reached.
+enum TargetTransport {
+    /// `/dev/sps/io` requests with basic auth
+    Http(HttpSender),
+    /// Datagrams to a Virtual UDP Input
+    Udp(UdpForwarder),
+}
+
+/// A Miniserver of `miniserver.targets`, besides the one configured in `[miniserver]`.
+pub struct Target {
+    name: String,
+    host: String,
+    port: u16,
+    transport: TargetTransport,
+}
+
+impl Target {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Set virtual input `name` to `value` on this Miniserver. Datagrams aren't answered,
+    /// so one that went out is unconfirmed.
+    pub async fn send(&self, name: &str, value: &str) -> DeliveryStatus {
+        let result = match &self.transport {
+            TargetTransport::Http(sender) => sender.send(&self.host, self.port, name, value).await,
+            TargetTransport::Udp(forwarder) => forwarder.send(name, value).map(|()| 0),
+        };
+        match result {
+            Ok(200) => DeliveryStatus::Delivered,
+            // No status for a datagram
+            Ok(0) => DeliveryStatus::Unconfirmed,
+            Ok(code) => {
+                warn!("Miniserver '{}' returned {} for {}={}", self.name, code, name, value);
+                DeliveryStatus::Failed
+            }
+            Err(e) => {
+                warn!("Send of {}={} to Miniserver '{}' failed: {}", name, value, self.name, e);
+                DeliveryStatus::Failed
+            }
+        }
+    }
+}
+
+struct Route {
+    source: String,
+    pattern: Regex,
+    /// Index into `Targets::targets`; `None` for the default Miniserver
+    target: Option<usize>,
+}
+
+/// The extra Miniservers and the routes choosing between them, first match wins.
+/// Topics no route matches go to the default Miniserver.
+#[derive(Default)]
+pub struct Targets {
+    targets: Vec<Arc<Target>>,
+    routes: Vec<Route>,
+}
+
+impl Targets {
+    /// The target of the first route matching `topic`; `None` for the default Miniserver.
+    pub fn route(&self, topic: &str) -> Option<&Arc<Target>> {
+        let route = self.routes.iter().find(|route| route.pattern.is_match(topic))?;
+        debug!("Topic '{}' routed by '{}'", topic, route.source);
+        route.target.map(|index| &self.targets[index])
+    }
+
+    /// The target name for `topic`, for `test_filters`.
+    pub fn route_name(&self, topic: &str) -> &str {
+        self.route(topic).map(|target| target.name()).unwrap_or(DEFAULT_TARGET)
+    }
+
+    /// The targets without their credentials, and the routes.
+    pub fn to_py<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let targets = PyList::empty(py);
+        for target in &self.targets {
+            let dict = PyDict::new(py);
+            dict.set_item("name", &target.name)?;
+            dict.set_item("ip", &target.host)?;
+            dict.set_item("port", target.port)?;
+            dict.set_item("transport", match target.transport {
+                TargetTransport::Http(_) => "http",
+                TargetTransport::Udp(_) => "udp",
+            })?;
+            targets.append(dict)?;
+        }
+        let routes = PyList::empty(py);
+        for route in &self.routes {
+            let dict = PyDict::new(py);
+            dict.set_item("match", &route.source)?;
+            dict.set_item("target", route.target.map(|index| self.targets[index].name.as_str()).unwrap_or(DEFAULT_TARGET))?;
+            routes.append(dict)?;
+        }
+        let result = PyDict::new(py);
+        result.set_item("targets", targets)?;
+        result.set_item("routes", routes)?;
+        Ok(result)
+    }
+}
+
+fn required<'py>(dict: &Bound<'py, PyDict>, key: &str, what: &str) -> PyResult<Bound<'py, PyAny>> {
+    dict.get_item(key)?
+        .filter(|v| !v.is_none())
+        .ok_or_else(|| PyValueError::new_err(format!("{} is missing '{}'", what, key)))
+}
+
+/// Build `Targets` from `miniserver.targets` (`{"name", "ip", "port", "user", "pass",
+/// "transport": "http" | "udp"}`, for HTTPS `"tls": true` with optional `"ca_file"`,
+/// `"cert_file"`, `"key_file"` and `"insecure"`) and `miniserver.routes` (`{"match": regex, "target": name}`,
+/// with `"default"` for the Miniserver in `[miniserver]`). Each HTTP target opens up to
+/// `max_parallel` connections. Any mistake raises a `ValueError`.
+pub fn parse_targets(targets: &Bound<'_, PyAny>, routes: &Bound<'_, PyAny>, max_parallel: usize) -> PyResult<Targets> {
+    let mut parsed: Vec<Arc<Target>> = Vec::new();
+    let mut names = HashSet::new();
+    for (index, dict) in dict_entries(targets, "Miniserver target")?.iter().enumerate() {
+        let what = format!("Miniserver target {}", index);
+        let name: String = required(dict, "name", &what)?.extract()?;
+        if name.is_empty() || name == DEFAULT_TARGET || !names.insert(name.clone()) {
+            return Err(PyValueError::new_err(format!(
+                "{} has the name '{}', which is empty, reserved or already used",
+                what, name
+            )));
+        }
+        let host: String = required(dict, "ip", &what)?.extract()?;
+        let flag = |key: &str| -> PyResult<bool> {
+            Ok(match dict.get_item(key)? {
+                Some(v) if !v.is_none() => v.extract()?,
+                _ => false,
+            })
+        };
+        let tls = flag("tls")?;
+        let transport = match dict.get_item("transport")? {
+            Some(v) if !v.is_none() => v.extract::<String>()?.trim().to_lowercase(),
+            _ => "http".to_string(),
+        };
+        let port: u16 = match dict.get_item("port")? {
+            Some(v) if !v.is_none() => v.extract()?,
+            _ if transport == "http" && tls => 443,
+            _ if transport == "http" => 80,
+            _ => return Err(PyValueError::new_err(format!("{} sends over UDP and needs a 'port'", what))),
+        };
+        let text = |key: &str| -> PyResult<String> {
+            Ok(match dict.get_item(key)? {
+                Some(v) if !v.is_none() => v.extract()?,
+                _ => String::new(),
+            })
+        };
+        let transport = match transport.as_str() {
+            "http" => {
+                let tls = match tls {
+                    true => Some(
+                        TlsOptions {
+                            ca_file: text("ca_file")?,
+                            cert_file: text("cert_file")?,
+                            key_file: text("key_file")?,
+                            insecure: flag("insecure")?,
+                        }
+                        .client_config()
+                        .map_err(|e| PyValueError::new_err(format!("{}: {}", what, e)))?,
+                    ),
+                    false => None,
+                };
+                TargetTransport::Http(HttpSender::new(&text("user")?, &text("pass")?, max_parallel, tls))
+            }
+            "udp" if tls => return Err(PyValueError::new_err(format!("{} sends over UDP, which has no TLS", what))),
+            "udp" => {
+                let destination = if host.contains(':') { format!("[{}]:{}", host, port) } else { format!("{}:{}", host, port) };
+                TargetTransport::Udp(UdpForwarder::new(&[destination])?)
+            }
+            other => {
+                return Err(PyValueError::new_err(format!(
+                    "{} has the invalid transport '{}': expected 'http' or 'udp'",
+                    what, other
+                )))
+            }
+        };
+        parsed.push(Arc::new(Target { name, host, port, transport }));
+    }
+    let mut parsed_routes = Vec::new();
+    for (index, dict) in dict_entries(routes, "Route")?.iter().enumerate() {
+        let what = format!("Route {}", index);
+        let source: String = required(dict, "match", &what)?.extract()?;
+        let name: String = required(dict, "target", &what)?.extract()?;
+        let target = match parsed.iter().position(|target| target.name == name) {
+            Some(index) => Some(index),
+            None if name == DEFAULT_TARGET => None,
+            None => return Err(PyValueError::new_err(format!("{} goes to the unknown target '{}'", what, name))),
+        };
+        let pattern = Regex::new(&source)
+            .map_err(|e| PyValueError::new_err(format!("{} has the invalid pattern '{}': {}", what, source, e)))?;
+        parsed_routes.push(Route { source, pattern, target });
+    }
+    debug!("Configured {} Miniserver targets and {} routes", parsed.len(), parsed_routes.len());
+    Ok(Targets { targets: parsed, routes: parsed_routes })
+}
+
+/// Sends per target, kept across updates of the targets: forwarded, delivered, failed
+/// and unconfirmed.
+#[derive(Default)]
+pub struct TargetStats {
+    counts: Mutex<BTreeMap<String, [u64; 4]>>,
+}
+
+impl TargetStats {
+    pub fn add_forwarded(&self, target: &str) {
+        self.counts.lock().unwrap().entry(target.to_string()).or_default()[0] += 1;
+    }
+
+    pub fn add_outcome(&self, target: &str, status: DeliveryStatus) {
+        let slot = match status {
+            DeliveryStatus::Delivered => 1,
+            DeliveryStatus::Failed => 2,
+            _ => 3,
+        };
+        self.counts.lock().unwrap().entry(target.to_string()).or_default()[slot] += 1;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.lock().unwrap().is_empty()
+    }
+
+    /// `{target: {forwarded, delivered, failed, unconfirmed}}`; with `reset` the counts start over.
+    pub fn snapshot(&self, reset: bool) -> Value {
+        let mut counts = self.counts.lock().unwrap();
+        let snapshot = counts
+            .iter()
+            .map(|(name, [forwarded, delivered, failed, unconfirmed])| {
+                (name.clone(), json!({"forwarded": forwarded, "delivered": delivered, "failed": failed, "unconfirmed": unconfirmed}))
+            })
+            .collect();
+        if reset {
+            counts.clear();
+        }
+        Value::Object(snapshot)
+    }
+}
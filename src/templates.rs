@@ -0,0 +1,111 @@
+use handlebars::{Context, Handlebars};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use serde_json::{json, Map, Value};
+
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::debug;
+
+use crate::flatten::{flatten_json, FlattenOptions};
+
+/// Destinations an outgoing payload template can be attached to: values sent to the
+/// Miniserver over HTTP or the websocket, values sent as UDP datagrams, and messages the
+/// Miniserver publishes through the UDP input.
+pub const DESTINATIONS: &[&str] = &["miniserver", "udp", "mqtt"];
+
+/// Handlebars templates rendering outgoing payloads, keyed by destination.
+///
+/// Templates see `topic`, `normalized_topic`, `value`, `json` (the parsed message, or null),
+/// `fields` (the flattened JSON message, e.g. `{{lookup fields "state/temp"}}`), `timestamp`
+/// (unix seconds) and `timestamp_ms`.
+pub struct PayloadTemplates {
+    registry: Handlebars<'static>,
+    /// `processing.flatten_*`, so `fields` is keyed like the expanded topics
+    flatten: FlattenOptions,
+}
+
+/// The template context of one message, parsed and flattened on the first render and
+/// reused for each of its values.
+pub struct MessageContext<'a> {
+    message: &'a str,
+    context: Option<Context>,
+}
+
+impl<'a> MessageContext<'a> {
+    pub fn new(message: &'a str) -> Self {
+        MessageContext { message, context: None }
+    }
+}
+
+impl PayloadTemplates {
+    pub fn has(&self, destination: &str) -> bool {
+        self.registry.has_template(destination)
+    }
+
+    /// Render the template for `destination`, or `None` if it has none.
+    pub fn render(
+        &self,
+        destination: &str,
+        message: &mut MessageContext<'_>,
+        topic: &str,
+        normalized_topic: &str,
+        value: &str,
+    ) -> Option<Result<String, String>> {
+        if !self.has(destination) {
+            return None;
+        }
+        let context = message.context.get_or_insert_with(|| Context::from(self.message_json(message.message)));
+        if let Value::Object(data) = context.data_mut() {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            data.insert("topic".to_string(), json!(topic));
+            data.insert("normalized_topic".to_string(), json!(normalized_topic));
+            data.insert("value".to_string(), json!(value));
+            data.insert("timestamp".to_string(), json!(now.as_secs()));
+            data.insert("timestamp_ms".to_string(), json!(now.as_millis() as u64));
+        }
+        let rendered = self
+            .registry
+            .render_with_context(destination, context)
+            .map_err(|e| format!("template '{}' failed: {}", destination, e));
+        debug!("Rendered '{}' payload for topic '{}': {:?}", destination, topic, rendered);
+        Some(rendered)
+    }
+
+    /// `json` and `fields` of the context; the per-value keys are set by `render`.
+    fn message_json(&self, message: &str) -> Value {
+        let parsed = serde_json::from_str::<Value>(message).ok();
+        let mut fields = Map::new();
+        if let Some(json_val) = parsed.as_ref().filter(|v| v.is_object() || v.is_array()) {
+            let mut flat = Vec::new();
+            flatten_json(json_val, "", &self.flatten, &mut flat);
+            fields.extend(flat.into_iter().map(|(k, v)| (k, Value::String(v))));
+        }
+        json!({
+            "json": parsed.unwrap_or(Value::Null),
+            "fields": fields,
+        })
+    }
+}
+
+/// Compile the configured `{destination: template}` map. Unknown destinations and
+/// templates that don't parse raise a `ValueError`.
+pub fn parse_templates(templates: HashMap<String, String>, flatten: FlattenOptions) -> PyResult<PayloadTemplates> {
+    let mut registry = Handlebars::new();
+    // Payloads aren't HTML, keep values as they are
+    registry.register_escape_fn(handlebars::no_escape);
+    for (destination, template) in templates {
+        if !DESTINATIONS.contains(&destination.as_str()) {
+            return Err(PyValueError::new_err(format!(
+                "Unknown payload template destination '{}': expected one of {}",
+                destination,
+                DESTINATIONS.join(", ")
+            )));
+        }
+        registry.register_template_string(&destination, &template).map_err(|e| {
+            PyValueError::new_err(format!("Invalid payload template for '{}': {}", destination, e))
+        })?;
+    }
+    Ok(PayloadTemplates { registry, flatten })
+}
@@ -0,0 +1,190 @@
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList};
+use regex::RegexSet;
+
+use log::debug;
+
+use crate::config_entries::dict_entries;
+
+/// 2009-01-01T00:00:00Z, the start of the Loxone epoch, in Unix seconds.
+const LOXONE_EPOCH: i64 = 1_230_768_000;
+/// Numbers from here on are taken as milliseconds: in seconds that is the year 5138.
+const MILLIS_THRESHOLD: f64 = 1e11;
+
+/// What a converted timestamp is counted from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Epoch {
+    /// Seconds since 2009-01-01, as the Miniserver counts time
+    Loxone,
+    Unix,
+}
+
+impl Epoch {
+    fn parse(value: &str) -> PyResult<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "" | "loxone" => Ok(Epoch::Loxone),
+            "unix" => Ok(Epoch::Unix),
+            other => Err(PyValueError::new_err(format!(
+                "Invalid timestamp epoch '{}': expected 'loxone' or 'unix'",
+                other
+            ))),
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Epoch::Loxone => "loxone",
+            Epoch::Unix => "unix",
+        }
+    }
+}
+
+/// A conversion of the timestamps of matching topics (`processing.timestamps`).
+#[derive(Clone, Debug)]
+struct TimestampRule {
+    source: String,
+    epoch: Epoch,
+    /// Added to the result, e.g. to get the Miniserver's local time
+    utc_offset_minutes: i64,
+}
+
+/// Ordered timestamp rules; the first whose pattern matches a topic applies.
+#[derive(Clone, Debug)]
+pub struct TimestampRules {
+    patterns: RegexSet,
+    rules: Vec<TimestampRule>,
+}
+
+impl Default for TimestampRules {
+    fn default() -> Self {
+        TimestampRules { patterns: RegexSet::empty(), rules: Vec::new() }
+    }
+}
+
+impl TimestampRules {
+    /// Whole seconds in the rule's epoch, or `None` if no rule matches `topic` or `value`
+    /// isn't a timestamp.
+    pub fn apply(&self, topic: &str, value: &str) -> Option<String> {
+        if self.rules.is_empty() {
+            return None;
+        }
+        let rule = &self.rules[self.patterns.matches(topic).iter().next()?];
+        let Some(unix) = parse_timestamp(value) else {
+            debug!("{}={} isn't a timestamp, forwarding it as it is", topic, value);
+            return None;
+        };
+        let mut seconds = unix + rule.utc_offset_minutes * 60;
+        if rule.epoch == Epoch::Loxone {
+            seconds -= LOXONE_EPOCH;
+        }
+        debug!("Converted timestamp {}={} to {} ('{}')", topic, value, seconds, rule.source);
+        Some(seconds.to_string())
+    }
+
+    /// Convert the rules back into the dict form they were configured with.
+    pub fn to_py<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyList>> {
+        let list = PyList::empty(py);
+        for rule in &self.rules {
+            let dict = PyDict::new(py);
+            dict.set_item("pattern", &rule.source)?;
+            dict.set_item("epoch", rule.epoch.as_str())?;
+            if rule.utc_offset_minutes != 0 {
+                dict.set_item("utc_offset_minutes", rule.utc_offset_minutes)?;
+            }
+            list.append(dict)?;
+        }
+        Ok(list)
+    }
+}
+
+/// Unix seconds of an RFC 3339 / ISO 8601 date and time (`2024-05-01T12:00:00.5+02:00`,
+/// a space instead of `T`, UTC without an offset) or of a number of seconds or
+/// milliseconds since 1970. Fractions of a second are dropped.
+fn parse_timestamp(value: &str) -> Option<i64> {
+    let value = value.trim().trim_matches('"');
+    if let Ok(number) = value.parse::<f64>() {
+        if !number.is_finite() {
+            return None;
+        }
+        let seconds = if number.abs() >= MILLIS_THRESHOLD { number / 1000.0 } else { number };
+        return Some(seconds.floor() as i64);
+    }
+    parse_rfc3339(value)
+}
+
+fn parse_rfc3339(value: &str) -> Option<i64> {
+    let bytes = value.as_bytes();
+    let digits = |range: std::ops::Range<usize>| -> Option<i64> {
+        let part = value.get(range)?;
+        part.bytes().all(|b| b.is_ascii_digit()).then(|| part.parse().ok())?
+    };
+    if bytes.len() < 19 || bytes[4] != b'-' || bytes[7] != b'-' || !matches!(bytes[10], b'T' | b't' | b' ') || bytes[13] != b':' || bytes[16] != b':' {
+        return None;
+    }
+    let (year, month, day) = (digits(0..4)?, digits(5..7)?, digits(8..10)?);
+    let (hour, minute, second) = (digits(11..13)?, digits(14..16)?, digits(17..19)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let mut rest = &value[19..];
+    if let Some(fraction) = rest.strip_prefix(['.', ',']) {
+        let len = fraction.bytes().take_while(u8::is_ascii_digit).count();
+        if len == 0 {
+            return None;
+        }
+        rest = &fraction[len..];
+    }
+    let offset = match rest {
+        "" | "Z" | "z" => 0,
+        _ => {
+            let sign = match rest.as_bytes()[0] {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return None,
+            };
+            let offset = rest[1..].replace(':', "");
+            if offset.len() != 4 || !offset.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            sign * (offset[..2].parse::<i64>().ok()? * 3600 + offset[2..].parse::<i64>().ok()? * 60)
+        }
+    };
+    Some(days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second - offset)
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Build `TimestampRules` from a list of `{"pattern": ..., "epoch": ..., "utc_offset_minutes": ...}`
+/// dicts. Any invalid entry, including an invalid regex, raises a `ValueError`.
+pub fn parse_timestamp_rules(entries: &Bound<'_, PyAny>) -> PyResult<TimestampRules> {
+    let mut rules = Vec::new();
+    for (index, entry) in dict_entries(entries, "Timestamp rule")?.iter().enumerate() {
+        let Some(source) = entry.get_item("pattern")? else {
+            return Err(PyValueError::new_err(format!("Timestamp rule {} is missing the 'pattern'", index)));
+        };
+        rules.push(TimestampRule {
+            source: source.extract()?,
+            epoch: match entry.get_item("epoch")? {
+                Some(v) if !v.is_none() => Epoch::parse(&v.extract::<String>()?)?,
+                _ => Epoch::Loxone,
+            },
+            utc_offset_minutes: match entry.get_item("utc_offset_minutes")? {
+                Some(v) if !v.is_none() => v.extract()?,
+                _ => 0,
+            },
+        });
+    }
+    let patterns = RegexSet::new(rules.iter().map(|r| r.source.as_str()))
+        .map_err(|e| PyValueError::new_err(format!("Invalid timestamp pattern: {}", e)))?;
+    debug!("Compiled {} timestamp rules", rules.len());
+    Ok(TimestampRules { patterns, rules })
+}
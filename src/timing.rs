@@ -0,0 +1,157 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Instant;
+
+use serde_json::{json, Map, Value};
+
+/// A pipeline stage timed by `StageTimings`.
+#[derive(Clone, Copy, Debug)]
+pub enum Stage {
+    /// UTF-8 decoding of the payload
+    Decode,
+    /// Parsing the payload as JSON
+    Parse,
+    /// Flattening parsed JSON into topic/value pairs
+    Flatten,
+    /// Rules, whitelist, subscription filters and do_not_forward, per value
+    Filter,
+    /// Boolean conversion and payload templates, per value
+    Convert,
+    /// Handing a value to the sender (not the HTTP/websocket round trip)
+    Dispatch,
+    /// All of the above for one data message, from the decoded payload to the last
+    /// value handed to the sender
+    Message,
+}
+
+const STAGES: [(Stage, &str); 7] = [
+    (Stage::Decode, "decode"),
+    (Stage::Parse, "parse"),
+    (Stage::Flatten, "flatten"),
+    (Stage::Filter, "filter"),
+    (Stage::Convert, "convert"),
+    (Stage::Dispatch, "dispatch"),
+    (Stage::Message, "message"),
+];
+
+/// Percentiles estimated from the buckets of each histogram.
+const PERCENTILES: [(f64, &str); 3] = [(0.5, "p50_us"), (0.9, "p90_us"), (0.99, "p99_us")];
+
+/// Upper bounds of the histogram buckets in microseconds; one more bucket takes the rest.
+const BUCKETS_US: [u64; 12] = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1_000, 5_000, 10_000];
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS_US.len() + 1],
+    count: AtomicU64,
+    total_ns: AtomicU64,
+    max_ns: AtomicU64,
+}
+
+impl Histogram {
+    fn record(&self, ns: u64) {
+        let bucket = BUCKETS_US.iter().position(|&bound| ns <= bound * 1_000).unwrap_or(BUCKETS_US.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_ns.fetch_add(ns, Ordering::Relaxed);
+        self.max_ns.fetch_max(ns, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self, reset: bool) -> Value {
+        let read = |slot: &AtomicU64| if reset { slot.swap(0, Ordering::Relaxed) } else { slot.load(Ordering::Relaxed) };
+        let count = read(&self.count);
+        let total_ns = read(&self.total_ns);
+        let max_ns = read(&self.max_ns);
+        let counts: Vec<u64> = self.buckets.iter().map(read).collect();
+        let buckets: Vec<Value> =
+            counts.iter().enumerate().map(|(i, n)| json!({"le_us": BUCKETS_US.get(i), "count": n})).collect();
+        let mut out = json!({
+            "count": count,
+            "mean_us": if count > 0 { total_ns as f64 / count as f64 / 1_000.0 } else { 0.0 },
+            "max_us": max_ns as f64 / 1_000.0,
+            "buckets": buckets,
+        });
+        for (quantile, name) in PERCENTILES {
+            out[name] = json!(percentile(&counts, quantile, max_ns as f64 / 1_000.0));
+        }
+        out
+    }
+}
+
+/// The upper bound of the bucket holding the `quantile` of the values, capped at the
+/// largest value seen (which also stands in for the open last bucket). 0 without values.
+fn percentile(counts: &[u64], quantile: f64, max_us: f64) -> f64 {
+    let total: u64 = counts.iter().sum();
+    if total == 0 {
+        return 0.0;
+    }
+    let rank = ((total as f64 * quantile).ceil() as u64).max(1);
+    let mut seen = 0;
+    for (i, &n) in counts.iter().enumerate() {
+        seen += n;
+        if seen >= rank {
+            return BUCKETS_US.get(i).map_or(max_us, |&bound| (bound as f64).min(max_us));
+        }
+    }
+    max_us
+}
+
+/// Opt-in duration histograms per pipeline stage, to see which stage to tune on slow
+/// hardware. While disabled, `start` returns `None` and nothing is measured.
+#[derive(Default)]
+pub struct StageTimings {
+    enabled: AtomicBool,
+    stages: [Histogram; STAGES.len()],
+}
+
+impl StageTimings {
+    pub fn new(enabled: bool) -> Self {
+        StageTimings { enabled: AtomicBool::new(enabled), ..Default::default() }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// The start of a measurement, if timing is on.
+    pub fn start(&self) -> Option<Instant> {
+        self.enabled().then(Instant::now)
+    }
+
+    /// Record the time since `start` for `stage`.
+    pub fn record(&self, stage: Stage, start: Option<Instant>) {
+        if let Some(start) = start {
+            self.stages[stage as usize].record(start.elapsed().as_nanos() as u64);
+        }
+    }
+
+    /// A measurement of `stage` that is recorded when dropped, for code with early returns.
+    pub fn time(&self, stage: Stage) -> StageTimer<'_> {
+        StageTimer { timings: self, stage, start: self.start() }
+    }
+
+    /// `{stage: {count, mean_us, max_us, p50_us, p90_us, p99_us, buckets: [{le_us, count}, ...]}}`,
+    /// the last bucket with `le_us: null`. With `reset` the histograms are cleared as they are read.
+    pub fn snapshot(&self, reset: bool) -> Value {
+        let mut out = Map::new();
+        for (stage, name) in STAGES {
+            out.insert(name.to_string(), self.stages[stage as usize].snapshot(reset));
+        }
+        Value::Object(out)
+    }
+}
+
+pub struct StageTimer<'a> {
+    timings: &'a StageTimings,
+    stage: Stage,
+    start: Option<Instant>,
+}
+
+impl Drop for StageTimer<'_> {
+    fn drop(&mut self) {
+        self.timings.record(self.stage, self.start);
+    }
+}
@@ -0,0 +1,168 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use log::warn;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use tokio_rustls::rustls::{ClientConfig, DigitallySignedStruct, Error, RootCertStore, SignatureScheme};
+use tokio_rustls::TlsConnector;
+
+/// TLS settings of a broker or Miniserver connection. Empty files mean the bundled
+/// webpki roots and no client certificate.
+#[derive(Clone, Debug, Default)]
+pub struct TlsOptions {
+    /// PEM bundle replacing the webpki roots
+    pub ca_file: String,
+    /// PEM certificate chain and private key for mutual TLS, both or neither
+    pub cert_file: String,
+    pub key_file: String,
+    /// Accept any server certificate, e.g. a Miniserver's self-signed one
+    pub insecure: bool,
+}
+
+impl TlsOptions {
+    /// The rustls config for these options; unreadable files or a key that doesn't
+    /// fit raise a `ValueError`.
+    pub fn client_config(&self) -> PyResult<Arc<ClientConfig>> {
+        let provider = Arc::new(ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| PyValueError::new_err(format!("TLS setup failed: {}", e)))?;
+        let builder = if self.insecure {
+            warn!("TLS certificate verification is disabled");
+            builder.dangerous().with_custom_certificate_verifier(Arc::new(NoVerification(provider)))
+        } else {
+            let mut roots = RootCertStore::empty();
+            if self.ca_file.is_empty() {
+                roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            } else {
+                let (added, _) = roots.add_parsable_certificates(read_certs(&self.ca_file, "CA file")?);
+                if added == 0 {
+                    return Err(PyValueError::new_err(format!("The CA file '{}' holds no certificate", self.ca_file)));
+                }
+            }
+            builder.with_root_certificates(roots)
+        };
+        let config = match (self.cert_file.is_empty(), self.key_file.is_empty()) {
+            (true, true) => builder.with_no_client_auth(),
+            (false, false) => {
+                let key = PrivateKeyDer::from_pem_file(&self.key_file)
+                    .map_err(|e| PyValueError::new_err(format!("Can't read the key file '{}': {}", self.key_file, e)))?;
+                builder
+                    .with_client_auth_cert(read_certs(&self.cert_file, "certificate file")?, key)
+                    .map_err(|e| PyValueError::new_err(format!("Invalid client certificate '{}': {}", self.cert_file, e)))?
+            }
+            _ => return Err(PyValueError::new_err("A client certificate needs both a cert_file and a key_file")),
+        };
+        Ok(Arc::new(config))
+    }
+}
+
+fn read_certs(path: &str, what: &str) -> PyResult<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| PyValueError::new_err(format!("Can't read the {} '{}': {}", what, path, e)))?;
+    if certs.is_empty() {
+        return Err(PyValueError::new_err(format!("The {} '{}' holds no certificate", what, path)));
+    }
+    Ok(certs)
+}
+
+/// `insecure`: any certificate passes, the handshake signatures are still checked.
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// A TCP connection, with TLS on top if configured.
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+/// Connect to `host:port`, with a TLS handshake when `tls` is given. The certificate
+/// has to be issued for `host`, a name or an IP address.
+pub async fn connect(host: &str, port: u16, tls: Option<&Arc<ClientConfig>>) -> io::Result<Stream> {
+    let stream = TcpStream::connect((host, port)).await?;
+    stream.set_nodelay(true)?;
+    let Some(config) = tls else {
+        return Ok(Stream::Plain(stream));
+    };
+    let name = ServerName::try_from(host.to_string()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let stream = TlsConnector::from(config.clone()).connect(name, stream).await?;
+    Ok(Stream::Tls(Box::new(stream)))
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
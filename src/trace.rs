@@ -0,0 +1,143 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use serde_json::{json, Value};
+
+/// Payloads are kept up to this many bytes in a trace.
+const MAX_PAYLOAD_BYTES: usize = 256;
+
+/// What happened to a value of a traced message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// Handed to the sender
+    Forwarded,
+    /// Added to a batch, sent with it
+    Batched,
+    /// Held by the rate limiter, sent once its interval is over
+    RateLimited,
+    /// Held back while forwarding is paused
+    Paused,
+    /// Published to `{base_topic}monitored/`, never sent to the Miniserver
+    Monitored,
+    /// Dropped by a filter, the whitelist, a rule or a transform's range
+    Filtered,
+    /// Not sent because the Miniserver already has this value
+    Duplicate,
+    /// Not sent because of an error, e.g. in a template
+    Skipped,
+}
+
+impl Outcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Forwarded => "forwarded",
+            Outcome::Batched => "batched",
+            Outcome::RateLimited => "rate_limited",
+            Outcome::Paused => "paused",
+            Outcome::Monitored => "monitored",
+            Outcome::Filtered => "filtered",
+            Outcome::Duplicate => "duplicate",
+            Outcome::Skipped => "skipped",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ValueTrace {
+    /// The flattened topic
+    pub topic: String,
+    /// Virtual input name
+    pub name: String,
+    /// The value as sent, or as it was when it was dropped
+    pub value: String,
+    pub outcome: Outcome,
+    /// Which filter, rule or check decided, e.g. `do_not_forward #1 '^dnf/'`
+    pub reason: Option<String>,
+}
+
+/// The path of one data message through the pipeline.
+#[derive(Clone, Debug)]
+pub struct MessageTrace {
+    pub at: f64,
+    pub topic: String,
+    pub payload: String,
+    /// Why the whole message was dropped before it was split into values
+    pub dropped: Option<String>,
+    pub values: Vec<ValueTrace>,
+}
+
+impl MessageTrace {
+    pub fn new(at: f64, topic: &str, payload: &str) -> Self {
+        let mut end = payload.len().min(MAX_PAYLOAD_BYTES);
+        while !payload.is_char_boundary(end) {
+            end -= 1;
+        }
+        MessageTrace { at, topic: topic.to_string(), payload: payload[..end].to_string(), dropped: None, values: Vec::new() }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let values: Vec<Value> = self
+            .values
+            .iter()
+            .map(|value| {
+                json!({
+                    "topic": value.topic,
+                    "name": value.name,
+                    "value": value.value,
+                    "outcome": value.outcome.as_str(),
+                    "reason": value.reason,
+                })
+            })
+            .collect();
+        json!({
+            "at": self.at,
+            "topic": self.topic,
+            "payload": self.payload,
+            "dropped": self.dropped,
+            "values": values,
+        })
+    }
+}
+
+/// The traces of the last `debug.trace_messages` data messages.
+pub struct TraceLog {
+    size: usize,
+    traces: Mutex<VecDeque<MessageTrace>>,
+}
+
+impl TraceLog {
+    pub fn new(size: usize) -> Self {
+        TraceLog { size, traces: Mutex::new(VecDeque::with_capacity(size.min(1024))) }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.size > 0
+    }
+
+    pub fn push(&self, trace: MessageTrace) {
+        if self.size == 0 {
+            return;
+        }
+        let mut traces = self.traces.lock().unwrap();
+        if traces.len() >= self.size {
+            traces.pop_front();
+        }
+        traces.push_back(trace);
+    }
+
+    /// The kept traces, oldest first; with `topic` only those of messages on that topic
+    /// or with a value of that topic or virtual input name.
+    pub fn snapshot(&self, topic: Option<&str>) -> Value {
+        let traces = self.traces.lock().unwrap();
+        let matching = traces.iter().filter(|trace| {
+            topic.is_none_or(|topic| {
+                trace.topic == topic || trace.values.iter().any(|value| value.topic == topic || value.name == topic)
+            })
+        });
+        Value::Array(matching.map(MessageTrace::to_json).collect())
+    }
+
+    pub fn clear(&self) {
+        self.traces.lock().unwrap().clear();
+    }
+}
@@ -0,0 +1,199 @@
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList};
+use regex::RegexSet;
+
+use log::debug;
+
+use crate::config_entries::dict_entries;
+
+/// Built-in unit conversions as `(name, factor, offset)`: `y = factor * x + offset`.
+const CONVERSIONS: [(&str, f64, f64); 22] = [
+    ("f_to_c", 5.0 / 9.0, -160.0 / 9.0),
+    ("c_to_f", 9.0 / 5.0, 32.0),
+    ("k_to_c", 1.0, -273.15),
+    ("c_to_k", 1.0, 273.15),
+    ("mph_to_kmh", 1.609344, 0.0),
+    ("kmh_to_mph", 1.0 / 1.609344, 0.0),
+    ("ms_to_kmh", 3.6, 0.0),
+    ("kmh_to_ms", 1.0 / 3.6, 0.0),
+    ("knots_to_kmh", 1.852, 0.0),
+    ("mi_to_km", 1.609344, 0.0),
+    ("in_to_mm", 25.4, 0.0),
+    ("inhg_to_hpa", 33.863_886_666_7, 0.0),
+    ("psi_to_bar", 0.068_947_572_9, 0.0),
+    ("gal_to_l", 3.785_411_784, 0.0),
+    ("lb_to_kg", 0.453_592_37, 0.0),
+    ("w_to_kw", 0.001, 0.0),
+    ("kw_to_w", 1000.0, 0.0),
+    ("wh_to_kwh", 0.001, 0.0),
+    ("kwh_to_wh", 1000.0, 0.0),
+    ("pa_to_hpa", 0.01, 0.0),
+    ("mbar_to_hpa", 1.0, 0.0),
+    ("fraction_to_percent", 100.0, 0.0),
+];
+
+/// Numeric adjustments for the values of matching topics (`processing.transforms`).
+#[derive(Clone, Debug)]
+struct Transform {
+    source: String,
+    /// Index into `CONVERSIONS`, applied before `scale` and `offset`
+    convert: Option<usize>,
+    scale: f64,
+    offset: f64,
+    /// Decimal places of the result; unrounded if unset
+    precision: Option<usize>,
+    min: Option<f64>,
+    max: Option<f64>,
+    /// Clamp values outside `min`/`max` instead of dropping them
+    clamp: bool,
+}
+
+/// The outcome of transforming one value.
+#[derive(Debug, PartialEq)]
+pub enum Transformed {
+    /// No transform matches, or the value isn't a number
+    Unchanged,
+    Value(String),
+    /// Outside `min`/`max` without `clamp`
+    OutOfRange(f64),
+}
+
+/// Ordered transforms; the first whose pattern matches a topic applies.
+#[derive(Clone, Debug)]
+pub struct TransformSet {
+    patterns: RegexSet,
+    transforms: Vec<Transform>,
+}
+
+impl Default for TransformSet {
+    fn default() -> Self {
+        TransformSet { patterns: RegexSet::empty(), transforms: Vec::new() }
+    }
+}
+
+impl TransformSet {
+    pub fn apply(&self, topic: &str, value: &str) -> Transformed {
+        if self.transforms.is_empty() {
+            return Transformed::Unchanged;
+        }
+        let Some(index) = self.patterns.matches(topic).iter().next() else {
+            return Transformed::Unchanged;
+        };
+        let Ok(number) = value.trim().parse::<f64>() else {
+            return Transformed::Unchanged;
+        };
+        if !number.is_finite() {
+            return Transformed::Unchanged;
+        }
+        let transform = &self.transforms[index];
+        let converted = match transform.convert {
+            Some(index) => number * CONVERSIONS[index].1 + CONVERSIONS[index].2,
+            None => number,
+        };
+        let mut result = converted * transform.scale + transform.offset;
+        let below = transform.min.is_some_and(|min| result < min);
+        let above = transform.max.is_some_and(|max| result > max);
+        if below || above {
+            if !transform.clamp {
+                return Transformed::OutOfRange(result);
+            }
+            result = if below { transform.min.unwrap() } else { transform.max.unwrap() };
+        }
+        debug!("Transformed {}={} to {} ('{}')", topic, value, result, transform.source);
+        Transformed::Value(match transform.precision {
+            Some(precision) => format!("{:.*}", precision, result),
+            None => result.to_string(),
+        })
+    }
+
+    /// Convert the transforms back into the dict form they were configured with.
+    pub fn to_py<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyList>> {
+        let list = PyList::empty(py);
+        for transform in &self.transforms {
+            let dict = PyDict::new(py);
+            dict.set_item("pattern", &transform.source)?;
+            if let Some(index) = transform.convert {
+                dict.set_item("convert", CONVERSIONS[index].0)?;
+            }
+            if transform.scale != 1.0 {
+                dict.set_item("scale", transform.scale)?;
+            }
+            if transform.offset != 0.0 {
+                dict.set_item("offset", transform.offset)?;
+            }
+            if let Some(precision) = transform.precision {
+                dict.set_item("precision", precision)?;
+            }
+            if let Some(min) = transform.min {
+                dict.set_item("min", min)?;
+            }
+            if let Some(max) = transform.max {
+                dict.set_item("max", max)?;
+            }
+            if transform.clamp {
+                dict.set_item("clamp", true)?;
+            }
+            list.append(dict)?;
+        }
+        Ok(list)
+    }
+}
+
+fn number(entry: &Bound<'_, PyDict>, key: &str) -> PyResult<Option<f64>> {
+    match entry.get_item(key)? {
+        Some(v) if !v.is_none() => Ok(Some(v.extract()?)),
+        _ => Ok(None),
+    }
+}
+
+/// The index of the unit conversion `name` in `CONVERSIONS`.
+fn conversion(index: usize, name: &str) -> PyResult<usize> {
+    let name = name.trim().to_lowercase();
+    CONVERSIONS.iter().position(|(known, _, _)| *known == name).ok_or_else(|| {
+        let known: Vec<&str> = CONVERSIONS.iter().map(|(known, _, _)| *known).collect();
+        PyValueError::new_err(format!("Transform {} has the unknown conversion '{}': expected one of {}", index, name, known.join(", ")))
+    })
+}
+
+/// Build a `TransformSet` from a list of
+/// `{"pattern": ..., "convert": ..., "scale": ..., "offset": ..., "precision": ..., "min": ..., "max": ..., "clamp": ...}`
+/// dicts. Any invalid entry, including an invalid regex, raises a `ValueError`: a
+/// transform that silently doesn't apply would send wrong values.
+pub fn parse_transforms(entries: &Bound<'_, PyAny>) -> PyResult<TransformSet> {
+    let mut transforms = Vec::new();
+    for (index, entry) in dict_entries(entries, "Transform")?.iter().enumerate() {
+        let Some(source) = entry.get_item("pattern")? else {
+            return Err(PyValueError::new_err(format!("Transform {} is missing the 'pattern'", index)));
+        };
+        let (min, max) = (number(entry, "min")?, number(entry, "max")?);
+        if let (Some(min), Some(max)) = (min, max) {
+            if min > max {
+                return Err(PyValueError::new_err(format!("Transform {} has min {} above max {}", index, min, max)));
+            }
+        }
+        transforms.push(Transform {
+            source: source.extract()?,
+            convert: match entry.get_item("convert")? {
+                Some(v) if !v.is_none() => Some(conversion(index, &v.extract::<String>()?)?),
+                _ => None,
+            },
+            scale: number(entry, "scale")?.unwrap_or(1.0),
+            offset: number(entry, "offset")?.unwrap_or(0.0),
+            precision: match entry.get_item("precision")? {
+                Some(v) if !v.is_none() => Some(v.extract()?),
+                _ => None,
+            },
+            min,
+            max,
+            clamp: match entry.get_item("clamp")? {
+                Some(v) if !v.is_none() => v.extract()?,
+                _ => false,
+            },
+        });
+    }
+    let patterns = RegexSet::new(transforms.iter().map(|t| t.source.as_str()))
+        .map_err(|e| PyValueError::new_err(format!("Invalid transform pattern: {}", e)))?;
+    debug!("Compiled {} transforms", transforms.len());
+    Ok(TransformSet { patterns, transforms })
+}
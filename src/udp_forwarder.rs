@@ -0,0 +1,63 @@
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+
+use log::debug;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+/// Sends `name=value` datagrams to Loxone Virtual UDP Inputs (`udp.udp_out_destinations`),
+/// for topics whose rule selects `transport = "udp"`. Fire and forget: there is no answer
+/// to wait for, so a send costs one syscall and no task.
+pub struct UdpForwarder {
+    destinations: Vec<SocketAddr>,
+    v4: Option<UdpSocket>,
+    v6: Option<UdpSocket>,
+}
+
+impl UdpForwarder {
+    /// Resolve the `host:port` destinations (`[addr]:port` for IPv6) once and open a
+    /// socket per address family in use.
+    pub fn new(destinations: &[String]) -> PyResult<Self> {
+        let mut resolved = Vec::with_capacity(destinations.len());
+        for destination in destinations {
+            let addr = destination
+                .to_socket_addrs()
+                .map_err(|e| PyValueError::new_err(format!("Invalid UDP destination '{}': {}", destination, e)))?
+                .next()
+                .ok_or_else(|| PyValueError::new_err(format!("UDP destination '{}' didn't resolve", destination)))?;
+            resolved.push(addr);
+        }
+        let open = |bind: &str| -> PyResult<UdpSocket> {
+            let socket = UdpSocket::bind(bind)?;
+            socket.set_nonblocking(true)?;
+            Ok(socket)
+        };
+        Ok(UdpForwarder {
+            v4: resolved.iter().any(SocketAddr::is_ipv4).then(|| open("0.0.0.0:0")).transpose()?,
+            v6: resolved.iter().any(SocketAddr::is_ipv6).then(|| open("[::]:0")).transpose()?,
+            destinations: resolved,
+        })
+    }
+
+    /// Send `name=value` to every destination; the first error, after trying them all.
+    pub fn send(&self, name: &str, value: &str) -> io::Result<()> {
+        self.send_datagram(&format!("{}={}", name, value))
+    }
+
+    /// Send a prepared datagram, e.g. a batch of `name=value` pairs, to every destination.
+    pub fn send_datagram(&self, datagram: &str) -> io::Result<()> {
+        let mut result = Ok(());
+        for destination in &self.destinations {
+            let socket = if destination.is_ipv4() { &self.v4 } else { &self.v6 };
+            let sent = match socket {
+                Some(socket) => socket.send_to(datagram.as_bytes(), destination).map(drop),
+                None => Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "no socket for the address family")),
+            };
+            if let Err(e) = sent {
+                debug!("UDP send of {} to {} failed: {}", datagram, destination, e);
+                result = result.and(Err(e));
+            }
+        }
+        result
+    }
+}
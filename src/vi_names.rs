@@ -0,0 +1,108 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use log::warn;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+/// Characters a virtual input name can't contain, always replaced.
+const ALWAYS_REPLACED: [char; 2] = ['/', '%'];
+
+/// Length of the `_xxxxxxxx` hash suffix appended to shortened names.
+const HASH_SUFFIX_LEN: usize = 9;
+
+/// Shortens virtual input names that exceed the Miniserver's name length limit.
+///
+/// Over-length names are truncated and get a stable hash suffix of the full name, so
+/// distinct topics stay distinct and map to the same name across restarts. Every
+/// shortened name is kept in a mapping table and warned about once.
+pub struct ViNameLimiter {
+    max_len: usize,
+    shortened: Mutex<HashMap<String, String>>,
+}
+
+impl ViNameLimiter {
+    /// A `max_len` of 0 disables the limit.
+    pub fn new(max_len: usize) -> Self {
+        ViNameLimiter {
+            max_len,
+            shortened: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn fit(&self, name: String) -> String {
+        if self.max_len == 0 || name.chars().count() <= self.max_len {
+            return name;
+        }
+        let mut shortened = self.shortened.lock().unwrap();
+        if let Some(short) = shortened.get(&name) {
+            return short.clone();
+        }
+        let short = shorten(&name, self.max_len);
+        warn!(
+            "Virtual input name '{}' exceeds {} characters, sending it as '{}'",
+            name, self.max_len, short
+        );
+        shortened.insert(name, short.clone());
+        short
+    }
+
+    /// Full name -> shortened name for every name shortened so far.
+    pub fn mapping(&self) -> HashMap<String, String> {
+        self.shortened.lock().unwrap().clone()
+    }
+}
+
+/// 32-bit FNV-1a, stable across builds and platforms (unlike `DefaultHasher`).
+fn fnv1a(input: &str) -> u32 {
+    input.bytes().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+fn shorten(name: &str, max_len: usize) -> String {
+    let hash = format!("{:08x}", fnv1a(name));
+    if max_len <= HASH_SUFFIX_LEN {
+        return hash.chars().take(max_len).collect();
+    }
+    let prefix: String = name.chars().take(max_len - HASH_SUFFIX_LEN).collect();
+    format!("{}_{}", prefix, hash)
+}
+
+/// Turns a topic into a virtual input name: `/`, `%` and the characters of
+/// `topics.name_replace_chars` become `topics.name_replacement`, and with
+/// `topics.name_lowercase` the name is lowercased. Works on characters, so umlauts and
+/// other non-ASCII letters are kept as they are.
+pub struct NameNormalizer {
+    replacement: String,
+    replaced: Vec<char>,
+    lowercase: bool,
+}
+
+impl NameNormalizer {
+    pub fn new(replacement: &str, extra_chars: &str, lowercase: bool) -> PyResult<Self> {
+        let mut replaced = ALWAYS_REPLACED.to_vec();
+        replaced.extend(extra_chars.chars().filter(|c| !ALWAYS_REPLACED.contains(c)));
+        if let Some(c) = replacement.chars().find(|c| replaced.contains(c)) {
+            return Err(PyValueError::new_err(format!(
+                "Invalid name_replacement '{}': contains '{}', which is itself replaced",
+                replacement, c
+            )));
+        }
+        Ok(NameNormalizer { replacement: replacement.to_string(), replaced, lowercase })
+    }
+
+    /// Whether `name` would be changed; whitelist entries that would are taken as topics.
+    pub fn changes(&self, name: &str) -> bool {
+        name.contains(self.replaced.as_slice()) || (self.lowercase && name.chars().any(char::is_uppercase))
+    }
+
+    pub fn normalize<'a>(&self, topic: &'a str) -> Cow<'a, str> {
+        if !self.changes(topic) {
+            return Cow::Borrowed(topic);
+        }
+        let replaced = topic.replace(self.replaced.as_slice(), &self.replacement);
+        Cow::Owned(if self.lowercase { replaced.to_lowercase() } else { replaced })
+    }
+}
@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::filters::FilterList;
+
+/// Inputs tracked at most, so a flood of one-off topics can't grow the map without bound.
+const MAX_WATCHED: usize = 10_000;
+
+struct Watched {
+    /// The topic of the last value, for the offline send
+    topic: String,
+    last: Instant,
+    stale: bool,
+}
+
+/// `processing.stale_timeout`: the time of the last value forwarded per virtual input, so
+/// an input that gets no value for that long can be sent `stale_value` and reported offline.
+pub struct Watchdog {
+    pub timeout: Duration,
+    /// Sent to the Miniserver when an input goes stale; empty to only publish the availability
+    pub offline_value: String,
+    /// `processing.stale_topics`; empty watches every forwarded topic
+    topics: FilterList,
+    watched: Mutex<HashMap<String, Watched>>,
+}
+
+impl Watchdog {
+    pub fn new(timeout: Duration, offline_value: String, topics: FilterList) -> Self {
+        Watchdog { timeout, offline_value, topics, watched: Mutex::default() }
+    }
+
+    /// How often `expire` runs: a quarter of the timeout, between 1 and 60 seconds.
+    pub fn interval(&self) -> Duration {
+        (self.timeout / 4).clamp(Duration::from_secs(1), Duration::from_secs(60))
+    }
+
+    pub fn watches(&self, topic: &str) -> bool {
+        self.topics.patterns().is_empty() || self.topics.is_match(topic)
+    }
+
+    /// Record a value forwarded for `name`; whether the input was stale until now.
+    pub fn touch(&self, name: &str, topic: &str) -> bool {
+        let mut watched = self.watched.lock().unwrap();
+        if let Some(entry) = watched.get_mut(name) {
+            entry.last = Instant::now();
+            if entry.topic != topic {
+                entry.topic = topic.to_string();
+            }
+            return std::mem::replace(&mut entry.stale, false);
+        }
+        if watched.len() < MAX_WATCHED {
+            watched.insert(name.to_string(), Watched { topic: topic.to_string(), last: Instant::now(), stale: false });
+        }
+        false
+    }
+
+    /// Mark the inputs that got no value within the timeout as stale and return their
+    /// `(name, topic)`; each input only once until it gets a value again.
+    pub fn expire(&self) -> Vec<(String, String)> {
+        let now = Instant::now();
+        let mut watched = self.watched.lock().unwrap();
+        watched
+            .iter_mut()
+            .filter(|(_, entry)| !entry.stale && now.duration_since(entry.last) >= self.timeout)
+            .map(|(name, entry)| {
+                entry.stale = true;
+                (name.clone(), entry.topic.clone())
+            })
+            .collect()
+    }
+
+    /// The stale inputs and the seconds since their last value.
+    pub fn stale(&self) -> Vec<(String, f64)> {
+        let watched = self.watched.lock().unwrap();
+        watched.iter().filter(|(_, entry)| entry.stale).map(|(name, entry)| (name.clone(), entry.last.elapsed().as_secs_f64())).collect()
+    }
+}
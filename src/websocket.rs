@@ -0,0 +1,197 @@
+//! A minimal RFC 6455 websocket client: the upgrade handshake, masked frames from the
+//! client, fragmented messages and ping/pong. No extensions, no TLS.
+
+use base64::{engine::general_purpose, Engine};
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::crypto;
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_HANDSHAKE_BYTES: usize = 16 * 1024;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+#[derive(Debug)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// Answer with `pong`
+    Ping(Vec<u8>),
+    /// The server closed the connection (close frame or EOF)
+    Close,
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn random<const N: usize>() -> io::Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    crypto::random_bytes(&mut bytes).map_err(io::Error::other)?;
+    Ok(bytes)
+}
+
+pub struct WebSocket {
+    stream: TcpStream,
+    buf: Vec<u8>,
+    /// Opcode and data of a fragmented message still being received
+    partial: Option<(u8, Vec<u8>)>,
+    max_message: usize,
+}
+
+impl WebSocket {
+    /// Open `ws://host:port/path`, asking for `protocol`.
+    pub async fn connect(host: &str, port: u16, path: &str, protocol: &str, max_message: usize) -> io::Result<Self> {
+        let mut stream = TcpStream::connect((host, port)).await?;
+        stream.set_nodelay(true)?;
+        let key = general_purpose::STANDARD.encode(random::<16>()?);
+        let host_header = if host.contains(':') { format!("[{}]:{}", host, port) } else { format!("{}:{}", host, port) };
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\n\
+             Sec-WebSocket-Version: 13\r\nSec-WebSocket-Protocol: {}\r\n\r\n",
+            path, host_header, key, protocol
+        );
+        stream.write_all(request.as_bytes()).await?;
+
+        let mut buf = Vec::with_capacity(4096);
+        let header_end = loop {
+            if let Some(at) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break at;
+            }
+            if buf.len() > MAX_HANDSHAKE_BYTES {
+                return Err(invalid("websocket handshake response too large"));
+            }
+            if stream.read_buf(&mut buf).await? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed during the websocket handshake"));
+            }
+        };
+        let head = String::from_utf8_lossy(&buf[..header_end]).into_owned();
+        let mut lines = head.lines();
+        let status = lines.next().unwrap_or_default();
+        if status.split_whitespace().nth(1) != Some("101") {
+            return Err(invalid(format!("websocket upgrade refused: {}", status)));
+        }
+        let expected = general_purpose::STANDARD.encode(crypto::sha1(format!("{}{}", key, ACCEPT_GUID).as_bytes()));
+        let accepted = lines
+            .filter_map(|line| line.split_once(':'))
+            .any(|(name, value)| name.trim().eq_ignore_ascii_case("sec-websocket-accept") && value.trim() == expected);
+        if !accepted {
+            return Err(invalid("websocket handshake without a valid Sec-WebSocket-Accept"));
+        }
+        buf.drain(..header_end + 4);
+        Ok(WebSocket { stream, buf, partial: None, max_message })
+    }
+
+    async fn send_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 14);
+        frame.push(0x80 | opcode);
+        match payload.len() {
+            len @ 0..=125 => frame.push(0x80 | len as u8),
+            len @ 126..=0xFFFF => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        let mask = random::<4>()?;
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        self.stream.write_all(&frame).await
+    }
+
+    pub async fn send_text(&mut self, text: &str) -> io::Result<()> {
+        self.send_frame(OP_TEXT, text.as_bytes()).await
+    }
+
+    pub async fn pong(&mut self, payload: &[u8]) -> io::Result<()> {
+        self.send_frame(OP_PONG, payload).await
+    }
+
+    pub async fn close(&mut self) -> io::Result<()> {
+        self.send_frame(OP_CLOSE, &1000u16.to_be_bytes()).await?;
+        self.stream.shutdown().await
+    }
+
+    /// The next complete frame in the buffer: FIN, opcode, payload.
+    fn next_frame(&mut self) -> io::Result<Option<(bool, u8, Vec<u8>)>> {
+        let buf = &self.buf;
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        let (fin, opcode, masked) = (buf[0] & 0x80 != 0, buf[0] & 0x0F, buf[1] & 0x80 != 0);
+        let (len, mut at) = match buf[1] & 0x7F {
+            126 if buf.len() >= 4 => (usize::from(u16::from_be_bytes([buf[2], buf[3]])), 4),
+            127 if buf.len() >= 10 => {
+                let len = u64::from_be_bytes(buf[2..10].try_into().unwrap());
+                (usize::try_from(len).map_err(|_| invalid("websocket frame too large"))?, 10)
+            }
+            126 | 127 => return Ok(None),
+            len => (usize::from(len), 2),
+        };
+        if len > self.max_message {
+            return Err(invalid(format!("{} byte websocket frame exceeds the {} byte limit", len, self.max_message)));
+        }
+        let mask = if masked {
+            let Some(mask) = buf.get(at..at + 4) else { return Ok(None) };
+            at += 4;
+            Some([mask[0], mask[1], mask[2], mask[3]])
+        } else {
+            None
+        };
+        let Some(payload) = buf.get(at..at + len) else { return Ok(None) };
+        let payload = match mask {
+            Some(mask) => payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]).collect(),
+            None => payload.to_vec(),
+        };
+        self.buf.drain(..at + len);
+        Ok(Some((fin, opcode, payload)))
+    }
+
+    /// The next text, binary or ping message. Cancel safe: it only reads.
+    pub async fn read_message(&mut self) -> io::Result<Message> {
+        loop {
+            while let Some((fin, opcode, payload)) = self.next_frame()? {
+                match opcode {
+                    OP_PING => return Ok(Message::Ping(payload)),
+                    OP_PONG => {}
+                    OP_CLOSE => return Ok(Message::Close),
+                    OP_TEXT | OP_BINARY | OP_CONTINUATION => {
+                        let (opcode, data) = match (opcode, self.partial.take()) {
+                            (OP_CONTINUATION, Some((opcode, mut data))) => {
+                                data.extend_from_slice(&payload);
+                                (opcode, data)
+                            }
+                            (OP_CONTINUATION, None) => return Err(invalid("websocket continuation without a message")),
+                            (opcode, _) => (opcode, payload),
+                        };
+                        if data.len() > self.max_message {
+                            return Err(invalid("websocket message too large"));
+                        }
+                        if !fin {
+                            self.partial = Some((opcode, data));
+                            continue;
+                        }
+                        return Ok(if opcode == OP_TEXT {
+                            Message::Text(String::from_utf8(data).map_err(|_| invalid("websocket text message isn't UTF-8"))?)
+                        } else {
+                            Message::Binary(data)
+                        });
+                    }
+                    other => return Err(invalid(format!("unknown websocket opcode {}", other))),
+                }
+            }
+            if self.stream.read_buf(&mut self.buf).await? == 0 {
+                return Ok(Message::Close);
+            }
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use roxmltree::{Document, Node};
+use serde_json::{Map, Value};
+
+/// Nesting deeper than this is rejected instead of risking the stack.
+const MAX_DEPTH: usize = 128;
+
+/// Text of an element that also has attributes or child elements goes under this key.
+const TEXT_KEY: &str = "value";
+
+/// An XML payload as the JSON value it is flattened from (`processing.xml_topics`): each
+/// element, the root included, becomes an object of its attributes and child elements,
+/// repeated children an array. An element with nothing but text becomes that text; next to
+/// attributes or children the text goes under `value`. Namespaces are dropped.
+pub fn decode(text: &str) -> Result<Value, String> {
+    let document = Document::parse(text).map_err(|e| e.to_string())?;
+    element(document.root_element(), 1)
+}
+
+fn element(node: Node<'_, '_>, depth: usize) -> Result<Value, String> {
+    if depth > MAX_DEPTH {
+        return Err(format!("nested deeper than {} levels", MAX_DEPTH));
+    }
+    let mut fields = Map::new();
+    for attribute in node.attributes() {
+        fields.insert(attribute.name().to_string(), Value::String(attribute.value().to_string()));
+    }
+    let mut text = String::new();
+    // Children by name, in the order the names first appear
+    let mut children: Vec<(&str, Vec<Value>)> = Vec::new();
+    for child in node.children() {
+        if child.is_text() {
+            text.push_str(child.text().unwrap_or_default());
+        } else if child.is_element() {
+            let name = child.tag_name().name();
+            let value = element(child, depth + 1)?;
+            match children.iter_mut().find(|(existing, _)| *existing == name) {
+                Some((_, values)) => values.push(value),
+                None => children.push((name, vec![value])),
+            }
+        }
+    }
+    for (name, mut values) in children {
+        let value = if values.len() == 1 { values.remove(0) } else { Value::Array(values) };
+        fields.insert(name.to_string(), value);
+    }
+    let text = text.trim();
+    if fields.is_empty() {
+        return Ok(Value::String(text.to_string()));
+    }
+    if !text.is_empty() {
+        fields.insert(TEXT_KEY.to_string(), Value::String(text.to_string()));
+    }
+    Ok(Value::Object(fields))
+}
@@ -0,0 +1,23 @@
+from loxmqttrelay.cloud_dns import parse_getip, parse_redirect
+
+
+def test_parse_getip():
+    payload = {
+        "cmd": "getip",
+        "Code": 200,
+        "IP": "93.184.216.34:7777",
+        "IPHTTPS": "93-184-216-34.504F94A0B1C2.dyndns.loxonecloud.com:7778",
+        "DNS-Status": "registered",
+    }
+    assert parse_getip(payload) == ("93.184.216.34", 7777)
+
+
+def test_parse_getip_without_port_or_registration():
+    assert parse_getip({"Code": 200, "IP": "93.184.216.34"}) == ("93.184.216.34", 80)
+    assert parse_getip({"Code": 403, "DNS-Status": "not registered"}) is None
+
+
+def test_parse_redirect():
+    assert parse_redirect("http://93.184.216.34:7777/") == ("93.184.216.34", 7777)
+    assert parse_redirect("https://[2001:db8::1]/") == ("2001:db8::1", 443)
+    assert parse_redirect("") is None
@@ -136,11 +136,9 @@ base_topic = "invalid_base_topic"
 
 [broker]
 host = ""
-port = "not_a_port"
 
 [miniserver]
 miniserver_ip = "invalid_ip"
-miniserver_port = "not_a_port"
 
 [topics]
 subscriptions = "not_a_list"
@@ -156,11 +154,50 @@ subscriptions = "not_a_list"
     assert config.general.log_level == "INVALID_LEVEL"  # Loaded as is
     assert config.general.base_topic == "invalid_base_topic"  # Loaded as is
     assert config.broker.host == ""  # Loaded as is
-    assert config.broker.port == "not_a_port"  # Loaded as string, though it should be int
     assert config.miniserver.miniserver_ip == "invalid_ip"  # Loaded as is
-    assert config.miniserver.miniserver_port == "not_a_port"  # Loaded as string
     assert config.topics.subscriptions == "not_a_list"  # Loaded as string
 
+@pytest.mark.parametrize("content, message", [
+    ('[broker]\nport = "not_a_port"\n', "port"),
+    ("[broker]\nport = 70000\n", "broker.port: 70000 is not a port"),
+    ("[udp]\nudp_in_port = 0\n", "udp.udp_in_port: 0 is not a port"),
+    ('[miniserver]\nminiserver_discovery = true\nminiserver_serial = "504F94A0B1C2"\n', "set only one"),
+    ("[general\nbase_topic = 1\n", "Invalid config file"),
+])
+def test_config_file_rejected(tmp_path, content, message):
+    """Ports, options that exclude each other and syntax are checked when the file is read"""
+    config_path = tmp_path / "config.toml"
+    config_path.write_text(content)
+    config = Config()
+    config.config_path = str(config_path)
+    with pytest.raises(ValueError, match=message):
+        config._load_config()
+
+def test_reload_from_file(config_instance, temp_config_file):
+    """Only fields edited in the file are taken over and reported"""
+    config_instance._file_state = config_instance._config.to_dict()
+    # Changed while running, e.g. by discovery; not an edit of the file
+    config_instance.miniserver.miniserver_ip = "192.168.1.50"
+    with open(temp_config_file) as f:
+        content = f.read()
+    with open(temp_config_file, "w") as f:
+        f.write(content.replace('do_not_forward = ["do_not_forward_topic"]', 'do_not_forward = ["other"]'))
+    assert config_instance.reload_from_file() == ["topics.do_not_forward"]
+    assert config_instance.topics.do_not_forward == ["other"]
+    assert config_instance.miniserver.miniserver_ip == "192.168.1.50"
+    assert config_instance.reload_from_file() == []
+
+def test_reload_from_file_rejects_invalid_config(config_instance, temp_config_file):
+    """A file failing validation leaves the config unchanged"""
+    config_instance._file_state = config_instance._config.to_dict()
+    with open(temp_config_file) as f:
+        content = f.read()
+    with open(temp_config_file, "w") as f:
+        f.write(content.replace('do_not_forward = ["do_not_forward_topic"]', 'do_not_forward = ["(unclosed"]'))
+    with pytest.raises(ValueError, match="topics.do_not_forward"):
+        config_instance.reload_from_file()
+    assert config_instance.topics.do_not_forward == ["do_not_forward_topic"]
+
 def test_config_update(config_instance):
     """Test updating configuration sections"""
     # Update Broker Config
@@ -225,6 +262,7 @@ def test_config_safe_config(config_instance):
     config_instance.broker.password = "secure_pass"
     config_instance.miniserver.miniserver_user = "ms_secure_user"
     config_instance.miniserver.miniserver_pass = "ms_secure_pass"
+    config_instance.miniserver.targets = [{"name": "garden", "ip": "10.0.0.2", "user": "u", "pass": "p"}]
     
     safe_config = config_instance.get_safe_config()
     
@@ -239,6 +277,7 @@ def test_config_safe_config(config_instance):
     # Ensure sensitive miniserver data is removed
     assert 'miniserver_user' not in miniserver_config
     assert 'miniserver_pass' not in miniserver_config
+    assert miniserver_config['targets'] == [{"name": "garden", "ip": "10.0.0.2"}]
     
     # Ensure non-sensitive data remains
     assert 'host' in broker_config
@@ -273,6 +312,46 @@ def test_update_fields(config_instance):
     assert config_instance.general.log_level == "WARNING"
     assert config_instance.general.cache_size == 200000
 
+def test_update_fields_rejects_wrong_types_without_changes(config_instance):
+    """A wrong type anywhere in the update names the field and leaves the config untouched"""
+    config_instance.general.log_level = "INFO"
+    config_instance.topics.subscriptions = ["topic1", "topic2"]
+    with pytest.raises(ValueError, match=r"subscriptions\[1\]: expected str, got int"):
+        config_instance.update_fields({"log_level": "DEBUG", "subscriptions": ["a", 1]})
+    assert config_instance.general.log_level == "INFO"
+    assert config_instance.topics.subscriptions == ["topic1", "topic2"]
+
+    with pytest.raises(ValueError, match="miniserver_port: expected int, got bool"):
+        config_instance.update_field("miniserver_port", True)
+    with pytest.raises(ValueError, match="filter_anchor: expected one of none, start, full"):
+        config_instance.update_field("filter_anchor", "middle")
+    with pytest.raises(ValueError, match="payload_templates: expected object"):
+        config_instance.update_field("payload_templates", ["x"])
+    with pytest.raises(ValueError, match="only works for lists and objects"):
+        config_instance.update_field("cache_size", 5, "add")
+
+def test_update_fields_merges_by_type(config_instance):
+    """Lists keep their order, sets stay sets, objects merge by key, ints are accepted for floats"""
+    config_instance.topics.subscriptions = ["topic1", "topic2"]
+    config_instance.topics.topic_whitelist = {"whitelist_topic"}
+    config_instance.update_field("subscriptions", ["topic3", "topic1"], "add")
+    assert config_instance.topics.subscriptions == ["topic1", "topic2", "topic3"]
+    config_instance.update_field("subscriptions", "topic2", "remove")
+    assert config_instance.topics.subscriptions == ["topic1", "topic3"]
+
+    config_instance.update_field("topic_whitelist", ["a", "b"], "add")
+    assert config_instance.topics.topic_whitelist == {"whitelist_topic", "a", "b"}
+
+    config_instance.update_field("payload_templates", {"miniserver": "{{value}}", "mqtt": "x"})
+    config_instance.update_field("payload_templates", {"mqtt": "y"}, "add")
+    assert config_instance.processing.payload_templates == {"miniserver": "{{value}}", "mqtt": "y"}
+    config_instance.update_field("payload_templates", ["miniserver"], "remove")
+    assert config_instance.processing.payload_templates == {"mqtt": "y"}
+
+    config_instance.update_field("shutdown_timeout", 10)
+    assert config_instance.general.shutdown_timeout == 10.0
+    assert isinstance(config_instance.general.shutdown_timeout, float)
+
 def test_thread_safety(tmp_path):
     """Test that Config is thread-safe"""
     config_path = tmp_path / "thread_safe_config.toml"
@@ -334,3 +413,14 @@ cache_size = 100000
     
     # Assert that all tasks received the correct base_topic
     assert all(result == "async_test/" for result in results)
+
+def test_relay_topic_includes_instance_id(config_instance):
+    """Test that instance_id namespaces the relay's own topics"""
+    assert config_instance.general.relay_topic == "test/"
+    config_instance.general.instance_id = "garage"
+    assert config_instance.general.relay_topic == "test/garage/"
+    config_instance.general.instance_id = "/house/"
+    assert config_instance.general.relay_topic == "test/house/"
+    config_instance.general.instance_id = ""
+    config_instance.general.base_topic = "myrelay"
+    assert config_instance.general.relay_topic == "myrelay/"
@@ -0,0 +1,53 @@
+import socket
+import struct
+
+import pytest
+
+from loxmqttrelay.discovery import (
+    CLASS_IN, TYPE_A, TYPE_PTR, TYPE_SRV, DiscoveredMiniserver, _decode_name, _encode_name,
+    build_query, parse_response,
+)
+
+SERVICE = "_http._tcp.local."
+
+
+def _record(name: bytes, rtype: int, rdata: bytes) -> bytes:
+    return name + struct.pack("!HHIH", rtype, CLASS_IN, 120, len(rdata)) + rdata
+
+
+def _response(instance: str, host: str, ip: str, port: int) -> bytes:
+    header = struct.pack("!HHHHHH", 0, 0x8400, 0, 1, 0, 2)
+    service = _encode_name(SERVICE)
+    # The PTR answer starts at offset 12; later names point back into it
+    ptr_rdata = _encode_name(instance.split(".")[0])[:-1] + b"\xc0\x0c"
+    answer = _record(service, TYPE_PTR, ptr_rdata)
+    instance_ptr = struct.pack("!H", 0xC000 | (12 + len(service) + 10))
+    srv = _record(instance_ptr, TYPE_SRV, struct.pack("!HHH", 0, 0, port) + _encode_name(host))
+    a = _record(_encode_name(host), TYPE_A, socket.inet_aton(ip))
+    return header + answer + srv + a
+
+
+def test_query_asks_for_service_ptr():
+    query = build_query(SERVICE)
+    assert struct.unpack("!HHHHHH", query[:12]) == (0, 0, 1, 0, 0, 0)
+    name, offset = _decode_name(query, 12)
+    assert name == SERVICE
+    assert struct.unpack("!HH", query[offset:]) == (TYPE_PTR, CLASS_IN)
+
+
+def test_parse_resolves_instance_via_srv_and_a():
+    data = _response("Loxone Miniserver 504F94A0._http._tcp.local.", "loxone-504f94a0.local.", "192.168.1.77", 8080)
+    assert parse_response(data, SERVICE, "loxone") == [
+        DiscoveredMiniserver("Loxone Miniserver 504F94A0._http._tcp.local", "192.168.1.77", 8080)
+    ]
+
+
+def test_parse_ignores_non_matching_instances():
+    data = _response("Printer._http._tcp.local.", "printer.local.", "192.168.1.20", 80)
+    assert parse_response(data, SERVICE, "Loxone") == []
+
+
+def test_decode_name_rejects_compression_loop():
+    with pytest.raises(ValueError):
+        _decode_name(b"\xc0\x00", 0)
+
@@ -0,0 +1,72 @@
+import socket
+
+import pytest
+from unittest.mock import AsyncMock
+
+from loxmqttrelay.dns_cache import CachingResolver
+
+
+def _addresses(ip: str):
+    return [{"hostname": "ms.example.org", "host": ip, "port": 80, "family": socket.AF_INET, "proto": 0, "flags": 0}]
+
+
+class Clock:
+    def __init__(self):
+        self.now = 1000.0
+
+    def __call__(self) -> float:
+        return self.now
+
+
+@pytest.fixture
+def clock(monkeypatch):
+    clock = Clock()
+    monkeypatch.setattr("loxmqttrelay.dns_cache.monotonic", clock)
+    return clock
+
+
+@pytest.mark.asyncio
+async def test_resolution_is_cached_until_refresh(clock):
+    inner = AsyncMock()
+    inner.resolve.side_effect = [_addresses("10.0.0.1"), _addresses("10.0.0.2")]
+    resolver = CachingResolver(300, inner)
+
+    assert (await resolver.resolve("ms.example.org", 80))[0]["host"] == "10.0.0.1"
+    clock.now += 299
+    assert (await resolver.resolve("ms.example.org", 80))[0]["host"] == "10.0.0.1"
+    assert inner.resolve.call_count == 1
+
+    clock.now += 2
+    assert (await resolver.resolve("ms.example.org", 80))[0]["host"] == "10.0.0.2"
+    assert inner.resolve.call_count == 2
+
+
+@pytest.mark.asyncio
+async def test_failed_refresh_keeps_cached_address(clock):
+    inner = AsyncMock()
+    inner.resolve.side_effect = [_addresses("10.0.0.1"), OSError("DNS timeout")]
+    resolver = CachingResolver(300, inner)
+    await resolver.resolve("ms.example.org", 80)
+    clock.now += 301
+    assert (await resolver.resolve("ms.example.org", 80))[0]["host"] == "10.0.0.1"
+
+
+@pytest.mark.asyncio
+async def test_first_resolution_failure_is_raised(clock):
+    inner = AsyncMock()
+    inner.resolve.side_effect = OSError("NXDOMAIN")
+    resolver = CachingResolver(300, inner)
+    with pytest.raises(OSError):
+        await resolver.resolve("ms.example.org", 80)
+
+
+@pytest.mark.asyncio
+async def test_expire_forces_re_resolution(clock):
+    inner = AsyncMock()
+    inner.resolve.side_effect = [_addresses("10.0.0.1"), _addresses("10.0.0.2")]
+    resolver = CachingResolver(300, inner)
+    await resolver.resolve("ms.example.org", 80)
+    resolver.expire("other.example.org")
+    assert (await resolver.resolve("ms.example.org", 80))[0]["host"] == "10.0.0.1"
+    resolver.expire()
+    assert (await resolver.resolve("ms.example.org", 80))[0]["host"] == "10.0.0.2"
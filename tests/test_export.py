@@ -0,0 +1,45 @@
+import xml.etree.ElementTree as ET
+
+from loxmqttrelay.export import collect_virtual_inputs, to_csv, to_loxone_xml
+
+
+def test_collect_merges_whitelist_and_forwarded():
+    inputs = collect_virtual_inputs(["b_topic", "a_topic"], {"c_topic": "c/topic", "a_topic": "a/topic"})
+    assert inputs == {"a_topic": "a/topic", "b_topic": "", "c_topic": "c/topic"}
+    assert list(inputs) == ["a_topic", "b_topic", "c_topic"]
+
+
+def test_csv_export():
+    assert to_csv({"sensor_temp": "sensor/temp", "only_whitelisted": ""}) == (
+        "virtual_input,topic\nsensor_temp,sensor/temp\nonly_whitelisted,\n"
+    )
+
+
+def test_loxone_xml_export():
+    xml = to_loxone_xml({"sensor_temp": "sensor/temp", 'odd"name': "odd/<topic>"}, title="Relay")
+    root = ET.fromstring(xml.encode("utf-8"))
+    assert root.tag == "VirtualIn"
+    assert root.get("Title") == "Relay"
+    cmds = root.findall("VirtualInCmd")
+    assert [(c.get("Title"), c.get("Comment"), c.get("Analog")) for c in cmds] == [
+        ("sensor_temp", "sensor/temp", "true"),
+        ('odd"name', "odd/<topic>", "true"),
+    ]
+
+
+def test_loxone_xml_export_udp():
+    xml = to_loxone_xml({"sensor_temp": "sensor/temp", 'odd"name': "odd/<topic>"}, title="Relay", udp_port=7000)
+    root = ET.fromstring(xml.encode("utf-8"))
+    assert root.tag == "VirtualInUdp"
+    assert root.get("Title") == "Relay"
+    assert root.get("Port") == "7000"
+    cmds = root.findall("VirtualInUdpCmd")
+    assert [(c.get("Title"), c.get("Comment"), c.get("Check")) for c in cmds] == [
+        ("sensor_temp", "sensor/temp", "sensor_temp=\\v"),
+        ('odd"name', "odd/<topic>", 'odd"name=\\v'),
+    ]
+
+
+def test_loxone_xml_export_empty():
+    root = ET.fromstring(to_loxone_xml({}).encode("utf-8"))
+    assert root.findall("VirtualInCmd") == []
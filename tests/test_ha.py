@@ -0,0 +1,89 @@
+import pytest
+import orjson
+from unittest.mock import AsyncMock, MagicMock
+
+from loxmqttrelay.ha import HaCoordinator
+
+
+class Clock:
+    def __init__(self):
+        self.now = 1000.0
+
+    def __call__(self):
+        return self.now
+
+
+@pytest.fixture
+def clock(monkeypatch):
+    clock = Clock()
+    monkeypatch.setattr('loxmqttrelay.ha.monotonic', clock)
+    return clock
+
+
+def make_node(node_id, on_change=None):
+    return HaCoordinator(node_id, "relay/ha/lock", "relay/ha/state", 30, AsyncMock(), on_change or MagicMock())
+
+
+def lock(owner, lease=30):
+    return orjson.dumps({"owner": owner, "lease": lease})
+
+
+@pytest.mark.asyncio
+async def test_claims_free_lock_and_becomes_active(clock):
+    on_change = MagicMock()
+    node = make_node("a", on_change)
+    await node.tick()
+    node._publish.assert_any_call("relay/ha/lock", lock("a", 30.0), True)
+    assert not node.active  # only active once the broker echoes the lock
+
+    node.handle_lock_message(lock("a"))
+    assert node.active
+    on_change.assert_called_once_with(True)
+
+
+@pytest.mark.asyncio
+async def test_standby_while_peer_holds_lock(clock):
+    node = make_node("b")
+    node.handle_lock_message(lock("a"))
+    await node.tick()
+    node._publish.assert_not_called()
+    assert not node.active
+    assert node.owner == "a"
+
+
+@pytest.mark.asyncio
+async def test_standby_takes_over_after_lease_expires(clock):
+    node = make_node("b")
+    node.handle_lock_message(lock("a"))
+    clock.now += 31
+    assert node.owner is None
+    await node.tick()
+    node._publish.assert_any_call("relay/ha/lock", lock("b", 30.0), True)
+    node.handle_lock_message(lock("b"))
+    assert node.active
+
+
+@pytest.mark.asyncio
+async def test_active_steps_down_when_peer_wins_lock(clock):
+    on_change = MagicMock()
+    node = make_node("a", on_change)
+    node.handle_lock_message(lock("a"))
+    node.handle_lock_message(lock("b"))
+    assert not node.active
+    assert on_change.call_args_list[-1].args == (False,)
+
+
+@pytest.mark.asyncio
+async def test_active_without_renewal_echo_goes_standby(clock):
+    node = make_node("a")
+    node.handle_lock_message(lock("a"))
+    clock.now += 31
+    await node.tick()  # renews, but the broker never confirms
+    assert not node.active
+
+
+def test_malformed_lock_message_is_ignored(clock):
+    node = make_node("a")
+    node.handle_lock_message(b"not json")
+    node.handle_lock_message(b'{"lease": 5}')
+    assert node.owner is None
@@ -2,6 +2,8 @@ import pytest
 import pytest_asyncio
 from unittest.mock import AsyncMock, patch, MagicMock
 from loxmqttrelay.http_miniserver_handler import HttpMiniserverHandler
+from loxmqttrelay.utils import split_host_port
+from loxmqttrelay.token_auth import TokenAuthError
 from loxmqttrelay.config import Config, AppConfig
 from loxmqttrelay.compatible._loxmqttrelay import MiniserverDataProcessor
 import aiohttp
@@ -102,10 +104,9 @@ async def test_http_authentication(
         normalized_topic = topic.replace('/', '_')
         await handler.send_to_miniserver_via_http(topic, normalized_topic, value)
 
-    mock_session.assert_called_with(
-        auth=aiohttp.BasicAuth("testuser", "testpass"),
-        timeout=aiohttp.ClientTimeout(total=10)
-    )
+    kwargs = mock_session.call_args.kwargs
+    assert kwargs["auth"] == aiohttp.BasicAuth("testuser", "testpass")
+    assert kwargs["timeout"] == aiohttp.ClientTimeout(total=10)
 
 @pytest.mark.asyncio
 async def test_http_topic_normalization(
@@ -325,3 +326,257 @@ async def test_standard_ports_behavior(
         # The current implementation might not include standard ports
         # This test documents the current behavior
         mock_session.return_value.__aenter__.return_value.get.assert_called()
+
+def _session_returning(status: int = 200, error: Exception | None = None) -> MagicMock:
+    """ClientSession mock whose get() works as `async with session.get(url) as resp`."""
+    session = MagicMock()
+    session.__aenter__.return_value = session
+    session.__aexit__.return_value = None
+    response = MagicMock()
+    response.status = status
+    response.__aenter__ = AsyncMock(side_effect=error) if error else AsyncMock(return_value=response)
+    response.__aexit__ = AsyncMock(return_value=None)
+    session.get = MagicMock(return_value=response)
+    return session
+
+# Connectivity Probe Tests
+@pytest.mark.asyncio
+async def test_failed_probe_gates_sends_into_offline_queue(handler: HttpMiniserverHandler) -> None:
+    """While the probe fails, values are queued (latest per input) instead of sent"""
+    with patch("aiohttp.ClientSession", return_value=_session_returning(error=OSError("unreachable"))):
+        assert await handler.probe_miniserver() is False
+    assert handler.online is False
+
+    with patch("aiohttp.ClientSession") as client_session:
+        assert await handler.send_to_miniserver("a/b", "a_b", "1") == {"queued": True}
+        await handler.send_to_miniserver("a/b", "a_b", "2")
+        await handler.send_to_miniserver("c", "c", "3")
+        client_session.assert_not_called()
+    assert list(handler.offline_queue.items()) == [("a_b", ("a/b", "2")), ("c", ("c", "3"))]
+
+@pytest.mark.asyncio
+async def test_probe_recovery_flushes_offline_queue(handler: HttpMiniserverHandler) -> None:
+    """Once the probe succeeds again the queued values are sent"""
+    handler.online = False
+    handler.offline_queue["a_b"] = ("a/b", "2")
+    session = _session_returning(200)
+    with patch("aiohttp.ClientSession", return_value=session), \
+            patch("loxmqttrelay.http_miniserver_handler.global_config.miniserver.use_websocket", False):
+        assert await handler.probe_miniserver() is True
+    assert handler.online is True
+    assert not handler.offline_queue
+    urls = [call[0][0] for call in session.get.call_args_list]
+    assert urls == [f"{handler.http_base_url}/jdev/cfg/api", f"{handler.http_base_url}/dev/sps/io/a_b/2"]
+
+@pytest.mark.asyncio
+async def test_connection_error_takes_handler_offline(handler: HttpMiniserverHandler) -> None:
+    """A send that can't connect gates the following sends immediately"""
+    with patch("aiohttp.ClientSession", return_value=_session_returning(error=OSError("refused"))):
+        assert await handler.send_to_miniserver_via_http("a/b", "a_b", "1") == {"code": 503}
+    assert handler.online is False
+    assert handler.offline_queue["a_b"] == ("a/b", "1")
+
+@pytest.mark.asyncio
+async def test_offline_queue_is_bounded(handler: HttpMiniserverHandler) -> None:
+    """The oldest queued input is dropped once the queue is full"""
+    handler.online = False
+    with patch("loxmqttrelay.http_miniserver_handler.global_config.miniserver.miniserver_offline_queue_size", 2):
+        for i in range(3):
+            await handler.send_to_miniserver(f"t{i}", f"t{i}", str(i))
+    assert list(handler.offline_queue) == ["t1", "t2"]
+
+# Connection Reuse Tests
+@pytest.mark.asyncio
+async def test_http_session_is_reused_across_sends(handler: HttpMiniserverHandler) -> None:
+    """All sends share one pooled keep-alive session instead of one session per value"""
+    session = _session_returning(200)
+    session.closed = False
+    with patch("aiohttp.ClientSession", return_value=session) as client_session, \
+            patch("aiohttp.TCPConnector") as connector:
+        for i in range(5):
+            assert await handler.send_to_miniserver_via_http(f"t{i}", f"t{i}", "1") == {"code": 200}
+        await handler.probe_miniserver()
+        client_session.assert_called_once()
+        assert client_session.call_args.kwargs["connector"] is connector.return_value
+        connector.assert_called_once()
+        assert connector.call_args.kwargs["limit"] == 5
+        assert connector.call_args.kwargs["keepalive_timeout"] == 30
+    assert session.get.call_count == 6
+
+@pytest.mark.asyncio
+async def test_http_session_recreated_after_close(handler: HttpMiniserverHandler) -> None:
+    """A closed session is replaced on the next send"""
+    session = _session_returning(200)
+    session.closed = False
+    session.close = AsyncMock()
+    with patch("aiohttp.ClientSession", return_value=session) as client_session, patch("aiohttp.TCPConnector"):
+        await handler.send_to_miniserver_via_http("t", "t", "1")
+        await handler.close()
+        session.close.assert_awaited_once()
+        await handler.send_to_miniserver_via_http("t", "t", "1")
+        assert client_session.call_count == 2
+
+# IPv6 Tests
+@pytest.mark.parametrize("address,expected", [
+    ("192.168.1.10", ("192.168.1.10", 80)),
+    ("192.168.1.10:8080", ("192.168.1.10", 8080)),
+    ("fd00::10", ("fd00::10", 80)),
+    ("[fd00::10]", ("fd00::10", 80)),
+    ("[fd00::10]:8080", ("fd00::10", 8080)),
+])
+def test_split_host_port(address: str, expected: Tuple[str, int]) -> None:
+    """Miniserver addresses may carry a port, IPv6 literals only in brackets"""
+    assert split_host_port(address, 80) == expected
+
+def test_ipv6_miniserver_urls(handler: HttpMiniserverHandler) -> None:
+    """IPv6 literals are bracketed in the HTTP and websocket URLs"""
+    handler.set_miniserver_address("fd00::10", 80)
+    assert handler.http_base_url == "http://[fd00::10]"
+    assert handler.ws_base_url == "http://[fd00::10]"
+
+    handler.set_miniserver_address("[fd00::10]:8080", 80)
+    assert handler.target_ip == "fd00::10"
+    assert handler.http_base_url == "http://[fd00::10]:8080"
+
+    handler.set_miniserver_address("fd00::10", 443)
+    assert handler.ws_base_url == "https://[fd00::10]"
+    assert handler.http_base_url == "http://[fd00::10]:443"
+
+# Proxy Tests
+@pytest.mark.asyncio
+async def test_http_session_uses_configured_proxy(handler: HttpMiniserverHandler) -> None:
+    """Miniserver requests go through miniserver_proxy if set"""
+    with patch("loxmqttrelay.http_miniserver_handler.global_config.miniserver.miniserver_proxy", "http://proxy.local:3128"), \
+            patch("aiohttp.ClientSession", return_value=_session_returning(200)) as client_session, patch("aiohttp.TCPConnector"):
+        await handler.send_to_miniserver_via_http("t", "t", "1")
+    assert client_session.call_args.kwargs["proxy"] == "http://proxy.local:3128"
+    assert client_session.call_args.kwargs["trust_env"] is False
+
+@pytest.mark.asyncio
+async def test_http_session_proxy_from_env(handler: HttpMiniserverHandler) -> None:
+    """Without a configured proxy, the environment's proxy settings are used only if enabled"""
+    with patch("loxmqttrelay.http_miniserver_handler.global_config.miniserver.miniserver_proxy_from_env", True), \
+            patch("aiohttp.ClientSession", return_value=_session_returning(200)) as client_session, patch("aiohttp.TCPConnector"):
+        await handler.send_to_miniserver_via_http("t", "t", "1")
+    assert client_session.call_args.kwargs["proxy"] is None
+    assert client_session.call_args.kwargs["trust_env"] is True
+
+# Websocket Lifecycle Tests
+@pytest.mark.asyncio
+async def test_websocket_reconnect_is_tracked(handler: HttpMiniserverHandler) -> None:
+    """A dropped websocket is reconnected on the next check and counted"""
+    with patch("loxmqttrelay.http_miniserver_handler.loxwebsocket") as ws:
+        ws.state = "IDLE"
+        ws.connect = AsyncMock(side_effect=lambda **kwargs: setattr(ws, "state", "CONNECTED"))
+        await handler.ensure_websocket()
+        assert handler.websocket_status()["connected_since"] is not None
+        assert handler.websocket_status()["reconnects"] == 0
+
+        await handler.ensure_websocket()
+        ws.connect.assert_awaited_once()
+
+        ws.state = "STOPPED"
+        await handler.ensure_websocket()
+        assert ws.connect.await_count == 2
+        assert handler.websocket_status()["reconnects"] == 1
+
+@pytest.mark.asyncio
+async def test_websocket_connect_failure_is_reported(handler: HttpMiniserverHandler) -> None:
+    """A failed connect fails the send with 500 and is kept as last_error"""
+    with patch("loxmqttrelay.http_miniserver_handler.loxwebsocket") as ws:
+        ws.state = "IDLE"
+        ws.connect = AsyncMock(side_effect=ConnectionError("token rejected"))
+        assert await handler.send_to_minisever_via_websocket("t", "t", "1") == {"code": 500}
+        assert handler.websocket_status()["last_error"] == "token rejected"
+        assert handler.websocket_status()["connected_since"] is None
+
+# Token Auth Tests
+@pytest.mark.asyncio
+async def test_http_send_with_token_auth(handler: HttpMiniserverHandler) -> None:
+    """With token auth the token goes into the query and a 401 drops it"""
+    handler.token_auth = MagicMock(get_token=AsyncMock(return_value="jwt"), query=MagicMock(return_value="autht=jwt&user=admin"))
+    session = _session_returning(200)
+    with patch("aiohttp.ClientSession", return_value=session) as client_session, patch("aiohttp.TCPConnector"):
+        assert await handler.send_to_miniserver_via_http("t", "t", "1") == {"code": 200}
+        assert client_session.call_args.kwargs["auth"] is None
+        assert session.get.call_args.args[0] == f"{handler.http_base_url}/dev/sps/io/t/1?autht=jwt&user=admin"
+
+        session.get.return_value.status = 401
+        assert await handler.send_to_miniserver_via_http("t", "t", "1") == {"code": 401}
+        handler.token_auth.invalidate.assert_called_once()
+
+@pytest.mark.asyncio
+async def test_http_send_falls_back_to_basic_auth(handler: HttpMiniserverHandler) -> None:
+    """If no token can be acquired the value is still sent with basic auth"""
+    handler.token_auth = MagicMock(get_token=AsyncMock(side_effect=TokenAuthError("getjwt failed with code 401")))
+    session = _session_returning(200)
+    with patch("aiohttp.ClientSession", return_value=session), patch("aiohttp.TCPConnector"):
+        assert await handler.send_to_miniserver_via_http("t", "t", "1") == {"code": 200}
+    assert session.get.call_args.args[0] == f"{handler.http_base_url}/dev/sps/io/t/1"
+    assert "auth" in session.get.call_args.kwargs
+
+# Busy Miniserver Tests
+@pytest.mark.asyncio
+async def test_busy_miniserver_defers_and_retries_latest_value(handler: HttpMiniserverHandler) -> None:
+    """A 503 queues the value and holds back sends until the back-off expired, then the latest value is sent"""
+    busy = _session_returning(503)
+    with patch("aiohttp.ClientSession", return_value=busy), \
+            patch("loxmqttrelay.http_miniserver_handler.global_config.miniserver.use_websocket", False), \
+            patch("loxmqttrelay.http_miniserver_handler.global_config.miniserver.miniserver_busy_backoff", 0.05):
+        assert await handler.send_to_miniserver("a/b", "a_b", "1") == {"queued": True}
+        assert await handler.send_to_miniserver("a/b", "a_b", "2") == {"queued": True}
+        assert busy.get.call_count == 1
+        assert handler.offline_queue["a_b"] == ("a/b", "2")
+
+        ready = _session_returning(200)
+        ready.closed = False
+        handler._session = ready
+        await asyncio.sleep(0.1)
+    assert [call[0][0] for call in ready.get.call_args_list] == [f"{handler.http_base_url}/dev/sps/io/a_b/2"]
+    assert not handler.offline_queue
+    assert handler.busy_backoff == 0
+
+@pytest.mark.asyncio
+async def test_refused_connections_back_off_exponentially(handler: HttpMiniserverHandler) -> None:
+    """While the Miniserver keeps refusing connections the back-off doubles up to the maximum"""
+    refused = _session_returning(error=aiohttp.ClientConnectorError(None, ConnectionRefusedError("refused")))
+    with patch("aiohttp.ClientSession", return_value=refused), \
+            patch("loxmqttrelay.http_miniserver_handler.global_config.miniserver.use_websocket", False), \
+            patch("loxmqttrelay.http_miniserver_handler.global_config.miniserver.miniserver_busy_backoff", 0.01), \
+            patch("loxmqttrelay.http_miniserver_handler.global_config.miniserver.miniserver_busy_backoff_max", 0.04):
+        assert await handler.send_to_miniserver_via_http("a/b", "a_b", "1") == {"queued": True}
+        await asyncio.sleep(0.2)
+        assert handler.busy_backoff == 0.04
+        assert handler.offline_queue["a_b"] == ("a/b", "1")
+        await handler.close()
+    assert handler.online is True
+
+# Connection State Tests
+@pytest.mark.asyncio
+async def test_connection_state_changes_are_reported(handler: HttpMiniserverHandler) -> None:
+    """Probe results and send outcomes move the reported state, each change once"""
+    reported = []
+    handler.state_listener = lambda state, error: reported.append((state, error))
+    with patch("aiohttp.ClientSession", return_value=_session_returning(error=OSError("unreachable"))):
+        await handler.probe_miniserver()
+        await handler.probe_miniserver()
+    with patch("aiohttp.ClientSession", return_value=_session_returning(200)), \
+            patch("loxmqttrelay.http_miniserver_handler.global_config.miniserver.use_websocket", False):
+        await handler.probe_miniserver()
+    with patch("aiohttp.ClientSession", return_value=_session_returning(500)):
+        await handler.send_to_miniserver_via_http("a/b", "a_b", "1")
+    with patch("aiohttp.ClientSession", return_value=_session_returning(200)):
+        await handler.send_to_miniserver_via_http("a/b", "a_b", "1")
+    assert [state for state, _ in reported] == ["offline", "connected", "degraded", "connected"]
+    assert reported[2] == ("degraded", "HTTP 500")
+
+def test_native_target_only_while_healthy(handler: HttpMiniserverHandler) -> None:
+    """The Rust sender only gets an address while nothing needs the Python sender's bookkeeping"""
+    assert handler.native_target() is None
+    handler.state = "connected"
+    assert handler.native_target() == (handler.target_ip, handler.target_port)
+    handler.busy_until = 1.0
+    assert handler.native_target() is None
+    handler.busy_until = 0.0
+    handler.online = False
+    assert handler.native_target() is None
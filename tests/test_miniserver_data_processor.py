@@ -1,10 +1,15 @@
 import pytest
 import pytest_asyncio
+import copy
+import gzip
 import json
+import struct
+import zlib
 from unittest.mock import AsyncMock, patch, MagicMock
 from loxmqttrelay.config import Config, AppConfig, global_config
 import asyncio
-from loxmqttrelay.compatible._loxmqttrelay import MiniserverDataProcessor  # Assuming 'librs' is the compiled Rust module
+import socket
+from loxmqttrelay.compatible._loxmqttrelay import MiniserverDataProcessor, _panic, validate_config  # Assuming 'librs' is the compiled Rust module
 
 TOPIC = 'mock/topic'  # Define a mock or placeholder for the TOPIC variable
 
@@ -35,6 +40,7 @@ def config_instance(temp_config_file):
     """Create and configure a Config instance"""
     with open(temp_config_file, 'r') as f:
         config_dict = json.load(f)
+    saved_config = copy.deepcopy(global_config._config)
     
     # Update global config for the test
     global_config.topics.subscription_filters = config_dict["topics"]["subscription_filters"]
@@ -45,7 +51,9 @@ def config_instance(temp_config_file):
     global_config.general.base_topic = config_dict["general"]["base_topic"]
     global_config.general.cache_size = config_dict["general"]["cache_size"]
     
-    return global_config
+    yield global_config
+    # Options set by individual tests must not leak into the next one
+    global_config._config = saved_config
 
 class DummyTopicNS:
     START_UI = "dummy_start_ui"
@@ -53,11 +61,32 @@ class DummyTopicNS:
     MINISERVER_STARTUP_EVENT = "dummy_startup"
     CONFIG_GET = "dummy_config_get"
     CONFIG_RESPONSE = "dummy_config_response"
+    CONFIG_VALIDATE = "dummy_config_validate"
+    CONFIG_VALIDATE_RESPONSE = "dummy_config_validate_response"
+    FILTERS_SET = "dummy_filters_set"
+    DNF_SET = "dummy_dnf_set"
+    WHITELIST_ADD = "dummy_whitelist_add"
+    WHITELIST_REMOVE = "dummy_whitelist_remove"
+    WHITELIST_GET = "dummy_whitelist_get"
+    WHITELIST_RESPONSE = "dummy_whitelist_response"
+    STATS_GET = "myrelay/stats/get"
+    STATS_RESET = "myrelay/stats/reset"
+    STATS_RESPONSE = "myrelay/stats/response"
+    DEBUG_SET = "myrelay/debug/set"
     CONFIG_SET = "dummy_config_set"
     CONFIG_ADD = "dummy_config_add"
     CONFIG_REMOVE = "dummy_config_remove"
     CONFIG_UPDATE = "dummy_config_update"
     CONFIG_RESTART = "dummy_config_restart"
+    HA_LOCK = "myrelay/ha/lock"
+    EXPORT_VI = "myrelay/export/virtual_inputs"
+    DELIVERY_GET = "myrelay/delivery/get"
+    DELIVERY_RESPONSE = "myrelay/delivery/response"
+    FILTERS_TEST = "myrelay/filters/test"
+    FILTERS_TEST_RESPONSE = "myrelay/filters/test/response"
+    PAUSE = "myrelay/pause"
+    RESUME = "myrelay/resume"
+    STATE = "myrelay/connection/state"
 
 class TestMiniserverDataProcessor:
     def __init__(self, config_instance):
@@ -65,7 +94,6 @@ class TestMiniserverDataProcessor:
         self.mock_http_handler = MagicMock()
         self.mock_mqtt_client = MagicMock()
         self.mock_relay_main = AsyncMock()
-        self.mock_orjson = MagicMock()
 
         self.dummy_topic_ns = DummyTopicNS()
         self.config_instance = config_instance
@@ -76,8 +104,7 @@ class TestMiniserverDataProcessor:
             self.config_instance, 
             self.mock_relay_main, 
             self.mock_mqtt_client, 
-            self.mock_http_handler, 
-            self.mock_orjson
+            self.mock_http_handler
         )
 
 @pytest_asyncio.fixture(scope="function")
@@ -114,6 +141,82 @@ def test_convert_boolean(processor, input_val, expected):
     in_val = input_val if input_val is not None else ""
     assert processor._convert_boolean(in_val) == expected
 
+@pytest.mark.asyncio
+async def test_custom_boolean_words(config_instance):
+    config_instance.processing.bool_true_values = ["EIN", "locked"]
+    config_instance.processing.bool_false_values = ["aus", "unlocked"]
+    config_instance.processing.bool_exclude_topics = ["^raw/"]
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    assert processor._convert_boolean("ein") == "1"
+    assert processor._convert_boolean(" Unlocked ") == "0"
+    # The defaults are replaced
+    assert processor._convert_boolean("true") == "true"
+
+    processor.process_data("lock/state", "locked")
+    processor.process_data("raw/state", "locked")
+    calls = [call[0] for call in processor.http_handler_obj.send_to_miniserver.call_args_list]
+    assert calls == [("lock/state", "lock_state", "1"), ("raw/state", "raw_state", "locked")]
+
+
+@pytest.mark.parametrize("value,expected", [
+    ("21.5 °C", "21.5"),
+    ("345 W", "345"),
+    ("3,14", "3.14"),
+    ("-3,5 °C", "-3.5"),
+    ("1.234,5 kWh", "1234.5"),
+    ("1,234.5", "1234.5"),
+    ("+7%", "7"),
+    ("offline", "offline"),
+])
+@pytest.mark.asyncio
+async def test_numeric_extraction(config_instance, value, expected):
+    config_instance.processing.numeric_topics = ["^sensor/"]
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    processor.process_data("sensor/temp", value)
+    processor.process_data("text/state", value)
+    calls = [call[0] for call in processor.http_handler_obj.send_to_miniserver.call_args_list]
+    assert calls == [("sensor/temp", "sensor_temp", expected), ("text/state", "text_state", value)]
+
+
+TIMESTAMPS = [
+    {"pattern": "/last_seen$", "epoch": "loxone"},
+    {"pattern": "/time$", "epoch": "unix", "utc_offset_minutes": 120},
+]
+
+
+@pytest.mark.parametrize("topic,value,expected", [
+    ("dev/last_seen", "2024-05-01T12:00:00Z", "483796800"),
+    ("dev/last_seen", "2024-05-01T14:00:00.5+02:00", "483796800"),
+    ("dev/last_seen", "1714564800000", "483796800"),
+    ("dev/time", "2024-05-01 12:00:00", "1714572000"),
+    ("dev/time", "1714564800", "1714572000"),
+    ("dev/time", "never", "never"),
+    ("dev/other", "2024-05-01T12:00:00Z", "2024-05-01T12:00:00Z"),
+])
+@pytest.mark.asyncio
+async def test_timestamp_conversion(config_instance, topic, value, expected):
+    config_instance.processing.timestamps = TIMESTAMPS
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    processor.process_data(topic, value)
+    assert processor.http_handler_obj.send_to_miniserver.call_args[0][2] == expected
+
+
+def test_update_timestamps(processor):
+    processor.update_timestamps(TIMESTAMPS)
+    assert processor.get_timestamps() == [
+        {"pattern": "/last_seen$", "epoch": "loxone"},
+        {"pattern": "/time$", "epoch": "unix", "utc_offset_minutes": 120},
+    ]
+    with pytest.raises(ValueError):
+        processor.update_timestamps([{"pattern": "/x$", "epoch": "mayan"}])
+
+
+def test_boolean_word_in_both_lists_rejected(config_instance):
+    config_instance.processing.bool_true_values = ["on"]
+    config_instance.processing.bool_false_values = ["ON"]
+    with pytest.raises(ValueError):
+        TestMiniserverDataProcessor(config_instance)
+
 def test_flatten_dict(processor):
     import json
     input_dict = {
@@ -147,6 +250,35 @@ def test_normalize_topic(processor):
     assert processor.normalize_topic("test/topic%with/both") == "test_topic_with_both"  # Test both / and %
     assert processor.normalize_topic("test/topic/%with/both") == "test_topic__with_both"  # Test both / and %
 
+@pytest.mark.parametrize("topic,expected", [
+    ("küche/temperatur", "küche_temperatur"),
+    ("Wohnzimmer/Rollläden/Höhe", "Wohnzimmer_Rollläden_Höhe"),
+    ("straße%außen", "straße_außen"),
+    ("größe", "größe"),
+    ("日本/温度", "日本_温度"),
+])
+def test_normalize_topic_keeps_multibyte_characters(processor, topic, expected):
+    assert processor.normalize_topic(topic) == expected
+    # Cached path returns the same
+    assert processor.normalize_topic(topic) == expected
+
+
+@pytest.mark.asyncio
+async def test_umlaut_topics_are_forwarded_intact(config_instance):
+    config_instance.topics.max_name_length = 12
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+    processor.process_data("küche/temperatur", "21")
+    processor.process_data("küche/luftfeuchtigkeit", "40")
+    await asyncio.sleep(0.05)
+    sent = [c.args for c in processor.http_handler_obj.send_to_miniserver.call_args_list]
+    # Shortened by characters, not bytes: no umlaut is cut in half
+    assert sent[0][0] == "küche/temperatur"
+    assert sent[0][1].startswith("küc_") and len(sent[0][1]) == 12
+    assert sent[1] == ("küche/luftfeuchtigkeit", processor.get_vi_name_mapping()["küche_luftfeuchtigkeit"], "40")
+    assert sent[0][1] != sent[1][1]
+
+
 def test_expand_json(processor):
     result = processor.expand_json("test", '{"key1": "val1", "key2": {"nested": "val2"}}')
     expected = {
@@ -208,6 +340,7 @@ async def test_process_data_filter_second_pass_after_flatten(processor, monkeypa
 
     processor.update_subscription_filters([r"ignore\/.*"])
     monkeypatch.setattr(global_config.processing, 'expand_json', True)
+    processor.reload_config()
 
     processor.process_data(topic, message)
     calls = processor.http_handler_obj.send_to_miniserver.call_args_list
@@ -216,6 +349,23 @@ async def test_process_data_filter_second_pass_after_flatten(processor, monkeypa
     assert "original/topic/ignore/nested" not in processed_topics
     assert "original/topic/key1" in processed_topics
 
+@pytest.mark.asyncio
+async def test_expand_json_cached_until_reload(processor, monkeypatch):
+    """expand_json is read once and re-read by reload_config"""
+    monkeypatch.setattr(global_config.processing, 'expand_json', True)
+    processor.process_data("dev", '{"a": 1}')
+    processor.http_handler_obj.send_to_miniserver.assert_called_once_with("dev", "dev", '{"a": 1}')
+    processor.http_handler_obj.send_to_miniserver.reset_mock()
+    processor.reload_config()
+    processor.process_data("dev", '{"a": 1}')
+    processor.http_handler_obj.send_to_miniserver.assert_called_once_with("dev/a", "dev_a", "1")
+
+@pytest.mark.asyncio
+@pytest.mark.parametrize("payload", [b"21.5", bytearray(b"21.5"), memoryview(b"21.5")])
+async def test_process_data_accepts_bytes(processor, payload):
+    processor.process_data("sensor/temp", payload)
+    processor.http_handler_obj.send_to_miniserver.assert_called_once_with("sensor/temp", "sensor_temp", "21.5")
+
 @pytest.mark.asyncio
 async def test_process_data_with_whitelist(processor):
     # Test non-whitelisted case
@@ -271,6 +421,7 @@ async def test_process_data_order_of_filters(processor, monkeypatch):
     processor.update_topic_whitelist(["whitelisted_foo", "normal_publish"])
     processor.update_do_not_forward([r"^dnf\/.*"])
     monkeypatch.setattr(global_config.processing, 'expand_json', True)
+    processor.reload_config()
 
     for topic, message in topic_messages:
         processor.process_data(topic, message)
@@ -663,3 +814,2538 @@ class TestDownstreamBinaryDataFlow:
         except Exception as e:
             pytest.fail(f"End-to-end binary message handling failed with exception: {e}")
 
+
+
+def test_warm_up_caches(processor):
+    """Warmed up topics end up in the normalization cache"""
+    warmed = processor.warm_up_caches(["sensor/temp", "sensor/humidity", ""])
+    assert warmed == 2
+    cached = processor.cached_topics()
+    assert "sensor/temp" in cached
+    assert "sensor/humidity" in cached
+    assert processor.normalize_topic("sensor/temp") == "sensor_temp"
+
+
+@pytest.mark.asyncio
+async def test_warm_up_caches_from_last_values_and_whitelist(processor):
+    """Without topics, the last-value store and the whitelist seed both caches"""
+    processor.process_data("sensor/temp", "21")
+    processor.update_topic_whitelist(["listed_input", "sensor_temp"])
+    processor.clear_caches()
+    assert processor.warm_up_caches() == 3
+    assert {"sensor/temp", "listed_input", "sensor_temp"} <= set(processor.cached_topics())
+    decisions = processor.cache_stats()["filter_decision"]
+    assert decisions["size"] == 3
+    # The next message for a warmed topic is decided from the cache
+    hits = decisions["hits"]
+    processor.process_data("listed_input", "1")
+    assert processor.cache_stats()["filter_decision"]["hits"] == hits + 1
+
+
+@pytest.mark.asyncio
+async def test_warm_up_caches_from_seen_topics(processor):
+    """Raw topics seen by a previous run make the first message after a restart a cache hit"""
+    processor.process_data("a/b/c", "1")
+    seen = processor.seen_topics()
+    assert seen == ["a/b/c"]
+    processor.clear_caches()
+    assert processor.warm_up_caches(seen) == 1
+    hits = processor.cache_stats()["filter_decision"]["hits"]
+    processor.process_data("a/b/c", "2")
+    assert processor.cache_stats()["filter_decision"]["hits"] == hits + 1
+
+
+def test_filter_decisions_follow_filter_changes(processor):
+    processor.update_topic_whitelist([])
+    processor.update_do_not_forward([])
+    assert processor.test_filters(["sensor/temp"])["sensor/temp"]["result"] == "forwarded"
+    processor.update_do_not_forward(["^sensor/"])
+    assert processor.test_filters(["sensor/temp"])["sensor/temp"]["result"] == "filtered"
+
+
+def test_cached_topics_most_recent_first(processor):
+    processor.normalize_topic("a/b")
+    processor.normalize_topic("c/d")
+    assert processor.cached_topics()[:2] == ["c/d", "a/b"]
+
+
+def test_cache_stats_resize_and_clear(processor):
+    processor.clear_caches()
+    before = processor.cache_stats()["normalize_topic"]
+    processor.resize_caches(2)
+    for topic in ["a/b", "c/d", "e/f", "a/b"]:
+        processor.normalize_topic(topic)
+    stats = processor.cache_stats()["normalize_topic"]
+    assert stats["capacity"] == 2
+    assert stats["size"] == 2
+    assert stats["misses"] - before["misses"] == 4
+    assert stats["evictions"] - before["evictions"] == 2
+    assert set(processor.cache_stats()) == {"normalize_topic", "convert_boolean", "filter_decision"}
+    processor.clear_caches()
+    assert processor.cache_stats()["normalize_topic"]["size"] == 0
+    with pytest.raises(ValueError):
+        processor.resize_caches(0)
+
+
+def test_invalid_filters_dropped_without_strict_mode(processor):
+    processor.update_subscription_filters([r"^valid/.*", r"(unclosed"])
+    assert processor.get_subscription_filters() == [r"^valid/.*"]
+
+
+def test_strict_filters_rejects_invalid_update(config_instance):
+    config_instance.topics.strict_filters = True
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    with pytest.raises(ValueError) as exc_info:
+        processor.update_subscription_filters([r"^valid/.*", r"(unclosed", r"[bad"])
+    assert "(unclosed" in str(exc_info.value)
+    assert "[bad" in str(exc_info.value)
+    with pytest.raises(ValueError):
+        processor.update_do_not_forward([r"*oops"])
+
+
+def test_strict_filters_rejects_invalid_config_at_construction(config_instance):
+    config_instance.topics.strict_filters = True
+    config_instance.topics.subscription_filters = [r"(unclosed"]
+    with pytest.raises(ValueError):
+        TestMiniserverDataProcessor(config_instance)
+
+
+def test_do_not_forward_loaded_from_config(config_instance):
+    config_instance.topics.do_not_forward = [r"^debug/.*"]
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    assert processor.get_do_not_forward_patterns() == [r"^debug/.*"]
+
+
+@pytest.mark.parametrize("anchor,topic,should_pass", [
+    ("none", "sensors/temp", False),
+    ("none", "home/sensors/temp", False),
+    ("start", "sensors/temp", False),
+    ("start", "home/sensors/temp", True),
+    ("full", "sensors/temp", True),
+    ("full", "sensors/", False),
+])
+@pytest.mark.asyncio
+async def test_filter_anchor_modes(config_instance, anchor, topic, should_pass):
+    config_instance.topics.filter_anchor = anchor
+    config_instance.topics.subscription_filters = ["sensors/"]
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    assert processor.filter_anchor == anchor
+    # The configured patterns are reported without the added anchors
+    assert processor.get_subscription_filters() == ["sensors/"]
+
+    processor.process_data(topic, "value")
+    if should_pass:
+        processor.http_handler_obj.send_to_miniserver.assert_called()
+    else:
+        processor.http_handler_obj.send_to_miniserver.assert_not_called()
+
+
+@pytest.mark.parametrize("topic,should_pass", [
+    ("sensors/temp", False),
+    ("debug/x", False),
+    ("home/sensors/temp", True),
+    ("home/debug/x", True),
+])
+@pytest.mark.asyncio
+async def test_start_anchor_applies_to_every_alternative(config_instance, topic, should_pass):
+    config_instance.topics.filter_anchor = "start"
+    config_instance.topics.subscription_filters = ["sensors/|debug/"]
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    processor.process_data(topic, "value")
+    if should_pass:
+        processor.http_handler_obj.send_to_miniserver.assert_called()
+    else:
+        processor.http_handler_obj.send_to_miniserver.assert_not_called()
+
+
+def test_invalid_filter_anchor_rejected(config_instance):
+    config_instance.topics.filter_anchor = "prefix"
+    with pytest.raises(ValueError):
+        TestMiniserverDataProcessor(config_instance)
+
+
+@pytest.mark.asyncio
+@pytest.mark.parametrize("syntax, filters, topic, should_pass", [
+    ("mqtt", ["sensors/+/battery"], "sensors/kitchen/battery", False),
+    ("mqtt", ["sensors/+/battery"], "sensors/kitchen/battery/low", True),
+    ("mqtt", ["sensors/+/battery"], "home/sensors/kitchen/battery", True),
+    ("mqtt", ["sensors/#"], "sensors", False),
+    ("mqtt", ["sensors/#"], "sensors/a/b/c", False),
+    ("mqtt", ["sensors/#"], "sensorsx/a", True),
+    ("mqtt", ["+/+"], "a/b", False),
+    ("mqtt", ["#"], "anything/at/all", False),
+    # A prefix selects the syntax of one entry
+    ("mqtt", ["regex:^home/"], "home/sensors/temp", False),
+    ("regex", ["mqtt:home/+/temp"], "home/sensors/temp", False),
+    ("regex", ["mqtt:home/+/temp"], "home/sensors/temperature", True),
+    # "+" and "#" as regex
+    ("regex", ["sensors/+/battery"], "sensors/kitchen/battery", True),
+])
+async def test_mqtt_filter_syntax(config_instance, syntax, filters, topic, should_pass):
+    config_instance.topics.filter_syntax = syntax
+    config_instance.topics.subscription_filters = filters
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    assert processor.filter_syntax == syntax
+    assert processor.get_subscription_filters() == filters
+    processor.process_data(topic, "value")
+    assert processor.http_handler_obj.send_to_miniserver.called == should_pass
+
+
+@pytest.mark.asyncio
+async def test_mqtt_filters_in_do_not_forward_and_whitelist(config_instance):
+    config_instance.topics.filter_syntax = "mqtt"
+    config_instance.topics.do_not_forward = ["zigbee2mqtt/+/linkquality"]
+    config_instance.topics.topic_whitelist = ["zigbee2mqtt/+/temperature", "zigbee2mqtt/+/linkquality", "shelly/plug/power"]
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    # Wildcard entries stay as written, topics become virtual input names
+    assert processor.topic_whitelist == {"zigbee2mqtt/+/temperature", "zigbee2mqtt/+/linkquality", "shelly_plug_power"}
+    assert processor.is_in_whitelist("zigbee2mqtt/kitchen/temperature")
+    for topic in ["zigbee2mqtt/kitchen/temperature", "zigbee2mqtt/kitchen/linkquality", "zigbee2mqtt/kitchen/humidity", "shelly/plug/power"]:
+        processor.process_data(topic, "1")
+    sent = [call[0][0] for call in processor.http_handler_obj.send_to_miniserver.call_args_list]
+    assert sent == ["zigbee2mqtt/kitchen/temperature", "shelly/plug/power"]
+
+    processor.update_topic_whitelist(["zigbee2mqtt/#"])
+    processor.process_data("zigbee2mqtt/kitchen/humidity", "1")
+    assert processor.http_handler_obj.send_to_miniserver.call_args[0][0] == "zigbee2mqtt/kitchen/humidity"
+
+
+@pytest.mark.parametrize("filters", [["sensors/#/battery"], ["sensors/bat+"], ["mqtt:a/b#"]])
+def test_invalid_mqtt_filters_rejected(config_instance, filters):
+    config_instance.topics.filter_syntax = "mqtt"
+    config_instance.topics.strict_filters = True
+    config_instance.topics.subscription_filters = filters
+    with pytest.raises(ValueError, match="Invalid filter patterns"):
+        TestMiniserverDataProcessor(config_instance)
+
+
+RULES = [
+    {"match": r"^zigbee/[^/]+/battery$", "action": "drop"},
+    {"match": r"^zigbee/(?P<room>[^/]+)/temperature$", "action": "accept", "target": "temp_${room}"},
+    {"match": r"^door/", "action": "accept", "value_map": {"open": "1", "closed": "0"}},
+]
+
+
+@pytest.fixture
+def rules_processor(config_instance):
+    config_instance.topics.rules = RULES
+    config_instance.topics.topic_whitelist = ["other_topic"]
+    config_instance.topics.subscription_filters = [r"^zigbee/"]
+    return TestMiniserverDataProcessor(config_instance).processor
+
+
+@pytest.mark.asyncio
+async def test_rules_first_match_wins(rules_processor):
+    rules_processor.process_data("zigbee/kitchen/temperature", "21")
+    rules_processor.process_data("zigbee/kitchen/battery", "90")
+    calls = [call[0] for call in rules_processor.http_handler_obj.send_to_miniserver.call_args_list]
+    # The accept rule overrides both the subscription filter and the whitelist
+    assert calls == [("zigbee/kitchen/temperature", "temp_kitchen", "21")]
+
+
+@pytest.mark.asyncio
+async def test_rules_value_map(rules_processor):
+    rules_processor.process_data("door/front", "open")
+    rules_processor.http_handler_obj.send_to_miniserver.assert_called_once_with("door/front", "door_front", "1")
+
+
+@pytest.mark.asyncio
+async def test_unmatched_topics_use_fixed_pipeline(rules_processor):
+    rules_processor.process_data("zigbee/kitchen/humidity", "50")
+    rules_processor.process_data("not/whitelisted", "1")
+    rules_processor.http_handler_obj.send_to_miniserver.assert_not_called()
+    rules_processor.process_data("other_topic", "1")
+    rules_processor.http_handler_obj.send_to_miniserver.assert_called_once()
+
+
+def test_update_and_get_rules(processor):
+    processor.update_rules([{"match": "^a/", "action": "drop"}])
+    assert processor.get_rules() == [{"match": "^a/", "action": "drop"}]
+
+
+@pytest.mark.parametrize("rules", [
+    [{"action": "drop"}],
+    [{"match": "^a/", "action": "maybe"}],
+    [{"match": "^a/", "action": "drop", "target": "x"}],
+    [{"match": "^a/", "action": "rewrite"}],
+    [{"match": "^a/", "action": "transform", "target": "x"}],
+    [{"match": "^a/", "action": "accept", "transport": "carrier-pigeon"}],
+    # No udp_out_destinations configured
+    [{"match": "^a/", "action": "accept", "transport": "udp"}],
+])
+def test_malformed_rules_rejected(processor, rules):
+    with pytest.raises(ValueError):
+        processor.update_rules(rules)
+
+
+@pytest.mark.asyncio
+async def test_rules_ahead_of_filter_lists(config_instance):
+    config_instance.topics.subscription_filters = [r"^zigbee/bridge/"]
+    config_instance.topics.do_not_forward = [r"/linkquality$"]
+    config_instance.topics.rules = [
+        {"match": r"^zigbee/(?P<room>[^/]+)/temperature$", "action": "rewrite", "target": "temp_${room}"},
+        {"match": r"^door/", "action": "transform", "value_map": {"open": "1"}},
+        {"match": r"^door/garage$", "action": "drop"},
+    ]
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    send = processor.http_handler_obj.send_to_miniserver
+    processor.process_data("zigbee/kitchen/temperature", "21")
+    processor.process_data("zigbee/kitchen/linkquality", "120")
+    processor.process_data("zigbee/bridge/state", "online")
+    processor.process_data("door/front", "open")
+    processor.process_data("door/garage", "open")
+    assert [call.args for call in send.call_args_list] == [
+        ("zigbee/kitchen/temperature", "temp_kitchen", "21"),
+        ("door/front", "door_front", "1"),
+    ]
+    filtered_by = processor.get_stats()["filtered_by"]
+    assert (filtered_by["subscription_filter"], filtered_by["do_not_forward"], filtered_by["rule"]) == (1, 1, 1)
+    assert processor.test_filters(["zigbee/kitchen/linkquality"])["zigbee/kitchen/linkquality"]["reason"] == "do_not_forward #0 '/linkquality$'"
+
+    # The lists behind the rules follow runtime updates
+    processor.update_do_not_forward([])
+    processor.update_topic_whitelist(["door_front"])
+    send.reset_mock()
+    processor.process_data("zigbee/kitchen/linkquality", "120")
+    processor.process_data("door/front", "open")
+    send.assert_called_once_with("door/front", "door_front", "1")
+
+
+@pytest.mark.asyncio
+async def test_unified_rules_is_a_deprecated_alias(config_instance):
+    config_instance.topics.unified_rules = True
+    config_instance.topics.do_not_forward = [r"/linkquality$"]
+    config_instance.topics.rules = [{"match": r"^door/garage$", "action": "drop"}]
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    assert processor.get_unified_rules() == processor.get_rules() == [{"match": r"^door/garage$", "action": "drop"}]
+    processor.process_data("zigbee/kitchen/linkquality", "120")
+    processor.process_data("door/garage", "open")
+    processor.process_data("door/front", "open")
+    processor.http_handler_obj.send_to_miniserver.assert_called_once_with("door/front", "door_front", "open")
+
+
+@pytest.mark.asyncio
+async def test_udp_transport_rule(config_instance):
+    receivers = []
+    for _ in range(2):
+        receiver = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+        receiver.bind(("127.0.0.1", 0))
+        receiver.settimeout(2)
+        receivers.append(receiver)
+    config_instance.udp.udp_out_destinations = [f"127.0.0.1:{r.getsockname()[1]}" for r in receivers]
+    config_instance.topics.rules = [
+        {"match": r"^fast/(?P<name>.+)$", "action": "accept", "target": "udp_${name}", "transport": "udp"},
+    ]
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    assert processor.get_rules()[0]["transport"] == "udp"
+
+    processor.process_data("fast/power", "1234")
+    processor.process_data("slow/power", "1")
+    for receiver in receivers:
+        assert receiver.recvfrom(1024)[0] == b"udp_power=1234"
+        receiver.close()
+    # Only topics of the UDP rule bypass HTTP
+    processor.http_handler_obj.send_to_miniserver.assert_called_once_with("slow/power", "slow_power", "1")
+    assert processor.get_delivery_status("fast/power")["udp_power"]["status"] == "unconfirmed"
+
+
+@pytest.mark.asyncio
+async def test_transport_per_rule_and_default(config_instance):
+    config_instance.miniserver.default_transport = "websocket"
+    config_instance.topics.rules = [
+        {"match": "^config/", "action": "accept", "transport": "http"},
+        {"match": "^plain/", "action": "accept", "transport": "miniserver"},
+    ]
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    send = processor.http_handler_obj.send_to_miniserver
+    processor.process_data("config/mode", "2")
+    processor.process_data("plain/value", "3")
+    processor.process_data("sensor/temp", "21.5")
+    assert [(c.args[0], c.kwargs) for c in send.call_args_list] == [
+        ("config/mode", {"transport": "http"}),
+        ("plain/value", {}),
+        ("sensor/temp", {"transport": "websocket"}),
+    ]
+    assert processor.get_rules()[0]["transport"] == "http"
+
+
+def test_invalid_default_transport_rejected(config_instance):
+    for transport in ("carrier-pigeon", "udp"):
+        config_instance.miniserver.default_transport = transport
+        with pytest.raises(ValueError):
+            TestMiniserverDataProcessor(config_instance)
+
+
+@pytest.mark.asyncio
+async def test_routes_to_miniserver_targets(config_instance):
+    receiver = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+    receiver.bind(("127.0.0.1", 0))
+    receiver.settimeout(2)
+    target = {"name": "garden", "ip": "127.0.0.1", "port": receiver.getsockname()[1], "transport": "udp"}
+    config_instance.miniserver.targets = [target]
+    config_instance.miniserver.routes = [
+        {"match": "^garden/shed", "target": "default"},
+        {"match": "^garden/", "target": "garden"},
+    ]
+    processor = TestMiniserverDataProcessor(config_instance).processor
+
+    processor.process_data("garden/pond/temp", "12")
+    processor.process_data("garden/shed/temp", "8")
+    assert receiver.recvfrom(1024)[0] == b"garden_pond_temp=12"
+    receiver.close()
+    await asyncio.sleep(0.05)
+    processor.http_handler_obj.send_to_miniserver.assert_called_once_with("garden/shed/temp", "garden_shed_temp", "8")
+    assert processor.get_stats()["targets"] == {"garden": {"forwarded": 1, "delivered": 0, "failed": 0, "unconfirmed": 1}}
+    assert processor.test_filters(["garden/pond/temp"])["garden/pond/temp"]["target"] == "garden"
+    assert processor.get_targets()["targets"] == [target]
+
+
+@pytest.mark.parametrize("targets,routes,error", [
+    ([{"name": "default", "ip": "10.0.0.2"}], [], "reserved"),
+    ([{"name": "a", "ip": "10.0.0.2", "transport": "udp"}], [], "needs a 'port'"),
+    ([], [{"match": "^x/", "target": "garden"}], "unknown target"),
+    ([], [{"match": "(", "target": "default"}], "invalid pattern"),
+    ([{"name": "a", "ip": "10.0.0.2", "tls": True, "ca_file": "/nonexistent/ca.pem"}], [], "Can't read the CA file"),
+    ([{"name": "a", "ip": "10.0.0.2", "port": 7000, "transport": "udp", "tls": True}], [], "no TLS"),
+])
+def test_update_targets_rejects_invalid(processor, targets, routes, error):
+    with pytest.raises(ValueError, match=error):
+        processor.update_targets(targets, routes)
+
+
+@pytest.mark.asyncio
+async def test_udp_batch_per_message(config_instance):
+    receiver = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+    receiver.bind(("127.0.0.1", 0))
+    receiver.settimeout(2)
+    config_instance.udp.udp_out_destinations = [f"127.0.0.1:{receiver.getsockname()[1]}"]
+    config_instance.topics.rules = [{"match": r"^meter/", "action": "accept", "transport": "udp"}]
+    config_instance.processing.expand_json = True
+    config_instance.miniserver.batch_mode = "message"
+    processor = TestMiniserverDataProcessor(config_instance).processor
+
+    processor.process_data("meter", '{"power": 1234, "energy": 5.5}')
+    assert receiver.recvfrom(1024)[0] == b"meter_energy=5.5;meter_power=1234"
+    receiver.close()
+    await asyncio.sleep(0.05)
+    assert processor.get_delivery_status("meter")["meter_energy"]["status"] == "unconfirmed"
+
+
+@pytest.mark.asyncio
+async def test_http_batch_window(config_instance):
+    config_instance.miniserver.batch_mode = "window"
+    config_instance.miniserver.batch_window_ms = 50
+    config_instance.miniserver.batch_input = "mqtt_batch"
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+
+    processor.process_data("a/x", "1")
+    processor.process_data("a/y", "2")
+    processor.http_handler_obj.send_to_miniserver.assert_not_called()
+    await asyncio.sleep(0.15)
+    processor.http_handler_obj.send_to_miniserver.assert_called_once_with("mqtt_batch", "mqtt_batch", "a_x=1;a_y=2")
+    assert processor.get_delivery_status("a/y")["a_y"]["status"] == "delivered"
+
+
+@pytest.mark.asyncio
+async def test_batch_window_sent_when_full(config_instance):
+    config_instance.miniserver.batch_mode = "window"
+    config_instance.miniserver.batch_window_ms = 10000
+    config_instance.miniserver.batch_max_values = 2
+    config_instance.miniserver.batch_input = "mqtt_batch"
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+
+    for topic in ["a/x", "a/y", "a/z"]:
+        processor.process_data(topic, "1")
+    await asyncio.sleep(0.05)
+    processor.http_handler_obj.send_to_miniserver.assert_called_once_with("mqtt_batch", "mqtt_batch", "a_x=1;a_y=1")
+
+
+def test_invalid_batch_mode_rejected(config_instance):
+    config_instance.miniserver.batch_mode = "sometimes"
+    with pytest.raises(ValueError):
+        TestMiniserverDataProcessor(config_instance)
+
+
+@pytest.mark.parametrize("policy,expected", [
+    ("deny_overrides", {"wl/ok"}),
+    ("allow_overrides", {"wl/ok", "wl/filtered", "wl/dnf", "other/ok"}),
+    ("whitelist_only", {"wl/ok", "wl/filtered", "wl/dnf"}),
+])
+@pytest.mark.asyncio
+async def test_filter_policies(config_instance, policy, expected):
+    config_instance.topics.policy = policy
+    config_instance.topics.topic_whitelist = ["wl_ok", "wl_filtered", "wl_dnf"]
+    config_instance.topics.subscription_filters = ["filtered"]
+    config_instance.topics.do_not_forward = ["dnf"]
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    assert processor.policy == policy
+
+    for topic in ["wl/ok", "wl/filtered", "wl/dnf", "other/ok", "other/filtered", "other/dnf"]:
+        processor.process_data(topic, "1")
+
+    forwarded = {call[0][0] for call in processor.http_handler_obj.send_to_miniserver.call_args_list}
+    assert forwarded == expected
+
+
+def test_invalid_policy_rejected(config_instance):
+    config_instance.topics.policy = "allow_all"
+    with pytest.raises(ValueError):
+        TestMiniserverDataProcessor(config_instance)
+
+
+SCRIPTS = [
+    {"match": r"^shelly/", "script": """
+let out = [];
+if json != () {
+    out.push([topic + "/power", json.apower * 2]);
+    out.push(#{topic: topic + "/on", value: json.output});
+}
+out
+"""},
+    {"match": r"^drop/", "script": "()"},
+    {"match": r"^endless/", "script": "let x = 0; loop { x += 1; }"},
+]
+
+
+@pytest.fixture
+def script_processor(config_instance):
+    config_instance.processing.scripts = SCRIPTS
+    return TestMiniserverDataProcessor(config_instance).processor
+
+
+@pytest.mark.asyncio
+async def test_script_outputs_are_forwarded(script_processor):
+    script_processor.process_data("shelly/plug", '{"apower": 10.5, "output": true}')
+    calls = [call[0] for call in script_processor.http_handler_obj.send_to_miniserver.call_args_list]
+    assert calls == [
+        ("shelly/plug/power", "shelly_plug_power", "21.0"),
+        ("shelly/plug/on", "shelly_plug_on", "1"),
+    ]
+
+
+@pytest.mark.asyncio
+async def test_script_can_drop_messages(script_processor):
+    script_processor.process_data("drop/me", "1")
+    script_processor.http_handler_obj.send_to_miniserver.assert_not_called()
+
+
+@pytest.mark.asyncio
+async def test_runaway_script_is_aborted(script_processor):
+    script_processor.process_data("endless/loop", "1")
+    script_processor.http_handler_obj.send_to_miniserver.assert_not_called()
+
+
+@pytest.mark.asyncio
+async def test_topics_without_script_are_expanded(script_processor, monkeypatch):
+    monkeypatch.setattr(global_config.processing, 'expand_json', True)
+    script_processor.reload_config()
+    script_processor.process_data("plain/topic", '{"a": 1}')
+    script_processor.http_handler_obj.send_to_miniserver.assert_called_once_with("plain/topic/a", "plain_topic_a", "1")
+
+
+def test_script_compile_error_rejected(config_instance):
+    config_instance.processing.scripts = [{"match": "^x/", "script": "let = ;"}]
+    with pytest.raises(ValueError):
+        TestMiniserverDataProcessor(config_instance)
+
+
+@pytest.mark.asyncio
+async def test_update_scripts_replaces_them(script_processor):
+    script_processor.update_scripts([{"match": r"^drop/", "script": '[[topic, "kept"]]'}])
+    assert script_processor.get_script_patterns() == [r"^drop/"]
+    script_processor.process_data("drop/me", "1")
+    script_processor.http_handler_obj.send_to_miniserver.assert_called_once_with("drop/me", "drop_me", "kept")
+
+
+def test_update_scripts_keeps_old_ones_on_error(script_processor):
+    with pytest.raises(ValueError):
+        script_processor.update_scripts([{"match": "^x/", "script": "let = ;"}])
+    assert script_processor.get_script_patterns() == [s["match"] for s in SCRIPTS]
+
+
+PLUGIN_WAT = r'''
+(module
+  (memory (export "memory") 1)
+  (global $next (mut i32) (i32.const 1024))
+  (data (i32.const 16) "[[\22dec/temp\22, 21.5], [\22dec/on\22, \22true\22]]")
+  (func (export "alloc") (param $len i32) (result i32)
+    (local $p i32)
+    (local.set $p (global.get $next))
+    (global.set $next (i32.add (global.get $next) (local.get $len)))
+    (local.get $p))
+  (func (export "process") (param i32 i32 i32 i32) (result i64)
+    (if (i32.eq (i32.load8_u (local.get 2)) (i32.const 48))
+      (then (return (i64.const 0))))
+    (i64.or (i64.shl (i64.const 16) (i64.const 32)) (i64.const 40))))
+'''
+
+wasm_only = pytest.mark.skipif(
+    not MiniserverDataProcessor.wasm_plugins_supported(), reason="built without the wasm feature"
+)
+
+
+@pytest.fixture
+def plugin_file(tmp_path):
+    path = tmp_path / "decoder.wat"
+    path.write_text(PLUGIN_WAT)
+    return str(path)
+
+
+@wasm_only
+@pytest.mark.asyncio
+async def test_plugin_outputs_are_forwarded(config_instance, plugin_file):
+    config_instance.processing.plugins = [{"match": "^dev/", "file": plugin_file}]
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    assert processor.get_plugin_patterns() == ["^dev/"]
+
+    processor.process_data("dev/raw", "1")
+    calls = [call[0] for call in processor.http_handler_obj.send_to_miniserver.call_args_list]
+    assert calls == [("dec/temp", "dec_temp", "21.5"), ("dec/on", "dec_on", "1")]
+
+    processor.http_handler_obj.send_to_miniserver.reset_mock()
+    processor.process_data("dev/raw", "0")
+    processor.http_handler_obj.send_to_miniserver.assert_not_called()
+
+
+@wasm_only
+def test_plugin_missing_file_rejected(config_instance):
+    config_instance.processing.plugins = [{"match": "^dev/", "file": "/nonexistent/decoder.wasm"}]
+    with pytest.raises(ValueError):
+        TestMiniserverDataProcessor(config_instance)
+
+
+@pytest.mark.skipif(MiniserverDataProcessor.wasm_plugins_supported(), reason="built with the wasm feature")
+def test_plugins_require_wasm_feature(config_instance, plugin_file):
+    config_instance.processing.plugins = [{"match": "^dev/", "file": plugin_file}]
+    with pytest.raises(ValueError, match="wasm"):
+        TestMiniserverDataProcessor(config_instance)
+
+
+@pytest.mark.asyncio
+async def test_miniserver_payload_template(config_instance, monkeypatch):
+    monkeypatch.setattr(global_config.processing, 'expand_json', True)
+    config_instance.processing.payload_templates = {"miniserver": '{{value}}|{{lookup fields "state/temp"}}'}
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    processor.process_data("dev/x", '{"state": {"temp": 21}}')
+    processor.http_handler_obj.send_to_miniserver.assert_called_once_with("dev/x/state/temp", "dev_x_state_temp", "21|21")
+
+
+@pytest.mark.asyncio
+async def test_payload_template_fields_use_flatten_options(config_instance, monkeypatch):
+    monkeypatch.setattr(global_config.processing, 'expand_json', True)
+    config_instance.processing.flatten_separator = "."
+    config_instance.processing.payload_templates = {"miniserver": '{{lookup fields "state.temp"}}/{{lookup fields "state.hum"}}'}
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    processor.process_data("dev/x", '{"state": {"temp": 21, "hum": 40}}')
+    sent = sorted(call[0][2] for call in processor.http_handler_obj.send_to_miniserver.call_args_list)
+    assert sent == ["21/40", "21/40"]
+
+
+@pytest.mark.asyncio
+async def test_udp_payload_template(config_instance):
+    receiver = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+    receiver.bind(("127.0.0.1", 0))
+    receiver.settimeout(2)
+    config_instance.udp.udp_out_destinations = [f"127.0.0.1:{receiver.getsockname()[1]}"]
+    config_instance.topics.rules = [{"match": r"^meter/", "action": "accept", "transport": "udp"}]
+    config_instance.processing.payload_templates = {"udp": "{{value}}0", "miniserver": "unused"}
+    processor = TestMiniserverDataProcessor(config_instance).processor
+
+    processor.process_data("meter/power", "42")
+    assert receiver.recvfrom(1024)[0] == b"meter_power=420"
+    receiver.close()
+
+
+def test_render_mqtt_payload_template(config_instance):
+    config_instance.processing.payload_templates = {"mqtt": '{"topic": "{{topic}}", "vi": "{{normalized_topic}}", "value": {{value}}}'}
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    assert json.loads(processor.render_payload("mqtt", "lox/out", "42")) == {"topic": "lox/out", "vi": "lox_out", "value": 42}
+    assert processor.render_payload("miniserver", "lox/out", "42") is None
+
+
+@pytest.mark.parametrize("templates", [{"unknown": "{{value}}"}, {"mqtt": "{{#if}}"}])
+def test_invalid_payload_templates_rejected(config_instance, templates):
+    config_instance.processing.payload_templates = templates
+    with pytest.raises(ValueError):
+        TestMiniserverDataProcessor(config_instance)
+
+
+BIG_NUMBERS = {
+    "i64_max": "9223372036854775807",
+    "u64_max": "18446744073709551615",
+    "above_u64": "18446744073709551616",
+    "i64_min_minus_one": "-9223372036854775809",
+    "above_2_53": "9007199254740993",
+    "ieee_addr": "340282366920938463463374607431768211455",
+}
+
+
+def test_expand_json_keeps_big_integers_exact(processor):
+    message = "{" + ", ".join(f'"{k}": {v}' for k, v in BIG_NUMBERS.items()) + ', "float": 21.50}'
+    result = dict(processor.expand_json("dev", message))
+    expected = {f"dev/{k}": v for k, v in BIG_NUMBERS.items()}
+    expected["dev/float"] = "21.5"
+    assert result == expected
+
+
+@pytest.mark.asyncio
+async def test_process_data_forwards_big_integers_exact(processor, monkeypatch):
+    monkeypatch.setattr(global_config.processing, 'expand_json', True)
+    processor.process_data("zigbee/device", '{"ieee": 340282366920938463463374607431768211455}')
+    processor.http_handler_obj.send_to_miniserver.assert_called_once_with(
+        "zigbee/device/ieee", "zigbee_device_ieee", "340282366920938463463374607431768211455"
+    )
+
+
+REWRITES = [
+    {"match": r"^zigbee2mqtt/(?P<room>[^/]+)/temperature$", "target": "temp_$room"},
+    {"match": r"^tele/", "target": ""},
+]
+
+
+@pytest.mark.asyncio
+async def test_rewrites_map_topics_to_virtual_inputs(config_instance):
+    config_instance.topics.rewrites = REWRITES
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    processor.process_data("zigbee2mqtt/kitchen/temperature", "21")
+    processor.process_data("tele/sensor/power", "5")
+    processor.process_data("other/topic", "1")
+    calls = [call[0] for call in processor.http_handler_obj.send_to_miniserver.call_args_list]
+    assert calls == [
+        ("zigbee2mqtt/kitchen/temperature", "temp_kitchen", "21"),
+        ("tele/sensor/power", "sensor_power", "5"),
+        ("other/topic", "other_topic", "1"),
+    ]
+
+
+@pytest.mark.asyncio
+async def test_whitelist_checks_rewritten_name(config_instance):
+    config_instance.topics.rewrites = REWRITES
+    config_instance.topics.topic_whitelist = ["temp_kitchen"]
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    processor.process_data("zigbee2mqtt/kitchen/temperature", "21")
+    processor.process_data("zigbee2mqtt/bath/temperature", "22")
+    processor.http_handler_obj.send_to_miniserver.assert_called_once_with(
+        "zigbee2mqtt/kitchen/temperature", "temp_kitchen", "21"
+    )
+
+
+@pytest.mark.asyncio
+async def test_filters_match_rewritten_topic(config_instance):
+    config_instance.topics.rewrites = REWRITES
+    config_instance.topics.subscription_filters = [r"^sensor/"]
+    config_instance.topics.do_not_forward = [r"^temp_kitchen$"]
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    processor.process_data("zigbee2mqtt/kitchen/temperature", "21")
+    processor.process_data("zigbee2mqtt/bath/temperature", "22")
+    processor.process_data("tele/sensor/power", "5")
+    processor.http_handler_obj.send_to_miniserver.assert_called_once_with(
+        "zigbee2mqtt/bath/temperature", "temp_bath", "22"
+    )
+    results = processor.test_filters(["zigbee2mqtt/kitchen/temperature", "tele/sensor/power"])
+    assert results["zigbee2mqtt/kitchen/temperature"]["reason"] == "do_not_forward #0 '^temp_kitchen$'"
+    assert results["tele/sensor/power"]["reason"] == "subscription_filter #0 '^sensor/'"
+
+    # Changed rewrites retire the cached decisions
+    processor.update_rewrites([])
+    assert processor.test_filters(["zigbee2mqtt/kitchen/temperature"])["zigbee2mqtt/kitchen/temperature"]["result"] == "forwarded"
+
+
+@pytest.mark.parametrize("expand_json", [True, False])
+@pytest.mark.asyncio
+async def test_extract_named_fields(config_instance, expand_json):
+    config_instance.processing.expand_json = expand_json
+    config_instance.processing.extract = [
+        {"pattern": "^zigbee2mqtt/", "fields": {"temp": "temperature", "battery": "/power/battery", "first": "list.0"}},
+        {"pattern": "^shelly/", "fields": ["meters.0.power", "/wifi"]},
+    ]
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    processor.process_data("zigbee2mqtt/kitchen", json.dumps({
+        "temperature": 21.5, "humidity": 40, "power": {"battery": 90, "voltage": 3000}, "list": ["a", "b"],
+    }))
+    processor.process_data("shelly/plug", json.dumps({"meters": [{"power": 12.5, "total": 4}], "wifi": {"rssi": -60}}))
+    # No match: the payload goes through expand_json as usual
+    processor.process_data("other/dev", json.dumps({"a": 1}))
+    sent = [call[0][1:] for call in processor.http_handler_obj.send_to_miniserver.call_args_list]
+    assert sent[:5] == [
+        ("zigbee2mqtt_kitchen_temp", "21.5"),
+        ("zigbee2mqtt_kitchen_battery", "90"),
+        ("zigbee2mqtt_kitchen_first", "a"),
+        ("shelly_plug_meters_0_power", "12.5"),
+        ("shelly_plug_wifi_rssi", "-60"),
+    ]
+    assert sent[5:] == ([("other_dev_a", "1")] if expand_json else [("other_dev", '{"a": 1}')])
+
+
+@pytest.mark.parametrize("options, expected", [
+    ({}, {("dev/a/b/c", "1"), ("dev/list/0", "x"), ("dev/list/1", "2"), ("dev/list/2/k", "v")}),
+    ({"flatten_separator": "_"}, {("dev/a_b_c", "1"), ("dev/list_0", "x"), ("dev/list_1", "2"), ("dev/list_2_k", "v")}),
+    ({"flatten_max_depth": 1}, {("dev/a", '{"b":{"c":1}}'), ("dev/list", '["x",2,{"k":"v"}]')}),
+    ({"flatten_max_depth": 2}, {("dev/a/b", '{"c":1}'), ("dev/list/0", "x"), ("dev/list/1", "2"), ("dev/list/2", '{"k":"v"}')}),
+    ({"flatten_arrays": "join"}, {("dev/a/b/c", "1"), ("dev/list", 'x,2,{"k":"v"}')}),
+    ({"flatten_arrays": "skip"}, {("dev/a/b/c", "1")}),
+])
+def test_flatten_options(config_instance, options, expected):
+    for name, value in options.items():
+        setattr(config_instance.processing, name, value)
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    payload = json.dumps({"a": {"b": {"c": 1}}, "list": ["x", 2, {"k": "v"}]})
+    assert set(processor.expand_json("dev", payload)) == expected
+
+
+@pytest.mark.asyncio
+async def test_flatten_options_apply_to_extracted_fields(config_instance):
+    config_instance.processing.flatten_arrays = "join"
+    config_instance.processing.extract = [{"pattern": "^dev$", "fields": ["list"]}]
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    processor.process_data("dev", json.dumps({"list": [1, 2, 3], "other": 4}))
+    processor.http_handler_obj.send_to_miniserver.assert_called_once_with("dev/list", "dev_list", "1,2,3")
+
+
+@pytest.mark.parametrize("name, value", [("flatten_arrays", "split"), ("flatten_separator", "")])
+def test_invalid_flatten_options_rejected(config_instance, name, value):
+    setattr(config_instance.processing, name, value)
+    with pytest.raises(ValueError):
+        TestMiniserverDataProcessor(config_instance)
+
+
+@pytest.mark.parametrize("extract", [
+    [{"pattern": "^a/"}],
+    [{"pattern": "^a/", "fields": "temperature"}],
+    [{"pattern": "^a/", "fields": {"": "temperature"}}],
+])
+def test_invalid_extractions_rejected(config_instance, extract):
+    config_instance.processing.extract = extract
+    with pytest.raises(ValueError):
+        TestMiniserverDataProcessor(config_instance)
+
+
+TRANSFORMS = [
+    {"pattern": "/power$", "scale": 0.001, "precision": 2},
+    {"pattern": "/temperature$", "offset": -0.5, "precision": 1, "min": -40, "max": 80},
+    {"pattern": "/humidity$", "min": 0, "max": 100, "clamp": True},
+    {"pattern": "/temperature_f$", "convert": "f_to_c", "precision": 1},
+    {"pattern": "/wind_mph$", "convert": "mph_to_kmh", "scale": 2, "offset": 1, "precision": 2},
+]
+
+
+@pytest.mark.parametrize("topic,value,expected", [
+    ("plug/power", "1234.5", "1.23"),
+    ("plug/power", " 500 ", "0.50"),
+    ("room/temperature", "21.26", "20.8"),
+    ("room/temperature", "85", None),
+    ("room/temperature", "unavailable", "unavailable"),
+    ("room/humidity", "104", "100"),
+    ("room/pressure", "1013.25", "1013.25"),
+    ("station/temperature_f", "68", "20.0"),
+    ("station/temperature_f", "-40", "-40.0"),
+    ("station/wind_mph", "10", "33.19"),
+])
+@pytest.mark.asyncio
+async def test_transforms(config_instance, topic, value, expected):
+    config_instance.processing.transforms = TRANSFORMS
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    processor.process_data(topic, value)
+    sent = [call[0][2] for call in processor.http_handler_obj.send_to_miniserver.call_args_list]
+    assert sent == ([] if expected is None else [expected])
+
+
+def test_update_and_get_transforms(processor):
+    assert processor.get_transforms() == []
+    processor.update_transforms(TRANSFORMS[1:2])
+    assert processor.get_transforms() == [
+        {"pattern": "/temperature$", "offset": -0.5, "precision": 1, "min": -40.0, "max": 80.0}
+    ]
+    processor.update_transforms(TRANSFORMS[3:4])
+    assert processor.get_transforms() == [{"pattern": "/temperature_f$", "convert": "f_to_c", "precision": 1}]
+    for transforms in ([{"scale": 2}], [{"pattern": "(", "scale": 2}], [{"pattern": "a", "min": 5, "max": 1}],
+                       [{"pattern": "a", "convert": "furlongs_to_m"}]):
+        with pytest.raises(ValueError):
+            processor.update_transforms(transforms)
+
+
+DERIVED = [
+    {"topic": "shelly/total_power", "sources": ["shelly/0/power", "shelly/1/power", "shelly/2/power"], "op": "sum"},
+    {"topic": "heating/spread", "sources": {"flow": "heating/flow", "ret": "heating/return"},
+     "expression": "flow - ret", "precision": 1},
+]
+
+
+@pytest.mark.asyncio
+async def test_derived_topics(config_instance):
+    config_instance.processing.derived = DERIVED
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    send = processor.http_handler_obj.send_to_miniserver
+    processor.process_data("shelly/0/power", "100")
+    processor.process_data("shelly/1/power", "50.5")
+    assert [c.args[0] for c in send.call_args_list] == ["shelly/0/power", "shelly/1/power"]
+    send.reset_mock()
+    processor.process_data("shelly/2/power", "20")
+    assert send.call_args_list[-1].args == ("shelly/total_power", "shelly_total_power", "170.5")
+    send.reset_mock()
+    processor.process_data("shelly/0/power", "off")
+    processor.process_data("heating/flow", "45.25")
+    processor.process_data("heating/return", "31")
+    assert [c.args[0] for c in send.call_args_list] == ["shelly/0/power", "heating/flow", "heating/return", "heating/spread"]
+    assert send.call_args_list[-1].args[2] == "14.2"
+
+
+def test_update_and_get_derived(processor):
+    assert processor.get_derived() == []
+    processor.update_derived(DERIVED)
+    assert processor.get_derived() == DERIVED
+    for derived in ([{"topic": "a", "sources": []}], [{"topic": "a", "sources": ["b"], "op": "median"}],
+                    [{"topic": "a", "sources": {"x": "b"}, "expression": "x +"}], [{"topic": "a", "sources": ["a"]}]):
+        with pytest.raises(ValueError):
+            processor.update_derived(derived)
+
+
+def test_update_and_get_rewrites(processor):
+    assert processor.get_rewrites() == []
+    processor.update_rewrites(REWRITES[:1])
+    assert processor.get_rewrites() == REWRITES[:1]
+    with pytest.raises(ValueError):
+        processor.update_rewrites([{"match": "^a/"}])
+    processor.update_topic_rewrites(REWRITES)
+    assert processor.get_rewrites() == REWRITES
+
+
+@pytest.mark.asyncio
+async def test_strip_prefixes_before_normalization(config_instance):
+    config_instance.topics.strip_prefixes = ["homeassistant/", "tele/"]
+    config_instance.topics.topic_whitelist = ["sensor_temp"]
+    config_instance.topics.do_not_forward = [r"^tele/secret"]
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    processor.process_data("homeassistant/sensor/temp", "1")
+    processor.process_data("tele/sensor/temp", "2")
+    processor.process_data("other/sensor/temp", "3")
+    calls = [call[0] for call in processor.http_handler_obj.send_to_miniserver.call_args_list]
+    assert calls == [
+        ("homeassistant/sensor/temp", "sensor_temp", "1"),
+        ("tele/sensor/temp", "sensor_temp", "2"),
+    ]
+    assert processor.is_in_whitelist("tele/sensor/temp")
+
+
+@pytest.mark.asyncio
+async def test_strip_prefixes_keep_original_topic_for_filters(config_instance):
+    config_instance.topics.strip_prefixes = ["tele/"]
+    config_instance.topics.do_not_forward = [r"^tele/secret"]
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    processor.process_data("tele/secret/value", "1")
+    processor.http_handler_obj.send_to_miniserver.assert_not_called()
+
+    processor.update_strip_prefixes([])
+    processor.process_data("tele/other/value", "1")
+    processor.http_handler_obj.send_to_miniserver.assert_called_once_with("tele/other/value", "tele_other_value", "1")
+
+
+@pytest.mark.asyncio
+async def test_standby_instance_does_not_forward(config_instance):
+    config_instance.ha.ha_enabled = True
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    assert processor.active is False
+    processor.process_data("sensor/temp", "21")
+    processor.http_handler_obj.send_to_miniserver.assert_not_called()
+
+    processor.set_active(True)
+    processor.process_data("sensor/temp", "21")
+    processor.http_handler_obj.send_to_miniserver.assert_called_once_with("sensor/temp", "sensor_temp", "21")
+
+
+def test_ha_lock_topic_is_handed_to_relay(config_instance):
+    test_processor = TestMiniserverDataProcessor(config_instance)
+    test_processor.mock_relay_main.handle_ha_lock = MagicMock()
+    test_processor.processor.handle_mqtt_message("myrelay/ha/lock", b'{"owner": "a", "lease": 30}')
+    test_processor.mock_relay_main.handle_ha_lock.assert_called_once_with('{"owner": "a", "lease": 30}')
+
+
+def test_export_topic_is_handed_to_relay(config_instance):
+    test_processor = TestMiniserverDataProcessor(config_instance)
+    test_processor.mock_relay_main.export_virtual_inputs = MagicMock()
+    test_processor.processor.handle_mqtt_message("myrelay/export/virtual_inputs", b"csv")
+    test_processor.mock_relay_main.export_virtual_inputs.assert_called_once_with("csv")
+
+
+@pytest.mark.asyncio
+async def test_forwarded_inputs_are_tracked(config_instance):
+    config_instance.processing.expand_json = True
+    config_instance.topics.strip_prefixes = ["zigbee2mqtt/"]
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    processor.process_data("zigbee2mqtt/kitchen", '{"temp": 21, "hum": 40}')
+    assert processor.get_forwarded_inputs() == {
+        "kitchen_temp": "zigbee2mqtt/kitchen/temp",
+        "kitchen_hum": "zigbee2mqtt/kitchen/hum",
+    }
+
+
+@pytest.mark.asyncio
+async def test_over_length_names_are_shortened_with_stable_hash(config_instance):
+    config_instance.topics.max_name_length = 24
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    long_topic = "zigbee2mqtt/living_room/thermostat/local_temperature"
+    processor.process_data(long_topic, "21")
+    processor.process_data("zigbee2mqtt/living_room/thermostat/local_humidity", "40")
+    processor.process_data("short/topic", "1")
+
+    calls = [call[0] for call in processor.http_handler_obj.send_to_miniserver.call_args_list]
+    first, second, short = (name for _, name, _ in calls)
+    assert len(first) == 24 and len(second) == 24
+    assert first.startswith("zigbee2mqtt_liv_") and first != second
+    assert short == "short_topic"
+
+    # Deterministic across processor instances and listed in the mapping table
+    again = TestMiniserverDataProcessor(config_instance).processor
+    again.process_data(long_topic, "21")
+    assert again.http_handler_obj.send_to_miniserver.call_args[0][1] == first
+    assert again.get_vi_name_mapping() == {"zigbee2mqtt_living_room_thermostat_local_temperature": first}
+
+
+@pytest.mark.asyncio
+async def test_name_length_limit_can_be_disabled(config_instance):
+    config_instance.topics.max_name_length = 0
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    processor.process_data("a" * 100, "1")
+    processor.http_handler_obj.send_to_miniserver.assert_called_once_with("a" * 100, "a" * 100, "1")
+
+
+@pytest.mark.asyncio
+async def test_delivery_outcome_is_recorded_per_input(config_instance):
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    processor.http_handler_obj.send_to_miniserver = AsyncMock(
+        side_effect=[{"code": 200}, {"code": 503}, {"queued": True}, None]
+    )
+    for topic in ("sensor/a", "sensor/b", "sensor/c", "sensor/d"):
+        processor.process_data(topic, "1")
+    await asyncio.sleep(0.1)
+
+    status = processor.get_delivery_status()
+    assert {name: (s["status"], s["code"]) for name, s in status.items()} == {
+        "sensor_a": ("delivered", 200),
+        "sensor_b": ("failed", 503),
+        "sensor_c": ("queued", None),
+        "sensor_d": ("unconfirmed", None),
+    }
+    assert status["sensor_a"]["topic"] == "sensor/a"
+    assert status["sensor_a"]["value"] == "1"
+    assert status["sensor_a"]["latency_ms"] is not None
+    assert len({s["id"] for s in status.values()}) == 4
+    assert list(processor.get_delivery_status("sensor/b")) == ["sensor_b"]
+
+
+@pytest.mark.asyncio
+async def test_late_result_does_not_override_newer_send(config_instance):
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    release = asyncio.Event()
+
+    async def slow_then_fast(topic, name, value):
+        if value == "1":
+            await release.wait()
+            return {"code": 200}
+        return {"code": 500}
+
+    processor.http_handler_obj.send_to_miniserver = slow_then_fast
+    processor.process_data("sensor/a", "1")
+    processor.process_data("sensor/a", "2")
+    await asyncio.sleep(0.05)
+    release.set()
+    await asyncio.sleep(0.05)
+
+    status = processor.get_delivery_status()["sensor_a"]
+    assert (status["value"], status["status"], status["code"]) == ("2", "failed", 500)
+
+
+@pytest.mark.asyncio
+async def test_delivery_status_topic_publishes_response(config_instance):
+    test_processor = TestMiniserverDataProcessor(config_instance)
+    test_processor.mock_mqtt_client.publish = AsyncMock()
+    test_processor.processor.handle_mqtt_message("myrelay/delivery/get", b"")
+    assert test_processor.mock_mqtt_client.publish.call_args[0] == ("myrelay/delivery/response", "{}")
+
+
+def test_filters_dry_run(processor):
+    processor.update_subscription_filters([r"^zigbee/bridge/"])
+    processor.update_do_not_forward([r"^x/", r"/raw$"])
+    processor.update_topic_whitelist(["sensor_temp", "dev_raw"])
+    results = processor.test_filters(["sensor/temp", "dev/raw", "zigbee/bridge/state", "other/topic"])
+    assert results["sensor/temp"] == {
+        "name": "sensor_temp", "result": "forwarded", "reason": None, "rule": None,
+        "subscription_filter": None, "whitelisted": True, "post_expansion_filter": None, "do_not_forward": None,
+        "monitor_topics": None, "target": "default",
+    }
+    assert results["dev/raw"]["result"] == "filtered"
+    assert results["dev/raw"]["reason"] == "do_not_forward #1 '/raw$'"
+    assert results["dev/raw"]["do_not_forward"] == {"index": 1, "pattern": "/raw$"}
+    assert results["zigbee/bridge/state"]["subscription_filter"] == {"index": 0, "pattern": "^zigbee/bridge/"}
+    assert results["other/topic"]["reason"] == "'other_topic' is not in the whitelist"
+    processor.http_handler_obj.send_to_miniserver.assert_not_called()
+
+
+@pytest.mark.asyncio
+async def test_monitor_topics_published_but_not_forwarded(config_instance):
+    config_instance.topics.monitor_topics = ["/linkquality$", "/state$"]
+    config_instance.processing.expand_json = True
+    test_processor = TestMiniserverDataProcessor(config_instance)
+    processor = test_processor.processor
+    test_processor.mock_mqtt_client.publish = AsyncMock()
+    processor.process_data("zigbee/kitchen", '{"linkquality": 120, "state": "ON", "temp": 21}')
+    await asyncio.sleep(0.05)
+
+    publish = test_processor.mock_mqtt_client.publish
+    publish.assert_any_call("myrelay/monitored/zigbee/kitchen/linkquality", "120")
+    publish.assert_any_call("myrelay/monitored/zigbee/kitchen/state", "1")
+    processor.http_handler_obj.send_to_miniserver.assert_called_once()
+    assert processor.http_handler_obj.send_to_miniserver.call_args[0][0] == "zigbee/kitchen/temp"
+    assert processor.get_stats()["monitored"] == 2
+    assert processor.test_filters(["zigbee/kitchen/linkquality"])["zigbee/kitchen/linkquality"]["result"] == "monitored"
+    assert processor.get_monitor_topics() == ["/linkquality$"]
+
+
+@pytest.mark.asyncio
+async def test_stale_inputs_reported_offline(config_instance):
+    config_instance.processing.stale_timeout = 0.05
+    config_instance.processing.stale_value = "-1"
+    config_instance.processing.stale_topics = ["^sensor/"]
+    test_processor = TestMiniserverDataProcessor(config_instance)
+    processor = test_processor.processor
+    test_processor.mock_mqtt_client.publish = AsyncMock()
+    send = processor.http_handler_obj.send_to_miniserver
+    processor.process_data("sensor/temp", "21.5")
+    processor.process_data("other/temp", "20")
+    assert processor.check_stale() == 0
+    await asyncio.sleep(0.1)
+
+    assert processor.check_stale() == 1
+    assert processor.check_stale() == 0
+    assert send.call_args.args == ("sensor/temp", "sensor_temp", "-1")
+    assert list(processor.get_stale_inputs()) == ["sensor_temp"]
+    assert processor.get_stats()["stale"] == 1
+    await asyncio.sleep(0.01)
+    test_processor.mock_mqtt_client.publish.assert_any_call("myrelay/availability/sensor_temp", "offline", True)
+
+    processor.process_data("sensor/temp", "22")
+    await asyncio.sleep(0.01)
+    test_processor.mock_mqtt_client.publish.assert_any_call("myrelay/availability/sensor_temp", "online", True)
+    assert processor.get_stale_inputs() == {}
+
+
+@pytest.mark.asyncio
+async def test_filters_test_topic_publishes_response(config_instance):
+    test_processor = TestMiniserverDataProcessor(config_instance)
+    test_processor.mock_mqtt_client.publish = AsyncMock()
+    test_processor.processor.handle_mqtt_message("myrelay/filters/test", b'["a/b"]')
+    topic, payload = test_processor.mock_mqtt_client.publish.call_args[0]
+    assert topic == "myrelay/filters/test/response"
+    assert json.loads(payload)["a/b"]["result"] == "forwarded"
+
+
+@pytest.mark.asyncio
+async def test_duplicate_window_suppresses_resend_after_delivery(config_instance):
+    config_instance.processing.duplicate_window_seconds = 60
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    send = processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+
+    processor.process_data("sensor/temp", "21")
+    await asyncio.sleep(0.05)
+    # Identical value again, e.g. a retained flood after a reconnect
+    processor.process_data("sensor/temp", "21")
+    await asyncio.sleep(0.05)
+    assert send.call_count == 1
+
+    # A changed value is sent, and so is the old one afterwards
+    processor.process_data("sensor/temp", "22")
+    await asyncio.sleep(0.05)
+    processor.process_data("sensor/temp", "21")
+    await asyncio.sleep(0.05)
+    assert [call[0][2] for call in send.call_args_list] == ["21", "22", "21"]
+
+
+@pytest.mark.asyncio
+async def test_duplicate_window_resends_after_failure(config_instance):
+    config_instance.processing.duplicate_window_seconds = 60
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    send = processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 503})
+    processor.process_data("sensor/temp", "21")
+    await asyncio.sleep(0.05)
+    processor.process_data("sensor/temp", "21")
+    await asyncio.sleep(0.05)
+    assert send.call_count == 2
+
+
+@pytest.mark.asyncio
+async def test_forward_only_changes(config_instance):
+    config_instance.processing.forward_only_changes = True
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    send = processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+
+    # Repeats are skipped even while the first send is still in flight
+    for value in ["21", "21", "22", "22", "21"]:
+        processor.process_data("sensor/temp", value)
+    await asyncio.sleep(0.05)
+    assert [call[0][2] for call in send.call_args_list] == ["21", "22", "21"]
+    assert processor.get_stats()["duplicates"] == 2
+
+    # After a failed send the same value is tried again
+    send.return_value = {"code": 503}
+    processor.process_data("sensor/temp", "23")
+    await asyncio.sleep(0.05)
+    processor.process_data("sensor/temp", "23")
+    await asyncio.sleep(0.05)
+    assert send.call_count == 5
+
+
+@pytest.mark.asyncio
+async def test_forward_only_changes_max_age(config_instance):
+    config_instance.processing.forward_only_changes = True
+    config_instance.processing.forward_only_changes_max_age = 0.1
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    send = processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+    processor.process_data("sensor/temp", "21")
+    processor.process_data("sensor/temp", "21")
+    await asyncio.sleep(0.15)
+    # Old enough to go out again as a heartbeat
+    processor.process_data("sensor/temp", "21")
+    await asyncio.sleep(0.05)
+    assert send.call_count == 2
+
+
+@pytest.mark.asyncio
+async def test_duplicates_are_sent_without_window(config_instance):
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    send = processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+    processor.process_data("sensor/temp", "21")
+    await asyncio.sleep(0.05)
+    processor.process_data("sensor/temp", "21")
+    await asyncio.sleep(0.05)
+    assert send.call_count == 2
+
+
+@pytest.mark.asyncio
+async def test_rate_limit_interval_keeps_the_last_value(config_instance):
+    config_instance.processing.rate_limit = [{"pattern": "^chatty_", "interval_ms": 200}]
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    send = processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+
+    for value in range(5):
+        processor.process_data("chatty/power", str(value))
+        processor.process_data("quiet/power", str(value))
+    await asyncio.sleep(0.05)
+    chatty = lambda: [call[0][2] for call in send.call_args_list if call[0][1] == "chatty_power"]
+    # The first value goes out right away, the rest wait for the interval
+    assert chatty() == ["0"]
+    assert send.call_count == 6
+    await asyncio.sleep(0.25)
+    assert chatty() == ["0", "4"]
+    stats = processor.get_stats()
+    assert stats["rate_limited"] == 3
+    assert stats["forwarded"] == 7
+
+
+@pytest.mark.asyncio
+async def test_rate_limit_debounce(config_instance):
+    config_instance.processing.rate_limit = [{"pattern": "^button_", "debounce_ms": 100}]
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    send = processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+
+    for value in ["1", "0", "1"]:
+        processor.process_data("button/living", value)
+        await asyncio.sleep(0.03)
+    send.assert_not_called()
+    assert processor.get_delivery_status("button/living") == {}
+    await asyncio.sleep(0.15)
+    send.assert_called_once_with("button/living", "button_living", "1")
+    assert processor.get_delivery_status("button/living")["button_living"]["status"] == "delivered"
+
+
+@pytest.mark.parametrize("rate_limit", [
+    [{"interval_ms": 100}],
+    [{"pattern": "^a"}],
+    [{"pattern": "(", "interval_ms": 100}],
+])
+def test_invalid_rate_limits_rejected(config_instance, rate_limit):
+    config_instance.topics.strict_filters = True
+    config_instance.processing.rate_limit = rate_limit
+    with pytest.raises(ValueError):
+        TestMiniserverDataProcessor(config_instance)
+
+
+@pytest.mark.asyncio
+async def test_skipped_messages_are_counted_and_sampled(config_instance):
+    config_instance.processing.max_message_bytes = 16
+    config_instance.processing.expand_json = True
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+
+    processor.handle_mqtt_message("sensor/big", b"x" * 17)
+    processor.handle_mqtt_message("sensor/bin", b"\xff\xfe")
+    processor.handle_mqtt_message("sensor/json", b'{"t": 21.5')
+    processor.handle_mqtt_message("sensor/ok", b"21")
+    await asyncio.sleep(0.05)
+
+    stats = processor.get_skip_stats()
+    assert stats["counts"] == {"invalid_utf8": 1, "oversized": 1, "invalid_json": 1}
+    assert [(s["reason"], s["topic"], s["size"]) for s in stats["samples"]] == [
+        ("oversized", "sensor/big", 17),
+        ("invalid_utf8", "sensor/bin", 2),
+        ("invalid_json", "sensor/json", 10),
+    ]
+    # Values are redacted, only the shape is kept
+    assert stats["samples"][2]["preview"] == '{"a": 00.0'
+    # Oversized messages are dropped, the others still forwarded
+    sent = {call[0][0] for call in processor.http_handler_obj.send_to_miniserver.call_args_list}
+    assert sent == {"sensor/bin", "sensor/json", "sensor/ok"}
+
+    processor.reset_skip_stats()
+    assert processor.get_skip_stats() == {"counts": {}, "samples": []}
+
+
+@pytest.mark.asyncio
+async def test_json_limits_drop_complex_messages(config_instance):
+    config_instance.processing.expand_json = True
+    config_instance.processing.max_json_depth = 2
+    config_instance.processing.max_json_keys = 3
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+
+    processor.handle_mqtt_message("sensor/deep", b'{"a": {"b": {"c": 1}}}')
+    processor.handle_mqtt_message("sensor/wide", b'{"a": 1, "b": 2, "c": 3, "d": 4}')
+    processor.handle_mqtt_message("sensor/ok", b'{"a": {"b": 1}, "c": 2, "d": 3}')
+    await asyncio.sleep(0.05)
+
+    assert processor.get_skip_stats()["counts"] == {"too_complex": 2}
+    sent = {call[0][0] for call in processor.http_handler_obj.send_to_miniserver.call_args_list}
+    assert sent == {"sensor/ok/a/b", "sensor/ok/c", "sensor/ok/d"}
+    assert processor.explain("sensor/wide", '{"a": 1, "b": 2, "c": 3, "d": 4}')["dropped"] == "4 values, more than 3"
+
+
+@pytest.mark.asyncio
+async def test_batch_processing(config_instance):
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+
+    handled = processor.handle_mqtt_messages_batch([("sensor/a", b"1"), (b"sensor/b", b"2"), ("sensor/c", object())])
+    # Same policy for both: the broken message is skipped, the rest is still sent
+    processed = processor.process_data_batch([("sensor/d", "4"), ("sensor/f", object()), ("sensor/e", b"5")])
+    await asyncio.sleep(0.05)
+
+    assert (handled, processed) == (2, 2)
+    sent = {call[0][0] for call in processor.http_handler_obj.send_to_miniserver.call_args_list}
+    assert sent == {"sensor/a", "sensor/b", "sensor/d", "sensor/e"}
+
+
+@pytest.mark.asyncio
+async def test_batch_call_values_sent_as_one_batch(config_instance):
+    config_instance.miniserver.batch_mode = "message"
+    config_instance.miniserver.batch_input = "mqtt_batch"
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+
+    assert processor.handle_mqtt_messages_batch([("a/x", b"1"), ("a/y", b"2")]) == 2
+    await asyncio.sleep(0.05)
+    processor.http_handler_obj.send_to_miniserver.assert_called_once_with("mqtt_batch", "mqtt_batch", "a_x=1;a_y=2")
+
+
+def _raw_deflate(data, level=6):
+    compressor = zlib.compressobj(level, zlib.DEFLATED, -15)
+    return compressor.compress(data) + compressor.flush()
+
+
+@pytest.mark.asyncio
+@pytest.mark.parametrize("compress", [
+    zlib.compress,
+    gzip.compress,
+    lambda data: zlib.compress(data, 0),  # stored blocks
+    lambda data: zlib.compress(data, 1),  # fixed Huffman codes for short input
+])
+async def test_compressed_payloads_are_inflated(config_instance, compress):
+    config_instance.processing.decompress = True
+    config_instance.processing.expand_json = True
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    send = processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+    readings = {f"sensor{i}": f"{i * 1.5} some repeated text" for i in range(200)}
+    processor.handle_mqtt_message("bridge/state", compress(json.dumps(readings).encode()))
+    processor.handle_mqtt_message("bridge/temp", compress(b"21.5"))
+    await asyncio.sleep(0.05)
+    sent = {call[0][0]: call[0][2] for call in send.call_args_list}
+    assert sent == {**{f"bridge/state/{k}": v for k, v in readings.items()}, "bridge/temp": "21.5"}
+    assert processor.get_skip_stats()["counts"] == {}
+
+
+@pytest.mark.asyncio
+async def test_decompress_topics_and_size_cap(config_instance):
+    config_instance.processing.decompress_topics = ["^raw/"]
+    config_instance.processing.max_decompressed_bytes = 100
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    send = processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+    # Raw DEFLATE has no header, only the topic says it is compressed
+    processor.handle_mqtt_message("raw/temp", _raw_deflate(b"21.5"))
+    # Without decompress, headers alone don't trigger it
+    processor.handle_mqtt_message("zlib/temp", zlib.compress(b"21.5"))
+    processor.handle_mqtt_message("raw/big", _raw_deflate(b"x" * 101))
+    await asyncio.sleep(0.05)
+    sent = {call[0][0]: call[0][2] for call in send.call_args_list}
+    assert sent["raw/temp"] == "21.5"
+    assert sent["zlib/temp"].startswith("[base64:")
+    assert sent["raw/big"].startswith("[base64:")
+    assert processor.get_skip_stats()["counts"] == {"invalid_compressed": 1, "invalid_utf8": 2}
+
+
+# {"temp": 21.5, "state": "ok", "ids": [1, -2], "big": 300}
+MSGPACK_PAYLOAD = (b"\x84\xa4temp\xcb" + struct.pack(">d", 21.5) + b"\xa5state\xa2ok"
+                   + b"\xa3ids\x92\x01\xfe\xa3big\xcd\x01\x2c")
+# {"temp": 21.5 (half float), "state": "ok", "ids": [1, -2], "bytes" (indefinite key): h'0102'}
+CBOR_PAYLOAD = (b"\xa4\x64temp\xf9\x4d\x60\x65state\x62ok\x63ids\x82\x01\x21"
+                + b"\x7f\x62by\x63tes\xff\x42\x01\x02")
+
+
+@pytest.mark.asyncio
+@pytest.mark.parametrize("fmt, payload, expected", [
+    ("msgpack", MSGPACK_PAYLOAD, {"temp": "21.5", "state": "ok", "ids/0": "1", "ids/1": "-2", "big": "300"}),
+    ("cbor", CBOR_PAYLOAD, {"temp": "21.5", "state": "ok", "ids/0": "1", "ids/1": "-2", "bytes": "[base64:AQI=]"}),
+    ("auto", MSGPACK_PAYLOAD, {"temp": "21.5", "state": "ok", "ids/0": "1", "ids/1": "-2", "big": "300"}),
+    ("auto", CBOR_PAYLOAD, {"temp": "21.5", "state": "ok", "ids/0": "1", "ids/1": "-2", "bytes": "[base64:AQI=]"}),
+])
+async def test_binary_payload_formats(config_instance, fmt, payload, expected):
+    config_instance.processing.expand_json = True
+    config_instance.processing.payload_formats = [{"pattern": "^esphome/", "format": fmt}]
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    send = processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+    processor.handle_mqtt_message("esphome/node", payload)
+    await asyncio.sleep(0.05)
+    assert {call[0][0]: call[0][2] for call in send.call_args_list} == {f"esphome/node/{k}": v for k, v in expected.items()}
+    assert processor.get_skip_stats()["counts"] == {}
+
+
+@pytest.mark.asyncio
+async def test_binary_payload_formats_fall_back_to_raw(config_instance):
+    config_instance.processing.payload_formats = [
+        {"pattern": "^auto/", "format": "auto"},
+        {"pattern": "^msgpack/", "format": "msgpack"},
+    ]
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    send = processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+    # Text stays text with "auto"
+    processor.handle_mqtt_message("auto/temp", b"21.5")
+    # Truncated: forwarded like any other binary payload
+    processor.handle_mqtt_message("msgpack/temp", MSGPACK_PAYLOAD[:-1])
+    await asyncio.sleep(0.05)
+    sent = {call[0][0]: call[0][2] for call in send.call_args_list}
+    assert sent["auto/temp"] == "21.5"
+    assert sent["msgpack/temp"].startswith("[base64:")
+    assert processor.get_skip_stats()["counts"] == {"invalid_binary": 1, "invalid_utf8": 1}
+
+
+@pytest.mark.asyncio
+async def test_xml_payloads_flattened(config_instance):
+    config_instance.processing.expand_json = False
+    config_instance.processing.xml_topics = ["^heatpump/"]
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    send = processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+    processor.handle_mqtt_message(
+        "heatpump/status",
+        b'<status unit="C"><temp id="flow">35.5</temp><temp id="return">30.1</temp><mode>heating</mode></status>',
+    )
+    processor.handle_mqtt_message("heatpump/broken", b"<status><temp>35.5</status>")
+    processor.handle_mqtt_message("heatpump/mode", b"<mode> heating </mode>")
+    processor.handle_mqtt_message("other/status", b"<mode>heating</mode>")
+    await asyncio.sleep(0.05)
+    sent = {call[0][0]: call[0][2] for call in send.call_args_list}
+    assert sent == {
+        "heatpump/status/unit": "C",
+        "heatpump/status/temp/0/id": "flow",
+        "heatpump/status/temp/0/value": "35.5",
+        "heatpump/status/temp/1/id": "return",
+        "heatpump/status/temp/1/value": "30.1",
+        "heatpump/status/mode": "heating",
+        "heatpump/mode": "heating",
+        "heatpump/broken": "<status><temp>35.5</status>",
+        "other/status": "<mode>heating</mode>",
+    }
+    assert processor.get_skip_stats()["counts"] == {"invalid_xml": 1}
+
+
+@pytest.mark.asyncio
+async def test_key_value_and_influx_payloads(config_instance):
+    config_instance.processing.key_value_topics = ["^gateway/"]
+    config_instance.processing.influx_topics = ["^telegraf/"]
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    send = processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+    processor.handle_mqtt_message("gateway/room", b'temp=21.5,hum=40; note="a, b"')
+    processor.handle_mqtt_message(
+        "telegraf/meter",
+        b'power,phase=L1 watts=230.5,count=3i,on=t 1700000000000000000\n# comment\nweather temp=-3.5',
+    )
+    processor.handle_mqtt_message("gateway/plain", b"21.5")
+    processor.handle_mqtt_message("telegraf/broken", b"power watts=")
+    await asyncio.sleep(0.05)
+    sent = {call[0][0]: call[0][2] for call in send.call_args_list}
+    assert sent == {
+        "gateway/room/temp": "21.5",
+        "gateway/room/hum": "40",
+        "gateway/room/note": "a, b",
+        "telegraf/meter/power/L1/watts": "230.5",
+        "telegraf/meter/power/L1/count": "3",
+        "telegraf/meter/power/L1/on": "true",
+        "telegraf/meter/weather/temp": "-3.5",
+        "gateway/plain": "21.5",
+        "telegraf/broken": "power watts=",
+    }
+    assert processor.get_skip_stats()["counts"] == {"invalid_key_value": 1, "invalid_influx": 1}
+
+
+@pytest.mark.parametrize("payload_formats", [
+    [{"pattern": "^a/"}],
+    [{"pattern": "^a/", "format": "protobuf"}],
+])
+def test_invalid_payload_formats_rejected(config_instance, payload_formats):
+    config_instance.processing.payload_formats = payload_formats
+    with pytest.raises(ValueError):
+        TestMiniserverDataProcessor(config_instance)
+
+
+@pytest.mark.asyncio
+@pytest.mark.parametrize("payload", [b"21.5", bytearray(b"21.5"), memoryview(b"xx21.5")[2:], memoryview(b"2x1x.x5")[::2]])
+async def test_handle_mqtt_message_accepts_buffers(config_instance, payload):
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    send = processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+    processor.handle_mqtt_message("sensor/temp", payload)
+    await asyncio.sleep(0.05)
+    assert send.call_args[0][2] == "21.5"
+
+
+@pytest.mark.asyncio
+@pytest.mark.parametrize("topic,expected,sanitized", [
+    (b"sensor/temp", "sensor/temp", False),
+    (b"sensor/\xfftemp", "sensor/_temp", True),
+    ("sensor/+/temp#", "sensor/_/temp_", True),
+    ("sensor/te\x00mp\n", "sensor/te_mp_", True),
+    # Lone surrogate: each of its three (surrogate-escaped) bytes is replaced
+    ("sensor/\udcfftemp", "sensor/___temp", True),
+])
+async def test_invalid_topics_are_sanitized(config_instance, topic, expected, sanitized):
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    send = processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+    processor.handle_mqtt_message(topic, b"21")
+    await asyncio.sleep(0.05)
+    assert send.call_args[0][0] == expected
+    assert processor.get_skip_stats()["counts"] == ({"invalid_topic": 1} if sanitized else {})
+
+
+def test_handle_mqtt_message_rejects_non_buffers(config_instance):
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    with pytest.raises(TypeError):
+        processor.handle_mqtt_message("sensor/temp", 21.5)
+
+
+def test_updates_while_processing_from_other_threads(config_instance):
+    """Filter and whitelist updates don't conflict with messages processed concurrently"""
+    import threading
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    errors = []
+
+    def process():
+        try:
+            for i in range(200):
+                processor.is_in_whitelist(f"sensor/{i}")
+        except Exception as e:
+            errors.append(e)
+
+    def update():
+        try:
+            for i in range(200):
+                processor.update_topic_whitelist([f"sensor_{i}"])
+                processor.update_subscription_filters([f"^ignore/{i}"])
+        except Exception as e:
+            errors.append(e)
+
+    threads = [threading.Thread(target=process) for _ in range(4)] + [threading.Thread(target=update) for _ in range(2)]
+    for thread in threads:
+        thread.start()
+    for thread in threads:
+        thread.join()
+    assert errors == []
+    assert processor.topic_whitelist == {"sensor_199"}
+
+
+@pytest.mark.asyncio
+async def test_shutdown_drains_sends_in_flight(config_instance):
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    delivered = []
+
+    async def slow_send(topic, name, value):
+        await asyncio.sleep(0.1)
+        delivered.append(value)
+        return {"code": 200}
+
+    processor.http_handler_obj.send_to_miniserver = AsyncMock(side_effect=slow_send)
+    processor.process_data("sensor/temp", "21")
+    processor.process_data("sensor/hum", "40")
+    assert await processor.shutdown(2.0) == {"drained": 2, "aborted": 0}
+    assert sorted(delivered) == ["21", "40"]
+
+    # Nothing new is forwarded once shutdown began
+    processor.process_data("sensor/temp", "22")
+    await asyncio.sleep(0.05)
+    assert processor.http_handler_obj.send_to_miniserver.call_count == 2
+
+
+@pytest.mark.asyncio
+async def test_shutdown_aborts_sends_after_timeout(config_instance):
+    processor = TestMiniserverDataProcessor(config_instance).processor
+
+    async def hanging_send(topic, name, value):
+        await asyncio.sleep(10)
+
+    processor.http_handler_obj.send_to_miniserver = AsyncMock(side_effect=hanging_send)
+    processor.process_data("sensor/temp", "21")
+    assert await processor.shutdown(0.05) == {"drained": 0, "aborted": 1}
+
+
+@pytest.mark.asyncio
+async def test_pause_holds_values_and_resume_sends_latest(config_instance):
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+    processor.pause()
+    assert processor.paused
+    processor.process_data("sensor/temp", "21")
+    processor.process_data("sensor/temp", "22")
+    processor.process_data("sensor/hum", "40")
+    await asyncio.sleep(0.05)
+    processor.http_handler_obj.send_to_miniserver.assert_not_called()
+    status = processor.get_delivery_status()
+    assert {name: (s["status"], s["value"]) for name, s in status.items()} == {
+        "sensor_temp": ("paused", "22"),
+        "sensor_hum": ("paused", "40"),
+    }
+
+    assert processor.resume() == 2
+    assert not processor.paused
+    await asyncio.sleep(0.05)
+    sent = [c.args for c in processor.http_handler_obj.send_to_miniserver.call_args_list]
+    assert sent == [("sensor/temp", "sensor_temp", "22"), ("sensor/hum", "sensor_hum", "40")]
+    assert processor.get_delivery_status()["sensor_temp"]["status"] == "delivered"
+    assert processor.resume() == 0
+
+
+@pytest.mark.asyncio
+async def test_pause_and_resume_topics(config_instance):
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+    processor.handle_mqtt_message("myrelay/pause", b"")
+    assert processor.paused
+    processor.process_data("sensor/temp", "21")
+    processor.handle_mqtt_message("myrelay/resume", b"")
+    assert not processor.paused
+    await asyncio.sleep(0.05)
+    processor.http_handler_obj.send_to_miniserver.assert_called_once_with("sensor/temp", "sensor_temp", "21")
+
+
+@pytest.mark.asyncio
+async def test_panics_are_published_to_error_topic(config_instance):
+    test_processor = TestMiniserverDataProcessor(config_instance)
+    test_processor.mock_mqtt_client.publish = AsyncMock()
+    with pytest.raises(BaseException, match="boom"):
+        _panic("boom")
+    await asyncio.sleep(0.01)
+    topic, summary = test_processor.mock_mqtt_client.publish.call_args[0]
+    assert topic == "myrelay/errors/panic"
+    summary = json.loads(summary)
+    assert summary["message"] == "boom"
+    assert summary["location"].startswith("src/panic_hook.rs:")
+
+
+@pytest.mark.asyncio
+async def test_send_callbacks_receive_outcome(config_instance):
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    processor.http_handler_obj.send_to_miniserver = AsyncMock(side_effect=[{"code": 200}, {"code": 503}])
+    calls = []
+    awaited = []
+
+    async def async_callback(topic, value, ok, error):
+        await asyncio.sleep(0)
+        awaited.append(value)
+
+    processor.add_send_callback(lambda *args: calls.append(args))
+    processor.add_send_callback(async_callback)
+    processor.process_data("sensor/temp", "21")
+    processor.process_data("sensor/hum", "40")
+    await asyncio.sleep(0.1)
+    assert sorted(calls) == [("sensor/hum", "40", False, "HTTP 503"), ("sensor/temp", "21", True, None)]
+    assert sorted(awaited) == ["21", "40"]
+
+
+@pytest.mark.asyncio
+async def test_send_callback_errors_and_removal(config_instance):
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+    calls = []
+
+    def failing(*args):
+        raise RuntimeError("callback broken")
+
+    def record(*args):
+        calls.append(args)
+
+    processor.add_send_callback(failing)
+    processor.add_send_callback(record)
+    processor.process_data("sensor/temp", "21")
+    await asyncio.sleep(0.05)
+    assert calls == [("sensor/temp", "21", True, None)]
+    assert processor.get_delivery_status()["sensor_temp"]["status"] == "delivered"
+
+    assert processor.remove_send_callback(record)
+    assert not processor.remove_send_callback(record)
+    processor.process_data("sensor/temp", "22")
+    await asyncio.sleep(0.05)
+    assert len(calls) == 1
+    with pytest.raises(ValueError):
+        processor.add_send_callback("not callable")
+
+
+@pytest.mark.asyncio
+async def test_connection_state_transitions_are_published(config_instance):
+    test_processor = TestMiniserverDataProcessor(config_instance)
+    test_processor.mock_mqtt_client.publish = AsyncMock()
+    processor = test_processor.processor
+    assert {name: link["state"] for name, link in processor.get_state().items()} == {
+        "broker": "connecting",
+        "miniserver": "connecting",
+    }
+
+    assert processor.set_connection_state("miniserver", "connected")
+    assert not processor.set_connection_state("miniserver", "connected")
+    assert processor.set_connection_state("miniserver", "degraded", "HTTP 500")
+    await asyncio.sleep(0.01)
+    states = [c.args for c in test_processor.mock_mqtt_client.publish.call_args_list if c.args[0] == "myrelay/connection/state"]
+    assert len(states) == 2
+    topic, payload, retain = states[-1]
+    assert (topic, retain) == ("myrelay/connection/state", True)
+    miniserver = json.loads(payload)["miniserver"]
+    assert (miniserver["state"], miniserver["previous"], miniserver["transitions"]) == ("degraded", "connected", 2)
+    assert miniserver["last_error"] == "HTTP 500"
+    assert processor.get_state()["broker"]["state"] == "connecting"
+
+
+@pytest.mark.asyncio
+async def test_reachability_and_connection_hooks(config_instance):
+    test_processor = TestMiniserverDataProcessor(config_instance)
+    test_processor.mock_mqtt_client.publish = AsyncMock()
+    processor = test_processor.processor
+    events = []
+
+    async def on_disconnect(connection, error):
+        events.append(("down", connection, error))
+
+    processor.on_connect(lambda connection, error: events.append(("up", connection, error)))
+    processor.on_disconnect(on_disconnect)
+    with pytest.raises(ValueError):
+        processor.on_connect("not callable")
+
+    processor.set_connection_state("miniserver", "connected")
+    processor.set_connection_state("miniserver", "degraded", "HTTP 503")
+    processor.set_connection_state("miniserver", "connecting", "timeout")
+    processor.set_connection_state("miniserver", "offline")
+    processor.set_connection_state("broker", "connected")
+    await asyncio.sleep(0.01)
+    assert events == [("up", "miniserver", None), ("down", "miniserver", "timeout"), ("up", "broker", None)]
+    reachability = [c.args for c in test_processor.mock_mqtt_client.publish.call_args_list if c.args[0] == "myrelay/status/miniserver"]
+    assert reachability == [("myrelay/status/miniserver", "online", True), ("myrelay/status/miniserver", "offline", True)]
+
+
+def test_invalid_connection_state_rejected(config_instance):
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    with pytest.raises(ValueError):
+        processor.set_connection_state("miniserver", "sleeping")
+    with pytest.raises(ValueError):
+        processor.set_connection_state("database", "connected")
+
+
+@pytest.mark.asyncio
+async def test_stats_snapshot_and_reset(config_instance):
+    test_processor = TestMiniserverDataProcessor(config_instance)
+    processor = test_processor.processor
+    test_processor.mock_mqtt_client.publish = AsyncMock()
+    processor.http_handler_obj.send_to_miniserver = AsyncMock(side_effect=[{"code": 200}, {"code": 500}])
+    processor.update_topic_whitelist(["sensor_temp", "sensor_hum"])
+    processor.process_data("sensor/temp", "21")
+    processor.process_data("sensor/hum", "40")
+    processor.process_data("other/topic", "1")
+    await asyncio.sleep(0.05)
+
+    processor.handle_mqtt_message(DummyTopicNS.STATS_GET, b"")
+    await asyncio.sleep(0)
+    topic, payload = test_processor.mock_mqtt_client.publish.call_args[0]
+    assert topic == DummyTopicNS.STATS_RESPONSE
+    stats = json.loads(payload)
+    assert stats["reset"] is False
+    assert {k: stats[k] for k in ("messages", "values", "filtered", "forwarded", "delivered", "failed")} == {
+        "messages": 3, "values": 3, "filtered": 1, "forwarded": 2, "delivered": 1, "failed": 1,
+    }
+
+    processor.handle_mqtt_message(DummyTopicNS.STATS_RESET, b"")
+    await asyncio.sleep(0)
+    final = json.loads(test_processor.mock_mqtt_client.publish.call_args[0][1])
+    assert final["reset"] is True
+    assert final["messages"] == 3
+    after = processor.get_stats()
+    assert after["messages"] == 0 and after["delivered"] == 0
+    assert after["since"] >= final["at"]
+
+@pytest.mark.asyncio
+async def test_base_topic_matches_whole_segment(config_instance):
+    config_instance.general.base_topic = "myrelay"
+    test_processor = TestMiniserverDataProcessor(config_instance)
+    processor = test_processor.processor
+    processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+
+    processor.handle_mqtt_message("myrelay2/temp", b"21")
+    await asyncio.sleep(0.05)
+    assert processor.http_handler_obj.send_to_miniserver.call_args_list[0][0][1] == "myrelay2_temp"
+
+    processor.handle_mqtt_message(DummyTopicNS.PAUSE, b"")
+    assert processor.paused
+
+@pytest.mark.asyncio
+async def test_unknown_subtopics_forwarded_when_enabled(config_instance):
+    test_processor = TestMiniserverDataProcessor(config_instance)
+    test_processor.processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+    test_processor.processor.handle_mqtt_message("myrelay/sensors/temp", b"21")
+    await asyncio.sleep(0.05)
+    test_processor.processor.http_handler_obj.send_to_miniserver.assert_not_called()
+
+    config_instance.general.forward_unknown_subtopics = True
+    test_processor = TestMiniserverDataProcessor(config_instance)
+    processor = test_processor.processor
+    processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+    processor.handle_mqtt_message("myrelay/sensors/temp", b"21")
+    await asyncio.sleep(0.05)
+    assert processor.http_handler_obj.send_to_miniserver.call_args_list[0][0][1:] == ("myrelay_sensors_temp", "21")
+
+    # Control topics keep their meaning
+    processor.handle_mqtt_message(DummyTopicNS.PAUSE, b"")
+    assert processor.paused
+    assert processor.http_handler_obj.send_to_miniserver.call_count == 1
+
+@pytest.mark.asyncio
+async def test_whitelist_accepts_original_topics(config_instance):
+    config_instance.topics.topic_whitelist = ["sensor/temp", "sensor_hum"]
+    test_processor = TestMiniserverDataProcessor(config_instance)
+    processor = test_processor.processor
+    assert processor.topic_whitelist == {"sensor_temp", "sensor_hum"}
+
+    processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+    processor.process_data("sensor/temp", "21")
+    processor.process_data("sensor/hum", "40")
+    processor.process_data("sensor/other", "1")
+    await asyncio.sleep(0.05)
+    sent = sorted(c[0][1] for c in processor.http_handler_obj.send_to_miniserver.call_args_list)
+    assert sent == ["sensor_hum", "sensor_temp"]
+
+    processor.update_topic_whitelist(["home/kitchen%light", "plain_name"])
+    assert processor.topic_whitelist == {"home_kitchen_light", "plain_name"}
+
+def test_sync_whitelist_from_structure(config_instance):
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    structure = {
+        "msInfo": {"serialNr": "504F00000000"},
+        "controls": {
+            "1a2b3c4d-0001-0002-ffff0123456789ab": {"name": "Kitchen Light", "type": "Switch", "uuidAction": "1A2B3C4D-0001-0002-FFFF0123456789AB"},
+            "1a2b3c4d-0001-0003-ffff0123456789ab": {"name": "Heating Setpoint", "type": "Slider"},
+            "1a2b3c4d-0001-0004-ffff0123456789ab": {
+                "name": "Blinds", "type": "CentralJalousie",
+                "subControls": {"1a2b3c4d-0001-0005-ffff0123456789ab": {"name": "Blinds Up", "type": "Pushbutton"}},
+            },
+            "1a2b3c4d-0001-0006-ffff0123456789ab": {"name": "Outdoor Temp", "type": "InfoOnlyAnalog"},
+        },
+    }
+    names = processor.sync_whitelist_from_structure(json.dumps(structure).encode())
+    assert names == ["Blinds Up", "Heating Setpoint", "Kitchen Light"]
+    assert processor.topic_whitelist == set(names)
+    assert processor.get_input_uuids()["Kitchen Light"] == "1a2b3c4d-0001-0002-ffff0123456789ab"
+    assert processor.get_input_uuids()["Blinds Up"] == "1a2b3c4d-0001-0005-ffff0123456789ab"
+
+    # Every control with an empty list of types, a str works as well
+    names = processor.sync_whitelist_from_structure(json.dumps(structure), control_types=[])
+    assert "Outdoor Temp" in names and "Blinds" in names
+
+    with pytest.raises(ValueError, match="LoxAPP3.json"):
+        processor.sync_whitelist_from_structure(b"{not json")
+    assert processor.topic_whitelist == set(names)
+
+@pytest.mark.asyncio
+@pytest.mark.parametrize("flattened_keys,post_filters,expected", [
+    (True, [], ["sensor_x_temp"]),
+    (False, [], ["sensor_x_battery", "sensor_x_temp", "sensor_x_voltage"]),
+    (False, ["voltage$"], ["sensor_x_battery", "sensor_x_temp"]),
+])
+async def test_post_expansion_filters(config_instance, flattened_keys, post_filters, expected):
+    config_instance.processing.expand_json = True
+    config_instance.topics.subscription_filters = ["battery|voltage"]
+    config_instance.topics.filter_flattened_keys = flattened_keys
+    config_instance.topics.post_expansion_filters = post_filters
+    test_processor = TestMiniserverDataProcessor(config_instance)
+    processor = test_processor.processor
+    processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+    processor.process_data("sensor/x", '{"temp": 21, "battery": 80, "voltage": 3}')
+    await asyncio.sleep(0.05)
+    sent = sorted(c[0][1] for c in processor.http_handler_obj.send_to_miniserver.call_args_list)
+    assert sent == expected
+    assert processor.get_post_expansion_filters() == post_filters
+
+@pytest.mark.asyncio
+async def test_debug_publishing_toggled_at_runtime(config_instance):
+    config_instance.topics.do_not_forward = ["ignored"]
+    test_processor = TestMiniserverDataProcessor(config_instance)
+    processor = test_processor.processor
+    test_processor.mock_mqtt_client.publish = AsyncMock()
+    processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+
+    processor.process_data("sensor/temp", "21")
+    await asyncio.sleep(0.05)
+    test_processor.mock_mqtt_client.publish.assert_not_called()
+
+    processor.handle_mqtt_message(DummyTopicNS.DEBUG_SET, b'{"publish_processed_topics": true, "publish_forwarded_topics": true}')
+    processor.process_data("sensor/temp", "on")
+    processor.process_data("ignored/topic", "1")
+    await asyncio.sleep(0.05)
+    published = [c[0] for c in test_processor.mock_mqtt_client.publish.call_args_list]
+    assert published == [
+        ("myrelay/processedtopics/sensor_temp", "on"),
+        ("myrelay/forwardedtopics/sensor_temp", "1"),
+        ("myrelay/processedtopics/ignored_topic", "1"),
+    ]
+
+    assert processor.set_debug_options(processed_topics=False) == {
+        "publish_processed_topics": False, "publish_forwarded_topics": True, "stage_timing": False,
+        "publish_trace": False,
+    }
+
+
+def test_explain_names_the_deciding_filter(processor):
+    processor.update_do_not_forward([r"^other/", r"^dnf/"])
+    explained = processor.explain("dnf/temp", "21")
+    assert explained["dropped"] is None
+    assert explained["values"] == [{
+        "topic": "dnf/temp", "name": "dnf_temp", "value": "21",
+        "outcome": "filtered", "reason": "do_not_forward #1 '^dnf/'",
+    }]
+    assert processor.explain("sensor/temp", "on")["values"][0]["outcome"] == "forwarded"
+    assert processor.explain("sensor/temp", "on")["values"][0]["value"] == "1"
+    # A dry run: nothing sent, nothing counted
+    processor.http_handler_obj.send_to_miniserver.assert_not_called()
+    assert processor.get_forwarded_inputs() == {}
+
+
+@pytest.mark.asyncio
+async def test_traces_of_recent_messages(config_instance):
+    config_instance.debug.trace_messages = 2
+    config_instance.topics.subscription_filters = ["^ignore/"]
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    processor.process_data("ignore/me", "1")
+    processor.process_data("sensor/a", "1")
+    processor.process_data("sensor/b", "2")
+    traces = processor.get_traces()
+    assert [trace["topic"] for trace in traces] == ["sensor/a", "sensor/b"]
+    assert traces[1]["values"][0]["outcome"] == "forwarded"
+    assert [trace["topic"] for trace in processor.get_traces("sensor_b")] == ["sensor/b"]
+    processor.clear_traces()
+    processor.process_data("ignore/me", "1")
+    assert processor.get_traces()[0]["dropped"] == "subscription_filter #0 '^ignore/'"
+
+
+@pytest.mark.asyncio
+@pytest.mark.parametrize("policy,topic,payload,expected", [
+    ("base64", b"sensor/t\xffemp", b"\xff\x01", ("sensor/t_emp", "[base64:/wE=]")),
+    ("replace", b"sensor/t\xffemp", b"a\xffb", ("sensor/t_emp", "a�b")),
+    ("lossy", b"sensor/temp", b"a\xffb", ("sensor/temp", "a�b")),
+    ("hex", b"sensor/temp", bytearray(b"\xff\x01A"), ("sensor/temp", "[hex:ff0141]")),
+    ("drop", b"sensor/t\xffemp", b"21", None),
+    ("drop", "sensor/\udcfftemp", b"21", None),
+    ("drop", b"sensor/temp", b"\xff", None),
+])
+async def test_invalid_utf8_policy(config_instance, policy, topic, payload, expected):
+    config_instance.processing.invalid_utf8 = policy
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    send = processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+    processor.handle_mqtt_message(topic, payload)
+    await asyncio.sleep(0.05)
+    if expected is None:
+        send.assert_not_called()
+        assert processor.get_skip_stats()["counts"]
+    else:
+        assert (send.call_args[0][0], send.call_args[0][2]) == expected
+
+
+def test_invalid_utf8_policy_is_validated(config_instance):
+    config_instance.processing.invalid_utf8 = "ignore"
+    with pytest.raises(ValueError, match="invalid_utf8"):
+        TestMiniserverDataProcessor(config_instance)
+
+
+@pytest.mark.asyncio
+async def test_stage_timing_metrics(config_instance):
+    config_instance.processing.expand_json = True
+    test_processor = TestMiniserverDataProcessor(config_instance)
+    processor = test_processor.processor
+    processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+    processor.handle_mqtt_message("sensor/x", b'{"temp": 21, "hum": 40}')
+    metrics = processor.get_metrics()
+    assert metrics["enabled"] is False
+    assert all(stage["count"] == 0 for stage in metrics["stages"].values())
+    assert "stages" not in processor.get_stats()
+
+    processor.set_debug_options(stage_timing=True)
+    processor.handle_mqtt_message("sensor/x", b'{"temp": 21, "hum": 40}')
+    await asyncio.sleep(0.05)
+    stages = processor.get_metrics()["stages"]
+    assert {name: stage["count"] for name, stage in stages.items()} == {
+        "decode": 1, "parse": 1, "flatten": 1, "filter": 2, "convert": 2, "dispatch": 2, "message": 1,
+    }
+    assert sum(bucket["count"] for bucket in stages["filter"]["buckets"]) == 2
+    assert stages["filter"]["buckets"][-1]["le_us"] is None
+
+    test_processor.mock_mqtt_client.publish = AsyncMock()
+    processor.handle_mqtt_message(DummyTopicNS.STATS_RESET, b"")
+    await asyncio.sleep(0)
+    published = json.loads(test_processor.mock_mqtt_client.publish.call_args[0][1])
+    assert published["stages"]["dispatch"]["count"] == 2
+    assert processor.get_metrics()["stages"]["dispatch"]["count"] == 0
+
+
+@pytest.mark.asyncio
+async def test_metrics_per_topic_filter_and_cache(config_instance):
+    config_instance.processing.expand_json = True
+    test_processor = TestMiniserverDataProcessor(config_instance)
+    processor = test_processor.processor
+    processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+    processor.update_subscription_filters(["^noisy/"])
+    processor.update_do_not_forward(["/secret$"])
+    processor.process_data("sensor/x", '{"temp": 21, "secret": 1}')
+    processor.process_data("sensor/x", '{"temp": 22, "secret": 2}')
+    processor.process_data("noisy/y", "1")
+    await asyncio.sleep(0.05)
+
+    metrics = processor.get_metrics()
+    assert metrics["topics"] == {
+        "sensor/x": {"received": 2, "filtered": 2, "forwarded": 2},
+        "noisy/y": {"received": 1, "filtered": 1, "forwarded": 0},
+    }
+    counters = metrics["counters"]
+    assert (counters["messages"], counters["filtered"], counters["forwarded"]) == (3, 3, 2)
+    assert counters["filtered_by"]["do_not_forward"] == 2
+    assert counters["filtered_by"]["subscription_filter"] == 1
+    assert counters["filtered_by"]["whitelist"] == 0
+    assert processor.get_stats()["filtered_by"] == counters["filtered_by"]
+    caches = metrics["caches"]["convert_boolean"]
+    assert (caches["hits"], caches["misses"]) == (0, 2)
+    assert metrics["caches"]["normalize_topic"]["hit_rate"] > 0
+
+    cleared = processor.get_metrics(reset=True)
+    assert cleared["topics"]["noisy/y"]["received"] == 1
+    after = processor.get_metrics()
+    assert after["topics"] == {}
+    assert after["caches"]["convert_boolean"] == {"hits": 0, "misses": 0, "hit_rate": 0.0}
+    # The counters belong to reset_stats
+    assert after["counters"]["messages"] == 3
+
+
+@pytest.mark.asyncio
+async def test_stage_timing_percentiles(config_instance):
+    config_instance.debug.stage_timing = True
+    test_processor = TestMiniserverDataProcessor(config_instance)
+    processor = test_processor.processor
+    processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+    assert processor.get_metrics()["stages"]["message"]["p99_us"] == 0.0
+    for i in range(20):
+        processor.process_data(f"sensor/{i}", "1")
+    await asyncio.sleep(0.05)
+    stage = processor.get_metrics()["stages"]["message"]
+    assert stage["count"] == 20
+    assert 0 < stage["p50_us"] <= stage["p90_us"] <= stage["p99_us"] <= stage["max_us"]
+
+
+@pytest.mark.asyncio
+async def test_send_queue_limits_concurrency(config_instance):
+    config_instance.miniserver.send_concurrency = 2
+    test_processor = TestMiniserverDataProcessor(config_instance)
+    processor = test_processor.processor
+    running, peak, sent = 0, 0, []
+
+    async def send(topic, name, value):
+        nonlocal running, peak
+        running += 1
+        peak = max(peak, running)
+        await asyncio.sleep(0.01)
+        running -= 1
+        sent.append(value)
+        return {"code": 200}
+
+    processor.http_handler_obj.send_to_miniserver = send
+    for i in range(10):
+        processor.process_data(f"sensor/{i}", str(i))
+    for _ in range(50):
+        if len(sent) == 10:
+            break
+        await asyncio.sleep(0.02)
+    assert sorted(sent, key=int) == [str(i) for i in range(10)]
+    assert peak == 2
+
+
+@pytest.mark.asyncio
+@pytest.mark.parametrize("overflow,kept", [("drop_oldest", ["1", "4", "5"]), ("drop_newest", ["1", "2", "3"])])
+async def test_send_queue_overflow(config_instance, overflow, kept):
+    config_instance.miniserver.send_concurrency = 1
+    config_instance.miniserver.send_queue_size = 2
+    config_instance.miniserver.send_queue_overflow = overflow
+    test_processor = TestMiniserverDataProcessor(config_instance)
+    processor = test_processor.processor
+    release, sent = asyncio.Event(), []
+
+    async def send(topic, name, value):
+        await release.wait()
+        sent.append(value)
+        return {"code": 200}
+
+    processor.http_handler_obj.send_to_miniserver = send
+    processor.process_data("sensor/1", "1")
+    await asyncio.sleep(0.05)
+    for i in range(2, 6):
+        processor.process_data(f"sensor/{i}", str(i))
+    release.set()
+    await asyncio.sleep(0.1)
+    assert sent == kept
+    assert processor.get_stats()["queue_overflow"] == 2
+    dropped = next(i for i in range(2, 6) if str(i) not in kept)
+    assert processor.get_delivery_status(f"sensor/{dropped}")[f"sensor_{dropped}"]["status"] == "failed"
+
+
+@pytest.mark.asyncio
+async def test_shutdown_drains_send_queue(config_instance):
+    config_instance.miniserver.send_concurrency = 1
+    test_processor = TestMiniserverDataProcessor(config_instance)
+    processor = test_processor.processor
+    sent = []
+
+    async def send(topic, name, value):
+        await asyncio.sleep(0.01)
+        sent.append(value)
+        return {"code": 200}
+
+    processor.http_handler_obj.send_to_miniserver = send
+    for i in range(3):
+        processor.process_data(f"sensor/{i}", str(i))
+    assert await processor.shutdown(1.0) == {"drained": 3, "aborted": 0}
+    assert sent == ["0", "1", "2"]
+
+
+def test_invalid_send_queue_overflow_rejected(config_instance):
+    config_instance.miniserver.send_queue_overflow = "wait"
+    with pytest.raises(ValueError):
+        TestMiniserverDataProcessor(config_instance)
+
+
+@pytest.mark.asyncio
+async def test_failed_sends_are_retried(config_instance):
+    config_instance.miniserver.send_retries = 2
+    config_instance.miniserver.send_retry_backoff = 0.01
+    config_instance.miniserver.send_retry_jitter = 0
+    test_processor = TestMiniserverDataProcessor(config_instance)
+    processor = test_processor.processor
+    send = AsyncMock(side_effect=[{"code": 500}, {"code": 500}, {"code": 200}])
+    processor.http_handler_obj.send_to_miniserver = send
+    processor.process_data("sensor/temp", "21")
+    await asyncio.sleep(0.2)
+    assert send.call_count == 3
+    stats = processor.get_stats()
+    assert (stats["retried"], stats["delivered"], stats["failed"]) == (2, 1, 0)
+    assert processor.get_delivery_status("sensor/temp")["sensor_temp"]["status"] == "delivered"
+
+
+@pytest.mark.asyncio
+async def test_replaced_values_are_not_retried(config_instance):
+    config_instance.miniserver.send_retries = 3
+    config_instance.miniserver.send_retry_backoff = 0.05
+    test_processor = TestMiniserverDataProcessor(config_instance)
+    processor = test_processor.processor
+    send = AsyncMock(side_effect=[{"code": 500}, {"code": 200}])
+    processor.http_handler_obj.send_to_miniserver = send
+    processor.process_data("sensor/temp", "21")
+    await asyncio.sleep(0.01)
+    processor.process_data("sensor/temp", "22")
+    await asyncio.sleep(0.2)
+    assert [c.args[2] for c in send.call_args_list] == ["21", "22"]
+    assert processor.get_stats()["retried"] == 0
+
+
+@pytest.mark.asyncio
+async def test_dead_letter_after_retries(config_instance):
+    config_instance.miniserver.send_retries = 1
+    config_instance.miniserver.send_retry_backoff = 0.01
+    config_instance.miniserver.dead_letter = True
+    test_processor = TestMiniserverDataProcessor(config_instance)
+    processor = test_processor.processor
+    test_processor.mock_mqtt_client.publish = AsyncMock()
+    processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 500})
+    processor.process_data("sensor/temp", "21")
+    await asyncio.sleep(0.2)
+    topic, payload = test_processor.mock_mqtt_client.publish.call_args[0]
+    assert topic == f"{config_instance.general.relay_topic}deadletter/sensor_temp"
+    letter = json.loads(payload)
+    assert {k: letter[k] for k in ("topic", "name", "value", "error", "code", "attempts")} == {
+        "topic": "sensor/temp", "name": "sensor_temp", "value": "21", "error": "HTTP 500", "code": 500, "attempts": 2,
+    }
+    stats = processor.get_stats()
+    assert (stats["retried"], stats["dead_lettered"], stats["failed"]) == (1, 1, 1)
+
+
+@pytest.mark.parametrize("name,value", [
+    ("send_retry_backoff", -1.0),
+    ("send_retry_jitter", 1.5),
+])
+def test_invalid_retry_options_rejected(config_instance, name, value):
+    setattr(config_instance.miniserver, name, value)
+    with pytest.raises(ValueError):
+        TestMiniserverDataProcessor(config_instance)
+
+
+@pytest.mark.asyncio
+async def test_homeassistant_discovery(config_instance):
+    config_instance.general.homeassistant_discovery = True
+    test_processor = TestMiniserverDataProcessor(config_instance)
+    processor = test_processor.processor
+    publish = test_processor.mock_mqtt_client.publish = AsyncMock()
+    processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+    relay = config_instance.general.relay_topic
+    node = relay.strip("/").replace("/", "_")
+    processor.process_data("sensor/temp", "21.5")
+    processor.process_data("sensor/temp", "22")
+    processor.process_data("door/open", "ON")
+    await asyncio.sleep(0.05)
+    configs = {c.args[0]: json.loads(c.args[1]) for c in publish.call_args_list if len(c.args) == 3}
+    assert all(c.args[2] is True for c in publish.call_args_list if len(c.args) == 3)
+    temp = configs[f"homeassistant/sensor/{node}/sensor_temp/config"]
+    assert temp["state_topic"] == f"{relay}forwardedtopics/sensor_temp"
+    assert (temp["name"], temp["unique_id"], temp["state_class"]) == ("sensor_temp", f"{node}_sensor_temp", "measurement")
+    door = configs[f"homeassistant/binary_sensor/{node}/door_open/config"]
+    assert (door["payload_on"], door["payload_off"]) == ("1", "0")
+    # Announced once; every value goes to the state topic
+    assert len(configs) == 2
+    states = [c.args for c in publish.call_args_list if len(c.args) == 2]
+    assert (f"{relay}forwardedtopics/sensor_temp", "22") in states
+
+    publish.reset_mock()
+    processor.update_topic_whitelist(["door_open"])
+    await asyncio.sleep(0.05)
+    assert [c.args for c in publish.call_args_list] == [(f"homeassistant/sensor/{node}/sensor_temp/config", "", True)]
+
+
+def _outbox_lines(path):
+    return [json.loads(line) for line in path.read_text().splitlines()]
+
+
+@pytest.mark.asyncio
+async def test_outbox_replays_after_reconnect(config_instance, tmp_path):
+    config_instance.miniserver.outbox = True
+    config_instance.miniserver.outbox_path = str(tmp_path / "outbox.jsonl")
+    test_processor = TestMiniserverDataProcessor(config_instance)
+    processor = test_processor.processor
+    send = AsyncMock(side_effect=OSError("Miniserver unreachable"))
+    processor.http_handler_obj.send_to_miniserver = send
+    for topic, value in [("meter/energy", "1"), ("meter/energy", "2"), ("sensor/temp", "21")]:
+        processor.process_data(topic, value)
+        await asyncio.sleep(0.05)
+    assert [line["value"] for line in _outbox_lines(tmp_path / "outbox.jsonl")] == ["1", "2", "21"]
+    assert processor.get_metrics()["outbox"] == 3
+
+    send.side_effect = None
+    send.return_value = {"code": 200}
+    send.reset_mock()
+    processor.set_connection_state("miniserver", "offline")
+    processor.set_connection_state("miniserver", "connected")
+    await asyncio.sleep(0.2)
+    assert [c.args for c in send.call_args_list] == [
+        ("meter/energy", "meter_energy", "1"),
+        ("meter/energy", "meter_energy", "2"),
+        ("sensor/temp", "sensor_temp", "21"),
+    ]
+    stats = processor.get_stats()
+    assert (stats["buffered"], stats["replayed"]) == (3, 3)
+    assert _outbox_lines(tmp_path / "outbox.jsonl") == []
+
+
+@pytest.mark.asyncio
+async def test_outbox_latest_per_input_after_restart(config_instance, tmp_path):
+    config_instance.miniserver.outbox = True
+    config_instance.miniserver.outbox_path = str(tmp_path / "outbox.jsonl")
+    config_instance.miniserver.outbox_replay = "latest"
+    first = TestMiniserverDataProcessor(config_instance).processor
+    first.http_handler_obj.send_to_miniserver = AsyncMock(side_effect=OSError("Miniserver unreachable"))
+    for value in ["1", "2", "3"]:
+        first.process_data("meter/energy", value)
+        await asyncio.sleep(0.05)
+
+    # A new relay picks the values up from the file
+    second = TestMiniserverDataProcessor(config_instance).processor
+    send = AsyncMock(return_value={"code": 200})
+    second.http_handler_obj.send_to_miniserver = send
+    second.set_connection_state("miniserver", "connected")
+    await asyncio.sleep(0.2)
+    assert [c.args for c in send.call_args_list] == [("meter/energy", "meter_energy", "3")]
+
+
+@pytest.mark.asyncio
+async def test_outbox_limits(config_instance, tmp_path):
+    path = tmp_path / "outbox.jsonl"
+    stale = {"name": "old", "topic": "old", "value": "0", "at": 1.0}
+    path.write_text(json.dumps(stale) + "\n" + "half a line\n")
+    config_instance.miniserver.outbox = True
+    config_instance.miniserver.outbox_path = str(path)
+    config_instance.miniserver.outbox_max_entries = 2
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    # Expired and unreadable lines are gone as soon as the outbox is opened
+    assert _outbox_lines(path) == []
+    send = AsyncMock(side_effect=OSError("Miniserver unreachable"))
+    processor.http_handler_obj.send_to_miniserver = send
+    for value in ["1", "2", "3"]:
+        processor.process_data("meter/energy", value)
+        await asyncio.sleep(0.05)
+    assert processor.get_metrics()["outbox"] == 2
+
+    # The Miniserver rejecting a value drops it instead of stopping the replay
+    send.side_effect = [{"code": 404}, {"code": 200}]
+    send.reset_mock()
+    processor.set_connection_state("miniserver", "connected")
+    await asyncio.sleep(0.2)
+    assert [c.args[2] for c in send.call_args_list] == ["2", "3"]
+    assert processor.get_stats()["replayed"] == 1
+    assert processor.get_metrics()["outbox"] == 0
+
+
+def test_invalid_outbox_replay_rejected(config_instance, tmp_path):
+    config_instance.miniserver.outbox = True
+    config_instance.miniserver.outbox_path = str(tmp_path / "outbox.jsonl")
+    config_instance.miniserver.outbox_replay = "newest"
+    with pytest.raises(ValueError):
+        TestMiniserverDataProcessor(config_instance)
+
+
+async def _scrape(port, path="/metrics"):
+    reader, writer = await asyncio.open_connection("127.0.0.1", port)
+    writer.write(f"GET {path} HTTP/1.1\r\nHost: relay\r\n\r\n".encode())
+    await writer.drain()
+    response = (await reader.read()).decode()
+    writer.close()
+    head, _, body = response.partition("\r\n\r\n")
+    return head.split("\r\n")[0], body
+
+
+@pytest.mark.asyncio
+async def test_prometheus_endpoint(config_instance):
+    with socket.socket() as s:
+        s.bind(("127.0.0.1", 0))
+        port = s.getsockname()[1]
+    config_instance.debug.prometheus_port = port
+    config_instance.debug.prometheus_host = "127.0.0.1"
+    test_processor = TestMiniserverDataProcessor(config_instance)
+    processor = test_processor.processor
+    processor.http_handler_obj.send_to_miniserver = AsyncMock(side_effect=[{"code": 200}, {"code": 500}])
+    processor.update_do_not_forward(["^other/"])
+    processor.process_data("sensor/temp", "21")
+    processor.process_data("sensor/hum", "40")
+    processor.process_data("other/topic", "1")
+    await asyncio.sleep(0.05)
+
+    status, body = await _scrape(port)
+    assert status == "HTTP/1.1 200 OK"
+    samples = dict(line.rsplit(" ", 1) for line in body.splitlines() if not line.startswith("#"))
+    assert samples["loxmqttrelay_messages_total"] == "3"
+    assert samples["loxmqttrelay_forwarded_total"] == "2"
+    assert samples["loxmqttrelay_failed_total"] == "1"
+    assert samples['loxmqttrelay_filtered_by_total{filter="do_not_forward"}'] == "1"
+    assert samples["loxmqttrelay_sends_in_flight"] == "0"
+    assert samples['loxmqttrelay_cache_entries{cache="convert_boolean"}'] == "2"
+    assert "# TYPE loxmqttrelay_messages_total counter" in body
+    assert (await _scrape(port, "/"))[0] == "HTTP/1.1 404 Not Found"
+
+    # A second processor can't take the port
+    with pytest.raises(ValueError, match="Prometheus"):
+        TestMiniserverDataProcessor(config_instance)
+
+
+@pytest.mark.asyncio
+async def test_failover_to_secondary_miniserver(config_instance):
+    async def secondary(reader, writer):
+        await reader.readuntil(b"\r\n\r\n")
+        writer.write(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+        await writer.drain()
+        writer.close()
+
+    server = await asyncio.start_server(secondary, "127.0.0.1", 0)
+    port = server.sockets[0].getsockname()[1]
+    with socket.socket() as s:
+        s.bind(("127.0.0.1", 0))
+        closed_port = s.getsockname()[1]
+    config_instance.miniserver.miniserver_ip = "127.0.0.1"
+    config_instance.miniserver.miniserver_port = closed_port
+    config_instance.miniserver.failover_ip = "127.0.0.1"
+    config_instance.miniserver.failover_port = port
+    config_instance.miniserver.health_check_interval = 0.05
+    config_instance.miniserver.health_check_failures = 2
+    test_processor = TestMiniserverDataProcessor(config_instance)
+    processor = test_processor.processor
+    test_processor.mock_mqtt_client.publish = AsyncMock()
+
+    checks = asyncio.ensure_future(processor.run_health_checks())
+    await asyncio.sleep(0.4)
+    processor.http_handler_obj.set_miniserver_address.assert_called_with("127.0.0.1", port)
+    availability = [
+        json.loads(call[0][1]) for call in test_processor.mock_mqtt_client.publish.call_args_list
+        if call[0][0] == "myrelay/miniserver/availability"
+    ]
+    # The first failed check doesn't switch yet, the second one does
+    assert [a["state"] for a in availability] == ["primary", "secondary"]
+    assert availability[-1]["port"] == port
+    assert processor.get_availability()["state"] == "secondary"
+
+    await processor.shutdown(0.1)
+    await asyncio.wait_for(checks, 1)
+    server.close()
+
+
+@pytest.mark.asyncio
+async def test_native_http_sender(config_instance):
+    requests, connections = [], []
+
+    async def miniserver(reader, writer):
+        connections.append(writer)
+        while True:
+            head = await reader.readuntil(b"\r\n\r\n")
+            requests.append(head.decode())
+            status = b"403 Forbidden" if b"/blocked_c/" in head else b"200 OK"
+            writer.write(b"HTTP/1.1 " + status + b"\r\nContent-Length: 2\r\n\r\nok")
+            await writer.drain()
+
+    server = await asyncio.start_server(miniserver, "127.0.0.1", 0)
+    port = server.sockets[0].getsockname()[1]
+    config_instance.miniserver.use_websocket = False
+    config_instance.miniserver.native_http = True
+    config_instance.miniserver.miniserver_user = "admin"
+    config_instance.miniserver.miniserver_pass = "secret"
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    processor.http_handler_obj.native_target = MagicMock(return_value=("127.0.0.1", port))
+    processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+
+    processor.process_data("sensor/a", "21 °C")
+    await asyncio.sleep(0.1)
+    processor.process_data("sensor/b", "1")
+    await asyncio.sleep(0.1)
+    assert requests[0].startswith("GET /dev/sps/io/sensor_a/21%20%C2%B0C HTTP/1.1\r\n")
+    assert "Authorization: Basic YWRtaW46c2VjcmV0\r\n" in requests[0]
+    # Both requests used the same keep-alive connection
+    assert len(requests) == 2 and len(connections) == 1
+    processor.http_handler_obj.send_to_miniserver.assert_not_called()
+    assert processor.get_delivery_status("sensor/a")["sensor_a"]["status"] == "delivered"
+
+    # Errors are repeated through the Python sender, which handles them
+    processor.process_data("blocked/c", "1")
+    await asyncio.sleep(0.1)
+    processor.http_handler_obj.send_to_miniserver.assert_called_once_with("blocked/c", "blocked_c", "1")
+
+    # While the Python handler doesn't report the Miniserver healthy, it sends itself
+    processor.http_handler_obj.native_target.return_value = None
+    processor.process_data("sensor/d", "1")
+    await asyncio.sleep(0.1)
+    assert len(requests) == 3
+    assert processor.http_handler_obj.send_to_miniserver.call_count == 2
+    for writer in connections:
+        writer.close()
+    server.close()
+
+
+@pytest.mark.asyncio
+async def test_capture_and_replay(config_instance, tmp_path):
+    capture = tmp_path / "capture.bin"
+    config_instance.debug.capture_file = str(capture)
+    test_processor = TestMiniserverDataProcessor(config_instance)
+    processor = test_processor.processor
+    processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+    test_processor.mock_mqtt_client.publish = AsyncMock()
+    processor.handle_mqtt_message("sensor/a", b"1")
+    processor.handle_mqtt_message(DummyTopicNS.STATS_RESET, b"")
+    processor.handle_mqtt_message("sensor/b", b"\xff\x00")
+    assert processor.stop_capture() == 2
+    assert processor.stop_capture() is None
+    await asyncio.sleep(0.05)
+
+    replayed = TestMiniserverDataProcessor(config_instance).processor
+    replayed.stop_capture()
+    send = replayed.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+    assert await replayed.replay_capture(str(capture), speed=0) == 2
+    await asyncio.sleep(0.05)
+    assert [call[0][0] for call in send.call_args_list] == ["sensor/a", "sensor/b"]
+
+    # A cancelled replay doesn't block the next one
+    slow = asyncio.ensure_future(replayed.replay_capture(str(capture), speed=1e-9))
+    await asyncio.sleep(0.05)
+    slow.cancel()
+    with pytest.raises(asyncio.CancelledError):
+        await slow
+    await asyncio.sleep(0.05)
+    assert await replayed.replay_capture(str(capture), speed=0) == 2
+
+    with pytest.raises(ValueError, match="speed"):
+        replayed.replay_capture(str(capture), speed=-1)
+    not_a_capture = tmp_path / "other.bin"
+    not_a_capture.write_bytes(b"something else")
+    with pytest.raises(ValueError, match="capture"):
+        replayed.replay_capture(str(not_a_capture))
+
+
+@pytest.mark.asyncio
+async def test_handle_mqtt_message_async_resolves_with_outcomes(config_instance):
+    config_instance.processing.expand_json = True
+    config_instance.topics.do_not_forward = ["^sensor/x/hum$"]
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    release = asyncio.Event()
+
+    async def send(topic, name, value, **kwargs):
+        await release.wait()
+        return {"code": 500 if name.endswith("temp") else 200}
+
+    processor.http_handler_obj.send_to_miniserver = send
+    result = asyncio.ensure_future(processor.handle_mqtt_message_async("sensor/x", b'{"temp": 21, "hum": 40, "on": 1}'))
+    await asyncio.sleep(0.05)
+    assert not result.done()
+    assert processor.in_flight == 2
+    release.set()
+    deliveries = await asyncio.wait_for(result, 5)
+    assert [(d["topic"], d["status"], d["http_code"]) for d in deliveries] == [
+        ("sensor/x/temp", "failed", 500),
+        ("sensor/x/on", "delivered", 200),
+    ]
+    assert processor.in_flight == 0
+
+    assert await processor.handle_mqtt_message_async("sensor/x/hum", b"40") == []
+    assert await processor.handle_mqtt_message_async(DummyTopicNS.STATS_GET, b"") == []
+
+
+def test_configurable_topic_normalization(config_instance):
+    config_instance.topics.name_replacement = "-"
+    config_instance.topics.name_replace_chars = " #+"
+    config_instance.topics.name_lowercase = True
+    config_instance.topics.topic_whitelist = {"Wohnzimmer/Küche Licht"}
+    processor = TestMiniserverDataProcessor(config_instance).processor
+    assert processor.normalize_topic("Wohnzimmer/Küche Licht") == "wohnzimmer-küche-licht"
+    assert processor.normalize_topic("a%b#c+d") == "a-b-c-d"
+    assert processor.normalize_topic("größe") == "größe"
+    assert processor.is_in_whitelist("Wohnzimmer/Küche Licht")
+
+
+def test_name_replacement_is_validated(config_instance):
+    config_instance.topics.name_replace_chars = " "
+    config_instance.topics.name_replacement = " "
+    assert any(error.startswith("topics.name_replacement") for error in validate_config(config_instance))
+    with pytest.raises(ValueError, match="name_replacement"):
+        TestMiniserverDataProcessor(config_instance)
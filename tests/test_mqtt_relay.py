@@ -1,8 +1,9 @@
 import pytest
-from unittest.mock import patch, MagicMock
+from unittest.mock import patch, MagicMock, AsyncMock
 import logging
 import json
-from loxmqttrelay.main import MQTTRelay, TOPIC
+from loxmqttrelay.main import MQTTRelay, TOPIC, http_miniserver_handler
+from loxmqttrelay.discovery import DiscoveredMiniserver
 from loxmqttrelay.config import (
     Config, AppConfig, GeneralConfig,
     TopicsConfig, MiniserverConfig, global_config
@@ -133,3 +134,380 @@ async def test_whitelist_sync_on_miniserver_startup(config_instance: Config, moc
 
             # Neue Whitelist sollte wieder "synced_topic1", "synced_topic2" enthalten
             assert global_config.topics.topic_whitelist == ["synced_topic1", "synced_topic2"]
+
+@pytest.mark.asyncio
+async def test_caches_warmed_up_from_whitelist(config_instance: Config, tmp_path, monkeypatch) -> None:
+    """Test: Beim Start werden die Caches aus der Whitelist vorgewärmt, ohne Zustandsdatei."""
+    monkeypatch.setattr(global_config, "config_path", str(tmp_path / "config.toml"))
+    config_instance.general.cache_warmup = True
+    config_instance.topics.topic_whitelist = ["sensor_temp"]
+    relay = MQTTRelay()
+    assert "sensor_temp" in relay.miniserver_data_processor.cached_topics()
+    assert relay.miniserver_data_processor.cache_stats()["filter_decision"]["size"] == 1
+    assert list(tmp_path.iterdir()) == []
+
+@pytest.mark.asyncio
+async def test_caches_warmed_up_from_persisted_topics(config_instance: Config, tmp_path, monkeypatch) -> None:
+    """Test: Die beim letzten Lauf gesehenen Topics werden gespeichert und beim Start vorgewärmt."""
+    monkeypatch.setattr(global_config, "config_path", str(tmp_path / "config.toml"))
+    config_instance.general.cache_warmup = True
+    relay = MQTTRelay()
+    relay.miniserver_data_processor.process_data("a/b/c", "1")
+    relay.persist_cache_state()
+    assert "a/b/c" in json.loads((tmp_path / "cache_state.json").read_text())
+
+    restarted = MQTTRelay()
+    processor = restarted.miniserver_data_processor
+    assert "a/b/c" in processor.seen_topics()
+    hits = processor.cache_stats()["filter_decision"]["hits"]
+    processor.process_data("a/b/c", "2")
+    assert processor.cache_stats()["filter_decision"]["hits"] == hits + 1
+
+@pytest.mark.asyncio
+async def test_vi_name_mapping_persisted(config_instance: Config, tmp_path, monkeypatch) -> None:
+    """Test: Gekürzte VI-Namen werden für die UI gespeichert."""
+    monkeypatch.setattr(global_config, "config_path", str(tmp_path / "config.toml"))
+    monkeypatch.setattr(global_config.topics, "max_name_length", 20)
+    relay = MQTTRelay()
+    assert relay.miniserver_data_processor.is_in_whitelist("a/very/long/topic/name/for/loxone") is False
+    relay.persist_vi_name_mapping()
+    mapping = json.loads((tmp_path / "vi_names.json").read_text())
+    assert list(mapping) == ["a_very_long_topic_name_for_loxone"]
+    assert len(mapping["a_very_long_topic_name_for_loxone"]) == 20
+
+@pytest.mark.asyncio
+async def test_export_virtual_inputs(config_instance: Config) -> None:
+    """Test: Whitelist und weitergeleitete Topics werden als CSV/XML exportiert."""
+    config_instance.topics.topic_whitelist = ["sensor_temp"]
+    relay = MQTTRelay()
+    with patch.object(http_miniserver_handler, "send_to_miniserver", AsyncMock(return_value={"code": 200})):
+        relay.miniserver_data_processor.process_data("sensor/temp", "21")
+    with patch('loxmqttrelay.main.mqtt_client.publish', new_callable=AsyncMock) as mock_publish:
+        relay.miniserver_data_processor.handle_mqtt_message(TOPIC.EXPORT_VI, b"csv")
+        await asyncio.sleep(0)
+        mock_publish.assert_called_once_with(TOPIC.EXPORT_RESPONSE, "virtual_input,topic\nsensor_temp,sensor/temp\n")
+
+        mock_publish.reset_mock()
+        relay.miniserver_data_processor.handle_mqtt_message(TOPIC.EXPORT_VI, b"")
+        await asyncio.sleep(0)
+        assert "<VirtualInCmd Title=\"sensor_temp\"" in mock_publish.call_args[0][1]
+
+
+@pytest.mark.asyncio
+async def test_export_virtual_inputs_for_udp_transport(config_instance: Config) -> None:
+    """Test: Mit UDP als Standard-Transport wird ein Virtual UDP Input mit dessen Port exportiert."""
+    config_instance.topics.topic_whitelist = ["sensor_temp"]
+    config_instance.udp.udp_out_destinations = ["192.168.1.10:7000"]
+    config_instance.miniserver.default_transport = "udp"
+    relay = MQTTRelay()
+    with patch('loxmqttrelay.main.mqtt_client.publish', new_callable=AsyncMock) as mock_publish:
+        relay.export_virtual_inputs("xml")
+        await asyncio.sleep(0)
+        xml = mock_publish.call_args[0][1]
+        assert 'Port="7000"' in xml
+        assert "<VirtualInUdpCmd Title=\"sensor_temp\"" in xml
+
+
+@pytest.mark.asyncio
+async def test_config_get_publishes_safe_config_as_json(config_instance: Config) -> None:
+    """Test: config/get publiziert die Konfiguration ohne Passwörter als JSON."""
+    relay = MQTTRelay()
+    with patch('loxmqttrelay.main.mqtt_client.publish', new_callable=AsyncMock) as mock_publish:
+        relay.miniserver_data_processor.handle_mqtt_message(TOPIC.CONFIG_GET, b"")
+        await asyncio.sleep(0)
+    topic, payload = mock_publish.call_args[0]
+    assert topic == TOPIC.CONFIG_RESPONSE
+    assert json.loads(payload) == json.loads(json.dumps(global_config.get_safe_config()))
+    assert "pass" not in payload
+
+
+@pytest.mark.asyncio
+async def test_config_get_returns_requested_section(config_instance: Config) -> None:
+    """Test: config/get mit Pfad liefert nur den angefragten Teil."""
+    config_instance.topics.topic_whitelist = {"b", "a"}
+    relay = MQTTRelay()
+    responses = []
+    with patch('loxmqttrelay.main.mqtt_client.publish', new_callable=AsyncMock) as mock_publish:
+        for path in (b"topics.topic_whitelist", b"topic_whitelist", b" broker ", b"topics.nope", b"topics."):
+            relay.miniserver_data_processor.handle_mqtt_message(TOPIC.CONFIG_GET, path)
+            await asyncio.sleep(0)
+            responses.append(json.loads(mock_publish.call_args[0][1]))
+    assert responses[0] == {"topics": {"topic_whitelist": ["a", "b"]}}
+    assert responses[1] == responses[0]
+    assert set(responses[2]) == {"broker"} and "password" not in responses[2]["broker"]
+    assert responses[3] == {"error": "Unknown configuration path: topics.nope"}
+    assert responses[4] == {"error": "Unknown configuration path: topics."}
+
+
+@pytest.mark.asyncio
+async def test_config_set_parses_json_payload(config_instance: Config) -> None:
+    """Test: config/set wird in Rust geparst, große Zahlen bleiben exakt."""
+    relay = MQTTRelay()
+    with patch.object(global_config, "update_fields") as update_fields, \
+            patch.object(relay, "restart_relay_incl_ui") as restart:
+        relay.miniserver_data_processor.handle_mqtt_message(
+            TOPIC.CONFIG_SET, b'{"topic_whitelist": ["a", "b"], "cache_size": 18446744073709551617}'
+        )
+        update_fields.assert_called_once_with(
+            {"topic_whitelist": ["a", "b"], "cache_size": 18446744073709551617}, "set"
+        )
+        # The restart waits for the config response to be published
+        for _ in range(40):
+            if restart.called:
+                break
+            await asyncio.sleep(0.05)
+        restart.assert_called_once()
+
+        update_fields.reset_mock()
+        relay.miniserver_data_processor.handle_mqtt_message(TOPIC.CONFIG_SET, b'{"topic_whitelist": ')
+        update_fields.assert_not_called()
+
+
+@pytest.mark.asyncio
+async def test_config_set_publishes_accepted_and_rejected_fields(config_instance: Config) -> None:
+    """Test: config/set meldet angenommene und abgelehnte Felder und wendet nur gültige Updates an."""
+    config_instance.topics.subscriptions = ["a"]
+    relay = MQTTRelay()
+    with patch('loxmqttrelay.main.mqtt_client.publish', new_callable=AsyncMock) as mock_publish, \
+            patch.object(global_config, "save_config") as save, \
+            patch.object(relay, "restart_relay_incl_ui") as restart:
+        relay.miniserver_data_processor.handle_mqtt_message(
+            TOPIC.CONFIG_SET,
+            b'{"subscriptions": ["b", 1], "do_not_forward": ["(open"], "max_name_length": 32, "nope": 1}',
+        )
+        await asyncio.sleep(0.05)
+        topic, payload = mock_publish.call_args[0]
+        assert topic == TOPIC.CONFIG_RESPONSE
+        result = json.loads(payload)
+        assert (result["mode"], result["applied"], result["restart"]) == ("set", False, False)
+        assert result["accepted"] == ["topics.max_name_length"]
+        assert result["rejected"][0] == {"field": "topics.subscriptions", "reason": "[1]: expected str, got int 1"}
+        assert result["rejected"][1] == {"field": "nope", "reason": "unknown configuration field"}
+        assert result["rejected"][2]["field"] == "topics.do_not_forward"
+        assert result["rejected"][2]["reason"].startswith("Invalid filter patterns")
+        save.assert_not_called()
+        restart.assert_not_called()
+        assert config_instance.topics.max_name_length == 64
+
+        relay.miniserver_data_processor.handle_mqtt_message(TOPIC.CONFIG_SET, b'["not", "an", "object"]')
+        await asyncio.sleep(0.05)
+        result = json.loads(mock_publish.call_args[0][1])
+        assert result["rejected"] == [{"field": None, "reason": "Expected a JSON object of configuration fields"}]
+
+        relay.miniserver_data_processor.handle_mqtt_message(TOPIC.CONFIG_ADD, b'{"topics": {"subscriptions": ["b"]}}')
+        for _ in range(40):
+            if restart.called:
+                break
+            await asyncio.sleep(0.05)
+        result = json.loads(mock_publish.call_args[0][1])
+        assert result == {"mode": "add", "applied": True, "restart": True, "accepted": ["topics.subscriptions"], "rejected": []}
+        restart.assert_called_once()
+        assert config_instance.topics.subscriptions == ["a", "b"]
+
+
+@pytest.mark.asyncio
+async def test_config_validate_reports_errors_without_applying(config_instance: Config) -> None:
+    """Test: config/validate meldet Schema- und Regex-Fehler, ohne etwas zu ändern."""
+    config_instance.topics.subscriptions = ["a"]
+    relay = MQTTRelay()
+    with patch('loxmqttrelay.main.mqtt_client.publish', new_callable=AsyncMock) as mock_publish, \
+            patch.object(global_config, "save_config") as save, \
+            patch.object(relay, "restart_relay_incl_ui") as restart:
+        relay.miniserver_data_processor.handle_mqtt_message(
+            TOPIC.CONFIG_VALIDATE,
+            b'{"subscriptions": ["b", 1], "do_not_forward": ["(open"], "topics": {"max_name_length": 32}}',
+        )
+        await asyncio.sleep(0)
+        topic, payload = mock_publish.call_args[0]
+        assert topic == TOPIC.CONFIG_VALIDATE_RESPONSE
+        result = json.loads(payload)
+        assert result["valid"] is False
+        assert result["hosts"] is None
+        assert result["errors"][0] == "subscriptions[1]: expected str, got int 1"
+        assert result["errors"][1].startswith("topics.do_not_forward: Invalid filter patterns")
+        assert len(result["errors"]) == 2
+
+        relay.miniserver_data_processor.handle_mqtt_message(
+            TOPIC.CONFIG_VALIDATE, b'{"config": {"subscriptions": ["b"]}, "mode": "add"}'
+        )
+        await asyncio.sleep(0)
+        assert json.loads(mock_publish.call_args[0][1]) == {"valid": True, "errors": [], "hosts": None}
+
+        save.assert_not_called()
+        restart.assert_not_called()
+        assert config_instance.topics.subscriptions == ["a"]
+        assert config_instance.topics.max_name_length == 64
+
+
+@pytest.mark.asyncio
+async def test_config_validate_checks_hosts(config_instance: Config) -> None:
+    """Test: Mit check_hosts wird die Erreichbarkeit von Broker und Miniserver geprüft."""
+    relay = MQTTRelay()
+    with patch('loxmqttrelay.main.mqtt_client.publish', new_callable=AsyncMock) as mock_publish:
+        # Port 1 on localhost refuses the connection right away
+        relay.miniserver_data_processor.handle_mqtt_message(
+            TOPIC.CONFIG_VALIDATE,
+            b'{"config": {"host": "127.0.0.1", "port": 1, "miniserver_ip": "127.0.0.1", "miniserver_port": 1},'
+            b' "check_hosts": true}',
+        )
+        for _ in range(100):
+            if mock_publish.called:
+                break
+            await asyncio.sleep(0.05)
+    result = json.loads(mock_publish.call_args[0][1])
+    assert result["valid"] is False
+    assert set(result["hosts"]) == {"broker", "miniserver"}
+    assert result["hosts"]["broker"] != "reachable"
+    assert result["errors"][0].startswith("broker: 127.0.0.1:1 is unreachable")
+
+
+@pytest.mark.asyncio
+async def test_filter_and_whitelist_topics_apply_without_restart(config_instance: Config) -> None:
+    """Test: Filter- und Whitelist-Topics wirken sofort, werden gespeichert und starten nichts neu."""
+    config_instance.topics.topic_whitelist = ["a"]
+    config_instance.topics.strict_filters = True
+    relay = MQTTRelay()
+    processor = relay.miniserver_data_processor
+    with patch.object(global_config, "save_config") as save, \
+            patch.object(relay, "restart_relay_incl_ui") as restart:
+        processor.handle_mqtt_message(TOPIC.WHITELIST_ADD, b'["c", "b"]')
+        assert processor.topic_whitelist == {"a", "b", "c"}
+        assert config_instance.topics.topic_whitelist == ["a", "b", "c"]
+        processor.handle_mqtt_message(TOPIC.WHITELIST_REMOVE, b'["a"]')
+        assert processor.topic_whitelist == {"b", "c"}
+
+        processor.handle_mqtt_message(TOPIC.FILTERS_SET, b'["^debug/"]')
+        processor.handle_mqtt_message(TOPIC.DNF_SET, b'["secret"]')
+        assert processor.get_subscription_filters() == ["^debug/"]
+        assert processor.get_do_not_forward_patterns() == ["secret"]
+        assert config_instance.topics.subscription_filters == ["^debug/"]
+        assert config_instance.topics.do_not_forward == ["secret"]
+        assert save.call_count == 4
+
+        # Rejected by strict_filters: neither applied nor saved
+        processor.handle_mqtt_message(TOPIC.FILTERS_SET, b'["(open"]')
+        assert processor.get_subscription_filters() == ["^debug/"]
+        assert config_instance.topics.subscription_filters == ["^debug/"]
+        assert save.call_count == 4
+        restart.assert_not_called()
+
+
+@pytest.mark.asyncio
+async def test_whitelist_add_remove_single_topic(config_instance: Config) -> None:
+    """Test: Einzelne Topics werden normalisiert in die Whitelist übernommen und gespeichert."""
+    config_instance.topics.topic_whitelist = ["a"]
+    relay = MQTTRelay()
+    processor = relay.miniserver_data_processor
+    with patch.object(global_config, "save_config") as save:
+        processor.handle_mqtt_message(TOPIC.WHITELIST_ADD, b"sensor/kitchen/temp ")
+        processor.handle_mqtt_message(TOPIC.WHITELIST_ADD, b'"sensor/hall/temp"')
+        assert processor.topic_whitelist == {"a", "sensor_kitchen_temp", "sensor_hall_temp"}
+        assert processor.is_in_whitelist("sensor/kitchen/temp")
+        assert config_instance.topics.topic_whitelist == ["a", "sensor_hall_temp", "sensor_kitchen_temp"]
+
+        processor.handle_mqtt_message(TOPIC.WHITELIST_REMOVE, b"sensor/kitchen/temp")
+        assert processor.topic_whitelist == {"a", "sensor_hall_temp"}
+        assert config_instance.topics.topic_whitelist == ["a", "sensor_hall_temp"]
+        assert save.call_count == 3
+
+        processor.handle_mqtt_message(TOPIC.WHITELIST_ADD, b"  ")
+        assert save.call_count == 3
+
+
+@pytest.mark.asyncio
+async def test_whitelist_get_publishes_chunks_with_sync_time(config_instance: Config) -> None:
+    """Test: whitelist/get publiziert die Whitelist in Teilen, mit Größe und Sync-Zeitpunkt."""
+    config_instance.miniserver.sync_with_miniserver = True
+    relay = MQTTRelay()
+    with patch('loxmqttrelay.main.sync_miniserver_whitelist', return_value=["c", "a", "b"]), \
+            patch.object(global_config, "save_config"):
+        await relay.handle_miniserver_sync()
+    with patch('loxmqttrelay.main.mqtt_client.publish', new_callable=AsyncMock) as mock_publish:
+        relay.miniserver_data_processor.handle_mqtt_message(TOPIC.WHITELIST_GET, b"2")
+        await asyncio.sleep(0.05)
+    chunks = [json.loads(c.args[1]) for c in mock_publish.call_args_list]
+    assert {c.args[0] for c in mock_publish.call_args_list} == {TOPIC.WHITELIST_RESPONSE}
+    assert [c["topics"] for c in chunks] == [["a", "b"], ["c"]]
+    assert [(c["chunk"], c["chunks"], c["size"]) for c in chunks] == [(1, 2, 3), (2, 2, 3)]
+    assert chunks[0]["synced_at"] is not None
+    assert chunks[0]["synced_at"] == chunks[0]["updated_at"]
+
+
+@pytest.mark.asyncio
+async def test_relay_switches_to_discovered_address(config_instance: Config) -> None:
+    """Test: Per mDNS gefundene Adresse wird übernommen."""
+    config_instance.miniserver.miniserver_ip = "192.168.1.10"
+    config_instance.miniserver.miniserver_port = 80
+    relay = MQTTRelay()
+    found = DiscoveredMiniserver("Loxone Miniserver", "192.168.1.77", 8080)
+    with patch("loxmqttrelay.main.discover_miniserver", new=AsyncMock(return_value=found)), \
+            patch.object(http_miniserver_handler, "set_miniserver_address") as set_address:
+        assert await relay.discover_miniserver() is True
+        set_address.assert_called_once_with("192.168.1.77", 8080)
+        assert relay.miniserver_data_processor.miniserver_address == ("192.168.1.77", 8080)
+        # Never written to the config, so save_config() can't persist it
+        assert config_instance.miniserver.miniserver_ip == "192.168.1.10"
+        assert config_instance.miniserver.miniserver_port == 80
+
+        # Unchanged address: nothing to do
+        assert await relay.discover_miniserver() is False
+        set_address.assert_called_once()
+
+
+@pytest.mark.asyncio
+async def test_relay_keeps_configured_address_when_nothing_found(config_instance: Config) -> None:
+    """Test: Ohne Antwort bleibt die konfigurierte Adresse."""
+    config_instance.miniserver.miniserver_ip = "192.168.1.10"
+    relay = MQTTRelay()
+    with patch("loxmqttrelay.main.discover_miniserver", new=AsyncMock(return_value=None)):
+        assert await relay.discover_miniserver() is False
+    assert config_instance.miniserver.miniserver_ip == "192.168.1.10"
+
+
+@pytest.mark.asyncio
+async def test_relay_switches_to_cloud_dns_address(config_instance: Config) -> None:
+    """Test: Die per Loxone Cloud DNS aufgelöste Adresse wird übernommen."""
+    config_instance.miniserver.miniserver_serial = "504F94A0B1C2"
+    relay = MQTTRelay()
+    with patch("loxmqttrelay.main.resolve_cloud_dns", new=AsyncMock(return_value=("93.184.216.34", 7777))) as resolve, \
+            patch.object(http_miniserver_handler, "set_miniserver_address") as set_address:
+        assert await relay.resolve_miniserver_via_cloud_dns() is True
+        resolve.assert_awaited_once_with("504F94A0B1C2")
+        set_address.assert_called_once_with("93.184.216.34", 7777)
+        assert relay.miniserver_data_processor.miniserver_address == ("93.184.216.34", 7777)
+        assert (config_instance.miniserver.miniserver_ip, config_instance.miniserver.miniserver_port) != ("93.184.216.34", 7777)
+
+        # Unchanged address: nothing to do
+        assert await relay.resolve_miniserver_via_cloud_dns() is False
+        set_address.assert_called_once()
+
+
+@pytest.mark.asyncio
+async def test_relay_shutdown_stops_tasks_and_drains_sends(config_instance: Config) -> None:
+    """Test: Beim Beenden werden Hintergrund-Tasks gestoppt und laufende Sends abgewartet."""
+    relay = MQTTRelay()
+    relay.miniserver_data_processor = MagicMock(shutdown=AsyncMock(return_value={"drained": 3, "aborted": 0}))
+    task = relay._start_background_task(asyncio.sleep(60))
+    with patch.object(http_miniserver_handler, "close", new=AsyncMock()) as close:
+        await relay.shutdown()
+        relay.miniserver_data_processor.shutdown.assert_awaited_once_with(config_instance.general.shutdown_timeout)
+        close.assert_awaited_once()
+    assert task.cancelled()
+    assert relay.background_tasks == []
+
+
+@pytest.mark.asyncio
+async def test_metrics_published_periodically(config_instance: Config) -> None:
+    """Test: Mit metrics_interval werden die Metriken regelmäßig nach <relay_topic>stats publiziert."""
+    config_instance.debug.metrics_interval = 0.01
+    relay = MQTTRelay()
+    relay.miniserver_data_processor.process_data("sensor/temp", "21")
+    with patch('loxmqttrelay.main.mqtt_client.publish', new_callable=AsyncMock) as mock_publish:
+        task = relay._start_background_task(relay.run_metrics_publishing())
+        await asyncio.sleep(0.05)
+        task.cancel()
+    topic, payload = mock_publish.call_args[0]
+    assert topic == TOPIC.METRICS
+    metrics = json.loads(payload)
+    assert metrics["topics"]["sensor/temp"]["received"] == 1
+    assert metrics["counters"]["messages"] == 1
@@ -0,0 +1,308 @@
+import asyncio
+import pytest
+from unittest.mock import AsyncMock
+from loxmqttrelay.config import global_config
+from loxmqttrelay.compatible._loxmqttrelay import RelayMqttClient
+from tests.test_miniserver_data_processor import TestMiniserverDataProcessor
+
+
+def _encode_length(length):
+    out = bytearray()
+    while True:
+        byte = length % 128
+        length //= 128
+        out.append(byte | 0x80 if length else byte)
+        if not length:
+            return bytes(out)
+
+
+def _string(data):
+    return len(data).to_bytes(2, "big") + data
+
+
+def _publish(topic, payload, qos=0, packet_id=1):
+    body = _string(topic) + (packet_id.to_bytes(2, "big") if qos else b"") + payload
+    return bytes([0x30 | qos << 1]) + _encode_length(len(body)) + body
+
+
+class FakeBroker:
+    """Just enough of an MQTT broker: records what clients send and answers CONNECT,
+    SUBSCRIBE and PINGREQ."""
+
+    def __init__(self):
+        self.packets = asyncio.Queue()
+        self.writers = []
+        self.server = None
+
+    async def start(self):
+        self.server = await asyncio.start_server(self._handle, "127.0.0.1", 0)
+        return self.server.sockets[0].getsockname()[1]
+
+    async def stop(self):
+        self.drop_clients()
+        self.server.close()
+        await self.server.wait_closed()
+
+    def drop_clients(self):
+        for writer in self.writers:
+            writer.close()
+        self.writers.clear()
+
+    def send(self, data):
+        self.writers[-1].write(data)
+
+    async def next(self, packet_type):
+        while True:
+            kind, body = await asyncio.wait_for(self.packets.get(), 5)
+            if kind == packet_type:
+                return body
+
+    async def _handle(self, reader, writer):
+        self.writers.append(writer)
+        try:
+            while True:
+                first = (await reader.readexactly(1))[0]
+                length, shift = 0, 0
+                while True:
+                    byte = (await reader.readexactly(1))[0]
+                    length += (byte & 0x7F) << shift
+                    shift += 7
+                    if not byte & 0x80:
+                        break
+                body = await reader.readexactly(length)
+                kind = first >> 4
+                await self.packets.put((kind, body if kind != 3 else (first, body)))
+                if kind == 1:
+                    writer.write(b"\x20\x02\x00\x00")
+                elif kind == 8:
+                    writer.write(b"\x90\x03" + body[:2] + b"\x00")
+                elif kind == 12:
+                    writer.write(b"\xd0\x00")
+        except (asyncio.IncompleteReadError, ConnectionError):
+            pass
+
+
+@pytest.fixture
+async def broker():
+    broker = FakeBroker()
+    broker.port = await broker.start()
+    yield broker
+    await broker.stop()
+
+
+def _subscribed_topics(body):
+    topics, at = [], 2
+    while at < len(body):
+        length = int.from_bytes(body[at:at + 2], "big")
+        topics.append(body[at + 2:at + 2 + length].decode())
+        at += 3 + length
+    return topics
+
+
+@pytest.mark.asyncio
+async def test_connect_subscribe_and_receive(broker):
+    received = []
+    states = []
+    client = RelayMqttClient("127.0.0.1", broker.port, client_id="relay-test", username="user", password="secret")
+    client.state_listener = lambda state, error: states.append(state)
+
+    await client.connect(["a/#", "b/c"], lambda topic, payload: received.append((topic, payload)))
+    connect = await broker.next(1)
+    assert connect[:7] == b"\x00\x04MQTT\x04"
+    assert connect[7] & 0xC0 == 0xC0
+    assert _string(b"relay-test") in connect
+    assert _subscribed_topics(await broker.next(8)) == ["a/#", "b/c"]
+    assert client.is_connected
+    assert states == ["connecting", "connected"]
+
+    broker.send(_publish(b"a/temp", b"21.5"))
+    broker.send(_publish(b"a/\xff", b"\x00\x01"))
+    broker.send(_publish(b"a/qos1", b"x", qos=1, packet_id=7))
+    assert await broker.next(4) == b"\x00\x07"
+    assert received == [("a/temp", b"21.5"), (b"a/\xff", b"\x00\x01"), ("a/qos1", b"x")]
+
+    await client.disconnect()
+    await broker.next(14)
+    assert not client.is_connected
+
+
+@pytest.mark.asyncio
+async def test_messages_go_straight_to_the_processor(broker):
+    processor = TestMiniserverDataProcessor(global_config).processor
+    processor.http_handler_obj.send_to_miniserver = AsyncMock(return_value={"code": 200})
+    client = RelayMqttClient("127.0.0.1", broker.port)
+    await client.connect(["sensors/#"], processor.handle_mqtt_message)
+    await broker.next(8)
+
+    broker.send(_publish(b"sensors/temp", b"21.5"))
+    for _ in range(50):
+        if processor.http_handler_obj.send_to_miniserver.call_count:
+            break
+        await asyncio.sleep(0.02)
+    processor.http_handler_obj.send_to_miniserver.assert_called_once_with("sensors/temp", "sensors_temp", "21.5")
+    await client.disconnect()
+
+
+@pytest.mark.asyncio
+async def test_full_send_queue_pauses_reading(broker, monkeypatch):
+    monkeypatch.setattr(global_config.miniserver, "send_concurrency", 1)
+    monkeypatch.setattr(global_config.miniserver, "send_queue_size", 1)
+    monkeypatch.setattr(global_config.miniserver, "send_queue_overflow", "block")
+    processor = TestMiniserverDataProcessor(global_config).processor
+    release, sent = asyncio.Event(), []
+
+    async def send(topic, name, value):
+        await release.wait()
+        sent.append(value)
+        return {"code": 200}
+
+    processor.http_handler_obj.send_to_miniserver = send
+    client = RelayMqttClient("127.0.0.1", broker.port)
+    await client.connect(["sensors/#"], processor.handle_mqtt_message)
+    await broker.next(8)
+
+    broker.send(_publish(b"sensors/a", b"1"))
+    await asyncio.sleep(0.1)
+    broker.send(_publish(b"sensors/b", b"2"))
+    await asyncio.sleep(0.1)
+    # The queue is full now: this one stays unread, so it isn't acknowledged yet
+    broker.send(_publish(b"sensors/c", b"3", qos=1, packet_id=9))
+    with pytest.raises(asyncio.TimeoutError):
+        await asyncio.wait_for(broker.next(4), 0.3)
+    release.set()
+    assert await broker.next(4) == b"\x00\x09"
+    for _ in range(50):
+        if len(sent) == 3:
+            break
+        await asyncio.sleep(0.02)
+    assert sent == ["1", "2", "3"]
+    assert processor.get_stats()["queue_overflow"] == 0
+    await client.disconnect()
+
+
+@pytest.mark.asyncio
+async def test_blocked_reading_keeps_the_connection(broker, monkeypatch):
+    """Pings go on while the full queue pauses reading, without timing out on the
+    PINGRESPs that can't be read yet"""
+    monkeypatch.setattr(global_config.miniserver, "send_concurrency", 1)
+    monkeypatch.setattr(global_config.miniserver, "send_queue_size", 1)
+    monkeypatch.setattr(global_config.miniserver, "send_queue_overflow", "block")
+    processor = TestMiniserverDataProcessor(global_config).processor
+    release = asyncio.Event()
+
+    async def send(topic, name, value):
+        await release.wait()
+        return {"code": 200}
+
+    processor.http_handler_obj.send_to_miniserver = send
+    states = []
+    client = RelayMqttClient("127.0.0.1", broker.port, keepalive=1)
+    client.state_listener = lambda state, error: states.append(state)
+    await client.connect(["sensors/#"], processor.handle_mqtt_message)
+    await broker.next(8)
+    for payload in (b"1", b"2", b"3"):
+        broker.send(_publish(b"sensors/a", payload))
+        await asyncio.sleep(0.1)
+
+    for _ in range(3):
+        await broker.next(12)
+    assert client.is_connected
+    assert states == ["connecting", "connected"]
+    release.set()
+    await asyncio.sleep(1.5)
+    assert states == ["connecting", "connected"]
+    await client.disconnect()
+
+
+@pytest.mark.asyncio
+async def test_publish_and_status_topic(broker):
+    client = RelayMqttClient("127.0.0.1", broker.port, status_topic="myrelay/status")
+    await client.connect([], lambda topic, payload: None)
+    connect = await broker.next(1)
+    # The status topic is the last will
+    assert connect[7] & 0x04
+    assert _string(b"myrelay/status") + _string(b"Connection lost") in connect
+    assert await broker.next(3) == (0x30, _string(b"myrelay/status") + b"Connected")
+
+    await client.publish("out/text", "hällo")
+    await client.publish("out/raw", b"\x01\x02", retain=True)
+    assert await broker.next(3) == (0x30, _string(b"out/text") + "hällo".encode())
+    assert await broker.next(3) == (0x31, _string(b"out/raw") + b"\x01\x02")
+    with pytest.raises(ValueError, match="qos"):
+        client.publish("out/text", "x", qos=2)
+
+    await client.disconnect()
+    assert await broker.next(3) == (0x30, _string(b"myrelay/status") + b"Disconnecting")
+
+
+@pytest.mark.asyncio
+async def test_retained_birth_and_will(broker):
+    client = RelayMqttClient(
+        "127.0.0.1", broker.port, status_topic="myrelay/status",
+        birth_message="online", will_message="offline", status_retain=True,
+    )
+    await client.connect([], lambda topic, payload: None)
+    connect = await broker.next(1)
+    # Will flag and will retain
+    assert connect[7] & 0x24 == 0x24
+    assert _string(b"myrelay/status") + _string(b"offline") in connect
+    assert await broker.next(3) == (0x31, _string(b"myrelay/status") + b"online")
+
+    await client.disconnect()
+    assert await broker.next(3) == (0x31, _string(b"myrelay/status") + b"Disconnecting")
+
+
+@pytest.mark.asyncio
+async def test_reconnect_resubscribes(broker):
+    states = []
+    client = RelayMqttClient("127.0.0.1", broker.port)
+    client.state_listener = lambda state, error: states.append(state)
+    await client.connect(["a/#"], lambda topic, payload: None)
+    await broker.next(8)
+    client.subscribe(["later/#"])
+    assert _subscribed_topics(await broker.next(8)) == ["later/#"]
+
+    broker.drop_clients()
+    await broker.next(1)
+    assert _subscribed_topics(await broker.next(8)) == ["a/#", "later/#"]
+    assert "offline" in states
+    assert states[-1] == "connected"
+    await client.disconnect()
+
+
+@pytest.mark.asyncio
+async def test_unacknowledged_publish_republished_after_reconnect(broker):
+    client = RelayMqttClient("127.0.0.1", broker.port)
+    await client.connect([], lambda topic, payload: None)
+    await broker.next(1)
+    await client.publish("out/qos1", "x", qos=1)
+    first, body = await broker.next(3)
+    assert first == 0x32
+
+    # The fake broker never acknowledges, so the publish goes out again on the new,
+    # clean session: as a new publish, without DUP
+    broker.drop_clients()
+    await broker.next(1)
+    assert await broker.next(3) == (0x32, body)
+    await client.disconnect()
+
+
+@pytest.mark.asyncio
+async def test_publish_without_connection_is_dropped():
+    client = RelayMqttClient("127.0.0.1", 1)
+    await client.publish("out/text", "x")
+    assert not client.is_connected
+
+
+@pytest.mark.parametrize("options,error", [
+    ({"ca_file": "/nonexistent/ca.pem"}, "Can't read the CA file"),
+    ({"cert_file": "/nonexistent/client.pem"}, "needs both"),
+    ({"cert_file": "/nonexistent/client.pem", "key_file": "/nonexistent/client.key"}, "Can't read the key file"),
+])
+def test_invalid_tls_options(options, error):
+    with pytest.raises(ValueError, match=error):
+        RelayMqttClient("127.0.0.1", 8883, tls=True, **options)
+
+
+def test_tls_options_unused_without_tls():
+    RelayMqttClient("127.0.0.1", 1883, ca_file="/nonexistent/ca.pem")
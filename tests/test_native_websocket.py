@@ -0,0 +1,365 @@
+import asyncio
+import base64
+import hashlib
+import hmac
+import json
+import re
+import struct
+import time
+import pytest
+from unittest.mock import AsyncMock, MagicMock
+from loxmqttrelay.config import global_config
+from loxmqttrelay.http_miniserver_handler import HttpMiniserverHandler
+from loxmqttrelay.compatible._loxmqttrelay import LoxWsClient, MiniserverDataProcessor
+from tests.test_miniserver_data_processor import DummyTopicNS
+
+# 1024 bit test key; the Miniserver hands out the public part as "CERTIFICATE"
+RSA_N = int(
+    "d6df9bea25dd49a3f94f9045fa430e180aa8deade28518ae0a3bbd960c9bcc4b160abce0ba6e321787f10488cc9c6ef5"
+    "19be935bab330aac9e88f1e0fb9062a19269299fff5af04bb562c4afd5aba0452e62c3fb978e97ddfbe58fdb72706c6a"
+    "02bc0639fda72b260bf5b7885e3da542da542de9faf03066efa9cdc3a6dbb9c3", 16)
+RSA_D = int(
+    "7e42db2d559e989d1fec9ecc5fe87329b169d59fbbd195d4fef267cefa0dba980c95b6b31f198d99c229e00e3a9d599d"
+    "f0c8c9723e4139ee8121db6fe6a2cb312de37aaf5eba1a00e826507929f2398a73f8bac6f4a483889207d55fdf920898"
+    "483579e31f348dc9f554b27e4cdc5e47c5c8ffe678c0000e6a2f0df71099d9b1", 16)
+PUBLIC_KEY = (
+    "-----BEGIN CERTIFICATE-----MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQDW35vqJd1Jo/lPkEX6Qw4YCqjereKFGK4KO72W"
+    "DJvMSxYKvOC6bjIXh/EEiMycbvUZvpNbqzMKrJ6I8eD7kGKhkmkpn/9a8Eu1YsSv1augRS5iw/uXjpfd++WP23JwbGoCvAY5/acr"
+    "Jgv1t4hePaVC2lQt6frwMGbvqc3Dptu5wwIDAQAB-----END CERTIFICATE-----")
+
+
+LOXONE_EPOCH = 1230768000
+# Key for hashing the token in refreshjwt, handed out with it
+TOKEN_KEY = "31323334"
+
+
+def _rsa_decrypt(cipher):
+    block = pow(int.from_bytes(cipher, "big"), RSA_D, RSA_N).to_bytes(128, "big")
+    assert block[:2] == b"\x00\x02"
+    return block[block.index(b"\x00", 2) + 1:]
+
+
+def _ll(control, value, code="200"):
+    return json.dumps({"LL": {"control": control, "value": value, "Code": code}})
+
+
+class FakeMiniserver:
+    """Serves getPublicKey over HTTP and answers the websocket handshake and commands."""
+
+    def __init__(self):
+        self.commands = asyncio.Queue()
+        self.writers = []
+        self.session_key = None
+        self.jwt_code = "200"
+        # Seconds until the tokens handed out expire
+        self.token_lifetime = 3600
+        self.refresh_code = "200"
+        self.answer_keepalive = True
+        # (identifier, payload) of the event tables sent after enablebinstatusupdate
+        self.event_tables = []
+
+    async def start(self):
+        self.server = await asyncio.start_server(self._handle, "127.0.0.1", 0)
+        return self.server.sockets[0].getsockname()[1]
+
+    async def stop(self):
+        for writer in self.writers:
+            writer.close()
+        self.server.close()
+        await self.server.wait_closed()
+
+    async def next(self):
+        return await asyncio.wait_for(self.commands.get(), 5)
+
+    def _token(self, token):
+        valid_until = int(time.time()) - LOXONE_EPOCH + self.token_lifetime
+        return {"token": token, "key": TOKEN_KEY, "validUntil": valid_until, "unsecurePass": False}
+
+    def _send(self, writer, opcode, payload):
+        length = len(payload)
+        head = bytes([0x80 | opcode]) + (bytes([length]) if length < 126 else b"\x7e" + length.to_bytes(2, "big"))
+        writer.write(head + payload)
+
+    def _respond(self, writer, text):
+        self._send(writer, 0x2, bytes([0x03, 0x00, 0x00, 0x00]) + len(text).to_bytes(4, "little"))
+        self._send(writer, 0x1, text.encode())
+
+    async def _handle(self, reader, writer):
+        self.writers.append(writer)
+        try:
+            head = (await reader.readuntil(b"\r\n\r\n")).decode()
+            if head.startswith("GET /jdev/sys/getPublicKey "):
+                body = _ll("dev/sys/getPublicKey", PUBLIC_KEY).encode()
+                writer.write(b"HTTP/1.1 200 OK\r\nContent-Length: %d\r\n\r\n" % len(body) + body)
+                await writer.drain()
+                writer.close()
+                return
+            assert head.startswith("GET /ws/rfc6455 ")
+            assert "Sec-WebSocket-Protocol: remotecontrol" in head
+            key = re.search(r"Sec-WebSocket-Key: (\S+)", head).group(1)
+            accept = base64.b64encode(hashlib.sha1((key + "258EAFA5-E914-47DA-95CA-C5AB0DC85B11").encode()).digest())
+            writer.write(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n"
+                         b"Sec-WebSocket-Accept: " + accept + b"\r\n\r\n")
+            while True:
+                first, second = await reader.readexactly(2)
+                assert second & 0x80, "client frames must be masked"
+                length = second & 0x7F
+                if length == 126:
+                    length = int.from_bytes(await reader.readexactly(2), "big")
+                mask = await reader.readexactly(4)
+                payload = bytes(b ^ mask[i % 4] for i, b in enumerate(await reader.readexactly(length)))
+                if first & 0x0F == 0x8:
+                    break
+                self._command(writer, payload.decode())
+        except (asyncio.IncompleteReadError, ConnectionError):
+            pass
+
+    def _command(self, writer, command):
+        self.commands.put_nowait(command)
+        if command == "keepalive":
+            if self.answer_keepalive:
+                self._send(writer, 0x2, bytes([0x03, 0x06, 0x00, 0x00, 0, 0, 0, 0]))
+        elif command.startswith("jdev/sys/keyexchange/"):
+            self.session_key = _rsa_decrypt(base64.b64decode(command[len("jdev/sys/keyexchange/"):])).decode()
+            self._respond(writer, _ll("jdev/sys/keyexchange", ""))
+        elif command.startswith("jdev/sys/getkey2/"):
+            self._respond(writer, _ll(command, {"key": "41424344", "salt": "0102", "hashAlg": "SHA256"}))
+        elif command.startswith("jdev/sys/enc/"):
+            cipher = base64.b64decode(command[len("jdev/sys/enc/"):].replace("%2B", "+").replace("%2F", "/").replace("%3D", "="))
+            assert len(cipher) % 16 == 0
+            value = self._token("abc") if self.jwt_code == "200" else ""
+            self._respond(writer, _ll("jdev/sys/getjwt", value, self.jwt_code))
+        elif command.startswith("jdev/sys/refreshjwt/"):
+            token_hash, user = command[len("jdev/sys/refreshjwt/"):].split("/")
+            assert token_hash == hmac.new(bytes.fromhex(TOKEN_KEY), b"abc", hashlib.sha256).hexdigest()
+            value = self._token("abc2") if self.refresh_code == "200" else ""
+            self._respond(writer, _ll("jdev/sys/refreshjwt", value, self.refresh_code))
+        elif command.startswith("jdev/sps/io/"):
+            self._respond(writer, _ll(command, "1"))
+        elif command == "jdev/sps/enablebinstatusupdate":
+            self._respond(writer, _ll("dev/sps/enablebinstatusupdate", "1"))
+            for kind, table in self.event_tables:
+                self._send(writer, 0x2, bytes([0x03, kind, 0x00, 0x00]) + len(table).to_bytes(4, "little"))
+                self._send(writer, 0x2, table)
+
+
+@pytest.fixture
+async def miniserver():
+    miniserver = FakeMiniserver()
+    miniserver.port = await miniserver.start()
+    yield miniserver
+    await miniserver.stop()
+
+
+async def _connected(miniserver, keepalive=60):
+    client = LoxWsClient("relay-test", keepalive)
+    await client.connect("relay user", "secret", f"http://127.0.0.1:{miniserver.port}")
+    return client
+
+
+@pytest.mark.asyncio
+async def test_handshake_and_send(miniserver):
+    client = await _connected(miniserver)
+    assert client.state == "CONNECTED"
+    assert (await miniserver.next()).startswith("jdev/sys/keyexchange/")
+    # The session key came through the RSA encryption intact
+    assert re.fullmatch(r"[0-9a-f]{64}:[0-9a-f]{32}", miniserver.session_key)
+    assert await miniserver.next() == "jdev/sys/getkey2/relay%20user"
+    assert (await miniserver.next()).startswith("jdev/sys/enc/")
+
+    await client.send_websocket_command("VI 1", "21.5 °C")
+    assert await miniserver.next() == "jdev/sps/io/VI%201/21.5%20%C2%B0C"
+    await client.close()
+    assert client.state == "DISCONNECTED"
+    with pytest.raises(OSError):
+        await client.send_websocket_command("VI1", "1")
+
+
+@pytest.mark.asyncio
+async def test_rejected_credentials(miniserver):
+    miniserver.jwt_code = "401"
+    client = LoxWsClient()
+    with pytest.raises(OSError, match="getjwt failed with code 401"):
+        await client.connect("relay", "wrong", f"ws://127.0.0.1:{miniserver.port}")
+    assert client.state == "DISCONNECTED"
+
+
+@pytest.mark.asyncio
+async def test_keepalive(miniserver):
+    client = await _connected(miniserver, keepalive=1)
+    for _ in range(3):
+        await miniserver.next()
+    assert await miniserver.next() == "keepalive"
+    assert await miniserver.next() == "keepalive"
+    assert client.state == "CONNECTED"
+
+    # Without an answer the connection counts as lost
+    miniserver.answer_keepalive = False
+    await asyncio.sleep(2.5)
+    assert client.state == "DISCONNECTED"
+
+
+@pytest.mark.asyncio
+async def test_token_refreshed_before_expiry(miniserver):
+    miniserver.token_lifetime = 2
+    client = await _connected(miniserver)
+    # The refreshed token lasts longer
+    miniserver.token_lifetime = 3600
+    assert client.token["refreshes"] == 0
+    for _ in range(3):
+        await miniserver.next()
+    # Refreshed once less than a tenth of the two seconds is left, hashed with the getjwt key
+    assert await miniserver.next() == "jdev/sys/refreshjwt/{}/relay%20user".format(
+        hmac.new(bytes.fromhex(TOKEN_KEY), b"abc", hashlib.sha256).hexdigest())
+    await asyncio.sleep(0.1)
+    token = client.token
+    assert token["refreshes"] == 1 and token["refresh_failures"] == 0
+    assert 3500 < token["expires_in"] <= 3600
+    assert client.state == "CONNECTED"
+    await client.close()
+
+
+@pytest.mark.asyncio
+async def test_failed_token_refresh_is_reported(miniserver):
+    miniserver.token_lifetime = 1
+    miniserver.refresh_code = "401"
+    client = await _connected(miniserver)
+    for _ in range(3):
+        await miniserver.next()
+    assert (await miniserver.next()).startswith("jdev/sys/refreshjwt/")
+    await asyncio.sleep(0.1)
+    token = client.token
+    assert token["refreshes"] == 0 and token["refresh_failures"] == 1
+    assert "refreshjwt failed with code 401" in token["last_error"]
+    await client.close()
+
+
+def test_tls_not_supported():
+    client = LoxWsClient()
+    with pytest.raises(ValueError, match="TLS"):
+        client.connect("relay", "secret", "https://miniserver.local")
+
+
+@pytest.mark.asyncio
+async def test_processor_sends_over_native_websocket(miniserver, monkeypatch):
+    monkeypatch.setattr(global_config.miniserver, "use_websocket", True)
+    client = await _connected(miniserver)
+    for _ in range(3):
+        await miniserver.next()
+    handler = MagicMock()
+    handler.native_ws = client
+    handler.native_target = MagicMock(return_value=("127.0.0.1", miniserver.port))
+    handler.send_to_miniserver = AsyncMock(return_value={"code": 200})
+    processor = MiniserverDataProcessor(DummyTopicNS(), global_config, AsyncMock(), MagicMock(), handler)
+
+    processor.process_data("sensor/temp", "21.5")
+    assert await miniserver.next() == "jdev/sps/io/sensor_temp/21.5"
+    await asyncio.sleep(0.05)
+    handler.send_to_miniserver.assert_not_called()
+    assert processor.get_delivery_status("sensor/temp")["sensor_temp"]["status"] == "delivered"
+
+    # Once it is gone the Python handler sends again
+    await client.close()
+    processor.process_data("sensor/temp", "22")
+    await asyncio.sleep(0.05)
+    handler.send_to_miniserver.assert_called_once_with("sensor/temp", "sensor_temp", "22")
+
+
+def _native_handler(miniserver):
+    handler = HttpMiniserverHandler()
+    handler.native_ws = LoxWsClient("relay-test", 60)
+    handler.ms_user, handler.ms_pass = "relay", "secret"
+    handler.ws_base_url = f"http://127.0.0.1:{miniserver.port}"
+    return handler
+
+
+@pytest.mark.asyncio
+async def test_handler_connects_native_websocket(miniserver):
+    """A disconnected native client ("DISCONNECTED") is connected by the handler"""
+    handler = _native_handler(miniserver)
+    assert handler.native_ws.state == "DISCONNECTED"
+    assert not handler.native_ws.is_connected
+
+    assert await handler.send_to_minisever_via_websocket("sensor/temp", "sensor_temp", "21.5") == {"code": 200}
+    assert handler.native_ws.is_connected
+    assert handler.websocket_status()["state"] == "CONNECTED"
+    for _ in range(3):
+        await miniserver.next()
+    assert await miniserver.next() == "jdev/sps/io/sensor_temp/21.5"
+
+    # Connected: no second handshake
+    await handler.ensure_websocket()
+    await handler.native_ws.send_websocket_command("VI1", "1")
+    assert await miniserver.next() == "jdev/sps/io/VI1/1"
+    await handler.native_ws.close()
+
+
+def _uuid_bytes(uuid):
+    first, second, third, rest = uuid.split("-")
+    return (int(first, 16).to_bytes(4, "little") + int(second, 16).to_bytes(2, "little")
+            + int(third, 16).to_bytes(2, "little") + bytes.fromhex(rest))
+
+
+@pytest.mark.asyncio
+async def test_states_published_to_mqtt(miniserver, monkeypatch):
+    light, temp, text = ("0f86a2fe-0378-3e08-ffffb2d4e9b0b6c8", "1a2b3c4d-0001-0002-ffff000000000001",
+                         "1a2b3c4d-0001-0002-ffff000000000002")
+    status = "Lüftung an".encode()
+    miniserver.event_tables = [
+        (2, _uuid_bytes(light) + struct.pack("<d", 1.0) + _uuid_bytes(temp) + struct.pack("<d", 21.5)),
+        (3, _uuid_bytes(text) + bytes(16) + len(status).to_bytes(4, "little") + status + bytes(-len(status) % 4)),
+    ]
+    monkeypatch.setattr(global_config.miniserver, "use_websocket", True)
+    monkeypatch.setattr(global_config.miniserver, "publish_states", True)
+    monkeypatch.setattr(global_config.miniserver, "state_mappings", [
+        {"uuid": light, "control": "Kitchen Light", "state": "active"},
+        {"uuid": text, "control": "Ventilation", "state": "text"},
+    ])
+    client = LoxWsClient("relay-test", 60)
+    handler = MagicMock()
+    handler.native_ws = client
+    mqtt_client = MagicMock()
+    mqtt_client.publish = AsyncMock()
+    MiniserverDataProcessor(DummyTopicNS(), global_config, AsyncMock(), mqtt_client, handler)
+
+    await client.connect("relay", "secret", f"http://127.0.0.1:{miniserver.port}", receive_updates=True)
+    for _ in range(3):
+        await miniserver.next()
+    assert await miniserver.next() == "jdev/sps/enablebinstatusupdate"
+    await asyncio.sleep(0.1)
+    prefix = f"{global_config.general.relay_topic.rstrip('/')}/miniserver/"
+    # The temperature has no mapping and publish_unmapped_states is off
+    assert [c.args for c in mqtt_client.publish.call_args_list] == [
+        (f"{prefix}Kitchen Light/active", "1", True),
+        (f"{prefix}Ventilation/text", "Lüftung an", True),
+    ]
+    await client.close()
+
+
+@pytest.mark.asyncio
+async def test_handler_connection_receives_states(miniserver, monkeypatch):
+    """ensure_websocket asks for state updates, which reach MQTT through the processor"""
+    light = "0f86a2fe-0378-3e08-ffffb2d4e9b0b6c8"
+    miniserver.event_tables = [(2, _uuid_bytes(light) + struct.pack("<d", 1.0))]
+    monkeypatch.setattr(global_config.miniserver, "use_websocket", True)
+    monkeypatch.setattr(global_config.miniserver, "publish_states", True)
+    monkeypatch.setattr(global_config.miniserver, "state_mappings", [{"uuid": light, "control": "Kitchen Light", "state": "active"}])
+    handler = _native_handler(miniserver)
+    mqtt_client = MagicMock()
+    mqtt_client.publish = AsyncMock()
+    MiniserverDataProcessor(DummyTopicNS(), global_config, AsyncMock(), mqtt_client, handler)
+
+    await handler.ensure_websocket()
+    for _ in range(3):
+        await miniserver.next()
+    assert await miniserver.next() == "jdev/sps/enablebinstatusupdate"
+    await asyncio.sleep(0.1)
+    prefix = f"{global_config.general.relay_topic.rstrip('/')}/miniserver/"
+    mqtt_client.publish.assert_called_once_with(f"{prefix}Kitchen Light/active", "1", True)
+    await handler.native_ws.close()
+
+
+def test_invalid_state_mapping_rejected(monkeypatch):
+    monkeypatch.setattr(global_config.miniserver, "state_mappings", [{"uuid": "0f86a2fe-0378-3e08-ffffb2d4e9b0b6c8"}])
+    with pytest.raises(ValueError, match="control"):
+        MiniserverDataProcessor(DummyTopicNS(), global_config, AsyncMock(), MagicMock(), MagicMock())
@@ -0,0 +1,95 @@
+import os
+import re
+import socket
+import subprocess
+import sys
+import textwrap
+
+
+def _run(script: str) -> subprocess.CompletedProcess:
+    # The Rust logger can only be set up once per process
+    env = dict(os.environ, RUST_LOG="error", PYTHONPATH=os.pathsep.join(sys.path))
+    result = subprocess.run([sys.executable, "-c", textwrap.dedent(script)], env=env, capture_output=True, text=True, timeout=60)
+    assert result.returncode == 0, result.stderr
+    return result
+
+
+def test_rust_log_file_rotates_by_size(tmp_path):
+    result = _run(f"""
+        from loxmqttrelay.compatible._loxmqttrelay import _panic, init_rust_logger
+        init_rust_logger({str(tmp_path / "logs" / "relay.log")!r}, 20000, 2)
+        for i in range(40):
+            try:
+                _panic(f"rotation test {{i}}")
+            except BaseException:
+                pass
+    """)
+
+    logs = tmp_path / "logs"
+    assert sorted(p.name for p in logs.iterdir()) == ["relay.log", "relay.log.1", "relay.log.2"]
+    for log in logs.iterdir():
+        assert 0 < log.stat().st_size <= 20000
+    assert "rotation test 39" in (logs / "relay.log").read_text()
+    # Still on stderr for docker logs
+    assert "rotation test 39" in result.stderr
+
+
+def test_rust_logs_go_to_syslog_in_rfc5424_format():
+    server = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+    server.bind(("127.0.0.1", 0))
+    server.settimeout(10)
+    port = server.getsockname()[1]
+    result = _run(f"""
+        from loxmqttrelay.compatible._loxmqttrelay import _panic, init_rust_logger
+        init_rust_logger(None, sink="syslog", syslog_address="127.0.0.1:{port}")
+        try:
+            _panic("syslog test")
+        except BaseException:
+            pass
+    """)
+    datagram = server.recv(65536).decode()
+    server.close()
+    # daemon facility (3) * 8 + error severity (3)
+    assert re.match(r"<27>1 \d{4}-\d\d-\d\dT\d\d:\d\d:\d\d\.\d{3}Z \S+ loxmqttrelay \d+ - - Panic in thread", datagram)
+    assert "syslog test" in datagram
+    # Not duplicated on stderr
+    assert "syslog test" not in result.stderr
+
+
+def test_rust_logs_go_through_python_logging():
+    result = _run("""
+        import logging
+        logging.basicConfig(format="PY %(levelname)s [%(name)s] %(message)s")
+        from loxmqttrelay.compatible._loxmqttrelay import _panic, init_rust_logger, set_rust_log_level
+        init_rust_logger(None, sink="python")
+        try:
+            _panic("bridged")
+        except BaseException:
+            pass
+        set_rust_log_level("loxmqttrelay.panic_hook", "off")
+        try:
+            _panic("silenced")
+        except BaseException:
+            pass
+        set_rust_log_level("loxmqttrelay::panic_hook", None)
+        try:
+            _panic("restored")
+        except BaseException:
+            pass
+    """)
+    assert "PY ERROR [loxmqttrelay.panic_hook] Panic in thread" in result.stderr
+    assert "bridged" in result.stderr
+    assert "silenced" not in result.stderr
+    assert "restored" in result.stderr
+
+
+def test_unknown_rust_log_level_is_rejected():
+    _run("""
+        from loxmqttrelay.compatible._loxmqttrelay import set_rust_log_level
+        try:
+            set_rust_log_level("", "loud")
+        except ValueError as e:
+            assert "loud" in str(e)
+        else:
+            raise AssertionError("expected a ValueError")
+    """)
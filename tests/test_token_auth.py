@@ -0,0 +1,97 @@
+import hashlib
+import hmac
+import time
+from types import SimpleNamespace
+from typing import Any, Dict, List
+from unittest.mock import AsyncMock, MagicMock
+
+import pytest
+
+from loxmqttrelay.token_auth import LOXONE_EPOCH, TokenAuth, TokenAuthError, hash_credentials
+
+KEY = "41434633443134324337383441373035"
+SALT = "7a9ae02f-1d1b-4ad1-bc2f-b2f3d5cb3b7e"
+
+
+class FakeMiniserver:
+    """Session stand-in answering the token commands, recording the requested commands."""
+
+    def __init__(self, valid_for: float = 3600, jwt_code: str = "200"):
+        self.valid_for = valid_for
+        self.jwt_code = jwt_code
+        self.now = 1_700_000_000.0
+        self.commands: List[str] = []
+        self.tokens = 0
+
+    def _answer(self, command: str) -> Dict[str, Any]:
+        valid_until = self.now + self.valid_for - LOXONE_EPOCH
+        if command.startswith("getkey2/"):
+            return {"LL": {"Code": "200", "value": {"key": KEY, "salt": SALT, "hashAlg": "SHA256"}}}
+        if command.startswith("getjwt/"):
+            self.tokens += 1
+            return {"LL": {"Code": self.jwt_code, "value": {"token": f"jwt{self.tokens}", "key": KEY, "validUntil": valid_until}}}
+        if command.startswith("refreshjwt/"):
+            self.tokens += 1
+            return {"LL": {"Code": "200", "value": {"token": f"jwt{self.tokens}", "validUntil": valid_until}}}
+        raise AssertionError(command)
+
+    def get(self, url: str) -> MagicMock:
+        command = url.split("/jdev/sys/", 1)[1]
+        self.commands.append(command.split("/")[0])
+        response = MagicMock()
+        response.status = 200
+        response.json = AsyncMock(return_value=self._answer(command))
+        response.__aenter__ = AsyncMock(return_value=response)
+        response.__aexit__ = AsyncMock(return_value=None)
+        return response
+
+
+@pytest.fixture
+def miniserver(monkeypatch) -> FakeMiniserver:
+    miniserver = FakeMiniserver()
+    monkeypatch.setattr("loxmqttrelay.token_auth.time", SimpleNamespace(time=lambda: miniserver.now, ctime=time.ctime))
+    return miniserver
+
+
+def test_hash_credentials() -> None:
+    pw_hash = hashlib.sha256(f"secret:{SALT}".encode()).hexdigest().upper()
+    expected = hmac.new(bytes.fromhex(KEY), f"admin:{pw_hash}".encode(), hashlib.sha256).hexdigest()
+    assert hash_credentials("admin", "secret", KEY, SALT, "SHA256") == expected
+    assert hash_credentials("admin", "secret", KEY, SALT, "SHA1") != expected
+
+
+@pytest.mark.asyncio
+async def test_token_is_acquired_once_and_cached(miniserver: FakeMiniserver) -> None:
+    auth = TokenAuth("admin", "secret", "relay")
+    assert await auth.get_token(miniserver, "http://ms") == "jwt1"
+    miniserver.now += 60
+    assert await auth.get_token(miniserver, "http://ms") == "jwt1"
+    assert miniserver.commands == ["getkey2", "getjwt"]
+    assert auth.query() == "autht=jwt1&user=admin"
+
+
+@pytest.mark.asyncio
+async def test_token_is_refreshed_before_expiry(miniserver: FakeMiniserver) -> None:
+    auth = TokenAuth("admin", "secret", "relay")
+    await auth.get_token(miniserver, "http://ms")
+    miniserver.now += 3300
+    assert await auth.get_token(miniserver, "http://ms") == "jwt2"
+    assert miniserver.commands == ["getkey2", "getjwt", "refreshjwt"]
+
+
+@pytest.mark.asyncio
+async def test_expired_or_rejected_token_is_reacquired(miniserver: FakeMiniserver) -> None:
+    auth = TokenAuth("admin", "secret", "relay")
+    await auth.get_token(miniserver, "http://ms")
+    miniserver.now += 3601
+    assert await auth.get_token(miniserver, "http://ms") == "jwt2"
+    auth.invalidate()
+    assert await auth.get_token(miniserver, "http://ms") == "jwt3"
+    assert miniserver.commands.count("getjwt") == 3
+
+
+@pytest.mark.asyncio
+async def test_rejected_credentials_raise(miniserver: FakeMiniserver) -> None:
+    miniserver.jwt_code = "401"
+    with pytest.raises(TokenAuthError):
+        await TokenAuth("admin", "wrong", "relay").get_token(miniserver, "http://ms")
@@ -191,3 +191,18 @@ async def test_start_udp_server(mock_mqtt_client):
         assert transport == mock_transport
         assert protocol == mock_protocol
         mock_loop.return_value.create_datagram_endpoint.assert_called_once()
+
+@pytest.mark.asyncio
+async def test_handle_udp_message_renders_template(mock_mqtt_client):
+    render = MagicMock(return_value='{"value": "42"}')
+    await handle_udp_message("publish test/topic 42", ("127.0.0.1", 1234), render)
+
+    render.assert_called_once_with("mqtt", "test/topic", "42")
+    mock_mqtt_client.publish.assert_called_once_with("test/topic", '{"value": "42"}', False)
+
+@pytest.mark.asyncio
+async def test_handle_udp_message_without_template(mock_mqtt_client):
+    render = MagicMock(return_value=None)
+    await handle_udp_message("publish test/topic 42", ("127.0.0.1", 1234), render)
+
+    mock_mqtt_client.publish.assert_called_once_with("test/topic", "42", False)
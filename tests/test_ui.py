@@ -34,6 +34,7 @@ def mock_args(monkeypatch: pytest.MonkeyPatch) -> MagicMock:
 def mock_config(monkeypatch: pytest.MonkeyPatch) -> AppConfig:
     config = AppConfig()
     config.general.base_topic = "test/topic/"
+    config.general.cache_warmup = False
     
     # Patch the global_config
     monkeypatch.setattr('loxmqttrelay.main.global_config', config)
@@ -119,12 +120,35 @@ def mock_topic(monkeypatch: pytest.MonkeyPatch) -> types.SimpleNamespace:
         MINISERVER_STARTUP_EVENT="test/miniserver/startup",
         CONFIG_GET="test/config/get",
         CONFIG_RESPONSE="test/config/response",
+        CONFIG_VALIDATE="test/config/validate",
+        CONFIG_VALIDATE_RESPONSE="test/config/validate/response",
+        FILTERS_SET="test/filters/set",
+        DNF_SET="test/dnf/set",
+        WHITELIST_ADD="test/whitelist/add",
+        WHITELIST_REMOVE="test/whitelist/remove",
+        WHITELIST_GET="test/whitelist/get",
+        WHITELIST_RESPONSE="test/whitelist/response",
+        STATS_GET="test/stats/get",
+        STATS_RESET="test/stats/reset",
+        STATS_RESPONSE="test/stats/response",
+        DEBUG_SET="test/debug/set",
         CONFIG_SET="test/config/set",
         CONFIG_ADD="test/config/add",
         CONFIG_REMOVE="test/config/remove",
         CONFIG_UPDATE="test/config/update",
         CONFIG_RESTART="test/config/restart",
-        UI_STATUS="test/ui/status"
+        UI_STATUS="test/ui/status",
+        HA_LOCK="test/ha/lock",
+        HA_STATE="test/ha/state",
+        EXPORT_VI="test/export/virtual_inputs",
+        EXPORT_RESPONSE="test/export/response",
+        DELIVERY_GET="test/delivery/get",
+        DELIVERY_RESPONSE="test/delivery/response",
+        FILTERS_TEST="test/filters/test",
+        FILTERS_TEST_RESPONSE="test/filters/test/response",
+        PAUSE="test/pause",
+        RESUME="test/resume",
+        STATE="test/connection/state"
     )
     monkeypatch.setattr('loxmqttrelay.main.TOPIC', topic)
     return topic
@@ -365,3 +389,53 @@ async def test_restart_relay_connection_retry(
         # Verify publish and disconnect were called after successful connection
         mock_client.publish.assert_called_once()
         mock_client.disconnect.assert_called_once()
+
+@pytest.mark.asyncio
+async def test_restart_relay_uses_instance_namespace(
+    mock_mqtt_client: MagicMock,
+    mock_config: AppConfig,
+    sample_toml_config: str
+) -> None:
+    """Restart command goes to the config topic of the configured instance"""
+    from loxmqttrelay.ui import restart_relay
+
+    config = tomlkit.loads(sample_toml_config)
+    config['general']['instance_id'] = 'garage'  # type: ignore
+
+    with patch('loxmqttrelay.ui.MQTTClient') as mock_gmqtt:
+        mock_client = AsyncMock()
+        mock_gmqtt.return_value = mock_client
+
+        await restart_relay(config)
+
+        mock_client.publish.assert_called_once_with(
+            'test/garage/config/restart',
+            b'',
+            qos=1,
+            retain=False
+        )
+
+@pytest.mark.asyncio
+async def test_send_rust_log_level(
+    mock_mqtt_client: MagicMock,
+    mock_config: AppConfig,
+    sample_toml_config: str
+) -> None:
+    """The level goes to the relay's debug/set topic"""
+    from loxmqttrelay.ui import send_rust_log_level
+
+    config = tomlkit.loads(sample_toml_config)
+
+    with patch('loxmqttrelay.ui.MQTTClient') as mock_gmqtt:
+        mock_client = AsyncMock()
+        mock_gmqtt.return_value = mock_client
+
+        await send_rust_log_level(config, "loxmqttrelay::mqtt_client", "DEBUG")
+
+        mock_client.publish.assert_called_once_with(
+            'test/debug/set',
+            b'{"rust_log_levels": {"loxmqttrelay::mqtt_client": "DEBUG"}}',
+            qos=1,
+            retain=False
+        )
+        mock_client.disconnect.assert_called_once()